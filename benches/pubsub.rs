@@ -2,8 +2,8 @@ use anyhow::Result;
 use criterion::{Criterion, criterion_group, criterion_main};
 use futures::StreamExt;
 use kv::{
-    ClientConfig, CommandRequest, ServerConfig, StorageConfig, YamuxCtrl, start_client_with_config,
-    start_server_with_config,
+    BoxedStream, Broadcaster, ClientConfig, CommandRequest, CommandResponse, ServerConfig,
+    StorageConfig, Topic, Value, YamuxCtrl, start_client_with_config, start_server_with_config,
 };
 use opentelemetry::sdk::propagation::TraceContextPropagator;
 use opentelemetry::sdk::trace::BatchConfig;
@@ -12,13 +12,11 @@ use opentelemetry::trace::Tracer;
 use opentelemetry::{KeyValue, global, runtime};
 use opentelemetry_otlp::WithExportConfig;
 use rand::prelude::SliceRandom;
-use std::sync::OnceLock;
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 use std::vec;
-use tokio::net::TcpStream;
 use tokio::runtime::{Builder, Runtime};
 use tokio::time;
-use tokio_rustls::client::TlsStream;
 use tracing::info;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
@@ -27,6 +25,8 @@ use tracing_subscriber::util::SubscriberInitExt;
 const SERVER_ADDR: &str = "127.0.0.1:9999";
 const OTLP_ENDPOINT: &str = "http://localhost:4317";
 const SUBSCRIBER_COUNT: usize = 100;
+// 直接测 Broadcaster 时的订阅者数量
+const FANOUT_SUBSCRIBER_COUNT: usize = 10_000;
 const TOPIC: &str = "lobby";
 const MESSAGE_VALUES: &[&str] = &["Hello", "Tyr", "Goodbye", "World"];
 
@@ -42,7 +42,7 @@ async fn start_server() -> Result<()> {
     Ok(())
 }
 
-async fn connect() -> Result<YamuxCtrl<TlsStream<TcpStream>>> {
+async fn connect() -> Result<YamuxCtrl<BoxedStream>> {
     let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
     config.general.addr = SERVER_ADDR.into();
 
@@ -145,9 +145,36 @@ fn start_benchmark(c: &mut Criterion) {
     global::shutdown_tracer_provider();
 }
 
+// 不经过网络，直接向 FANOUT_SUBSCRIBER_COUNT 个订阅者发布，订阅者在后台不停地读
+fn fanout_benchmark(c: &mut Criterion) {
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(4)
+        .thread_name("fanout")
+        .enable_all()
+        .build()
+        .unwrap();
+    let broadcaster = Arc::new(Broadcaster::default());
+    runtime.block_on(async {
+        for _ in 0..FANOUT_SUBSCRIBER_COUNT {
+            let mut rx = broadcaster.clone().subscribe(TOPIC.into()).unwrap();
+            tokio::spawn(async move { while rx.recv().await.is_some() {} });
+        }
+    });
+    let value: Value = "Hello".into();
+    let message = Arc::new(CommandResponse::from(value));
+
+    c.bench_function("fanout 10k subscribers", |b| {
+        b.to_async(&runtime).iter(|| async {
+            broadcaster
+                .clone()
+                .publish(TOPIC.into(), Arc::clone(&message))
+        });
+    });
+}
+
 criterion_group! {
     name = benches;
     config = Criterion::default().sample_size(20).measurement_time(Duration::from_secs(10)).warm_up_time(Duration::from_secs(10));
-    targets = start_benchmark
+    targets = fanout_benchmark, start_benchmark
 }
 criterion_main!(benches);
@@ -1,7 +1,16 @@
 fn main() {
     let mut config = prost_build::Config::new();
     config.bytes(["."]);
-    config.type_attribute(".", "#[derive(PartialOrd)]");
+    // 只给 message（以及其中的 oneof）加，prost 生成的枚举已经 derive 了 PartialOrd
+    let proto = std::fs::read_to_string("abi.proto").unwrap();
+    for line in proto.lines() {
+        if let Some(name) = line
+            .strip_prefix("message ")
+            .and_then(|s| s.split_whitespace().next())
+        {
+            config.type_attribute(format!(".abi.{}", name), "#[derive(PartialOrd)]");
+        }
+    }
     config
         .out_dir("src/pb")
         .compile_protos(&["abi.proto"], &["."])
@@ -1,21 +1,22 @@
 use anyhow::Result;
 use async_prost::AsyncProstStream;
 use futures::prelude::*;
-use kv::{CommandRequest, CommandResponse, Service, SledDb};
+use kv::{CommandRequest, CommandResponse, RequestContext, Service, SledDb};
 use tokio::net::TcpListener;
 use tracing::info;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
-    let service: Service =
-        Service::new(SledDb::new("/tmp/kvserver")).fn_before_send(|res: &mut CommandResponse| {
+    let service: Service = Service::new(SledDb::new("/tmp/kvserver")).fn_before_send(
+        |_: &RequestContext, res: &mut CommandResponse| {
             match res.message.as_ref() {
                 "" => res.message = "altered. Original message is empty.".into(),
                 s => res.message = format!("altered: {}", s),
             }
             None // 返回 None 表示继续处理
-        });
+        },
+    );
     let addr = "127.0.0.1:9527";
     let listener = TcpListener::bind(addr).await?;
     info!("Start listening to {}", addr);
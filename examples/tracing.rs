@@ -36,9 +36,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 fn do_work() {
-    tracing::info_span!("do_work").in_scope(async || {
+    tracing::info_span!("do_work").in_scope(|| {
         tracing::info!("Doing some work");
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        std::thread::sleep(std::time::Duration::from_millis(100));
         tracing::info!("Work completed");
     });
 }
@@ -0,0 +1,385 @@
+//! 压测工具，kv-bench 的实现
+//!
+//! 多个连接并发地按比例发送 HSET/HGET/PUBLISH，持续一段时间后按命令汇总吞吐和延迟的百分位数
+
+use crate::{ClientConfig, CommandRequest, KvClient, KvError};
+use bytes::Bytes;
+use rand::Rng;
+use rand::distributions::{Distribution, WeightedIndex};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// 压测的命令
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BenchOp {
+    Hset,
+    Hget,
+    Publish,
+}
+
+impl BenchOp {
+    const ALL: [BenchOp; 3] = [BenchOp::Hset, BenchOp::Hget, BenchOp::Publish];
+
+    fn name(&self) -> &'static str {
+        match self {
+            BenchOp::Hset => "hset",
+            BenchOp::Hget => "hget",
+            BenchOp::Publish => "publish",
+        }
+    }
+}
+
+/// 各个命令的比例，比如 `hset=1,hget=9` 表示 10% 的 HSET 和 90% 的 HGET，没有列出的命令不发送
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mix {
+    weights: [u32; 3],
+}
+
+impl Default for Mix {
+    fn default() -> Self {
+        "hset=1,hget=1".parse().unwrap()
+    }
+}
+
+impl FromStr for Mix {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || KvError::InvalidConfig(format!("invalid mix: {}", s));
+        let mut weights = [0; 3];
+        for part in s.split(',').filter(|p| !p.trim().is_empty()) {
+            let (name, weight) = part.split_once('=').ok_or_else(invalid)?;
+            let i = BenchOp::ALL
+                .iter()
+                .position(|op| op.name().eq_ignore_ascii_case(name.trim()))
+                .ok_or_else(invalid)?;
+            weights[i] = weight.trim().parse().map_err(|_| invalid())?;
+        }
+        if weights.iter().all(|w| *w == 0) {
+            return Err(invalid());
+        }
+        Ok(Self { weights })
+    }
+}
+
+/// key 的分布
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyDistribution {
+    /// 每个 key 的概率一样
+    Uniform,
+    /// Zipf 分布，少数热点 key 占大部分请求，参数是指数，YCSB 默认使用 0.99
+    Zipf(f64),
+}
+
+impl FromStr for KeyDistribution {
+    type Err = KvError;
+
+    /// `uniform`、`zipf` 或者 `zipf:<指数>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || KvError::InvalidConfig(format!("invalid key distribution: {}", s));
+        match s.split_once(':') {
+            None if s.eq_ignore_ascii_case("uniform") => Ok(Self::Uniform),
+            None if s.eq_ignore_ascii_case("zipf") => Ok(Self::Zipf(0.99)),
+            Some((name, exponent)) if name.eq_ignore_ascii_case("zipf") => {
+                match exponent.parse::<f64>() {
+                    Ok(e) if e > 0.0 => Ok(Self::Zipf(e)),
+                    _ => Err(invalid()),
+                }
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    pub client: ClientConfig,
+    /// 并发的连接数，每个连接上的请求依次发送
+    pub concurrency: usize,
+    pub duration: Duration,
+    pub mix: Mix,
+    /// key 的数量，key 是 `key:0` 到 `key:<keys - 1>`
+    pub keys: usize,
+    pub distribution: KeyDistribution,
+    /// HSET 和 PUBLISH 的 value 的字节数
+    pub value_size: usize,
+    pub table: String,
+    pub topic: String,
+}
+
+impl BenchConfig {
+    /// 其它参数使用默认值：16 个连接，持续 10 秒，HSET 和 HGET 各一半，10000 个均匀分布的 key
+    pub fn new(client: ClientConfig) -> Self {
+        Self {
+            client,
+            concurrency: 16,
+            duration: Duration::from_secs(10),
+            mix: Mix::default(),
+            keys: 10_000,
+            distribution: KeyDistribution::Uniform,
+            value_size: 64,
+            table: "bench".into(),
+            topic: "bench".into(),
+        }
+    }
+}
+
+/// 一种命令的压测结果
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpReport {
+    pub command: &'static str,
+    pub count: u64,
+    /// 连接出错或者返回了 200、404 以外的状态码
+    pub errors: u64,
+    pub ops_per_sec: f64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// 类似 LATENCY 的单行输出，延迟单位是微秒
+impl fmt::Display for OpReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cmd={} count={} errors={} ops/s={:.0} p50={}us p95={}us p99={}us max={}us",
+            self.command,
+            self.count,
+            self.errors,
+            self.ops_per_sec,
+            self.p50.as_micros(),
+            self.p95.as_micros(),
+            self.p99.as_micros(),
+            self.max.as_micros(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchReport {
+    pub elapsed: Duration,
+    /// 按命令名排序，没有发送过的命令不出现
+    pub ops: Vec<OpReport>,
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total: u64 = self.ops.iter().map(|op| op.count).sum();
+        writeln!(
+            f,
+            "{} requests in {:.2}s, {:.0} ops/s",
+            total,
+            self.elapsed.as_secs_f64(),
+            total as f64 / self.elapsed.as_secs_f64()
+        )?;
+        for op in &self.ops {
+            writeln!(f, "{}", op)?;
+        }
+        Ok(())
+    }
+}
+
+/// 按分布选择 key
+#[derive(Debug)]
+struct KeySampler {
+    keys: usize,
+    /// Zipf 分布的累积概率，均匀分布时为空
+    cdf: Vec<f64>,
+}
+
+impl KeySampler {
+    fn new(keys: usize, distribution: KeyDistribution) -> Self {
+        let keys = keys.max(1);
+        let cdf = match distribution {
+            KeyDistribution::Uniform => Vec::new(),
+            KeyDistribution::Zipf(exponent) => {
+                let mut sum = 0.0;
+                let mut cdf: Vec<f64> = (1..=keys)
+                    .map(|i| {
+                        sum += 1.0 / (i as f64).powf(exponent);
+                        sum
+                    })
+                    .collect();
+                cdf.iter_mut().for_each(|c| *c /= sum);
+                cdf
+            }
+        };
+        Self { keys, cdf }
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> usize {
+        if self.cdf.is_empty() {
+            return rng.gen_range(0..self.keys);
+        }
+        let r: f64 = rng.r#gen();
+        self.cdf.partition_point(|c| *c < r).min(self.keys - 1)
+    }
+}
+
+/// 一个连接上记录的延迟，每种命令一组
+#[derive(Default)]
+struct Samples {
+    latencies: [Vec<Duration>; 3],
+    errors: [u64; 3],
+}
+
+/// 运行压测，所有连接建立之后开始计时
+pub async fn run(config: &BenchConfig) -> Result<BenchReport, KvError> {
+    let mut clients = Vec::with_capacity(config.concurrency);
+    for _ in 0..config.concurrency.max(1) {
+        clients.push(KvClient::connect(config.client.clone()).await?);
+    }
+    let sampler = Arc::new(KeySampler::new(config.keys, config.distribution));
+    let ops = WeightedIndex::new(config.mix.weights)
+        .map_err(|e| KvError::InvalidConfig(format!("invalid mix: {}", e)))?;
+    let value = Bytes::from(vec![b'x'; config.value_size]);
+
+    let start = Instant::now();
+    let deadline = start + config.duration;
+    let workers: Vec<_> = clients
+        .into_iter()
+        .map(|client| {
+            let worker = Worker {
+                client,
+                sampler: sampler.clone(),
+                ops: ops.clone(),
+                value: value.clone(),
+                table: config.table.clone(),
+                topic: config.topic.clone(),
+            };
+            tokio::spawn(worker.run(deadline))
+        })
+        .collect();
+
+    let mut samples = Samples::default();
+    for worker in workers {
+        let s = worker
+            .await
+            .map_err(|e| KvError::Internal(format!("bench worker failed: {}", e)))?;
+        for i in 0..BenchOp::ALL.len() {
+            samples.latencies[i].extend_from_slice(&s.latencies[i]);
+            samples.errors[i] += s.errors[i];
+        }
+    }
+    let elapsed = start.elapsed();
+    Ok(report(samples, elapsed))
+}
+
+struct Worker {
+    client: KvClient,
+    sampler: Arc<KeySampler>,
+    ops: WeightedIndex<u32>,
+    value: Bytes,
+    table: String,
+    topic: String,
+}
+
+impl Worker {
+    async fn run(mut self, deadline: Instant) -> Samples {
+        let mut samples = Samples::default();
+        while Instant::now() < deadline {
+            let (i, cmd) = {
+                let mut rng = rand::thread_rng();
+                let i = self.ops.sample(&mut rng);
+                let key = format!("key:{}", self.sampler.sample(&mut rng));
+                (i, self.command(BenchOp::ALL[i], key))
+            };
+            let start = Instant::now();
+            let ok = match self.client.execute_unary(cmd).await {
+                Ok(res) => res.status == 200 || res.status == 404,
+                Err(_) => false,
+            };
+            samples.latencies[i].push(start.elapsed());
+            if !ok {
+                samples.errors[i] += 1;
+            }
+        }
+        samples
+    }
+
+    fn command(&self, op: BenchOp, key: String) -> CommandRequest {
+        match op {
+            BenchOp::Hset => CommandRequest::new_hset(&self.table, key, self.value.clone().into()),
+            BenchOp::Hget => CommandRequest::new_hget(&self.table, key),
+            BenchOp::Publish => {
+                CommandRequest::new_publish(&self.topic, vec![self.value.clone().into()])
+            }
+        }
+    }
+}
+
+fn report(samples: Samples, elapsed: Duration) -> BenchReport {
+    let ops = BenchOp::ALL
+        .iter()
+        .zip(samples.latencies)
+        .zip(samples.errors)
+        .filter(|((_, latencies), _)| !latencies.is_empty())
+        .map(|((op, mut latencies), errors)| {
+            latencies.sort_unstable();
+            let percentile = |p: f64| {
+                let i = ((latencies.len() as f64 * p).ceil() as usize).max(1) - 1;
+                latencies[i.min(latencies.len() - 1)]
+            };
+            OpReport {
+                command: op.name(),
+                count: latencies.len() as u64,
+                errors,
+                ops_per_sec: latencies.len() as f64 / elapsed.as_secs_f64(),
+                p50: percentile(0.5),
+                p95: percentile(0.95),
+                p99: percentile(0.99),
+                max: *latencies.last().unwrap(),
+            }
+        })
+        .collect();
+    BenchReport { elapsed, ops }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn mix_and_distribution_should_parse() {
+        let mix: Mix = "HSET=1, hget=9".parse().unwrap();
+        assert_eq!(mix.weights, [1, 9, 0]);
+        assert!("hset=0".parse::<Mix>().is_err());
+        assert!("hdel=1".parse::<Mix>().is_err());
+
+        let parse = |s: &str| s.parse::<KeyDistribution>().unwrap();
+        assert_eq!(parse("uniform"), KeyDistribution::Uniform);
+        assert_eq!(parse("zipf"), KeyDistribution::Zipf(0.99));
+        assert_eq!(parse("zipf:1.2"), KeyDistribution::Zipf(1.2));
+        assert!("zipf:-1".parse::<KeyDistribution>().is_err());
+    }
+
+    #[test]
+    fn zipf_should_favor_hot_keys() {
+        let sampler = KeySampler::new(100, KeyDistribution::Zipf(0.99));
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut counts = [0; 100];
+        for _ in 0..10_000 {
+            counts[sampler.sample(&mut rng)] += 1;
+        }
+        assert!(counts[0] > counts[9] && counts[9] > counts[99]);
+        // 前 10% 的 key 占了一半以上的请求
+        assert!(counts[..10].iter().sum::<i32>() > 5_000);
+    }
+
+    #[test]
+    fn report_should_compute_percentiles() {
+        let mut samples = Samples::default();
+        samples.latencies[1] = (1..=100).rev().map(Duration::from_micros).collect();
+        samples.errors[1] = 2;
+        let report = report(samples, Duration::from_secs(2));
+        assert_eq!(report.ops.len(), 1);
+        assert_eq!(
+            report.ops[0].to_string(),
+            "cmd=hget count=100 errors=2 ops/s=50 p50=50us p95=95us p99=99us max=100us"
+        );
+    }
+}
@@ -0,0 +1,520 @@
+use crate::{
+    ChangeEvent, ChangeOp, CommandRequest, CommandResponse, CompareOp, Filter, KvError, Kvpair,
+    Value, value,
+};
+
+/// 命令行里的一个参数，带引号的参数总是当作字符串
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Bare(String),
+    Quoted(String),
+}
+
+impl Token {
+    fn text(&self) -> &str {
+        match self {
+            Token::Bare(s) | Token::Quoted(s) => s,
+        }
+    }
+
+    /// 把参数解析成 Value：整数、浮点数、布尔值，其它都是字符串。超过 i64 的正整数是 u64
+    fn value(&self) -> Value {
+        match self {
+            Token::Quoted(s) => s.as_str().into(),
+            Token::Bare(s) => {
+                if let Ok(i) = s.parse::<i64>() {
+                    i.into()
+                } else if let Ok(u) = s.parse::<u64>() {
+                    u.into()
+                } else if let Ok(f) = s.parse::<f64>() {
+                    f.into()
+                } else if let Ok(b) = s.parse::<bool>() {
+                    b.into()
+                } else {
+                    s.as_str().into()
+                }
+            }
+        }
+    }
+}
+
+/// 解析一行命令，比如 `HSET t1 k "hello world"`
+pub fn parse_command(line: &str) -> Result<CommandRequest, KvError> {
+    parse_tokens(tokenize(line)?)
+}
+
+/// 解析已经由 shell 拆分好的参数，比如 `kv-cli HSET t1 k v`
+pub fn parse_args(args: &[String]) -> Result<CommandRequest, KvError> {
+    parse_tokens(args.iter().map(|s| Token::Bare(s.clone())).collect())
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token>, KvError> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => s.push('\n'),
+                        Some('t') => s.push('\t'),
+                        Some(c) => s.push(c),
+                        None => return Err(KvError::InvalidCommand("unterminated quote".into())),
+                    },
+                    Some(c) => s.push(c),
+                    None => return Err(KvError::InvalidCommand("unterminated quote".into())),
+                }
+            }
+            tokens.push(Token::Quoted(s));
+        } else {
+            let mut s = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                s.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Bare(s));
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn parse_tokens(tokens: Vec<Token>) -> Result<CommandRequest, KvError> {
+    let Some((name, args)) = tokens.split_first() else {
+        return Err(KvError::InvalidCommand("empty command".into()));
+    };
+    let name = name.text().to_lowercase();
+    let texts =
+        |args: &[Token]| -> Vec<String> { args.iter().map(|t| t.text().to_string()).collect() };
+
+    let cmd = match (name.as_str(), args) {
+        ("hget", [table, key]) => CommandRequest::new_hget(table.text(), key.text()),
+        ("hgetall", [table]) => CommandRequest::new_hgetall(table.text()),
+        ("hgetall", [table, op, arg]) => {
+            CommandRequest::new_hgetall_filtered(table.text(), parse_filter(op, arg)?)
+        }
+        ("hmget", [table, keys @ ..]) if !keys.is_empty() => {
+            CommandRequest::new_hmget(table.text(), texts(keys))
+        }
+        ("hset", [table, key, value]) => {
+            CommandRequest::new_hset(table.text(), key.text(), value.value())
+        }
+        ("hmset", [table, pairs @ ..]) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            let pairs = pairs
+                .chunks(2)
+                .map(|kv| Kvpair::new(kv[0].text(), kv[1].value()))
+                .collect();
+            CommandRequest::new_hmset(table.text(), pairs)
+        }
+        ("hdel", [table, key]) => CommandRequest::new_hdel(table.text(), key.text()),
+        ("hmdel", [table, keys @ ..]) if !keys.is_empty() => {
+            CommandRequest::new_hmdel(table.text(), texts(keys))
+        }
+        ("hdelprefix", [table, prefix]) => {
+            CommandRequest::new_hdelprefix(table.text(), prefix.text())
+        }
+        ("hdebug", [table, key]) => CommandRequest::new_hdebug(table.text(), key.text()),
+        ("htableexists", [table]) => CommandRequest::new_htableexists(table.text()),
+        ("hexist", [table, key]) => CommandRequest::new_hexist(table.text(), key.text()),
+        ("hmexist", [table, keys @ ..]) if !keys.is_empty() => {
+            CommandRequest::new_hmexist(table.text(), texts(keys))
+        }
+        ("subscribe", [topic]) => CommandRequest::new_subscribe(topic.text()),
+        ("hwatch", []) => CommandRequest::new_hwatch(""),
+        ("hwatch", [table]) => CommandRequest::new_hwatch(table.text()),
+        ("hwatch", [table, flag]) if flag.text().eq_ignore_ascii_case("snapshot") => {
+            CommandRequest::new_hwatch_snapshot(table.text())
+        }
+        ("unsubscribe", [topic, id]) => {
+            let id = id
+                .text()
+                .parse()
+                .map_err(|_| KvError::InvalidCommand(format!("invalid id: {}", id.text())))?;
+            CommandRequest::new_unsubscribe(topic.text(), id)
+        }
+        ("publish", [topic, data @ ..]) if !data.is_empty() => {
+            CommandRequest::new_publish(topic.text(), data.iter().map(|t| t.value()).collect())
+        }
+        ("client", [sub]) if sub.text().eq_ignore_ascii_case("list") => {
+            CommandRequest::new_client_list()
+        }
+        ("client", [sub, rest @ ..]) if sub.text().eq_ignore_ascii_case("kill") => {
+            parse_client_kill(rest)?
+        }
+        ("latency", []) => CommandRequest::new_latency(),
+        ("jobs", []) => CommandRequest::new_jobs(),
+        ("save", [name]) => CommandRequest::new_save(name.text()),
+        ("restore", [name]) => CommandRequest::new_restore(name.text()),
+        ("reload", []) => CommandRequest::new_reload(),
+        ("flushall", [confirm]) => CommandRequest::new_flushall(confirm.text()),
+        ("cluster", []) => CommandRequest::new_cluster(),
+        ("cluster", [sub]) if sub.text().eq_ignore_ascii_case("nodes") => {
+            CommandRequest::new_cluster()
+        }
+        ("cluster", [sub]) if sub.text().eq_ignore_ascii_case("slots") => {
+            CommandRequest::new_cluster_slots()
+        }
+        ("auth", [password]) => CommandRequest::new_auth(password.text()),
+        ("promote", []) => CommandRequest::new_promote(0),
+        ("promote", [epoch]) => {
+            let epoch = epoch
+                .text()
+                .parse()
+                .map_err(|_| KvError::InvalidCommand(format!("invalid epoch: {}", epoch.text())))?;
+            CommandRequest::new_promote(epoch)
+        }
+        (
+            "hget" | "hgetall" | "hmget" | "hset" | "hmset" | "hdel" | "hmdel" | "hdelprefix"
+            | "htableexists" | "hdebug" | "hexist" | "hmexist" | "subscribe" | "hwatch"
+            | "unsubscribe" | "publish" | "client" | "latency" | "cluster" | "promote" | "auth"
+            | "jobs" | "save" | "restore" | "reload" | "flushall",
+            _,
+        ) => {
+            return Err(KvError::InvalidCommand(format!(
+                "wrong number of arguments for {}",
+                name
+            )));
+        }
+        _ => return Err(KvError::InvalidCommand(format!("unknown command {}", name))),
+    };
+
+    Ok(cmd)
+}
+
+/// CLIENT KILL <addr> | CLIENT KILL ID <id> | CLIENT KILL ADDR <addr>
+fn parse_client_kill(args: &[Token]) -> Result<CommandRequest, KvError> {
+    match args {
+        [addr] => Ok(CommandRequest::new_client_kill_addr(addr.text())),
+        [filter, id] if filter.text().eq_ignore_ascii_case("id") => {
+            let id = id
+                .text()
+                .parse()
+                .map_err(|_| KvError::InvalidCommand(format!("invalid id: {}", id.text())))?;
+            Ok(CommandRequest::new_client_kill(id))
+        }
+        [filter, addr] if filter.text().eq_ignore_ascii_case("addr") => {
+            Ok(CommandRequest::new_client_kill_addr(addr.text()))
+        }
+        _ => Err(KvError::InvalidCommand(
+            "wrong number of arguments for client kill".into(),
+        )),
+    }
+}
+
+/// HGETALL 的过滤条件：TYPE <type> | PREFIX <s> | CONTAINS <s> | <op> <number>，
+/// op 是 =、!=、>、>=、<、<= 之一
+fn parse_filter(op: &Token, arg: &Token) -> Result<Filter, KvError> {
+    let compare = |op| {
+        let n = arg
+            .text()
+            .parse()
+            .map_err(|_| KvError::InvalidCommand(format!("invalid number: {}", arg.text())))?;
+        Ok(Filter::compare(op, n))
+    };
+    match op.text().to_ascii_lowercase().as_str() {
+        "type" => Ok(Filter::value_type(arg.text())),
+        "prefix" => Ok(Filter::prefix(arg.text())),
+        "contains" => Ok(Filter::contains(arg.text())),
+        "=" | "==" => compare(CompareOp::Eq),
+        "!=" => compare(CompareOp::Ne),
+        ">" => compare(CompareOp::Gt),
+        ">=" => compare(CompareOp::Ge),
+        "<" => compare(CompareOp::Lt),
+        "<=" => compare(CompareOp::Le),
+        other => Err(KvError::InvalidCommand(format!(
+            "invalid filter: {}",
+            other
+        ))),
+    }
+}
+
+/// 把 Value 格式化成便于阅读的字符串
+pub fn format_value(v: &Value) -> String {
+    match &v.value {
+        Some(value::Value::String(s)) => format!("{:?}", s),
+        Some(value::Value::Binary(b)) => format!("(binary) {:?}", b),
+        Some(value::Value::Integer(i)) => format!("(integer) {}", i),
+        Some(value::Value::Unsigned(u)) => format!("(integer) {}", u),
+        Some(value::Value::Float(f)) => format!("(float) {}", f),
+        Some(value::Value::Bool(b)) => format!("(bool) {}", b),
+        None => "(nil)".into(),
+    }
+}
+
+/// 把 HWATCH 收到的变更格式化成一行，比如 `#3 set t1/k1: "v1" -> "v2"`
+pub fn format_change(event: &ChangeEvent) -> String {
+    let value = |v: &Option<Value>| v.as_ref().map_or("(nil)".into(), format_value);
+    let op = match event.op() {
+        ChangeOp::Set => "set",
+        ChangeOp::Del => "del",
+    };
+    format!(
+        "#{} {} {}/{}: {} -> {}",
+        event.seq,
+        op,
+        event.table,
+        event.key,
+        value(&event.old_value),
+        value(&event.new_value)
+    )
+}
+
+/// 把 CommandResponse 格式化成便于阅读的多行字符串
+pub fn format_response(res: &CommandResponse) -> String {
+    if res.status >= 400 {
+        let err = format!("(error {}) {}", res.status, res.message);
+        // 带上请求 id，方便在服务器的 tracing 里找到这个请求
+        return match res.request_id.as_str() {
+            "" => err,
+            id => format!("{} [request {}]", err, id),
+        };
+    }
+    if res.values.is_empty() && res.pairs.is_empty() {
+        return "OK".into();
+    }
+
+    let values = res
+        .values
+        .iter()
+        .enumerate()
+        .map(|(i, v)| format!("{}) {}", i + 1, format_value(v)));
+    let pairs = res.pairs.iter().map(|pair| {
+        let value = pair.value.as_ref().map(format_value);
+        format!(
+            "{} => {}",
+            pair.key,
+            value.unwrap_or_else(|| "(nil)".into())
+        )
+    });
+
+    values.chain(pairs).collect::<Vec<_>>().join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_command_should_infer_value_types() {
+        let cmd = parse_command("HSET t1 k1 10").unwrap();
+        assert_eq!(cmd, CommandRequest::new_hset("t1", "k1", 10.into()));
+
+        // 超过 i64 的正整数是 u64
+        let cmd = parse_command("hset t1 k1 18446744073709551615").unwrap();
+        assert_eq!(cmd, CommandRequest::new_hset("t1", "k1", u64::MAX.into()));
+
+        let cmd = parse_command("hset t1 k1 \"10\"").unwrap();
+        assert_eq!(cmd, CommandRequest::new_hset("t1", "k1", "10".into()));
+
+        let cmd = parse_command("hset t1 k1 \"hello world\"").unwrap();
+        assert_eq!(
+            cmd,
+            CommandRequest::new_hset("t1", "k1", "hello world".into())
+        );
+
+        let cmd = parse_command("publish lobby 1.5 true hi").unwrap();
+        assert_eq!(
+            cmd,
+            CommandRequest::new_publish("lobby", vec![1.5.into(), true.into(), "hi".into()])
+        );
+    }
+
+    #[test]
+    fn parse_command_should_handle_multiple_pairs() {
+        let cmd = parse_command("HMSET t1 k1 v1 k2 2").unwrap();
+        let pairs = vec![Kvpair::new("k1", "v1".into()), Kvpair::new("k2", 2.into())];
+        assert_eq!(cmd, CommandRequest::new_hmset("t1", pairs));
+
+        let args: Vec<String> = ["HMGET", "t1", "k1", "k2"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let cmd = parse_args(&args).unwrap();
+        let keys = vec!["k1".to_string(), "k2".to_string()];
+        assert_eq!(cmd, CommandRequest::new_hmget("t1", keys));
+    }
+
+    #[test]
+    fn parse_client_list_should_work() {
+        let cmd = parse_command("CLIENT list").unwrap();
+        assert_eq!(cmd, CommandRequest::new_client_list());
+
+        assert!(parse_command("CLIENT").is_err());
+        assert!(parse_command("CLIENT foo").is_err());
+    }
+
+    #[test]
+    fn parse_hgetall_filter_should_work() {
+        let cmd = parse_command("HGETALL users > 10").unwrap();
+        let filter = Filter::compare(CompareOp::Gt, 10.0);
+        assert_eq!(cmd, CommandRequest::new_hgetall_filtered("users", filter));
+
+        let cmd = parse_command("hgetall users prefix \"ab c\"").unwrap();
+        let filter = Filter::prefix("ab c");
+        assert_eq!(cmd, CommandRequest::new_hgetall_filtered("users", filter));
+
+        assert!(parse_command("hgetall users > abc").is_err());
+        assert!(parse_command("hgetall users like abc").is_err());
+    }
+
+    #[test]
+    fn parse_client_kill_should_work() {
+        let cmd = parse_command("CLIENT KILL 127.0.0.1:9527").unwrap();
+        assert_eq!(cmd, CommandRequest::new_client_kill_addr("127.0.0.1:9527"));
+
+        let cmd = parse_command("client kill id 3").unwrap();
+        assert_eq!(cmd, CommandRequest::new_client_kill(3));
+
+        let cmd = parse_command("client kill addr 127.0.0.1:9527").unwrap();
+        assert_eq!(cmd, CommandRequest::new_client_kill_addr("127.0.0.1:9527"));
+
+        assert!(parse_command("CLIENT KILL").is_err());
+        assert!(parse_command("CLIENT KILL id abc").is_err());
+    }
+
+    #[test]
+    fn parse_latency_should_work() {
+        assert_eq!(
+            parse_command("LATENCY").unwrap(),
+            CommandRequest::new_latency()
+        );
+        assert!(parse_command("LATENCY hget").is_err());
+    }
+
+    #[test]
+    fn parse_cluster_slots_should_work() {
+        assert_eq!(
+            parse_command("cluster slots").unwrap(),
+            CommandRequest::new_cluster_slots()
+        );
+        assert!(parse_command("CLUSTER foo").is_err());
+    }
+
+    #[test]
+    fn parse_cluster_should_work() {
+        assert_eq!(
+            parse_command("CLUSTER").unwrap(),
+            CommandRequest::new_cluster()
+        );
+        assert_eq!(
+            parse_command("cluster nodes").unwrap(),
+            CommandRequest::new_cluster()
+        );
+    }
+
+    #[test]
+    fn parse_hwatch_should_work() {
+        assert_eq!(
+            parse_command("HWATCH").unwrap(),
+            CommandRequest::new_hwatch("")
+        );
+        assert_eq!(
+            parse_command("hwatch t1").unwrap(),
+            CommandRequest::new_hwatch("t1")
+        );
+        assert_eq!(
+            parse_command("hwatch t1 SNAPSHOT").unwrap(),
+            CommandRequest::new_hwatch_snapshot("t1")
+        );
+        assert!(parse_command("HWATCH t1 t2").is_err());
+    }
+
+    #[test]
+    fn parse_auth_should_work() {
+        assert_eq!(
+            parse_command("AUTH \"my secret\"").unwrap(),
+            CommandRequest::new_auth("my secret")
+        );
+        assert!(parse_command("auth").is_err());
+    }
+
+    #[test]
+    fn parse_jobs_should_work() {
+        assert_eq!(parse_command("JOBS").unwrap(), CommandRequest::new_jobs());
+        assert_eq!(
+            parse_command("SAVE backup.bin").unwrap(),
+            CommandRequest::new_save("backup.bin")
+        );
+        assert_eq!(
+            parse_command("RELOAD").unwrap(),
+            CommandRequest::new_reload()
+        );
+        assert_eq!(
+            parse_command("FLUSHALL FLUSHALL").unwrap(),
+            CommandRequest::new_flushall("FLUSHALL")
+        );
+        assert!(parse_command("flushall").is_err());
+        assert!(parse_command("jobs gossip").is_err());
+    }
+
+    #[test]
+    fn parse_promote_should_work() {
+        assert_eq!(
+            parse_command("PROMOTE").unwrap(),
+            CommandRequest::new_promote(0)
+        );
+        assert_eq!(
+            parse_command("promote 3").unwrap(),
+            CommandRequest::new_promote(3)
+        );
+        assert!(parse_command("PROMOTE abc").is_err());
+        assert!(parse_command("PROMOTE 1 2").is_err());
+    }
+
+    #[test]
+    fn parse_invalid_command_should_fail() {
+        assert!(parse_command("").is_err());
+        assert!(parse_command("HSET t1 k1").is_err());
+        assert!(parse_command("HMSET t1 k1 v1 k2").is_err());
+        assert!(parse_command("FOO t1").is_err());
+        assert!(parse_command("HSET t1 k1 \"unterminated").is_err());
+        assert!(parse_command("UNSUBSCRIBE lobby abc").is_err());
+    }
+
+    #[test]
+    fn format_response_should_work() {
+        let res: CommandResponse = vec![Value::from("v1"), 10.into(), Value::default()].into();
+        assert_eq!(
+            format_response(&res),
+            "1) \"v1\"\n2) (integer) 10\n3) (nil)"
+        );
+
+        let res: CommandResponse = vec![Kvpair::new("k1", true.into())].into();
+        assert_eq!(format_response(&res), "k1 => (bool) true");
+
+        let res: CommandResponse = KvError::NotFound("k1".into()).into();
+        assert_eq!(format_response(&res), "(error 404) Not found: k1");
+        let res = CommandResponse {
+            request_id: "r1".into(),
+            ..res
+        };
+        assert_eq!(
+            format_response(&res),
+            "(error 404) Not found: k1 [request r1]"
+        );
+
+        assert_eq!(format_response(&CommandResponse::ok()), "OK");
+    }
+
+    #[test]
+    fn format_change_should_work() {
+        let event = ChangeEvent {
+            seq: 3,
+            op: ChangeOp::Del as i32,
+            table: "t1".into(),
+            key: "k1".into(),
+            old_value: Some("v1".into()),
+            new_value: None,
+        };
+        assert_eq!(format_change(&event), "#3 del t1/k1: \"v1\" -> (nil)");
+    }
+}
@@ -1,38 +1,26 @@
 use anyhow::Result;
-use kv::{CommandRequest, NoiseClientConnector, ProstClientStream};
-use tokio::net::TcpStream;
+use kv::{ClientConfig, CommandRequest, start_client_with_config};
 use tracing::info;
 
+/// 使用 Noise 协议的 KV 客户端。设置了 KV_CLIENT_CONFIG 时读取该配置文件，
+/// 否则使用内置的 fixtures_noise/client.conf，握手模式和密钥都在 [noise] 里配置
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
-    // 加载客户端静态密钥和服务器公钥（可选）
-    let client_key = include_str!("../fixtures_noise/client.key");
-    let server_pubkey = include_str!("../fixtures_noise/server.pub");
-
-    let client_key_bytes = if client_key.is_empty() {
-        None
-    } else {
-        Some(kv::load_key(client_key)?)
-    };
-
-    let server_pubkey_bytes = if server_pubkey.is_empty() {
-        None
-    } else {
-        Some(kv::load_key(server_pubkey)?)
+    let config = match std::env::var("KV_CLIENT_CONFIG") {
+        Ok(path) => ClientConfig::load(&path)?,
+        Err(_) => {
+            let config: ClientConfig =
+                toml::from_str(include_str!("../fixtures_noise/client.conf"))?;
+            config.validate()?;
+            config
+        }
     };
 
-    let addr = "127.0.0.1:9527";
-
-    // 创建 Noise 连接器
-    let connector = NoiseClientConnector::new(client_key_bytes, server_pubkey_bytes)?;
-
-    // 连接服务器
-    let stream = TcpStream::connect(addr).await?;
-    let stream = connector.connect(stream).await?;
-
-    let mut client = ProstClientStream::new(stream);
+    // 完成 Noise 握手，在连接上打开一个 yamux ctrl
+    let mut ctrl = start_client_with_config(&config).await?;
+    let mut client = ctrl.open_stream().await?;
 
     // 生成一个 HSET 命令
     let cmd = CommandRequest::new_hset("table1", "hello", "world".to_string().into());
@@ -41,5 +29,11 @@ async fn main() -> Result<()> {
     let data = client.execute_unary(cmd).await?;
     info!("Got response {:?}", data);
 
+    // 在同一个连接上再打开一个 stream 发送 HGET 命令
+    let mut client = ctrl.open_stream().await?;
+    let cmd = CommandRequest::new_hget("table1", "hello");
+    let data = client.execute_unary(cmd).await?;
+    info!("Got response {:?}", data);
+
     Ok(())
 }
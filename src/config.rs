@@ -1,38 +1,206 @@
-use crate::KvError;
+use crate::network::MAX_NUM_STREAMS;
+use crate::{CommandRequest, FrameLimits, KvError, NoisePattern, load_key};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// builder 没有设置地址时使用的默认地址
+const DEFAULT_ADDR: &str = "127.0.0.1:9527";
+
+/// 覆盖配置的环境变量前缀
+const ENV_PREFIX: &str = "KV_";
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ServerConfig {
     pub general: GeneralConfig,
     pub storage: StorageConfig,
-    pub tls: ServerTlsConfig,
+    pub tls: Option<ServerTlsConfig>,
+    #[serde(default)]
+    pub noise: Option<ServerNoiseConfig>,
     pub log: LogConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    #[serde(default)]
+    pub replication: ReplicationConfig,
+    #[serde(default)]
+    pub raft: RaftConfig,
+    #[serde(default)]
+    pub sharding: ShardingConfig,
+    #[serde(default)]
+    pub membership: MembershipConfig,
+    #[serde(default)]
+    pub multi_master: MultiMasterConfig,
+    #[serde(default)]
+    pub mirror: MirrorConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub overload: OverloadConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub scheduler: SchedulerConfig,
+    #[serde(default)]
+    pub commands: CommandsConfig,
+    #[serde(default)]
+    pub shadow: ShadowConfig,
+    #[serde(default)]
+    pub idempotency: IdempotencyConfig,
+    #[serde(default)]
+    pub pubsub: PubSubConfig,
+    #[serde(default)]
+    pub memcached: MemcachedConfig,
+    #[serde(default)]
+    pub snapshot: SnapshotConfig,
+    #[serde(default)]
+    pub read_cache: ReadCacheConfig,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ClientConfig {
     pub general: GeneralConfig,
-    pub tls: ClientTlsConfig,
+    pub tls: Option<ClientTlsConfig>,
+    #[serde(default)]
+    pub noise: Option<ClientNoiseConfig>,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub cluster: ClusterConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub commands: CommandsConfig,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct GeneralConfig {
     pub addr: String,
+    #[serde(default)]
+    pub security: Security,
+    #[serde(default)]
+    pub socket: SocketConfig,
+}
+
+/// 传输层的安全模式
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    /// 使用 TLS，需要配置 [tls]
+    #[default]
+    Tls,
+    /// 使用 Noise 协议，需要配置 [noise]
+    Noise,
+    /// 明文 TCP，仅用于本地开发和测试
+    None,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct SocketConfig {
+    /// 是否设置 TCP_NODELAY（关闭 Nagle 算法）
+    pub nodelay: bool,
+    /// SO_KEEPALIVE 的空闲时间（秒），None 表示不开启
+    pub keepalive_secs: Option<u64>,
+    /// SO_SNDBUF 大小，None 表示使用系统默认值
+    pub send_buffer_size: Option<usize>,
+    /// SO_RCVBUF 大小，None 表示使用系统默认值
+    pub recv_buffer_size: Option<usize>,
+    /// 监听的 socket 是否设置 SO_REUSEPORT，让新旧两个进程可以同时监听，用于滚动升级
+    pub reuse_port: bool,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive_secs: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            reuse_port: false,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(tag = "type", content = "args")]
 pub enum StorageConfig {
     MemTable,
+    /// key 有序的 MemTable，HGETALL 按 key 的顺序返回，支持高效的范围和前缀查询
+    MemTableOrdered,
     SledDb(String),
+    /// MemTable 作为 sled 的缓存：写入同时写两层，读取先读 MemTable，没有时从 sled 读出来放进去。
+    /// MemTable 最多保留 read_cache.fast_capacity 个 key
+    CachedSledDb(String),
+}
+
+impl StorageConfig {
+    /// sled 数据目录的路径，内存存储返回 None
+    pub fn sled_path(&self) -> Option<&str> {
+        match self {
+            StorageConfig::SledDb(path) | StorageConfig::CachedSledDb(path) => Some(path),
+            StorageConfig::MemTable | StorageConfig::MemTableOrdered => None,
+        }
+    }
+}
+
+/// 命令行里的存储：`memory`、`memory-ordered`、`sled:<path>` 或者 `cached-sled:<path>`
+impl FromStr for StorageConfig {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s.eq_ignore_ascii_case("memory") => Ok(StorageConfig::MemTable),
+            None if s.eq_ignore_ascii_case("memory-ordered") => Ok(StorageConfig::MemTableOrdered),
+            Some(("sled", path)) if !path.is_empty() => Ok(StorageConfig::SledDb(path.into())),
+            Some(("cached-sled", path)) if !path.is_empty() => {
+                Ok(StorageConfig::CachedSledDb(path.into()))
+            }
+            _ => Err(KvError::InvalidConfig(format!(
+                "invalid storage: {} (expected memory, memory-ordered, sled:<path> or cached-sled:<path>)",
+                s
+            ))),
+        }
+    }
 }
 
+/// 证书和私钥既可以直接以 PEM 内容写在配置里，也可以通过 *_path 指定文件，
+/// 同时设置时文件优先
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ServerTlsConfig {
+    #[serde(default)]
     pub cert: String,
+    #[serde(default)]
     pub key: String,
     pub ca: Option<String>,
+    #[serde(default)]
+    pub cert_path: Option<String>,
+    #[serde(default)]
+    pub key_path: Option<String>,
+    #[serde(default)]
+    pub ca_path: Option<String>,
+    #[serde(default)]
+    pub session: ServerSessionConfig,
+    /// 证书吊销列表（CRL），mTLS 时拒绝已经被吊销的客户端证书。
+    /// CRL 和 CA 一样被信任，不检查签名；重新加载配置时会重新读取
+    #[serde(default)]
+    pub crl: Option<String>,
+    #[serde(default)]
+    pub crl_path: Option<String>,
+    /// 允许的最低 TLS 版本
+    #[serde(default)]
+    pub min_version: TlsVersion,
+    /// 允许的 cipher suite，名字和 IANA 的一样，比如 TLS13_AES_256_GCM_SHA384。
+    /// 为空时使用 rustls 默认的 cipher suite
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -40,6 +208,943 @@ pub struct ClientTlsConfig {
     pub domain: String,
     pub identity: Option<(String, String)>,
     pub ca: Option<String>,
+    /// 客户端证书和私钥的文件路径
+    #[serde(default)]
+    pub identity_path: Option<(String, String)>,
+    #[serde(default)]
+    pub ca_path: Option<String>,
+    #[serde(default)]
+    pub session: ClientSessionConfig,
+    /// 允许的最低 TLS 版本
+    #[serde(default)]
+    pub min_version: TlsVersion,
+    /// 允许的 cipher suite，为空时使用 rustls 默认的 cipher suite
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+}
+
+/// TLS 协议版本，rustls 只支持 1.2 和 1.3
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsVersion::Tls12 => f.write_str("1.2"),
+            TlsVersion::Tls13 => f.write_str("1.3"),
+        }
+    }
+}
+
+/// 从配置中读出的 PEM 内容
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerPem {
+    pub cert: String,
+    pub key: String,
+    pub ca: Option<String>,
+    pub crl: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientPem {
+    pub identity: Option<(String, String)>,
+    pub ca: Option<String>,
+}
+
+impl ServerTlsConfig {
+    /// 读取证书、私钥和 CA，每次调用都会重新读文件
+    pub fn load_pem(&self) -> Result<ServerPem, KvError> {
+        let cert = read_pem(Some(&self.cert), self.cert_path.as_deref())?;
+        let key = read_pem(Some(&self.key), self.key_path.as_deref())?;
+        let (Some(cert), Some(key)) = (cert, key) else {
+            return Err(KvError::InvalidConfig(
+                "tls.cert/tls.cert_path and tls.key/tls.key_path are required".into(),
+            ));
+        };
+        let ca = read_pem(self.ca.as_deref(), self.ca_path.as_deref())?;
+        let crl = read_pem(self.crl.as_deref(), self.crl_path.as_deref())?;
+        Ok(ServerPem { cert, key, ca, crl })
+    }
+}
+
+impl ClientTlsConfig {
+    /// 读取客户端证书、私钥和 CA，每次调用都会重新读文件
+    pub fn load_pem(&self) -> Result<ClientPem, KvError> {
+        let identity = match (&self.identity_path, &self.identity) {
+            (Some((cert, key)), _) => Some((fs::read_to_string(cert)?, fs::read_to_string(key)?)),
+            (None, identity) => identity.clone(),
+        };
+        let ca = read_pem(self.ca.as_deref(), self.ca_path.as_deref())?;
+        Ok(ClientPem { identity, ca })
+    }
+}
+
+/// Noise 协议的服务器配置，security = "noise" 时使用。
+/// 密钥是 base64 编码的 32 字节 X25519 密钥（`kvs gen-keys` 生成），
+/// 既可以直接写在配置里，也可以通过 *_path 指定文件，同时设置时文件优先
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ServerNoiseConfig {
+    /// 服务器的静态私钥
+    pub key: String,
+    pub key_path: Option<String>,
+    /// 预共享密钥，格式和 key 一样，设置后只接受使用同一个 PSK 的客户端，为空时不使用
+    pub psk: String,
+    pub psk_path: Option<String>,
+    /// 握手模式，客户端必须使用同一个模式
+    pub pattern: NoisePattern,
+}
+
+/// Noise 协议的客户端配置，security = "noise" 时使用，密钥的格式和 ServerNoiseConfig 一样
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ClientNoiseConfig {
+    /// 客户端的静态私钥，NK 模式不需要
+    pub key: String,
+    pub key_path: Option<String>,
+    /// 服务器的静态公钥，IK 和 NK 模式需要
+    pub server_pub: String,
+    pub server_pub_path: Option<String>,
+    /// 预共享密钥，服务器设置了 PSK 时必须设置同一个
+    pub psk: String,
+    pub psk_path: Option<String>,
+    /// 握手模式，IK 和 NK 在 1 个 RTT 内完成握手
+    pub pattern: NoisePattern,
+}
+
+impl ServerNoiseConfig {
+    /// 读取服务器的静态私钥，每次调用都会重新读文件
+    pub fn load_key(&self) -> Result<Vec<u8>, KvError> {
+        read_noise_key(&self.key, self.key_path.as_deref())?
+            .ok_or_else(|| KvError::InvalidConfig("noise.key/noise.key_path is required".into()))
+    }
+
+    /// 读取预共享密钥，没有设置时返回 None
+    pub fn load_psk(&self) -> Result<Option<Vec<u8>>, KvError> {
+        read_noise_key(&self.psk, self.psk_path.as_deref())
+    }
+}
+
+/// 从配置中读出的 Noise 密钥，没有设置的为 None
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientNoiseKeys {
+    pub key: Option<Vec<u8>>,
+    pub server_pub: Option<Vec<u8>>,
+    pub psk: Option<Vec<u8>>,
+}
+
+impl ClientNoiseConfig {
+    /// 读取客户端的静态私钥、服务器的公钥和预共享密钥，每次调用都会重新读文件
+    pub fn load_keys(&self) -> Result<ClientNoiseKeys, KvError> {
+        Ok(ClientNoiseKeys {
+            key: read_noise_key(&self.key, self.key_path.as_deref())?,
+            server_pub: read_noise_key(&self.server_pub, self.server_pub_path.as_deref())?,
+            psk: read_noise_key(&self.psk, self.psk_path.as_deref())?,
+        })
+    }
+}
+
+fn read_noise_key(inline: &str, path: Option<&str>) -> Result<Option<Vec<u8>>, KvError> {
+    read_pem(Some(inline), path)?
+        .map(|key| load_key(&key))
+        .transpose()
+}
+
+/// 设置了文件路径就读文件，否则使用内嵌的内容，空字符串视为没有设置
+fn read_pem(inline: Option<&str>, path: Option<&str>) -> Result<Option<String>, KvError> {
+    match (path, inline) {
+        (Some(path), _) => Ok(Some(fs::read_to_string(path)?)),
+        (None, Some(pem)) if !pem.is_empty() => Ok(Some(pem.to_string())),
+        _ => Ok(None),
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ServerSessionConfig {
+    /// 服务器端缓存的 TLS session 数量，0 表示不缓存
+    pub cache_size: usize,
+    /// 是否签发 session ticket
+    pub tickets: bool,
+}
+
+impl Default for ServerSessionConfig {
+    fn default() -> Self {
+        Self {
+            cache_size: 256,
+            tickets: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ClientSessionConfig {
+    /// 客户端缓存的 TLS session 数量
+    pub cache_size: usize,
+    /// 把 session 持久化到文件，这样短生命周期的进程重连时也能跳过完整握手
+    pub cache_path: Option<String>,
+}
+
+impl Default for ClientSessionConfig {
+    fn default() -> Self {
+        Self {
+            cache_size: 32,
+            cache_path: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// 每个连接同时处理的 stream 数量上限，超过上限的 stream 收到 503（Busy），客户端稍后重试
+    pub max_concurrent_streams: usize,
+    /// 优雅关闭时等待处理中的请求结束的最长时间，超时后直接断开所有连接
+    pub shutdown_timeout_ms: u64,
+    /// 严格检查客户端发来的 frame，服务器暴露在不可信网络上时打开
+    pub strict_frames: bool,
+    /// strict_frames 时单个 frame 的最大字节数（压缩后）
+    pub max_frame_bytes: usize,
+    /// strict_frames 时 frame 解压缩之后的最大字节数
+    pub max_decompressed_bytes: usize,
+    /// 单个响应 frame 的最大字节数（压缩前），更大的响应拆成多个 frame，由客户端拼起来
+    pub max_response_frame_bytes: usize,
+    /// 连续收到多少个无法 decode 的 frame 之后断开 stream。在这之前每个坏的 frame 回复 400，
+    /// 跳过之后接着读下一个 frame。0 表示收到第一个坏的 frame 就断开
+    pub max_malformed_frames: usize,
+    /// 单个 HGETALL/HMGET 响应最多包含的 pair 或者 value 数，超过时返回错误，None 表示不限制。
+    /// 经过 Raft 的读和 HWATCH 的快照同样受限
+    pub max_result_entries: Option<usize>,
+    /// 单个命令最多包含的 pair、key 或者 value 数（HMSET/HMGET/HMDEL/HMEXIST/PUBLISH），
+    /// 超过时返回 413，None 表示不限制。frame 的大小用 max_frame_bytes 限制
+    pub max_request_entries: Option<usize>,
+    /// 写入的 key 的最大字节数，超过时拒绝写入，None 表示不限制
+    pub max_key_bytes: Option<usize>,
+    /// 写入的 value encode 之后的最大字节数，超过时拒绝写入，None 表示不限制
+    pub max_value_bytes: Option<usize>,
+    /// 等待客户端下一个命令的最长时间（毫秒），超时后关闭 stream，None 表示不限制
+    pub read_timeout_ms: Option<u64>,
+    /// 发送一个响应的最长时间（毫秒），客户端不读取数据时超时关闭 stream，None 表示不限制
+    pub write_timeout_ms: Option<u64>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_streams: 128,
+            shutdown_timeout_ms: 10_000,
+            strict_frames: false,
+            max_frame_bytes: 16 * 1024 * 1024,
+            max_decompressed_bytes: 64 * 1024 * 1024,
+            max_response_frame_bytes: 16 * 1024 * 1024,
+            max_malformed_frames: 0,
+            max_result_entries: None,
+            max_request_entries: None,
+            max_key_bytes: None,
+            max_value_bytes: None,
+            read_timeout_ms: None,
+            write_timeout_ms: None,
+        }
+    }
+}
+
+impl LimitsConfig {
+    /// 服务器读取客户端 frame 时的限制
+    pub fn frame_limits(&self) -> FrameLimits {
+        match self.strict_frames {
+            true => FrameLimits::strict(self.max_frame_bytes, self.max_decompressed_bytes),
+            false => FrameLimits::default(),
+        }
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout_ms.map(Duration::from_millis)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// 单次请求的超时时间（毫秒）
+    pub timeout_ms: u64,
+    /// 最多尝试的次数（包括第一次）
+    pub max_attempts: u32,
+    /// 第一次重试前的等待时间（毫秒），之后每次翻倍
+    pub backoff_ms: u64,
+    /// 重试等待时间的上限（毫秒）
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 5000,
+            max_attempts: 3,
+            backoff_ms: 100,
+            max_backoff_ms: 2000,
+        }
+    }
+}
+
+/// OpenTelemetry 链路追踪的配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// 是否把 trace 导出到 OTLP collector
+    pub enabled: bool,
+    /// OTLP collector 的 gRPC 地址
+    pub endpoint: String,
+    /// 采样比例，1.0 表示全部采样，0 表示全不采样
+    pub sample_ratio: f64,
+    /// 上报的 service.name
+    pub service_name: String,
+    /// 批量导出时队列里最多缓存的 span 数量
+    pub max_queue_size: usize,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            endpoint: "http://localhost:4317".into(),
+            sample_ratio: 1.0,
+            service_name: "kv_server".into(),
+            max_queue_size: 9999999,
+        }
+    }
+}
+
+/// 客户端本地读缓存，capacity 为 0 时不启用
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// 最多缓存的 key 数量
+    pub capacity: usize,
+}
+
+/// 客户端连接多个服务器时的配置，用于 KvCluster
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ClusterConfig {
+    /// 所有服务器的地址，为空时只使用 general.addr
+    pub addrs: Vec<String>,
+    /// 请求路由到哪个服务器
+    pub routing: Routing,
+    /// 对不可用的服务器做健康检查的间隔（毫秒）
+    pub health_check_interval_ms: u64,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            addrs: Vec::new(),
+            routing: Routing::default(),
+            health_check_interval_ms: 1000,
+        }
+    }
+}
+
+/// 主从复制的配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ReplicationConfig {
+    pub role: Role,
+    /// replica 连接 primary 使用的客户端配置，primary.general.addr 是 primary 的地址
+    pub primary: Option<ClientConfig>,
+    /// primary 上复制日志缓冲的条数，replica 落后超过这个数量会被断开重连
+    pub log_capacity: usize,
+    /// replica 和 primary 断开后重连的间隔（毫秒）
+    pub reconnect_interval_ms: u64,
+    pub failover: FailoverConfig,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            role: Role::default(),
+            primary: None,
+            log_capacity: 1024,
+            reconnect_interval_ms: 1000,
+            failover: FailoverConfig::default(),
+        }
+    }
+}
+
+/// 自动故障转移的配置，primary 和 replica 上都要开启
+///
+/// replica 每隔 lease_ms / 3 向 primary 确认一次，primary 超过 lease_ms 没有收到确认就拒绝写操作；
+/// replica 超过 timeout_ms 联系不上 primary 时把自己提升为 primary。
+/// timeout_ms 大于 lease_ms，保证 replica 提升时原来的 primary 已经不再接受写操作
+///
+/// 只适用于一个 primary 加一个 replica 的部署；需要更多节点时使用 Raft 模式，它自带 leader 选举
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct FailoverConfig {
+    pub enabled: bool,
+    /// replica 联系不上 primary 多久之后提升为 primary（毫秒）
+    pub timeout_ms: u64,
+    /// primary 的写租约（毫秒）
+    pub lease_ms: u64,
+}
+
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout_ms: 5000,
+            lease_ms: 3000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum Role {
+    /// 不参与复制
+    #[default]
+    Standalone,
+    /// 把所有写操作通过复制日志发送给 replica
+    Primary,
+    /// 从 primary 接收写操作，只处理读请求
+    Replica,
+}
+
+/// Raft 强一致模式的配置
+///
+/// term、投票和日志保存在本节点 storage 的 __raft__ table 里，storage 是 MemTable 时重启后从空开始。
+/// __ 开头的 table 是内部 table，客户端不能读写
+/// raft_vote 和 raft_append 只接受 peers 里的地址（按 IP）发来的请求
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RaftConfig {
+    pub enabled: bool,
+    /// 本节点的 id，是本节点地址在 peers 里的位置，从 1 开始
+    pub id: u64,
+    /// 所有节点（包括本节点）的地址
+    pub peers: Vec<String>,
+    /// 选举超时（毫秒），实际超时在 [election_timeout_ms, 2 * election_timeout_ms) 之间随机
+    pub election_timeout_ms: u64,
+    /// leader 发送心跳的间隔（毫秒）
+    pub heartbeat_interval_ms: u64,
+    /// 连接其它节点使用的客户端配置，addr 会被替换成节点地址，为空时使用明文 TCP
+    pub client: Option<ClientConfig>,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            id: 1,
+            peers: Vec::new(),
+            election_timeout_ms: 300,
+            heartbeat_interval_ms: 50,
+            client: None,
+        }
+    }
+}
+
+/// 分片集群的配置，key 按 table 做一致性 hash 分布到各个节点
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ShardingConfig {
+    pub enabled: bool,
+    /// 本节点的 id，是本节点地址在 nodes 里的位置，从 1 开始
+    pub id: u64,
+    /// 所有节点（包括本节点）的地址，所有节点上的配置必须一致
+    pub nodes: Vec<String>,
+    /// 每个节点在 hash 环上的虚拟节点数量
+    pub virtual_nodes: usize,
+    /// 收到不属于本节点的 key 时的处理方式
+    pub mode: ShardMode,
+    /// 代理请求的超时（毫秒）
+    pub proxy_timeout_ms: u64,
+    /// 代理请求时连接其它节点使用的客户端配置，addr 会被替换成节点地址，为空时使用明文 TCP
+    pub client: Option<ClientConfig>,
+}
+
+impl Default for ShardingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            id: 1,
+            nodes: Vec::new(),
+            virtual_nodes: 64,
+            mode: ShardMode::default(),
+            proxy_timeout_ms: 1000,
+            client: None,
+        }
+    }
+}
+
+/// 集群成员管理的配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct MembershipConfig {
+    pub enabled: bool,
+    /// 其它节点通过这个地址访问本节点，为空时使用 general.addr
+    pub advertise_addr: Option<String>,
+    /// 启动时已知的成员，静态模式下就是全部成员
+    pub seeds: Vec<String>,
+    /// 为 true 时和其它节点交换成员列表，自动发现新成员；为 false 时只检查 seeds 的健康状态
+    pub gossip: bool,
+    /// 发送 gossip 的间隔（毫秒）
+    pub gossip_interval_ms: u64,
+    /// 超过这个时间（毫秒）没有收到成员的心跳，认为成员可能有问题
+    pub suspect_timeout_ms: u64,
+    /// 超过这个时间（毫秒）没有收到成员的心跳，认为成员已经下线
+    pub down_timeout_ms: u64,
+    /// 连接其它节点使用的客户端配置，addr 会被替换成节点地址，为空时使用明文 TCP
+    pub client: Option<ClientConfig>,
+}
+
+impl Default for MembershipConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            advertise_addr: None,
+            seeds: Vec::new(),
+            gossip: true,
+            gossip_interval_ms: 1000,
+            suspect_timeout_ms: 3000,
+            down_timeout_ms: 10000,
+            client: None,
+        }
+    }
+}
+
+/// 多主模式的配置，每个节点都接受写操作，节点之间定期同步，最终一致
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct MultiMasterConfig {
+    pub enabled: bool,
+    /// 节点在向量时钟里的名字，所有节点之间不能重复，为空时使用 general.addr
+    pub node_id: Option<String>,
+    /// 其它节点的地址，只接受从这些地址（按 IP）发来的同步
+    pub peers: Vec<String>,
+    /// 和其它节点做反熵同步的间隔（毫秒）
+    pub sync_interval_ms: u64,
+    /// 连接其它节点使用的客户端配置，addr 会被替换成节点地址，为空时使用明文 TCP
+    pub client: Option<ClientConfig>,
+}
+
+impl Default for MultiMasterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            node_id: None,
+            peers: Vec::new(),
+            sync_interval_ms: 1000,
+            client: None,
+        }
+    }
+}
+
+/// 把本地的变更同步到另一个 KV 服务器的配置，用于容灾和迁移
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct MirrorConfig {
+    pub enabled: bool,
+    /// 远端服务器的客户端配置，重试策略也按这个配置
+    pub remote: Option<ClientConfig>,
+    /// 只同步这些 table，为空时同步所有 table
+    pub tables: Vec<String>,
+    /// 不同步这些 table
+    pub exclude_tables: Vec<String>,
+    /// 远端的数据和本地不一致时怎么处理
+    pub conflict: MirrorConflict,
+    /// 开始同步时先把已有的数据复制到远端；为 false 时只同步之后的变更，断开期间的变更会丢失
+    pub initial_sync: bool,
+    /// 出错之后重新开始同步的间隔（毫秒）
+    pub reconnect_interval_ms: u64,
+}
+
+impl Default for MirrorConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            remote: None,
+            tables: Vec::new(),
+            exclude_tables: Vec::new(),
+            conflict: MirrorConflict::default(),
+            initial_sync: true,
+            reconnect_interval_ms: 1000,
+        }
+    }
+}
+
+impl MirrorConfig {
+    /// table 是否需要同步
+    pub fn selects(&self, table: &str) -> bool {
+        (self.tables.is_empty() || self.tables.iter().any(|t| t == table))
+            && !self.exclude_tables.iter().any(|t| t == table)
+    }
+}
+
+/// 影子流量：把收到的一部分数据命令异步地再发给另一个 KV 服务器，丢弃它的响应。
+/// 用于切换之前用真实流量验证新的存储后端或者新版本，不影响正常请求的延迟
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ShadowConfig {
+    pub enabled: bool,
+    /// 影子服务器的客户端配置
+    pub remote: Option<ClientConfig>,
+    /// 转发的比例，1.0 表示全部转发
+    pub sample_ratio: f64,
+    /// 等待转发的命令的队列长度，影子服务器跟不上时丢弃新的命令
+    pub queue_capacity: usize,
+}
+
+impl Default for ShadowConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            remote: None,
+            sample_ratio: 1.0,
+            queue_capacity: 1024,
+        }
+    }
+}
+
+/// 记住带幂等 key 的写命令的响应，客户端重试时不会重复执行
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct IdempotencyConfig {
+    pub enabled: bool,
+    /// 响应保存的时间（毫秒），要比客户端重试的总时间长
+    pub ttl_ms: u64,
+    /// 最多保存的响应数
+    pub capacity: usize,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl_ms: 60_000,
+            capacity: 100_000,
+        }
+    }
+}
+
+/// 发布订阅
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PubSubConfig {
+    /// 没有 subscriber 的主题空闲多久（毫秒）之后被回收，为 0 时不回收。
+    /// 取消订阅时主题会立即删除，这里回收的是 subscriber 断开却没有取消订阅的主题
+    pub topic_idle_ms: u64,
+    /// 每个主题的订阅者数量上限，超过时拒绝新的 SUBSCRIBE，None 表示不限制
+    pub max_subscribers_per_topic: Option<usize>,
+    /// 整个服务器的订阅者数量上限，避免有问题的客户端开大量订阅把内存耗尽，None 表示不限制
+    pub max_subscribers: Option<usize>,
+}
+
+impl Default for PubSubConfig {
+    fn default() -> Self {
+        Self {
+            topic_idle_ms: 300_000,
+            max_subscribers_per_topic: None,
+            max_subscribers: None,
+        }
+    }
+}
+
+/// memcached 文本协议的监听端口，老的 memcached 客户端不用修改代码就能连到这个服务器。
+/// get/set/delete/incr/decr 读写同一个 table。memcached 协议没有认证，连接也没有身份，
+/// 所以不能和 auth 里的密码、角色、租户一起使用，配置了这些时不能开启
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct MemcachedConfig {
+    pub enabled: bool,
+    pub addr: String,
+    /// 所有的 key 都存在这个 table 里
+    pub table: String,
+    /// 只接受本机（loopback 地址）的连接
+    pub local_only: bool,
+}
+
+impl Default for MemcachedConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            addr: "127.0.0.1:11211".into(),
+            table: "memcached".into(),
+            local_only: true,
+        }
+    }
+}
+
+/// MemTable 的定期快照：每隔 interval_ms 把所有数据写到 path，启动时从 path 恢复。
+/// 介于纯内存和 sled 之间，进程崩溃时最多丢失一个间隔内的写入；正常关闭时会再写一次快照
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct SnapshotConfig {
+    pub enabled: bool,
+    pub path: String,
+    pub interval_ms: u64,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "/tmp/kv/snapshot.bin".into(),
+            interval_ms: 60_000,
+        }
+    }
+}
+
+/// sled 前面的读缓存：HGET 读到的值在内存里保留 ttl_ms，写入时让对应的 key 失效。
+/// 写入仍然直接落到 sled，持久性不变；绕过服务器修改数据目录时最多读到 ttl_ms 之前的值
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ReadCacheConfig {
+    /// 最多缓存的 key 数，超出时淘汰最久没用的，0 表示不开启
+    pub capacity: usize,
+    pub ttl_ms: u64,
+    /// cached-sled 存储里 MemTable 最多保留的 key 数，超出时淘汰最久没用的
+    pub fast_capacity: usize,
+}
+
+impl Default for ReadCacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 0,
+            ttl_ms: 1000,
+            fast_capacity: 100_000,
+        }
+    }
+}
+
+/// 单独的管理端口。CLIENT LIST、CLIENT KILL、LATENCY、PROMOTE、FLUSHALL 这些运维命令
+/// 只能在管理端口上执行，数据端口上只有 auth.roles 里 admin 角色的身份可以执行；
+/// 没有开启管理端口也没有设置角色时，客户端不能执行运维命令
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub enabled: bool,
+    /// 管理端口的监听地址，安全模式（TLS）和数据端口一样
+    pub addr: String,
+    /// 只接受本机（loopback 地址）的连接
+    pub local_only: bool,
+    /// SAVE 和 RESTORE 读写的目录，客户端只能指定其中的文件名。为空时不能执行这两个命令
+    pub backup_dir: String,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            addr: "127.0.0.1:9528".into(),
+            local_only: true,
+            backup_dir: "/tmp/kv/backup".into(),
+        }
+    }
+}
+
+/// 类似 Redis 的 requirepass。服务器设置了 password 时，连接要先用 AUTH 认证才能执行其它命令；
+/// 客户端设置了 password 时，每次建立连接后自动发送 AUTH。
+/// 集群节点之间的连接使用各自的 client 配置，需要在那里设置同样的 password
+///
+/// roles 按认证后的身份（mTLS 客户端证书的指纹，和 CLIENT LIST 里的 identity 一样）分配角色，
+/// 限制能执行的命令，只对服务器有效。指纹是 `cert:` 加上证书 DER 编码的 SHA-256（小写十六进制），
+/// 可以用 `openssl x509 -in client.cert -outform der | sha256sum` 算出来
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub password: Option<String>,
+    /// 身份 -> 角色
+    pub roles: BTreeMap<String, AccessRole>,
+    /// 不在 roles 里的连接（包括没有客户端证书的连接）的角色。
+    /// 为 None 时，设置了 roles 就拒绝这些连接的命令，没有设置 roles 时不限制
+    pub default_role: Option<AccessRole>,
+    /// 身份 -> 租户。租户的 table 名和主题名自动加上 namespace 前缀，看不到其它租户的数据
+    pub tenants: BTreeMap<String, TenantConfig>,
+}
+
+impl AuthConfig {
+    /// 是否设置了密码、角色或租户，设置了之后每个连接都要有认证后的身份
+    pub fn is_configured(&self) -> bool {
+        self.password.is_some()
+            || !self.roles.is_empty()
+            || self.default_role.is_some()
+            || !self.tenants.is_empty()
+    }
+}
+
+/// 一个租户绑定的 namespace 和配额。多个身份可以使用同一个 namespace
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TenantConfig {
+    /// table 名和主题名的前缀，不能为空，也不能包含 ':'
+    pub namespace: String,
+    /// namespace 里最多可以有多少个 table（按本节点计算），None 表示不限制
+    pub max_tables: Option<usize>,
+    /// namespace 里最多同时有多少个订阅，None 表示不限制
+    pub max_subscribers: Option<usize>,
+}
+
+/// 访问控制的角色。所有角色都可以执行 AUTH、CLUSTER 和 CLUSTER SLOTS
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessRole {
+    /// 可以执行所有命令，包括运维命令和集群内部的命令
+    Admin,
+    /// 数据命令、HWATCH 和 pub/sub 命令
+    ReadWrite,
+    /// 只读的数据命令、HWATCH、SUBSCRIBE 和 UNSUBSCRIBE
+    ReadOnly,
+    /// 只能执行 pub/sub 命令
+    #[serde(rename = "pubsub-only")]
+    PubSubOnly,
+}
+
+impl fmt::Display for AccessRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            AccessRole::Admin => "admin",
+            AccessRole::ReadWrite => "read-write",
+            AccessRole::ReadOnly => "read-only",
+            AccessRole::PubSubOnly => "pubsub-only",
+        };
+        f.write_str(s)
+    }
+}
+
+/// 过载保护。处理中的请求或者事件循环延迟超过上限时，拒绝数据命令和 pub/sub 命令，
+/// 返回 503 让客户端稍后重试，避免所有请求的延迟一起变得不可接受
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct OverloadConfig {
+    pub enabled: bool,
+    /// 所有连接上同时处理中的请求数上限
+    pub max_in_flight: usize,
+    /// 事件循环延迟的上限（毫秒）
+    pub max_lag_ms: u64,
+    /// 采样事件循环延迟的间隔（毫秒）
+    pub sample_interval_ms: u64,
+}
+
+impl Default for OverloadConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_in_flight: 4096,
+            max_lag_ms: 100,
+            sample_interval_ms: 100,
+        }
+    }
+}
+
+/// 后台任务调度器，gossip、反熵同步等定期执行的维护工作都由它调度
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct SchedulerConfig {
+    /// 每次执行之间的间隔在默认间隔上下随机浮动的百分比，避免集群里的节点同时执行
+    pub jitter_pct: u32,
+    /// job 名字 -> 这个 job 的配置，没有配置的 job 使用默认值
+    pub jobs: BTreeMap<String, JobConfig>,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            jitter_pct: 10,
+            jobs: BTreeMap::new(),
+        }
+    }
+}
+
+/// 一个 job 的配置，可以在 JOBS 命令的输出里看到 job 的名字
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct JobConfig {
+    pub enabled: bool,
+    /// 执行的间隔（毫秒），不设置时使用对应功能的配置，比如 membership.gossip_interval_ms
+    pub interval_ms: Option<u64>,
+}
+
+impl Default for JobConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_ms: None,
+        }
+    }
+}
+
+/// 整个关闭某些命令，比如生产环境里禁止 hgetall。命令的名字和 LATENCY 的输出里一样，不区分大小写。
+/// 被关闭的命令在执行之前就被拒绝，对所有连接都生效，包括管理端口
+///
+/// rename 类似 Redis 的 rename-command，把危险的命令藏在另一个名字后面：服务器只执行带着新名字的请求，
+/// 客户端配置了同样的 rename 时自动带上新名字。allow 和 deny 只对服务器有效
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CommandsConfig {
+    /// 只允许执行这些命令，为空时不限制。AUTH 总是可以执行；
+    /// 集群内部的命令（gossip、raft_append 等）也要列出来，否则节点之间无法通信
+    pub allow: Vec<String>,
+    /// 不允许执行这些命令，优先于 allow
+    pub deny: Vec<String>,
+    /// 命令的名字 -> 新名字，新名字为空时任何请求都不能执行这个命令。
+    /// 集群节点之间的连接使用各自的 client 配置，改了集群内部命令的名字时需要在那里设置同样的 rename
+    pub rename: BTreeMap<String, String>,
+}
+
+impl CommandsConfig {
+    /// 命令配置了新名字时带上新名字，客户端发送命令之前调用
+    pub fn renamed(&self, cmd: CommandRequest) -> CommandRequest {
+        let alias = self
+            .rename
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(cmd.name()));
+        match alias {
+            Some((_, alias)) => cmd.with_alias(alias.as_str()),
+            None => cmd,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum MirrorConflict {
+    /// 总是用本地的值覆盖远端
+    #[default]
+    Overwrite,
+    /// 只写入远端不存在的 key，不覆盖也不删除远端已有的 key
+    SkipExisting,
+    /// 远端的值和变更之前本地的值一样时才写入，否则跳过并打印警告
+    IfMatch,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum ShardMode {
+    /// 返回 421 和 key 所在的节点，由客户端重新路由
+    #[default]
+    Redirect,
+    /// 由本节点转发给 key 所在的节点
+    Proxy,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum Routing {
+    /// 依次轮流使用每个服务器
+    #[default]
+    RoundRobin,
+    /// 按 table（pub/sub 按 topic）做 hash，同一个 table 总是落到同一个服务器
+    KeyHash,
+    /// 从服务器获取分片集群的 slot 分布，直接发给 table（pub/sub 按 topic）所在的节点，
+    /// cluster.addrs 需要包含所有节点
+    Slots,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -48,6 +1153,19 @@ pub struct LogConfig {
     pub rotation: RotationConfig,
     pub log_level: LogLevel,
     pub enable_log_file: bool,
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+/// 日志输出格式
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// 便于人阅读的紧凑格式
+    #[default]
+    Text,
+    /// 每行一个 JSON 对象，便于 Loki / ELK 采集
+    Json,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -67,18 +1185,1132 @@ pub enum LogLevel {
     Fatal,
 }
 
+/// 不区分大小写，比如 `debug`、`Warn`
+impl FromStr for LogLevel {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "trace" => Ok(LogLevel::Trace),
+            "debug" => Ok(LogLevel::Debug),
+            "info" => Ok(LogLevel::Info),
+            "warn" => Ok(LogLevel::Warn),
+            "error" => Ok(LogLevel::Error),
+            "fatal" => Ok(LogLevel::Fatal),
+            _ => Err(KvError::InvalidConfig(format!("invalid log level: {}", s))),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            path: "/tmp/kv-log".into(),
+            rotation: RotationConfig::Daily,
+            log_level: LogLevel::Info,
+            enable_log_file: false,
+            format: LogFormat::default(),
+        }
+    }
+}
+
 impl ServerConfig {
+    /// 读取配置文件，再用 KV_* 环境变量覆盖
     pub fn load(path: &str) -> Result<Self, KvError> {
         let config = fs::read_to_string(path)?;
         let config: Self = toml::from_str(&config)?;
+        let config = config.apply_env()?;
+        config.validate()?;
         Ok(config)
     }
+
+    /// 用 KV_* 环境变量覆盖配置，比如 KV_GENERAL_ADDR 覆盖 general.addr，
+    /// KV_LIMITS_MAX_CONCURRENT_STREAMS 覆盖 limits.max_concurrent_streams
+    pub fn apply_env(self) -> Result<Self, KvError> {
+        self.apply_vars(std::env::vars())
+    }
+
+    /// 变量名去掉前缀后按 `_` 分段，从配置的根开始逐层匹配最长的 key，所以 key 本身可以带 `_`。
+    /// 值按配置里原有的类型解析，数组用逗号分隔。
+    /// KV_STORAGE 和 `--storage` 一样是 `memory`、`memory-ordered`、`sled:<path>` 或者
+    /// `cached-sled:<path>`，KV_STORAGE_PATH 使用 sled 存储。
+    /// 不认识的顶层 section 会被忽略，避免和其它 KV_ 开头的变量冲突
+    pub fn apply_vars(
+        self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, KvError> {
+        let mut root = toml::Value::try_from(&self)
+            .map_err(|e| KvError::InvalidConfig(format!("failed to serialize config: {}", e)))?;
+        let mut storage = None;
+        for (name, value) in vars {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            match key {
+                // 配置文件路径，由 kvs 的 --config 处理
+                "SERVER_CONFIG" | "CLIENT_CONFIG" => {}
+                "STORAGE" => storage = Some(value.parse()?),
+                "STORAGE_PATH" => storage = Some(StorageConfig::SledDb(value)),
+                _ => {
+                    let segments: Vec<_> =
+                        key.to_lowercase().split('_').map(String::from).collect();
+                    if let toml::Value::Table(table) = &mut root {
+                        set_env_value(table, &segments, &value, &name, true)?;
+                    }
+                }
+            }
+        }
+
+        let mut config: Self = root.try_into()?;
+        if let Some(storage) = storage {
+            config.storage = storage;
+        }
+        Ok(config)
+    }
+
+    /// 和 new 相比有修改、但需要重启才能生效的 section。
+    /// 日志级别、limits、TLS 证书和 Noise 密钥可以热加载，不在其中
+    pub fn restart_required(&self, new: &ServerConfig) -> Vec<&'static str> {
+        let mut log = new.log.clone();
+        log.log_level = self.log.log_level.clone();
+        [
+            ("general", self.general != new.general),
+            ("storage", self.storage != new.storage),
+            ("log", self.log != log),
+            ("telemetry", self.telemetry != new.telemetry),
+            ("replication", self.replication != new.replication),
+            ("raft", self.raft != new.raft),
+            ("sharding", self.sharding != new.sharding),
+            ("membership", self.membership != new.membership),
+            ("multi_master", self.multi_master != new.multi_master),
+            ("mirror", self.mirror != new.mirror),
+            ("admin", self.admin != new.admin),
+            ("overload", self.overload != new.overload),
+            ("auth", self.auth != new.auth),
+            ("scheduler", self.scheduler != new.scheduler),
+            ("commands", self.commands != new.commands),
+            ("shadow", self.shadow != new.shadow),
+            ("idempotency", self.idempotency != new.idempotency),
+            ("pubsub", self.pubsub != new.pubsub),
+            ("memcached", self.memcached != new.memcached),
+            ("snapshot", self.snapshot != new.snapshot),
+            ("read_cache", self.read_cache != new.read_cache),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(section, _)| section)
+        .collect()
+    }
+
+    /// 通过代码构造配置，不需要拼 TOML
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder::default()
+    }
+
+    /// 检查配置是否合法，所有问题一起报告
+    pub fn validate(&self) -> Result<(), KvError> {
+        Problems::into_result(self.diagnose())
+    }
+
+    /// 检查配置，返回发现的所有问题，每个问题都带上出问题的字段。
+    /// 除了字段的取值，还会检查证书文件是否存在、私钥和证书是否匹配、存储和日志目录是否可写
+    pub fn diagnose(&self) -> Vec<String> {
+        let mut p = Problems::default();
+        let has_noise = self.noise.is_some();
+        check_general(&mut p, "", &self.general, self.tls.is_some(), has_noise);
+        if let Some(tls) = &self.tls {
+            check_server_tls(&mut p, tls);
+        }
+        if let Some(noise) = &self.noise {
+            if let Err(e) = noise.load_key() {
+                p.push(format!("noise.key: {}", e));
+            }
+            if let Err(e) = noise.load_psk() {
+                p.push(format!("noise.psk: {}", e));
+            }
+        }
+        if let Some(path) = self.storage.sled_path() {
+            check_writable_dir(&mut p, "storage.args", path);
+        }
+        if self.log.enable_log_file {
+            check_writable_dir(&mut p, "log.path", &self.log.path);
+        }
+        p.check((0.0..=1.0).contains(&self.telemetry.sample_ratio), || {
+            "telemetry.sample_ratio must be between 0 and 1".into()
+        });
+        p.check(self.limits.max_concurrent_streams > 0, || {
+            "limits.max_concurrent_streams must be greater than 0".into()
+        });
+        p.check(
+            self.limits.max_concurrent_streams <= MAX_NUM_STREAMS,
+            || {
+                format!(
+                    "limits.max_concurrent_streams must not exceed {}",
+                    MAX_NUM_STREAMS
+                )
+            },
+        );
+        if self.limits.strict_frames {
+            p.check(self.limits.max_frame_bytes > 0, || {
+                "limits.max_frame_bytes must be greater than 0".into()
+            });
+            p.check(self.limits.max_decompressed_bytes > 0, || {
+                "limits.max_decompressed_bytes must be greater than 0".into()
+            });
+        }
+        p.check(self.limits.max_response_frame_bytes > 0, || {
+            "limits.max_response_frame_bytes must be greater than 0".into()
+        });
+        p.check(self.limits.max_result_entries != Some(0), || {
+            "limits.max_result_entries must be greater than 0".into()
+        });
+        p.check(self.limits.max_request_entries != Some(0), || {
+            "limits.max_request_entries must be greater than 0".into()
+        });
+        p.check(self.limits.max_key_bytes != Some(0), || {
+            "limits.max_key_bytes must be greater than 0".into()
+        });
+        p.check(self.limits.max_value_bytes != Some(0), || {
+            "limits.max_value_bytes must be greater than 0".into()
+        });
+        p.check(self.pubsub.max_subscribers_per_topic != Some(0), || {
+            "pubsub.max_subscribers_per_topic must be greater than 0".into()
+        });
+        p.check(self.pubsub.max_subscribers != Some(0), || {
+            "pubsub.max_subscribers must be greater than 0".into()
+        });
+        p.check(self.auth.password.as_deref() != Some(""), || {
+            "auth.password must not be empty".into()
+        });
+        for (identity, tenant) in &self.auth.tenants {
+            let ns = &tenant.namespace;
+            p.check(!ns.is_empty() && !ns.contains(':'), || {
+                format!(
+                    "auth.tenants.{}.namespace must be non-empty and must not contain ':'",
+                    identity
+                )
+            });
+            p.check(tenant.max_tables != Some(0), || {
+                format!(
+                    "auth.tenants.{}.max_tables must be greater than 0",
+                    identity
+                )
+            });
+            p.check(tenant.max_subscribers != Some(0), || {
+                format!(
+                    "auth.tenants.{}.max_subscribers must be greater than 0",
+                    identity
+                )
+            });
+        }
+        p.check(self.limits.read_timeout_ms != Some(0), || {
+            "limits.read_timeout_ms must be greater than 0".into()
+        });
+        p.check(self.limits.write_timeout_ms != Some(0), || {
+            "limits.write_timeout_ms must be greater than 0".into()
+        });
+        match (&self.replication.role, &self.replication.primary) {
+            (Role::Replica, Some(primary)) => primary.check(&mut p, "replication.primary."),
+            (Role::Replica, None) => {
+                p.push("replication.primary is required when role = \"Replica\"");
+            }
+            _ => {}
+        }
+        p.check(self.replication.log_capacity > 0, || {
+            "replication.log_capacity must be greater than 0".into()
+        });
+        let failover = &self.replication.failover;
+        p.check(
+            !failover.enabled || (failover.lease_ms > 0 && failover.timeout_ms > failover.lease_ms),
+            || "replication.failover.timeout_ms must be greater than lease_ms".into(),
+        );
+        if self.raft.enabled {
+            self.check_raft(&mut p);
+        }
+        if self.sharding.enabled {
+            self.check_sharding(&mut p);
+        }
+        if self.membership.enabled {
+            self.check_membership(&mut p);
+        }
+        if self.multi_master.enabled {
+            self.check_multi_master(&mut p);
+        }
+        if self.mirror.enabled {
+            match &self.mirror.remote {
+                Some(remote) => remote.check(&mut p, "mirror.remote."),
+                None => p.push("mirror.remote is required when mirror is enabled"),
+            }
+        }
+        if self.shadow.enabled {
+            match &self.shadow.remote {
+                Some(remote) => remote.check(&mut p, "shadow.remote."),
+                None => p.push("shadow.remote is required when shadow is enabled"),
+            }
+            p.check((0.0..=1.0).contains(&self.shadow.sample_ratio), || {
+                "shadow.sample_ratio must be between 0 and 1".into()
+            });
+            p.check(self.shadow.queue_capacity > 0, || {
+                "shadow.queue_capacity must be greater than 0".into()
+            });
+        }
+        if self.idempotency.enabled {
+            p.check(self.idempotency.ttl_ms > 0, || {
+                "idempotency.ttl_ms must be greater than 0".into()
+            });
+            p.check(self.idempotency.capacity > 0, || {
+                "idempotency.capacity must be greater than 0".into()
+            });
+        }
+        if self.admin.enabled {
+            check_addr(&mut p, "admin.addr", &self.admin.addr);
+            p.check(self.admin.addr != self.general.addr, || {
+                "admin.addr must be different from general.addr".into()
+            });
+        }
+        if !self.admin.backup_dir.is_empty() {
+            check_writable_dir(&mut p, "admin.backup_dir", &self.admin.backup_dir);
+        }
+        if self.memcached.enabled {
+            check_addr(&mut p, "memcached.addr", &self.memcached.addr);
+            p.check(self.memcached.addr != self.general.addr, || {
+                "memcached.addr must be different from general.addr".into()
+            });
+            p.check(!self.memcached.table.is_empty(), || {
+                "memcached.table must not be empty".into()
+            });
+            p.check(!self.auth.is_configured(), || {
+                "memcached can not be enabled with auth: the memcached protocol has no authentication"
+                    .into()
+            });
+        }
+        if self.snapshot.enabled {
+            p.check(self.storage.sled_path().is_none(), || {
+                "snapshot only works with memory storage".into()
+            });
+            p.check(!self.snapshot.path.is_empty(), || {
+                "snapshot.path must not be empty".into()
+            });
+            p.check(self.snapshot.interval_ms > 0, || {
+                "snapshot.interval_ms must be greater than 0".into()
+            });
+            if let Some(dir) = Path::new(&self.snapshot.path).parent() {
+                check_writable_dir(&mut p, "snapshot.path", &dir.to_string_lossy());
+            }
+        }
+        if self.read_cache.capacity > 0 {
+            p.check(matches!(self.storage, StorageConfig::SledDb(_)), || {
+                "read_cache only works with sled storage".into()
+            });
+            p.check(self.read_cache.ttl_ms > 0, || {
+                "read_cache.ttl_ms must be greater than 0".into()
+            });
+        }
+        if matches!(self.storage, StorageConfig::CachedSledDb(_)) {
+            p.check(self.read_cache.fast_capacity > 0, || {
+                "read_cache.fast_capacity must be greater than 0".into()
+            });
+        }
+        if self.overload.enabled {
+            p.check(self.overload.max_in_flight > 0, || {
+                "overload.max_in_flight must be greater than 0".into()
+            });
+            p.check(self.overload.sample_interval_ms > 0, || {
+                "overload.sample_interval_ms must be greater than 0".into()
+            });
+        }
+        p.check(self.scheduler.jitter_pct < 100, || {
+            "scheduler.jitter_pct must be less than 100".into()
+        });
+        for (name, job) in &self.scheduler.jobs {
+            p.check(job.interval_ms != Some(0), || {
+                format!("scheduler.jobs.{}.interval_ms must be greater than 0", name)
+            });
+        }
+        for (field, names) in [
+            ("commands.allow", &self.commands.allow),
+            ("commands.deny", &self.commands.deny),
+        ] {
+            for name in names {
+                let known = CommandRequest::NAMES
+                    .iter()
+                    .any(|n| n.eq_ignore_ascii_case(name));
+                p.check(known, || format!("{} has unknown command {}", field, name));
+            }
+        }
+        for name in self.commands.rename.keys() {
+            let known = CommandRequest::NAMES
+                .iter()
+                .any(|n| n.eq_ignore_ascii_case(name));
+            p.check(known, || {
+                format!("commands.rename has unknown command {}", name)
+            });
+            // AUTH 在检查命令之前处理
+            p.check(!name.eq_ignore_ascii_case("auth"), || {
+                "commands.rename can not rename auth".into()
+            });
+        }
+        p.0
+    }
+
+    fn check_multi_master(&self, p: &mut Problems) {
+        let multi_master = &self.multi_master;
+        p.check(
+            !self.raft.enabled
+                && !self.sharding.enabled
+                && self.replication.role == Role::Standalone,
+            || "multi_master can not be used together with raft, sharding or replication".into(),
+        );
+        p.check(multi_master.node_id.as_deref() != Some(""), || {
+            "multi_master.node_id must not be empty".into()
+        });
+        for addr in &multi_master.peers {
+            check_addr(p, "multi_master.peers", addr);
+        }
+        p.check(multi_master.sync_interval_ms > 0, || {
+            "multi_master.sync_interval_ms must be greater than 0".into()
+        });
+    }
+
+    fn check_membership(&self, p: &mut Problems) {
+        let membership = &self.membership;
+        if let Some(addr) = &membership.advertise_addr {
+            check_addr(p, "membership.advertise_addr", addr);
+        }
+        for addr in &membership.seeds {
+            check_addr(p, "membership.seeds", addr);
+        }
+        p.check(membership.gossip_interval_ms > 0, || {
+            "membership.gossip_interval_ms must be greater than 0".into()
+        });
+        p.check(
+            membership.suspect_timeout_ms < membership.down_timeout_ms,
+            || "membership.suspect_timeout_ms must be less than down_timeout_ms".into(),
+        );
+    }
+
+    fn check_sharding(&self, p: &mut Problems) {
+        let sharding = &self.sharding;
+        p.check(!self.raft.enabled, || {
+            "sharding can not be used together with raft".into()
+        });
+        p.check(
+            sharding.id > 0 && sharding.id as usize <= sharding.nodes.len(),
+            || "sharding.id must be the position of this node in sharding.nodes".into(),
+        );
+        for addr in &sharding.nodes {
+            check_addr(p, "sharding.nodes", addr);
+        }
+        p.check(sharding.virtual_nodes > 0, || {
+            "sharding.virtual_nodes must be greater than 0".into()
+        });
+    }
+
+    fn check_raft(&self, p: &mut Problems) {
+        let raft = &self.raft;
+        p.check(self.replication.role == Role::Standalone, || {
+            "raft can not be used together with replication".into()
+        });
+        p.check(raft.id > 0 && raft.id as usize <= raft.peers.len(), || {
+            "raft.id must be the position of this node in raft.peers".into()
+        });
+        for addr in &raft.peers {
+            check_addr(p, "raft.peers", addr);
+        }
+        p.check(
+            raft.heartbeat_interval_ms > 0 && raft.heartbeat_interval_ms < raft.election_timeout_ms,
+            || "raft.heartbeat_interval_ms must be between 0 and election_timeout_ms".into(),
+        );
+    }
 }
 
 impl ClientConfig {
     pub fn load(path: &str) -> Result<Self, KvError> {
         let config = fs::read_to_string(path)?;
         let config: Self = toml::from_str(&config)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// 通过代码构造配置，不需要拼 TOML
+    pub fn builder() -> ClientConfigBuilder {
+        ClientConfigBuilder::default()
+    }
+
+    /// 检查配置是否合法，所有问题一起报告
+    pub fn validate(&self) -> Result<(), KvError> {
+        Problems::into_result(self.diagnose())
+    }
+
+    /// 检查配置，返回发现的所有问题
+    pub fn diagnose(&self) -> Vec<String> {
+        let mut p = Problems::default();
+        self.check(&mut p, "");
+        p.0
+    }
+
+    /// 嵌在 ServerConfig 里的 ClientConfig 用 prefix 标明字段的位置
+    fn check(&self, p: &mut Problems, prefix: &str) {
+        let has_noise = self.noise.is_some();
+        check_general(p, prefix, &self.general, self.tls.is_some(), has_noise);
+        if let Some(noise) = &self.noise {
+            match noise.load_keys() {
+                Ok(keys) => p.check(
+                    noise.pattern == NoisePattern::XX || keys.server_pub.is_some(),
+                    || {
+                        format!(
+                            "{}noise.server_pub is required for pattern {}",
+                            prefix, noise.pattern
+                        )
+                    },
+                ),
+                Err(e) => p.push(format!("{}noise: {}", prefix, e)),
+            }
+        }
+        if let Some(tls) = &self.tls {
+            if let Some(path) = &tls.ca_path {
+                check_file(p, &format!("{}tls.ca_path", prefix), path);
+            }
+            if let Some((cert, key)) = &tls.identity_path {
+                check_file(p, &format!("{}tls.identity_path", prefix), cert);
+                check_file(p, &format!("{}tls.identity_path", prefix), key);
+            }
+            if let Err(e) = crate::parse_cipher_suites(&tls.cipher_suites) {
+                p.push(format!("{}tls.cipher_suites: {}", prefix, e));
+            }
+        }
+        p.check(self.retry.max_attempts > 0, || {
+            format!("{}retry.max_attempts must be greater than 0", prefix)
+        });
+        for addr in &self.cluster.addrs {
+            check_addr(p, &format!("{}cluster.addrs", prefix), addr);
+        }
+        p.check(self.auth.password.as_deref() != Some(""), || {
+            format!("{}auth.password must not be empty", prefix)
+        });
+    }
+}
+
+/// 检查配置时收集到的问题
+#[derive(Debug, Default)]
+struct Problems(Vec<String>);
+
+impl Problems {
+    fn push(&mut self, problem: impl Into<String>) {
+        self.0.push(problem.into());
+    }
+
+    /// ok 为 false 时记录问题
+    fn check(&mut self, ok: bool, problem: impl FnOnce() -> String) {
+        if !ok {
+            self.0.push(problem());
+        }
+    }
+
+    /// 只有一个问题时原样报告，多个问题时逐行列出
+    fn into_result(problems: Vec<String>) -> Result<(), KvError> {
+        match problems.len() {
+            0 => Ok(()),
+            1 => Err(KvError::InvalidConfig(problems.into_iter().next().unwrap())),
+            n => Err(KvError::InvalidConfig(format!(
+                "found {} problems:\n  - {}",
+                n,
+                problems.join("\n  - ")
+            ))),
+        }
+    }
+}
+
+fn check_general(
+    p: &mut Problems,
+    prefix: &str,
+    general: &GeneralConfig,
+    has_tls: bool,
+    has_noise: bool,
+) {
+    check_addr(p, &format!("{}general.addr", prefix), &general.addr);
+    p.check(general.security != Security::Tls || has_tls, || {
+        format!("{}[tls] is required when security = \"tls\"", prefix)
+    });
+    p.check(general.security != Security::Noise || has_noise, || {
+        format!("{}[noise] is required when security = \"noise\"", prefix)
+    });
+}
+
+/// 证书和私钥必须都设置，文件必须存在，私钥必须和证书匹配
+fn check_server_tls(p: &mut Problems, tls: &ServerTlsConfig) {
+    let has_cert = !tls.cert.is_empty() || tls.cert_path.is_some();
+    let has_key = !tls.key.is_empty() || tls.key_path.is_some();
+    if !has_cert || !has_key {
+        p.push("tls.cert/tls.cert_path and tls.key/tls.key_path are required");
+        return;
+    }
+    let paths = [
+        ("tls.cert_path", &tls.cert_path),
+        ("tls.key_path", &tls.key_path),
+        ("tls.ca_path", &tls.ca_path),
+        ("tls.crl_path", &tls.crl_path),
+    ];
+    let mut missing = false;
+    for (field, path) in paths {
+        if let Some(path) = path {
+            missing |= !check_file(p, field, path);
+        }
+    }
+    if missing {
+        return;
+    }
+    if let Err(e) = crate::parse_cipher_suites(&tls.cipher_suites) {
+        p.push(format!("tls.cipher_suites: {}", e));
+    }
+    let pem = match tls.load_pem() {
+        Ok(pem) => pem,
+        Err(e) => {
+            p.push(format!("tls: {}", e));
+            return;
+        }
+    };
+    match crate::verify_key_pair(&pem.cert, &pem.key) {
+        Ok(()) => {}
+        Err(KvError::InvalidConfig(e)) => p.push(format!("tls.key: {}", e)),
+        Err(e) => p.push(format!("tls: {}", e)),
+    }
+    if let Some(crl) = &pem.crl
+        && let Err(e) = crate::RevocationList::from_pem(crl)
+    {
+        p.push(format!("tls.crl: {}", e));
+    }
+}
+
+/// 文件必须存在并且可读，返回是否通过检查
+fn check_file(p: &mut Problems, field: &str, path: &str) -> bool {
+    match fs::File::open(path) {
+        Ok(_) => true,
+        Err(e) => {
+            p.push(format!("{}: can not read {}: {}", field, path, e));
+            false
+        }
+    }
+}
+
+/// 目录存在时必须可写；不存在时会在启动时创建，最近的已经存在的上级目录必须可写
+fn check_writable_dir(p: &mut Problems, field: &str, path: &str) {
+    let dir = Path::new(path)
+        .ancestors()
+        .map(|dir| match dir.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => dir,
+        })
+        .find(|dir| dir.exists());
+    let Some(dir) = dir else {
+        return;
+    };
+    match fs::metadata(dir) {
+        Ok(meta) if !meta.is_dir() => {
+            p.push(format!("{}: {} is not a directory", field, dir.display()));
+        }
+        Ok(meta) if meta.permissions().readonly() => {
+            p.push(format!("{}: {} is not writable", field, dir.display()));
+        }
+        Ok(_) => {}
+        Err(e) => p.push(format!(
+            "{}: can not access {}: {}",
+            field,
+            dir.display(),
+            e
+        )),
+    }
+}
+
+/// 在 table 里找到 segments 对应的 key 并设置值，返回是否设置成功
+#[allow(clippy::collapsible_match)]
+fn set_env_value(
+    table: &mut toml::Table,
+    segments: &[String],
+    value: &str,
+    name: &str,
+    root: bool,
+) -> Result<bool, KvError> {
+    // 优先匹配最长的 key，比如 max_concurrent_streams
+    for n in (1..=segments.len()).rev() {
+        let key = segments[..n].join("_");
+        let rest = &segments[n..];
+        match table.get_mut(&key) {
+            Some(toml::Value::Table(sub)) if !rest.is_empty() => {
+                if set_env_value(sub, rest, value, name, false)? {
+                    return Ok(true);
+                }
+            }
+            Some(toml::Value::Table(_)) => {
+                return Err(KvError::InvalidConfig(format!(
+                    "{} can not override the whole section",
+                    name
+                )));
+            }
+            Some(old) if rest.is_empty() => {
+                *old = parse_env_value(Some(old), value, name)?;
+                return Ok(true);
+            }
+            _ => {}
+        }
+    }
+    // 配置里没有的 key（比如值为 None 的字段）直接加上，不认识的 key 反序列化时会被忽略
+    if root {
+        return Ok(false);
+    }
+    table.insert(segments.join("_"), parse_env_value(None, value, name)?);
+    Ok(true)
+}
+
+/// 按原来的类型解析环境变量的值，没有原来的值时按内容推断
+fn parse_env_value(
+    old: Option<&toml::Value>,
+    value: &str,
+    name: &str,
+) -> Result<toml::Value, KvError> {
+    let invalid = || KvError::InvalidConfig(format!("invalid value of {}: {}", name, value));
+    let parsed = match old {
+        Some(toml::Value::String(_)) => toml::Value::String(value.into()),
+        Some(toml::Value::Integer(_)) => {
+            toml::Value::Integer(value.trim().parse().map_err(|_| invalid())?)
+        }
+        Some(toml::Value::Float(_)) => {
+            toml::Value::Float(value.trim().parse().map_err(|_| invalid())?)
+        }
+        Some(toml::Value::Boolean(_)) => {
+            toml::Value::Boolean(value.trim().parse().map_err(|_| invalid())?)
+        }
+        Some(toml::Value::Array(items)) => {
+            let item = items.first();
+            let items = value
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|s| parse_env_value(item, s, name))
+                .collect::<Result<_, _>>()?;
+            toml::Value::Array(items)
+        }
+        Some(_) => return Err(invalid()),
+        None => {
+            if let Ok(v) = value.parse() {
+                toml::Value::Boolean(v)
+            } else if let Ok(v) = value.parse() {
+                toml::Value::Integer(v)
+            } else if let Ok(v) = value.parse() {
+                toml::Value::Float(v)
+            } else {
+                toml::Value::String(value.into())
+            }
+        }
+    };
+    Ok(parsed)
+}
+
+/// 地址必须是 host:port 的形式
+fn check_addr(p: &mut Problems, field: &str, addr: &str) {
+    let valid = match addr.rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+        None => false,
+    };
+    p.check(valid, || format!("{}: invalid address: {}", field, addr));
+}
+
+/// ServerConfig 的 builder，没有设置的字段使用默认值：
+/// 监听 127.0.0.1:9527，MemTable 存储，不设置 TLS 和 Noise 时使用明文 TCP
+#[derive(Debug, Default)]
+pub struct ServerConfigBuilder {
+    addr: Option<String>,
+    storage: Option<StorageConfig>,
+    tls: Option<ServerTlsConfig>,
+    noise: Option<ServerNoiseConfig>,
+    security: Option<Security>,
+    socket: SocketConfig,
+    log: LogConfig,
+    limits: LimitsConfig,
+    telemetry: TelemetryConfig,
+    replication: ReplicationConfig,
+    raft: RaftConfig,
+    sharding: ShardingConfig,
+    membership: MembershipConfig,
+    multi_master: MultiMasterConfig,
+    mirror: MirrorConfig,
+    admin: AdminConfig,
+    overload: OverloadConfig,
+    auth: AuthConfig,
+    scheduler: SchedulerConfig,
+    commands: CommandsConfig,
+    shadow: ShadowConfig,
+    idempotency: IdempotencyConfig,
+    pubsub: PubSubConfig,
+    memcached: MemcachedConfig,
+    snapshot: SnapshotConfig,
+    read_cache: ReadCacheConfig,
+}
+
+impl ServerConfigBuilder {
+    pub fn addr(mut self, addr: impl Into<String>) -> Self {
+        self.addr = Some(addr.into());
+        self
+    }
+
+    pub fn storage(mut self, storage: StorageConfig) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// 设置证书和私钥（PEM 格式），ca 用于校验客户端证书
+    pub fn tls(
+        mut self,
+        cert: impl Into<String>,
+        key: impl Into<String>,
+        ca: Option<String>,
+    ) -> Self {
+        self.tls = Some(ServerTlsConfig {
+            cert: cert.into(),
+            key: key.into(),
+            ca,
+            cert_path: None,
+            key_path: None,
+            ca_path: None,
+            session: ServerSessionConfig::default(),
+            crl: None,
+            crl_path: None,
+            min_version: TlsVersion::default(),
+            cipher_suites: Vec::new(),
+        });
+        self
+    }
+
+    /// 和 tls() 一样，但证书、私钥和 CA 在启动时从文件读取
+    pub fn tls_files(
+        mut self,
+        cert_path: impl Into<String>,
+        key_path: impl Into<String>,
+        ca_path: Option<String>,
+    ) -> Self {
+        self.tls = Some(ServerTlsConfig {
+            cert: String::new(),
+            key: String::new(),
+            ca: None,
+            cert_path: Some(cert_path.into()),
+            key_path: Some(key_path.into()),
+            ca_path,
+            session: ServerSessionConfig::default(),
+            crl: None,
+            crl_path: None,
+            min_version: TlsVersion::default(),
+            cipher_suites: Vec::new(),
+        });
+        self
+    }
+
+    /// 设置最低 TLS 版本和允许的 cipher suite，需要先调用 tls() 或者 tls_files()
+    pub fn tls_policy(mut self, min_version: TlsVersion, cipher_suites: Vec<String>) -> Self {
+        if let Some(tls) = self.tls.as_mut() {
+            tls.min_version = min_version;
+            tls.cipher_suites = cipher_suites;
+        }
+        self
+    }
+
+    /// 使用 Noise 协议代替 TLS
+    pub fn noise(mut self, noise: ServerNoiseConfig) -> Self {
+        self.noise = Some(noise);
+        self
+    }
+
+    /// 显式指定安全模式，不指定时按是否设置了 TLS 或者 Noise 推断
+    pub fn security(mut self, security: Security) -> Self {
+        self.security = Some(security);
+        self
+    }
+
+    pub fn socket(mut self, socket: SocketConfig) -> Self {
+        self.socket = socket;
+        self
+    }
+
+    pub fn log(mut self, log: LogConfig) -> Self {
+        self.log = log;
+        self
+    }
+
+    pub fn limits(mut self, limits: LimitsConfig) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn telemetry(mut self, telemetry: TelemetryConfig) -> Self {
+        self.telemetry = telemetry;
+        self
+    }
+
+    pub fn replication(mut self, replication: ReplicationConfig) -> Self {
+        self.replication = replication;
+        self
+    }
+
+    pub fn raft(mut self, raft: RaftConfig) -> Self {
+        self.raft = raft;
+        self
+    }
+
+    pub fn sharding(mut self, sharding: ShardingConfig) -> Self {
+        self.sharding = sharding;
+        self
+    }
+
+    pub fn membership(mut self, membership: MembershipConfig) -> Self {
+        self.membership = membership;
+        self
+    }
+
+    pub fn multi_master(mut self, multi_master: MultiMasterConfig) -> Self {
+        self.multi_master = multi_master;
+        self
+    }
+
+    pub fn mirror(mut self, mirror: MirrorConfig) -> Self {
+        self.mirror = mirror;
+        self
+    }
+
+    pub fn admin(mut self, admin: AdminConfig) -> Self {
+        self.admin = admin;
+        self
+    }
+
+    pub fn overload(mut self, overload: OverloadConfig) -> Self {
+        self.overload = overload;
+        self
+    }
+
+    pub fn auth(mut self, auth: AuthConfig) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// 要求客户端先用 AUTH 认证
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.auth.password = Some(password.into());
+        self
+    }
+
+    pub fn scheduler(mut self, scheduler: SchedulerConfig) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+
+    pub fn commands(mut self, commands: CommandsConfig) -> Self {
+        self.commands = commands;
+        self
+    }
+
+    pub fn shadow(mut self, shadow: ShadowConfig) -> Self {
+        self.shadow = shadow;
+        self
+    }
+
+    pub fn idempotency(mut self, idempotency: IdempotencyConfig) -> Self {
+        self.idempotency = idempotency;
+        self
+    }
+
+    pub fn pubsub(mut self, pubsub: PubSubConfig) -> Self {
+        self.pubsub = pubsub;
+        self
+    }
+
+    pub fn memcached(mut self, memcached: MemcachedConfig) -> Self {
+        self.memcached = memcached;
+        self
+    }
+
+    pub fn snapshot(mut self, snapshot: SnapshotConfig) -> Self {
+        self.snapshot = snapshot;
+        self
+    }
+
+    pub fn read_cache(mut self, read_cache: ReadCacheConfig) -> Self {
+        self.read_cache = read_cache;
+        self
+    }
+
+    pub fn build(self) -> Result<ServerConfig, KvError> {
+        let security = self.security.unwrap_or(match (&self.tls, &self.noise) {
+            (Some(_), _) => Security::Tls,
+            (None, Some(_)) => Security::Noise,
+            (None, None) => Security::None,
+        });
+        let config = ServerConfig {
+            general: GeneralConfig {
+                addr: self.addr.unwrap_or_else(|| DEFAULT_ADDR.into()),
+                security,
+                socket: self.socket,
+            },
+            storage: self.storage.unwrap_or(StorageConfig::MemTable),
+            tls: self.tls,
+            noise: self.noise,
+            log: self.log,
+            limits: self.limits,
+            telemetry: self.telemetry,
+            replication: self.replication,
+            raft: self.raft,
+            sharding: self.sharding,
+            membership: self.membership,
+            multi_master: self.multi_master,
+            mirror: self.mirror,
+            admin: self.admin,
+            overload: self.overload,
+            auth: self.auth,
+            scheduler: self.scheduler,
+            commands: self.commands,
+            shadow: self.shadow,
+            idempotency: self.idempotency,
+            pubsub: self.pubsub,
+            memcached: self.memcached,
+            snapshot: self.snapshot,
+            read_cache: self.read_cache,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// ClientConfig 的 builder，没有设置的字段使用默认值：
+/// 连接 127.0.0.1:9527，不设置 TLS 和 Noise 时使用明文 TCP
+#[derive(Debug, Default)]
+pub struct ClientConfigBuilder {
+    addr: Option<String>,
+    tls: Option<ClientTlsConfig>,
+    noise: Option<ClientNoiseConfig>,
+    security: Option<Security>,
+    socket: SocketConfig,
+    retry: RetryConfig,
+    cache: CacheConfig,
+    cluster: ClusterConfig,
+    auth: AuthConfig,
+    commands: CommandsConfig,
+}
+
+impl ClientConfigBuilder {
+    pub fn addr(mut self, addr: impl Into<String>) -> Self {
+        self.addr = Some(addr.into());
+        self
+    }
+
+    /// 设置服务器域名和 CA 证书（PEM 格式），ca 为 None 时使用系统根证书
+    pub fn tls(mut self, domain: impl Into<String>, ca: Option<String>) -> Self {
+        self.tls = Some(ClientTlsConfig {
+            domain: domain.into(),
+            identity: None,
+            ca,
+            identity_path: None,
+            ca_path: None,
+            session: ClientSessionConfig::default(),
+            min_version: TlsVersion::default(),
+            cipher_suites: Vec::new(),
+        });
+        self
+    }
+
+    /// 和 tls() 一样，但 CA 证书在连接时从文件读取
+    pub fn tls_files(mut self, domain: impl Into<String>, ca_path: Option<String>) -> Self {
+        self.tls = Some(ClientTlsConfig {
+            domain: domain.into(),
+            identity: None,
+            ca: None,
+            identity_path: None,
+            ca_path,
+            session: ClientSessionConfig::default(),
+            min_version: TlsVersion::default(),
+            cipher_suites: Vec::new(),
+        });
+        self
+    }
+
+    /// 设置客户端证书和私钥（PEM 格式），需要先调用 tls()
+    pub fn identity(mut self, cert: impl Into<String>, key: impl Into<String>) -> Self {
+        if let Some(tls) = self.tls.as_mut() {
+            tls.identity = Some((cert.into(), key.into()));
+        }
+        self
+    }
+
+    /// 设置最低 TLS 版本和允许的 cipher suite，需要先调用 tls() 或者 tls_files()
+    pub fn tls_policy(mut self, min_version: TlsVersion, cipher_suites: Vec<String>) -> Self {
+        if let Some(tls) = self.tls.as_mut() {
+            tls.min_version = min_version;
+            tls.cipher_suites = cipher_suites;
+        }
+        self
+    }
+
+    /// 使用 Noise 协议代替 TLS
+    pub fn noise(mut self, noise: ClientNoiseConfig) -> Self {
+        self.noise = Some(noise);
+        self
+    }
+
+    /// 显式指定安全模式，不指定时按是否设置了 TLS 或者 Noise 推断
+    pub fn security(mut self, security: Security) -> Self {
+        self.security = Some(security);
+        self
+    }
+
+    pub fn socket(mut self, socket: SocketConfig) -> Self {
+        self.socket = socket;
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn cache(mut self, cache: CacheConfig) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn cluster(mut self, cluster: ClusterConfig) -> Self {
+        self.cluster = cluster;
+        self
+    }
+
+    /// 连接后用 password 认证
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.auth.password = Some(password.into());
+        self
+    }
+
+    /// 服务器把 name 命令改名成了 alias，执行这个命令时带上 alias
+    pub fn rename_command(mut self, name: impl Into<String>, alias: impl Into<String>) -> Self {
+        self.commands.rename.insert(name.into(), alias.into());
+        self
+    }
+
+    pub fn build(self) -> Result<ClientConfig, KvError> {
+        let security = self.security.unwrap_or(match (&self.tls, &self.noise) {
+            (Some(_), _) => Security::Tls,
+            (None, Some(_)) => Security::Noise,
+            (None, None) => Security::None,
+        });
+        let config = ClientConfig {
+            general: GeneralConfig {
+                addr: self.addr.unwrap_or_else(|| DEFAULT_ADDR.into()),
+                security,
+                socket: self.socket,
+            },
+            tls: self.tls,
+            noise: self.noise,
+            retry: self.retry,
+            cache: self.cache,
+            cluster: self.cluster,
+            auth: self.auth,
+            commands: self.commands,
+        };
+        config.validate()?;
         Ok(config)
     }
 }
@@ -87,6 +2319,77 @@ impl ClientConfig {
 mod tests {
     use super::*;
 
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn env_should_override_config() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        let config = config
+            .apply_vars(vars(&[
+                ("KV_GENERAL_ADDR", "0.0.0.0:1234"),
+                ("KV_LIMITS_MAX_CONCURRENT_STREAMS", "16"),
+                ("KV_LOG_ENABLE_LOG_FILE", "false"),
+                ("KV_LOG_LOG_LEVEL", "Debug"),
+                ("KV_TELEMETRY_SAMPLE_RATIO", "0.5"),
+                ("KV_MEMBERSHIP_SEEDS", "127.0.0.1:9528, 127.0.0.1:9529"),
+                ("KV_MEMBERSHIP_ADVERTISE_ADDR", "10.0.0.1:9527"),
+                ("KV_STORAGE_PATH", "/data/kv"),
+                ("KV_SERVER_CONFIG", "/etc/kv/server.conf"),
+                ("KV_VERSION", "1"),
+                ("PATH", "/usr/bin"),
+            ]))
+            .unwrap();
+        assert_eq!(config.general.addr, "0.0.0.0:1234");
+        assert_eq!(config.limits.max_concurrent_streams, 16);
+        assert!(!config.log.enable_log_file);
+        assert_eq!(config.log.log_level, LogLevel::Debug);
+        assert_eq!(config.telemetry.sample_ratio, 0.5);
+        assert_eq!(
+            config.membership.seeds,
+            vec!["127.0.0.1:9528".to_string(), "127.0.0.1:9529".to_string()]
+        );
+        assert_eq!(
+            config.membership.advertise_addr.as_deref(),
+            Some("10.0.0.1:9527")
+        );
+        assert_eq!(config.storage, StorageConfig::SledDb("/data/kv".into()));
+
+        let config = config
+            .apply_vars(vars(&[("KV_STORAGE", "memory")]))
+            .unwrap();
+        assert_eq!(config.storage, StorageConfig::MemTable);
+    }
+
+    #[test]
+    fn restart_required_should_skip_reloadable_changes() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        let mut new = config.clone();
+        new.log.log_level = LogLevel::Debug;
+        new.limits.max_concurrent_streams = 16;
+        new.tls.as_mut().unwrap().ca = Some("ca".into());
+        assert!(config.restart_required(&new).is_empty());
+
+        new.general.addr = "0.0.0.0:9527".into();
+        new.log.path = "/var/log/kv".into();
+        assert_eq!(config.restart_required(&new), vec!["general", "log"]);
+    }
+
+    #[test]
+    fn invalid_env_should_be_rejected() {
+        let config = ServerConfig::builder().build().unwrap();
+        let err = config
+            .clone()
+            .apply_vars(vars(&[("KV_LIMITS_MAX_CONCURRENT_STREAMS", "many")]))
+            .unwrap_err();
+        assert!(err.to_string().contains("KV_LIMITS_MAX_CONCURRENT_STREAMS"));
+        assert!(config.apply_vars(vars(&[("KV_GENERAL", "x")])).is_err());
+    }
+
     #[test]
     fn server_config_should_be_loaded() {
         let result: Result<ServerConfig, toml::de::Error> =
@@ -94,10 +2397,556 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn server_config_without_limits_should_use_default() {
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.limits, LimitsConfig::default());
+        assert_eq!(config.telemetry, TelemetryConfig::default());
+        assert_eq!(config.log.format, LogFormat::Text);
+        assert_eq!(config.general.socket, SocketConfig::default());
+        assert_eq!(config.general.security, Security::Tls);
+    }
+
+    #[test]
+    fn stream_timeouts_should_be_validated() {
+        let mut limits = LimitsConfig {
+            read_timeout_ms: Some(30_000),
+            ..Default::default()
+        };
+        assert_eq!(limits.read_timeout(), Some(Duration::from_secs(30)));
+        assert_eq!(limits.write_timeout(), None);
+
+        limits.write_timeout_ms = Some(0);
+        let result = ServerConfig::builder().limits(limits).build();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn roles_should_be_loaded() {
+        let config: AuthConfig = toml::from_str(
+            r#"
+            default_role = "read-only"
+            [roles]
+            "cert:deec35a4e56c280d7f9f6065e5e85f13c8ce3bf63a6304f41f9c8f2bb928d538" = "admin"
+            alice = "pubsub-only"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.default_role, Some(AccessRole::ReadOnly));
+        assert_eq!(config.roles["alice"], AccessRole::PubSubOnly);
+        assert_eq!(
+            config.roles["cert:deec35a4e56c280d7f9f6065e5e85f13c8ce3bf63a6304f41f9c8f2bb928d538"]
+                .to_string(),
+            "admin"
+        );
+    }
+
+    #[test]
+    fn tls_policy_should_be_loaded() {
+        let config: ClientConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+            [tls]
+            domain = "kvserver.acme.inc"
+            min_version = "1.3"
+            cipher_suites = ["TLS13_AES_256_GCM_SHA384"]
+            "#,
+        )
+        .unwrap();
+        let tls = config.tls.as_ref().unwrap();
+        assert_eq!(tls.min_version, TlsVersion::Tls13);
+        assert!(config.validate().is_ok());
+
+        let config = ClientConfig::builder()
+            .tls("kvserver.acme.inc", None)
+            .tls_policy(TlsVersion::Tls12, vec!["TLS_NOPE".into()])
+            .build();
+        assert!(
+            config
+                .unwrap_err()
+                .to_string()
+                .contains("tls.cipher_suites")
+        );
+    }
+
+    #[test]
+    fn empty_password_should_be_rejected() {
+        assert!(ServerConfig::builder().password("").build().is_err());
+        assert!(ClientConfig::builder().password("").build().is_err());
+
+        let config = ServerConfig::builder().password("secret").build().unwrap();
+        let config = config
+            .apply_vars(vars(&[("KV_AUTH_PASSWORD", "another")]))
+            .unwrap();
+        assert_eq!(config.auth.password.as_deref(), Some("another"));
+    }
+
+    #[test]
+    fn plaintext_config_should_not_require_tls() {
+        let config: ClientConfig = toml::from_str(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+            security = "none"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.general.security, Security::None);
+        assert!(config.tls.is_none());
+    }
+
+    #[test]
+    fn config_builder_should_work() {
+        let config = ServerConfig::builder()
+            .addr("0.0.0.0:9527")
+            .storage(StorageConfig::SledDb("/tmp/kv".into()))
+            .build()
+            .unwrap();
+        assert_eq!(config.general.addr, "0.0.0.0:9527");
+        assert_eq!(config.general.security, Security::None);
+        assert_eq!(config.storage, StorageConfig::SledDb("/tmp/kv".into()));
+
+        let config = ClientConfig::builder()
+            .tls("kvserver.acme.inc", None)
+            .build()
+            .unwrap();
+        assert_eq!(config.general.addr, DEFAULT_ADDR);
+        assert_eq!(config.general.security, Security::Tls);
+        assert_eq!(config.retry, RetryConfig::default());
+    }
+
+    #[test]
+    fn validate_should_report_all_problems() {
+        let mut config = ServerConfig::builder().build().unwrap();
+        config.general.addr = "9527".into();
+        config.limits.max_concurrent_streams = 0;
+        config.telemetry.sample_ratio = 2.0;
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("found 3 problems"));
+        assert!(err.contains("general.addr: invalid address: 9527"));
+        assert!(err.contains("limits.max_concurrent_streams"));
+        assert!(err.contains("telemetry.sample_ratio"));
+
+        // 嵌套的 ClientConfig 的问题带上所在的 section
+        config = ServerConfig::builder().build().unwrap();
+        let mut remote = ClientConfig::builder().build().unwrap();
+        remote.retry.max_attempts = 0;
+        config.mirror.enabled = true;
+        config.mirror.remote = Some(remote);
+        assert_eq!(
+            config.diagnose(),
+            vec!["mirror.remote.retry.max_attempts must be greater than 0".to_string()]
+        );
+    }
+
+    #[test]
+    fn validate_should_check_files_and_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        config.log.path = dir.path().join("log").display().to_string();
+        config.storage = StorageConfig::SledDb(file.join("db").display().to_string());
+        assert_eq!(config.diagnose().len(), 1);
+        assert!(config.diagnose()[0].contains("is not a directory"));
+
+        config.storage = StorageConfig::MemTable;
+        let tls = config.tls.as_mut().unwrap();
+        tls.key = include_str!("../fixtures/client.key").into();
+        tls.ca_path = Some(dir.path().join("ca.cert").display().to_string());
+        assert!(config.diagnose()[0].starts_with("tls.ca_path: can not read"));
+        config.tls.as_mut().unwrap().ca_path = None;
+        assert!(config.diagnose()[0].contains("does not match"));
+
+        let tls = config.tls.as_mut().unwrap();
+        tls.key = include_str!("../fixtures/server.key").into();
+        tls.crl = Some(include_str!("../fixtures/ca.cert").into());
+        assert!(config.diagnose()[0].starts_with("tls.crl: "));
+        config.tls.as_mut().unwrap().crl = Some(include_str!("../fixtures/ca.crl").into());
+        assert!(config.diagnose().is_empty());
+    }
+
+    #[test]
+    fn config_builder_should_validate() {
+        assert!(ServerConfig::builder().addr("9527").build().is_err());
+        assert!(
+            ServerConfig::builder()
+                .security(Security::Tls)
+                .build()
+                .is_err()
+        );
+        let retry = RetryConfig {
+            max_attempts: 0,
+            ..Default::default()
+        };
+        assert!(ClientConfig::builder().retry(retry).build().is_err());
+
+        let replication = ReplicationConfig {
+            role: Role::Replica,
+            ..Default::default()
+        };
+        assert!(
+            ServerConfig::builder()
+                .replication(replication)
+                .build()
+                .is_err()
+        );
+
+        let raft = RaftConfig {
+            enabled: true,
+            id: 3,
+            peers: vec!["127.0.0.1:9527".into(), "127.0.0.1:9528".into()],
+            ..Default::default()
+        };
+        assert!(ServerConfig::builder().raft(raft).build().is_err());
+
+        let sharding = ShardingConfig {
+            enabled: true,
+            id: 1,
+            nodes: vec!["127.0.0.1:9527".into(), "127.0.0.1:9528".into()],
+            virtual_nodes: 0,
+            ..Default::default()
+        };
+        assert!(ServerConfig::builder().sharding(sharding).build().is_err());
+
+        let replication = ReplicationConfig {
+            failover: FailoverConfig {
+                enabled: true,
+                timeout_ms: 1000,
+                lease_ms: 2000,
+            },
+            ..Default::default()
+        };
+        assert!(
+            ServerConfig::builder()
+                .replication(replication)
+                .build()
+                .is_err()
+        );
+
+        let membership = MembershipConfig {
+            enabled: true,
+            suspect_timeout_ms: 5000,
+            down_timeout_ms: 1000,
+            ..Default::default()
+        };
+        assert!(
+            ServerConfig::builder()
+                .membership(membership)
+                .build()
+                .is_err()
+        );
+
+        // 多主模式不能和主从复制一起使用
+        let multi_master = MultiMasterConfig {
+            enabled: true,
+            peers: vec!["127.0.0.1:9528".into()],
+            ..Default::default()
+        };
+        let replication = ReplicationConfig {
+            role: Role::Primary,
+            ..Default::default()
+        };
+        assert!(
+            ServerConfig::builder()
+                .multi_master(multi_master.clone())
+                .replication(replication)
+                .build()
+                .is_err()
+        );
+        assert!(
+            ServerConfig::builder()
+                .multi_master(multi_master)
+                .build()
+                .is_ok()
+        );
+
+        let mirror = MirrorConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        assert!(ServerConfig::builder().mirror(mirror).build().is_err());
+
+        let admin = AdminConfig {
+            enabled: true,
+            addr: DEFAULT_ADDR.into(),
+            ..Default::default()
+        };
+        assert!(ServerConfig::builder().admin(admin).build().is_err());
+
+        let overload = OverloadConfig {
+            enabled: true,
+            max_in_flight: 0,
+            ..Default::default()
+        };
+        assert!(ServerConfig::builder().overload(overload).build().is_err());
+
+        let scheduler = SchedulerConfig {
+            jobs: [(
+                "gossip".to_string(),
+                JobConfig {
+                    interval_ms: Some(0),
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let err = ServerConfig::builder()
+            .scheduler(scheduler)
+            .build()
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("scheduler.jobs.gossip.interval_ms")
+        );
+
+        let commands = CommandsConfig {
+            deny: vec!["HGETALL".into(), "flushdb".into()],
+            ..Default::default()
+        };
+        let err = ServerConfig::builder()
+            .commands(commands)
+            .build()
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("commands.deny has unknown command flushdb")
+        );
+        assert!(!err.to_string().contains("HGETALL"));
+
+        let commands = CommandsConfig {
+            rename: [("auth".to_string(), "login".to_string())].into(),
+            ..Default::default()
+        };
+        let err = ServerConfig::builder()
+            .commands(commands)
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("can not rename auth"));
+
+        let shadow = ShadowConfig {
+            enabled: true,
+            sample_ratio: 2.0,
+            ..Default::default()
+        };
+        let err = ServerConfig::builder().shadow(shadow).build().unwrap_err();
+        assert!(err.to_string().contains("shadow.remote is required"));
+        assert!(err.to_string().contains("shadow.sample_ratio"));
+
+        let idempotency = IdempotencyConfig {
+            ttl_ms: 0,
+            ..Default::default()
+        };
+        let err = ServerConfig::builder()
+            .idempotency(idempotency)
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("idempotency.ttl_ms"));
+
+        let memcached = MemcachedConfig {
+            enabled: true,
+            table: "".into(),
+            ..Default::default()
+        };
+        let err = ServerConfig::builder()
+            .memcached(memcached)
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("memcached.table"));
+
+        // memcached 的连接会绕过密码和访问控制
+        let memcached = MemcachedConfig {
+            enabled: true,
+            local_only: false,
+            ..Default::default()
+        };
+        let auth = AuthConfig {
+            default_role: Some(AccessRole::ReadOnly),
+            ..Default::default()
+        };
+        let err = ServerConfig::builder()
+            .memcached(memcached)
+            .auth(auth)
+            .build()
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("memcached can not be enabled with auth")
+        );
+
+        let snapshot = SnapshotConfig {
+            enabled: true,
+            interval_ms: 0,
+            ..Default::default()
+        };
+        let err = ServerConfig::builder()
+            .storage(StorageConfig::SledDb("/tmp/kv".into()))
+            .snapshot(snapshot)
+            .build()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("snapshot only works with memory storage"));
+        assert!(err.contains("snapshot.interval_ms"));
+
+        let read_cache = ReadCacheConfig {
+            capacity: 1024,
+            ttl_ms: 0,
+            ..Default::default()
+        };
+        let err = ServerConfig::builder()
+            .read_cache(read_cache)
+            .build()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("read_cache only works with sled storage"));
+        assert!(err.contains("read_cache.ttl_ms"));
+
+        let read_cache = ReadCacheConfig {
+            fast_capacity: 0,
+            ..Default::default()
+        };
+        let err = ServerConfig::builder()
+            .storage(StorageConfig::CachedSledDb("/tmp/kv".into()))
+            .read_cache(read_cache)
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("read_cache.fast_capacity"));
+    }
+
+    #[test]
+    fn storage_and_log_level_should_parse_from_str() {
+        assert_eq!(
+            "memory".parse::<StorageConfig>().unwrap(),
+            StorageConfig::MemTable
+        );
+        assert_eq!(
+            "memory-ordered".parse::<StorageConfig>().unwrap(),
+            StorageConfig::MemTableOrdered
+        );
+        assert_eq!(
+            "sled:/tmp/kv".parse::<StorageConfig>().unwrap(),
+            StorageConfig::SledDb("/tmp/kv".into())
+        );
+        assert_eq!(
+            "cached-sled:/tmp/kv".parse::<StorageConfig>().unwrap(),
+            StorageConfig::CachedSledDb("/tmp/kv".into())
+        );
+        assert!("sled:".parse::<StorageConfig>().is_err());
+        assert!("rocksdb:/tmp/kv".parse::<StorageConfig>().is_err());
+
+        assert_eq!("Debug".parse::<LogLevel>().unwrap(), LogLevel::Debug);
+        assert!("verbose".parse::<LogLevel>().is_err());
+    }
+
+    #[test]
+    fn mirror_should_select_tables() {
+        let mut mirror = MirrorConfig::default();
+        assert!(mirror.selects("t1"));
+
+        mirror.tables = vec!["t1".into(), "t2".into()];
+        mirror.exclude_tables = vec!["t2".into()];
+        assert!(mirror.selects("t1"));
+        assert!(!mirror.selects("t2"));
+        assert!(!mirror.selects("t3"));
+    }
+
+    #[test]
+    fn tls_pem_should_be_loaded_from_files() {
+        let config: ServerConfig = toml::from_str(&format!(
+            r#"
+            [general]
+            addr = "127.0.0.1:9527"
+
+            [storage]
+            type = "MemTable"
+
+            [tls]
+            cert_path = "{dir}/fixtures/server.cert"
+            key_path = "{dir}/fixtures/server.key"
+
+            [log]
+            path = "/tmp/kv-log"
+            rotation = "Daily"
+            log_level = "Info"
+            enable_log_file = false
+            "#,
+            dir = env!("CARGO_MANIFEST_DIR")
+        ))
+        .unwrap();
+        config.validate().unwrap();
+
+        let pem = config.tls.unwrap().load_pem().unwrap();
+        assert_eq!(pem.cert, include_str!("../fixtures/server.cert"));
+        assert_eq!(pem.key, include_str!("../fixtures/server.key"));
+        assert_eq!(pem.ca, None);
+
+        // 内嵌的 PEM 依旧可用
+        let config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf")).unwrap();
+        let pem = config.tls.unwrap().load_pem().unwrap();
+        assert!(pem.ca.is_some());
+        assert!(pem.identity.is_none());
+    }
+
     #[test]
     fn client_config_should_be_loaded() {
         let result: Result<ClientConfig, toml::de::Error> =
             toml::from_str(include_str!("../fixtures/client.conf"));
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn noise_config_should_be_loaded_and_validated() {
+        let server: ServerConfig =
+            toml::from_str(include_str!("../fixtures_noise/server.conf")).unwrap();
+        assert_eq!(server.general.security, Security::Noise);
+        assert_eq!(server.noise.as_ref().unwrap().load_key().unwrap().len(), 32);
+        assert!(server.validate().is_ok());
+
+        let client: ClientConfig =
+            toml::from_str(include_str!("../fixtures_noise/client.conf")).unwrap();
+        let keys = client.noise.as_ref().unwrap().load_keys().unwrap();
+        assert!(keys.key.is_some() && keys.server_pub.is_some());
+        assert!(client.validate().is_ok());
+
+        // security = "noise" 时必须有 [noise]，IK/NK 必须知道服务器公钥
+        let mut server = server;
+        server.noise = None;
+        assert!(server.validate().is_err());
+        let mut client = client;
+        let noise = client.noise.as_mut().unwrap();
+        noise.server_pub = String::new();
+        noise.pattern = NoisePattern::IK;
+        assert!(client.validate().is_err());
+    }
+
+    #[test]
+    fn noise_psk_should_be_loaded_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("noise.psk");
+        fs::write(&path, include_str!("../fixtures_noise/client.key")).unwrap();
+        let path = path.display().to_string();
+
+        let config: ServerConfig =
+            toml::from_str(include_str!("../fixtures_noise/server.conf")).unwrap();
+        let noise = config.noise.as_ref().unwrap();
+        assert_eq!(noise.load_psk().unwrap(), None);
+        let config = config
+            .apply_vars(vars(&[("KV_NOISE_PSK_PATH", &path)]))
+            .unwrap();
+        let psk = config.noise.as_ref().unwrap().load_psk().unwrap();
+        assert_eq!(psk.map(|psk| psk.len()), Some(32));
+
+        // PSK 文件不存在或者格式不对时报告出来
+        let mut config = config;
+        config.noise.as_mut().unwrap().psk_path = Some(format!("{}.missing", path));
+        assert!(config.validate().is_err());
+        let mut client: ClientConfig =
+            toml::from_str(include_str!("../fixtures_noise/client.conf")).unwrap();
+        client.noise.as_mut().unwrap().psk = "not a key".into();
+        assert!(client.validate().is_err());
+    }
 }
@@ -0,0 +1,158 @@
+//! 离线查看数据目录，kv-dump 的实现
+//!
+//! 服务器停止之后直接打开 sled 的数据目录，列出 table，按 Value 的类型输出其中的 kv pair，
+//! 或者导出成 JSON。服务器还在运行时数据目录被锁住，打开会失败
+
+use crate::{KvError, SledDb, Storage, format_value, is_reserved_table};
+use serde::Serialize;
+use serde::ser::{Error as _, SerializeMap, Serializer};
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// 打开已经存在的数据目录。sled 打开不存在的目录时会创建一个空的数据库，这里先检查
+pub fn open(path: impl AsRef<Path>) -> Result<SledDb, KvError> {
+    let path = path.as_ref();
+    if !path.is_dir() {
+        return Err(KvError::NotFound(format!(
+            "data directory {}",
+            path.display()
+        )));
+    }
+    SledDb::open(path)
+}
+
+/// 除了内部 table 以外所有的 table 和它们的 key 数
+pub fn table_stats(store: &dyn Storage) -> Result<Vec<(String, usize)>, KvError> {
+    store
+        .tables()?
+        .into_iter()
+        .filter(|table| !is_reserved_table(table))
+        .map(|table| {
+            let count = store.get_iter(&table)?.count();
+            Ok((table, count))
+        })
+        .collect()
+}
+
+/// 要输出的 table：指定了 table 时只输出它（不存在时报错），否则输出除了内部 table 以外所有的 table
+pub fn select_tables(store: &dyn Storage, table: Option<&str>) -> Result<Vec<String>, KvError> {
+    match table {
+        Some(table) if store.table_exists(table)? => Ok(vec![table.into()]),
+        Some(table) => Err(KvError::TableNotFound(table.into())),
+        None => Ok(store
+            .tables()?
+            .into_iter()
+            .filter(|table| !is_reserved_table(table))
+            .collect()),
+    }
+}
+
+/// 每个 kv pair 输出一行，比如 `t1/k1 => (integer) 10`，返回输出的 key 数
+pub fn write_text(
+    store: &dyn Storage,
+    tables: &[String],
+    out: &mut dyn Write,
+) -> Result<usize, KvError> {
+    let mut count = 0;
+    for table in tables {
+        for pair in store.get_iter(table)? {
+            let value = pair.value.unwrap_or_default();
+            writeln!(out, "{}/{} => {}", table, pair.key, format_value(&value))?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// 导出成 `{"table": {"key": value, ...}, ...}`，value 的格式和 Value 的 JSON 序列化一样。
+/// 一边遍历一边写，不会把整个 table 读进内存。返回导出的 key 数
+pub fn write_json(
+    store: &dyn Storage,
+    tables: &[String],
+    out: impl Write,
+) -> Result<usize, KvError> {
+    let count = Cell::new(0);
+    let mut serializer = serde_json::Serializer::pretty(out);
+    let mut map = serializer.serialize_map(None).map_err(io::Error::from)?;
+    for table in tables {
+        let pairs = TablePairs {
+            store,
+            table,
+            count: &count,
+        };
+        map.serialize_entry(table, &pairs)
+            .map_err(io::Error::from)?;
+    }
+    map.end().map_err(io::Error::from)?;
+    let mut out = serializer.into_inner();
+    writeln!(out)?;
+    Ok(count.get())
+}
+
+/// 序列化时才遍历 table
+struct TablePairs<'a> {
+    store: &'a dyn Storage,
+    table: &'a str,
+    count: &'a Cell<usize>,
+}
+
+impl Serialize for TablePairs<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let pairs = self.store.get_iter(self.table).map_err(S::Error::custom)?;
+        let mut map = serializer.serialize_map(None)?;
+        for pair in pairs {
+            map.serialize_entry(&pair.key, &pair.value.unwrap_or_default())?;
+            self.count.set(self.count.get() + 1);
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+    use tempfile::tempdir;
+
+    #[test]
+    fn data_directory_should_be_dumped() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("db");
+        assert!(matches!(open(&path), Err(KvError::NotFound(_))));
+
+        let store = SledDb::new(&path);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), 10.into()).unwrap();
+        store.set("t2", "k1".into(), vec![1u8, 2].into()).unwrap();
+        // 服务器还在运行时数据目录被锁住
+        assert!(open(&path).is_err());
+
+        assert_eq!(
+            table_stats(&store).unwrap(),
+            vec![("t1".to_string(), 2), ("t2".to_string(), 1)]
+        );
+        assert!(matches!(
+            select_tables(&store, Some("t3")),
+            Err(KvError::TableNotFound(_))
+        ));
+
+        let tables = select_tables(&store, Some("t1")).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(write_text(&store, &tables, &mut out).unwrap(), 2);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "t1/k1 => \"v1\"\nt1/k2 => (integer) 10\n"
+        );
+
+        let tables = select_tables(&store, None).unwrap();
+        let mut out = Vec::new();
+        assert_eq!(write_json(&store, &tables, &mut out).unwrap(), 3);
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["t1"]["k1"], "v1");
+        assert_eq!(json["t1"]["k2"], 10);
+        // 二进制的 value 可以原样读回来
+        let value: Value = serde_json::from_value(json["t2"]["k1"].clone()).unwrap();
+        assert_eq!(value, Value::from(vec![1u8, 2]));
+    }
+}
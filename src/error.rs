@@ -1,3 +1,4 @@
+use std::error::Error as StdError;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -15,13 +16,14 @@ pub enum KvError {
     EncodeError(#[from] prost::EncodeError),
     #[error("Failed to decode protobuf message")]
     DecodeError(#[from] prost::DecodeError),
-    #[error("Failed to access sled db")]
-    SledError(#[from] sled::Error),
-    #[error("I/O error")]
-    IoError(#[from] std::io::Error),
+    /// 存储引擎（sled 等）返回的错误
+    #[error("Storage backend error: {0}")]
+    Storage(#[source] Box<dyn StdError + Send + Sync>),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
 
-    #[error("Failed to build initiator")]
-    NoiseBuildInitiatorError(#[from] snow::error::Error),
+    #[error("Noise error: {0}")]
+    Noise(#[from] snow::error::Error),
 
     #[error("Internal error: {0}")]
     Internal(String),
@@ -31,15 +33,170 @@ pub enum KvError {
 
     #[error("Not found for table: {0}")]
     TableNotFound(String),
-    #[error("Frame is larger than max size")]
-    FrameError,
+    #[error("Invalid frame: {0}")]
+    Frame(String),
+    /// frame 超过了长度上限，已经被跳过，连接可以继续使用
+    #[error("Frame too large: {0}")]
+    FrameTooLarge(String),
+    /// 收到了完整的 frame 但是无法 decode，已经被跳过，连接可以继续使用
+    #[error("Malformed frame: {0}")]
+    MalformedFrame(String),
 
-    #[error("Certificate parse error: error to load {0} {0}")]
+    #[error("Certificate parse error: error to load {0} {1}")]
     CertifcateParseError(&'static str, &'static str),
 
-    #[error("TLS error")]
-    TlsError(#[from] tokio_rustls::rustls::TLSError),
+    #[error("TLS error: {0}")]
+    Tls(#[from] tokio_rustls::rustls::TLSError),
 
     #[error("Parse config error")]
     ConfigError(#[from] toml::de::Error),
+
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+
+    #[error("Yamux connection error")]
+    ConnectionError(#[from] yamux::ConnectionError),
+
+    #[error("Request timed out: {0}")]
+    Timeout(String),
+
+    #[error("Stream {0} timed out")]
+    StreamTimeout(&'static str),
+
+    #[error("Not leader, leader is {0}")]
+    NotLeader(String),
+
+    #[error("Slot {0} is owned by {1}")]
+    Moved(u16, String),
+
+    #[error("Writes are fenced: {0}")]
+    Fenced(String),
+
+    #[error("Server busy, retry later: {0}")]
+    Busy(String),
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Command disabled: {0}")]
+    CommandDisabled(String),
+
+    #[error("Result too large: more than {0} entries")]
+    ResultTooLarge(usize),
+
+    #[error("Request too large: {0}")]
+    RequestTooLarge(String),
+
+    #[error("Entry too large: {0}")]
+    EntryTooLarge(String),
+
+    #[error("Too many subscribers: {0}")]
+    TooManySubscribers(String),
+
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+}
+
+/// 错误的类别，决定调用方是否应该重试
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// 暂时性的错误，比如超时、连接断开、leader 切换，稍后重试可能成功
+    Transient,
+    /// 请求本身有问题或者被拒绝，重试也不会成功
+    Permanent,
+}
+
+impl KvError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            KvError::Timeout(_)
+            | KvError::StreamTimeout(_)
+            | KvError::ConnectionError(_)
+            | KvError::NotLeader(_)
+            | KvError::Moved(..)
+            | KvError::Fenced(_)
+            | KvError::Busy(_) => ErrorKind::Transient,
+            KvError::Io(e) if is_transient_io(e) => ErrorKind::Transient,
+            _ => ErrorKind::Permanent,
+        }
+    }
+
+    /// 客户端只重试暂时性的错误，集群客户端遇到这类错误时切换到下一个节点
+    pub fn is_retryable(&self) -> bool {
+        self.kind() == ErrorKind::Transient
+    }
+}
+
+/// 连接相关的 I/O 错误，重新连接之后可能恢复
+fn is_transient_io(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(
+        e.kind(),
+        ConnectionRefused
+            | ConnectionReset
+            | ConnectionAborted
+            | NotConnected
+            | BrokenPipe
+            | TimedOut
+            | Interrupted
+            | UnexpectedEof
+            | WouldBlock
+    )
+}
+
+impl From<sled::Error> for KvError {
+    fn from(e: sled::Error) -> Self {
+        KvError::Storage(Box::new(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CommandResponse;
+
+    #[test]
+    fn errors_should_map_to_distinct_status() {
+        let io = KvError::from(std::io::Error::from(std::io::ErrorKind::BrokenPipe));
+        let errors = [
+            (KvError::Timeout("hget".into()), 504),
+            (KvError::StreamTimeout("write"), 408),
+            (io, 502),
+            (KvError::Frame("too large".into()), 413),
+            (KvError::MalformedFrame("bad".into()), 400),
+            (KvError::ResultTooLarge(1000), 413),
+            (KvError::EntryTooLarge("value of t1/k1".into()), 413),
+            (KvError::FrameTooLarge("frame of 1024 bytes".into()), 413),
+            (KvError::RequestTooLarge("hmset has 2 entries".into()), 413),
+            (KvError::TooManySubscribers("lobby".into()), 429),
+            (KvError::QuotaExceeded("max_tables".into()), 429),
+            (KvError::Storage("disk full".into()), 507),
+            (KvError::Noise(snow::Error::Decrypt), 401),
+            (KvError::Internal("oops".into()), 500),
+        ];
+        for (e, status) in errors {
+            assert_eq!(CommandResponse::from(e).status, status);
+        }
+    }
+
+    #[test]
+    fn errors_should_be_classified() {
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert_eq!(KvError::from(reset).kind(), ErrorKind::Transient);
+        assert!(KvError::NotLeader("127.0.0.1:9527".into()).is_retryable());
+        assert!(KvError::Fenced("lease expired".into()).is_retryable());
+
+        let missing = std::io::Error::from(std::io::ErrorKind::NotFound);
+        assert_eq!(KvError::from(missing).kind(), ErrorKind::Permanent);
+        assert!(!KvError::NotFound("k1".into()).is_retryable());
+        assert!(!KvError::PermissionDenied("admin".into()).is_retryable());
+        assert!(!KvError::Unauthorized("invalid password".into()).is_retryable());
+    }
+
+    #[test]
+    fn errors_should_keep_source() {
+        let e = KvError::from(std::io::Error::other("boom"));
+        assert_eq!(e.to_string(), "I/O error: boom");
+        assert_eq!(e.source().unwrap().to_string(), "boom");
+    }
 }
@@ -0,0 +1,97 @@
+//! fuzz target 的入口，cargo-fuzz 之类的工具直接调用这里的函数
+//!
+//! 使用 strict 模式解码，任意输入都只能返回错误，不能 panic，也不能按 frame 声明的长度分配内存
+
+use crate::{CommandRequest, CommandResponse, FrameCoder, FrameLimits, KvError, read_frame_with};
+use bytes::BytesMut;
+use futures::executor::block_on;
+
+/// fuzz 时的限制比服务器默认的小，更容易触发超过上限的情况
+fn limits() -> FrameLimits {
+    FrameLimits::strict(1024 * 1024, 4 * 1024 * 1024)
+}
+
+/// 把数据当作一个完整的 frame，decode 成 CommandRequest
+pub fn decode_request(data: &[u8]) -> Result<CommandRequest, KvError> {
+    CommandRequest::decode_frame_with(&mut BytesMut::from(data), &limits())
+}
+
+/// 把数据当作一个完整的 frame，decode 成 CommandResponse
+pub fn decode_response(data: &[u8]) -> Result<CommandResponse, KvError> {
+    CommandResponse::decode_frame_with(&mut BytesMut::from(data), &limits())
+}
+
+/// 和服务器处理客户端数据的过程一样，先从 stream 读出 frame 再 decode
+pub fn read_request(mut data: &[u8]) -> Result<CommandRequest, KvError> {
+    let limits = limits();
+    let mut buf = BytesMut::new();
+    block_on(read_frame_with(&mut data, &mut buf, &limits))?;
+    CommandRequest::decode_frame_with(&mut buf, &limits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Compression, write::GzEncoder};
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use std::io::Write;
+
+    fn frame(header: u32, payload: &[u8]) -> Vec<u8> {
+        let mut data = header.to_be_bytes().to_vec();
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn malformed_frames_should_be_rejected() {
+        let bomb = gzip(&vec![0u8; 8 * 1024 * 1024]);
+        let mut trailing = gzip(b"");
+        trailing.extend_from_slice(b"garbage");
+        let inputs = [
+            vec![],
+            vec![0, 0],
+            frame(0x7fff_ffff, b""),
+            frame(10, b"short"),
+            frame(5, &[0xff; 5]),
+            frame((1 << 31) | 5, b"plain"),
+            frame((1 << 31) | bomb.len() as u32, &bomb),
+            frame((1 << 31) | trailing.len() as u32, &trailing),
+        ];
+        for input in inputs {
+            assert!(decode_request(&input).is_err());
+            assert!(decode_response(&input).is_err());
+            assert!(read_request(&input).is_err());
+        }
+    }
+
+    #[test]
+    fn random_input_should_not_panic() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..1000 {
+            let len = rng.gen_range(0..64);
+            let mut data: Vec<u8> = (0..len).map(|_| rng.r#gen()).collect();
+            // 一半的输入使用合理的长度，让数据能走到 protobuf 解码
+            if len >= 4 && rng.gen_bool(0.5) {
+                data[..4].copy_from_slice(&((len - 4) as u32).to_be_bytes());
+            }
+            let _ = decode_request(&data);
+            let _ = decode_response(&data);
+            let _ = read_request(&data);
+        }
+    }
+
+    #[test]
+    fn valid_frame_should_be_decoded() {
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let mut buf = BytesMut::new();
+        cmd.encode_frame(&mut buf).unwrap();
+        assert_eq!(decode_request(&buf).unwrap(), cmd);
+        assert_eq!(read_request(&buf).unwrap(), cmd);
+    }
+}
@@ -0,0 +1,120 @@
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use kv::{
+    BoxedStream, ClientConfig, CommandRequest, CommandResponse, YamuxCtrl, format_response,
+    start_client_with_config,
+};
+use std::process;
+
+/// KV 服务器的运维工具，使用 client.conf 里的 TLS 证书和密码连接服务器的管理端口
+#[derive(Debug, Parser)]
+#[command(name = "kv-admin", version, about)]
+struct Args {
+    /// 客户端配置文件，不指定时使用内置的 fixtures/client.conf
+    #[arg(short, long, env = "KV_CLIENT_CONFIG")]
+    config: Option<String>,
+    /// 服务器地址，覆盖配置文件里的值。一般是 admin.addr，数据端口上只有 admin 角色的证书可以执行运维命令
+    #[arg(long)]
+    addr: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// 列出所有的客户端连接
+    Clients,
+    /// 断开客户端连接
+    Kill {
+        /// CLIENT LIST 里的连接 id
+        #[arg(long, conflicts_with = "addr", required_unless_present = "addr")]
+        id: Option<u64>,
+        /// 客户端地址，比如 127.0.0.1:52110
+        #[arg(long)]
+        addr: Option<String>,
+    },
+    /// 每种命令最近的延迟
+    Latency,
+    /// 后台任务的状态
+    Jobs,
+    /// 集群成员和 slot 的分配
+    Cluster,
+    /// 把 replica 提升为 primary
+    Promote {
+        #[arg(long, default_value_t = 0)]
+        epoch: u64,
+    },
+    /// 把所有数据写到服务器备份目录（admin.backup_dir）里的文件
+    Backup { name: String },
+    /// 把服务器备份目录里的备份读回存储，已有的 key 被覆盖
+    Restore { name: String },
+    /// 让服务器重新读取配置文件，和发送 SIGHUP 一样
+    Reload,
+    /// 删除所有 table 里的数据，不能恢复
+    Flushall {
+        /// 确认删除，必须是 FLUSHALL
+        #[arg(long)]
+        confirm: String,
+    },
+}
+
+impl Command {
+    fn requests(&self) -> Vec<CommandRequest> {
+        match self {
+            Command::Clients => vec![CommandRequest::new_client_list()],
+            Command::Kill { id: Some(id), .. } => vec![CommandRequest::new_client_kill(*id)],
+            Command::Kill { addr, .. } => vec![CommandRequest::new_client_kill_addr(
+                addr.clone().unwrap_or_default(),
+            )],
+            Command::Latency => vec![CommandRequest::new_latency()],
+            Command::Jobs => vec![CommandRequest::new_jobs()],
+            Command::Cluster => vec![
+                CommandRequest::new_cluster(),
+                CommandRequest::new_cluster_slots(),
+            ],
+            Command::Promote { epoch } => vec![CommandRequest::new_promote(*epoch)],
+            Command::Backup { name } => vec![CommandRequest::new_save(name)],
+            Command::Restore { name } => vec![CommandRequest::new_restore(name)],
+            Command::Reload => vec![CommandRequest::new_reload()],
+            Command::Flushall { confirm } => vec![CommandRequest::new_flushall(confirm)],
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut config = match &args.config {
+        Some(path) => ClientConfig::load(path)?,
+        None => toml::from_str(include_str!("../fixtures/client.conf"))?,
+    };
+    if let Some(addr) = args.addr {
+        config.general.addr = addr;
+    }
+    let mut ctrl = start_client_with_config(&config).await?;
+
+    let mut failed = false;
+    for cmd in args.command.requests() {
+        let name = cmd.name();
+        let res = execute(&mut ctrl, cmd).await?;
+        // 没有开启分片时 CLUSTER SLOTS 返回错误，只显示成员
+        if name == "cluster_slots" && res.status >= 400 {
+            continue;
+        }
+        println!("{}", format_response(&res));
+        failed |= res.status >= 400;
+    }
+    // 错误已经打印出来了，脚本通过退出码判断是否成功
+    if failed {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+async fn execute(
+    ctrl: &mut YamuxCtrl<BoxedStream>,
+    cmd: CommandRequest,
+) -> Result<CommandResponse> {
+    let res = ctrl.open_stream().await?.execute_unary(cmd).await?;
+    Ok(res)
+}
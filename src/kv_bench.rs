@@ -0,0 +1,70 @@
+use anyhow::Result;
+use clap::Parser;
+use kv::ClientConfig;
+use kv::bench::{self, BenchConfig, KeyDistribution, Mix};
+use std::time::Duration;
+
+/// KV 服务器的压测工具，按比例发送 HSET/HGET/PUBLISH，输出每种命令的吞吐和延迟
+#[derive(Debug, Parser)]
+#[command(name = "kv-bench", version, about)]
+struct Args {
+    /// 客户端配置文件，不指定时使用内置的 fixtures/client.conf
+    #[arg(short, long, env = "KV_CLIENT_CONFIG")]
+    config: Option<String>,
+    /// 服务器地址，覆盖配置文件里的值
+    #[arg(long)]
+    addr: Option<String>,
+    /// 并发的连接数
+    #[arg(short = 'n', long, default_value_t = 16)]
+    concurrency: usize,
+    /// 持续的秒数
+    #[arg(short, long, default_value_t = 10)]
+    duration: u64,
+    /// 命令的比例，比如 hset=1,hget=9,publish=0
+    #[arg(long, default_value = "hset=1,hget=1")]
+    mix: Mix,
+    /// key 的数量
+    #[arg(long, default_value_t = 10_000)]
+    keys: usize,
+    /// key 的分布：uniform、zipf 或者 zipf:<指数>
+    #[arg(long, default_value = "uniform")]
+    distribution: KeyDistribution,
+    /// value 的字节数
+    #[arg(long, default_value_t = 64)]
+    value_size: usize,
+    #[arg(long, default_value = "bench")]
+    table: String,
+    #[arg(long, default_value = "bench")]
+    topic: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut client = match &args.config {
+        Some(path) => ClientConfig::load(path)?,
+        None => toml::from_str(include_str!("../fixtures/client.conf"))?,
+    };
+    if let Some(addr) = args.addr {
+        client.general.addr = addr;
+    }
+
+    let config = BenchConfig {
+        concurrency: args.concurrency,
+        duration: Duration::from_secs(args.duration),
+        mix: args.mix,
+        keys: args.keys,
+        distribution: args.distribution,
+        value_size: args.value_size,
+        table: args.table,
+        topic: args.topic,
+        ..BenchConfig::new(client)
+    };
+    println!(
+        "Running {}s against {} with {} connections",
+        args.duration, config.client.general.addr, config.concurrency
+    );
+    let report = bench::run(&config).await?;
+    print!("{}", report);
+    Ok(())
+}
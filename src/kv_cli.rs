@@ -0,0 +1,116 @@
+use anyhow::Result;
+use futures::StreamExt;
+use kv::{
+    BoxedStream, ClientConfig, CommandRequest, YamuxCtrl, command_request::RequestData,
+    decode_change, format_change, format_response, parse_args, parse_command,
+    start_client_with_config,
+};
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+use std::env;
+use tokio::signal;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let config = match env::var("KV_CLIENT_CONFIG") {
+        Ok(path) => ClientConfig::load(&path)?,
+        Err(_) => toml::from_str(include_str!("../fixtures/client.conf"))?,
+    };
+
+    let mut ctrl = start_client_with_config(&config).await?;
+
+    // 带参数时只执行一条命令，比如 `kv-cli HSET t1 k v`
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        let cmd = config.commands.renamed(parse_args(&args)?);
+        return execute(&mut ctrl, cmd).await;
+    }
+
+    let mut rl = DefaultEditor::new()?;
+    loop {
+        match rl.readline("kv> ") {
+            Ok(line) => {
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                if line.eq_ignore_ascii_case("quit") || line.eq_ignore_ascii_case("exit") {
+                    break;
+                }
+                let _ = rl.add_history_entry(line);
+
+                let result = match parse_command(line) {
+                    Ok(cmd) => execute(&mut ctrl, config.commands.renamed(cmd)).await,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = result {
+                    println!("(error) {}", e);
+                }
+            }
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// 在一个新的 stream 上执行命令并打印结果，SUBSCRIBE 和 HWATCH 会一直打印收到的消息直到 Ctrl-C
+async fn execute(ctrl: &mut YamuxCtrl<BoxedStream>, cmd: CommandRequest) -> Result<()> {
+    let mut stream = ctrl.open_stream().await?;
+
+    let sub = match &cmd.request_data {
+        Some(RequestData::Subscribe(sub)) => sub,
+        Some(RequestData::Hwatch(watch)) => {
+            let mut res = stream.execute_stream(&cmd).await?;
+            match watch.table.as_str() {
+                "" => println!("Watching all tables, press Ctrl-C to stop"),
+                table => println!("Watching {}, press Ctrl-C to stop", table),
+            }
+            if watch.snapshot
+                && let Some(snapshot) = res.next().await
+            {
+                println!("{}", format_response(&snapshot?));
+            }
+            // 断开 stream 就是取消 watch
+            loop {
+                tokio::select! {
+                    data = res.next() => match data {
+                        Some(Ok(data)) => println!("{}", format_change(&decode_change(&data)?)),
+                        _ => break,
+                    },
+                    _ = signal::ctrl_c() => break,
+                }
+            }
+            return Ok(());
+        }
+        _ => {
+            let res = stream.execute_unary(cmd).await?;
+            println!("{}", format_response(&res));
+            return Ok(());
+        }
+    };
+
+    let topic = sub.topic.clone();
+    let mut res = stream
+        .execute_stream(&cmd)
+        .await?
+        .unsubscribe_on_drop(ctrl.clone(), &topic);
+    let id = res.id;
+    println!("Subscribed to {} (id {}), press Ctrl-C to stop", topic, id);
+
+    loop {
+        tokio::select! {
+            data = res.next() => match data {
+                Some(Ok(data)) => println!("{}", format_response(&data)),
+                _ => break,
+            },
+            _ = signal::ctrl_c() => break,
+        }
+    }
+
+    // 退出前取消订阅，等服务器确认之后再退出
+    res.unsubscribe().await?;
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use super::KvClient;
+use crate::{CommandRequest, CommandResponse, KvError, command_request::RequestData};
+
+/// 批量执行多个命令的 builder，通过 `KvClient::batch` 创建
+///
+/// 所有命令在同一个 stream 上以 pipeline 的方式发出，响应按命令顺序返回。
+/// 服务器逐条执行这些命令，中途失败的命令不会影响其它命令，也不会回滚
+pub struct Batch<'a> {
+    client: &'a mut KvClient,
+    cmds: Vec<CommandRequest>,
+}
+
+impl<'a> Batch<'a> {
+    pub(crate) fn new(client: &'a mut KvClient) -> Self {
+        Self {
+            client,
+            cmds: Vec::new(),
+        }
+    }
+
+    /// 加入一个命令
+    #[allow(clippy::should_implement_trait)]
+    pub fn add(mut self, cmd: CommandRequest) -> Self {
+        self.cmds.push(cmd);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.cmds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cmds.is_empty()
+    }
+
+    /// 执行所有命令，返回每个命令各自的响应
+    pub async fn execute(self) -> Result<Vec<CommandResponse>, KvError> {
+        // SUBSCRIBE 会返回一个持续的流，没法和其它命令一起 pipeline
+        if let Some(cmd) = self
+            .cmds
+            .iter()
+            .find(|cmd| matches!(cmd.request_data, Some(RequestData::Subscribe(_))))
+        {
+            return Err(KvError::InvalidCommand(format!(
+                "{} is not allowed in batch",
+                cmd.name()
+            )));
+        }
+        if self.cmds.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.client.execute_pipeline(self.cmds).await
+    }
+}
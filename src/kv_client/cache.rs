@@ -0,0 +1,107 @@
+use crate::Value;
+use crate::storage::LruCache;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// KvClient 的 HGET 读缓存
+///
+/// 每个被缓存的 table 都订阅了它的 keyspace 主题，收到通知时让对应的 key 失效。
+/// generation 在每次失效时递增：读请求发出前记下 generation，响应回来时如果
+/// generation 已经变了，说明期间有写入，这个响应就不再放进缓存
+pub struct ReadCache {
+    inner: Mutex<CacheInner>,
+}
+
+struct CacheInner {
+    entries: LruCache<(String, String), Value>,
+    generation: u64,
+    tables: HashSet<String>,
+}
+
+impl ReadCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(CacheInner {
+                entries: LruCache::new(capacity),
+                generation: 0,
+                tables: HashSet::new(),
+            }),
+        }
+    }
+
+    pub fn get(&self, table: &str, key: &str) -> Option<Value> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.get(&(table.into(), key.into()))
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.inner.lock().unwrap().generation
+    }
+
+    /// 只有 generation 没变、table 仍在订阅中时才写入缓存
+    pub fn insert(&self, generation: u64, table: &str, key: &str, value: Value) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.generation == generation && inner.tables.contains(table) {
+            inner.entries.insert((table.into(), key.into()), value);
+        }
+    }
+
+    pub fn invalidate(&self, table: &str, key: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        inner.entries.remove(&(table.into(), key.into()));
+    }
+
+    /// 让 table 里以 prefix 开头的 key 都失效
+    pub fn invalidate_prefix(&self, table: &str, prefix: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        inner
+            .entries
+            .retain(|(t, k)| t != table || !k.starts_with(prefix));
+    }
+
+    pub fn is_watching(&self, table: &str) -> bool {
+        self.inner.lock().unwrap().tables.contains(table)
+    }
+
+    pub fn watch(&self, table: &str) {
+        self.inner.lock().unwrap().tables.insert(table.into());
+    }
+
+    /// 订阅断开后收不到通知了，丢弃这个 table 的所有缓存
+    pub fn unwatch(&self, table: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        inner.tables.remove(table);
+        inner.entries.retain(|(t, _)| t != table);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_cache_should_skip_stale_insert() {
+        let cache = ReadCache::new(16);
+
+        // 没有订阅的 table 不缓存
+        cache.insert(cache.generation(), "t1", "k1", "v1".into());
+        assert!(cache.get("t1", "k1").is_none());
+
+        cache.watch("t1");
+        let generation = cache.generation();
+        cache.insert(generation, "t1", "k1", "v1".into());
+        assert_eq!(cache.get("t1", "k1"), Some("v1".into()));
+
+        // 读请求进行中发生了写入，旧的响应不能放进缓存
+        let generation = cache.generation();
+        cache.invalidate("t1", "k2");
+        cache.insert(generation, "t1", "k2", "v2".into());
+        assert!(cache.get("t1", "k2").is_none());
+
+        cache.unwatch("t1");
+        assert!(cache.get("t1", "k1").is_none());
+    }
+}
@@ -0,0 +1,309 @@
+use super::{ClientMetrics, KvClient};
+use crate::{
+    ClientConfig, CommandRequest, CommandResponse, KvError, Routing, SlotMap,
+    command_request::RequestData, key_slot, start_client_with_config,
+};
+use http::StatusCode;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{info, warn};
+
+/// 集群里的一个服务器
+struct Node {
+    addr: String,
+    client: KvClient,
+    healthy: Arc<AtomicBool>,
+}
+
+/// 连接多个服务器的客户端
+///
+/// 按 `ClusterConfig::routing` 选择服务器，某个服务器出现网络错误时标记为不可用，
+/// 请求转到下一个可用的服务器；后台任务定期检查不可用的服务器，恢复后重新启用。
+/// 各个服务器之间不同步数据，KeyHash 路由下 failover 后读到的是另一个服务器上的数据。
+/// Slots 路由下从服务器获取分片集群的 slot 分布，服务器返回 421 时重新获取再重试一次。
+/// 服务器返回 503 时（比如自动故障转移之后原来的 primary 拒绝写操作）把请求发给下一个服务器
+pub struct KvCluster {
+    nodes: Vec<Node>,
+    routing: Routing,
+    next: usize,
+    /// 分片集群的 slot 分布，只在 Slots 路由下使用
+    slots: Option<SlotMap>,
+    checker: JoinHandle<()>,
+}
+
+impl KvCluster {
+    /// 按配置连接所有服务器，至少要有一个服务器可用
+    pub async fn connect(config: ClientConfig) -> Result<Self, KvError> {
+        let addrs = if config.cluster.addrs.is_empty() {
+            vec![config.general.addr.clone()]
+        } else {
+            config.cluster.addrs.clone()
+        };
+
+        let mut nodes = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            let mut node_config = config.clone();
+            node_config.general.addr = addr.clone();
+            let mut client = KvClient::new(node_config);
+
+            let healthy = match start_client_with_config(&client.config).await {
+                Ok(ctrl) => {
+                    client.ctrl = Some(ctrl);
+                    true
+                }
+                Err(e) => {
+                    warn!("Failed to connect {}: {}", addr, e);
+                    false
+                }
+            };
+            nodes.push(Node {
+                addr,
+                client,
+                healthy: Arc::new(AtomicBool::new(healthy)),
+            });
+        }
+
+        if !nodes
+            .iter()
+            .any(|node| node.healthy.load(Ordering::Relaxed))
+        {
+            return Err(KvError::Internal("no healthy server available".into()));
+        }
+
+        let interval = Duration::from_millis(config.cluster.health_check_interval_ms);
+        let targets = nodes
+            .iter()
+            .map(|node| (node.addr.clone(), node.healthy.clone()))
+            .collect();
+        let checker = tokio::spawn(health_check(targets, interval));
+
+        let mut cluster = Self {
+            nodes,
+            routing: config.cluster.routing,
+            next: 0,
+            slots: None,
+            checker,
+        };
+        if cluster.routing == Routing::Slots {
+            cluster.refresh_slots().await;
+        }
+        Ok(cluster)
+    }
+
+    /// 给所有服务器的客户端设置同一个 metrics 回调
+    pub fn with_metrics(mut self, metrics: Arc<dyn ClientMetrics>) -> Self {
+        for node in &mut self.nodes {
+            node.client.metrics = metrics.clone();
+        }
+        self
+    }
+
+    /// 在路由到的服务器上执行命令，网络错误时 failover 到下一个可用的服务器
+    pub async fn execute_unary(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let res = self.execute_routed(cmd.clone()).await?;
+        // 分片集群的 slot 分布变了，重新获取之后再试一次
+        if self.routing == Routing::Slots
+            && res.status == StatusCode::MISDIRECTED_REQUEST.as_u16() as u32
+        {
+            self.refresh_slots().await;
+            return self.execute_routed(cmd).await;
+        }
+        Ok(res)
+    }
+
+    /// 当前使用的 slot 分布
+    pub fn slots(&self) -> Option<&SlotMap> {
+        self.slots.as_ref()
+    }
+
+    /// 从可用的服务器获取 slot 分布，获取不到时保留原来的分布
+    async fn refresh_slots(&mut self) {
+        for node in &mut self.nodes {
+            if !node.healthy.load(Ordering::Relaxed) {
+                continue;
+            }
+            let cmd = CommandRequest::new_cluster_slots();
+            let res = node
+                .client
+                .execute_unary(cmd)
+                .await
+                .and_then(|res| match res.status {
+                    200 => SlotMap::from_response(&res),
+                    _ => Err(KvError::Internal(res.message)),
+                });
+            match res {
+                Ok(slots) => {
+                    self.slots = Some(slots);
+                    return;
+                }
+                Err(e) => warn!("Failed to get cluster slots from {}: {}", node.addr, e),
+            }
+        }
+    }
+
+    async fn execute_routed(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let start = self.route(&cmd);
+        let n = self.nodes.len();
+
+        let mut last_err = None;
+        let mut unavailable = None;
+        for i in 0..n {
+            let node = &mut self.nodes[(start + i) % n];
+            if !node.healthy.load(Ordering::Relaxed) {
+                continue;
+            }
+            match node.client.execute_unary(cmd.clone()).await {
+                Err(e) if e.is_retryable() => {
+                    warn!(
+                        "Server {} failed: {}, failover to next server",
+                        node.addr, e
+                    );
+                    node.healthy.store(false, Ordering::Relaxed);
+                    last_err = Some(e);
+                }
+                Ok(res) if res.status == StatusCode::SERVICE_UNAVAILABLE.as_u16() as u32 => {
+                    warn!("Server {} unavailable: {}", node.addr, res.message);
+                    unavailable = Some(res);
+                }
+                res => return res,
+            }
+        }
+
+        if let Some(res) = unavailable {
+            return Ok(res);
+        }
+        Err(last_err.unwrap_or_else(|| KvError::Internal("no healthy server available".into())))
+    }
+
+    /// 当前可用的服务器地址
+    pub fn healthy_nodes(&self) -> Vec<&str> {
+        self.nodes
+            .iter()
+            .filter(|node| node.healthy.load(Ordering::Relaxed))
+            .map(|node| node.addr.as_str())
+            .collect()
+    }
+
+    fn route(&mut self, cmd: &CommandRequest) -> usize {
+        let n = self.nodes.len();
+        match self.routing {
+            Routing::RoundRobin => {
+                let i = self.next;
+                self.next = (self.next + 1) % n;
+                i
+            }
+            Routing::KeyHash => routing_key(cmd).map_or(0, |key| hash_key(key) % n),
+            // slot 所在的节点不在 cluster.addrs 里时按 KeyHash 路由，由服务器重定向或者代理
+            Routing::Slots => routing_key(cmd).map_or(0, |key| {
+                self.slot_owner(key).unwrap_or_else(|| hash_key(key) % n)
+            }),
+        }
+    }
+
+    fn slot_owner(&self, key: &str) -> Option<usize> {
+        let addr = self.slots.as_ref()?.owner(key_slot(key))?;
+        self.nodes.iter().position(|node| node.addr == addr)
+    }
+}
+
+impl Drop for KvCluster {
+    fn drop(&mut self) {
+        self.checker.abort();
+    }
+}
+
+/// 定期尝试连接不可用的服务器，能连上就重新启用
+async fn health_check(targets: Vec<(String, Arc<AtomicBool>)>, interval: Duration) {
+    let mut ticker = time::interval(interval);
+    loop {
+        ticker.tick().await;
+        for (addr, healthy) in &targets {
+            if healthy.load(Ordering::Relaxed) {
+                continue;
+            }
+            if let Ok(Ok(_)) = time::timeout(interval, TcpStream::connect(addr)).await {
+                info!("Server {} is back", addr);
+                healthy.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// 数据命令按 table 路由，pub/sub 命令按 topic 路由
+fn routing_key(cmd: &CommandRequest) -> Option<&str> {
+    let key = match cmd.request_data.as_ref()? {
+        RequestData::Hget(v) => &v.table,
+        RequestData::Hgetall(v) => &v.table,
+        RequestData::Hmget(v) => &v.table,
+        RequestData::Hset(v) => &v.table,
+        RequestData::Hmset(v) => &v.table,
+        RequestData::Hdel(v) => &v.table,
+        RequestData::Hmdel(v) => &v.table,
+        RequestData::Hexist(v) => &v.table,
+        RequestData::Hmexist(v) => &v.table,
+        RequestData::Hdelprefix(v) => &v.table,
+        RequestData::Htableexists(v) => &v.table,
+        RequestData::Hdebug(v) => &v.table,
+        RequestData::Subscribe(v) => &v.topic,
+        RequestData::Unsubscribe(v) => &v.topic,
+        RequestData::Publish(v) => &v.topic,
+        RequestData::Hwatch(v) => &v.table,
+        // 管理命令只和某一个服务器相关，不参与路由
+        RequestData::ClientList(_)
+        | RequestData::ClientKill(_)
+        | RequestData::Latency(_)
+        | RequestData::Replicate(_)
+        | RequestData::RaftVote(_)
+        | RequestData::RaftAppend(_)
+        | RequestData::ClusterSlots(_)
+        | RequestData::Cluster(_)
+        | RequestData::Gossip(_)
+        | RequestData::ReplicaAck(_)
+        | RequestData::Promote(_)
+        | RequestData::CrdtSync(_)
+        | RequestData::Auth(_)
+        | RequestData::Jobs(_)
+        | RequestData::Save(_)
+        | RequestData::Restore(_)
+        | RequestData::Reload(_)
+        | RequestData::Flushall(_) => return None,
+    };
+    Some(key)
+}
+
+fn hash_key(key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routing_key_should_use_table_or_topic() {
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        assert_eq!(routing_key(&cmd), Some("t1"));
+
+        let cmd = CommandRequest::new_hmget("t2", vec!["k1".into(), "k2".into()]);
+        assert_eq!(routing_key(&cmd), Some("t2"));
+
+        let cmd = CommandRequest::new_publish("lobby", vec![]);
+        assert_eq!(routing_key(&cmd), Some("lobby"));
+
+        assert_eq!(routing_key(&CommandRequest::default()), None);
+    }
+
+    #[test]
+    fn same_table_should_hash_to_same_value() {
+        assert_eq!(hash_key("t1"), hash_key("t1"));
+        assert_ne!(hash_key("t1"), hash_key("t2"));
+    }
+}
@@ -0,0 +1,33 @@
+use crate::{CommandResponse, KvError};
+use std::time::Duration;
+
+/// 客户端的 metrics 回调
+///
+/// 应用实现这个 trait，把数据转给自己的 Prometheus / OpenTelemetry 等系统。
+/// 所有方法都有空的默认实现，只需要实现关心的部分。回调在请求路径上同步执行，
+/// 实现里不要做耗时的操作
+pub trait ClientMetrics: Send + Sync + 'static {
+    /// 开始执行一个请求，可以用来维护 in-flight 请求数
+    fn on_request_start(&self, _cmd: &'static str) {}
+
+    /// 请求执行完毕（包括所有重试），latency 是总耗时。
+    /// 服务器返回的业务错误在 CommandResponse 的 status 里，网络错误和超时是 Err
+    fn on_request_end(
+        &self,
+        _cmd: &'static str,
+        _latency: Duration,
+        _res: &Result<CommandResponse, KvError>,
+    ) {
+    }
+
+    /// 第 attempt 次尝试失败，即将重试
+    fn on_retry(&self, _cmd: &'static str, _attempt: u32, _error: &KvError) {}
+
+    /// 连接断开后重连，error 为 None 表示重连成功
+    fn on_reconnect(&self, _addr: &str, _error: Option<&KvError>) {}
+}
+
+/// 什么都不做的默认实现
+pub struct NoopMetrics;
+
+impl ClientMetrics for NoopMetrics {}
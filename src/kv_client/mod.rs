@@ -0,0 +1,528 @@
+mod batch;
+mod cache;
+mod cluster;
+mod metrics;
+mod subscription;
+
+use crate::{
+    BoxedStream, ClientConfig, CommandRequest, CommandResponse, KvError, Kvpair, Service,
+    StreamResult, Value, YamuxCtrl, command_request::RequestData, connect_in_process,
+    keyspace_topic, start_client_with_config, value,
+};
+use cache::ReadCache;
+use futures::{Future, StreamExt};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{Span, debug, instrument, warn};
+
+pub use batch::Batch;
+pub use cluster::KvCluster;
+pub use metrics::{ClientMetrics, NoopMetrics};
+pub use subscription::Subscription;
+
+/// 带超时和重试策略的 KV 客户端
+///
+/// 每个请求在一个新的 yamux stream 上执行，超时、网络错误或者服务器返回 421/503 时按
+/// `ClientConfig::retry` 的配置退避重试，连接断开时自动重连。
+/// 配置了 `ClientConfig::cache` 时，HGET 优先从本地缓存读取
+pub struct KvClient {
+    config: ClientConfig,
+    ctrl: Option<YamuxCtrl<BoxedStream>>,
+    cache: Option<Arc<ReadCache>>,
+    /// 监听 keyspace 通知的后台任务，KvClient drop 时一起结束
+    watchers: Vec<JoinHandle<()>>,
+    metrics: Arc<dyn ClientMetrics>,
+    /// 进程内连接的 Service，重连时不经过网络
+    service: Option<Service>,
+}
+
+impl KvClient {
+    /// 按配置连接服务器
+    pub async fn connect(config: ClientConfig) -> Result<Self, KvError> {
+        let mut client = Self::new(config);
+        client.ctrl = Some(start_client_with_config(&client.config).await?);
+        Ok(client)
+    }
+
+    /// 在进程内连接 Service，不经过 TCP 和 TLS，超时和重试的策略使用默认配置
+    pub fn in_process(service: Service) -> Self {
+        // 默认配置不涉及文件和 TLS，一定是合法的
+        let config = ClientConfig::builder().build().unwrap();
+        let mut client = Self::new(config);
+        client.ctrl = Some(connect_in_process(service.clone()));
+        client.service = Some(service);
+        client
+    }
+
+    /// 创建客户端但不立即连接，第一次请求时再连接
+    fn new(config: ClientConfig) -> Self {
+        let cache = match config.cache.capacity {
+            0 => None,
+            n => Some(Arc::new(ReadCache::new(n))),
+        };
+        Self {
+            config,
+            ctrl: None,
+            cache,
+            watchers: Vec::new(),
+            metrics: Arc::new(NoopMetrics),
+            service: None,
+        }
+    }
+
+    /// 设置 metrics 回调，把请求耗时、错误、重连等信息交给应用自己的监控系统
+    pub fn with_metrics(mut self, metrics: Arc<dyn ClientMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// 执行一个 unary 命令，可重试的错误会按退避策略重试。
+    /// 服务器返回的请求 id 记在 span 的 request_id 里，可以用它在服务器的 trace 里找到这个请求
+    #[instrument(skip_all, fields(cmd = cmd.name(), request_id))]
+    pub async fn execute_unary(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let name = cmd.name();
+        let start = Instant::now();
+        self.metrics.on_request_start(name);
+        let res = self.execute_cached(cmd).await;
+        self.metrics.on_request_end(name, start.elapsed(), &res);
+        if let Ok(res) = &res
+            && !res.request_id.is_empty()
+        {
+            Span::current().record("request_id", res.request_id.as_str());
+        }
+        res
+    }
+
+    async fn execute_cached(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let Some(cache) = self.cache.clone() else {
+            return self.execute_with_retry(cmd).await;
+        };
+
+        if let Some(RequestData::Hget(param)) = &cmd.request_data {
+            let (table, key) = (param.table.clone(), param.key.clone());
+            return self.cached_hget(&cache, &table, &key, cmd).await;
+        }
+
+        // 自己的写入不用等服务器通知，直接让本地缓存失效
+        let res = self.execute_with_retry(cmd.clone()).await;
+        invalidate(&cache, &cmd);
+        res
+    }
+
+    async fn cached_hget(
+        &mut self,
+        cache: &Arc<ReadCache>,
+        table: &str,
+        key: &str,
+        cmd: CommandRequest,
+    ) -> Result<CommandResponse, KvError> {
+        if let Some(v) = cache.get(table, key) {
+            debug!("Cache hit: {}/{}", table, key);
+            return Ok(v.into());
+        }
+
+        // 先订阅 keyspace 通知再读，保证之后的写入一定能让缓存失效
+        if !cache.is_watching(table)
+            && let Err(e) = self.watch(cache, table).await
+        {
+            warn!("Failed to watch keyspace of {}: {}", table, e);
+            return self.execute_with_retry(cmd).await;
+        }
+
+        let generation = cache.generation();
+        let res = self.execute_with_retry(cmd).await?;
+        if let (200, [v]) = (res.status, &res.values[..]) {
+            cache.insert(generation, table, key, v.clone());
+        }
+        Ok(res)
+    }
+
+    /// 创建一个批量执行命令的 builder
+    pub fn batch(&mut self) -> Batch<'_> {
+        Batch::new(self)
+    }
+
+    /// pipeline 执行多个命令
+    ///
+    /// batch 里可能有写命令，部分写入后失败再重试会导致重复写入，所以只做超时，不重试
+    async fn execute_pipeline(
+        &mut self,
+        cmds: Vec<CommandRequest>,
+    ) -> Result<Vec<CommandResponse>, KvError> {
+        let timeout = Duration::from_millis(self.config.retry.timeout_ms);
+        let fut = async {
+            let renamed: Vec<_> = cmds
+                .iter()
+                .map(|cmd| self.config.commands.renamed(cmd.clone()))
+                .collect();
+            let ctrl = self.connection().await?;
+            ctrl.open_stream().await?.execute_pipeline(&renamed).await
+        };
+        let res = match time::timeout(timeout, fut).await {
+            Ok(res) => res,
+            Err(_) => Err(KvError::Timeout(format!(
+                "batch of {} after {:?}",
+                cmds.len(),
+                timeout
+            ))),
+        };
+
+        if let Some(cache) = &self.cache {
+            for cmd in &cmds {
+                invalidate(cache, cmd);
+            }
+        }
+        if matches!(res, Err(KvError::Io(_)) | Err(KvError::ConnectionError(_))) {
+            self.ctrl = None;
+        }
+
+        res
+    }
+
+    /// 订阅一个主题，每收到一条消息就调用一次 handler
+    ///
+    /// 返回的 Subscription 被 drop 时会自动取消订阅
+    pub async fn subscribe_with<F, Fut>(
+        &mut self,
+        topic: impl Into<String>,
+        mut handler: F,
+    ) -> Result<Subscription, KvError>
+    where
+        F: FnMut(Vec<Value>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let topic = topic.into();
+        let mut stream = self.subscribe(&topic).await?;
+        let id = stream.id;
+
+        // 收到 stop 时把消息流交还给 Subscription::unsubscribe；消息流结束时返回 None
+        let (stop_tx, mut stop_rx) = oneshot::channel();
+        let handle = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = &mut stop_rx => return Some(stream),
+                    res = stream.next() => match res {
+                        Some(Ok(res)) => handler(res.values).await,
+                        _ => return None,
+                    },
+                }
+            }
+        });
+
+        Ok(Subscription::new(id, topic, stop_tx, handle))
+    }
+
+    /// 订阅 table 的变更流，table 为空时订阅所有 table，每条消息用 `decode_change` 解出变更
+    ///
+    /// 消费太慢时服务器会断开变更流，这时需要重新订阅
+    pub async fn hwatch(&mut self, table: impl Into<String>) -> Result<StreamResult, KvError> {
+        self.execute_stream(CommandRequest::new_hwatch(table)).await
+    }
+
+    /// 订阅 table 的变更流，先返回 table 当前的数据，用 `snapshot` 取出；
+    /// 之后的每条消息都是快照之后的变更，依次应用到快照上就能得到和服务器一致的副本
+    pub async fn hwatch_snapshot(
+        &mut self,
+        table: impl Into<String>,
+    ) -> Result<(Vec<Kvpair>, StreamResult), KvError> {
+        let mut stream = self
+            .execute_stream(CommandRequest::new_hwatch_snapshot(table))
+            .await?;
+        match stream.next().await {
+            Some(Ok(res)) if res.status == 200 => Ok((res.pairs, stream)),
+            Some(Ok(res)) => Err(KvError::Internal(res.message)),
+            Some(Err(e)) => Err(e),
+            None => Err(KvError::Internal("hwatch stream closed".into())),
+        }
+    }
+
+    /// 在新的 stream 上发送 SUBSCRIBE，拿到 subscription id 后返回消息流。
+    /// 消息流被 drop 时自动取消订阅
+    async fn subscribe(&mut self, topic: &str) -> Result<StreamResult, KvError> {
+        let stream = self
+            .execute_stream(CommandRequest::new_subscribe(topic))
+            .await?;
+        // subscribe 成功说明连接一定存在
+        let ctrl = self.ctrl.clone().unwrap();
+        Ok(stream.unsubscribe_on_drop(ctrl, topic))
+    }
+
+    /// 在新的 stream 上发送流式命令，拿到第一个响应（id）后返回消息流
+    async fn execute_stream(&mut self, cmd: CommandRequest) -> Result<StreamResult, KvError> {
+        let timeout = Duration::from_millis(self.config.retry.timeout_ms);
+        let name = cmd.name();
+        let cmd = self.config.commands.renamed(cmd);
+        let fut = async {
+            let ctrl = self.connection().await?;
+            ctrl.open_stream().await?.execute_stream(&cmd).await
+        };
+        match time::timeout(timeout, fut).await {
+            Ok(res) => res,
+            Err(_) => Err(KvError::Timeout(format!("{} after {:?}", name, timeout))),
+        }
+    }
+
+    /// 订阅 table 的 keyspace 主题，收到通知时让对应的 key 失效
+    async fn watch(&mut self, cache: &Arc<ReadCache>, table: &str) -> Result<(), KvError> {
+        let mut stream = self.subscribe(&keyspace_topic(table)).await?;
+
+        cache.watch(table);
+        let cache = cache.clone();
+        let table = table.to_string();
+        let handle = tokio::spawn(async move {
+            while let Some(Ok(res)) = stream.next().await {
+                for v in res.values {
+                    if let Some(value::Value::String(key)) = v.value {
+                        cache.invalidate(&table, &key);
+                    }
+                }
+            }
+            // 订阅断开了，收不到通知的缓存不能再用
+            cache.unwatch(&table);
+        });
+        self.watchers.push(handle);
+
+        Ok(())
+    }
+
+    /// 拿到当前的连接，之前的连接断开了就先重连
+    async fn connection(&mut self) -> Result<&mut YamuxCtrl<BoxedStream>, KvError> {
+        if self.ctrl.is_none() {
+            let res = match &self.service {
+                Some(service) => Ok(connect_in_process(service.clone())),
+                None => start_client_with_config(&self.config).await,
+            };
+            self.metrics
+                .on_reconnect(&self.config.general.addr, res.as_ref().err());
+            self.ctrl = Some(res?);
+        }
+        Ok(self.ctrl.as_mut().unwrap())
+    }
+
+    async fn execute_with_retry(
+        &mut self,
+        cmd: CommandRequest,
+    ) -> Result<CommandResponse, KvError> {
+        let retry = &self.config.retry;
+        let max_attempts = retry.max_attempts.max(1);
+        let max_backoff = Duration::from_millis(retry.max_backoff_ms);
+        let mut backoff = Duration::from_millis(retry.backoff_ms).min(max_backoff);
+        // 写命令带上幂等 key，超时之后重试时服务器不会再执行一次
+        let cmd = match cmd.modified_keys() {
+            Some(_) if cmd.idempotency_key.is_empty() => {
+                cmd.with_idempotency_key(format!("{:032x}", rand::random::<u128>()))
+            }
+            _ => cmd,
+        };
+
+        let mut attempt = 1;
+        loop {
+            // 只有暂时性的错误值得重试，服务器返回的业务错误直接交给调用者。
+            // 过载、leader 切换这些错误是以 421/503 响应返回的，同样要重试，重试用完后返回最后的响应
+            let e = match self.try_execute(cmd.clone()).await {
+                Ok(res) => match res.transient_error() {
+                    Some(e) if attempt < max_attempts && e.is_retryable() => e,
+                    _ => return Ok(res),
+                },
+                Err(e) if attempt < max_attempts && e.is_retryable() => e,
+                res => return res,
+            };
+            warn!("Attempt {} failed: {}, retry in {:?}", attempt, e, backoff);
+            self.metrics.on_retry(cmd.name(), attempt, &e);
+            time::sleep(backoff).await;
+            backoff = (backoff * 2).min(max_backoff);
+            attempt += 1;
+        }
+    }
+
+    async fn try_execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let name = cmd.name();
+        let timeout = Duration::from_millis(self.config.retry.timeout_ms);
+        let cmd = self.config.commands.renamed(cmd);
+
+        let fut = async {
+            let ctrl = self.connection().await?;
+            ctrl.open_stream().await?.execute_unary(cmd).await
+        };
+
+        let res = match time::timeout(timeout, fut).await {
+            Ok(res) => res,
+            Err(_) => Err(KvError::Timeout(format!("{} after {:?}", name, timeout))),
+        };
+
+        // 连接层面的错误说明底层连接已经不可用，下次请求时重连
+        if matches!(res, Err(KvError::Io(_)) | Err(KvError::ConnectionError(_))) {
+            self.ctrl = None;
+        }
+
+        res
+    }
+}
+
+impl Drop for KvClient {
+    fn drop(&mut self) {
+        for handle in &self.watchers {
+            handle.abort();
+        }
+    }
+}
+
+/// 让写命令修改的 key 在本地缓存里失效
+fn invalidate(cache: &ReadCache, cmd: &CommandRequest) {
+    match &cmd.request_data {
+        Some(RequestData::Hdelprefix(param)) => {
+            cache.invalidate_prefix(&param.table, &param.prefix)
+        }
+        _ => {
+            if let Some((table, keys)) = cmd.modified_keys() {
+                for key in keys {
+                    cache.invalidate(table, key);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LoadShedder, MemTable, OverloadConfig, RetryConfig, Security};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tokio::net::TcpListener;
+
+    #[derive(Default)]
+    struct CountingMetrics {
+        started: AtomicU32,
+        failed: AtomicU32,
+        retries: AtomicU32,
+    }
+
+    impl ClientMetrics for CountingMetrics {
+        fn on_request_start(&self, _cmd: &'static str) {
+            self.started.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_request_end(
+            &self,
+            _cmd: &'static str,
+            _latency: Duration,
+            res: &Result<CommandResponse, KvError>,
+        ) {
+            if res.is_err() {
+                self.failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        fn on_retry(&self, _cmd: &'static str, _attempt: u32, _error: &KvError) {
+            self.retries.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn execute_unary_should_timeout_and_retry() {
+        // 只接受连接、从不回复的服务器
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                conns.push(stream);
+            }
+        });
+
+        let mut config: ClientConfig =
+            toml::from_str(include_str!("../../fixtures/client.conf")).unwrap();
+        config.general.addr = addr.to_string();
+        config.general.security = Security::None;
+        config.tls = None;
+        config.retry = RetryConfig {
+            timeout_ms: 100,
+            max_attempts: 2,
+            backoff_ms: 10,
+            max_backoff_ms: 10,
+        };
+
+        let metrics = Arc::new(CountingMetrics::default());
+        let mut client = KvClient::connect(config)
+            .await
+            .unwrap()
+            .with_metrics(metrics.clone());
+        let start = Instant::now();
+        let res = client
+            .execute_unary(CommandRequest::new_hget("t1", "k1"))
+            .await;
+
+        assert!(matches!(res, Err(KvError::Timeout(_))));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        assert_eq!(metrics.started.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.failed.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.retries.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn in_process_client_should_work() {
+        let service = Service::new(MemTable::new());
+        let mut client = KvClient::in_process(service.clone());
+        let res = client
+            .execute_unary(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await
+            .unwrap();
+        assert_eq!(res.status, 200);
+
+        // 直接在 yamux stream 上执行命令，和 TCP 连接一样
+        let mut ctrl = connect_in_process(service);
+        let mut stream = ctrl.open_stream().await.unwrap();
+        let res = stream
+            .execute_unary(CommandRequest::new_hget("t1", "k1"))
+            .await
+            .unwrap();
+        assert_eq!(res.values, &["v1".into()]);
+        let res = stream
+            .execute_unary(CommandRequest::new_client_list())
+            .await
+            .unwrap();
+        assert_eq!(res.values.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn busy_response_should_be_retried() {
+        let config = OverloadConfig {
+            enabled: true,
+            max_in_flight: 1,
+            ..Default::default()
+        };
+        let service = Service::new(MemTable::new()).with_load_shedding(LoadShedder::new(&config));
+        let metrics = Arc::new(CountingMetrics::default());
+        let mut client = KvClient::in_process(service.clone()).with_metrics(metrics.clone());
+
+        // 一直过载时，重试用完之后返回最后的 503 响应
+        let _load = service.track_request();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client.execute_unary(cmd.clone()).await.unwrap();
+        assert_eq!(res.status, 503);
+        assert_eq!(metrics.retries.load(Ordering::Relaxed), 2);
+
+        // 负载在重试之前降下来，第二次就成功了
+        let load = service.track_request();
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(50)).await;
+            drop(load);
+        });
+        drop(_load);
+        let res = client.execute_unary(cmd).await.unwrap();
+        assert_eq!(res.status, 200);
+        assert_eq!(metrics.retries.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn is_retryable_should_work() {
+        assert!(KvError::Timeout("hget".into()).is_retryable());
+        assert!(KvError::Io(std::io::ErrorKind::BrokenPipe.into()).is_retryable());
+        assert!(!KvError::NotFound("k1".into()).is_retryable());
+        assert!(!KvError::InvalidCommand("foo".into()).is_retryable());
+    }
+}
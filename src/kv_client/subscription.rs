@@ -0,0 +1,55 @@
+use crate::{KvError, StreamResult};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// 通过 `KvClient::subscribe_with` 创建的订阅
+///
+/// 消息在后台任务里交给 handler 处理。drop 时后台任务停止，任务持有的消息流随之被 drop，
+/// 由消息流向服务器取消订阅
+pub struct Subscription {
+    pub id: u32,
+    topic: String,
+    /// 通知后台任务停下来，把消息流交还给 `unsubscribe`
+    stop: Option<oneshot::Sender<()>>,
+    handle: JoinHandle<Option<StreamResult>>,
+}
+
+impl Subscription {
+    pub(crate) fn new(
+        id: u32,
+        topic: String,
+        stop: oneshot::Sender<()>,
+        handle: JoinHandle<Option<StreamResult>>,
+    ) -> Self {
+        Self {
+            id,
+            topic,
+            stop: Some(stop),
+            handle,
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// 主动取消订阅，并等待服务器确认
+    pub async fn unsubscribe(mut self) -> Result<(), KvError> {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        match (&mut self.handle).await {
+            Ok(Some(stream)) => stream.unsubscribe().await,
+            _ => Err(KvError::Internal(format!(
+                "subscription {} is closed",
+                self.id
+            ))),
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
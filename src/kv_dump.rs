@@ -0,0 +1,54 @@
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use kv::dump;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+/// 离线查看 KV 服务器的 sled 数据目录，需要先停止服务器
+#[derive(Debug, Parser)]
+#[command(name = "kv-dump", version, about)]
+struct Args {
+    /// 数据目录，和服务器配置里 sled 的路径一样
+    path: PathBuf,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// 列出所有的 table 和它们的 key 数
+    Tables,
+    /// 输出 kv pair，每行一个
+    Dump {
+        /// 只输出这个 table
+        #[arg(short, long)]
+        table: Option<String>,
+        /// 导出成 JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let store = dump::open(&args.path)?;
+    let mut out = BufWriter::new(io::stdout().lock());
+    match args.command {
+        Command::Tables => {
+            for (table, count) in dump::table_stats(&store)? {
+                writeln!(out, "{}\t{}", table, count)?;
+            }
+        }
+        Command::Dump { table, json } => {
+            let tables = dump::select_tables(&store, table.as_deref())?;
+            let count = match json {
+                true => dump::write_json(&store, &tables, &mut out)?,
+                false => dump::write_text(&store, &tables, &mut out)?,
+            };
+            // 统计信息写到 stderr，不影响导出的数据
+            eprintln!("Dumped {} keys from {} tables", count, tables.len());
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
@@ -1,83 +1,641 @@
+pub mod bench;
+mod cli;
 mod config;
+#[cfg(feature = "serde")]
+pub mod dump;
 mod error;
+pub mod fuzz;
+mod kv_client;
+mod membership;
+mod memcached;
+mod mirror;
+mod multi_master;
 mod network;
 mod pb;
+mod raft;
+mod replica;
 mod service;
+mod setup;
+mod shadow;
+mod shard;
 mod storage;
 
+pub use cli::{format_change, format_response, format_value, parse_args, parse_command};
 pub use config::*;
-pub use error::KvError;
+pub use error::{ErrorKind, KvError};
+pub use kv_client::{Batch, ClientMetrics, KvClient, KvCluster, NoopMetrics, Subscription};
+pub use membership::{MemberInfo, MemberStatus, Membership};
+pub use multi_master::MultiMaster;
 pub use network::*;
 pub use pb::abi::*;
+pub use raft::RaftNode;
 pub use service::*;
+pub use setup::{gen_config, gen_keys};
+pub use shadow::Shadow;
+pub use shard::{SLOTS, ShardRouter, SlotMap, key_slot};
 pub use storage::*;
 
 use anyhow::Result;
+use futures::future::{self, BoxFuture};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
-use tokio_rustls::client;
+use tokio::sync::{Semaphore, watch};
+use tokio::time::{self, Instant};
 use tokio_util::compat::FuturesAsyncReadCompatExt;
-use tracing::{info, instrument, span};
+use tracing::{debug, info, instrument, span, warn};
+
+/// 进程内连接的 duplex 管道每个方向的缓冲区大小
+const IN_PROCESS_BUFFER: usize = 64 * 1024;
+/// 打开存储失败时重试的间隔
+const OPEN_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+/// 等旧进程释放存储时，在它的 shutdown_timeout_ms 之外多等的时间
+const OPEN_GRACE: Duration = Duration::from_secs(5);
+
+/// 运行中的服务器的控制：热加载配置、优雅关闭，以及开始监听时的通知
+#[derive(Default)]
+pub struct ServerControl {
+    updates: Option<watch::Receiver<ServerConfig>>,
+    shutdown: Option<BoxFuture<'static, ()>>,
+    on_ready: Option<Box<dyn FnOnce(SocketAddr) + Send>>,
+    listener: Option<std::net::TcpListener>,
+    reload_requests: Option<Arc<tokio::sync::Notify>>,
+}
+
+impl ServerControl {
+    /// updates 收到新的配置时热加载 limits 和 TLS 证书
+    ///
+    /// 新的配置只对之后建立的连接生效，已有的连接继续使用原来的 TLS 会话和 stream 上限。
+    /// 其它配置的修改需要重启，见 [`ServerConfig::restart_required`]
+    pub fn reload(mut self, updates: watch::Receiver<ServerConfig>) -> Self {
+        self.updates = Some(updates);
+        self
+    }
+
+    /// signal 完成时停止接受新连接，等处理中的请求结束（最多 limits.shutdown_timeout_ms），
+    /// 然后断开所有连接，服务器正常返回
+    pub fn shutdown(mut self, signal: impl Future<Output = ()> + Send + 'static) -> Self {
+        self.shutdown = Some(Box::pin(signal));
+        self
+    }
+
+    /// 开始监听之后调用，参数是监听的地址
+    pub fn on_ready(mut self, f: impl FnOnce(SocketAddr) + Send + 'static) -> Self {
+        self.on_ready = Some(Box::new(f));
+        self
+    }
+
+    /// 收到 RELOAD 命令时通知 notify。调用者重新读取配置之后，和 SIGHUP 一样通过 reload 的 updates 发出来
+    pub fn on_reload_request(mut self, notify: Arc<tokio::sync::Notify>) -> Self {
+        self.reload_requests = Some(notify);
+        self
+    }
+
+    /// 使用已经在监听的 socket，而不是绑定 general.addr，比如平滑重启时从旧进程继承的 socket
+    pub fn listener(mut self, listener: std::net::TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+}
 
 /// 通过配置创建 KV 服务器
-#[instrument(skip_all)]
 pub async fn start_server_with_config(config: &ServerConfig) -> Result<()> {
-    let acceptor =
-        TlsServerAcceptor::new(&config.tls.cert, &config.tls.key, config.tls.ca.as_deref())?;
+    start_server_with_control(config, ServerControl::default()).await
+}
+
+/// 通过配置创建 KV 服务器，运行过程中可以通过 control 热加载配置和优雅关闭
+#[instrument(skip_all)]
+pub async fn start_server_with_control(
+    config: &ServerConfig,
+    control: ServerControl,
+) -> Result<()> {
+    let acceptor = server_acceptor(&config.general.security, config)?;
 
-    let addr = &config.general.addr;
     match &config.storage {
-        StorageConfig::MemTable => start_tls_server(addr, MemTable::new(), acceptor).await?,
-        StorageConfig::SledDb(path) => start_tls_server(addr, SledDb::new(path), acceptor).await?,
+        StorageConfig::MemTable => start_server(config, MemTable::new(), acceptor, control).await?,
+        StorageConfig::MemTableOrdered => {
+            start_server(config, MemTableOrdered::new(), acceptor, control).await?
+        }
+        StorageConfig::SledDb(path) => {
+            // 平滑重启时旧进程处理完请求退出之前，数据库还被它锁着
+            let timeout = Duration::from_millis(config.limits.shutdown_timeout_ms) + OPEN_GRACE;
+            let store = open_sled(path, timeout).await?;
+            match config.read_cache.capacity {
+                0 => start_server(config, store, acceptor, control).await?,
+                capacity => {
+                    let ttl = Duration::from_millis(config.read_cache.ttl_ms);
+                    let store = TtlCache::new(store, capacity, ttl);
+                    start_server(config, store, acceptor, control).await?
+                }
+            }
+        }
+        StorageConfig::CachedSledDb(path) => {
+            let timeout = Duration::from_millis(config.limits.shutdown_timeout_ms) + OPEN_GRACE;
+            let slow = open_sled(path, timeout).await?;
+            let store = CachedStorage::new(MemTable::new(), slow, config.read_cache.fast_capacity);
+            start_server(config, store, acceptor, control).await?
+        }
     };
 
     Ok(())
 }
 
+/// 打开 sled，失败时在 timeout 之内重试
+async fn open_sled(path: &str, timeout: Duration) -> Result<SledDb, KvError> {
+    let deadline = Instant::now() + timeout;
+    let mut warned = false;
+    loop {
+        match SledDb::open(path) {
+            Ok(store) => return Ok(store),
+            Err(e) if Instant::now() < deadline => {
+                if !warned {
+                    warn!("Failed to open {}: {}, retrying for {:?}", path, e, timeout);
+                    warned = true;
+                }
+                time::sleep(OPEN_RETRY_INTERVAL).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// 数据端口和管理端口的传输层加密，由 general.security 决定
+#[derive(Clone)]
+enum ServerAcceptor {
+    Tls(TlsServerAcceptor),
+    Noise(NoiseServerAcceptor),
+}
+
+/// 按 TLS 或者 Noise 配置创建 acceptor，每次调用都会重新读取证书和密钥文件。
+/// security 需要重启才能修改，热加载时沿用启动时的值
+fn server_acceptor(
+    security: &Security,
+    config: &ServerConfig,
+) -> Result<Option<ServerAcceptor>, KvError> {
+    match security {
+        Security::Tls => {
+            let tls = config.tls.as_ref().ok_or_else(|| {
+                KvError::InvalidConfig("[tls] is required when security = \"tls\"".into())
+            })?;
+            let pem = tls.load_pem()?;
+            let mut acceptor = TlsServerAcceptor::new(&pem.cert, &pem.key, pem.ca.as_deref())?
+                .with_session_resumption(&tls.session)
+                .with_policy(tls.min_version, &tls.cipher_suites)?;
+            if let Some(crl) = &pem.crl {
+                acceptor = acceptor.with_crl(RevocationList::from_pem(crl)?);
+            }
+            Ok(Some(ServerAcceptor::Tls(acceptor)))
+        }
+        Security::Noise => {
+            let noise = config.noise.as_ref().ok_or_else(|| {
+                KvError::InvalidConfig("[noise] is required when security = \"noise\"".into())
+            })?;
+            let mut acceptor =
+                NoiseServerAcceptor::new(Some(noise.load_key()?))?.with_pattern(noise.pattern);
+            if let Some(psk) = noise.load_psk()? {
+                acceptor = acceptor.with_psk(psk)?;
+            }
+            Ok(Some(ServerAcceptor::Noise(acceptor)))
+        }
+        Security::None => Ok(None),
+    }
+}
+
+/// 在进程内把客户端连到 Service，不经过 TCP 和 TLS，用于测试或者把服务器作为库嵌入使用
+///
+/// 服务端的处理和 TCP 连接完全一样，只是不需要 AUTH 认证，并且和管理端口一样可以执行运维命令。
+/// 连接也会出现在 CLIENT LIST 里，地址是 0.0.0.0:0
+pub fn connect_in_process(service: Service) -> YamuxCtrl<BoxedStream> {
+    let (client, server) = tokio::io::duplex(IN_PROCESS_BUFFER);
+    let addr = SocketAddr::from(([0, 0, 0, 0], 0));
+    tokio::spawn(serve_stream(
+        service.without_auth().with_admin_listener(),
+        Box::new(server),
+        addr,
+        None,
+        LimitsConfig::default(),
+    ));
+    let client: BoxedStream = Box::new(client);
+    YamuxCtrl::new_client(client, None)
+}
+
 /// 通过配置创建 KV 客户端
 #[instrument(skip_all)]
 pub async fn start_client_with_config(
     config: &ClientConfig,
-) -> Result<YamuxCtrl<client::TlsStream<TcpStream>>> {
+) -> Result<YamuxCtrl<BoxedStream>, KvError> {
     let addr = &config.general.addr;
-    let tls = &config.tls;
-
-    let identity = tls.identity.as_ref().map(|(c, k)| (c.as_str(), k.as_str()));
-    let connector = TlsClientConnector::new(&tls.domain, identity, tls.ca.as_deref())?;
     let stream = TcpStream::connect(addr).await?;
-    let stream = connector.connect(stream).await?;
+    set_socket_options(&stream, &config.general.socket)?;
+
+    let stream: BoxedStream = match config.general.security {
+        Security::Tls => {
+            let tls = config.tls.as_ref().ok_or_else(|| {
+                KvError::InvalidConfig("[tls] is required when security = \"tls\"".into())
+            })?;
+            let pem = tls.load_pem()?;
+            let identity = pem.identity.as_ref().map(|(c, k)| (c.as_str(), k.as_str()));
+            let connector = TlsClientConnector::new(&tls.domain, identity, pem.ca.as_deref())?
+                .with_session_resumption(&tls.session)
+                .with_policy(tls.min_version, &tls.cipher_suites)?;
+            Box::new(connector.connect(stream).await?)
+        }
+        Security::Noise => {
+            let noise = config.noise.as_ref().ok_or_else(|| {
+                KvError::InvalidConfig("[noise] is required when security = \"noise\"".into())
+            })?;
+            let keys = noise.load_keys()?;
+            let mut connector =
+                NoiseClientConnector::new(keys.key, keys.server_pub)?.with_pattern(noise.pattern);
+            if let Some(psk) = keys.psk {
+                connector = connector.with_psk(psk)?;
+            }
+            Box::new(connector.connect(stream).await?)
+        }
+        Security::None => Box::new(stream),
+    };
 
     // 打开一个 stream
-    Ok(YamuxCtrl::new_client(stream, None))
+    let mut ctrl = YamuxCtrl::new_client(stream, None);
+    if let Some(password) = &config.auth.password {
+        let cmd = CommandRequest::new_auth(password.as_str());
+        let res = ctrl.open_stream().await?.execute_unary(cmd).await?;
+        if res.status != 200 {
+            let msg = format!("AUTH to {} failed with status {}", addr, res.status);
+            return Err(KvError::Unauthorized(msg));
+        }
+    }
+    Ok(ctrl)
 }
 
-async fn start_tls_server<Store: Storage>(
-    addr: &str,
+async fn start_server<Store: Storage>(
+    config: &ServerConfig,
     store: Store,
-    acceptor: TlsServerAcceptor,
+    mut acceptor: Option<ServerAcceptor>,
+    control: ServerControl,
 ) -> Result<()> {
-    let service: Service = Service::new(store);
-    let listener = TcpListener::bind(addr).await?;
+    let addr = &config.general.addr;
+    if config.snapshot.enabled {
+        let count = load_snapshot(&store, &config.snapshot.path)?;
+        info!("Loaded {} keys from {}", count, config.snapshot.path);
+    }
+    let mut service: Service =
+        Service::new(store).with_scheduler(Scheduler::new(&config.scheduler));
+    // 先设置限制，后面的 Raft 等组件拿到的 Service clone 也要带着它们
+    if let Some(limit) = config.limits.max_result_entries {
+        service = service.with_max_result_entries(limit);
+    }
+    if let Some(limit) = config.limits.max_request_entries {
+        service = service.with_max_request_entries(limit);
+    }
+    match config.replication.role {
+        Role::Standalone => {}
+        Role::Primary => {
+            let mut log = ReplicationLog::new(config.replication.log_capacity);
+            let failover = &config.replication.failover;
+            if failover.enabled {
+                log = log.with_lease(Duration::from_millis(failover.lease_ms));
+            }
+            service = service.with_replication(log);
+        }
+        Role::Replica => {
+            let primary = config.replication.primary.clone().ok_or_else(|| {
+                KvError::InvalidConfig("replication.primary is required for replica".into())
+            })?;
+            // replica 也准备好复制日志，提升为 primary 之后使用
+            let log = ReplicationLog::new(config.replication.log_capacity);
+            service = service.with_replication(log).read_only();
+            let replication = config.replication.clone();
+            tokio::spawn(replica::run_replica(primary, service.clone(), replication));
+        }
+    }
+    if config.raft.enabled {
+        // 已提交的日志直接应用到本地 Storage，不再经过 Raft
+        let svc = service.clone();
+        let store = Arc::clone(&service.store);
+        let raft = RaftNode::new(&config.raft, store, move |cmd| svc.apply_replicated(cmd))?;
+        raft.start();
+        service = service.with_raft(raft);
+    }
+    if config.sharding.enabled {
+        service = service.with_sharding(ShardRouter::new(&config.sharding)?);
+    }
+    if config.membership.enabled {
+        let membership = Membership::new(config);
+        membership.start(service.scheduler());
+        service = service.with_membership(membership);
+    }
+    if config.multi_master.enabled {
+        let multi_master = MultiMaster::new(config, Arc::clone(&service.store))?;
+        service = service.with_multi_master(multi_master.clone());
+        multi_master.start(service.clone());
+    }
+    if config.mirror.enabled {
+        tokio::spawn(mirror::run_mirror(service.clone(), config.mirror.clone()));
+    }
+    if let Some(notify) = &control.reload_requests {
+        service = service.with_reload_trigger(Arc::clone(notify));
+    }
+    if !config.admin.backup_dir.is_empty() {
+        service = service.with_backup_dir(&config.admin.backup_dir);
+    }
+    let limits = &config.limits;
+    service = service.with_entry_limits(limits.max_key_bytes, limits.max_value_bytes);
+    if config.snapshot.enabled {
+        start_snapshots(&service, &config.snapshot);
+    }
+    if config.idempotency.enabled {
+        service = service.with_idempotency(IdempotencyCache::new(&config.idempotency));
+    }
+    let pubsub = &config.pubsub;
+    service =
+        service.with_subscriber_limits(pubsub.max_subscribers_per_topic, pubsub.max_subscribers);
+    if config.pubsub.topic_idle_ms > 0 {
+        service.start_topic_gc(Duration::from_millis(config.pubsub.topic_idle_ms));
+    }
+    if config.shadow.enabled {
+        service = service.with_shadow(Shadow::start(&config.shadow)?);
+    }
+    if config.overload.enabled {
+        let shedder = LoadShedder::new(&config.overload);
+        shedder.start();
+        service = service.with_load_shedding(shedder);
+    }
+    if let Some(password) = &config.auth.password {
+        service = service.with_password(password.as_str());
+    }
+    if !config.auth.roles.is_empty() || config.auth.default_role.is_some() {
+        service = service.with_access_control(AccessControl::new(&config.auth));
+    }
+    if !config.auth.tenants.is_empty() {
+        service = service.with_namespaces(Namespaces::new(&config.auth));
+    }
+    let filter = CommandFilter::new(&config.commands);
+    if !filter.is_empty() {
+        service = service.with_command_filter(filter);
+    }
+    // 只有管理端口的 Service 接受所有连接的运维命令，数据端口上只有 admin 角色可以执行
+    let admin = match &config.admin {
+        admin if admin.enabled => {
+            let listener = bind_listener(&admin.addr, &config.general.socket)?;
+            let listener = TcpListener::from_std(listener)?;
+            info!("Start listening for admin on {}", admin.addr);
+            let task = run_admin(
+                listener,
+                service.clone().with_admin_listener(),
+                acceptor.clone(),
+                admin.local_only,
+                config.limits.clone(),
+            );
+            Some(tokio::spawn(task))
+        }
+        _ => None,
+    };
+    // memcached 端口使用数据端口的 Service
+    let memcached = match &config.memcached {
+        memcached if memcached.enabled => {
+            let listener = bind_listener(&memcached.addr, &config.general.socket)?;
+            let listener = TcpListener::from_std(listener)?;
+            let task = memcached::run_memcached(listener, service.clone(), memcached.clone());
+            Some(tokio::spawn(task))
+        }
+        _ => None,
+    };
+    let listener = match control.listener {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            listener
+        }
+        None => bind_listener(addr, &config.general.socket)?,
+    };
+    let listener = TcpListener::from_std(listener)?;
     info!("Start listening on {}", addr);
+    if let Some(on_ready) = control.on_ready {
+        on_ready(listener.local_addr()?);
+    }
+    // 没有设置时用一个 sender 已经 drop 的 channel，第一次 changed() 之后就不再检查
+    let mut updates = control
+        .updates
+        .unwrap_or_else(|| watch::channel(config.clone()).1);
+    let mut shutdown = control
+        .shutdown
+        .unwrap_or_else(|| Box::pin(future::pending()));
+    let mut limits = config.limits.clone();
+    let mut reloadable = true;
     loop {
         let root = span!(tracing::Level::INFO, "server_process");
         let _enter = root.enter();
+        let (stream, addr) = tokio::select! {
+            conn = accept_connection(&listener) => conn,
+            _ = &mut shutdown => break,
+            res = updates.changed(), if reloadable => {
+                if res.is_err() {
+                    reloadable = false;
+                    continue;
+                }
+                let new = updates.borrow_and_update().clone();
+                // 使用 TLS 还是 Noise 需要重启才能修改，这里只重新加载证书和密钥
+                match server_acceptor(&config.general.security, &new) {
+                    Ok(new_acceptor) => {
+                        acceptor = new_acceptor;
+                        limits = new.limits.clone();
+                        info!("Reloaded limits and TLS certificates or noise keys");
+                    }
+                    Err(e) => warn!("Failed to reload TLS certificates or noise keys: {}", e),
+                }
+                continue;
+            }
+        };
         let tls = acceptor.clone();
-        let (stream, addr) = listener.accept().await?;
         info!("Client {:?} connected", addr);
+        if let Err(e) = set_socket_options(&stream, &config.general.socket) {
+            warn!("Failed to set socket options for {:?}: {:?}", addr, e);
+        }
 
-        let svc = service.clone();
-        tokio::spawn(async move {
-            let stream = tls.accept(stream).await.unwrap();
-            YamuxCtrl::new_server(stream, None, move |stream| {
-                let svc1 = svc.clone();
-                async move {
-                    let stream = ProstServerStream::new(stream.compat(), svc1.clone());
-                    // 延迟 100ms 处理
-                    // time::sleep(time::Duration::from_millis(100)).await;
-                    stream.process().await.unwrap();
-                    Ok(())
+        tokio::spawn(serve_connection(
+            service.clone(),
+            stream,
+            addr,
+            tls,
+            limits.clone(),
+        ));
+    }
+
+    drop(listener);
+    if let Some(admin) = admin {
+        admin.abort();
+    }
+    if let Some(memcached) = memcached {
+        memcached.abort();
+    }
+    drain(&service, Duration::from_millis(limits.shutdown_timeout_ms)).await;
+    service.scheduler().shutdown();
+    // 请求都处理完了，最后写一次快照，正常关闭不会丢失数据
+    if config.snapshot.enabled {
+        let path = config.snapshot.path.clone();
+        let count = write_snapshot(Arc::clone(&service.store), path).await?;
+        info!("Saved {} keys to {}", count, config.snapshot.path);
+    }
+    Ok(())
+}
+
+/// 注册定期写快照的 job
+fn start_snapshots(service: &Service, config: &SnapshotConfig) {
+    let (store, path) = (Arc::clone(&service.store), config.path.clone());
+    let interval = Duration::from_millis(config.interval_ms);
+    service.scheduler().spawn("snapshot", interval, move || {
+        let (store, path) = (Arc::clone(&store), path.clone());
+        async move {
+            let count = write_snapshot(store, path).await?;
+            debug!("Saved snapshot with {} keys", count);
+            Ok(())
+        }
+    });
+}
+
+/// 在 blocking 线程里写快照，不阻塞请求的处理
+async fn write_snapshot(store: Arc<dyn Storage>, path: String) -> Result<usize, KvError> {
+    tokio::task::spawn_blocking(move || save_snapshot(store.as_ref(), path))
+        .await
+        .map_err(|e| KvError::Internal(format!("snapshot task failed: {}", e)))?
+}
+
+/// 管理端口的 accept 循环，local_only 时拒绝非本机的连接
+async fn run_admin(
+    listener: TcpListener,
+    service: Service,
+    acceptor: Option<ServerAcceptor>,
+    local_only: bool,
+    limits: LimitsConfig,
+) {
+    loop {
+        let (stream, addr) = accept_connection(&listener).await;
+        if local_only && !addr.ip().is_loopback() {
+            warn!("Rejected admin connection from {:?}", addr);
+            continue;
+        }
+        info!("Admin client {:?} connected", addr);
+        tokio::spawn(serve_connection(
+            service.clone(),
+            stream,
+            addr,
+            acceptor.clone(),
+            limits.clone(),
+        ));
+    }
+}
+
+/// 处理一个客户端连接，直到连接断开或者被 CLIENT KILL
+async fn serve_connection(
+    svc: Service,
+    stream: TcpStream,
+    addr: SocketAddr,
+    acceptor: Option<ServerAcceptor>,
+    limits: LimitsConfig,
+) {
+    // 没有配置 TLS 和 Noise 时直接使用明文 TCP，方便本地开发
+    let (stream, identity): (BoxedStream, _) = match acceptor {
+        Some(ServerAcceptor::Tls(tls)) => match tls.accept(stream).await {
+            Ok(stream) => {
+                let identity = peer_identity(&stream);
+                (Box::new(stream), identity)
+            }
+            // 握手失败只影响这一个连接
+            Err(e) => {
+                warn!("TLS handshake with {:?} failed: {}", addr, e);
+                return;
+            }
+        },
+        // Noise 连接没有证书，身份为空，按 auth.default_role 授权
+        Some(ServerAcceptor::Noise(noise)) => match noise.accept(stream).await {
+            Ok(stream) => (Box::new(stream), None),
+            Err(e) => {
+                warn!("Noise handshake with {:?} failed: {}", addr, e);
+                return;
+            }
+        },
+        None => (Box::new(stream), None),
+    };
+    serve_stream(svc, stream, addr, identity, limits).await;
+}
+
+/// 在已经建立好的 stream 上运行 yamux 会话，处理客户端的所有请求
+async fn serve_stream(
+    svc: Service,
+    stream: BoxedStream,
+    addr: SocketAddr,
+    identity: Option<String>,
+    limits: LimitsConfig,
+) {
+    // 在注册表中登记连接，yamux 连接结束时 guard 被 drop，连接自动注销
+    let conn = svc.connections().register(addr);
+    let stats = conn.stats();
+    if let Some(identity) = identity {
+        stats.set_identity(identity);
+    }
+    let svc_kill = svc.clone();
+    // 每个连接一个信号量，超过上限的 stream 收到 503，不影响连接上的其它 stream
+    let max_streams = limits.max_concurrent_streams;
+    let limiter = Arc::new(Semaphore::new(max_streams));
+    let frame_limits = limits.frame_limits();
+    let max_response_frame = limits.max_response_frame_bytes;
+    let max_malformed_frames = limits.max_malformed_frames;
+    let (read_timeout, write_timeout) = (limits.read_timeout(), limits.write_timeout());
+    let mut ctrl = YamuxCtrl::new_server(stream, None, move |stream| {
+        let svc1 = svc.clone();
+        let permit = limiter.clone().try_acquire_owned();
+        let stats = conn.stats();
+        async move {
+            let stream = ProstServerStream::new(stream.compat(), svc1.clone())
+                .with_frame_limits(frame_limits)
+                .with_max_response_frame(max_response_frame)
+                .with_max_malformed_frames(max_malformed_frames)
+                .with_timeouts(read_timeout, write_timeout)
+                .with_connection(stats);
+            // 延迟 100ms 处理
+            // time::sleep(time::Duration::from_millis(100)).await;
+            let res = match permit {
+                Ok(_permit) => stream.process().await,
+                Err(_) => {
+                    let e = KvError::Busy(format!(
+                        "more than {} concurrent streams on this connection",
+                        max_streams
+                    ));
+                    stream.reject(e).await
                 }
-            });
-        });
+            };
+            if let Err(e) = res {
+                warn!("Failed to process stream: {:?}", e);
+            }
+            Ok(())
+        }
+    });
+
+    // 连接被 CLIENT KILL 时先清理订阅，让订阅的 stream 结束，再关闭 yamux 会话
+    stats.closed().await;
+    if stats.is_killed() {
+        info!("Client {:?} killed", addr);
+        svc_kill.remove_subscriptions(&stats);
+        if let Err(e) = ctrl.close().await {
+            warn!("Failed to close connection {:?}: {:?}", addr, e);
+        }
+    }
+}
+
+/// 等所有连接上处理中的 stream 结束，然后断开所有连接。
+/// 订阅这样一直不结束的 stream 最多等到 timeout
+async fn drain(service: &Service, timeout: Duration) {
+    let connections = service.connections();
+    info!("Shutting down, draining {} connections", connections.len());
+    let deadline = Instant::now() + timeout;
+    while connections.open_streams() > 0 && Instant::now() < deadline {
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    connections.kill(|_| true);
+    while !connections.is_empty() && Instant::now() < deadline {
+        time::sleep(Duration::from_millis(50)).await;
     }
+    info!("Server stopped");
 }
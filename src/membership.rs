@@ -0,0 +1,397 @@
+use crate::{
+    CommandRequest, CommandResponse, KvError, Member, MembershipConfig, PeerClient, Role,
+    Scheduler, ServerConfig, Value,
+};
+use bytes::Bytes;
+use prost::Message;
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
+
+/// 成员的健康状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberStatus {
+    /// 还没有收到过这个成员的心跳
+    Unknown,
+    Up,
+    /// 超过 suspect_timeout 没有收到心跳
+    Suspect,
+    /// 超过 down_timeout 没有收到心跳
+    Down,
+}
+
+impl fmt::Display for MemberStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            MemberStatus::Unknown => "unknown",
+            MemberStatus::Up => "up",
+            MemberStatus::Suspect => "suspect",
+            MemberStatus::Down => "down",
+        };
+        f.write_str(s)
+    }
+}
+
+/// CLUSTER 命令返回的成员信息
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemberInfo {
+    pub addr: String,
+    pub role: String,
+    pub status: MemberStatus,
+    pub heartbeat: u64,
+    /// 距离上次收到心跳的时间
+    pub last_seen: Duration,
+}
+
+/// 类似 CLIENT LIST 的单行输出
+impl fmt::Display for MemberInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "addr={} role={} status={} heartbeat={} last_seen={}ms",
+            self.addr,
+            self.role,
+            self.status,
+            self.heartbeat,
+            self.last_seen.as_millis()
+        )
+    }
+}
+
+#[derive(Debug)]
+struct MemberState {
+    member: Member,
+    updated: Instant,
+}
+
+/// 集群成员管理
+///
+/// 每个节点定期递增自己的心跳，并随机选一个成员交换成员列表，双方都保留 (incarnation, heartbeat)
+/// 更大的那份。节点重启后 incarnation 变大，旧的心跳不会覆盖它。
+/// 长时间收不到心跳的成员先变成 suspect，再变成 down，down 的成员不再传给其它节点。
+/// 静态模式下只检查 seeds 的健康状态，不会加入新成员
+pub struct Membership {
+    addr: String,
+    members: Mutex<HashMap<String, MemberState>>,
+    peers: Mutex<HashMap<String, Arc<PeerClient>>>,
+    config: MembershipConfig,
+}
+
+impl Membership {
+    pub fn new(config: &ServerConfig) -> Arc<Self> {
+        let membership = &config.membership;
+        let addr = membership
+            .advertise_addr
+            .clone()
+            .unwrap_or_else(|| config.general.addr.clone());
+        let incarnation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+
+        let now = Instant::now();
+        let mut members = HashMap::new();
+        for seed in &membership.seeds {
+            let member = Member {
+                addr: seed.clone(),
+                ..Default::default()
+            };
+            members.insert(
+                seed.clone(),
+                MemberState {
+                    member,
+                    updated: now,
+                },
+            );
+        }
+        let me = Member {
+            addr: addr.clone(),
+            role: node_role(config).into(),
+            incarnation,
+            heartbeat: 1,
+        };
+        members.insert(
+            addr.clone(),
+            MemberState {
+                member: me,
+                updated: now,
+            },
+        );
+
+        Arc::new(Self {
+            addr,
+            members: Mutex::new(members),
+            peers: Mutex::new(HashMap::new()),
+            config: membership.clone(),
+        })
+    }
+
+    /// 在调度器里注册定期发送 gossip 的 job
+    pub fn start(self: &Arc<Self>, scheduler: &Scheduler) {
+        let interval = Duration::from_millis(self.config.gossip_interval_ms);
+        let membership = self.clone();
+        scheduler.spawn("gossip", interval, move || {
+            let membership = membership.clone();
+            async move {
+                membership.gossip(interval).await;
+                Ok(())
+            }
+        });
+    }
+
+    /// 所有成员，按地址排序
+    pub fn members(&self) -> Vec<MemberInfo> {
+        let now = Instant::now();
+        let members = self.members.lock().unwrap();
+        let mut infos: Vec<_> = members
+            .values()
+            .map(|state| MemberInfo {
+                addr: state.member.addr.clone(),
+                role: state.member.role.clone(),
+                status: self.status(state, now),
+                heartbeat: state.member.heartbeat,
+                last_seen: now.duration_since(state.updated),
+            })
+            .collect();
+        infos.sort_by(|a, b| a.addr.cmp(&b.addr));
+        infos
+    }
+
+    /// 处理其它节点发来的 gossip，返回本节点知道的成员
+    pub fn handle_gossip(&self, members: &[Member]) -> CommandResponse {
+        self.merge(members);
+        self.digest()
+            .iter()
+            .map(|member| Value::from(Bytes::from(member.encode_to_vec())))
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    /// 一轮 gossip。联系不上的成员是正常情况，由成员状态反映，不算 job 失败
+    async fn gossip(&self, timeout: Duration) {
+        self.tick();
+        for target in self.targets() {
+            let cmd = CommandRequest::new_gossip(self.digest());
+            let res = async { self.peer(&target)?.call(cmd, timeout).await };
+            match res.await {
+                Ok(res) => match decode_members(&res) {
+                    Ok(members) => self.merge(&members),
+                    Err(e) => debug!("Invalid gossip response from {}: {}", target, e),
+                },
+                Err(e) => debug!("Failed to gossip with {}: {}", target, e),
+            }
+        }
+    }
+
+    /// 递增自己的心跳
+    fn tick(&self) {
+        let mut members = self.members.lock().unwrap();
+        if let Some(me) = members.get_mut(&self.addr) {
+            me.member.heartbeat += 1;
+            me.updated = Instant::now();
+        }
+    }
+
+    /// 这一轮要发送 gossip 的成员：gossip 模式下随机选一个，静态模式下是所有成员
+    fn targets(&self) -> Vec<String> {
+        let mut addrs: Vec<String> = self
+            .members
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|addr| **addr != self.addr)
+            .cloned()
+            .collect();
+        if self.config.gossip {
+            addrs.shuffle(&mut rand::thread_rng());
+            addrs.truncate(1);
+        }
+        addrs
+    }
+
+    fn peer(&self, addr: &str) -> Result<Arc<PeerClient>, KvError> {
+        let mut peers = self.peers.lock().unwrap();
+        if let Some(peer) = peers.get(addr) {
+            return Ok(peer.clone());
+        }
+        let peer = Arc::new(PeerClient::new(addr, self.config.client.as_ref())?);
+        peers.insert(addr.into(), peer.clone());
+        Ok(peer)
+    }
+
+    /// 合并收到的成员列表，(incarnation, heartbeat) 更大的一方胜出
+    fn merge(&self, incoming: &[Member]) {
+        let now = Instant::now();
+        let mut members = self.members.lock().unwrap();
+        for member in incoming {
+            if member.addr == self.addr || member.heartbeat == 0 {
+                continue;
+            }
+            match members.get_mut(&member.addr) {
+                Some(state) => {
+                    let known = (state.member.incarnation, state.member.heartbeat);
+                    if (member.incarnation, member.heartbeat) > known {
+                        state.member = member.clone();
+                        state.updated = now;
+                    }
+                }
+                None if self.config.gossip => {
+                    info!("Member {} ({}) joined", member.addr, member.role);
+                    let state = MemberState {
+                        member: member.clone(),
+                        updated: now,
+                    };
+                    members.insert(member.addr.clone(), state);
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// 发给其它节点的成员列表，不包括已经下线的成员
+    fn digest(&self) -> Vec<Member> {
+        let now = Instant::now();
+        self.members
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|state| {
+                !matches!(
+                    self.status(state, now),
+                    MemberStatus::Down | MemberStatus::Unknown
+                )
+            })
+            .map(|state| state.member.clone())
+            .collect()
+    }
+
+    fn status(&self, state: &MemberState, now: Instant) -> MemberStatus {
+        if state.member.addr == self.addr {
+            return MemberStatus::Up;
+        }
+        if state.member.heartbeat == 0 {
+            return MemberStatus::Unknown;
+        }
+        let elapsed = now.duration_since(state.updated);
+        if elapsed >= Duration::from_millis(self.config.down_timeout_ms) {
+            MemberStatus::Down
+        } else if elapsed >= Duration::from_millis(self.config.suspect_timeout_ms) {
+            MemberStatus::Suspect
+        } else {
+            MemberStatus::Up
+        }
+    }
+}
+
+/// 节点在集群里的角色
+fn node_role(config: &ServerConfig) -> &'static str {
+    if config.raft.enabled {
+        return "raft";
+    }
+    if config.sharding.enabled {
+        return "shard";
+    }
+    match config.replication.role {
+        Role::Standalone => "standalone",
+        Role::Primary => "primary",
+        Role::Replica => "replica",
+    }
+}
+
+fn decode_members(res: &CommandResponse) -> Result<Vec<Member>, KvError> {
+    if res.status != 200 {
+        return Err(KvError::Internal(res.message.clone()));
+    }
+    res.values
+        .iter()
+        .map(|v| {
+            let data: Bytes = v.clone().try_into()?;
+            Ok(Member::decode(data)?)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn membership(addr: &str, seeds: &[&str], gossip: bool) -> Arc<Membership> {
+        let mut config = ServerConfig::builder().addr(addr).build().unwrap();
+        config.membership = MembershipConfig {
+            enabled: true,
+            seeds: seeds.iter().map(|s| s.to_string()).collect(),
+            gossip,
+            ..Default::default()
+        };
+        Membership::new(&config)
+    }
+
+    #[test]
+    fn gossip_should_spread_members() {
+        let a = membership("127.0.0.1:9527", &["127.0.0.1:9528"], true);
+        let b = membership("127.0.0.1:9528", &[], true);
+        let c = membership("127.0.0.1:9529", &["127.0.0.1:9528"], true);
+
+        // a 和 c 都只知道 b，经过 b 之后互相知道
+        let res = b.handle_gossip(&a.digest());
+        a.merge(&decode_members(&res).unwrap());
+        let res = b.handle_gossip(&c.digest());
+        c.merge(&decode_members(&res).unwrap());
+
+        let members = c.members();
+        assert_eq!(members.len(), 3);
+        assert!(members.iter().all(|m| m.status == MemberStatus::Up));
+        assert_eq!(members[0].role, "standalone");
+        assert!(
+            members[0]
+                .to_string()
+                .starts_with("addr=127.0.0.1:9527 role=standalone")
+        );
+    }
+
+    #[test]
+    fn merge_should_keep_newer_heartbeat() {
+        let a = membership("127.0.0.1:9527", &[], true);
+        let b = membership("127.0.0.1:9528", &[], true);
+        let old = b.digest();
+        b.tick();
+        a.merge(&b.digest());
+        a.merge(&old);
+
+        let members = a.members();
+        assert_eq!(members[1].heartbeat, 2);
+    }
+
+    #[test]
+    fn static_membership_should_ignore_unknown_members() {
+        let a = membership("127.0.0.1:9527", &["127.0.0.1:9528"], false);
+        let c = membership("127.0.0.1:9529", &[], false);
+        a.merge(&c.digest());
+
+        let members = a.members();
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[1].status, MemberStatus::Unknown);
+        assert_eq!(a.targets(), vec!["127.0.0.1:9528".to_string()]);
+    }
+
+    #[test]
+    fn silent_member_should_be_marked_down() {
+        let a = membership("127.0.0.1:9527", &[], true);
+        let b = membership("127.0.0.1:9528", &[], true);
+        a.merge(&b.digest());
+
+        let past = Instant::now() - Duration::from_millis(a.config.down_timeout_ms);
+        a.members
+            .lock()
+            .unwrap()
+            .get_mut("127.0.0.1:9528")
+            .unwrap()
+            .updated = past;
+
+        assert_eq!(a.members()[1].status, MemberStatus::Down);
+        // 下线的成员不会再传给其它节点
+        assert_eq!(a.digest().len(), 1);
+    }
+}
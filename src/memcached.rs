@@ -0,0 +1,304 @@
+use crate::{
+    CommandRequest, CommandResponse, MemcachedConfig, RequestContext, Service, Value,
+    accept_connection, value,
+};
+use futures::StreamExt;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+
+/// 一行命令的最大长度，和 memcached 的 key 长度限制（250）相比留出足够的余量
+const MAX_LINE: usize = 2048;
+/// 一个 value 的最大长度，和 memcached 默认的 item 大小一样
+const MAX_VALUE: usize = 1024 * 1024;
+
+/// memcached 文本协议的 accept 循环，local_only 时拒绝非本机的连接
+///
+/// 每个连接把 get/set/delete/incr/decr 翻译成对 table 的 HGET/HSET/HDEL，经过 Service 执行，
+/// 命令过滤和 hook 都和普通的命令一样生效。memcached 协议没有认证，连接没有身份，
+/// 所以配置了 auth 的密码、角色或租户时服务器不允许开启 memcached（见 ServerConfig::diagnose）；
+/// flags 总是返回 0，exptime 被忽略
+pub async fn run_memcached(listener: TcpListener, service: Service, config: MemcachedConfig) {
+    info!(
+        "Start listening for memcached on {}, table {}",
+        config.addr, config.table
+    );
+    loop {
+        let (stream, addr) = accept_connection(&listener).await;
+        if config.local_only && !addr.ip().is_loopback() {
+            warn!("Rejected memcached connection from {:?}", addr);
+            continue;
+        }
+        debug!("Memcached client {:?} connected", addr);
+        let (service, table) = (service.clone(), config.table.clone());
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream, addr, service, table).await {
+                debug!("Memcached connection {:?} closed: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// 处理一个 memcached 连接，直到客户端断开或者发送 quit
+async fn serve<S>(
+    stream: S,
+    addr: SocketAddr,
+    service: Service,
+    table: String,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ctx = RequestContext {
+        peer_addr: Some(addr),
+        // 配置了 auth 时不会开启 memcached，这里没有需要检查的密码和身份
+        authenticated: true,
+        ..Default::default()
+    };
+    let adapter = Adapter {
+        service,
+        table,
+        ctx,
+    };
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let n = (&mut stream)
+            .take(MAX_LINE as u64)
+            .read_line(&mut line)
+            .await?;
+        if n == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') {
+            stream.write_all(b"CLIENT_ERROR line too long\r\n").await?;
+            return Ok(());
+        }
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let reply = match args.as_slice() {
+            [] => continue,
+            ["quit"] => return Ok(()),
+            ["version"] => format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes(),
+            ["get" | "gets", keys @ ..] if !keys.is_empty() => adapter.get(keys).await,
+            ["set", key, _flags, _exptime, len, rest @ ..] if rest.len() <= 1 => {
+                let Ok(len) = len.parse::<usize>() else {
+                    stream.write_all(b"CLIENT_ERROR bad data chunk\r\n").await?;
+                    continue;
+                };
+                if len > MAX_VALUE {
+                    stream
+                        .write_all(b"SERVER_ERROR object too large for cache\r\n")
+                        .await?;
+                    return Ok(());
+                }
+                let mut data = vec![0; len + 2];
+                stream.read_exact(&mut data).await?;
+                if !data.ends_with(b"\r\n") {
+                    stream.write_all(b"CLIENT_ERROR bad data chunk\r\n").await?;
+                    continue;
+                }
+                data.truncate(len);
+                noreply(rest, adapter.set(key, data).await)
+            }
+            ["delete", key, rest @ ..] if rest.len() <= 1 => {
+                noreply(rest, adapter.delete(key).await)
+            }
+            [cmd @ ("incr" | "decr"), key, delta, rest @ ..] if rest.len() <= 1 => {
+                let reply = match delta.parse::<u64>() {
+                    Ok(delta) => adapter.incr(key, delta, *cmd == "incr").await,
+                    Err(_) => b"CLIENT_ERROR invalid numeric delta argument\r\n".to_vec(),
+                };
+                noreply(rest, reply)
+            }
+            _ => b"ERROR\r\n".to_vec(),
+        };
+        stream.write_all(&reply).await?;
+    }
+}
+
+/// 带 noreply 时不返回结果
+fn noreply(rest: &[&str], reply: Vec<u8>) -> Vec<u8> {
+    match rest {
+        ["noreply"] => Vec::new(),
+        _ => reply,
+    }
+}
+
+struct Adapter {
+    service: Service,
+    table: String,
+    ctx: RequestContext,
+}
+
+impl Adapter {
+    async fn execute(&self, cmd: CommandRequest) -> CommandResponse {
+        match self.service.execute_with(cmd, &self.ctx).next().await {
+            Some(res) => (*res).clone(),
+            None => CommandResponse::default(),
+        }
+    }
+
+    async fn get(&self, keys: &[&str]) -> Vec<u8> {
+        let mut reply = Vec::new();
+        for key in keys {
+            let res = self
+                .execute(CommandRequest::new_hget(&self.table, *key))
+                .await;
+            match (res.status, res.values.first()) {
+                (200, Some(value)) => {
+                    let data = to_bytes(value);
+                    reply.extend_from_slice(
+                        format!("VALUE {} 0 {}\r\n", key, data.len()).as_bytes(),
+                    );
+                    reply.extend_from_slice(&data);
+                    reply.extend_from_slice(b"\r\n");
+                }
+                (404, _) => {}
+                _ => return server_error(&res),
+            }
+        }
+        reply.extend_from_slice(b"END\r\n");
+        reply
+    }
+
+    async fn set(&self, key: &str, data: Vec<u8>) -> Vec<u8> {
+        let res = self
+            .execute(CommandRequest::new_hset(&self.table, key, data.into()))
+            .await;
+        match res.status {
+            200 => b"STORED\r\n".to_vec(),
+            _ => server_error(&res),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Vec<u8> {
+        let res = self
+            .execute(CommandRequest::new_hdel(&self.table, key))
+            .await;
+        match res.status {
+            200 => b"DELETED\r\n".to_vec(),
+            404 => b"NOT_FOUND\r\n".to_vec(),
+            _ => server_error(&res),
+        }
+    }
+
+    /// 读出旧值加上 delta 再写回，整个过程持有 key 的锁。incr 超过 u64 时回绕，decr 最小到 0
+    async fn incr(&self, key: &str, delta: u64, incr: bool) -> Vec<u8> {
+        let _guard = self.service.key_locks().lock(&self.table, key).await;
+        let res = self
+            .execute(CommandRequest::new_hget(&self.table, key))
+            .await;
+        let current = match (res.status, res.values.first()) {
+            (200, Some(value)) => value,
+            (404, _) => return b"NOT_FOUND\r\n".to_vec(),
+            _ => return server_error(&res),
+        };
+        let Some(current) = String::from_utf8(to_bytes(current))
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+        else {
+            return b"CLIENT_ERROR cannot increment or decrement non-numeric value\r\n".to_vec();
+        };
+        let new = match incr {
+            true => current.wrapping_add(delta),
+            false => current.saturating_sub(delta),
+        };
+        let res = self
+            .execute(CommandRequest::new_hset(
+                &self.table,
+                key,
+                new.to_string().into_bytes().into(),
+            ))
+            .await;
+        match res.status {
+            200 => format!("{}\r\n", new).into_bytes(),
+            _ => server_error(&res),
+        }
+    }
+}
+
+fn server_error(res: &CommandResponse) -> Vec<u8> {
+    format!("SERVER_ERROR {}\r\n", res.message).into_bytes()
+}
+
+/// 其它客户端写入的非二进制的值按文本返回
+fn to_bytes(v: &Value) -> Vec<u8> {
+    match &v.value {
+        Some(value::Value::Binary(b)) => b.to_vec(),
+        Some(value::Value::String(s)) => s.clone().into_bytes(),
+        Some(value::Value::Integer(i)) => i.to_string().into_bytes(),
+        Some(value::Value::Unsigned(u)) => u.to_string().into_bytes(),
+        Some(value::Value::Float(f)) => f.to_string().into_bytes(),
+        Some(value::Value::Bool(b)) => (*b as u8).to_string().into_bytes(),
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemTable;
+    use tokio::io::duplex;
+
+    async fn roundtrip(service: &Service, input: &[u8]) -> String {
+        let (mut client, server) = duplex(4096);
+        let addr = "127.0.0.1:50000".parse().unwrap();
+        let task = tokio::spawn(serve(server, addr, service.clone(), "mc".into()));
+        client.write_all(input).await.unwrap();
+        client.write_all(b"quit\r\n").await.unwrap();
+        task.await.unwrap().unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        output
+    }
+
+    #[tokio::test]
+    async fn memcached_commands_should_map_to_table() {
+        let service = Service::new(MemTable::new());
+        let output = roundtrip(
+            &service,
+            b"set k1 0 0 5\r\nhello\r\nget k1 k2\r\nset n 5 0 1 noreply\r\n9\r\nincr n 3\r\ndecr n 100\r\ndelete k1\r\ndelete k1\r\nincr k1 1\r\nincr k1 x\r\nbogus\r\n",
+        )
+        .await;
+        assert_eq!(
+            output,
+            "STORED\r\nVALUE k1 0 5\r\nhello\r\nEND\r\n12\r\n0\r\nDELETED\r\nNOT_FOUND\r\nNOT_FOUND\r\nCLIENT_ERROR invalid numeric delta argument\r\nERROR\r\n"
+        );
+
+        // 数据写在配置的 table 里，普通的客户端也能读到
+        let res = service
+            .execute(CommandRequest::new_hget("mc", "n"))
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(res.values, vec![b"0".into()]);
+    }
+
+    #[tokio::test]
+    async fn unsigned_value_should_be_incremented() {
+        let service = Service::new(MemTable::new());
+        let cmd = CommandRequest::new_hset("mc", "n", (u64::MAX - 1).into());
+        service.execute(cmd).next().await.unwrap();
+        let output = roundtrip(&service, b"incr n 1\r\nget n\r\n").await;
+        assert_eq!(
+            output,
+            "18446744073709551615\r\nVALUE n 0 20\r\n18446744073709551615\r\nEND\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn non_numeric_value_should_not_be_incremented() {
+        let service = Service::new(MemTable::new());
+        let output = roundtrip(
+            &service,
+            b"set k1 0 0 2\r\nab\r\nincr k1 1\r\nset k2 0 0 1\r\nab\r\n",
+        )
+        .await;
+        assert_eq!(
+            output,
+            "STORED\r\nCLIENT_ERROR cannot increment or decrement non-numeric value\r\nCLIENT_ERROR bad data chunk\r\n"
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{
+    ChangeEvent, ClientConfig, CommandRequest, CommandResponse, KvClient, KvError, MirrorConfig,
+    MirrorConflict, Service, Value, decode_change,
+};
+use futures::StreamExt;
+use std::time::Duration;
+use tokio::time;
+use tracing::{info, warn};
+
+/// 复制已有数据时每个 HMSET 里 key 的数量
+const SYNC_BATCH: usize = 128;
+
+/// 持续把本地选中的 table 的变更同步到远端服务器，出错后按间隔重新开始
+///
+/// 同步的是本地的变更流，和 HWATCH 收到的一样。远端也同步回本地时（双向同步），
+/// 用 Overwrite 会让同一个变更在两边来回同步，这时应该使用 IfMatch
+pub async fn run_mirror(service: Service, config: MirrorConfig) {
+    let Some(remote) = config.remote.clone() else {
+        return;
+    };
+    let addr = remote.general.addr.clone();
+    let interval = Duration::from_millis(config.reconnect_interval_ms);
+    loop {
+        match mirror(&service, &remote, &config).await {
+            Ok(()) => warn!("Mirror to {} fell behind, restarting", addr),
+            Err(e) => warn!("Mirror to {} failed: {}", addr, e),
+        }
+        time::sleep(interval).await;
+    }
+}
+
+/// 连接远端，复制已有数据，然后同步之后的变更，直到变更流断开
+async fn mirror(
+    service: &Service,
+    remote: &ClientConfig,
+    config: &MirrorConfig,
+) -> Result<(), KvError> {
+    let mut client = KvClient::connect(remote.clone()).await?;
+
+    // 先订阅再复制已有数据，复制期间的变更不会丢
+    let mut changes = service.watch_changes("");
+    changes.next().await;
+    if config.initial_sync {
+        let count = sync_tables(service, &mut client, config).await?;
+        info!("Mirrored {} existing keys", count);
+    }
+
+    while let Some(res) = changes.next().await {
+        let event = decode_change(&res)?;
+        if config.selects(&event.table) {
+            apply(&mut client, config.conflict, &event).await?;
+        }
+    }
+    Ok(())
+}
+
+/// 把选中的 table 里已有的数据复制到远端，返回 key 的数量
+async fn sync_tables(
+    service: &Service,
+    client: &mut KvClient,
+    config: &MirrorConfig,
+) -> Result<usize, KvError> {
+    let mut count = 0;
+    let snapshot = service.store.snapshot_handle()?;
+    for table in snapshot.tables() {
+        if !config.selects(&table) {
+            continue;
+        }
+        let pairs: Vec<_> = snapshot.get_iter(&table).collect();
+        count += pairs.len();
+        if config.conflict == MirrorConflict::Overwrite {
+            for chunk in pairs.chunks(SYNC_BATCH) {
+                let cmd = CommandRequest::new_hmset(&table, chunk.to_vec());
+                check_status(client.execute_unary(cmd).await?)?;
+            }
+            continue;
+        }
+        // 其它策略下当作新写入的 key，远端已经有的 key 不会被覆盖
+        for pair in pairs {
+            let event = ChangeEvent {
+                table: table.clone(),
+                key: pair.key,
+                new_value: pair.value,
+                ..Default::default()
+            };
+            apply(client, config.conflict, &event).await?;
+        }
+    }
+    Ok(count)
+}
+
+/// 按冲突策略把一个变更写到远端
+async fn apply(
+    client: &mut KvClient,
+    conflict: MirrorConflict,
+    event: &ChangeEvent,
+) -> Result<(), KvError> {
+    let (table, key) = (&event.table, &event.key);
+    let skip = match conflict {
+        MirrorConflict::Overwrite => false,
+        MirrorConflict::SkipExisting => {
+            event.new_value.is_none() || remote_value(client, table, key).await?.is_some()
+        }
+        MirrorConflict::IfMatch => {
+            let current = remote_value(client, table, key).await?;
+            // 远端已经是新的值时不算冲突
+            if current != event.old_value && current != event.new_value {
+                warn!("Mirror conflict on {}/{}, skipped", table, key);
+            }
+            current != event.old_value
+        }
+    };
+    if skip {
+        return Ok(());
+    }
+
+    let cmd = match &event.new_value {
+        Some(value) => CommandRequest::new_hset(table, key, value.clone()),
+        None => CommandRequest::new_hdel(table, key),
+    };
+    let res = client.execute_unary(cmd).await?;
+    // 远端已经没有要删除的 key
+    if event.new_value.is_none() && res.status == 404 {
+        return Ok(());
+    }
+    check_status(res).map(|_| ())
+}
+
+async fn remote_value(
+    client: &mut KvClient,
+    table: &str,
+    key: &str,
+) -> Result<Option<Value>, KvError> {
+    let res = client
+        .execute_unary(CommandRequest::new_hget(table, key))
+        .await?;
+    match res.status {
+        404 => Ok(None),
+        _ => Ok(check_status(res)?.values.into_iter().next()),
+    }
+}
+
+fn check_status(res: CommandResponse) -> Result<CommandResponse, KvError> {
+    match res.status {
+        200 => Ok(res),
+        _ => Err(KvError::Internal(format!("mirror failed: {}", res.message))),
+    }
+}
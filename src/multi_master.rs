@@ -0,0 +1,478 @@
+use crate::{
+    CommandRequest, CommandResponse, CrdtEntry, CrdtSync, Dot, KvError, PeerClient, PeerIps,
+    ServerConfig, Service, Storage, Value, Version, command_request::RequestData,
+    is_reserved_table,
+};
+use bytes::Bytes;
+use prost::Message;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+/// 保存 key 版本的 table 的前缀，每个数据 table 对应一个。以 __ 开头，是客户端不能读写的内部 table
+const VERSION_TABLE_PREFIX: &str = "__crdt__:";
+
+/// 向量时钟：节点 -> 这个节点上的写操作计数
+type Clock = BTreeMap<String, u64>;
+
+/// 多主模式
+///
+/// 每个节点都接受写操作。每个 key 是一个 last-writer-wins 寄存器，版本里带着向量时钟：
+/// 一个版本的时钟包含另一个时，新的版本胜出；两个版本并发时，时间戳大的胜出，时间戳相同时比较节点名。
+/// 删除的 key 留下墓碑，这样删除也能同步到其它节点，墓碑不会被清理。
+///
+/// 节点定期和每个 peer 做一次反熵同步：把 peer 上次告诉我们的时钟之后的写操作发给它，
+/// 再取回我们的时钟之后 peer 上的写操作。所有节点最终得到一样的数据
+pub struct MultiMaster {
+    node: String,
+    store: Arc<dyn Storage>,
+    /// 本节点知道的所有写操作，每个节点取最大的计数。本地写操作和合并都在这个锁里进行
+    clock: Mutex<Clock>,
+    peers: Vec<PeerClient>,
+    /// 只接受从这些 IP 发来的 CRDTSYNC
+    peer_ips: PeerIps,
+    /// 上次同步时每个 peer 的时钟
+    known: Mutex<HashMap<String, Clock>>,
+    interval: Duration,
+}
+
+impl MultiMaster {
+    /// 从 store 里已有的版本恢复时钟
+    pub fn new(config: &ServerConfig, store: Arc<dyn Storage>) -> Result<Arc<Self>, KvError> {
+        let multi_master = &config.multi_master;
+        let node = multi_master
+            .node_id
+            .clone()
+            .unwrap_or_else(|| config.general.addr.clone());
+        let peers = multi_master
+            .peers
+            .iter()
+            .map(|addr| PeerClient::new(addr, multi_master.client.as_ref()))
+            .collect::<Result<_, _>>()?;
+
+        let mut clock = Clock::new();
+        for table in version_tables(store.as_ref())? {
+            for pair in store.get_iter(&table)? {
+                let version = decode_version(pair.value)?;
+                merge_clock(&mut clock, &to_clock(&version.clock));
+            }
+        }
+
+        Ok(Arc::new(Self {
+            node,
+            store,
+            clock: Mutex::new(clock),
+            peers,
+            peer_ips: PeerIps::resolve(&multi_master.peers),
+            known: Mutex::new(HashMap::new()),
+            interval: Duration::from_millis(multi_master.sync_interval_ms),
+        }))
+    }
+
+    /// 在 svc 的调度器里注册定期做反熵同步的 job，从其它节点取回的写操作通过 svc 应用，
+    /// 会发布 keyspace 通知
+    pub fn start(self: &Arc<Self>, svc: Service) {
+        let multi_master = self.clone();
+        let scheduler = svc.scheduler();
+        let job_svc = svc.clone();
+        scheduler.spawn("anti-entropy", self.interval, move || {
+            let (multi_master, svc) = (multi_master.clone(), job_svc.clone());
+            async move { multi_master.sync_all(&svc).await }
+        });
+    }
+
+    /// 执行本地的写操作，成功后给修改的 key 生成新的版本
+    pub fn apply(
+        &self,
+        cmd: &CommandRequest,
+        f: impl FnOnce() -> CommandResponse,
+    ) -> CommandResponse {
+        // 版本是按 key 记录的，删除的 key 执行之前不知道，没法同步给其它节点
+        if let Some(RequestData::Hdelprefix(_)) = &cmd.request_data {
+            return KvError::InvalidCommand(
+                "hdelprefix is not supported in multi-master mode".into(),
+            )
+            .into();
+        }
+        let mut clock = self.clock.lock().unwrap();
+        let res = f();
+        if res.status != 200 {
+            return res;
+        }
+        if let Some((table, keys)) = cmd.modified_keys() {
+            for key in keys {
+                if let Err(e) = self.record(&mut clock, table, key) {
+                    return e.into();
+                }
+            }
+        }
+        res
+    }
+
+    /// 同步只接受 multi_master.peers 里的节点发来的。进程内的调用没有地址，不检查
+    pub fn is_peer(&self, addr: Option<SocketAddr>) -> bool {
+        self.peer_ips.contains(addr)
+    }
+
+    /// 处理其它节点发来的同步：合并对方的写操作，返回对方的时钟之后本节点的写操作
+    pub fn handle_sync(
+        &self,
+        sync: CrdtSync,
+        apply: impl Fn(CommandRequest) -> CommandResponse,
+    ) -> CommandResponse {
+        let res = self.merge(sync.entries, apply).and_then(|_| {
+            let entries = self.delta(&to_clock(&sync.clock))?;
+            let clock = to_dots(&self.clock.lock().unwrap());
+            Ok(CrdtSync { clock, entries })
+        });
+        match res {
+            Ok(sync) => Value::from(Bytes::from(sync.encode_to_vec())).into(),
+            Err(e) => e.into(),
+        }
+    }
+
+    /// 和所有 peer 各做一次同步，有 peer 同步失败时返回错误
+    async fn sync_all(&self, svc: &Service) -> Result<(), KvError> {
+        let mut failed = 0;
+        for peer in &self.peers {
+            let apply = |cmd| svc.apply_replicated(cmd);
+            match self.sync(peer, apply).await {
+                Ok(n) if n > 0 => debug!("Merged {} keys from {}", n, peer.addr()),
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Failed to sync with {}: {}", peer.addr(), e);
+                    failed += 1;
+                }
+            }
+        }
+        match failed {
+            0 => Ok(()),
+            n => Err(KvError::Internal(format!(
+                "failed to sync with {} of {} peers",
+                n,
+                self.peers.len()
+            ))),
+        }
+    }
+
+    /// 和一个 peer 做一次双向同步，返回从 peer 合并过来的 key 的数量
+    async fn sync(
+        &self,
+        peer: &PeerClient,
+        apply: impl Fn(CommandRequest) -> CommandResponse,
+    ) -> Result<usize, KvError> {
+        let known = self
+            .known
+            .lock()
+            .unwrap()
+            .get(peer.addr())
+            .cloned()
+            .unwrap_or_default();
+        let entries = self.delta(&known)?;
+        let clock = to_dots(&self.clock.lock().unwrap());
+        let res = peer
+            .call(CommandRequest::new_crdt_sync(clock, entries), self.interval)
+            .await?;
+
+        let reply = decode_sync(&res)?;
+        let n = self.merge(reply.entries, apply)?;
+        self.known
+            .lock()
+            .unwrap()
+            .insert(peer.addr().into(), to_clock(&reply.clock));
+        Ok(n)
+    }
+
+    /// 给本地修改的 key 生成新版本：在原来的时钟上递增本节点的计数
+    fn record(&self, clock: &mut Clock, table: &str, key: &str) -> Result<(), KvError> {
+        let counter = clock.entry(self.node.clone()).or_default();
+        *counter += 1;
+        let counter = *counter;
+
+        let mut key_clock = match self.load_version(table, key)? {
+            Some(version) => to_clock(&version.clock),
+            None => Clock::new(),
+        };
+        key_clock.insert(self.node.clone(), counter);
+        let version = Version {
+            clock: to_dots(&key_clock),
+            timestamp: now(),
+            node: self.node.clone(),
+            deleted: !self.store.contains(table, key)?,
+        };
+        self.save_version(table, key, &version)
+    }
+
+    /// 合并其它节点的写操作，返回覆盖了本地数据的 key 的数量
+    fn merge(
+        &self,
+        entries: Vec<CrdtEntry>,
+        apply: impl Fn(CommandRequest) -> CommandResponse,
+    ) -> Result<usize, KvError> {
+        let mut clock = self.clock.lock().unwrap();
+        let mut applied = 0;
+        for entry in entries {
+            let Some(remote) = entry.version else {
+                continue;
+            };
+            if is_reserved_table(&entry.table) {
+                let msg = format!("can not sync reserved table {}", entry.table);
+                return Err(KvError::InvalidCommand(msg));
+            }
+            let remote_clock = to_clock(&remote.clock);
+            merge_clock(&mut clock, &remote_clock);
+
+            let local = self.load_version(&entry.table, &entry.key)?;
+            let mut version = match &local {
+                Some(local) if !wins(&remote, local) => local.clone(),
+                _ => {
+                    let cmd = match (remote.deleted, entry.value) {
+                        (false, Some(value)) => {
+                            CommandRequest::new_hset(&entry.table, &entry.key, value)
+                        }
+                        _ => CommandRequest::new_hdel(&entry.table, &entry.key),
+                    };
+                    let res = apply(cmd);
+                    if res.status != 200 {
+                        return Err(KvError::Internal(res.message));
+                    }
+                    applied += 1;
+                    remote.clone()
+                }
+            };
+
+            // 合并之后的版本包含双方的时钟，下次比较时不会再被当成并发写
+            let mut merged = to_clock(&version.clock);
+            merge_clock(&mut merged, &remote_clock);
+            version.clock = to_dots(&merged);
+            if local.as_ref() != Some(&version) {
+                self.save_version(&entry.table, &entry.key, &version)?;
+            }
+        }
+        Ok(applied)
+    }
+
+    /// 时钟不被 since 包含的所有 key，也就是对方还不知道的写操作
+    fn delta(&self, since: &Clock) -> Result<Vec<CrdtEntry>, KvError> {
+        let _clock = self.clock.lock().unwrap();
+        let mut entries = Vec::new();
+        for version_table in version_tables(self.store.as_ref())? {
+            let table = &version_table[VERSION_TABLE_PREFIX.len()..];
+            for pair in self.store.get_iter(&version_table)? {
+                let version = decode_version(pair.value)?;
+                if descends(since, &to_clock(&version.clock)) {
+                    continue;
+                }
+                let value = match version.deleted {
+                    true => None,
+                    false => self.store.get(table, &pair.key)?,
+                };
+                entries.push(CrdtEntry {
+                    table: table.into(),
+                    key: pair.key,
+                    value,
+                    version: Some(version),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    /// key 当前的版本，没有写入过时返回 None
+    pub fn version(&self, table: &str, key: &str) -> Result<Option<Version>, KvError> {
+        self.load_version(table, key)
+    }
+
+    fn load_version(&self, table: &str, key: &str) -> Result<Option<Version>, KvError> {
+        self.store
+            .get(&version_table(table), key)?
+            .map(|v| decode_version(Some(v)))
+            .transpose()
+    }
+
+    fn save_version(&self, table: &str, key: &str, version: &Version) -> Result<(), KvError> {
+        let value = Value::from(Bytes::from(version.encode_to_vec()));
+        self.store.set(&version_table(table), key.into(), value)?;
+        Ok(())
+    }
+}
+
+/// remote 是否应该覆盖 local
+fn wins(remote: &Version, local: &Version) -> bool {
+    let (r, l) = (to_clock(&remote.clock), to_clock(&local.clock));
+    if descends(&l, &r) {
+        return false;
+    }
+    if descends(&r, &l) {
+        return true;
+    }
+    // 并发写，时间戳大的胜出，时间戳相同时比较节点名，每个节点得到同样的结果
+    (remote.timestamp, &remote.node) > (local.timestamp, &local.node)
+}
+
+/// a 是否包含 b：b 里每个节点的计数都不超过 a
+fn descends(a: &Clock, b: &Clock) -> bool {
+    b.iter()
+        .all(|(node, counter)| a.get(node).is_some_and(|c| c >= counter))
+}
+
+fn merge_clock(a: &mut Clock, b: &Clock) {
+    for (node, counter) in b {
+        let c = a.entry(node.clone()).or_default();
+        *c = (*c).max(*counter);
+    }
+}
+
+fn to_clock(dots: &[Dot]) -> Clock {
+    dots.iter().map(|d| (d.node.clone(), d.counter)).collect()
+}
+
+fn to_dots(clock: &Clock) -> Vec<Dot> {
+    clock
+        .iter()
+        .map(|(node, counter)| Dot {
+            node: node.clone(),
+            counter: *counter,
+        })
+        .collect()
+}
+
+fn version_table(table: &str) -> String {
+    format!("{}{}", VERSION_TABLE_PREFIX, table)
+}
+
+fn version_tables(store: &dyn Storage) -> Result<Vec<String>, KvError> {
+    Ok(store
+        .tables()?
+        .into_iter()
+        .filter(|table| table.starts_with(VERSION_TABLE_PREFIX))
+        .collect())
+}
+
+fn decode_version(value: Option<Value>) -> Result<Version, KvError> {
+    let data: Bytes = value.unwrap_or_default().try_into()?;
+    Ok(Version::decode(data)?)
+}
+
+fn decode_sync(res: &CommandResponse) -> Result<CrdtSync, KvError> {
+    match (res.status, res.values.first()) {
+        (200, Some(value)) => {
+            let data: Bytes = value.clone().try_into()?;
+            Ok(CrdtSync::decode(data)?)
+        }
+        _ => Err(KvError::Internal(format!("sync failed: {}", res.message))),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, MultiMasterConfig, dispatch};
+
+    fn node(name: &str) -> Arc<MultiMaster> {
+        let mut config = ServerConfig::builder().build().unwrap();
+        config.multi_master = MultiMasterConfig {
+            enabled: true,
+            node_id: Some(name.into()),
+            ..Default::default()
+        };
+        MultiMaster::new(&config, Arc::new(MemTable::new())).unwrap()
+    }
+
+    fn write(node: &MultiMaster, cmd: CommandRequest) {
+        let res = node.apply(&cmd, || dispatch(cmd.clone(), node.store.as_ref()));
+        assert_eq!(res.status, 200);
+    }
+
+    /// a 把 b 不知道的写操作发给 b，再取回 b 上 a 不知道的写操作
+    fn sync(a: &MultiMaster, b: &MultiMaster) {
+        let apply_b = |cmd| dispatch(cmd, b.store.as_ref());
+        let apply_a = |cmd| dispatch(cmd, a.store.as_ref());
+        let clock = to_dots(&a.clock.lock().unwrap());
+        let sync = CrdtSync {
+            clock,
+            entries: a.delta(&Clock::new()).unwrap(),
+        };
+        let reply = decode_sync(&b.handle_sync(sync, apply_b)).unwrap();
+        a.merge(reply.entries, apply_a).unwrap();
+    }
+
+    fn get(node: &MultiMaster, table: &str, key: &str) -> Option<Value> {
+        node.store.get(table, key).unwrap()
+    }
+
+    #[test]
+    fn clock_should_compare_by_containment() {
+        let a: Clock = [("a".to_string(), 2), ("b".to_string(), 1)].into();
+        let b: Clock = [("a".to_string(), 1)].into();
+        assert!(descends(&a, &b));
+        assert!(!descends(&b, &a));
+        assert!(descends(&a, &Clock::new()));
+    }
+
+    #[test]
+    fn writes_on_different_nodes_should_converge() {
+        let a = node("a");
+        let b = node("b");
+        write(&a, CommandRequest::new_hset("t1", "k1", "a".into()));
+        write(&b, CommandRequest::new_hset("t1", "k2", "b".into()));
+        sync(&a, &b);
+
+        for n in [&a, &b] {
+            assert_eq!(get(n, "t1", "k1"), Some("a".into()));
+            assert_eq!(get(n, "t1", "k2"), Some("b".into()));
+        }
+        // 版本 table 不会出现在数据里
+        assert_eq!(a.store.get_all("t1").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn later_write_should_win() {
+        let a = node("a");
+        let b = node("b");
+        write(&a, CommandRequest::new_hset("t1", "k1", "v1".into()));
+        sync(&a, &b);
+
+        // b 在 a 的写操作之后修改，时钟包含 a 的版本
+        write(&b, CommandRequest::new_hset("t1", "k1", "v2".into()));
+        sync(&a, &b);
+        assert_eq!(get(&a, "t1", "k1"), Some("v2".into()));
+
+        // 删除也会同步
+        write(&a, CommandRequest::new_hdel("t1", "k1"));
+        sync(&b, &a);
+        assert_eq!(get(&b, "t1", "k1"), None);
+    }
+
+    #[test]
+    fn concurrent_writes_should_resolve_to_same_value() {
+        let a = node("a");
+        let b = node("b");
+        write(&a, CommandRequest::new_hset("t1", "k1", "a".into()));
+        write(&b, CommandRequest::new_hset("t1", "k1", "b".into()));
+        sync(&a, &b);
+
+        let winner = get(&a, "t1", "k1");
+        assert!(winner.is_some());
+        assert_eq!(get(&b, "t1", "k1"), winner);
+    }
+
+    #[test]
+    fn clock_should_be_restored_from_store() {
+        let a = node("a");
+        write(&a, CommandRequest::new_hset("t1", "k1", "v1".into()));
+        write(&a, CommandRequest::new_hset("t1", "k2", "v2".into()));
+
+        let config = ServerConfig::builder().build().unwrap();
+        let restored = MultiMaster::new(&config, a.store.clone()).unwrap();
+        assert_eq!(restored.clock.lock().unwrap().get("a"), Some(&2));
+    }
+}
@@ -0,0 +1,131 @@
+use bytes::BytesMut;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/// 缓冲区初始容量
+const DEFAULT_CAPACITY: usize = 4 * 1024;
+/// 超过这个容量的缓冲区不再放回池子，避免长期占用大块内存
+const MAX_POOLED_CAPACITY: usize = 1024 * 1024;
+/// 池子里最多缓存的缓冲区个数
+const MAX_POOLED_BUFFERS: usize = 64;
+
+/// frame 编解码使用的全局缓冲池
+pub static FRAME_POOL: BufferPool = BufferPool::new();
+
+/// 可复用的 BytesMut 缓冲池，减少高 QPS 下的内存分配
+pub struct BufferPool {
+    bufs: Mutex<Vec<BytesMut>>,
+}
+
+impl BufferPool {
+    /// 创建一个空的缓冲池
+    pub const fn new() -> Self {
+        Self {
+            bufs: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// 从池子里取出一个空的缓冲区，没有就新建一个
+    pub fn get(&self) -> PooledBuf<'_> {
+        let buf = self
+            .bufs
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| BytesMut::with_capacity(DEFAULT_CAPACITY));
+
+        PooledBuf {
+            buf: Some(buf),
+            pool: self,
+        }
+    }
+
+    /// 池子里当前缓存的缓冲区个数
+    pub fn len(&self) -> usize {
+        self.bufs.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn put(&self, mut buf: BytesMut) {
+        if buf.capacity() > MAX_POOLED_CAPACITY {
+            return;
+        }
+
+        buf.clear();
+        let mut bufs = self.bufs.lock().unwrap();
+        if bufs.len() < MAX_POOLED_BUFFERS {
+            bufs.push(buf);
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 从 BufferPool 中借出的缓冲区，drop 时自动归还
+pub struct PooledBuf<'a> {
+    buf: Option<BytesMut>,
+    pool: &'a BufferPool,
+}
+
+impl Deref for PooledBuf<'_> {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &Self::Target {
+        self.buf.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledBuf<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.buf.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuf<'_> {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.put(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+
+    #[test]
+    fn pooled_buffer_should_be_reused() {
+        let pool = BufferPool::new();
+        assert!(pool.is_empty());
+
+        {
+            let mut buf = pool.get();
+            buf.put_slice(b"hello");
+        }
+        assert_eq!(pool.len(), 1);
+
+        // 取回的缓冲区应该是清空过的
+        let buf = pool.get();
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= DEFAULT_CAPACITY);
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn oversized_buffer_should_not_be_pooled() {
+        let pool = BufferPool::new();
+        {
+            let mut buf = pool.get();
+            buf.reserve(MAX_POOLED_CAPACITY + 1);
+        }
+        assert!(pool.is_empty());
+    }
+}
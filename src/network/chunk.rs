@@ -0,0 +1,83 @@
+use crate::{CommandResponse, KvError};
+use bytes::{Bytes, BytesMut};
+use prost::Message;
+
+/// 每个分块除了数据之外，chunk 和 more 字段本身占用的最大字节数
+const CHUNK_OVERHEAD: usize = 16;
+
+/// 把 encode 之后超过 max_frame 的响应拆成多个分块，每块带原响应 encode 之后的一段，
+/// 除了最后一块之外 more 都是 true。不超过时返回 None，按原样发送
+pub(crate) fn split_response(
+    res: &CommandResponse,
+    max_frame: usize,
+) -> Option<Vec<CommandResponse>> {
+    if res.encoded_len() <= max_frame {
+        return None;
+    }
+    let data = Bytes::from(res.encode_to_vec());
+    let size = max_frame.saturating_sub(CHUNK_OVERHEAD).max(1);
+    let count = data.len().div_ceil(size);
+    let chunks = (0..count)
+        .map(|i| CommandResponse {
+            chunk: data.slice(i * size..((i + 1) * size).min(data.len())),
+            more: i + 1 < count,
+            ..Default::default()
+        })
+        .collect();
+    Some(chunks)
+}
+
+/// 客户端把分块的响应拼回完整的响应
+#[derive(Default)]
+pub(crate) struct Reassembler {
+    buf: BytesMut,
+}
+
+impl Reassembler {
+    /// 收到一个响应。分块先缓存起来，收到最后一块时 decode 出完整的响应；不是分块的响应原样返回
+    pub fn push(&mut self, res: CommandResponse) -> Result<Option<CommandResponse>, KvError> {
+        if res.chunk.is_empty() && !res.more && self.buf.is_empty() {
+            return Ok(Some(res));
+        }
+        self.buf.extend_from_slice(&res.chunk);
+        if res.more {
+            return Ok(None);
+        }
+        let data = self.buf.split().freeze();
+        Ok(Some(CommandResponse::decode(data)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Kvpair, Value};
+
+    #[test]
+    fn large_response_should_be_split_and_reassembled() {
+        let pairs: Vec<_> = (0..100)
+            .map(|i| Kvpair::new(format!("key{}", i), Value::from(i)))
+            .collect();
+        let res: CommandResponse = pairs.into();
+        assert!(split_response(&res, res.encoded_len()).is_none());
+
+        let chunks = split_response(&res, 128).unwrap();
+        assert!(chunks.len() > 1);
+        let mut reassembler = Reassembler::default();
+        let last = chunks.len() - 1;
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            assert!(chunk.encoded_len() <= 128);
+            match reassembler.push(chunk).unwrap() {
+                Some(whole) => {
+                    assert_eq!(i, last);
+                    assert_eq!(whole, res);
+                }
+                None => assert!(i < last),
+            }
+        }
+
+        // 不是分块的响应原样返回
+        let ok = CommandResponse::ok();
+        assert_eq!(reassembler.push(ok.clone()).unwrap(), Some(ok));
+    }
+}
@@ -0,0 +1,382 @@
+use crate::{CommandRequest, CommandResponse, command_request::RequestData, intern};
+use dashmap::DashMap;
+use prost::Message;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Notify;
+
+use crate::network::frame::LEN_LEN;
+
+/// 下一个 connection id
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// 单个连接的统计信息，由网络层在处理请求时更新
+#[derive(Debug)]
+pub struct ConnectionStats {
+    pub id: u64,
+    pub peer_addr: SocketAddr,
+    pub connected_at: SystemTime,
+    commands: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    subscriptions: AtomicU64,
+    errors: AtomicU64,
+    streams: AtomicU64,
+    last_command: Mutex<&'static str>,
+    identity: Mutex<Option<String>>,
+    /// 当前还有效的订阅，subscription id -> topic
+    active_subscriptions: Mutex<HashMap<u32, Arc<str>>>,
+    /// 连接上是否已经 AUTH 成功
+    authenticated: AtomicBool,
+    killed: AtomicBool,
+    disconnected: AtomicBool,
+    notify: Notify,
+}
+
+/// 某个时间点上连接统计信息的快照
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub peer_addr: SocketAddr,
+    pub connected_at: SystemTime,
+    pub age: Duration,
+    pub commands: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub subscriptions: u64,
+    pub errors: u64,
+    pub streams: u64,
+    pub last_command: &'static str,
+    /// 认证后的客户端身份，没有认证时为 None
+    pub identity: Option<String>,
+}
+
+impl ConnectionStats {
+    fn new(peer_addr: SocketAddr) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            peer_addr,
+            connected_at: SystemTime::now(),
+            commands: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            subscriptions: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            streams: AtomicU64::new(0),
+            last_command: Mutex::new(""),
+            identity: Mutex::new(None),
+            active_subscriptions: Mutex::new(HashMap::new()),
+            authenticated: AtomicBool::new(false),
+            killed: AtomicBool::new(false),
+            disconnected: AtomicBool::new(false),
+            notify: Notify::new(),
+        }
+    }
+
+    /// 记录客户端认证后的身份
+    pub fn set_identity(&self, identity: impl Into<String>) {
+        *self.identity.lock().unwrap() = Some(identity.into());
+    }
+
+    /// 认证后的身份
+    pub fn identity(&self) -> Option<String> {
+        self.identity.lock().unwrap().clone()
+    }
+
+    /// 记录收到的请求
+    pub fn record_request(&self, cmd: &CommandRequest) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in
+            .fetch_add((cmd.encoded_len() + LEN_LEN) as u64, Ordering::Relaxed);
+        if let Some(RequestData::Subscribe(_)) = cmd.request_data {
+            self.subscriptions.fetch_add(1, Ordering::Relaxed);
+        }
+        *self.last_command.lock().unwrap() = cmd.name();
+    }
+
+    /// 记录发出的响应
+    pub fn record_response(&self, res: &CommandResponse) {
+        self.bytes_out
+            .fetch_add((res.encoded_len() + LEN_LEN) as u64, Ordering::Relaxed);
+        if res.status >= 400 {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// 根据 SUBSCRIBE/UNSUBSCRIBE 的第一个响应更新当前有效的订阅
+    pub fn track_subscription(&self, data: &RequestData, res: &CommandResponse) {
+        let mut subs = self.active_subscriptions.lock().unwrap();
+        match data {
+            RequestData::Subscribe(param) => {
+                if let Ok(id) = i64::try_from(res) {
+                    subs.insert(id as u32, intern(&param.topic));
+                }
+            }
+            RequestData::Unsubscribe(param) if res.status < 400 => {
+                subs.remove(&param.id);
+            }
+            _ => {}
+        }
+    }
+
+    /// 当前有效的订阅，(topic, subscription id)
+    pub fn active_subscriptions(&self) -> Vec<(Arc<str>, u32)> {
+        let subs = self.active_subscriptions.lock().unwrap();
+        subs.iter()
+            .map(|(id, topic)| (Arc::clone(topic), *id))
+            .collect()
+    }
+
+    /// 记录连接上 AUTH 成功，之后这个连接上所有的 stream 都不需要再认证
+    pub fn set_authenticated(&self) {
+        self.authenticated.store(true, Ordering::Release);
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated.load(Ordering::Acquire)
+    }
+
+    /// 要求断开这个连接，等待在 closed() 上的任务会被唤醒
+    pub fn kill(&self) {
+        self.killed.store(true, Ordering::Release);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::Acquire)
+    }
+
+    /// 等到连接被 kill 或者已经断开
+    pub async fn closed(&self) {
+        loop {
+            // 先拿到 notified 再检查状态，避免错过检查之后才发出的通知
+            let notified = self.notify.notified();
+            if self.is_killed() || self.disconnected.load(Ordering::Acquire) {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// 记录一个新打开的 stream，返回的 guard drop 时 stream 计数减一
+    pub fn open_stream(self: &Arc<Self>) -> StreamGuard {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        StreamGuard(self.clone())
+    }
+
+    pub fn snapshot(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            id: self.id,
+            peer_addr: self.peer_addr,
+            connected_at: self.connected_at,
+            age: self.connected_at.elapsed().unwrap_or_default(),
+            commands: self.commands.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            subscriptions: self.subscriptions.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            streams: self.streams.load(Ordering::Relaxed),
+            last_command: *self.last_command.lock().unwrap(),
+            identity: self.identity.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// 类似 redis CLIENT LIST 的单行输出
+impl fmt::Display for ConnectionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "id={} addr={} user={} age={} cmds={} bytes_in={} bytes_out={} subs={} errors={} streams={} cmd={}",
+            self.id,
+            self.peer_addr,
+            self.identity.as_deref().unwrap_or("-"),
+            self.age.as_secs(),
+            self.commands,
+            self.bytes_in,
+            self.bytes_out,
+            self.subscriptions,
+            self.errors,
+            self.streams,
+            self.last_command,
+        )
+    }
+}
+
+/// 打开的 stream 计数，drop 时自动减一
+pub struct StreamGuard(Arc<ConnectionStats>);
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 当前所有连接的注册表
+#[derive(Debug, Default)]
+pub struct ConnectionRegistry {
+    conns: DashMap<u64, Arc<ConnectionStats>>,
+}
+
+impl ConnectionRegistry {
+    /// 注册一个新连接，返回的 guard drop 时连接自动从注册表中删除
+    pub fn register(self: &Arc<Self>, peer_addr: SocketAddr) -> ConnectionGuard {
+        let stats = Arc::new(ConnectionStats::new(peer_addr));
+        self.conns.insert(stats.id, stats.clone());
+        ConnectionGuard {
+            stats,
+            registry: self.clone(),
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<ConnectionStats>> {
+        self.conns.get(&id).map(|v| v.value().clone())
+    }
+
+    /// 按 connection id 排序返回所有连接的快照
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut list: Vec<_> = self.conns.iter().map(|v| v.value().snapshot()).collect();
+        list.sort_by_key(|v| v.id);
+        list
+    }
+
+    /// 断开所有满足条件的连接，返回断开的连接数
+    pub fn kill(&self, f: impl Fn(&ConnectionStats) -> bool) -> usize {
+        let mut killed = 0;
+        for conn in self.conns.iter().filter(|v| f(v.value())) {
+            conn.value().kill();
+            killed += 1;
+        }
+        killed
+    }
+
+    pub fn len(&self) -> usize {
+        self.conns.len()
+    }
+
+    /// 所有连接上正在处理的 stream 数量
+    pub fn open_streams(&self) -> u64 {
+        self.conns
+            .iter()
+            .map(|v| v.value().streams.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conns.is_empty()
+    }
+}
+
+/// 持有连接的生命周期，drop 时把连接从注册表中删除
+pub struct ConnectionGuard {
+    stats: Arc<ConnectionStats>,
+    registry: Arc<ConnectionRegistry>,
+}
+
+impl ConnectionGuard {
+    pub fn stats(&self) -> Arc<ConnectionStats> {
+        self.stats.clone()
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.registry.conns.remove(&self.stats.id);
+        self.stats.disconnected.store(true, Ordering::Release);
+        self.stats.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    #[test]
+    fn connection_registry_should_track_connections() {
+        let registry = Arc::new(ConnectionRegistry::default());
+        let addr: SocketAddr = "127.0.0.1:9527".parse().unwrap();
+
+        let conn1 = registry.register(addr);
+        let conn2 = registry.register(addr);
+        assert_eq!(registry.len(), 2);
+        assert!(conn1.stats().id < conn2.stats().id);
+
+        let stream = conn2.stats().open_stream();
+        assert_eq!(registry.open_streams(), 1);
+        drop(stream);
+        assert_eq!(registry.open_streams(), 0);
+
+        drop(conn1);
+        assert_eq!(registry.len(), 1);
+        assert_eq!(registry.list()[0].id, conn2.stats().id);
+    }
+
+    #[test]
+    fn connection_stats_should_be_recorded() {
+        let registry = Arc::new(ConnectionRegistry::default());
+        let conn = registry.register("127.0.0.1:9527".parse().unwrap());
+        let stats = conn.stats();
+
+        let stream = stats.open_stream();
+        stats.record_request(&CommandRequest::new_hget("t1", "k1"));
+        stats.record_request(&CommandRequest::new_subscribe("lobby"));
+        stats.record_response(&Value::from("v1").into());
+        stats.record_response(&CommandResponse::internal_error("oops".into()));
+
+        let info = stats.snapshot();
+        assert_eq!(info.commands, 2);
+        assert_eq!(info.subscriptions, 1);
+        assert_eq!(info.errors, 1);
+        assert_eq!(info.streams, 1);
+        assert_eq!(info.last_command, "subscribe");
+        assert!(info.bytes_in > 0 && info.bytes_out > 0);
+
+        drop(stream);
+        assert_eq!(stats.snapshot().streams, 0);
+
+        assert!(info.to_string().contains("user=-"));
+        stats.set_identity("cert:abcd");
+        assert!(stats.snapshot().to_string().contains("user=cert:abcd"));
+    }
+
+    #[test]
+    fn active_subscriptions_should_be_tracked() {
+        let registry = Arc::new(ConnectionRegistry::default());
+        let conn = registry.register("127.0.0.1:9527".parse().unwrap());
+        let stats = conn.stats();
+
+        let cmd = CommandRequest::new_subscribe("lobby");
+        let res: CommandResponse = Value::from(1).into();
+        stats.track_subscription(cmd.request_data.as_ref().unwrap(), &res);
+        assert_eq!(stats.active_subscriptions(), vec![("lobby".into(), 1)]);
+
+        let cmd = CommandRequest::new_unsubscribe("lobby", 1);
+        let res: CommandResponse = Value::from(1).into();
+        stats.track_subscription(cmd.request_data.as_ref().unwrap(), &res);
+        assert!(stats.active_subscriptions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn kill_should_wake_up_closed() {
+        let registry = Arc::new(ConnectionRegistry::default());
+        let conn1 = registry.register("127.0.0.1:9527".parse().unwrap());
+        let conn2 = registry.register("127.0.0.1:9528".parse().unwrap());
+        let (stats1, stats2) = (conn1.stats(), conn2.stats());
+
+        let waiter = tokio::spawn(async move { stats1.closed().await });
+        let addr: SocketAddr = "127.0.0.1:9527".parse().unwrap();
+        assert_eq!(registry.kill(|conn| conn.peer_addr == addr), 1);
+        waiter.await.unwrap();
+        assert!(!stats2.is_killed());
+
+        // 正常断开的连接同样会唤醒 closed()
+        let waiter = tokio::spawn(async move { stats2.closed().await });
+        drop(conn2);
+        waiter.await.unwrap();
+    }
+}
@@ -1,8 +1,9 @@
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 
+use crate::network::buffer::FRAME_POOL;
 use crate::{CommandRequest, CommandResponse, KvError};
 use bytes::{Buf, BufMut, BytesMut};
-use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use flate2::{Compression, bufread::GzDecoder, write::GzEncoder};
 use prost::Message;
 use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::debug;
@@ -15,6 +16,42 @@ const MAX_FRAME: usize = 2 * 1024 * 1024 * 1024;
 const COMPRESSION_LIMIT: usize = 1436;
 /// 代表压缩的 bit（整个长度 4 字节的最高位）
 const COMPRESSION_BIT: usize = 1 << 31;
+/// gzip 数据开头的两个字节
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// 解码 frame 时的限制
+///
+/// 默认只检查协议本身的上限。来自不可信网络的数据应该使用 strict 模式：
+/// frame 和解压缩之后的大小都有上限，缓冲区随着实际收到的数据增长，
+/// 压缩标记和内容不一致、gzip 之后还有多余数据的 frame 会被拒绝
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameLimits {
+    /// frame 的最大长度（压缩后）
+    pub max_frame: usize,
+    /// 解压缩之后的最大长度
+    pub max_decompressed: usize,
+    pub strict: bool,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        Self {
+            max_frame: MAX_FRAME - 1,
+            max_decompressed: usize::MAX,
+            strict: false,
+        }
+    }
+}
+
+impl FrameLimits {
+    pub fn strict(max_frame: usize, max_decompressed: usize) -> Self {
+        Self {
+            max_frame: max_frame.min(MAX_FRAME - 1),
+            max_decompressed,
+            strict: true,
+        }
+    }
+}
 
 /// 处理 Frame 的 encode/decode
 pub trait FrameCoder
@@ -26,37 +63,37 @@ where
         let size = self.encoded_len();
 
         if size >= MAX_FRAME {
-            return Err(KvError::FrameError);
+            return Err(KvError::Frame(format!(
+                "message of {} bytes is larger than max size",
+                size
+            )));
         }
 
-        // 我们先写入长度，如果需要压缩，再重写压缩后的长度
-        buf.put_u32(size as _);
-
         if size > COMPRESSION_LIMIT {
-            let mut buf1 = Vec::with_capacity(size);
-            self.encode(&mut buf1)?;
+            // 先把消息 encode 到池子里借来的缓冲区，避免每次都分配
+            let mut raw = FRAME_POOL.get();
+            self.encode(&mut *raw)?;
 
-            // BytesMut 支持逻辑上的 split（之后还能 unsplit）
-            // 所以我们先把长度这 4 字节拿走，清除
-            let payload = buf.split_off(LEN_LEN);
-            buf.clear();
+            // 先占住 4 字节长度，gzip 直接写到 buf 尾部，
+            // 压缩完成后再回填长度，省去 split/unsplit 和额外的拷贝
+            let start = buf.len();
+            buf.put_u32(0);
 
             // 处理 gzip 压缩，具体可以参考 flate2 文档
-            let mut encoder = GzEncoder::new(payload.writer(), Compression::default());
-            encoder.write_all(&buf1[..])?;
-
-            // 压缩完成后，从 gzip encoder 中把 BytesMut 再拿回来
-            let payload = encoder.finish()?.into_inner();
-            debug!("Encode a frame: size {}({})", size, payload.len());
-
-            // 写入压缩后的长度
-            buf.put_u32((payload.len() | COMPRESSION_BIT) as _);
+            let mut encoder = GzEncoder::new((&mut *buf).writer(), Compression::default());
+            encoder.write_all(&raw[..])?;
+            encoder.finish()?;
 
-            // 把 BytesMut 再合并回来
-            buf.unsplit(payload);
+            // 回填压缩后的长度
+            let len = buf.len() - start - LEN_LEN;
+            debug!("Encode a frame: size {}({})", size, len);
+            let header = ((len | COMPRESSION_BIT) as u32).to_be_bytes();
+            buf[start..start + LEN_LEN].copy_from_slice(&header);
 
             Ok(())
         } else {
+            buf.reserve(LEN_LEN + size);
+            buf.put_u32(size as _);
             self.encode(buf)?;
             Ok(())
         }
@@ -64,20 +101,39 @@ where
 
     /// 把一个完整的 frame decode 成一个 Message
     fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
+        Self::decode_frame_with(buf, &FrameLimits::default())
+    }
+
+    /// 按给定的限制把一个完整的 frame decode 成一个 Message，不完整的 frame 返回错误而不是 panic
+    fn decode_frame_with(buf: &mut BytesMut, limits: &FrameLimits) -> Result<Self, KvError> {
+        if buf.len() < LEN_LEN {
+            return Err(KvError::Frame(format!(
+                "header needs {} bytes, got {}",
+                LEN_LEN,
+                buf.len()
+            )));
+        }
         // 先取 4 字节，从中拿出长度和 compression bit
         let header = buf.get_u32() as usize;
         let (len, compressed) = decode_header(header);
         debug!("Got a frame: msg len {}, compressed {}", len, compressed);
+        check_len(len, limits)?;
+        if buf.len() < len {
+            return Err(KvError::Frame(format!(
+                "frame needs {} bytes, got {}",
+                len,
+                buf.len()
+            )));
+        }
 
         if compressed {
-            // 解压缩
-            let mut decoder = GzDecoder::new(&buf[..len]);
-            let mut buf1 = Vec::with_capacity(len * 2);
-            decoder.read_to_end(&mut buf1)?;
+            // 解压缩到池子里借来的缓冲区
+            let mut raw = FRAME_POOL.get();
+            decompress(&buf[..len], &mut raw, limits)?;
             buf.advance(len);
 
             // decode 成相应的消息
-            Ok(Self::decode(&buf1[..buf1.len()])?)
+            Ok(Self::decode(&raw[..])?)
         } else {
             let msg = Self::decode(&buf[..len])?;
             buf.advance(len);
@@ -89,21 +145,81 @@ where
 impl FrameCoder for CommandRequest {}
 impl FrameCoder for CommandResponse {}
 
-fn decode_header(header: usize) -> (usize, bool) {
+pub(crate) fn decode_header(header: usize) -> (usize, bool) {
     let len = header & !COMPRESSION_BIT;
     let compressed = header & COMPRESSION_BIT == COMPRESSION_BIT;
     (len, compressed)
 }
 
+pub(crate) fn check_len(len: usize, limits: &FrameLimits) -> Result<(), KvError> {
+    if len > limits.max_frame {
+        return Err(KvError::FrameTooLarge(format!(
+            "frame of {} bytes is larger than {}",
+            len, limits.max_frame
+        )));
+    }
+    Ok(())
+}
+
+/// 解压缩 gzip 数据，解压缩之后超过上限时立即停止
+fn decompress(data: &[u8], out: &mut BytesMut, limits: &FrameLimits) -> Result<(), KvError> {
+    if limits.strict && !data.starts_with(&GZIP_MAGIC) {
+        return Err(KvError::Frame("compressed frame is not gzip".into()));
+    }
+    let max = limits.max_decompressed;
+    let mut decoder = GzDecoder::new(data);
+    let n = io::copy(
+        &mut (&mut decoder).take((max as u64).saturating_add(1)),
+        &mut out.writer(),
+    )
+    .map_err(|e| KvError::Frame(format!("invalid gzip data: {}", e)))?;
+    if n > max as u64 {
+        return Err(KvError::FrameTooLarge(format!(
+            "decompressed frame is larger than {}",
+            max
+        )));
+    }
+    if limits.strict && !decoder.into_inner().is_empty() {
+        return Err(KvError::Frame("trailing data after gzip stream".into()));
+    }
+    Ok(())
+}
+
 /// 从 stream 中读取一个完整的 frame
 pub async fn read_frame<S>(stream: &mut S, buf: &mut BytesMut) -> Result<(), KvError>
+where
+    S: AsyncRead + Unpin + Send,
+{
+    read_frame_with(stream, buf, &FrameLimits::default()).await
+}
+
+/// 按给定的限制从 stream 中读取一个完整的 frame，超过长度上限的 frame 在分配内存之前就被拒绝
+pub async fn read_frame_with<S>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    limits: &FrameLimits,
+) -> Result<(), KvError>
 where
     S: AsyncRead + Unpin + Send,
 {
     let header = stream.read_u32().await? as usize;
-    println!("Header received: {}", header);
     let (len, _compressed) = decode_header(header);
-    println!("Frame length: {}", len);
+    debug!("Frame header received: len {}", len);
+    check_len(len, limits)?;
+
+    if limits.strict {
+        // 缓冲区随着实际收到的数据增长，对端声明了很大的长度却不发送数据时不会提前分配
+        buf.put_u32(header as _);
+        let end = buf.len() + len;
+        let mut payload = (&mut *stream).take(len as u64);
+        while buf.len() < end {
+            if payload.read_buf(buf).await? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+        return Ok(());
+    }
+
     // 如果没有这么大的内存，就分配至少一个 frame 的内存，保证它可用
     buf.reserve(LEN_LEN + len);
     buf.put_u32(header as _);
@@ -166,6 +282,26 @@ mod tests {
         assert_eq!(res, res1);
     }
 
+    #[test]
+    fn compressed_frame_appended_to_existing_buffer_should_work() {
+        let mut buf = BytesMut::new();
+
+        // 先写入一个未压缩的 frame，再追加一个压缩的 frame
+        let cmd = CommandRequest::new_hdel("t1", "k1");
+        cmd.encode_frame(&mut buf).unwrap();
+        let value: Value = Bytes::from(vec![1u8; COMPRESSION_LIMIT * 4]).into();
+        let res: CommandResponse = value.into();
+        res.encode_frame(&mut buf).unwrap();
+
+        let cmd1 = CommandRequest::decode_frame(&mut buf).unwrap();
+        assert_eq!(cmd, cmd1);
+
+        assert!(is_compressed(&buf));
+        let res1 = CommandResponse::decode_frame(&mut buf).unwrap();
+        assert_eq!(res, res1);
+        assert!(buf.is_empty());
+    }
+
     fn is_compressed(data: &[u8]) -> bool {
         if let &[v] = &data[..1] {
             v >> 7 == 1
@@ -187,4 +323,53 @@ mod tests {
         let cmd1 = CommandRequest::decode_frame(&mut data).unwrap();
         assert_eq!(cmd, cmd1);
     }
+
+    #[tokio::test]
+    async fn strict_read_frame_should_not_trust_length() {
+        // 声明 1G 的长度，实际只有几个字节
+        let mut buf = BytesMut::new();
+        buf.put_u32(1 << 30);
+        buf.put_slice(b"hello");
+        let mut stream = DummyStream { buf };
+
+        let limits = FrameLimits::strict(usize::MAX, usize::MAX);
+        let mut data = BytesMut::new();
+        assert!(
+            read_frame_with(&mut stream, &mut data, &limits)
+                .await
+                .is_err()
+        );
+        assert!(data.capacity() < 1024 * 1024);
+
+        // 超过上限的长度直接拒绝
+        let mut buf = BytesMut::new();
+        buf.put_u32(1024);
+        let mut stream = DummyStream { buf };
+        let limits = FrameLimits::strict(512, usize::MAX);
+        let res = read_frame_with(&mut stream, &mut BytesMut::new(), &limits).await;
+        assert!(matches!(res, Err(KvError::FrameTooLarge(_))));
+    }
+
+    #[test]
+    fn decode_frame_should_reject_oversized_decompression() {
+        let mut buf = BytesMut::new();
+        let value: Value = Bytes::from(vec![0u8; 1024 * 1024]).into();
+        let res: CommandResponse = value.into();
+        res.encode_frame(&mut buf).unwrap();
+
+        let limits = FrameLimits::strict(usize::MAX, 1024);
+        let res = CommandResponse::decode_frame_with(&mut buf.clone(), &limits);
+        assert!(matches!(res, Err(KvError::FrameTooLarge(_))));
+        // 默认的限制可以正常解码
+        assert!(CommandResponse::decode_frame(&mut buf).is_ok());
+    }
+
+    #[test]
+    fn decode_incomplete_frame_should_fail() {
+        assert!(CommandRequest::decode_frame(&mut BytesMut::from(&[0u8, 0][..])).is_err());
+        let mut buf = BytesMut::new();
+        buf.put_u32(100);
+        buf.put_slice(b"short");
+        assert!(CommandRequest::decode_frame(&mut buf).is_err());
+    }
 }
@@ -1,26 +1,66 @@
+mod buffer;
+mod chunk;
+mod connection;
 mod frame;
 mod multiplex;
 mod noise;
+mod peer;
+mod socket;
 mod stream;
 mod stream_result;
 mod tls;
 
-pub use frame::{FrameCoder, read_frame};
-use futures::{SinkExt, StreamExt};
+pub use buffer::{BufferPool, FRAME_POOL, PooledBuf};
+pub use connection::{
+    ConnectionGuard, ConnectionInfo, ConnectionRegistry, ConnectionStats, StreamGuard,
+};
+pub use frame::{FrameCoder, FrameLimits, read_frame, read_frame_with};
+use futures::{FutureExt, SinkExt, StreamExt, future};
+pub(crate) use multiplex::MAX_NUM_STREAMS;
 pub use multiplex::YamuxCtrl;
-pub use noise::{NoiseClientConnector, NoiseServerAcceptor, load_key};
-pub use tls::{TlsClientConnector, TlsServerAcceptor};
+pub use noise::{
+    NoiseClientConnector, NoisePattern, NoiseServerAcceptor, generate_keypair, load_key,
+    load_key_file,
+};
+pub use peer::{PeerClient, PeerIps};
+pub use socket::{accept_connection, bind_listener, set_socket_options};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+pub use stream_result::StreamResult;
+pub use tls::{
+    RevocationList, TlsClientConnector, TlsServerAcceptor, cert_fingerprint, parse_cipher_suites,
+    peer_identity, verify_key_pair,
+};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tracing::info;
+use tokio::time;
+use tracing::{info, info_span, warn};
 
+use crate::network::chunk::{Reassembler, split_response};
 use crate::network::stream::ProstStream;
-use crate::network::stream_result::StreamResult;
-use crate::{CommandRequest, CommandResponse, KvError, Service};
+use crate::{
+    CommandRequest, CommandResponse, KvError, RequestContext, Service, command_request::RequestData,
+};
+
+/// 任意可读写的 stream，用于屏蔽 TLS / Noise / 明文 TCP 之间的差异
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> AsyncStream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// 类型擦除后的 stream
+pub type BoxedStream = Box<dyn AsyncStream>;
 
 /// 处理服务器端的某个 accept 下来的 socket 的读写
 pub struct ProstServerStream<S> {
     inner: ProstStream<S, CommandRequest, CommandResponse>,
     service: Service,
+    conn: Option<Arc<ConnectionStats>>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    /// 超过这个大小的响应拆成多个 frame 发送
+    max_response_frame: usize,
+    /// 连续收到多少个无法 decode 的 frame 之后断开 stream
+    max_malformed_frames: usize,
 }
 
 /// 处理客户端 socket 的读写
@@ -36,21 +76,226 @@ where
         Self {
             inner: ProstStream::new(stream),
             service,
+            conn: None,
+            read_timeout: None,
+            write_timeout: None,
+            max_response_frame: FrameLimits::default().max_frame,
+            max_malformed_frames: 0,
         }
     }
 
+    /// 设置读取下一个命令和发送响应的超时时间，避免不读也不写的客户端一直占着 stream 和缓冲区
+    pub fn with_timeouts(mut self, read: Option<Duration>, write: Option<Duration>) -> Self {
+        self.read_timeout = read;
+        self.write_timeout = write;
+        self
+    }
+
+    /// 设置读取客户端 frame 时的限制
+    pub fn with_frame_limits(mut self, limits: FrameLimits) -> Self {
+        self.inner = self.inner.with_limits(limits);
+        self
+    }
+
+    /// 设置单个响应 frame 的最大字节数（压缩前），更大的响应拆成多个 frame，由客户端拼起来
+    pub fn with_max_response_frame(mut self, size: usize) -> Self {
+        self.max_response_frame = size;
+        self
+    }
+
+    /// 允许连续收到 limit 个无法 decode 的 frame，每个回复 400 之后继续读下一个 frame，
+    /// 默认为 0，收到第一个就断开 stream
+    ///
+    /// 只有完整收到的 frame 才能跳过：frame 按 header 里的长度整个取出来之后才 decode，
+    /// 坏的内容不会影响下一个 frame 的边界。读取出错和连接关闭仍然直接断开
+    pub fn with_max_malformed_frames(mut self, limit: usize) -> Self {
+        self.max_malformed_frames = limit;
+        self
+    }
+
+    /// 关联所属的连接，处理请求时会更新连接的统计信息
+    pub fn with_connection(mut self, conn: Arc<ConnectionStats>) -> Self {
+        self.conn = Some(conn);
+        self
+    }
+
     pub async fn process(mut self) -> Result<(), KvError> {
+        let _guard = self.conn.as_ref().map(|conn| conn.open_stream());
         let stream = &mut self.inner;
-        while let Some(Ok(cmd)) = stream.next().await {
-            info!("Got a new command: {:?}", cmd);
-            let mut res = self.service.execute(cmd);
-            while let Some(data) = res.next().await {
-                stream.send(&data).await?;
+        // 没有关联连接时，认证只对这个 stream 有效
+        let mut authenticated = !self.service.requires_auth();
+        // 身份在 TLS 握手时确定，之后不会改变
+        let identity = self.conn.as_ref().and_then(|conn| conn.identity());
+        // 连续收到的无法 decode 的 frame 数
+        let mut malformed = 0;
+        loop {
+            // 客户端 pipeline 的下一个命令已经收到时，响应留在写缓存里和下一个响应一起发出
+            if !stream.has_buffered_frame() {
+                with_timeout(self.write_timeout, "write", stream.flush()).await??;
+            }
+            let mut cmd = match with_timeout(self.read_timeout, "read", stream.next()).await? {
+                Some(Ok(cmd)) => {
+                    malformed = 0;
+                    cmd
+                }
+                // 超过上限的 frame 已经被跳过，回复错误之后继续处理后面的命令，不断开连接
+                Some(Err(e @ KvError::FrameTooLarge(_))) => {
+                    let res: CommandResponse = e.into();
+                    if let Some(conn) = &self.conn {
+                        conn.record_response(&res);
+                    }
+                    with_timeout(self.write_timeout, "write", stream.feed(&res)).await??;
+                    continue;
+                }
+                Some(Err(e @ (KvError::Frame(_) | KvError::DecodeError(_))))
+                    if malformed < self.max_malformed_frames =>
+                {
+                    malformed += 1;
+                    warn!(
+                        "Skip malformed frame ({}/{}): {}",
+                        malformed, self.max_malformed_frames, e
+                    );
+                    let res: CommandResponse = KvError::MalformedFrame(e.to_string()).into();
+                    if let Some(conn) = &self.conn {
+                        conn.record_response(&res);
+                    }
+                    with_timeout(self.write_timeout, "write", stream.feed(&res)).await??;
+                    continue;
+                }
+                _ => break,
+            };
+            // 沿用客户端带来的请求 id，没有时生成一个。代理到其它节点时一起带过去
+            if cmd.request_id.is_empty() {
+                cmd.request_id = next_request_id();
+            }
+            let span = info_span!("request", request_id = %cmd.request_id, cmd = cmd.name());
+            let is_auth = matches!(cmd.request_data, Some(RequestData::Auth(_)));
+            span.in_scope(|| match is_auth {
+                true => info!("Got a new command: auth"),
+                false => info!("Got a new command: {:?}", cmd),
+            });
+            let mut request_id = Some(cmd.request_id.clone());
+            if let Some(conn) = &self.conn {
+                conn.record_request(&cmd);
+            }
+            // 设置了密码时，AUTH 成功之前只接受 AUTH 命令，之后按身份的角色检查权限
+            authenticated =
+                authenticated || self.conn.as_ref().is_some_and(|c| c.is_authenticated());
+            let denied = match authenticated || is_auth {
+                true => self.service.authorize(identity.as_deref(), &cmd).err(),
+                false => Some(KvError::Unauthorized(format!(
+                    "{} requires AUTH",
+                    cmd.name()
+                ))),
+            };
+            if let Some(e) = denied {
+                let mut res: CommandResponse = e.into();
+                res.request_id = request_id.take().unwrap_or_default();
+                if let Some(conn) = &self.conn {
+                    conn.record_response(&res);
+                }
+                with_timeout(self.write_timeout, "write", stream.feed(&res)).await??;
+                continue;
+            }
+            // SUBSCRIBE/UNSUBSCRIBE 的第一个响应决定订阅是否生效，需要记到连接上
+            let mut pending = match &cmd.request_data {
+                Some(data @ (RequestData::Subscribe(_) | RequestData::Unsubscribe(_))) => {
+                    Some(data.clone())
+                }
+                _ => None,
+            };
+            let ctx = RequestContext {
+                conn_id: self.conn.as_ref().map(|conn| conn.id),
+                peer_addr: self.conn.as_ref().map(|conn| conn.peer_addr),
+                identity: identity.clone(),
+                authenticated,
+                role: self.service.role(identity.as_deref()),
+            };
+            // 第一个响应发出之前算作处理中的请求，订阅之后的持续推送不算
+            let mut in_flight = self.service.track_request();
+            let mut res = span.in_scope(|| self.service.execute_with(cmd, &ctx));
+            loop {
+                let mut data = match res.next().now_or_never() {
+                    Some(Some(data)) => data,
+                    Some(None) => break,
+                    // 后面的响应还没有准备好（比如订阅在等待消息），先把已经缓存的发出去
+                    None => {
+                        with_timeout(self.write_timeout, "write", stream.flush()).await??;
+                        match res.next().await {
+                            Some(data) => data,
+                            None => break,
+                        }
+                    }
+                };
+                // 流式的响应只有第一个带上请求 id
+                if let Some(id) = request_id.take() {
+                    Arc::make_mut(&mut data).request_id = id;
+                }
+                if let Some(conn) = &self.conn {
+                    conn.record_response(&data);
+                    if let Some(req) = pending.take() {
+                        conn.track_subscription(&req, &data);
+                    }
+                }
+                if is_auth && data.status == 200 {
+                    authenticated = true;
+                    if let Some(conn) = &self.conn {
+                        conn.set_authenticated();
+                    }
+                }
+                match split_response(&data, self.max_response_frame) {
+                    Some(chunks) => {
+                        for chunk in &chunks {
+                            with_timeout(self.write_timeout, "write", stream.feed(chunk)).await??;
+                        }
+                    }
+                    None => with_timeout(self.write_timeout, "write", stream.feed(&data)).await??,
+                }
+                self.service.notify_sent(&ctx);
+                in_flight.take();
             }
         }
         // info!("Client {:?} disconnected", self.addr);
         Ok(())
     }
+
+    /// 不处理这个 stream：读到第一个命令后回复错误，然后结束 stream，连接上的其它 stream 不受影响
+    pub async fn reject(mut self, e: KvError) -> Result<(), KvError> {
+        let stream = &mut self.inner;
+        let cmd = match with_timeout(self.read_timeout, "read", stream.next()).await? {
+            Some(Ok(cmd)) => cmd,
+            _ => return Ok(()),
+        };
+        let mut res: CommandResponse = e.into();
+        res.request_id = cmd.request_id;
+        if let Some(conn) = &self.conn {
+            conn.record_response(&res);
+        }
+        with_timeout(self.write_timeout, "write", stream.send(&res)).await??;
+        Ok(())
+    }
+}
+
+/// 生成进程内唯一的请求 id：启动时随机生成的前缀加上递增的序号，多个节点之间也不容易重复
+fn next_request_id() -> String {
+    static PREFIX: OnceLock<u32> = OnceLock::new();
+    static SEQ: AtomicU64 = AtomicU64::new(1);
+    let prefix = PREFIX.get_or_init(rand::random);
+    format!("{:08x}-{:x}", prefix, SEQ.fetch_add(1, Ordering::Relaxed))
+}
+
+/// 超时返回 KvError::StreamTimeout，timeout 为 None 时一直等待
+async fn with_timeout<F: Future>(
+    timeout: Option<Duration>,
+    op: &'static str,
+    fut: F,
+) -> Result<F::Output, KvError> {
+    match timeout {
+        Some(timeout) => time::timeout(timeout, fut)
+            .await
+            .map_err(|_| KvError::StreamTimeout(op)),
+        None => Ok(fut.await),
+    }
 }
 
 impl<S> ProstClientStream<S>
@@ -64,30 +309,65 @@ where
     }
 
     pub async fn execute_unary(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        self.inner.send(&cmd).await?;
+        self.next_response().await
+    }
+
+    /// 读取下一个完整的响应，分块发送的响应在这里拼起来
+    async fn next_response(&mut self) -> Result<CommandResponse, KvError> {
+        let mut reassembler = Reassembler::default();
+        loop {
+            let res = self
+                .inner
+                .next()
+                .await
+                .unwrap_or_else(|| Err(KvError::Internal("didn't get any response".into())))?;
+            if let Some(res) = reassembler.push(res)? {
+                return Ok(res);
+            }
+        }
+    }
+
+    /// 把多个命令一次性写出，再按顺序读回所有响应，省去每个命令一次的往返
+    pub async fn execute_pipeline(
+        &mut self,
+        cmds: &[CommandRequest],
+    ) -> Result<Vec<CommandResponse>, KvError> {
         let stream = &mut self.inner;
-        stream.send(&cmd).await?;
-        stream
-            .next()
-            .await
-            .unwrap_or_else(|| Err(KvError::Internal("didn't get any response".into())))
+        for cmd in cmds {
+            stream.feed(cmd).await?;
+        }
+        stream.flush().await?;
+
+        let mut responses = Vec::with_capacity(cmds.len());
+        for _ in cmds {
+            responses.push(self.next_response().await?);
+        }
+        Ok(responses)
     }
 
     pub async fn execute_stream(self, cmd: &CommandRequest) -> Result<StreamResult, KvError> {
         let mut stream = self.inner;
         stream.send(cmd).await?;
         stream.close().await?;
-        StreamResult::new(stream).await
+        let stream = stream
+            .scan(Reassembler::default(), |reassembler, res| {
+                future::ready(Some(res.and_then(|res| reassembler.push(res)).transpose()))
+            })
+            .filter_map(future::ready);
+        StreamResult::new(Box::pin(stream)).await
     }
 }
 
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
-    use bytes::Bytes;
+    use bytes::{Bytes, BytesMut};
     use std::net::SocketAddr;
+    use tokio::io::AsyncWriteExt;
     use tokio::net::{TcpListener, TcpStream};
 
-    use crate::{MemTable, Value, assert_res_ok};
+    use crate::{AccessControl, AccessRole, AuthConfig, Kvpair, MemTable, Value, assert_res_ok};
 
     use super::*;
 
@@ -118,6 +398,31 @@ mod tests {
         let res = client.execute_stream(&cmd).await?;
         let id = res.id;
         assert!(id > 0);
+        assert_eq!(res.subscription().id, id);
+        assert_eq!(res.subscription().topic, "chat");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_pipeline_should_work() -> Result<()> {
+        let addr = start_server().await?;
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        let cmds = vec![
+            CommandRequest::new_hset("t3", "k1", "v1".into()),
+            CommandRequest::new_hget("t3", "k1"),
+            CommandRequest::new_hget("t3", "k2"),
+        ];
+        let res = client.execute_pipeline(&cmds).await?;
+
+        // 响应顺序和命令顺序一致
+        assert_eq!(res.len(), 3);
+        assert_res_ok(&res[0], &[Value::default()], &[]);
+        assert_res_ok(&res[1], &["v1".into()], &[]);
+        assert_eq!(res[2].status, 404);
 
         Ok(())
     }
@@ -143,6 +448,159 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn oversized_response_should_be_sent_in_chunks() -> Result<()> {
+        let (client, server) = tokio::io::duplex(4096);
+        let service = Service::new(MemTable::new());
+        let server = ProstServerStream::new(server, service).with_max_response_frame(256);
+        tokio::spawn(server.process());
+        let mut client = ProstClientStream::new(client);
+
+        let pairs: Vec<_> = (0..64)
+            .map(|i| Kvpair::new(format!("key{:02}", i), Value::from(i)))
+            .collect();
+        client
+            .execute_unary(CommandRequest::new_hmset("t1", pairs.clone()))
+            .await?;
+
+        // 拆开发送的响应在客户端拼回完整的响应，pipeline 里的下一个响应不受影响
+        let cmds = [
+            CommandRequest::new_hgetall("t1"),
+            CommandRequest::new_hget("t1", "key01"),
+        ];
+        let res = client.execute_pipeline(&cmds).await?;
+        let mut got = res[0].pairs.clone();
+        got.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(got, pairs);
+        assert_res_ok(&res[1], &[1.into()], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn responses_should_carry_request_id() -> Result<()> {
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(ProstServerStream::new(server, Service::new(MemTable::new())).process());
+        let mut client = ProstClientStream::new(client);
+
+        // 客户端带来的 id 原样返回，没有带时服务器生成一个
+        let cmd = CommandRequest::new_hget("t1", "k1").with_request_id("req-1");
+        let res = client.execute_unary(cmd).await?;
+        assert_eq!(res.status, 404);
+        assert_eq!(res.request_id, "req-1");
+        let cmds = [
+            CommandRequest::new_hget("t1", "k1"),
+            CommandRequest::new_hget("t1", "k1"),
+        ];
+        let res = client.execute_pipeline(&cmds).await?;
+        assert!(!res[0].request_id.is_empty());
+        assert_ne!(res[0].request_id, res[1].request_id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_should_be_skipped_when_allowed() -> Result<()> {
+        // 一个 body 不是合法 protobuf 的 frame，后面跟着一个正常的命令
+        let mut buf = BytesMut::from(&[0, 0, 0, 3, 0xff, 0xff, 0xff][..]);
+        CommandRequest::new_hget("t1", "k1").encode_frame(&mut buf)?;
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        let service = Service::new(MemTable::new());
+        let server = ProstServerStream::new(server, service.clone()).with_max_malformed_frames(1);
+        tokio::spawn(server.process());
+        client.write_all(&buf).await?;
+        let mut client = ProstStream::<_, CommandResponse, CommandRequest>::new(client);
+        let res = client.next().await.unwrap()?;
+        assert_eq!(res.status, 400);
+        assert!(res.message.starts_with("Malformed frame"));
+        assert_eq!(client.next().await.unwrap()?.status, 404);
+
+        // 默认收到第一个坏的 frame 就断开
+        let (mut client, server) = tokio::io::duplex(4096);
+        tokio::spawn(ProstServerStream::new(server, service).process());
+        client.write_all(&buf).await?;
+        let mut client = ProstStream::<_, CommandResponse, CommandRequest>::new(client);
+        assert!(client.next().await.unwrap().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn commands_should_require_auth_when_password_is_set() -> Result<()> {
+        let (client, server) = tokio::io::duplex(4096);
+        let service = Service::new(MemTable::new()).with_password("secret");
+        tokio::spawn(ProstServerStream::new(server, service).process());
+        let mut client = ProstClientStream::new(client);
+
+        let res = client
+            .execute_unary(CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_eq!(res.status, 401);
+        let res = client
+            .execute_unary(CommandRequest::new_auth("wrong"))
+            .await?;
+        assert_eq!(res.status, 401);
+
+        let res = client
+            .execute_unary(CommandRequest::new_auth("secret"))
+            .await?;
+        assert_eq!(res.status, 200);
+        let res = client
+            .execute_unary(CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_eq!(res.status, 404);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn commands_should_be_limited_by_role() -> Result<()> {
+        let (client, server) = tokio::io::duplex(4096);
+        let auth = AuthConfig {
+            default_role: Some(AccessRole::ReadOnly),
+            ..Default::default()
+        };
+        let service = Service::new(MemTable::new()).with_access_control(AccessControl::new(&auth));
+        tokio::spawn(ProstServerStream::new(server, service).process());
+        let mut client = ProstClientStream::new(client);
+
+        let res = client
+            .execute_unary(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await?;
+        assert_eq!(res.status, 403);
+        let res = client
+            .execute_unary(CommandRequest::new_hget("t1", "k1"))
+            .await?;
+        assert_eq!(res.status, 404);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn idle_stream_should_time_out() {
+        let (_client, server) = tokio::io::duplex(64);
+        let server = ProstServerStream::new(server, Service::new(MemTable::new()))
+            .with_timeouts(Some(Duration::from_millis(10)), None);
+        let res = server.process().await;
+        assert!(matches!(res, Err(KvError::StreamTimeout("read"))));
+    }
+
+    #[tokio::test]
+    async fn stalled_reader_should_time_out() -> Result<()> {
+        let service = Service::new(MemTable::new());
+        let value: Value = Bytes::from((0..1024).map(|i| i as u8).collect::<Vec<_>>()).into();
+        let cmd = CommandRequest::new_hset("t1", "k1", value);
+        service.execute(cmd).next().await;
+
+        // 客户端发出 HGET 之后不再读取，响应写不进只有 16 字节的缓冲区
+        let (mut client, server) = tokio::io::duplex(16);
+        let server = ProstServerStream::new(server, service)
+            .with_timeouts(None, Some(Duration::from_millis(10)));
+        let handle = tokio::spawn(server.process());
+        let mut buf = BytesMut::new();
+        CommandRequest::new_hget("t1", "k1").encode_frame(&mut buf)?;
+        client.write_all(&buf).await?;
+        let res = handle.await?;
+        assert!(matches!(res, Err(KvError::StreamTimeout("write"))));
+        Ok(())
+    }
+
     async fn start_server() -> Result<SocketAddr> {
         let listener = TcpListener::bind("127.0.0.1:0").await?;
         let addr = listener.local_addr()?;
@@ -6,6 +6,10 @@ use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompat
 use tracing::instrument;
 use yamux::{Config, Connection, ConnectionError, Control, Mode, WindowUpdateMode};
 
+/// 每个 yamux 连接最多打开的 stream 数量，超过时 yamux 断开整个连接。
+/// 同时处理的 stream 也以此为上限：达到上限后不再 poll 连接，低于它就不会卡住已有的 stream
+pub(crate) const MAX_NUM_STREAMS: usize = 8192;
+
 /// Yamux 控制结构
 pub struct YamuxCtrl<S> {
     /// yamux control，用于创建新的 stream
@@ -13,6 +17,16 @@ pub struct YamuxCtrl<S> {
     _conn: PhantomData<S>,
 }
 
+// Control 本身可以 clone，S 只是个类型占位符，所以手动实现 Clone
+impl<S> Clone for YamuxCtrl<S> {
+    fn clone(&self) -> Self {
+        Self {
+            ctrl: self.ctrl.clone(),
+            _conn: PhantomData,
+        }
+    }
+}
+
 impl<S> YamuxCtrl<S>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
@@ -49,6 +63,7 @@ where
         // 创建 config
         let mut config = config.unwrap_or_default();
         config.set_window_update_mode(WindowUpdateMode::OnRead);
+        config.set_max_num_streams(MAX_NUM_STREAMS);
 
         // 创建 config，yamux::Stream 使用的是 futures 的 trait 所以需要 compat() 到 tokio 的 trait
         let conn = Connection::new(stream.compat(), config, mode);
@@ -57,7 +72,7 @@ where
         let ctrl = conn.control();
 
         // pull 所有 stream 下的数据
-        tokio::spawn(yamux::into_stream(conn).try_for_each_concurrent(None, f));
+        tokio::spawn(yamux::into_stream(conn).try_for_each_concurrent(MAX_NUM_STREAMS, f));
 
         Self {
             ctrl,
@@ -73,6 +88,11 @@ where
         let stream = self.ctrl.open_stream().await?;
         Ok(ProstClientStream::new(stream.compat()))
     }
+
+    /// 关闭整个 yamux 会话，所有 stream 随之关闭
+    pub async fn close(&mut self) -> Result<(), ConnectionError> {
+        self.ctrl.close().await
+    }
 }
 
 #[cfg(test)]
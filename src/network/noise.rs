@@ -1,14 +1,86 @@
-use bytes::{Buf, BytesMut};
-use snow::{Builder, TransportState};
+use bytes::{Buf, BufMut, BytesMut};
+use futures::ready;
+use serde::{Deserialize, Serialize};
+use snow::{Builder, HandshakeState, TransportState};
+use std::fmt;
 use std::pin::Pin;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::task::{Context, Poll};
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 
 use crate::KvError;
 
 /// KV Server 自己的协议标识 Noise<握手的模式>  <公钥算法>  <对称加密算法>  <哈希算法>。
 const PROTOCOL_NAME: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// PSK 的长度（协议规定）
+const PSK_LEN: usize = 32;
+
+/// Noise 握手的模式，客户端和服务器必须使用同一个模式
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NoisePattern {
+    /// 双方在握手中交换静态公钥，需要 1.5 个 RTT
+    #[default]
+    XX,
+    /// 客户端事先知道服务器公钥，并发送自己的静态公钥，1 个 RTT 完成握手
+    IK,
+    /// 客户端事先知道服务器公钥，客户端不使用静态密钥，1 个 RTT 完成握手
+    NK,
+}
+
+impl NoisePattern {
+    /// 握手的消息数，PSK 在最后一条消息里混入
+    fn messages(&self) -> u8 {
+        match self {
+            NoisePattern::XX => 3,
+            NoisePattern::IK | NoisePattern::NK => 2,
+        }
+    }
+
+    /// 客户端是否需要事先知道服务器公钥
+    fn needs_remote_key(&self) -> bool {
+        !matches!(self, NoisePattern::XX)
+    }
+
+    fn protocol_name(&self, psk: bool) -> String {
+        match psk {
+            true => format!(
+                "Noise_{}psk{}_25519_ChaChaPoly_BLAKE2s",
+                self,
+                self.messages()
+            ),
+            false => format!("Noise_{}_25519_ChaChaPoly_BLAKE2s", self),
+        }
+    }
+}
+
+impl fmt::Display for NoisePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            NoisePattern::XX => "XX",
+            NoisePattern::IK => "IK",
+            NoisePattern::NK => "NK",
+        };
+        f.write_str(s)
+    }
+}
+
+impl FromStr for NoisePattern {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "XX" => Ok(NoisePattern::XX),
+            "IK" => Ok(NoisePattern::IK),
+            "NK" => Ok(NoisePattern::NK),
+            _ => Err(KvError::InvalidConfig(format!(
+                "unknown noise pattern: {}",
+                s
+            ))),
+        }
+    }
+}
 
 /// 存放 Noise Server 配置并提供方法 accept 把底层的协议转换成 Noise
 #[derive(Clone)]
@@ -27,6 +99,9 @@ pub struct NoiseClientConnector {
 pub struct NoiseConfig {
     pub static_key: Option<Vec<u8>>,
     pub remote_public_key: Option<Vec<u8>>,
+    /// 预共享密钥，设置后双方的 PSK 必须一致才能完成握手
+    pub psk: Option<Vec<u8>>,
+    pub pattern: NoisePattern,
 }
 
 impl NoiseConfig {
@@ -34,17 +109,95 @@ impl NoiseConfig {
         Self {
             static_key,
             remote_public_key,
+            psk: None,
+            pattern: NoisePattern::default(),
         }
     }
+
+    /// 按配置创建握手状态
+    fn handshake_state(&self, initiator: bool) -> Result<HandshakeState, KvError> {
+        let pattern = self.pattern;
+        let name = pattern.protocol_name(self.psk.is_some());
+        let mut builder = Builder::new(name.parse()?);
+        if let Some(psk) = &self.psk {
+            builder = builder.psk(pattern.messages(), check_psk(psk)?)?;
+        }
+
+        // NK 模式下客户端不使用静态密钥
+        if let Some(key) = &self.static_key
+            && !(initiator && pattern == NoisePattern::NK)
+        {
+            builder = builder.local_private_key(key)?;
+        }
+
+        match &self.remote_public_key {
+            Some(key) => builder = builder.remote_public_key(key)?,
+            None if initiator && pattern.needs_remote_key() => {
+                return Err(KvError::InvalidConfig(format!(
+                    "noise pattern {} requires the server public key",
+                    pattern
+                )));
+            }
+            None => {}
+        }
+
+        Ok(match initiator {
+            true => builder.build_initiator()?,
+            false => builder.build_responder()?,
+        })
+    }
+}
+
+/// 按握手模式交替收发握手消息，直到握手完成
+async fn handshake<S>(stream: &mut S, mut state: HandshakeState) -> Result<TransportState, KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0u8; MAX_MESSAGE_LEN];
+    let mut msg = vec![0u8; MAX_MESSAGE_LEN];
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let len = state.write_message(&[], &mut buffer)?;
+            send_handshake(stream, &buffer[..len]).await?;
+        } else {
+            let len = recv_handshake(stream, &mut msg).await?;
+            state.read_message(&msg[..len], &mut buffer)?;
+        }
+    }
+    Ok(state.into_transport_mode()?)
+}
+
+fn check_psk(psk: &[u8]) -> Result<&[u8; PSK_LEN], KvError> {
+    psk.try_into().map_err(|_| {
+        KvError::InvalidConfig(format!(
+            "noise psk must be {} bytes, got {}",
+            PSK_LEN,
+            psk.len()
+        ))
+    })
 }
 
+/// Noise 单条消息的最大长度（协议规定）
+const MAX_MESSAGE_LEN: usize = 65535;
+/// ChaChaPoly 认证 tag 的长度
+const TAG_LEN: usize = 16;
+/// 单条消息能承载的最大明文长度
+const MAX_PAYLOAD_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+/// 每条消息前 2 字节存放消息长度
+const MSG_LEN_LEN: usize = 2;
+
 /// Noise 流包装器
+///
+/// 底层 TCP 不保证消息边界，所以每条加密消息前都加上 2 字节长度，
+/// 读取时凑齐一条完整的消息再解密
 pub struct NoiseStream<S> {
     inner: S,
     state: TransportState,
-    read_buffer: BytesMut,   // 存储解密的数据
-    encrypt_buffer: Vec<u8>, // 加密临时缓冲区
-    decrypt_buffer: Vec<u8>, // 解密临时缓冲区
+    read_buffer: BytesMut,      // 存储解密的数据
+    encrypted_buffer: BytesMut, // 还没凑成完整消息的密文
+    write_buffer: BytesMut,     // 已加密、还没写入底层的数据
+    encrypt_buffer: Vec<u8>,    // 加密临时缓冲区
+    decrypt_buffer: Vec<u8>,    // 解密临时缓冲区
 }
 
 impl<S: AsyncRead + AsyncWrite + Unpin> NoiseStream<S> {
@@ -53,9 +206,44 @@ impl<S: AsyncRead + AsyncWrite + Unpin> NoiseStream<S> {
             inner,
             state,
             read_buffer: BytesMut::with_capacity(4096),
-            encrypt_buffer: vec![0u8; 4096],
-            decrypt_buffer: vec![0u8; 4096],
+            encrypted_buffer: BytesMut::with_capacity(4096),
+            write_buffer: BytesMut::with_capacity(4096),
+            encrypt_buffer: vec![0u8; MAX_MESSAGE_LEN],
+            decrypt_buffer: vec![0u8; MAX_MESSAGE_LEN],
+        }
+    }
+
+    /// 从密文缓冲区中取出一条完整的消息并解密
+    fn decrypt_message(&mut self) -> std::io::Result<bool> {
+        if self.encrypted_buffer.len() < MSG_LEN_LEN {
+            return Ok(false);
+        }
+        let len = u16::from_be_bytes([self.encrypted_buffer[0], self.encrypted_buffer[1]]) as usize;
+        if self.encrypted_buffer.len() < MSG_LEN_LEN + len {
+            return Ok(false);
         }
+
+        self.encrypted_buffer.advance(MSG_LEN_LEN);
+        let msg = self.encrypted_buffer.split_to(len);
+        let n = self
+            .state
+            .read_message(&msg, &mut self.decrypt_buffer)
+            .map_err(|e| std::io::Error::other(format!("Decryption error: {}", e)))?;
+        self.read_buffer
+            .extend_from_slice(&self.decrypt_buffer[..n]);
+        Ok(true)
+    }
+
+    /// 把已加密的数据写入底层 stream
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.write_buffer.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buffer))?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buffer.advance(n);
+        }
+        Poll::Ready(Ok(()))
     }
 }
 
@@ -67,54 +255,33 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for NoiseStream<S> {
     ) -> Poll<std::io::Result<()>> {
         let this = &mut *self;
 
-        // 首先从缓冲区提供已解密的数据
-        if !this.read_buffer.is_empty() {
-            let to_copy = std::cmp::min(this.read_buffer.len(), buf.remaining());
-            buf.put_slice(&this.read_buffer[..to_copy]);
-            this.read_buffer.advance(to_copy);
-            return Poll::Ready(Ok(()));
-        }
+        loop {
+            // 首先从缓冲区提供已解密的数据
+            if !this.read_buffer.is_empty() {
+                let to_copy = std::cmp::min(this.read_buffer.len(), buf.remaining());
+                buf.put_slice(&this.read_buffer[..to_copy]);
+                this.read_buffer.advance(to_copy);
+                return Poll::Ready(Ok(()));
+            }
 
-        // 需要从底层流读取加密数据
-        let mut encrypted_buf = [0u8; 4096];
-        let mut read_buf = ReadBuf::new(&mut encrypted_buf);
+            // 密文缓冲区里如果已经有完整的消息，解密后再试
+            if this.decrypt_message()? {
+                continue;
+            }
 
-        match Pin::new(&mut this.inner).poll_read(cx, &mut read_buf) {
-            Poll::Ready(Ok(())) => {
-                let n = read_buf.filled().len();
-                if n == 0 {
-                    return Poll::Ready(Ok(())); // EOF
-                }
+            // 需要从底层流读取更多加密数据
+            let mut encrypted_buf = [0u8; 4096];
+            let mut read_buf = ReadBuf::new(&mut encrypted_buf);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
 
-                // 解密数据
-                match this
-                    .state
-                    .read_message(&encrypted_buf[..n], &mut this.decrypt_buffer)
-                {
-                    Ok(decrypted_len) => {
-                        if decrypted_len == 0 {
-                            return Poll::Ready(Ok(()));
-                        }
-
-                        // 将解密的数据存入缓冲区
-                        this.read_buffer
-                            .extend_from_slice(&this.decrypt_buffer[..decrypted_len]);
-
-                        // 提供数据给调用者
-                        let to_copy = std::cmp::min(this.read_buffer.len(), buf.remaining());
-                        buf.put_slice(&this.read_buffer[..to_copy]);
-                        this.read_buffer.advance(to_copy);
-
-                        Poll::Ready(Ok(()))
-                    }
-                    Err(e) => Poll::Ready(Err(std::io::Error::other(format!(
-                        "Decryption error: {}",
-                        e
-                    )))),
+            let n = read_buf.filled().len();
+            if n == 0 {
+                if this.encrypted_buffer.is_empty() {
+                    return Poll::Ready(Ok(())); // EOF
                 }
+                return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
             }
-            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
-            Poll::Pending => Poll::Pending,
+            this.encrypted_buffer.extend_from_slice(read_buf.filled());
         }
     }
 }
@@ -127,34 +294,57 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for NoiseStream<S> {
     ) -> Poll<std::io::Result<usize>> {
         let this = &mut *self;
 
-        // 加密数据
-        match this.state.write_message(buf, &mut this.encrypt_buffer) {
-            Ok(encrypted_len) => {
-                // 写入加密后的数据
-                match Pin::new(&mut this.inner)
-                    .poll_write(cx, &this.encrypt_buffer[..encrypted_len])
-                {
-                    Poll::Ready(Ok(_)) => Poll::Ready(Ok(buf.len())),
-                    Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
-                    Poll::Pending => Poll::Pending,
-                }
-            }
-            Err(e) => Poll::Ready(Err(std::io::Error::other(format!(
-                "Encryption error: {}",
-                e
-            )))),
+        // 先把之前没写完的密文写出去，避免缓冲区无限增长
+        ready!(this.poll_write_pending(cx))?;
+
+        // 加密数据，超过单条消息上限的部分留给下一次 poll_write
+        let n = std::cmp::min(buf.len(), MAX_PAYLOAD_LEN);
+        let encrypted_len = this
+            .state
+            .write_message(&buf[..n], &mut this.encrypt_buffer)
+            .map_err(|e| std::io::Error::other(format!("Encryption error: {}", e)))?;
+        this.write_buffer.put_u16(encrypted_len as u16);
+        this.write_buffer
+            .extend_from_slice(&this.encrypt_buffer[..encrypted_len]);
+
+        // 尽量写入底层；没写完的部分由下一次 poll_write / poll_flush 继续
+        if let Poll::Ready(Err(e)) = this.poll_write_pending(cx) {
+            return Poll::Ready(Err(e));
         }
+        Poll::Ready(Ok(n))
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_write_pending(cx))?;
         Pin::new(&mut self.inner).poll_flush(cx)
     }
 
     fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_write_pending(cx))?;
         Pin::new(&mut self.inner).poll_shutdown(cx)
     }
 }
 
+/// 发送一条带 2 字节长度的握手消息
+async fn send_handshake<S>(stream: &mut S, msg: &[u8]) -> Result<(), KvError>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_u16(msg.len() as u16).await?;
+    stream.write_all(msg).await?;
+    Ok(())
+}
+
+/// 接收一条带 2 字节长度的握手消息，返回消息长度
+async fn recv_handshake<S>(stream: &mut S, buf: &mut [u8]) -> Result<usize, KvError>
+where
+    S: AsyncRead + Unpin,
+{
+    let len = stream.read_u16().await? as usize;
+    stream.read_exact(&mut buf[..len]).await?;
+    Ok(len)
+}
+
 impl NoiseClientConnector {
     /// 创建新的 Noise 客户端连接器
     pub fn new(
@@ -168,37 +358,32 @@ impl NoiseClientConnector {
         })
     }
 
+    /// 使用预共享密钥（比如 Noise_XXpsk3），服务器必须配置同一个 PSK
+    pub fn with_psk(self, psk: Vec<u8>) -> Result<Self, KvError> {
+        check_psk(&psk)?;
+        let mut config = (*self.config).clone();
+        config.psk = Some(psk);
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    /// 设置握手模式，IK 和 NK 需要事先知道服务器公钥
+    pub fn with_pattern(self, pattern: NoisePattern) -> Self {
+        let mut config = (*self.config).clone();
+        config.pattern = pattern;
+        Self {
+            config: Arc::new(config),
+        }
+    }
+
     /// 触发 Noise 协议握手，把底层的 stream 转换成 Noise stream
     pub async fn connect<S>(&self, mut stream: S) -> Result<NoiseStream<S>, KvError>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send,
     {
-        let mut builder = Builder::new(PROTOCOL_NAME.parse()?);
-
-        if let Some(key) = &self.config.static_key {
-            builder = builder.local_private_key(key)?;
-        }
-
-        if let Some(key) = &self.config.remote_public_key {
-            builder = builder.remote_public_key(key)?;
-        }
-
-        let mut state = builder.build_initiator()?;
-        let mut buffer = vec![0u8; 65536];
-
-        // 握手阶段 1: 发送消息
-        let len = state.write_message(&[], &mut buffer)?;
-        tokio::io::AsyncWriteExt::write_all(&mut stream, &buffer[..len]).await?;
-
-        // 握手阶段 2: 接收并处理响应
-        let len = tokio::io::AsyncReadExt::read(&mut stream, &mut buffer).await?;
-        state.read_message(&buffer[..len], &mut [])?;
-
-        // 握手阶段 3: 发送最终消息
-        let len = state.write_message(&[], &mut buffer)?;
-        tokio::io::AsyncWriteExt::write_all(&mut stream, &buffer[..len]).await?;
-
-        let transport = state.into_transport_mode()?;
+        let state = self.config.handshake_state(true)?;
+        let transport = handshake(&mut stream, state).await?;
 
         Ok(NoiseStream::new(stream, transport))
     }
@@ -214,37 +399,32 @@ impl NoiseServerAcceptor {
         })
     }
 
+    /// 使用预共享密钥（比如 Noise_XXpsk3），只接受配置了同一个 PSK 的客户端
+    pub fn with_psk(self, psk: Vec<u8>) -> Result<Self, KvError> {
+        check_psk(&psk)?;
+        let mut config = (*self.config).clone();
+        config.psk = Some(psk);
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    /// 设置握手模式，只接受使用同一个模式的客户端
+    pub fn with_pattern(self, pattern: NoisePattern) -> Self {
+        let mut config = (*self.config).clone();
+        config.pattern = pattern;
+        Self {
+            config: Arc::new(config),
+        }
+    }
+
     /// 触发 Noise 协议握手，把底层的 stream 转换成 Noise stream
     pub async fn accept<S>(&self, mut stream: S) -> Result<NoiseStream<S>, KvError>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send,
     {
-        let mut builder = Builder::new(PROTOCOL_NAME.parse()?);
-
-        if let Some(key) = &self.config.static_key {
-            builder = builder.local_private_key(key)?;
-        }
-
-        if let Some(key) = &self.config.remote_public_key {
-            builder = builder.remote_public_key(key)?;
-        }
-
-        let mut state = builder.build_responder()?;
-        let mut buffer = vec![0u8; 65536];
-
-        // 握手阶段 1: 接收客户端消息
-        let len = tokio::io::AsyncReadExt::read(&mut stream, &mut buffer).await?;
-        state.read_message(&buffer[..len], &mut [])?;
-
-        // 握手阶段 2: 发送响应
-        let len = state.write_message(&[], &mut buffer)?;
-        tokio::io::AsyncWriteExt::write_all(&mut stream, &buffer[..len]).await?;
-
-        // 握手阶段 3: 接收最终消息
-        let len = tokio::io::AsyncReadExt::read(&mut stream, &mut buffer).await?;
-        state.read_message(&buffer[..len], &mut [])?;
-
-        let transport = state.into_transport_mode()?;
+        let state = self.config.handshake_state(false)?;
+        let transport = handshake(&mut stream, state).await?;
 
         Ok(NoiseStream::new(stream, transport))
     }
@@ -264,10 +444,28 @@ pub fn load_key(key: &str) -> Result<Vec<u8>, KvError> {
     Err(KvError::CertifcateParseError("noise", "key"))
 }
 
+/// 从文件加载密钥，文件内容和 load_key 的格式一致
+pub fn load_key_file(path: &str) -> Result<Vec<u8>, KvError> {
+    load_key(&std::fs::read_to_string(path)?)
+}
+
+/// 生成一对静态密钥，返回 base64 编码的 (私钥, 公钥)，可以直接用 load_key 加载
+pub fn generate_keypair() -> Result<(String, String), KvError> {
+    let keypair = Builder::new(PROTOCOL_NAME.parse()?).generate_keypair()?;
+    Ok((
+        base64::encode(keypair.private),
+        base64::encode(keypair.public),
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{CommandRequest, MemTable, ProstServerStream, Service, YamuxCtrl, assert_res_ok};
     use anyhow::Result;
+    use std::net::SocketAddr;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::compat::FuturesAsyncReadCompatExt;
     // use tokio::{
     //     io::{AsyncReadExt, AsyncWriteExt},
     //     net::{TcpListener},
@@ -359,6 +557,230 @@ mod tests {
     //     Ok(addr)
     // }
 
+    #[tokio::test]
+    async fn noise_large_payload_should_work() -> Result<()> {
+        let addr = start_echo_server().await?;
+        let mut stream = connect(addr).await?;
+
+        // 超过单条 Noise 消息上限的数据需要拆成多条消息
+        let data = vec![42u8; MAX_PAYLOAD_LEN * 2 + 100];
+        stream.write_all(&data).await?;
+        stream.flush().await?;
+        let mut buf = vec![0u8; data.len()];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(buf, data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn noise_yamux_client_server_should_work() -> Result<()> {
+        let addr = start_yamux_server().await?;
+        let stream = connect(addr).await?;
+        let mut ctrl = YamuxCtrl::new_client(stream, None);
+
+        // 在同一个 Noise 连接上打开多个 stream
+        let mut stream1 = ctrl.open_stream().await?;
+        let mut stream2 = ctrl.open_stream().await?;
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        stream1.execute_unary(cmd).await?;
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = stream2.execute_unary(cmd).await?;
+        assert_res_ok(&res, &["v1".into()], &[]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn noise_with_psk_should_work() -> Result<()> {
+        let psk = load_key(SERVER_PRIVATE_KEY)?;
+        let addr = start_psk_server(psk.clone()).await?;
+
+        let connector = connector()?.with_psk(psk)?;
+        let mut stream = connector.connect(TcpStream::connect(addr).await?).await?;
+        stream.write_all(b"hello").await?;
+        stream.flush().await?;
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn noise_with_mismatched_psk_should_fail() -> Result<()> {
+        let addr = start_psk_server(load_key(SERVER_PRIVATE_KEY)?).await?;
+
+        // 没有 PSK 和 PSK 不一致的客户端都无法完成握手
+        let stream = TcpStream::connect(addr).await?;
+        assert!(connect_stream(&connector()?, stream).await.is_err());
+        let addr = start_psk_server(load_key(SERVER_PRIVATE_KEY)?).await?;
+        let connector = connector()?.with_psk(vec![7; PSK_LEN])?;
+        let stream = TcpStream::connect(addr).await?;
+        assert!(connect_stream(&connector, stream).await.is_err());
+
+        assert!(
+            NoiseClientConnector::new(None, None)?
+                .with_psk(vec![0; 16])
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn noise_one_round_trip_patterns_should_work() -> Result<()> {
+        for pattern in [NoisePattern::IK, NoisePattern::NK] {
+            let addr = start_server_with(acceptor()?.with_pattern(pattern)).await?;
+            let connector = connector()?.with_pattern(pattern);
+            let stream = TcpStream::connect(addr).await?;
+            connect_stream(&connector, stream).await?;
+        }
+
+        // 同时使用 PSK
+        let psk = load_key(SERVER_PRIVATE_KEY)?;
+        let acceptor = acceptor()?
+            .with_pattern(NoisePattern::IK)
+            .with_psk(psk.clone())?;
+        let addr = start_server_with(acceptor).await?;
+        let connector = connector()?.with_pattern(NoisePattern::IK).with_psk(psk)?;
+        connect_stream(&connector, TcpStream::connect(addr).await?).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn noise_with_mismatched_pattern_should_fail() -> Result<()> {
+        let addr = start_server_with(acceptor()?.with_pattern(NoisePattern::IK)).await?;
+        let stream = TcpStream::connect(addr).await?;
+        assert!(connect_stream(&connector()?, stream).await.is_err());
+
+        // IK 和 NK 需要事先知道服务器公钥
+        let addr = start_server_with(acceptor()?.with_pattern(NoisePattern::NK)).await?;
+        let connector = NoiseClientConnector::new(None, None)?.with_pattern(NoisePattern::NK);
+        let stream = TcpStream::connect(addr).await?;
+        assert!(connector.connect(stream).await.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn noise_pattern_should_parse() {
+        assert_eq!("ik".parse::<NoisePattern>().unwrap(), NoisePattern::IK);
+        assert_eq!(NoisePattern::NK.to_string(), "NK");
+        assert!("XK".parse::<NoisePattern>().is_err());
+        assert_eq!(
+            NoisePattern::XX.protocol_name(true),
+            "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s"
+        );
+        assert_eq!(
+            NoisePattern::IK.protocol_name(false),
+            "Noise_IK_25519_ChaChaPoly_BLAKE2s"
+        );
+    }
+
+    #[test]
+    fn load_key_file_should_work() -> Result<()> {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures_noise/server.key");
+        let key = load_key_file(path)?;
+        assert_eq!(
+            key,
+            load_key(include_str!("../../fixtures_noise/server.key"))?
+        );
+        assert!(load_key_file("/not/exist").is_err());
+        Ok(())
+    }
+
+    async fn connect(addr: SocketAddr) -> Result<NoiseStream<TcpStream>> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(connector()?.connect(stream).await?)
+    }
+
+    /// 握手之后再读一次，服务器拒绝最后一条握手消息时客户端在这里发现连接已经断开
+    async fn connect_stream(
+        connector: &NoiseClientConnector,
+        stream: TcpStream,
+    ) -> Result<NoiseStream<TcpStream>> {
+        let mut stream = connector.connect(stream).await?;
+        stream.write_all(b"ping").await?;
+        stream.flush().await?;
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await?;
+        Ok(stream)
+    }
+
+    fn connector() -> Result<NoiseClientConnector> {
+        let client_key = load_key(include_str!("../../fixtures_noise/client.key"))?;
+        let server_pub = load_key(include_str!("../../fixtures_noise/server.pub"))?;
+        Ok(NoiseClientConnector::new(
+            Some(client_key),
+            Some(server_pub),
+        )?)
+    }
+
+    async fn start_psk_server(psk: Vec<u8>) -> Result<SocketAddr> {
+        start_server_with(acceptor()?.with_psk(psk)?).await
+    }
+
+    /// 只接受一个连接的 echo 服务器，握手失败时直接断开
+    async fn start_server_with(acceptor: NoiseServerAcceptor) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Ok(stream) = acceptor.accept(stream).await {
+                let (mut reader, mut writer) = tokio::io::split(stream);
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            }
+        });
+
+        Ok(addr)
+    }
+
+    fn acceptor() -> Result<NoiseServerAcceptor> {
+        let server_key = load_key(include_str!("../../fixtures_noise/server.key"))?;
+        Ok(NoiseServerAcceptor::new(Some(server_key))?)
+    }
+
+    async fn start_echo_server() -> Result<SocketAddr> {
+        let acceptor = acceptor()?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            let (mut reader, mut writer) = tokio::io::split(stream);
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+        });
+
+        Ok(addr)
+    }
+
+    async fn start_yamux_server() -> Result<SocketAddr> {
+        let acceptor = acceptor()?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let service = Service::new(MemTable::new());
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            YamuxCtrl::new_server(stream, None, move |s| {
+                let svc = service.clone();
+                async move {
+                    let stream = ProstServerStream::new(s.compat(), svc);
+                    stream.process().await.unwrap();
+                    Ok(())
+                }
+            });
+        });
+
+        Ok(addr)
+    }
+
     // 添加一个测试来验证密钥加载
     #[test]
     fn test_load_key() -> Result<()> {
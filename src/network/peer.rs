@@ -0,0 +1,96 @@
+use crate::{
+    BoxedStream, ClientConfig, CommandRequest, CommandResponse, KvError, YamuxCtrl,
+    start_client_with_config,
+};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time;
+use tracing::warn;
+
+/// 集群里其它节点的 IP，节点之间的命令（Raft 的 RPC、多主的同步）只接受从这些 IP 发来的。
+/// 节点之间的连接从临时端口发出，只比较 IP
+pub struct PeerIps(HashSet<IpAddr>);
+
+impl PeerIps {
+    /// 启动时解析一次地址，解析失败的地址被忽略
+    pub fn resolve<'a>(addrs: impl IntoIterator<Item = &'a String>) -> Self {
+        let ips = addrs
+            .into_iter()
+            .filter_map(|addr| match addr.to_socket_addrs() {
+                Ok(addrs) => Some(addrs),
+                Err(e) => {
+                    warn!("Failed to resolve peer {}: {}", addr, e);
+                    None
+                }
+            })
+            .flatten()
+            .map(|addr| addr.ip().to_canonical())
+            .collect();
+        Self(ips)
+    }
+
+    /// 进程内的调用没有地址，不检查
+    pub fn contains(&self, addr: Option<SocketAddr>) -> bool {
+        addr.is_none_or(|addr| self.0.contains(&addr.ip().to_canonical()))
+    }
+}
+
+/// 集群里到另一个服务器的连接，用于服务器之间互相发送命令
+///
+/// 第一次发送命令时才连接，出错后丢掉连接，下次发送时重新连接
+pub struct PeerClient {
+    config: ClientConfig,
+    ctrl: Mutex<Option<YamuxCtrl<BoxedStream>>>,
+}
+
+impl PeerClient {
+    /// config 为 None 时使用明文 TCP，否则使用 config 并把地址替换成 addr
+    pub fn new(addr: &str, config: Option<&ClientConfig>) -> Result<Self, KvError> {
+        let mut config = match config {
+            Some(config) => config.clone(),
+            None => ClientConfig::builder().addr(addr).build()?,
+        };
+        config.general.addr = addr.into();
+        Ok(Self {
+            config,
+            ctrl: Mutex::new(None),
+        })
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.config.general.addr
+    }
+
+    /// 在新的 stream 上执行命令，超时也算出错
+    pub async fn call(
+        &self,
+        cmd: CommandRequest,
+        timeout: Duration,
+    ) -> Result<CommandResponse, KvError> {
+        let fut = async {
+            let mut ctrl = {
+                let mut ctrl = self.ctrl.lock().await;
+                match ctrl.as_ref() {
+                    Some(ctrl) => ctrl.clone(),
+                    None => {
+                        let new = start_client_with_config(&self.config).await?;
+                        *ctrl = Some(new.clone());
+                        new
+                    }
+                }
+            };
+            ctrl.open_stream().await?.execute_unary(cmd).await
+        };
+
+        let res = match time::timeout(timeout, fut).await {
+            Ok(res) => res,
+            Err(_) => Err(KvError::Timeout(format!("call {}", self.addr()))),
+        };
+        if res.is_err() {
+            *self.ctrl.lock().await = None;
+        }
+        res
+    }
+}
@@ -0,0 +1,125 @@
+use crate::{KvError, SocketConfig};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tracing::warn;
+
+/// accept 连续失败时第一次等待的时间，之后每次翻倍
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// accept 一个连接。出错时（比如文件描述符用完）记录日志并退避重试，不会让整个服务器退出
+pub async fn accept_connection(listener: &TcpListener) -> (TcpStream, SocketAddr) {
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+    loop {
+        match listener.accept().await {
+            Ok(conn) => return conn,
+            Err(e) => {
+                warn!("Failed to accept connection: {}, retry in {:?}", e, backoff);
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+            }
+        }
+    }
+}
+
+/// listen 的 backlog，和 tokio 的 TcpListener::bind 一样
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// 按配置创建监听的 socket。开启 reuse_port 时，多个进程可以同时监听同一个地址，
+/// 滚动升级时新进程先启动，旧进程再退出，期间不会拒绝连接
+pub fn bind_listener(addr: &str, config: &SocketConfig) -> Result<std::net::TcpListener, KvError> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| KvError::InvalidConfig(format!("invalid addr: {}", addr)))?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if config.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(socket.into())
+}
+
+/// 把配置里的 socket 选项应用到 accept 或 connect 得到的 TcpStream 上
+pub fn set_socket_options(stream: &TcpStream, config: &SocketConfig) -> Result<(), KvError> {
+    // 小命令为主的场景下，Nagle 算法会明显增加延迟
+    stream.set_nodelay(config.nodelay)?;
+
+    let sock = SockRef::from(stream);
+    if let Some(secs) = config.keepalive_secs {
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(secs));
+        sock.set_tcp_keepalive(&keepalive)?;
+    }
+    if let Some(size) = config.send_buffer_size {
+        sock.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = config.recv_buffer_size {
+        sock.set_recv_buffer_size(size)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn socket_options_should_be_applied() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let stream = TcpStream::connect(addr).await?;
+
+        let config = SocketConfig {
+            nodelay: true,
+            keepalive_secs: Some(30),
+            send_buffer_size: Some(64 * 1024),
+            recv_buffer_size: Some(64 * 1024),
+            ..Default::default()
+        };
+        set_socket_options(&stream, &config)?;
+
+        assert!(stream.nodelay()?);
+        assert!(SockRef::from(&stream).keepalive()?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reuse_port_should_allow_multiple_listeners() -> Result<()> {
+        let config = SocketConfig {
+            reuse_port: true,
+            ..Default::default()
+        };
+        let l1 = TcpListener::from_std(bind_listener("127.0.0.1:0", &config)?)?;
+        let addr = l1.local_addr()?;
+        let l2 = TcpListener::from_std(bind_listener(&addr.to_string(), &config)?)?;
+        assert_eq!(l2.local_addr()?, addr);
+
+        // 没有开启时地址不能重复使用
+        assert!(bind_listener(&addr.to_string(), &SocketConfig::default()).is_err());
+
+        // 关掉一个之后，另一个继续接受连接
+        drop(l1);
+        let _client = TcpStream::connect(addr).await?;
+        accept_connection(&l2).await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accept_connection_should_work() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let client = TcpStream::connect(addr).await?;
+
+        let (_stream, peer) = accept_connection(&listener).await;
+        assert_eq!(peer, client.local_addr()?);
+        Ok(())
+    }
+}
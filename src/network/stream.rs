@@ -1,10 +1,16 @@
-use crate::{FrameCoder, KvError, read_frame};
-use bytes::BytesMut;
-use futures::{FutureExt, Sink, Stream, ready};
+use crate::network::frame::{LEN_LEN, check_len, decode_header};
+use crate::{FrameCoder, FrameLimits, KvError};
+use bytes::{Buf, BytesMut};
+use futures::{Sink, Stream, ready};
+use std::io;
 use std::marker::PhantomData;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::io::poll_read_buf;
+
+/// 写缓存超过这个大小时，poll_ready 先把缓存写出去再接受新的消息
+const MAX_WRITE_BUFFER: usize = 64 * 1024;
 
 pub struct ProstStream<S, In, Out> {
     // innner stream
@@ -13,8 +19,14 @@ pub struct ProstStream<S, In, Out> {
     wbuf: BytesMut,
     // 写入了多少字节
     written: usize,
-    // 读缓存
+    // 读缓存，保存已经收到、还没有 decode 的数据，可能包含下一个 frame 的开头
     rbuf: BytesMut,
+    // 正在读的 frame 的长度（不含 header），header 还没有收全时为 None
+    rlen: Option<usize>,
+    // 超过长度上限的 frame 还有多少字节没有丢弃
+    skip: usize,
+    // 读取 frame 时的限制
+    limits: FrameLimits,
 
     // 类型占位符
     _in: PhantomData<In>,
@@ -32,10 +44,31 @@ where
             written: 0,
             wbuf: BytesMut::new(),
             rbuf: BytesMut::new(),
+            rlen: None,
+            skip: 0,
+            limits: FrameLimits::default(),
             _in: PhantomData,
             _out: PhantomData,
         }
     }
+
+    /// 设置读取 frame 时的限制
+    pub fn with_limits(mut self, limits: FrameLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// 读缓存里已经有一个完整的 frame，下一次 poll_next 不需要等待 stream
+    pub fn has_buffered_frame(&self) -> bool {
+        if self.skip > 0 || self.rbuf.len() < LEN_LEN {
+            return false;
+        }
+        let len = self.rlen.unwrap_or_else(|| {
+            let header = u32::from_be_bytes(self.rbuf[..LEN_LEN].try_into().unwrap());
+            decode_header(header as usize).0
+        });
+        self.rbuf.len() >= LEN_LEN + len
+    }
 }
 
 impl<S, In, Out> Stream for ProstStream<S, In, Out>
@@ -46,16 +79,55 @@ where
 {
     type Item = Result<In, KvError>;
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        assert!(self.rbuf.is_empty());
-
-        let mut rest = self.rbuf.split_off(0);
-
-        let fut = read_frame(&mut self.stream, &mut rest);
-        ready!(Box::pin(fut).poll_unpin(cx)?);
-
-        self.rbuf.unsplit(rest);
-        Poll::Ready(Some(Ok(In::decode_frame(&mut self.rbuf)?)))
+    /// 读取的进度保存在 rbuf 和 rlen 里，Pending 之后再次 poll（或者 next() 的 future 被丢弃后重新创建）
+    /// 会接着上次的位置读，不会丢失已经收到的数据，也不需要为每次 poll 分配 future
+    ///
+    /// 超过长度上限的 frame 返回 FrameTooLarge，之后的 poll 边读边丢弃它的内容，不放进缓存，
+    /// 丢弃完之后接着读下一个 frame，调用者可以回复一个错误然后继续处理
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match this.rlen {
+                _ if this.skip > 0 && !this.rbuf.is_empty() => {
+                    let n = this.skip.min(this.rbuf.len());
+                    this.rbuf.advance(n);
+                    this.skip -= n;
+                }
+                None if this.skip == 0 && this.rbuf.len() >= LEN_LEN => {
+                    let header = u32::from_be_bytes(this.rbuf[..LEN_LEN].try_into().unwrap());
+                    let (len, _compressed) = decode_header(header as usize);
+                    if let Err(e) = check_len(len, &this.limits) {
+                        this.rbuf.advance(LEN_LEN);
+                        this.skip = len;
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                    // strict 模式下缓冲区随着实际收到的数据增长，不相信对端声明的长度
+                    if !this.limits.strict {
+                        this.rbuf
+                            .reserve((LEN_LEN + len).saturating_sub(this.rbuf.len()));
+                    }
+                    this.rlen = Some(len);
+                }
+                Some(len) if this.rbuf.len() >= LEN_LEN + len => {
+                    this.rlen = None;
+                    let mut frame = this.rbuf.split_to(LEN_LEN + len);
+                    return Poll::Ready(Some(In::decode_frame_with(&mut frame, &this.limits)));
+                }
+                _ => {
+                    let n = ready!(poll_read_buf(
+                        Pin::new(&mut this.stream),
+                        cx,
+                        &mut this.rbuf
+                    ))?;
+                    if n == 0 {
+                        return Poll::Ready(Some(Err(io::Error::from(
+                            io::ErrorKind::UnexpectedEof,
+                        )
+                        .into())));
+                    }
+                }
+            }
+        }
     }
 }
 
@@ -67,10 +139,15 @@ where
 {
     type Error = KvError;
 
-    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
+    /// 写缓存没满时直接接受，满了之后先写出去，慢的对端会让发送方在这里等待
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.wbuf.len() < MAX_WRITE_BUFFER {
+            return Poll::Ready(Ok(()));
+        }
+        self.poll_flush(cx)
     }
 
+    /// 只 encode 到写缓存，连续 feed 的多个消息在 flush 时合并成一次写
     fn start_send(self: Pin<&mut Self>, item: &Out) -> Result<(), Self::Error> {
         let this = self.get_mut();
         item.encode_frame(&mut this.wbuf)?;
@@ -84,6 +161,9 @@ where
         // 循环写入 stream 中
         while this.written != this.wbuf.len() {
             let n = ready!(Pin::new(&mut this.stream).poll_write(cx, &this.wbuf[this.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero).into()));
+            }
             this.written += n;
         }
 
@@ -115,6 +195,8 @@ mod tests {
     use crate::{CommandRequest, utils::DummyStream};
     use anyhow::Result;
     use futures::prelude::*;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
 
     #[tokio::test]
     async fn prost_stream_should_work() -> Result<()> {
@@ -131,4 +213,89 @@ mod tests {
         }
         Ok(())
     }
+
+    #[tokio::test]
+    async fn fed_frames_should_be_written_together() -> Result<()> {
+        let mut stream =
+            ProstStream::<_, CommandRequest, CommandRequest>::new(DummyStream::default());
+        let cmds: Vec<_> = (0..3)
+            .map(|i| CommandRequest::new_hset("t1", format!("k{}", i), "v".into()))
+            .collect();
+        for cmd in &cmds {
+            stream.feed(cmd).await?;
+        }
+        // flush 之前没有写到底层的 stream
+        assert!(stream.stream.buf.is_empty());
+        stream.flush().await?;
+        for cmd in &cmds {
+            assert_eq!(&stream.next().await.unwrap()?, cmd);
+        }
+
+        // 写缓存超过上限之后，下一个 feed 会先把缓存写出去
+        // 随机数据压缩不了
+        let mut value = vec![0u8; MAX_WRITE_BUFFER];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut value[..]);
+        let big = CommandRequest::new_hset("t1", "big", value.into());
+        stream.feed(&big).await?;
+        assert!(stream.stream.buf.is_empty());
+        stream.feed(&cmds[0]).await?;
+        assert!(!stream.stream.buf.is_empty());
+        stream.flush().await?;
+        assert_eq!(stream.next().await.unwrap()?, big);
+        assert_eq!(stream.next().await.unwrap()?, cmds[0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn partial_frame_should_resume_after_dropped_poll() -> Result<()> {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut stream = ProstStream::<_, CommandRequest, CommandRequest>::new(server);
+
+        let cmd1 = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let cmd2 = CommandRequest::new_hdel("t1", "k1");
+        let mut buf = BytesMut::new();
+        cmd1.encode_frame(&mut buf)?;
+        let first = buf.len();
+        cmd2.encode_frame(&mut buf)?;
+
+        // 先发 header 的一部分，再发到第一个 frame 的中间，每次 next() 都在读完之前被丢弃
+        for chunk in [&buf[..2], &buf[2..first - 3]] {
+            client.write_all(chunk).await?;
+            let res = tokio::time::timeout(Duration::from_millis(10), stream.next()).await;
+            assert!(res.is_err());
+        }
+        // 剩下的数据和第二个 frame 一起到达
+        client.write_all(&buf[first - 3..]).await?;
+        assert_eq!(stream.next().await.unwrap()?, cmd1);
+        assert_eq!(stream.next().await.unwrap()?, cmd2);
+
+        // 连接关闭之后返回错误
+        drop(client);
+        assert!(stream.next().await.unwrap().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_should_be_skipped() -> Result<()> {
+        let (mut client, server) = tokio::io::duplex(64);
+        let limits = FrameLimits {
+            max_frame: 64,
+            ..Default::default()
+        };
+        let mut stream =
+            ProstStream::<_, CommandRequest, CommandRequest>::new(server).with_limits(limits);
+
+        let big = CommandRequest::new_hset("t1", "big", vec![1u8; 256].into());
+        let cmd = CommandRequest::new_hdel("t1", "k1");
+        let mut buf = BytesMut::new();
+        big.encode_frame(&mut buf)?;
+        cmd.encode_frame(&mut buf)?;
+        // duplex 的缓存比 frame 小，超大的 frame 边读边丢弃
+        tokio::spawn(async move { client.write_all(&buf).await });
+
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(err, KvError::FrameTooLarge(_)));
+        assert_eq!(stream.next().await.unwrap()?, cmd);
+        Ok(())
+    }
 }
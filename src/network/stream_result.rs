@@ -1,17 +1,23 @@
 use std::{
-    convert::TryInto,
     ops::{Deref, DerefMut},
     pin::Pin,
 };
 
 use futures::{Stream, StreamExt};
+use tokio::runtime::Handle;
+use tracing::{info, warn};
 
-use crate::{CommandResponse, KvError};
+use crate::{BoxedStream, CommandRequest, CommandResponse, KvError, SubscriptionAck, YamuxCtrl};
 
-/// 创建时之前取得 subscription id，并使用 Deref/DerefMut 使其用起来和 Stream 一致
+/// 创建时先取得订阅确认，并使用 Deref/DerefMut 使其用起来和 Stream 一致
+///
+/// 设置了 `unsubscribe_on_drop` 的订阅流被 drop 时自动向服务器取消订阅
 pub struct StreamResult {
     pub id: u32,
+    ack: SubscriptionAck,
     inner: Pin<Box<dyn Stream<Item = Result<CommandResponse, KvError>> + Send>>,
+    /// drop 时用来发送 UNSUBSCRIBE 的连接和主题
+    unsubscriber: Option<(YamuxCtrl<BoxedStream>, String)>,
 }
 
 impl StreamResult {
@@ -19,26 +25,75 @@ impl StreamResult {
     where
         T: Stream<Item = Result<CommandResponse, KvError>> + Send + Unpin + 'static,
     {
-        let id = match stream.next().await {
-            Some(Ok(CommandResponse {
-                status: 200,
-                values: v,
-                ..
-            })) => {
-                if v.is_empty() {
-                    return Err(KvError::Internal("Invalid stream".into()));
-                }
-                let id: i64 = (&v[0]).try_into()?;
-                Ok(id as u32)
-            }
-            _ => Err(KvError::Internal("Invalid stream".into())),
+        let ack = match stream.next().await {
+            Some(Ok(res)) if res.status == 200 => SubscriptionAck::try_from(&res)
+                .map_err(|_| KvError::Internal("Invalid stream".into()))?,
+            // 服务器拒绝了请求，比如订阅者已满
+            Some(Ok(res)) => return Err(KvError::Internal(res.message)),
+            _ => return Err(KvError::Internal("Invalid stream".into())),
         };
 
         Ok(StreamResult {
+            id: ack.id,
+            ack,
             inner: Box::pin(stream),
-            id: id?,
+            unsubscriber: None,
         })
     }
+
+    /// 服务器返回的订阅确认
+    pub fn subscription(&self) -> &SubscriptionAck {
+        &self.ack
+    }
+
+    /// SUBSCRIBE 的消息流被 drop 时，通过 ctrl 新开一个 stream 向服务器取消对 topic 的订阅，
+    /// 避免服务器一直为不再读取的订阅保留队列
+    pub fn unsubscribe_on_drop(
+        mut self,
+        ctrl: YamuxCtrl<BoxedStream>,
+        topic: impl Into<String>,
+    ) -> Self {
+        self.unsubscriber = Some((ctrl, topic.into()));
+        self
+    }
+
+    /// 主动取消订阅，并等待服务器确认。没有设置 `unsubscribe_on_drop` 时什么都不做
+    pub async fn unsubscribe(mut self) -> Result<(), KvError> {
+        match self.unsubscriber.take() {
+            Some((ctrl, topic)) => unsubscribe(ctrl, topic, self.id).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for StreamResult {
+    fn drop(&mut self) {
+        // 已经主动取消过了，或者不在 tokio runtime 里，就不再发 UNSUBSCRIBE
+        let (Some((ctrl, topic)), Ok(rt)) = (self.unsubscriber.take(), Handle::try_current())
+        else {
+            return;
+        };
+        let id = self.id;
+        rt.spawn(async move {
+            if let Err(e) = unsubscribe(ctrl, topic, id).await {
+                warn!("Failed to unsubscribe {}: {}", id, e);
+            }
+        });
+    }
+}
+
+async fn unsubscribe(
+    mut ctrl: YamuxCtrl<BoxedStream>,
+    topic: String,
+    id: u32,
+) -> Result<(), KvError> {
+    let cmd = CommandRequest::new_unsubscribe(topic, id);
+    let res = ctrl.open_stream().await?.execute_unary(cmd).await?;
+    if res.status != 200 {
+        return Err(KvError::Internal(res.message));
+    }
+    info!("Subscription {} is cancelled", id);
+    Ok(())
 }
 
 impl Deref for StreamResult {
@@ -1,16 +1,27 @@
-use std::io::Cursor;
-use std::sync::Arc;
-
-use crate::KvError;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::{ClientSessionConfig, KvError, ServerSessionConfig, TlsVersion};
+use sha2::{Digest, Sha256};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_rustls::TlsConnector;
-use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore};
+use tokio_rustls::rustls::sign;
+use tokio_rustls::rustls::{
+    ALL_CIPHERSUITES, AllowAnyAuthenticatedClient, ClientSessionMemoryCache, NoClientAuth,
+    NoServerSessionStorage, PrivateKey, ProtocolVersion, RootCertStore, ServerSessionMemoryCache,
+    Session, SignatureScheme, StoresClientSessions, SupportedCipherSuite, TLSError, Ticketer,
+};
 use tokio_rustls::rustls::{Certificate, ClientConfig, ServerConfig, internal::pemfile};
-use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::webpki::{self, DNSNameRef, EndEntityCert};
 use tokio_rustls::{
     TlsAcceptor, client::TlsStream as ClientTlsStream, server::TlsStream as ServerTlsStream,
 };
-use tracing::instrument;
+use tracing::{instrument, warn};
+use x509_parser::pem::Pem;
+use x509_parser::prelude::{CertificateRevocationList, FromDer, X509Certificate};
 
 /// KV Server 自己的 ALPN (Application-Layer Protocol Negotiation)
 const ALPN_KV: &str = "kv";
@@ -19,6 +30,13 @@ const ALPN_KV: &str = "kv";
 #[derive(Clone)]
 pub struct TlsServerAcceptor {
     inner: Arc<ServerConfig>,
+    crl: Option<Arc<RevocationList>>,
+}
+
+/// 证书吊销列表里被吊销的证书，用 (签发者, 序列号) 标识
+#[derive(Debug, Default)]
+pub struct RevocationList {
+    revoked: HashSet<(Vec<u8>, Vec<u8>)>,
 }
 
 /// 存放 TLS Client 并提供方法 connect 把底层的协议转换成 TLS
@@ -64,6 +82,29 @@ impl TlsClientConnector {
         })
     }
 
+    /// 开启 session 复用，重连时跳过完整的握手
+    pub fn with_session_resumption(mut self, session: &ClientSessionConfig) -> Self {
+        let config = Arc::make_mut(&mut self.config);
+        config.enable_tickets = true;
+        config.session_persistence = match &session.cache_path {
+            Some(path) => Arc::new(FileSessionStore::new(path, session.cache_size)),
+            None => ClientSessionMemoryCache::new(session.cache_size),
+        };
+        self
+    }
+
+    /// 限制最低 TLS 版本和 cipher suite，cipher_suites 为空时使用 rustls 的默认值
+    pub fn with_policy(
+        mut self,
+        min_version: TlsVersion,
+        cipher_suites: &[String],
+    ) -> Result<Self, KvError> {
+        let config = Arc::make_mut(&mut self.config);
+        (config.versions, config.ciphersuites) =
+            tls_policy(min_version, cipher_suites, &config.ciphersuites)?;
+        Ok(self)
+    }
+
     /// 触发 TLS 协议，把底层的 stream 转换成 TLS stream
     #[instrument(name = "tls_client_connect", skip_all)]
     pub async fn connect<S>(&self, stream: S) -> Result<ClientTlsStream<S>, KvError>
@@ -111,9 +152,42 @@ impl TlsServerAcceptor {
 
         Ok(Self {
             inner: Arc::new(config),
+            crl: None,
         })
     }
 
+    /// 配置 session 缓存和 session ticket，让重连的客户端可以跳过完整握手
+    pub fn with_session_resumption(mut self, session: &ServerSessionConfig) -> Self {
+        let config = Arc::make_mut(&mut self.inner);
+        config.session_storage = if session.cache_size > 0 {
+            ServerSessionMemoryCache::new(session.cache_size)
+        } else {
+            Arc::new(NoServerSessionStorage {})
+        };
+        if session.tickets {
+            config.ticketer = Ticketer::new();
+        }
+        self
+    }
+
+    /// 拒绝 CRL 里已经被吊销的客户端证书，只检查客户端证书本身，不检查中间 CA
+    pub fn with_crl(mut self, crl: RevocationList) -> Self {
+        self.crl = Some(Arc::new(crl));
+        self
+    }
+
+    /// 限制最低 TLS 版本和 cipher suite，cipher_suites 为空时使用 rustls 的默认值
+    pub fn with_policy(
+        mut self,
+        min_version: TlsVersion,
+        cipher_suites: &[String],
+    ) -> Result<Self, KvError> {
+        let config = Arc::make_mut(&mut self.inner);
+        (config.versions, config.ciphersuites) =
+            tls_policy(min_version, cipher_suites, &config.ciphersuites)?;
+        Ok(self)
+    }
+
     /// 触发 TLS 协议，把底层的 stream 转换成 TLS stream
     #[instrument(name = "tls_server_accept", skip_all)]
     pub async fn accept<S>(&self, stream: S) -> Result<ServerTlsStream<S>, KvError>
@@ -121,7 +195,244 @@ impl TlsServerAcceptor {
         S: AsyncRead + AsyncWrite + Unpin + Send,
     {
         let acceptor = TlsAcceptor::from(self.inner.clone());
-        Ok(acceptor.accept(stream).await?)
+        let stream = acceptor.accept(stream).await?;
+        // rustls 不支持 CRL，握手完成后再检查客户端证书，被吊销时直接断开
+        if let Some(crl) = &self.crl
+            && let Some(certs) = stream.get_ref().1.get_peer_certificates()
+            && let Some(cert) = certs.first()
+            && crl.is_revoked(&cert.0)?
+        {
+            return Err(TLSError::General("client certificate has been revoked".into()).into());
+        }
+        Ok(stream)
+    }
+}
+
+impl RevocationList {
+    /// 加载 PEM 格式的 CRL，可以包含多个 CA 的 CRL
+    pub fn from_pem(pem: &str) -> Result<Self, KvError> {
+        let mut revoked = HashSet::new();
+        let mut found = false;
+        for pem in Pem::iter_from_buffer(pem.as_bytes()) {
+            let pem = pem.map_err(|_| KvError::CertifcateParseError("CRL", "pem"))?;
+            if pem.label != "X509 CRL" {
+                continue;
+            }
+            let (_, crl) = CertificateRevocationList::from_der(&pem.contents)
+                .map_err(|_| KvError::CertifcateParseError("CRL", "der"))?;
+            let issuer = crl.issuer().as_raw();
+            for cert in crl.iter_revoked_certificates() {
+                revoked.insert((issuer.to_vec(), cert.raw_serial().to_vec()));
+            }
+            found = true;
+        }
+        if !found {
+            return Err(KvError::InvalidConfig("no CRL found".into()));
+        }
+        Ok(Self { revoked })
+    }
+
+    /// DER 格式的证书是否已经被吊销
+    pub fn is_revoked(&self, cert: &[u8]) -> Result<bool, KvError> {
+        let (_, cert) = X509Certificate::from_der(cert)
+            .map_err(|_| KvError::CertifcateParseError("client", "cert"))?;
+        let key = (cert.issuer().as_raw().to_vec(), cert.raw_serial().to_vec());
+        Ok(self.revoked.contains(&key))
+    }
+}
+
+/// 按名字查找 rustls 支持的 cipher suite，名字和 IANA 的一样，不区分大小写
+pub fn parse_cipher_suites(
+    names: &[String],
+) -> Result<Vec<&'static SupportedCipherSuite>, KvError> {
+    names
+        .iter()
+        .map(|name| {
+            ALL_CIPHERSUITES
+                .iter()
+                .copied()
+                .find(|suite| format!("{:?}", suite.suite).eq_ignore_ascii_case(name))
+                .ok_or_else(|| KvError::InvalidConfig(format!("unknown cipher suite {}", name)))
+        })
+        .collect()
+}
+
+/// 按最低版本和 cipher suite 算出允许的协议版本和 cipher suite，
+/// 允许的 cipher suite 都不能用于允许的协议版本时报错，否则握手一定会失败
+fn tls_policy(
+    min_version: TlsVersion,
+    names: &[String],
+    defaults: &[&'static SupportedCipherSuite],
+) -> Result<(Vec<ProtocolVersion>, Vec<&'static SupportedCipherSuite>), KvError> {
+    let versions = match min_version {
+        TlsVersion::Tls12 => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+        TlsVersion::Tls13 => vec![ProtocolVersion::TLSv1_3],
+    };
+    let suites = match names.is_empty() {
+        true => defaults.to_vec(),
+        false => parse_cipher_suites(names)?,
+    };
+    let usable = suites
+        .iter()
+        .any(|suite| versions.iter().any(|v| suite.usable_for_version(*v)));
+    if !usable {
+        return Err(KvError::InvalidConfig(format!(
+            "none of the cipher suites can be used with TLS {} and above",
+            min_version
+        )));
+    }
+    Ok((versions, suites))
+}
+
+/// 用客户端证书的指纹标识 mTLS 连接的身份，客户端没有提供证书时返回 None
+pub fn peer_identity<S>(stream: &ServerTlsStream<S>) -> Option<String> {
+    let certs = stream.get_ref().1.get_peer_certificates()?;
+    Some(cert_fingerprint(&certs.first()?.0))
+}
+
+/// 证书（DER 编码）的 SHA-256 指纹，格式是 `cert:` 加上小写的十六进制，
+/// 和 `openssl x509 -in client.cert -outform der | sha256sum` 的输出一样
+pub fn cert_fingerprint(der: &[u8]) -> String {
+    let digest = Sha256::digest(der);
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("cert:{}", hex)
+}
+
+/// 检查私钥和证书是否匹配：用私钥签名一段数据，再用证书里的公钥验证
+pub fn verify_key_pair(cert: &str, key: &str) -> Result<(), KvError> {
+    const MESSAGE: &[u8] = b"kv key pair check";
+    let certs = load_certs(cert)?;
+    let cert = certs
+        .first()
+        .ok_or(KvError::CertifcateParseError("server", "cert"))?;
+    let key = load_key(key)?;
+
+    let signer = sign::any_supported_type(&key)
+        .ok()
+        .and_then(|key| {
+            key.choose_scheme(&[
+                SignatureScheme::ECDSA_NISTP256_SHA256,
+                SignatureScheme::ECDSA_NISTP384_SHA384,
+                SignatureScheme::ED25519,
+                SignatureScheme::RSA_PSS_SHA256,
+            ])
+        })
+        .ok_or(KvError::CertifcateParseError("private", "key"))?;
+    let algorithm = match signer.get_scheme() {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        _ => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    };
+    let signature = signer.sign(MESSAGE)?;
+
+    EndEntityCert::from(&cert.0)
+        .and_then(|cert| cert.verify_signature(algorithm, MESSAGE, &signature))
+        .map_err(|_| KvError::InvalidConfig("private key does not match the certificate".into()))
+}
+
+/// 把客户端的 TLS session 持久化到文件
+///
+/// put 在 rustls 的握手里被调用，只更新内存里的 session，写文件放到 blocking 线程里做。
+/// session 里有恢复连接用的密钥，文件先以 0600 的权限写到临时文件，再 rename 覆盖原来的文件
+pub struct FileSessionStore {
+    max_size: usize,
+    file: Arc<SessionFile>,
+}
+
+struct SessionFile {
+    path: PathBuf,
+    sessions: Mutex<HashMap<Vec<u8>, Vec<u8>>>,
+    /// 同一时间只有一个线程写文件
+    writing: Mutex<()>,
+}
+
+impl SessionFile {
+    /// 拿到 writing 锁之后才读取 session，所以最后写完的总是最新的内容
+    fn persist(&self) -> std::io::Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        let content: String = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| format!("{} {}\n", base64::encode(k), base64::encode(v)))
+            .collect();
+
+        let tmp = self.path.with_extension("tmp");
+        // 只有新建的文件才会用上 mode，先删掉之前留下的临时文件
+        match fs::remove_file(&tmp) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+impl FileSessionStore {
+    /// 从文件中加载之前保存的 session，文件不存在或者格式错误时从空开始
+    pub fn new(path: impl Into<PathBuf>, max_size: usize) -> Self {
+        let path = path.into();
+        let sessions = fs::read_to_string(&path)
+            .map(|content| {
+                content
+                    .lines()
+                    .filter_map(|line| {
+                        let (k, v) = line.split_once(' ')?;
+                        Some((base64::decode(k).ok()?, base64::decode(v).ok()?))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            max_size,
+            file: Arc::new(SessionFile {
+                path,
+                sessions: Mutex::new(sessions),
+                writing: Mutex::new(()),
+            }),
+        }
+    }
+}
+
+impl StoresClientSessions for FileSessionStore {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        {
+            let mut sessions = self.file.sessions.lock().unwrap();
+            // 超过上限时随便淘汰一个，session 丢了最多就是多一次完整握手
+            if sessions.len() >= self.max_size
+                && !sessions.contains_key(&key)
+                && let Some(k) = sessions.keys().next().cloned()
+            {
+                sessions.remove(&k);
+            }
+            sessions.insert(key, value);
+        }
+
+        let file = self.file.clone();
+        let persist = move || {
+            if let Err(e) = file.persist() {
+                warn!("Failed to persist TLS sessions to {:?}: {}", file.path, e);
+            }
+        };
+        // 在 tokio 里时不阻塞 reactor，写文件失败也不影响这次连接
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => drop(handle.spawn_blocking(persist)),
+            Err(_) => persist(),
+        }
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.file.sessions.lock().unwrap().get(key).cloned()
     }
 }
 
@@ -237,9 +548,178 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn cert_fingerprint_should_be_sha256_of_der() {
+        let certs = load_certs(include_str!("../../fixtures/client.cert")).unwrap();
+        assert_eq!(
+            cert_fingerprint(&certs[0].0),
+            "cert:deec35a4e56c280d7f9f6065e5e85f13c8ce3bf63a6304f41f9c8f2bb928d538"
+        );
+    }
+
+    #[test]
+    fn verify_key_pair_should_detect_mismatch() {
+        let cert = include_str!("../../fixtures/server.cert");
+        assert!(verify_key_pair(cert, include_str!("../../fixtures/server.key")).is_ok());
+        assert!(verify_key_pair(cert, include_str!("../../fixtures/client.key")).is_err());
+    }
+
+    #[test]
+    fn file_session_store_should_persist_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sessions");
+
+        let store = FileSessionStore::new(&path, 2);
+        assert!(store.put(b"k1".to_vec(), b"v1".to_vec()));
+        assert!(store.put(b"k2".to_vec(), b"v2".to_vec()));
+        assert_eq!(store.get(b"k1"), Some(b"v1".to_vec()));
+
+        // 重新打开文件，session 应该还在
+        let store = FileSessionStore::new(&path, 2);
+        assert_eq!(store.get(b"k2"), Some(b"v2".to_vec()));
+
+        // 超过上限会淘汰旧的 session
+        assert!(store.put(b"k3".to_vec(), b"v3".to_vec()));
+        assert_eq!(store.file.sessions.lock().unwrap().len(), 2);
+        assert_eq!(store.get(b"k3"), Some(b"v3".to_vec()));
+
+        // 文件里有恢复连接用的密钥，只有自己能读
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn tls_policy_should_limit_handshake() -> Result<()> {
+        let suites = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let acceptor = tls_acceptor(false)?
+            .with_policy(TlsVersion::Tls13, &suites(&["TLS13_AES_256_GCM_SHA384"]))?;
+
+        let connector = tls_connector(false)?.with_policy(TlsVersion::Tls12, &[])?;
+        let addr = start_server_with(acceptor.clone()).await?;
+        let mut stream = connector.connect(TcpStream::connect(addr).await?).await?;
+        stream.write_all(b"hello world!").await?;
+        let mut buf = [0; 12];
+        stream.read_exact(&mut buf).await?;
+        let (_, session) = stream.get_ref();
+        assert_eq!(
+            session.get_protocol_version(),
+            Some(ProtocolVersion::TLSv1_3)
+        );
+
+        // 没有共同的 cipher suite 时握手失败
+        let names = suites(&["TLS13_CHACHA20_POLY1305_SHA256"]);
+        let connector = tls_connector(false)?.with_policy(TlsVersion::Tls12, &names)?;
+        let addr = start_server_with(acceptor).await?;
+        let result = connector.connect(TcpStream::connect(addr).await?).await;
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn tls_policy_should_reject_unusable_cipher_suites() {
+        let names = vec!["TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384".to_string()];
+        assert_eq!(parse_cipher_suites(&names).unwrap().len(), 1);
+        assert!(tls_policy(TlsVersion::Tls12, &names, &[]).is_ok());
+        assert!(tls_policy(TlsVersion::Tls13, &names, &[]).is_err());
+        assert!(parse_cipher_suites(&["TLS_RSA_WITH_RC4_128_MD5".to_string()]).is_err());
+    }
+
+    #[test]
+    fn revocation_list_should_find_revoked_certs() -> Result<()> {
+        let crl = RevocationList::from_pem(include_str!("../../fixtures/ca.crl"))?;
+        let client = load_certs(include_str!("../../fixtures/client.cert"))?;
+        let server = load_certs(include_str!("../../fixtures/server.cert"))?;
+        assert!(crl.is_revoked(&client[0].0)?);
+        assert!(!crl.is_revoked(&server[0].0)?);
+
+        // 不是 CRL 的 PEM 不能当作 CRL 加载
+        assert!(RevocationList::from_pem(include_str!("../../fixtures/ca.cert")).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_with_session_resumption_should_work() -> Result<()> {
+        let acceptor = tls_acceptor(false)?.with_session_resumption(&Default::default());
+        let connector = tls_connector(false)?.with_session_resumption(&Default::default());
+
+        // 同一个 connector 连续连接两次，第二次复用第一次的 session
+        assert!(!resumed_connect(&acceptor, &connector).await?);
+        assert!(resumed_connect(&acceptor, &connector).await?);
+
+        // 没开启 session 复用的 connector 每次都是完整握手
+        let connector = tls_connector(false)?;
+        assert!(!resumed_connect(&acceptor, &connector).await?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_session_should_be_resumed_from_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sessions");
+        let session = ClientSessionConfig {
+            cache_path: Some(path.to_string_lossy().into()),
+            ..Default::default()
+        };
+        let acceptor = tls_acceptor(false)?.with_session_resumption(&Default::default());
+        let connector = tls_connector(false)?.with_session_resumption(&session);
+        assert!(!resumed_connect(&acceptor, &connector).await?);
+
+        // 文件在 blocking 线程里写，等它写完
+        for _ in 0..100 {
+            if path.exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        // 新的进程从文件里加载 session，重连时不用完整握手
+        let connector = tls_connector(false)?.with_session_resumption(&session);
+        assert!(resumed_connect(&acceptor, &connector).await?);
+
+        Ok(())
+    }
+
+    /// 连接一次 echo 服务器，返回服务器是否复用了之前的 session
+    async fn resumed_connect(
+        acceptor: &TlsServerAcceptor,
+        connector: &TlsClientConnector,
+    ) -> Result<bool> {
+        let echo = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = echo.local_addr()?;
+        let acceptor = TlsAcceptor::from(acceptor.inner.clone());
+        let server = tokio::spawn(async move {
+            let (stream, _) = echo.accept().await.unwrap();
+            // 复用 session 时服务器能拿回签发 session 时设置的数据
+            let mut stream = acceptor
+                .accept_with(stream, |session| session.set_resumption_data(b"kv"))
+                .await
+                .unwrap();
+            let mut buf = [0; 12];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.get_ref().1.received_resumption_data() == Some(b"kv".as_ref())
+        });
+
+        let mut stream = connector.connect(TcpStream::connect(addr).await?).await?;
+        stream.write_all(b"hello world!").await?;
+        let mut buf = [0; 12];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world!");
+        Ok(server.await?)
+    }
+
     async fn start_server(client_cert: bool) -> Result<SocketAddr> {
-        let acceptor = tls_acceptor(client_cert)?;
+        start_server_with(tls_acceptor(client_cert)?).await
+    }
 
+    async fn start_server_with(acceptor: TlsServerAcceptor) -> Result<SocketAddr> {
         let echo = TcpListener::bind("127.0.0.1:0").await?;
         let addr = echo.local_addr()?;
 
@@ -2,7 +2,16 @@
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
-    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12")]
+    /// 服务器配置了 commands.rename 时，改了名字的命令要带上新的名字才能执行
+    #[prost(string, tag="100")]
+    pub alias: ::prost::alloc::string::String,
+    /// 写命令的幂等 key，服务器对同一个 key 的重复请求返回第一次的响应
+    #[prost(string, tag="101")]
+    pub idempotency_key: ::prost::alloc::string::String,
+    /// 请求 id，出现在服务器这个请求的 tracing span 里。为空时由服务器生成
+    #[prost(string, tag="102")]
+    pub request_id: ::prost::alloc::string::String,
+    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34")]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
 /// Nested message and enum types in `CommandRequest`.
@@ -34,6 +43,50 @@ pub mod command_request {
         Unsubscribe(super::Unsubscribe),
         #[prost(message, tag="12")]
         Publish(super::Publish),
+        #[prost(message, tag="13")]
+        ClientList(super::ClientList),
+        #[prost(message, tag="14")]
+        ClientKill(super::ClientKill),
+        #[prost(message, tag="15")]
+        Latency(super::Latency),
+        #[prost(message, tag="16")]
+        Replicate(super::Replicate),
+        #[prost(message, tag="17")]
+        RaftVote(super::RaftVote),
+        #[prost(message, tag="18")]
+        RaftAppend(super::RaftAppend),
+        #[prost(message, tag="19")]
+        ClusterSlots(super::ClusterSlots),
+        #[prost(message, tag="20")]
+        Cluster(super::Cluster),
+        #[prost(message, tag="21")]
+        Gossip(super::Gossip),
+        #[prost(message, tag="22")]
+        ReplicaAck(super::ReplicaAck),
+        #[prost(message, tag="23")]
+        Promote(super::Promote),
+        #[prost(message, tag="24")]
+        CrdtSync(super::CrdtSync),
+        #[prost(message, tag="25")]
+        Hwatch(super::Hwatch),
+        #[prost(message, tag="26")]
+        Auth(super::Auth),
+        #[prost(message, tag="27")]
+        Jobs(super::Jobs),
+        #[prost(message, tag="28")]
+        Hdelprefix(super::Hdelprefix),
+        #[prost(message, tag="29")]
+        Htableexists(super::Htableexists),
+        #[prost(message, tag="30")]
+        Hdebug(super::Hdebug),
+        #[prost(message, tag="31")]
+        Save(super::Save),
+        #[prost(message, tag="32")]
+        Restore(super::Restore),
+        #[prost(message, tag="33")]
+        Reload(super::Reload),
+        #[prost(message, tag="34")]
+        Flushall(super::Flushall),
     }
 }
 /// 服务器的响应
@@ -52,6 +105,29 @@ pub struct CommandResponse {
     /// 成功返回的 kv pairs
     #[prost(message, repeated, tag="4")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// 超过 frame 上限的响应拆成多个 frame 发送，每个 frame 只带原响应 encode 之后的一段
+    #[prost(bytes="bytes", tag="5")]
+    pub chunk: ::prost::bytes::Bytes,
+    /// 后面还有分块，客户端收到 more 为 false 的分块后把所有分块拼起来 decode
+    #[prost(bool, tag="6")]
+    pub more: bool,
+    /// SUBSCRIBE / HWATCH 流的第一个响应带上订阅确认，客户端从这里取 subscription id
+    #[prost(message, optional, tag="7")]
+    pub subscription: ::core::option::Option<SubscriptionAck>,
+    /// 请求 id，和请求里的一样（请求没有带时是服务器生成的），用来在 tracing 里找到这个请求。
+    /// 流式的响应只有第一个响应带上
+    #[prost(string, tag="8")]
+    pub request_id: ::prost::alloc::string::String,
+}
+/// 订阅成功的确认。为了兼容旧的客户端，id 同时也放在 values\[0\] 里
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SubscriptionAck {
+    #[prost(uint32, tag="1")]
+    pub id: u32,
+    /// 订阅的主题，HWATCH 时是 table 名（订阅所有 table 时为空）
+    #[prost(string, tag="2")]
+    pub topic: ::prost::alloc::string::String,
 }
 /// 从 table 中获取一个 key，返回 value
 #[derive(PartialOrd)]
@@ -62,12 +138,49 @@ pub struct Hget {
     #[prost(string, tag="2")]
     pub key: ::prost::alloc::string::String,
 }
-/// 从 table 中获取所有的 Kvpair
+/// 从 table 中获取所有的 Kvpair，带 filter 时只返回 value 满足条件的
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hgetall {
     #[prost(string, tag="1")]
     pub table: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="2")]
+    pub filter: ::core::option::Option<Filter>,
+}
+/// HGETALL 在服务器上遍历 table 时执行的过滤条件，没有条件时匹配所有的值
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Filter {
+    #[prost(oneof="filter::Condition", tags="1, 2, 3, 4")]
+    pub condition: ::core::option::Option<filter::Condition>,
+}
+/// Nested message and enum types in `Filter`.
+pub mod filter {
+    #[derive(PartialOrd)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Condition {
+        /// value 的类型，和 HDEBUG 返回的 encoding 一样，比如 integer
+        #[prost(string, tag="1")]
+        ValueType(::prost::alloc::string::String),
+        /// 和一个数比较，只匹配 integer、unsigned 和 float
+        #[prost(message, tag="2")]
+        Compare(super::Compare),
+        /// 以 prefix 开头的字符串
+        #[prost(string, tag="3")]
+        Prefix(::prost::alloc::string::String),
+        /// 包含这个子串的字符串
+        #[prost(string, tag="4")]
+        Contains(::prost::alloc::string::String),
+    }
+}
+/// value 和 operand 都转换成 double 比较，超过 2^53 的整数会损失精度
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Compare {
+    #[prost(enumeration="CompareOp", tag="1")]
+    pub op: i32,
+    #[prost(double, tag="2")]
+    pub operand: f64,
 }
 /// 从 table 中获取一组 key，返回它们的 value
 #[derive(PartialOrd)]
@@ -82,7 +195,7 @@ pub struct Hmget {
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Value {
-    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5")]
+    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5, 6")]
     pub value: ::core::option::Option<value::Value>,
 }
 /// Nested message and enum types in `Value`.
@@ -100,6 +213,9 @@ pub mod value {
         Float(f64),
         #[prost(bool, tag="5")]
         Bool(bool),
+        /// 超过 int64 范围的计数器和 ID
+        #[prost(uint64, tag="6")]
+        Unsigned(u64),
     }
 }
 /// 返回的 kvpair
@@ -149,6 +265,34 @@ pub struct Hmdel {
     #[prost(string, repeated, tag="2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// 从 table 中删除所有以 prefix 开头的 key，返回删除的数量
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hdelprefix {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub prefix: ::prost::alloc::string::String,
+}
+/// 查看 table 是否存在，由存储的元数据回答，不需要遍历 table
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Htableexists {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 查看 key 的存储细节，用于排查问题。返回一组 kvpair：
+/// encoding（值的类型）、serialized_length（encode 之后的字节数）、storage（存储后端）、
+/// slot（table 所在的 slot）、ttl（-1 表示不过期），开启分片时还有 node（slot 所在的节点），
+/// 开启多主时还有 last_write（最近一次写入的毫秒时间戳）、writer（写入的节点）和 clock（版本向量）
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hdebug {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
 /// 查看 key 是否存在
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -168,7 +312,7 @@ pub struct Hmexist {
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
 /// subscribe 到某个主题，任何发布到这个主题的数据都会被收到
-/// 成功后，第一个返回的 CommandResponse，我们返回一个唯一的 subscription id
+/// 成功后，第一个返回的 CommandResponse 带有 SubscriptionAck，里面是唯一的 subscription id
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Subscribe {
@@ -193,3 +337,254 @@ pub struct Publish {
     #[prost(message, repeated, tag="2")]
     pub data: ::prost::alloc::vec::Vec<Value>,
 }
+/// 列出当前所有的客户端连接
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClientList {
+}
+/// 断开某个客户端连接，id 为 0 时按 addr 匹配
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClientKill {
+    #[prost(uint64, tag="1")]
+    pub id: u64,
+    #[prost(string, tag="2")]
+    pub addr: ::prost::alloc::string::String,
+}
+/// 查看每种命令最近的延迟 p50/p95/p99
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Latency {
+}
+/// replica 向 primary 订阅复制日志
+/// 第一个返回的 CommandResponse 是 primary 当前的复制位置，之后每个 CommandResponse
+/// 是一条写操作：values\[0\] 是序号，values\[1\] 是 encode 后的 CommandRequest
+/// snapshot 为 true 时，复制位置之后先返回 storage 的快照：序号都是复制位置的 HMSET，
+/// 快照结束时再返回一次复制位置
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Replicate {
+    #[prost(bool, tag="1")]
+    pub snapshot: bool,
+}
+/// Raft 节点之间的 RequestVote，返回 [term, 是否投票]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftVote {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    #[prost(uint64, tag="2")]
+    pub candidate: u64,
+    #[prost(uint64, tag="3")]
+    pub last_log_index: u64,
+    #[prost(uint64, tag="4")]
+    pub last_log_term: u64,
+}
+/// Raft 日志条目，command 是 encode 后的 CommandRequest，为空时是 leader 上任时的空操作
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftEntry {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    #[prost(bytes="bytes", tag="2")]
+    pub command: ::prost::bytes::Bytes,
+}
+/// Raft 节点之间的 AppendEntries，返回 [term, 是否成功]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct RaftAppend {
+    #[prost(uint64, tag="1")]
+    pub term: u64,
+    #[prost(uint64, tag="2")]
+    pub leader: u64,
+    #[prost(uint64, tag="3")]
+    pub prev_log_index: u64,
+    #[prost(uint64, tag="4")]
+    pub prev_log_term: u64,
+    #[prost(message, repeated, tag="5")]
+    pub entries: ::prost::alloc::vec::Vec<RaftEntry>,
+    #[prost(uint64, tag="6")]
+    pub leader_commit: u64,
+}
+/// 查看分片集群的 slot 分布，每三个 value 是一段连续的 slot：[起始 slot, 结束 slot, 节点地址]
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClusterSlots {
+}
+/// 查看集群成员的地址、角色和健康状态，每个成员一行
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Cluster {
+}
+/// 集群成员，incarnation 是成员启动的时间，heartbeat 是成员自己定期递增的计数
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Member {
+    #[prost(string, tag="1")]
+    pub addr: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub role: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub incarnation: u64,
+    #[prost(uint64, tag="4")]
+    pub heartbeat: u64,
+}
+/// 节点之间交换成员列表，返回对方知道的成员，每个 value 是 encode 后的 Member
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Gossip {
+    #[prost(message, repeated, tag="1")]
+    pub members: ::prost::alloc::vec::Vec<Member>,
+}
+/// replica 定期告诉 primary 自己还在复制，返回 primary 的 epoch
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReplicaAck {
+    #[prost(uint64, tag="1")]
+    pub epoch: u64,
+    #[prost(uint64, tag="2")]
+    pub position: u64,
+}
+/// 把 replica 提升为 primary，epoch 为 0 时使用当前 epoch + 1，返回新的 epoch
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Promote {
+    #[prost(uint64, tag="1")]
+    pub epoch: u64,
+}
+/// 向量时钟里的一项：节点和这个节点上的写操作计数
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Dot {
+    #[prost(string, tag="1")]
+    pub node: ::prost::alloc::string::String,
+    #[prost(uint64, tag="2")]
+    pub counter: u64,
+}
+/// 多主模式下一个 key 的版本，deleted 为 true 时是删除之后留下的墓碑
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Version {
+    #[prost(message, repeated, tag="1")]
+    pub clock: ::prost::alloc::vec::Vec<Dot>,
+    #[prost(uint64, tag="2")]
+    pub timestamp: u64,
+    #[prost(string, tag="3")]
+    pub node: ::prost::alloc::string::String,
+    #[prost(bool, tag="4")]
+    pub deleted: bool,
+}
+/// 多主模式下同步的一个 key，删除的 key 没有 value
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CrdtEntry {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub value: ::core::option::Option<Value>,
+    #[prost(message, optional, tag="4")]
+    pub version: ::core::option::Option<Version>,
+}
+/// 多主节点之间的反熵同步：合并 entries，返回一个 value，是 encode 后的 CrdtSync，
+/// 包含本节点的 clock 和请求的 clock 之后本节点知道的写操作
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CrdtSync {
+    #[prost(message, repeated, tag="1")]
+    pub clock: ::prost::alloc::vec::Vec<Dot>,
+    #[prost(message, repeated, tag="2")]
+    pub entries: ::prost::alloc::vec::Vec<CrdtEntry>,
+}
+/// 订阅 table 的变更流，table 为空时订阅所有 table。第一个响应是带 watch id 的 SubscriptionAck，
+/// 之后每个响应是一个变更，只有一个 value，是 encode 后的 ChangeEvent。
+/// snapshot 为 true 时必须指定 table，watch id 之后先返回 table 当前所有的数据（和 HGETALL 一样），
+/// 再返回快照之后的变更，快照和变更之间既不会遗漏也不会重复
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hwatch {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(bool, tag="2")]
+    pub snapshot: bool,
+}
+/// 一个 key 的变更，seq 在服务器内单调递增。新增的 key 没有 old_value，删除的 key 没有 new_value
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ChangeEvent {
+    #[prost(uint64, tag="1")]
+    pub seq: u64,
+    #[prost(enumeration="ChangeOp", tag="2")]
+    pub op: i32,
+    #[prost(string, tag="3")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="4")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="5")]
+    pub old_value: ::core::option::Option<Value>,
+    #[prost(message, optional, tag="6")]
+    pub new_value: ::core::option::Option<Value>,
+}
+/// 服务器设置了密码时，连接要先用 AUTH 认证才能执行其它命令。没有设置密码时总是成功
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Auth {
+    #[prost(string, tag="1")]
+    pub password: ::prost::alloc::string::String,
+}
+/// 查看后台任务调度器里每个 job 的执行情况
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Jobs {
+}
+/// 把所有数据写到服务器备份目录（admin.backup_dir）里名为 name 的文件，格式和定期的快照一样，
+/// 返回写入的 key 数。name 只能是文件名，不能带目录
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Save {
+    #[prost(string, tag="1")]
+    pub name: ::prost::alloc::string::String,
+}
+/// 把备份目录里名为 name 的备份读回存储，已有的 key 被覆盖，返回读入的 key 数。
+/// 备份里的数据当成 HMSET 执行，和普通的写操作一样复制给 replica，发布 keyspace 通知和变更。
+/// 开启 Raft 时不支持
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Restore {
+    #[prost(string, tag="1")]
+    pub name: ::prost::alloc::string::String,
+}
+/// 让服务器重新读取配置文件，和收到 SIGHUP 一样，结果记在服务器的日志里
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Reload {
+}
+/// 删除所有 table 里的数据，返回删除的 key 数。confirm 必须是 "FLUSHALL"，避免误操作。
+/// 逐个 table 执行 HDELPREFIX，和普通的写操作一样复制给 replica，发布 keyspace 通知和变更，
+/// table 之间不是原子的。开启 Raft 或者多主时不支持。
+/// 和其它运维命令一样，只能在管理端口上或者由 admin 角色执行。
+/// __ 开头的内部 table 不会被删除
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Flushall {
+    #[prost(string, tag="1")]
+    pub confirm: ::prost::alloc::string::String,
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum CompareOp {
+    Eq = 0,
+    Ne = 1,
+    Gt = 2,
+    Ge = 3,
+    Lt = 4,
+    Le = 5,
+}
+/// 变更的类型
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ChangeOp {
+    Set = 0,
+    Del = 1,
+}
@@ -1,10 +1,58 @@
 pub mod abi;
+#[cfg(feature = "serde")]
+mod serde_impl;
 
 use crate::KvError;
 use abi::{command_request::RequestData, *};
 use bytes::Bytes;
 use http::StatusCode;
 use prost::Message;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+
+/// 共享的常见响应：OK、空值、true、false。HSET 一个新的 key、HEXIST 这类命令的结果都是其中之一，
+/// 发送时直接使用共享的实例，不用每次分配
+static SHARED_RESPONSES: LazyLock<[Arc<CommandResponse>; 4]> = LazyLock::new(|| {
+    [
+        CommandResponse::ok(),
+        Value::default().into(),
+        Value::from(true).into(),
+        Value::from(false).into(),
+    ]
+    .map(Arc::new)
+});
+
+/// 每个线程最多缓存的 values
+const VALUES_POOL_SIZE: usize = 64;
+/// 容量更大的 values 不放回缓存，避免一直占着大块内存
+const POOLED_VALUES_CAPACITY: usize = 4;
+
+thread_local! {
+    /// 换成共享实例的响应留下的空的 values，下一个只有一个值的响应直接拿来用
+    static VALUES_POOL: RefCell<Vec<Vec<Value>>> = const { RefCell::new(Vec::new()) };
+}
+
+fn pooled_values(v: Value) -> Vec<Value> {
+    let mut values = VALUES_POOL
+        .with(|pool| pool.borrow_mut().pop())
+        .unwrap_or_else(|| Vec::with_capacity(1));
+    values.push(v);
+    values
+}
+
+fn recycle_values(mut values: Vec<Value>) {
+    if values.capacity() == 0 || values.capacity() > POOLED_VALUES_CAPACITY {
+        return;
+    }
+    values.clear();
+    VALUES_POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() < VALUES_POOL_SIZE {
+            pool.push(values);
+        }
+    });
+}
 
 impl CommandRequest {
     /// 创建 HSET 命令
@@ -14,6 +62,7 @@ impl CommandRequest {
                 table: table.into(),
                 pair: Some(Kvpair::new(key, value)),
             })),
+            ..Default::default()
         }
     }
 
@@ -23,6 +72,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -30,7 +80,20 @@ impl CommandRequest {
         Self {
             request_data: Some(RequestData::Hgetall(Hgetall {
                 table: table.into(),
+                filter: None,
             })),
+            ..Default::default()
+        }
+    }
+
+    /// 只返回 value 满足 filter 的 kv pair，过滤在服务器上进行
+    pub fn new_hgetall_filtered(table: impl Into<String>, filter: Filter) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetall(Hgetall {
+                table: table.into(),
+                filter: Some(filter),
+            })),
+            ..Default::default()
         }
     }
 
@@ -40,6 +103,7 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
@@ -49,6 +113,7 @@ impl CommandRequest {
                 table: table.into(),
                 pairs,
             })),
+            ..Default::default()
         }
     }
 
@@ -58,6 +123,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -67,6 +133,36 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hdelprefix(table: impl Into<String>, prefix: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hdelprefix(Hdelprefix {
+                table: table.into(),
+                prefix: prefix.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_htableexists(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Htableexists(Htableexists {
+                table: table.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hdebug(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hdebug(Hdebug {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
         }
     }
 
@@ -76,6 +172,7 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            ..Default::default()
         }
     }
 
@@ -85,12 +182,14 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            ..Default::default()
         }
     }
 
     pub fn new_subscribe(name: impl Into<String>) -> Self {
         Self {
             request_data: Some(RequestData::Subscribe(Subscribe { topic: name.into() })),
+            ..Default::default()
         }
     }
 
@@ -100,6 +199,7 @@ impl CommandRequest {
                 topic: name.into(),
                 id,
             })),
+            ..Default::default()
         }
     }
 
@@ -109,6 +209,386 @@ impl CommandRequest {
                 topic: name.into(),
                 data,
             })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 CLIENT LIST 命令
+    pub fn new_client_list() -> Self {
+        Self {
+            request_data: Some(RequestData::ClientList(ClientList {})),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 CLIENT KILL 命令，按 connection id 断开连接
+    pub fn new_client_kill(id: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::ClientKill(ClientKill {
+                id,
+                addr: String::new(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 CLIENT KILL 命令，按客户端地址断开连接
+    pub fn new_client_kill_addr(addr: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::ClientKill(ClientKill {
+                id: 0,
+                addr: addr.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 LATENCY 命令
+    pub fn new_latency() -> Self {
+        Self {
+            request_data: Some(RequestData::Latency(Latency {})),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 REPLICATE 命令，replica 用它订阅 primary 的复制日志，snapshot 为 true 时先拉取快照
+    pub fn new_replicate(snapshot: bool) -> Self {
+        Self {
+            request_data: Some(RequestData::Replicate(Replicate { snapshot })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 CLUSTER SLOTS 命令
+    pub fn new_cluster_slots() -> Self {
+        Self {
+            request_data: Some(RequestData::ClusterSlots(ClusterSlots {})),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 CLUSTER 命令
+    pub fn new_cluster() -> Self {
+        Self {
+            request_data: Some(RequestData::Cluster(Cluster {})),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 GOSSIP 命令，节点之间交换成员列表
+    pub fn new_gossip(members: Vec<Member>) -> Self {
+        Self {
+            request_data: Some(RequestData::Gossip(Gossip { members })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 REPLICAACK 命令，replica 定期发给 primary
+    pub fn new_replica_ack(epoch: u64, position: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::ReplicaAck(ReplicaAck { epoch, position })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 PROMOTE 命令，外部的协调者用它把 replica 提升为 primary
+    pub fn new_promote(epoch: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Promote(Promote { epoch })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 SAVE 命令，把所有数据写到服务器备份目录里名为 name 的文件
+    pub fn new_save(name: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Save(Save { name: name.into() })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 RESTORE 命令，把服务器备份目录里名为 name 的备份读回存储
+    pub fn new_restore(name: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Restore(Restore { name: name.into() })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_reload() -> Self {
+        Self {
+            request_data: Some(RequestData::Reload(Reload {})),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 FLUSHALL 命令，confirm 不是 Flushall::CONFIRM 时服务器拒绝执行
+    pub fn new_flushall(confirm: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Flushall(Flushall {
+                confirm: confirm.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 CRDTSYNC 命令，多主节点之间做反熵同步
+    pub fn new_crdt_sync(clock: Vec<Dot>, entries: Vec<CrdtEntry>) -> Self {
+        Self {
+            request_data: Some(RequestData::CrdtSync(CrdtSync { clock, entries })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 HWATCH 命令，table 为空时订阅所有 table 的变更
+    pub fn new_hwatch(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hwatch(Hwatch {
+                table: table.into(),
+                snapshot: false,
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建带快照的 HWATCH 命令，先返回 table 当前的数据，再返回之后的变更，用于在客户端维护副本
+    pub fn new_hwatch_snapshot(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hwatch(Hwatch {
+                table: table.into(),
+                snapshot: true,
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 AUTH 命令
+    pub fn new_auth(password: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Auth(Auth {
+                password: password.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// 创建 JOBS 命令
+    pub fn new_jobs() -> Self {
+        Self {
+            request_data: Some(RequestData::Jobs(Jobs {})),
+            ..Default::default()
+        }
+    }
+
+    /// 带上服务器给这个命令配置的新名字（commands.rename）
+    pub fn with_alias(mut self, alias: impl Into<String>) -> Self {
+        self.alias = alias.into();
+        self
+    }
+
+    /// 带上幂等 key，重试时使用同一个 key，服务器不会重复执行写命令
+    pub fn with_idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = key.into();
+        self
+    }
+
+    /// 带上请求 id，服务器在 tracing 里和响应里使用这个 id，不带时由服务器生成
+    pub fn with_request_id(mut self, id: impl Into<String>) -> Self {
+        self.request_id = id.into();
+        self
+    }
+
+    /// 所有命令的名字，和 name() 的返回值一一对应
+    pub const NAMES: &'static [&'static str] = &[
+        "hget",
+        "hgetall",
+        "hmget",
+        "hset",
+        "hmset",
+        "hdel",
+        "hmdel",
+        "hexist",
+        "hmexist",
+        "subscribe",
+        "unsubscribe",
+        "publish",
+        "client_list",
+        "client_kill",
+        "latency",
+        "replicate",
+        "raft_vote",
+        "raft_append",
+        "cluster_slots",
+        "cluster",
+        "gossip",
+        "replica_ack",
+        "promote",
+        "crdt_sync",
+        "hwatch",
+        "auth",
+        "jobs",
+        "hdelprefix",
+        "htableexists",
+        "hdebug",
+        "save",
+        "restore",
+        "reload",
+        "flushall",
+    ];
+
+    /// 命令的名字，用于统计和日志
+    pub fn name(&self) -> &'static str {
+        match &self.request_data {
+            Some(RequestData::Hget(_)) => "hget",
+            Some(RequestData::Hgetall(_)) => "hgetall",
+            Some(RequestData::Hmget(_)) => "hmget",
+            Some(RequestData::Hset(_)) => "hset",
+            Some(RequestData::Hmset(_)) => "hmset",
+            Some(RequestData::Hdel(_)) => "hdel",
+            Some(RequestData::Hmdel(_)) => "hmdel",
+            Some(RequestData::Hexist(_)) => "hexist",
+            Some(RequestData::Hmexist(_)) => "hmexist",
+            Some(RequestData::Subscribe(_)) => "subscribe",
+            Some(RequestData::Unsubscribe(_)) => "unsubscribe",
+            Some(RequestData::Publish(_)) => "publish",
+            Some(RequestData::ClientList(_)) => "client_list",
+            Some(RequestData::ClientKill(_)) => "client_kill",
+            Some(RequestData::Latency(_)) => "latency",
+            Some(RequestData::Replicate(_)) => "replicate",
+            Some(RequestData::RaftVote(_)) => "raft_vote",
+            Some(RequestData::RaftAppend(_)) => "raft_append",
+            Some(RequestData::ClusterSlots(_)) => "cluster_slots",
+            Some(RequestData::Cluster(_)) => "cluster",
+            Some(RequestData::Gossip(_)) => "gossip",
+            Some(RequestData::ReplicaAck(_)) => "replica_ack",
+            Some(RequestData::Promote(_)) => "promote",
+            Some(RequestData::CrdtSync(_)) => "crdt_sync",
+            Some(RequestData::Hwatch(_)) => "hwatch",
+            Some(RequestData::Auth(_)) => "auth",
+            Some(RequestData::Jobs(_)) => "jobs",
+            Some(RequestData::Hdelprefix(_)) => "hdelprefix",
+            Some(RequestData::Htableexists(_)) => "htableexists",
+            Some(RequestData::Hdebug(_)) => "hdebug",
+            Some(RequestData::Save(_)) => "save",
+            Some(RequestData::Restore(_)) => "restore",
+            Some(RequestData::Reload(_)) => "reload",
+            Some(RequestData::Flushall(_)) => "flushall",
+            None => "unknown",
+        }
+    }
+
+    /// 只读 Storage 的命令
+    pub fn is_read(&self) -> bool {
+        matches!(
+            self.request_data,
+            Some(
+                RequestData::Hget(_)
+                    | RequestData::Hgetall(_)
+                    | RequestData::Hmget(_)
+                    | RequestData::Hexist(_)
+                    | RequestData::Hmexist(_)
+                    | RequestData::Htableexists(_)
+            )
+        )
+    }
+
+    /// 响应是持续的流的 pub/sub 命令
+    pub fn is_pubsub(&self) -> bool {
+        matches!(
+            self.request_data,
+            Some(RequestData::Subscribe(_) | RequestData::Unsubscribe(_) | RequestData::Publish(_))
+        )
+    }
+
+    /// 运维命令，只能在管理端口上或者由 admin 角色执行。
+    /// GOSSIP、REPLICAACK 等集群内部的命令不算，节点之间通过数据端口通信
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self.request_data,
+            Some(
+                RequestData::ClientList(_)
+                    | RequestData::ClientKill(_)
+                    | RequestData::Latency(_)
+                    | RequestData::Jobs(_)
+                    | RequestData::Promote(_)
+                    | RequestData::Save(_)
+                    | RequestData::Restore(_)
+                    | RequestData::Reload(_)
+                    | RequestData::Flushall(_)
+            )
+        )
+    }
+
+    /// 数据命令操作的 table，管理命令和 pub/sub 命令返回 None
+    pub fn table(&self) -> Option<&str> {
+        let table = match self.request_data.as_ref()? {
+            RequestData::Hget(v) => &v.table,
+            RequestData::Hgetall(v) => &v.table,
+            RequestData::Hmget(v) => &v.table,
+            RequestData::Hset(v) => &v.table,
+            RequestData::Hmset(v) => &v.table,
+            RequestData::Hdel(v) => &v.table,
+            RequestData::Hmdel(v) => &v.table,
+            RequestData::Hexist(v) => &v.table,
+            RequestData::Hmexist(v) => &v.table,
+            RequestData::Hdelprefix(v) => &v.table,
+            RequestData::Htableexists(v) => &v.table,
+            RequestData::Hdebug(v) => &v.table,
+            _ => return None,
+        };
+        Some(table)
+    }
+
+    /// 命令里 pair、key 或者 value 的数量，用于限制单个命令的大小。只操作一个 key 的命令返回 1
+    pub fn entries(&self) -> usize {
+        match &self.request_data {
+            Some(RequestData::Hmset(v)) => v.pairs.len(),
+            Some(RequestData::Hmget(v)) => v.keys.len(),
+            Some(RequestData::Hmdel(v)) => v.keys.len(),
+            Some(RequestData::Hmexist(v)) => v.keys.len(),
+            Some(RequestData::Publish(v)) => v.data.len(),
+            _ => 1,
+        }
+    }
+
+    /// 和 table 一样，用于修改请求里的 table 名
+    pub fn table_mut(&mut self) -> Option<&mut String> {
+        let table = match self.request_data.as_mut()? {
+            RequestData::Hget(v) => &mut v.table,
+            RequestData::Hgetall(v) => &mut v.table,
+            RequestData::Hmget(v) => &mut v.table,
+            RequestData::Hset(v) => &mut v.table,
+            RequestData::Hmset(v) => &mut v.table,
+            RequestData::Hdel(v) => &mut v.table,
+            RequestData::Hmdel(v) => &mut v.table,
+            RequestData::Hexist(v) => &mut v.table,
+            RequestData::Hmexist(v) => &mut v.table,
+            RequestData::Hdelprefix(v) => &mut v.table,
+            RequestData::Htableexists(v) => &mut v.table,
+            RequestData::Hdebug(v) => &mut v.table,
+            _ => return None,
+        };
+        Some(table)
+    }
+
+    /// 写命令修改的 table 和 key，读命令和 pub/sub 命令返回 None。
+    /// HDELPREFIX 删除哪些 key 要执行时才知道，返回的 key 是空的
+    pub fn modified_keys(&self) -> Option<(&str, Vec<&str>)> {
+        match &self.request_data {
+            Some(RequestData::Hset(v)) => {
+                let keys = v.pair.iter().map(|p| p.key.as_str()).collect();
+                Some((&v.table, keys))
+            }
+            Some(RequestData::Hmset(v)) => {
+                let keys = v.pairs.iter().map(|p| p.key.as_str()).collect();
+                Some((&v.table, keys))
+            }
+            Some(RequestData::Hdel(v)) => Some((&v.table, vec![v.key.as_str()])),
+            Some(RequestData::Hmdel(v)) => {
+                Some((&v.table, v.keys.iter().map(|k| k.as_str()).collect()))
+            }
+            Some(RequestData::Hdelprefix(v)) => Some((&v.table, vec![])),
+            _ => None,
         }
     }
 
@@ -126,6 +606,39 @@ impl CommandResponse {
         }
     }
 
+    /// 包装成 Arc 用于发送。和共享的常见响应相同时返回共享的实例，values 放回当前线程的缓存
+    pub fn into_shared(self) -> Arc<CommandResponse> {
+        let i = match self.values.as_slice() {
+            [] => 0,
+            [v] => match v.value {
+                None => 1,
+                Some(value::Value::Bool(true)) => 2,
+                Some(value::Value::Bool(false)) => 3,
+                _ => return Arc::new(self),
+            },
+            _ => return Arc::new(self),
+        };
+        let shared = &SHARED_RESPONSES[i];
+        if self != **shared {
+            return Arc::new(self);
+        }
+        recycle_values(self.values);
+        Arc::clone(shared)
+    }
+
+    /// SUBSCRIBE / HWATCH 流的第一个响应。id 同时放在 values[0] 里，旧的客户端也能读到
+    pub fn subscription_ack(id: u32, topic: impl Into<String>) -> Self {
+        CommandResponse {
+            status: StatusCode::OK.as_u16() as _,
+            values: vec![(id as i64).into()],
+            subscription: Some(SubscriptionAck {
+                id,
+                topic: topic.into(),
+            }),
+            ..Default::default()
+        }
+    }
+
     pub fn internal_error(msg: String) -> Self {
         CommandResponse {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
@@ -138,6 +651,39 @@ impl CommandResponse {
     pub fn format(&self) -> String {
         format!("{:?}", self)
     }
+
+    /// 把 421 和 503 的响应还原成对应的暂时性错误（NotLeader、Moved、Fenced、Busy），
+    /// 客户端据此决定是否重试。其它响应返回 None
+    pub fn transient_error(&self) -> Option<KvError> {
+        let msg = self.message.as_str();
+        let status = StatusCode::from_u16(self.status as u16).ok()?;
+        let e = match status {
+            StatusCode::MISDIRECTED_REQUEST => {
+                let moved = msg
+                    .strip_prefix("Slot ")
+                    .and_then(|s| s.split_once(" is owned by "))
+                    .and_then(|(slot, addr)| Some((slot.parse().ok()?, addr)));
+                match moved {
+                    Some((slot, addr)) => KvError::Moved(slot, addr.into()),
+                    None => KvError::NotLeader(
+                        msg.strip_prefix("Not leader, leader is ")
+                            .unwrap_or(msg)
+                            .into(),
+                    ),
+                }
+            }
+            StatusCode::SERVICE_UNAVAILABLE => match msg.strip_prefix("Writes are fenced: ") {
+                Some(reason) => KvError::Fenced(reason.into()),
+                None => KvError::Busy(
+                    msg.strip_prefix("Server busy, retry later: ")
+                        .unwrap_or(msg)
+                        .into(),
+                ),
+            },
+            _ => return None,
+        };
+        Some(e)
+    }
 }
 
 impl Value {
@@ -145,6 +691,97 @@ impl Value {
     pub fn format(&self) -> String {
         format!("{:?}", self)
     }
+
+    /// 值的类型，没有值时是 null
+    pub fn type_name(&self) -> &'static str {
+        match &self.value {
+            Some(value::Value::String(_)) => "string",
+            Some(value::Value::Binary(_)) => "binary",
+            Some(value::Value::Integer(_)) => "integer",
+            Some(value::Value::Unsigned(_)) => "unsigned",
+            Some(value::Value::Float(_)) => "float",
+            Some(value::Value::Bool(_)) => "bool",
+            None => "null",
+        }
+    }
+}
+
+impl Filter {
+    /// 值的类型是 value_type，类型的名字和 Value::type_name 一样
+    pub fn value_type(value_type: impl Into<String>) -> Self {
+        Self::new(filter::Condition::ValueType(value_type.into()))
+    }
+
+    /// 数值和 operand 比较的结果满足 op
+    pub fn compare(op: CompareOp, operand: f64) -> Self {
+        Self::new(filter::Condition::Compare(Compare {
+            op: op as _,
+            operand,
+        }))
+    }
+
+    pub fn prefix(prefix: impl Into<String>) -> Self {
+        Self::new(filter::Condition::Prefix(prefix.into()))
+    }
+
+    pub fn contains(s: impl Into<String>) -> Self {
+        Self::new(filter::Condition::Contains(s.into()))
+    }
+
+    fn new(condition: filter::Condition) -> Self {
+        Self {
+            condition: Some(condition),
+        }
+    }
+
+    /// value 是否满足条件
+    pub fn matches(&self, v: &Value) -> bool {
+        let Some(condition) = &self.condition else {
+            return true;
+        };
+        match (condition, &v.value) {
+            (filter::Condition::ValueType(t), _) => v.type_name() == t,
+            (filter::Condition::Prefix(p), Some(value::Value::String(s))) => s.starts_with(p),
+            (filter::Condition::Contains(p), Some(value::Value::String(s))) => s.contains(p),
+            (filter::Condition::Compare(c), _) => {
+                let n = match v.value {
+                    Some(value::Value::Integer(i)) => i as f64,
+                    Some(value::Value::Unsigned(u)) => u as f64,
+                    Some(value::Value::Float(f)) => f,
+                    _ => return false,
+                };
+                match c.op() {
+                    CompareOp::Eq => n == c.operand,
+                    CompareOp::Ne => n != c.operand,
+                    CompareOp::Gt => n > c.operand,
+                    CompareOp::Ge => n >= c.operand,
+                    CompareOp::Lt => n < c.operand,
+                    CompareOp::Le => n <= c.operand,
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Hgetall {
+    /// kv pair 是否满足 filter，没有 filter 时总是满足
+    pub fn matches(&self, pair: &Kvpair) -> bool {
+        match (&self.filter, &pair.value) {
+            (None, _) => true,
+            (Some(filter), Some(v)) => filter.matches(v),
+            (Some(filter), None) => filter.matches(&Value::default()),
+        }
+    }
+}
+
+impl Flushall {
+    /// 确认要删除所有数据时 confirm 的值
+    pub const CONFIRM: &'static str = "FLUSHALL";
+
+    pub fn is_confirmed(&self) -> bool {
+        self.confirm == Self::CONFIRM
+    }
 }
 
 impl Kvpair {
@@ -182,6 +819,26 @@ impl From<i64> for Value {
         }
     }
 }
+
+/// 整数字面量（比如 `Value::from(1)`）的默认类型是 i32，有了 u64 之后需要这个转换才能推导出来
+impl From<i32> for Value {
+    fn from(i: i32) -> Self {
+        (i as i64).into()
+    }
+}
+
+/// i64 放得下的还是 Integer，只懂 Integer 的老客户端也能读；更大的才是 Unsigned
+impl From<u64> for Value {
+    fn from(u: u64) -> Self {
+        match i64::try_from(u) {
+            Ok(i) => i.into(),
+            Err(_) => Self {
+                value: Some(value::Value::Unsigned(u)),
+            },
+        }
+    }
+}
+
 impl From<f64> for Value {
     fn from(f: f64) -> Self {
         Self {
@@ -211,7 +868,7 @@ impl From<Value> for CommandResponse {
     fn from(v: Value) -> Self {
         Self {
             status: StatusCode::OK.as_u16() as _,
-            values: vec![v],
+            values: pooled_values(v),
             ..Default::default()
         }
     }
@@ -234,14 +891,44 @@ impl From<KvError> for CommandResponse {
         let mut result = Self {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
             message: e.to_string(),
-            values: vec![],
-            pairs: vec![],
+            ..Default::default()
         };
 
         match e {
             KvError::NotFound(_) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
-            KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
-            KvError::PermissionDenied(_) => result.status = StatusCode::FORBIDDEN.as_u16() as _,
+            KvError::InvalidCommand(_) | KvError::MalformedFrame(_) => {
+                result.status = StatusCode::BAD_REQUEST.as_u16() as _
+            }
+            KvError::PermissionDenied(_) | KvError::CommandDisabled(_) => {
+                result.status = StatusCode::FORBIDDEN.as_u16() as _
+            }
+            KvError::NotLeader(_) | KvError::Moved(..) => {
+                result.status = StatusCode::MISDIRECTED_REQUEST.as_u16() as _
+            }
+            KvError::Fenced(_) | KvError::Busy(_) => {
+                result.status = StatusCode::SERVICE_UNAVAILABLE.as_u16() as _
+            }
+            KvError::Timeout(_) => result.status = StatusCode::GATEWAY_TIMEOUT.as_u16() as _,
+            KvError::StreamTimeout(_) => result.status = StatusCode::REQUEST_TIMEOUT.as_u16() as _,
+            KvError::Io(_) | KvError::ConnectionError(_) => {
+                result.status = StatusCode::BAD_GATEWAY.as_u16() as _
+            }
+            KvError::Frame(_)
+            | KvError::FrameTooLarge(_)
+            | KvError::RequestTooLarge(_)
+            | KvError::ResultTooLarge(_)
+            | KvError::EntryTooLarge(_) => {
+                result.status = StatusCode::PAYLOAD_TOO_LARGE.as_u16() as _
+            }
+            KvError::Storage(_) => result.status = StatusCode::INSUFFICIENT_STORAGE.as_u16() as _,
+            KvError::TooManySubscribers(_) | KvError::QuotaExceeded(_) => {
+                result.status = StatusCode::TOO_MANY_REQUESTS.as_u16() as _
+            }
+            KvError::Noise(_) | KvError::Unauthorized(_) => {
+                result.status = StatusCode::UNAUTHORIZED.as_u16() as _
+            }
+            // 和 nginx 一样，495 表示证书错误
+            KvError::Tls(_) => result.status = 495,
             _ => {}
         }
 
@@ -263,19 +950,18 @@ impl TryFrom<Value> for i64 {
     type Error = KvError;
 
     fn try_from(v: Value) -> Result<Self, Self::Error> {
-        match v.value {
-            Some(value::Value::Integer(i)) => Ok(i),
-            _ => Err(KvError::ConvertError(v.format(), "Integer")),
-        }
+        i64::try_from(&v)
     }
 }
 
+/// 超过 i64 的 u64 无法转换
 impl TryFrom<&Value> for i64 {
     type Error = KvError;
 
     fn try_from(v: &Value) -> Result<Self, Self::Error> {
         match v.value {
             Some(value::Value::Integer(i)) => Ok(i),
+            Some(value::Value::Unsigned(u)) if u <= i64::MAX as u64 => Ok(u as i64),
             _ => Err(KvError::ConvertError(v.format(), "Integer")),
         }
     }
@@ -358,3 +1044,251 @@ impl TryFrom<&CommandResponse> for i64 {
         }
     }
 }
+
+/// 真正的二进制数据，不会被当成字符串
+impl From<Vec<u8>> for Value {
+    fn from(buf: Vec<u8>) -> Self {
+        Bytes::from(buf).into()
+    }
+}
+
+impl From<f32> for Value {
+    fn from(f: f32) -> Self {
+        (f as f64).into()
+    }
+}
+
+/// None 转换成没有值的 Value
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Self {
+        v.map(Into::into).unwrap_or_default()
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::String(s)) => Ok(s),
+            _ => Err(KvError::ConvertError(v.format(), "String")),
+        }
+    }
+}
+
+impl TryFrom<&Value> for String {
+    type Error = KvError;
+
+    fn try_from(v: &Value) -> Result<Self, Self::Error> {
+        match &v.value {
+            Some(value::Value::String(s)) => Ok(s.clone()),
+            _ => Err(KvError::ConvertError(v.format(), "String")),
+        }
+    }
+}
+
+impl TryFrom<&Value> for f64 {
+    type Error = KvError;
+
+    fn try_from(v: &Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::Float(f)) => Ok(f),
+            _ => Err(KvError::ConvertError(v.format(), "Float")),
+        }
+    }
+}
+
+impl TryFrom<&Value> for bool {
+    type Error = KvError;
+
+    fn try_from(v: &Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::Bool(b)) => Ok(b),
+            _ => Err(KvError::ConvertError(v.format(), "Boolean")),
+        }
+    }
+}
+
+/// 负数无法转换
+impl TryFrom<Value> for u64 {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::Integer(i)) if i >= 0 => Ok(i as u64),
+            Some(value::Value::Unsigned(u)) => Ok(u),
+            _ => Err(KvError::ConvertError(v.format(), "Unsigned integer")),
+        }
+    }
+}
+
+/// 从 HashMap 转换成 CommandResponse，pairs 按 key 排序
+impl From<HashMap<String, Value>> for CommandResponse {
+    fn from(map: HashMap<String, Value>) -> Self {
+        let mut pairs: Vec<_> = map.into_iter().map(|(k, v)| Kvpair::new(k, v)).collect();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        pairs.into()
+    }
+}
+
+/// 从 bool 的 slice 转换成 CommandResponse，和 HMEXIST 的响应一样
+impl From<&[bool]> for CommandResponse {
+    fn from(v: &[bool]) -> Self {
+        v.iter().map(|b| Value::from(*b)).collect::<Vec<_>>().into()
+    }
+}
+
+/// 取出 HGETALL 这样返回 kv pairs 的响应
+impl TryFrom<&CommandResponse> for HashMap<String, Value> {
+    type Error = KvError;
+
+    fn try_from(res: &CommandResponse) -> Result<Self, Self::Error> {
+        if res.status != StatusCode::OK.as_u16() as u32 {
+            return Err(KvError::ConvertError(res.format(), "HashMap"));
+        }
+        Ok(res
+            .pairs
+            .iter()
+            .map(|pair| (pair.key.clone(), pair.value.clone().unwrap_or_default()))
+            .collect())
+    }
+}
+
+/// 取出订阅流第一个响应里的订阅确认。旧的服务器没有 subscription 字段，从 values[0] 里取 id
+impl TryFrom<&CommandResponse> for SubscriptionAck {
+    type Error = KvError;
+
+    fn try_from(res: &CommandResponse) -> Result<Self, Self::Error> {
+        if res.status != StatusCode::OK.as_u16() as u32 {
+            return Err(KvError::ConvertError(res.format(), "SubscriptionAck"));
+        }
+        if let Some(ack) = &res.subscription {
+            return Ok(ack.clone());
+        }
+        let id = i64::try_from(res)?;
+        let id = u32::try_from(id)
+            .map_err(|_| KvError::ConvertError(res.format(), "SubscriptionAck"))?;
+        Ok(SubscriptionAck {
+            id,
+            topic: String::new(),
+        })
+    }
+}
+
+/// 取出 HMEXIST 这样返回一组 bool 的响应
+impl TryFrom<&CommandResponse> for Vec<bool> {
+    type Error = KvError;
+
+    fn try_from(res: &CommandResponse) -> Result<Self, Self::Error> {
+        if res.status != StatusCode::OK.as_u16() as u32 {
+            return Err(KvError::ConvertError(res.format(), "Vec<bool>"));
+        }
+        res.values.iter().map(bool::try_from).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn common_responses_should_be_shared() {
+        let a = CommandResponse::ok().into_shared();
+        assert!(Arc::ptr_eq(&a, &CommandResponse::ok().into_shared()));
+
+        let res: CommandResponse = Value::from(true).into();
+        let values = res.values.as_ptr();
+        let t = res.into_shared();
+        assert!(Arc::ptr_eq(&t, &SHARED_RESPONSES[2]));
+        // values 放回了缓存，下一个只有一个值的响应直接使用
+        let res: CommandResponse = Value::from(1).into();
+        assert_eq!(res.values.as_ptr(), values);
+
+        // 其它的响应不共享
+        assert_eq!(Arc::strong_count(&res.into_shared()), 1);
+        let res = CommandResponse {
+            message: "done".into(),
+            ..CommandResponse::ok()
+        };
+        assert_eq!(Arc::strong_count(&res.into_shared()), 1);
+    }
+
+    #[test]
+    fn value_should_convert_from_rust_types() {
+        assert_eq!(
+            Value::from(vec![0u8, 255]),
+            Value::from(Bytes::from_static(&[0, 255]))
+        );
+        assert_eq!(Value::from(42u64), Value::from(42));
+        assert_eq!(
+            Value::from(u64::MAX).value,
+            Some(value::Value::Unsigned(u64::MAX))
+        );
+        assert_eq!(Value::from(1.5f32), Value::from(1.5));
+        assert_eq!(Value::from(Some("v1")), Value::from("v1"));
+        assert_eq!(Value::from(None::<i64>), Value::default());
+    }
+
+    #[test]
+    fn value_should_convert_to_rust_types() {
+        assert_eq!(String::try_from(Value::from("v1")).unwrap(), "v1");
+        assert_eq!(String::try_from(&Value::from("v1")).unwrap(), "v1");
+        assert_eq!(f64::try_from(&Value::from(1.5)).unwrap(), 1.5);
+        assert!(bool::try_from(&Value::from(true)).unwrap());
+        assert_eq!(u64::try_from(Value::from(7)).unwrap(), 7);
+        assert_eq!(u64::try_from(Value::from(u64::MAX)).unwrap(), u64::MAX);
+        assert_eq!(i64::try_from(Value::from(7u64)).unwrap(), 7);
+        assert!(i64::try_from(Value::from(u64::MAX)).is_err());
+
+        let err = u64::try_from(Value::from(-1)).unwrap_err();
+        assert!(matches!(err, KvError::ConvertError(_, "Unsigned integer")));
+        let err = String::try_from(Value::from(1)).unwrap_err();
+        assert!(matches!(err, KvError::ConvertError(_, "String")));
+    }
+
+    #[test]
+    fn response_should_convert_to_collections() {
+        let map = HashMap::from([("k2".to_string(), 2.into()), ("k1".to_string(), 1.into())]);
+        let res = CommandResponse::from(map.clone());
+        assert_eq!(res.pairs[0].key, "k1");
+        assert_eq!(HashMap::try_from(&res).unwrap(), map);
+
+        let res = CommandResponse::from(&[true, false][..]);
+        assert_eq!(Vec::<bool>::try_from(&res).unwrap(), [true, false]);
+
+        let res: CommandResponse = KvError::NotFound("t1".into()).into();
+        assert!(Vec::<bool>::try_from(&res).is_err());
+        assert!(HashMap::<String, Value>::try_from(&res).is_err());
+        assert!(SubscriptionAck::try_from(&res).is_err());
+    }
+
+    #[test]
+    fn subscription_ack_should_be_read_from_response() {
+        let res = CommandResponse::subscription_ack(7, "lobby");
+        let ack = SubscriptionAck::try_from(&res).unwrap();
+        assert_eq!((ack.id, ack.topic.as_str()), (7, "lobby"));
+        assert_eq!(i64::try_from(&res).unwrap(), 7);
+
+        // 旧的服务器只在 values[0] 里返回 id
+        let res: CommandResponse = Value::from(8).into();
+        let ack = SubscriptionAck::try_from(&res).unwrap();
+        assert_eq!((ack.id, ack.topic.as_str()), (8, ""));
+    }
+
+    #[test]
+    fn transient_error_should_be_restored_from_response() {
+        let restore = |e: KvError| CommandResponse::from(e).transient_error().unwrap();
+        let e = restore(KvError::Moved(12, "127.0.0.1:9527".into()));
+        assert!(matches!(e, KvError::Moved(12, addr) if addr == "127.0.0.1:9527"));
+        let e = restore(KvError::NotLeader("127.0.0.1:9527".into()));
+        assert!(matches!(e, KvError::NotLeader(leader) if leader == "127.0.0.1:9527"));
+        let e = restore(KvError::Fenced("epoch 2".into()));
+        assert!(matches!(e, KvError::Fenced(reason) if reason == "epoch 2"));
+        let e = restore(KvError::Busy("10 requests in flight".into()));
+        assert!(matches!(e, KvError::Busy(reason) if reason == "10 requests in flight"));
+
+        assert!(CommandResponse::ok().transient_error().is_none());
+        let res: CommandResponse = KvError::PermissionDenied("no".into()).into();
+        assert!(res.transient_error().is_none());
+    }
+}
@@ -0,0 +1,192 @@
+use super::abi::{CommandResponse, Kvpair, Value, value};
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Value 序列化成对应的 JSON 类型：字符串、整数、浮点数、布尔值，没有值时是 null。
+/// 二进制没有对应的 JSON 类型，序列化成 `{"binary": "<base64>"}`，不会被当成字符串
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.value {
+            None => serializer.serialize_none(),
+            Some(value::Value::String(s)) => serializer.serialize_str(s),
+            Some(value::Value::Integer(i)) => serializer.serialize_i64(*i),
+            Some(value::Value::Unsigned(u)) => serializer.serialize_u64(*u),
+            Some(value::Value::Float(f)) => serializer.serialize_f64(*f),
+            Some(value::Value::Bool(b)) => serializer.serialize_bool(*b),
+            Some(value::Value::Binary(b)) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("binary", &base64::encode(b))?;
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a string, number, bool, null or {\"binary\": base64}")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::default())
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::default())
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let Some((key, data)) = map.next_entry::<String, String>()? else {
+            return Err(de::Error::custom("empty map is not a value"));
+        };
+        if key != "binary" || map.next_key::<String>()?.is_some() {
+            return Err(de::Error::custom("expected {\"binary\": base64}"));
+        }
+        let data = base64::decode(data).map_err(de::Error::custom)?;
+        Ok(Value {
+            value: Some(value::Value::Binary(data.into())),
+        })
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct KvpairRepr {
+    key: String,
+    value: Value,
+}
+
+impl Serialize for Kvpair {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        KvpairRepr {
+            key: self.key.clone(),
+            value: self.value.clone().unwrap_or_default(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Kvpair {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = KvpairRepr::deserialize(deserializer)?;
+        Ok(Kvpair::new(repr.key, repr.value))
+    }
+}
+
+/// 和 protobuf 里的字段一一对应，反序列化时没有的字段使用默认值
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct CommandResponseRepr {
+    status: u32,
+    message: String,
+    values: Vec<Value>,
+    pairs: Vec<Kvpair>,
+}
+
+impl Serialize for CommandResponse {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        CommandResponseRepr {
+            status: self.status,
+            message: self.message.clone(),
+            values: self.values.clone(),
+            pairs: self.pairs.clone(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CommandResponse {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = CommandResponseRepr::deserialize(deserializer)?;
+        Ok(CommandResponse {
+            status: repr.status,
+            message: repr.message,
+            values: repr.values,
+            pairs: repr.pairs,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn value_should_map_to_plain_json() {
+        let values: Vec<Value> = vec![
+            "hello".into(),
+            42i64.into(),
+            u64::MAX.into(),
+            1.5.into(),
+            true.into(),
+            Value::default(),
+            b"\x00\xff".into(),
+        ];
+        let json = serde_json::to_value(&values).unwrap();
+        assert_eq!(
+            json,
+            json!(["hello", 42, u64::MAX, 1.5, true, null, {"binary": "AP8="}])
+        );
+        let back: Vec<Value> = serde_json::from_value(json).unwrap();
+        assert_eq!(back, values);
+
+        assert!(serde_json::from_value::<Value>(json!({"text": "x"})).is_err());
+    }
+
+    #[test]
+    fn response_should_round_trip() {
+        let res: CommandResponse = vec![Kvpair::new("k1", "v1".into())].into();
+        let json = serde_json::to_string(&res).unwrap();
+        assert_eq!(
+            json,
+            r#"{"status":200,"message":"","values":[],"pairs":[{"key":"k1","value":"v1"}]}"#
+        );
+        assert_eq!(serde_json::from_str::<CommandResponse>(&json).unwrap(), res);
+
+        // 没有的字段使用默认值
+        let res: CommandResponse = serde_json::from_str(r#"{"status": 404}"#).unwrap();
+        assert_eq!((res.status, res.values.len()), (404, 0));
+    }
+}
@@ -0,0 +1,484 @@
+mod state;
+mod store;
+
+use state::{RaftCore, RaftRole};
+use store::RaftStore;
+
+use crate::{
+    CommandRequest, CommandResponse, KvError, PeerClient, PeerIps, RaftAppend, RaftConfig,
+    RaftVote, Storage, Value, command_request::RequestData,
+};
+use bytes::Bytes;
+use futures::future::join_all;
+use prost::Message;
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{info, warn};
+
+type ExecuteFn = Box<dyn Fn(CommandRequest) -> CommandResponse + Send + Sync>;
+
+/// 集群里的另一个节点
+struct Peer {
+    id: u64,
+    client: PeerClient,
+}
+
+/// 运行 Raft 协议的节点
+///
+/// 写命令追加到 Raft 日志，多数节点复制之后才应用到本地 Storage 并返回；
+/// 读命令走 read index：确认自己仍是 leader 并等本地应用到 read index 之后再读，保证线性一致。
+/// follower 不处理读写命令，返回 leader 的地址让客户端重试。
+/// term、投票和日志在回复其它节点之前写入本地 Storage，见 RaftStore
+pub struct RaftNode {
+    core: Mutex<RaftCore>,
+    store: RaftStore,
+    /// 所有节点的地址，下标是 id - 1
+    addrs: Vec<String>,
+    /// 只接受从这些 IP 发来的 Raft RPC
+    peer_ips: PeerIps,
+    peers: Vec<Peer>,
+    /// 把已提交的命令应用到本地，同时也用来执行读命令
+    execute: ExecuteFn,
+    apply_lock: Mutex<()>,
+    applied: watch::Sender<u64>,
+    // 持有一个 receiver，保证 applied.send 总是成功
+    _applied_rx: watch::Receiver<u64>,
+    /// 等待日志应用的写请求，index -> (term, sender)
+    waiters: Mutex<HashMap<u64, (u64, oneshot::Sender<CommandResponse>)>>,
+    deadline: Mutex<Instant>,
+    notify: Notify,
+    election_timeout: Duration,
+    heartbeat: Duration,
+}
+
+impl RaftNode {
+    /// store 是本节点的 Storage，Raft 的状态也保存在里面，启动时从中恢复
+    pub fn new(
+        config: &RaftConfig,
+        store: Arc<dyn Storage>,
+        execute: impl Fn(CommandRequest) -> CommandResponse + Send + Sync + 'static,
+    ) -> Result<Arc<Self>, KvError> {
+        let mut peers = Vec::new();
+        for (i, addr) in config.peers.iter().enumerate() {
+            let id = i as u64 + 1;
+            if id == config.id {
+                continue;
+            }
+            let client = PeerClient::new(addr, config.client.as_ref())?;
+            peers.push(Peer { id, client });
+        }
+
+        let peer_ips = PeerIps::resolve(&config.peers);
+
+        let ids = peers.iter().map(|p| p.id).collect();
+        let mut core = RaftCore::new(config.id, ids);
+        let store = RaftStore::new(store);
+        store.load(&mut core)?;
+        if core.last_index() > 0 {
+            info!(
+                "Restored raft state: term {}, {} entries, applied {}",
+                core.term,
+                core.last_index(),
+                core.last_applied()
+            );
+        }
+        let (applied, applied_rx) = watch::channel(core.last_applied());
+        let election_timeout = Duration::from_millis(config.election_timeout_ms);
+        let node = Self {
+            core: Mutex::new(core),
+            store,
+            addrs: config.peers.clone(),
+            peer_ips,
+            peers,
+            execute: Box::new(execute),
+            apply_lock: Mutex::new(()),
+            applied,
+            _applied_rx: applied_rx,
+            waiters: Mutex::new(HashMap::new()),
+            deadline: Mutex::new(Instant::now() + election_timeout),
+            notify: Notify::new(),
+            election_timeout,
+            heartbeat: Duration::from_millis(config.heartbeat_interval_ms),
+        };
+        node.reset_deadline();
+        Ok(Arc::new(node))
+    }
+
+    /// 启动选举和心跳的后台任务
+    pub fn start(self: &Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(self.clone().run())
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.core.lock().unwrap().role == RaftRole::Leader
+    }
+
+    /// 当前 leader 的地址
+    pub fn leader(&self) -> Option<String> {
+        let leader = self.core.lock().unwrap().leader?;
+        let index = leader.checked_sub(1)?;
+        self.addrs.get(index as usize).cloned()
+    }
+
+    /// Raft 的 RPC 只接受 raft.peers 里的节点发来的。进程内的调用没有地址，不检查
+    pub fn is_peer(&self, addr: Option<SocketAddr>) -> bool {
+        self.peer_ips.contains(addr)
+    }
+
+    /// 执行读写命令，只有 leader 能处理
+    pub async fn execute(&self, cmd: CommandRequest) -> CommandResponse {
+        if cmd.is_read() {
+            self.read(cmd).await
+        } else {
+            self.propose(cmd).await
+        }
+    }
+
+    pub fn handle_vote(&self, req: &RaftVote) -> CommandResponse {
+        let (term, granted) = {
+            let mut core = self.core.lock().unwrap();
+            let res = core.handle_vote(req);
+            // 投票写下来之后才能回复，重启之后不会在同一个 term 里投给另一个节点
+            if let Err(e) = self.persist(&mut core) {
+                return e.into();
+            }
+            res
+        };
+        if granted {
+            self.reset_deadline();
+        }
+        reply(term, granted)
+    }
+
+    pub fn handle_append(&self, req: &RaftAppend) -> CommandResponse {
+        let (term, success) = {
+            let mut core = self.core.lock().unwrap();
+            let res = core.handle_append(req);
+            if let Err(e) = self.persist(&mut core) {
+                return e.into();
+            }
+            res
+        };
+        // 收到当前 leader 的消息，推迟选举
+        if req.term == term {
+            self.reset_deadline();
+        }
+        self.apply_committed();
+        reply(term, success)
+    }
+
+    async fn run(self: Arc<Self>) {
+        loop {
+            tokio::select! {
+                _ = time::sleep(self.heartbeat) => {}
+                _ = self.notify.notified() => {}
+            }
+
+            if self.is_leader() {
+                self.replicate().await;
+            } else if Instant::now() >= *self.deadline.lock().unwrap() {
+                self.election().await;
+            }
+        }
+    }
+
+    fn reset_deadline(&self) {
+        let ms = self.election_timeout.as_millis() as u64;
+        let jitter = rand::thread_rng().gen_range(0..ms.max(1));
+        *self.deadline.lock().unwrap() =
+            Instant::now() + self.election_timeout + Duration::from_millis(jitter);
+    }
+
+    fn not_leader(&self) -> CommandResponse {
+        let leader = self.leader().unwrap_or_else(|| "unknown".into());
+        KvError::NotLeader(leader).into()
+    }
+
+    async fn election(&self) {
+        let req = {
+            let mut core = self.core.lock().unwrap();
+            let req = core.start_election();
+            if self.persist(&mut core).is_err() {
+                return;
+            }
+            req
+        };
+        info!("Start election for term {}", req.term);
+        self.reset_deadline();
+
+        let cmd = CommandRequest {
+            request_data: Some(RequestData::RaftVote(req.clone())),
+            ..Default::default()
+        };
+        let calls = self.peers.iter().map(|peer| {
+            let cmd = cmd.clone();
+            async move { (peer.id, self.call(peer, cmd).await) }
+        });
+        for (id, res) in join_all(calls).await {
+            let Some((term, granted)) = res.ok().as_ref().and_then(parse_reply) else {
+                continue;
+            };
+            let mut core = self.core.lock().unwrap();
+            if core.on_vote_response(id, req.term, term, granted) {
+                info!("Became leader for term {}", core.term);
+            }
+            let _ = self.persist(&mut core);
+        }
+
+        if self.is_leader() {
+            self.replicate().await;
+        }
+    }
+
+    /// 给所有节点发送 AppendEntries，返回多数节点是否仍然承认自己是 leader
+    async fn replicate(&self) -> bool {
+        let (term, requests) = {
+            let core = self.core.lock().unwrap();
+            if core.role != RaftRole::Leader {
+                return false;
+            }
+            let requests: Vec<_> = self
+                .peers
+                .iter()
+                .map(|peer| core.append_request(peer.id))
+                .collect();
+            (core.term, requests)
+        };
+
+        let calls = self
+            .peers
+            .iter()
+            .zip(requests)
+            .map(|(peer, req)| async move {
+                let cmd = CommandRequest {
+                    request_data: Some(RequestData::RaftAppend(req.clone())),
+                    ..Default::default()
+                };
+                (peer.id, req, self.call(peer, cmd).await)
+            });
+
+        let mut acks = 1;
+        for (id, req, res) in join_all(calls).await {
+            let Some((t, success)) = res.ok().as_ref().and_then(parse_reply) else {
+                continue;
+            };
+            let mut core = self.core.lock().unwrap();
+            core.on_append_response(id, &req, t, success);
+            let _ = self.persist(&mut core);
+            drop(core);
+            if success && t == term {
+                acks += 1;
+            }
+        }
+        self.apply_committed();
+
+        let core = self.core.lock().unwrap();
+        core.role == RaftRole::Leader && core.term == term && acks * 2 > self.addrs.len()
+    }
+
+    async fn propose(&self, cmd: CommandRequest) -> CommandResponse {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut core = self.core.lock().unwrap();
+            let Some(index) = core.propose(Bytes::from(cmd.encode_to_vec())) else {
+                drop(core);
+                return self.not_leader();
+            };
+            if let Err(e) = self.persist(&mut core) {
+                return e.into();
+            }
+            self.waiters.lock().unwrap().insert(index, (core.term, tx));
+        }
+        // 单节点时 propose 之后已经提交，其它情况唤醒后台任务立即复制
+        self.apply_committed();
+        self.notify.notify_one();
+
+        match time::timeout(self.election_timeout * 2, rx).await {
+            Ok(Ok(res)) => res,
+            _ => KvError::Timeout("raft proposal is not committed".into()).into(),
+        }
+    }
+
+    async fn read(&self, cmd: CommandRequest) -> CommandResponse {
+        // 刚当选的 leader 要等空操作提交之后才有 read index
+        let mut read_index = self.core.lock().unwrap().read_index();
+        if read_index.is_none() && self.is_leader() {
+            self.replicate().await;
+            read_index = self.core.lock().unwrap().read_index();
+        }
+        let Some(read_index) = read_index else {
+            return self.not_leader();
+        };
+
+        // 确认多数节点仍然承认自己是 leader，避免被隔离的旧 leader 读到过期数据
+        if !self.replicate().await {
+            return self.not_leader();
+        }
+
+        let mut applied = self.applied.subscribe();
+        while *applied.borrow() < read_index {
+            if applied.changed().await.is_err() {
+                break;
+            }
+        }
+        (self.execute)(cmd)
+    }
+
+    /// 保存 core 里还没持久化的状态，失败的部分在下次保存时重试
+    fn persist(&self, core: &mut RaftCore) -> Result<(), KvError> {
+        self.store
+            .save(core)
+            .inspect_err(|e| warn!("Failed to persist raft state: {}", e))
+    }
+
+    async fn call(&self, peer: &Peer, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        peer.client.call(cmd, self.election_timeout / 2).await
+    }
+
+    /// 按顺序应用已提交的日志，并唤醒等待的写请求
+    fn apply_committed(&self) {
+        let _guard = self.apply_lock.lock().unwrap();
+        let entries = self.core.lock().unwrap().take_committed();
+        for (index, entry) in entries {
+            // 空的 command 是 leader 上任时追加的空操作
+            let res = if entry.command.is_empty() {
+                CommandResponse::ok()
+            } else {
+                match CommandRequest::decode(entry.command.clone()) {
+                    Ok(cmd) => (self.execute)(cmd),
+                    Err(e) => KvError::from(e).into(),
+                }
+            };
+
+            if let Some((term, tx)) = self.waiters.lock().unwrap().remove(&index) {
+                // 这个 index 上提交的是新 leader 的日志，原来的写请求已经丢失
+                let res = if term == entry.term {
+                    res
+                } else {
+                    KvError::NotLeader("leadership changed".into()).into()
+                };
+                let _ = tx.send(res);
+            }
+            if let Err(e) = self.store.save_applied(index) {
+                warn!("Failed to persist raft applied index {}: {}", index, e);
+            }
+            let _ = self.applied.send(index);
+        }
+    }
+}
+
+/// Raft RPC 的响应：[term, 是否成功]
+fn reply(term: u64, success: bool) -> CommandResponse {
+    vec![Value::from(term as i64), Value::from(success)].into()
+}
+
+fn parse_reply(res: &CommandResponse) -> Option<(u64, bool)> {
+    match &res.values[..] {
+        [term, success] => {
+            let term: i64 = term.try_into().ok()?;
+            let success: bool = success.clone().try_into().ok()?;
+            Some((term as u64, success))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn reply_should_be_parsed() {
+        assert_eq!(parse_reply(&reply(3, true)), Some((3, true)));
+        assert_eq!(parse_reply(&CommandResponse::ok()), None);
+    }
+
+    fn single_node_config() -> RaftConfig {
+        RaftConfig {
+            enabled: true,
+            id: 1,
+            peers: vec!["127.0.0.1:9527".into()],
+            election_timeout_ms: 50,
+            heartbeat_interval_ms: 10,
+            client: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn single_node_should_execute_commands() {
+        let config = single_node_config();
+        let store: Arc<dyn Storage> = Arc::new(crate::MemTable::new());
+        let data = Arc::clone(&store);
+        let node = RaftNode::new(&config, store, move |cmd| {
+            crate::dispatch(cmd, data.as_ref())
+        })
+        .unwrap();
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        assert_eq!(node.execute(cmd).await.status, 421);
+
+        node.start();
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(node.is_leader());
+        assert_eq!(node.leader(), Some("127.0.0.1:9527".into()));
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        assert_eq!(node.execute(cmd).await.status, 200);
+        let res = node.execute(CommandRequest::new_hget("t1", "k1")).await;
+        assert_eq!(res.values, &["v1".into()]);
+    }
+
+    #[tokio::test]
+    async fn restarted_node_should_restore_state_without_reapplying() {
+        let config = single_node_config();
+        let store: Arc<dyn Storage> = Arc::new(crate::MemTable::new());
+        let start = |applied: Arc<AtomicUsize>| {
+            let data = Arc::clone(&store);
+            let node = RaftNode::new(&config, Arc::clone(&store), move |cmd| {
+                applied.fetch_add(cmd.modified_keys().is_some() as usize, Ordering::SeqCst);
+                crate::dispatch(cmd, data.as_ref())
+            })
+            .unwrap();
+            let task = node.start();
+            (node, task)
+        };
+
+        let applied = Arc::new(AtomicUsize::new(0));
+        let (node, task) = start(applied.clone());
+        time::sleep(Duration::from_millis(200)).await;
+        for key in ["k1", "k2"] {
+            let cmd = CommandRequest::new_hset("t1", key, "v1".into());
+            assert_eq!(node.execute(cmd).await.status, 200);
+        }
+        assert_eq!(applied.load(Ordering::SeqCst), 2);
+        task.abort();
+        let (term, last_index) = {
+            let core = node.core.lock().unwrap();
+            (core.term, core.last_index())
+        };
+
+        // 重启后恢复 term 和日志，已经应用过的日志不再应用
+        let applied = Arc::new(AtomicUsize::new(0));
+        let (node, _task) = start(applied.clone());
+        {
+            let core = node.core.lock().unwrap();
+            assert_eq!((core.term, core.last_index()), (term, last_index));
+        }
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(node.core.lock().unwrap().term > term);
+        let cmd = CommandRequest::new_hset("t1", "k3", "v1".into());
+        assert_eq!(node.execute(cmd).await.status, 200);
+        assert_eq!(applied.load(Ordering::SeqCst), 1);
+
+        // 不存在的 leader id 不会让 leader() 越界
+        node.core.lock().unwrap().leader = Some(0);
+        assert_eq!(node.leader(), None);
+    }
+}
@@ -0,0 +1,463 @@
+use crate::{RaftAppend, RaftEntry, RaftVote};
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+
+/// 每次 AppendEntries 最多携带的日志条数
+const MAX_ENTRIES_PER_APPEND: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RaftRole {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// 修改之后还没有持久化的状态
+pub struct Unsaved {
+    /// term 或 voted_for 变了时为 Some
+    pub vote: Option<(u64, Option<u64>)>,
+    /// 新追加的和被覆盖的日志
+    pub entries: Vec<(u64, RaftEntry)>,
+    /// 日志被截断后，已经持久化但不再存在的日志
+    pub removed: RangeInclusive<u64>,
+}
+
+/// Raft 的状态机，不涉及网络和定时器，方便单独测试
+///
+/// 持久化由调用者负责：修改之后用 unsaved 取出变化的部分，写完之后调用 mark_saved
+pub struct RaftCore {
+    pub id: u64,
+    peers: Vec<u64>,
+    pub term: u64,
+    voted_for: Option<u64>,
+    /// log[0] 是占位的哨兵，日志 index 从 1 开始
+    log: Vec<RaftEntry>,
+    pub commit_index: u64,
+    last_applied: u64,
+    pub role: RaftRole,
+    pub leader: Option<u64>,
+    votes: HashSet<u64>,
+    next_index: HashMap<u64, u64>,
+    match_index: HashMap<u64, u64>,
+    /// 已经持久化的 (term, voted_for)
+    saved_vote: (u64, Option<u64>),
+    /// 这个 index 及之后的日志还没有持久化
+    unsaved_from: u64,
+    /// 已经持久化的最后一条日志
+    saved_last: u64,
+}
+
+impl RaftCore {
+    /// peers 是其它节点的 id，不包括自己
+    pub fn new(id: u64, peers: Vec<u64>) -> Self {
+        Self {
+            id,
+            peers,
+            term: 0,
+            voted_for: None,
+            log: vec![RaftEntry::default()],
+            commit_index: 0,
+            last_applied: 0,
+            role: RaftRole::Follower,
+            leader: None,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            saved_vote: (0, None),
+            unsaved_from: 1,
+            saved_last: 0,
+        }
+    }
+
+    /// 从持久化的状态恢复，log 不包括哨兵。已经应用的日志一定已经提交
+    pub fn restore(
+        &mut self,
+        term: u64,
+        voted_for: Option<u64>,
+        log: Vec<RaftEntry>,
+        applied: u64,
+    ) {
+        self.term = term;
+        self.voted_for = voted_for;
+        self.log.truncate(1);
+        self.log.extend(log);
+        self.last_applied = applied.min(self.last_index());
+        self.commit_index = self.last_applied;
+        self.mark_saved();
+    }
+
+    /// 上次 mark_saved 之后变化的状态
+    pub fn unsaved(&self) -> Unsaved {
+        let vote = (self.term, self.voted_for);
+        let last = self.last_index();
+        Unsaved {
+            vote: (vote != self.saved_vote).then_some(vote),
+            entries: (self.unsaved_from..=last)
+                .map(|i| (i, self.log[i as usize].clone()))
+                .collect(),
+            removed: last + 1..=self.saved_last,
+        }
+    }
+
+    /// unsaved 返回的状态都已经写完
+    pub fn mark_saved(&mut self) {
+        self.saved_vote = (self.term, self.voted_for);
+        self.saved_last = self.last_index();
+        self.unsaved_from = self.saved_last + 1;
+    }
+
+    pub fn last_applied(&self) -> u64 {
+        self.last_applied
+    }
+
+    pub fn last_index(&self) -> u64 {
+        (self.log.len() - 1) as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log[self.log.len() - 1].term
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        self.log.get(index as usize).map(|e| e.term)
+    }
+
+    fn quorum(&self) -> usize {
+        self.peers.len().div_ceil(2) + 1
+    }
+
+    /// 看到更大的 term 时退回 follower
+    fn observe_term(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.role = RaftRole::Follower;
+            self.leader = None;
+        }
+    }
+
+    /// 处理 RequestVote，返回 (term, 是否投票)
+    pub fn handle_vote(&mut self, req: &RaftVote) -> (u64, bool) {
+        self.observe_term(req.term);
+
+        // 只投给日志至少和自己一样新的 candidate
+        let up_to_date =
+            (req.last_log_term, req.last_log_index) >= (self.last_term(), self.last_index());
+        let granted = req.term == self.term
+            && self.voted_for.is_none_or(|id| id == req.candidate)
+            && up_to_date;
+        if granted {
+            self.voted_for = Some(req.candidate);
+        }
+        (self.term, granted)
+    }
+
+    /// 选举超时，开始新一轮选举，返回要发给其它节点的 RequestVote
+    pub fn start_election(&mut self) -> RaftVote {
+        self.term += 1;
+        self.role = RaftRole::Candidate;
+        self.voted_for = Some(self.id);
+        self.leader = None;
+        self.votes = HashSet::from([self.id]);
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+        }
+
+        RaftVote {
+            term: self.term,
+            candidate: self.id,
+            last_log_index: self.last_index(),
+            last_log_term: self.last_term(),
+        }
+    }
+
+    /// 处理投票结果，成为 leader 时返回 true
+    pub fn on_vote_response(&mut self, from: u64, req_term: u64, term: u64, granted: bool) -> bool {
+        self.observe_term(term);
+        if self.role != RaftRole::Candidate || self.term != req_term || !granted {
+            return false;
+        }
+
+        self.votes.insert(from);
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+            return true;
+        }
+        false
+    }
+
+    fn become_leader(&mut self) {
+        self.role = RaftRole::Leader;
+        self.leader = Some(self.id);
+        let next = self.last_index() + 1;
+        for peer in &self.peers {
+            self.next_index.insert(*peer, next);
+            self.match_index.insert(*peer, 0);
+        }
+        // 上任后先追加一条空操作，提交之后才能确定之前 term 的日志都已提交
+        self.log.push(RaftEntry {
+            term: self.term,
+            command: Bytes::new(),
+        });
+        self.advance_commit();
+    }
+
+    /// leader 追加一条日志，返回日志的 index，不是 leader 时返回 None
+    pub fn propose(&mut self, command: Bytes) -> Option<u64> {
+        if self.role != RaftRole::Leader {
+            return None;
+        }
+        self.log.push(RaftEntry {
+            term: self.term,
+            command,
+        });
+        self.advance_commit();
+        Some(self.last_index())
+    }
+
+    /// 生成发给某个节点的 AppendEntries，同时用作心跳
+    pub fn append_request(&self, peer: u64) -> RaftAppend {
+        let next = self.next_index.get(&peer).copied().unwrap_or(1).max(1);
+        let prev = next - 1;
+        let end = self.log.len().min(next as usize + MAX_ENTRIES_PER_APPEND);
+        RaftAppend {
+            term: self.term,
+            leader: self.id,
+            prev_log_index: prev,
+            prev_log_term: self.term_at(prev).unwrap_or(0),
+            entries: self.log[(next as usize).min(end)..end].to_vec(),
+            leader_commit: self.commit_index,
+        }
+    }
+
+    /// 处理 AppendEntries 的结果
+    pub fn on_append_response(&mut self, peer: u64, req: &RaftAppend, term: u64, success: bool) {
+        self.observe_term(term);
+        if self.role != RaftRole::Leader || self.term != req.term {
+            return;
+        }
+
+        if success {
+            let matched = req.prev_log_index + req.entries.len() as u64;
+            let entry = self.match_index.entry(peer).or_default();
+            *entry = (*entry).max(matched);
+            self.next_index.insert(peer, *entry + 1);
+            self.advance_commit();
+        } else {
+            // 日志对不上，从 prev_log_index 往前退一条再试
+            self.next_index.insert(peer, req.prev_log_index.max(1));
+        }
+    }
+
+    /// 多数节点都已复制、并且属于当前 term 的日志可以提交
+    fn advance_commit(&mut self) {
+        for n in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.term_at(n) != Some(self.term) {
+                break;
+            }
+            let replicated = 1 + self.match_index.values().filter(|m| **m >= n).count();
+            if replicated >= self.quorum() {
+                self.commit_index = n;
+                break;
+            }
+        }
+    }
+
+    /// 处理 AppendEntries，返回 (term, 是否成功)
+    pub fn handle_append(&mut self, req: &RaftAppend) -> (u64, bool) {
+        self.observe_term(req.term);
+        if req.term < self.term {
+            return (self.term, false);
+        }
+        self.role = RaftRole::Follower;
+        self.leader = Some(req.leader);
+
+        if self.term_at(req.prev_log_index) != Some(req.prev_log_term) {
+            return (self.term, false);
+        }
+
+        for (i, entry) in req.entries.iter().enumerate() {
+            let index = req.prev_log_index + 1 + i as u64;
+            match self.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                // 和 leader 冲突的日志以及之后的日志都要删掉
+                Some(_) => {
+                    self.log.truncate(index as usize);
+                    self.unsaved_from = self.unsaved_from.min(index);
+                }
+                None => {}
+            }
+            self.log.push(entry.clone());
+        }
+
+        let last_new = req.prev_log_index + req.entries.len() as u64;
+        // 过期的请求可能只带了一部分日志，commit index 不能往回退
+        let commit = req.leader_commit.min(last_new);
+        if commit > self.commit_index {
+            self.commit_index = commit;
+        }
+        (self.term, true)
+    }
+
+    /// 取出已提交但还没应用的日志
+    pub fn take_committed(&mut self) -> Vec<(u64, RaftEntry)> {
+        let start = self.last_applied + 1;
+        let entries = (start..=self.commit_index)
+            .map(|i| (i, self.log[i as usize].clone()))
+            .collect();
+        self.last_applied = self.commit_index;
+        entries
+    }
+
+    /// 线性一致读的 read index，leader 在当前 term 还没有提交日志时返回 None
+    pub fn read_index(&self) -> Option<u64> {
+        let ready =
+            self.role == RaftRole::Leader && self.term_at(self.commit_index) == Some(self.term);
+        ready.then_some(self.commit_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn elect(leader: &mut RaftCore, followers: &mut [&mut RaftCore]) {
+        let vote = leader.start_election();
+        for f in followers.iter_mut() {
+            let (term, granted) = f.handle_vote(&vote);
+            leader.on_vote_response(f.id, vote.term, term, granted);
+        }
+        assert_eq!(leader.role, RaftRole::Leader);
+    }
+
+    fn replicate(leader: &mut RaftCore, followers: &mut [&mut RaftCore]) {
+        for f in followers.iter_mut() {
+            let req = leader.append_request(f.id);
+            let (term, success) = f.handle_append(&req);
+            leader.on_append_response(f.id, &req, term, success);
+        }
+    }
+
+    #[test]
+    fn single_node_should_commit_immediately() {
+        let mut node = RaftCore::new(1, vec![]);
+        node.start_election();
+        assert_eq!(node.role, RaftRole::Leader);
+
+        let index = node.propose(Bytes::from("cmd")).unwrap();
+        assert_eq!(node.commit_index, index);
+        assert_eq!(node.take_committed().len(), 2);
+        assert_eq!(node.read_index(), Some(index));
+    }
+
+    #[test]
+    fn leader_should_replicate_and_commit() {
+        let mut n1 = RaftCore::new(1, vec![2, 3]);
+        let mut n2 = RaftCore::new(2, vec![1, 3]);
+        let mut n3 = RaftCore::new(3, vec![1, 2]);
+        elect(&mut n1, &mut [&mut n2, &mut n3]);
+        assert!(n1.read_index().is_none());
+
+        let index = n1.propose(Bytes::from("cmd")).unwrap();
+        assert_eq!(n1.commit_index, 0);
+
+        // 只复制到 n2 也能达到多数
+        replicate(&mut n1, &mut [&mut n2]);
+        assert_eq!(n1.commit_index, index);
+        assert_eq!(n1.read_index(), Some(index));
+
+        // 下一次心跳把 commit index 带给 follower
+        replicate(&mut n1, &mut [&mut n2, &mut n3]);
+        assert_eq!(n2.commit_index, index);
+        assert_eq!(n3.commit_index, index);
+        let entries = n3.take_committed();
+        assert_eq!(entries.last().unwrap().1.command, Bytes::from("cmd"));
+    }
+
+    #[test]
+    fn stale_candidate_should_not_get_vote() {
+        let mut n1 = RaftCore::new(1, vec![2, 3]);
+        let mut n2 = RaftCore::new(2, vec![1, 3]);
+        let mut n3 = RaftCore::new(3, vec![1, 2]);
+        elect(&mut n1, &mut [&mut n2, &mut n3]);
+        n1.propose(Bytes::from("cmd")).unwrap();
+        replicate(&mut n1, &mut [&mut n2]);
+
+        // n3 的日志落后，n2 不会投票给它
+        let vote = n3.start_election();
+        let (_, granted) = n2.handle_vote(&vote);
+        assert!(!granted);
+
+        // 同一个 term 里只投一票
+        let vote = n2.start_election();
+        let (_, granted) = n1.handle_vote(&vote);
+        assert!(granted);
+        let vote = RaftVote {
+            candidate: 3,
+            ..vote
+        };
+        let (_, granted) = n1.handle_vote(&vote);
+        assert!(!granted);
+    }
+
+    #[test]
+    fn follower_should_drop_conflicting_entries() {
+        let mut n1 = RaftCore::new(1, vec![2, 3]);
+        let mut n2 = RaftCore::new(2, vec![1, 3]);
+        let mut n3 = RaftCore::new(3, vec![1, 2]);
+        elect(&mut n1, &mut [&mut n2, &mut n3]);
+        replicate(&mut n1, &mut [&mut n2, &mut n3]);
+
+        // n1 在没能复制出去的情况下追加了一条日志
+        n1.propose(Bytes::from("lost")).unwrap();
+
+        // n2 当选，写入新的日志并复制给 n1，n1 的那条日志被覆盖
+        elect(&mut n2, &mut [&mut n3]);
+        n2.propose(Bytes::from("kept")).unwrap();
+        for _ in 0..3 {
+            replicate(&mut n2, &mut [&mut n1, &mut n3]);
+        }
+        assert_eq!(n1.role, RaftRole::Follower);
+        assert_eq!(n1.last_index(), n2.last_index());
+        assert_eq!(n1.log.last().unwrap().command, Bytes::from("kept"));
+        assert_eq!(n1.commit_index, n2.commit_index);
+    }
+
+    #[test]
+    fn unsaved_should_track_changes_since_last_save() {
+        let mut n1 = RaftCore::new(1, vec![2, 3]);
+        let mut n2 = RaftCore::new(2, vec![1, 3]);
+        let mut n3 = RaftCore::new(3, vec![1, 2]);
+        elect(&mut n1, &mut [&mut n2, &mut n3]);
+        let unsaved = n1.unsaved();
+        assert_eq!(unsaved.vote, Some((1, Some(1))));
+        assert_eq!(unsaved.entries.len(), 1);
+        n1.mark_saved();
+        n1.propose(Bytes::from("a")).unwrap();
+        n1.propose(Bytes::from("b")).unwrap();
+        n1.mark_saved();
+        let unsaved = n1.unsaved();
+        assert!(unsaved.vote.is_none() && unsaved.entries.is_empty());
+
+        // n1 的日志都没复制出去，被 n2 的日志覆盖：重写被覆盖的日志，删掉多出来的日志
+        elect(&mut n2, &mut [&mut n3]);
+        for _ in 0..3 {
+            replicate(&mut n2, &mut [&mut n1, &mut n3]);
+        }
+        let unsaved = n1.unsaved();
+        assert_eq!(unsaved.vote, Some((2, None)));
+        let indexes: Vec<_> = unsaved.entries.iter().map(|(i, _)| *i).collect();
+        assert_eq!(indexes, [1]);
+        assert_eq!(unsaved.removed, 2..=3);
+
+        // 从持久化的状态恢复
+        let log = n1.log[1..].to_vec();
+        let mut restored = RaftCore::new(1, vec![2, 3]);
+        restored.restore(2, None, log, 1);
+        assert_eq!(restored.last_index(), n1.last_index());
+        assert_eq!((restored.commit_index, restored.last_applied()), (1, 1));
+        assert!(restored.unsaved().entries.is_empty());
+        assert_eq!(restored.take_committed().len(), 0);
+    }
+}
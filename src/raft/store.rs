@@ -0,0 +1,104 @@
+use super::state::RaftCore;
+use crate::{KvError, RaftEntry, Storage, Value};
+use bytes::Bytes;
+use prost::Message;
+use std::sync::Arc;
+
+/// Raft 的状态保存在本地 Storage 的这个 table 里，是内部 table，客户端不能读写
+const RAFT_TABLE: &str = "__raft__";
+const LOG_PREFIX: &str = "log:";
+
+/// 把 term、投票、日志和已应用的 index 保存到本地 Storage
+///
+/// 和数据放在同一个 Storage 里：Storage 会持久化时，节点重启后接着之前的状态运行，
+/// 已经应用的日志不会再应用一次（应用日志和更新 applied 是两次写入，崩溃在两者之间时会重复应用一条）；
+/// MemTable 重启后数据和 Raft 的状态一起从空开始，由 leader 重新同步
+pub(super) struct RaftStore {
+    store: Arc<dyn Storage>,
+}
+
+impl RaftStore {
+    pub fn new(store: Arc<dyn Storage>) -> Self {
+        Self { store }
+    }
+
+    /// 把之前保存的状态恢复到 core
+    pub fn load(&self, core: &mut RaftCore) -> Result<(), KvError> {
+        let term = self.get_u64("term")?;
+        let voted_for = Some(self.get_u64("voted_for")?).filter(|id| *id > 0);
+        let applied = self.get_u64("applied")?;
+
+        let mut entries = self
+            .store
+            .get_prefix(RAFT_TABLE, LOG_PREFIX)?
+            .map(|pair| {
+                let index = pair.key[LOG_PREFIX.len()..].parse::<u64>();
+                let index = index.map_err(|_| invalid(&pair.key))?;
+                let data: Bytes = pair.value.unwrap_or_default().try_into()?;
+                Ok((index, RaftEntry::decode(data)?))
+            })
+            .collect::<Result<Vec<_>, KvError>>()?;
+        entries.sort_by_key(|(index, _)| *index);
+        // 日志从 1 开始，中间不能有空缺
+        if let Some((index, _)) = entries
+            .iter()
+            .enumerate()
+            .find(|(i, (index, _))| *index != *i as u64 + 1)
+            .map(|(_, entry)| entry)
+        {
+            return Err(invalid(&log_key(*index)));
+        }
+
+        let log = entries.into_iter().map(|(_, entry)| entry).collect();
+        core.restore(term, voted_for, log, applied);
+        Ok(())
+    }
+
+    /// 保存 core 里还没持久化的状态，写完之后才能回复其它节点或者发出请求
+    pub fn save(&self, core: &mut RaftCore) -> Result<(), KvError> {
+        let unsaved = core.unsaved();
+        for (index, entry) in unsaved.entries {
+            let value = Value::from(Bytes::from(entry.encode_to_vec()));
+            self.store.set(RAFT_TABLE, log_key(index), value)?;
+        }
+        for index in unsaved.removed {
+            self.store.del(RAFT_TABLE, &log_key(index))?;
+        }
+        if let Some((term, voted_for)) = unsaved.vote {
+            self.set_u64("term", term)?;
+            self.set_u64("voted_for", voted_for.unwrap_or(0))?;
+        }
+        core.mark_saved();
+        Ok(())
+    }
+
+    /// 应用完一条日志之后记下它的 index
+    pub fn save_applied(&self, index: u64) -> Result<(), KvError> {
+        self.set_u64("applied", index)
+    }
+
+    fn get_u64(&self, key: &str) -> Result<u64, KvError> {
+        match self.store.get(RAFT_TABLE, key)? {
+            Some(value) => {
+                let n: i64 = (&value).try_into()?;
+                Ok(n as u64)
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn set_u64(&self, key: &str, n: u64) -> Result<(), KvError> {
+        self.store
+            .set(RAFT_TABLE, key.into(), Value::from(n as i64))?;
+        Ok(())
+    }
+}
+
+/// 补零让 key 的顺序和 index 的顺序一致
+fn log_key(index: u64) -> String {
+    format!("{}{:020}", LOG_PREFIX, index)
+}
+
+fn invalid(key: &str) -> KvError {
+    KvError::Internal(format!("invalid raft state {}/{}", RAFT_TABLE, key))
+}
@@ -0,0 +1,179 @@
+use crate::{
+    ClientConfig, CommandRequest, CommandResponse, FailoverConfig, KvError, PeerClient,
+    ReplicationConfig, Service, StreamResult, decode_entry, start_client_with_config,
+};
+use futures::StreamExt;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::time;
+use tracing::{info, warn};
+
+/// replica 持续从 primary 拉取复制日志并应用到本地，断开后按间隔重连
+///
+/// 每次连接都先拉取 primary 的快照，这样新加入的 replica 不需要手动拷贝数据目录，
+/// 断开期间错过的写操作也会在重连时补上。
+/// 开启自动故障转移时，同时定期向 primary 确认，联系不上 primary 时把自己提升为 primary。
+/// 被提升为 primary 之后（包括外部协调者发来 PROMOTE）不再复制
+pub async fn run_replica(primary: ClientConfig, service: Service, config: ReplicationConfig) {
+    let addr = primary.general.addr.clone();
+    let interval = Duration::from_millis(config.reconnect_interval_ms);
+    let position = Arc::new(AtomicU64::new(0));
+    if config.failover.enabled {
+        let (service, position) = (service.clone(), position.clone());
+        tokio::spawn(watch_primary(
+            primary.clone(),
+            service,
+            position,
+            config.failover,
+        ));
+    }
+
+    while service.is_read_only() {
+        let res = follow(&primary, &service, &position).await;
+        let at = position.load(Ordering::SeqCst);
+        match res {
+            Ok(()) => warn!("Replication from {} closed at {}", addr, at),
+            Err(e) => warn!("Replication from {} failed at {}: {}", addr, at, e),
+        }
+        time::sleep(interval).await;
+    }
+    info!("Stopped replicating from {}", addr);
+}
+
+/// 每隔 lease_ms / 3 向 primary 确认一次，让 primary 的写租约保持有效
+///
+/// 超过 timeout_ms 没有成功确认时提升为 primary。timeout_ms 比 lease_ms 大，
+/// 这时原来的 primary 即使还活着，写租约也已经过期，不会出现两个 primary 同时写
+async fn watch_primary(
+    primary: ClientConfig,
+    service: Service,
+    position: Arc<AtomicU64>,
+    config: FailoverConfig,
+) {
+    let interval = Duration::from_millis(config.lease_ms / 3).max(Duration::from_millis(1));
+    let timeout = Duration::from_millis(config.timeout_ms);
+    let peer = match PeerClient::new(&primary.general.addr, Some(&primary)) {
+        Ok(peer) => peer,
+        Err(e) => return warn!("Failover disabled: {}", e),
+    };
+
+    let mut last_contact = Instant::now();
+    let mut ticker = time::interval(interval);
+    while service.is_read_only() {
+        ticker.tick().await;
+        let Some(log) = service.replication() else {
+            return;
+        };
+        let cmd = CommandRequest::new_replica_ack(log.epoch(), position.load(Ordering::SeqCst));
+        match peer.call(cmd, interval).await {
+            Ok(res) if res.status == 200 => {
+                last_contact = Instant::now();
+                // 跟上 primary 的 epoch，提升时才能用更大的 epoch
+                let epoch: i64 = (&res).try_into().unwrap_or_default();
+                if epoch as u64 > log.epoch() {
+                    log.set_epoch(epoch as u64);
+                }
+            }
+            Ok(res) => warn!("Primary rejected ack: {}", res.message),
+            Err(e) => warn!("Failed to ack primary {}: {}", peer.addr(), e),
+        }
+
+        if service.is_read_only() && last_contact.elapsed() >= timeout {
+            warn!("Primary {} unreachable, failing over", peer.addr());
+            if let Err(e) = service.promote(0) {
+                warn!("Failed to promote: {}", e);
+            }
+        }
+    }
+}
+
+/// 连接 primary，应用快照和之后的复制日志，直到复制流结束或者被提升为 primary
+async fn follow(
+    primary: &ClientConfig,
+    service: &Service,
+    position: &AtomicU64,
+) -> Result<(), KvError> {
+    let mut ctrl = start_client_with_config(primary).await?;
+    let cmd = CommandRequest::new_replicate(true);
+    let mut stream = ctrl.open_stream().await?.execute_stream(&cmd).await?;
+    info!("Replicating from {}", primary.general.addr);
+
+    // 第一个响应（复制位置）已经被 StreamResult 读掉了，快照结束标记里还有一份
+    let (start, count) = apply_snapshot(&mut stream, service).await?;
+    info!("Applied snapshot at {} with {} keys", start, count);
+    position.store(start, Ordering::SeqCst);
+
+    while let Some(res) = stream.next().await {
+        // 已经提升为 primary，原来的 primary 发来的写操作不再应用
+        if !service.is_read_only() {
+            break;
+        }
+        let (seq, cmd) = decode_entry(&res?)?;
+        let expected = position.load(Ordering::SeqCst) + 1;
+        if seq != expected {
+            warn!("Replication gap: expected {}, got {}", expected, seq);
+        }
+        let res = service.apply_replicated(cmd);
+        if res.status != 200 {
+            warn!("Failed to apply replicated write {}: {}", seq, res.message);
+        }
+        position.store(seq, Ordering::SeqCst);
+    }
+
+    Ok(())
+}
+
+/// 应用快照里的 HMSET，直到快照结束标记，然后删除本地有但快照里没有的 key
+///
+/// 返回快照对应的复制位置和快照里 key 的数量
+async fn apply_snapshot(
+    stream: &mut StreamResult,
+    service: &Service,
+) -> Result<(u64, usize), KvError> {
+    let mut keys = HashSet::new();
+    let position = loop {
+        let res = stream
+            .next()
+            .await
+            .ok_or_else(|| KvError::Internal("replication stream closed".into()))??;
+        check_status(&res)?;
+        // 快照结束标记只有复制位置一个 value
+        if res.values.len() == 1 {
+            let position: i64 = (&res).try_into()?;
+            break position as u64;
+        }
+
+        let (_, cmd) = decode_entry(&res)?;
+        if let Some((table, modified)) = cmd.modified_keys() {
+            for key in modified {
+                keys.insert((table.to_string(), key.to_string()));
+            }
+        }
+        let res = service.apply_replicated(cmd);
+        if res.status != 200 {
+            warn!("Failed to apply snapshot: {}", res.message);
+        }
+    };
+
+    // 断开期间在 primary 上被删掉的 key
+    for table in service.store.tables()? {
+        for pair in service.store.get_all(&table)? {
+            if !keys.contains(&(table.clone(), pair.key.clone())) {
+                service.apply_replicated(CommandRequest::new_hdel(&table, pair.key));
+            }
+        }
+    }
+    Ok((position, keys.len()))
+}
+
+fn check_status(res: &CommandResponse) -> Result<(), KvError> {
+    match res.status {
+        200 => Ok(()),
+        _ => Err(KvError::Internal(format!(
+            "replication failed: {}",
+            res.message
+        ))),
+    }
+}
@@ -1,52 +1,203 @@
 use anyhow::Result;
-use kv::{LogLevel, RotationConfig, ServerConfig, start_server_with_config};
+use anyhow::{anyhow, bail};
+use clap::{Parser, Subcommand};
+use daemonize::Daemonize;
+use kv::{
+    LogFormat, LogLevel, RotationConfig, ServerConfig, ServerControl, StorageConfig,
+    TelemetryConfig, bind_listener, gen_config, gen_keys, start_server_with_control,
+};
 use opentelemetry::{KeyValue, global};
 use opentelemetry_otlp::WithExportConfig;
 use opentelemetry_sdk::propagation::TraceContextPropagator;
-use opentelemetry_sdk::trace::BatchConfig;
+use opentelemetry_sdk::trace::{BatchConfig, Sampler, Tracer};
 use opentelemetry_sdk::{Resource, runtime, trace};
-use std::env;
-use tokio::fs;
+use sd_notify::NotifyState;
+use socket2::SockRef;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::{env, fs};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{Notify, watch};
+use tracing::{info, warn};
 use tracing_subscriber::filter::LevelFilter;
-use tracing_subscriber::fmt::time::LocalTime;
 use tracing_subscriber::fmt::{format, time};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
-use tracing_subscriber::{EnvFilter, Layer, fmt};
+use tracing_subscriber::{EnvFilter, Layer, Registry, fmt, reload};
 
-const OTLP_ENDPOINT: &str = "http://localhost:4317";
+/// 平滑重启时，旧进程通过这两个环境变量把监听的 socket 和通知管道的 fd 传给新进程
+const LISTEN_FD_ENV: &str = "KV_LISTEN_FD";
+const READY_FD_ENV: &str = "KV_READY_FD";
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let config = match env::var("KV_SERVER_CONFIG") {
-        Ok(path) => fs::read_to_string(&path).await?,
-        Err(_) => include_str!("../fixtures/server.conf").to_string(),
-    };
+/// KV 服务器，命令行参数会覆盖配置文件里的值
+#[derive(Debug, Parser)]
+#[command(name = "kvs", version, about)]
+struct Args {
+    /// 配置文件，不指定时使用内置的 fixtures/server.conf
+    #[arg(short, long, env = "KV_SERVER_CONFIG")]
+    config: Option<String>,
+    /// 监听地址，比如 127.0.0.1:9527
+    #[arg(long)]
+    addr: Option<String>,
+    /// 存储：memory、memory-ordered、sled:<path> 或者 cached-sled:<path>
+    #[arg(long)]
+    storage: Option<StorageConfig>,
+    /// 日志级别：trace、debug、info、warn、error
+    #[arg(long)]
+    log_level: Option<LogLevel>,
+    /// 在后台运行，工作目录不变
+    #[arg(long)]
+    daemonize: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-    let config: ServerConfig = toml::from_str(&config)?;
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// 生成 CA、服务器和客户端证书，以及使用这些证书的 server.conf 和 client.conf
+    GenConfig {
+        /// 输出目录
+        #[arg(long, default_value = "fixtures")]
+        dir: PathBuf,
+        /// 配置里的服务器地址
+        #[arg(long, default_value = "127.0.0.1:9527")]
+        addr: String,
+        /// 服务器证书的域名，客户端用它校验服务器
+        #[arg(long, default_value = "localhost")]
+        domain: String,
+        /// 覆盖已经存在的文件
+        #[arg(long)]
+        force: bool,
+    },
+    /// 生成 Noise 协议的服务器和客户端密钥对
+    GenKeys {
+        /// 输出目录
+        #[arg(long, default_value = "fixtures_noise")]
+        dir: PathBuf,
+        /// 覆盖已经存在的文件
+        #[arg(long)]
+        force: bool,
+    },
+}
 
-    let tracer = opentelemetry_otlp::new_pipeline()
-        .tracing()
-        .with_exporter(
-            opentelemetry_otlp::new_exporter()
-                .tonic()
-                .with_endpoint(OTLP_ENDPOINT),
-        )
-        .with_batch_config(BatchConfig::default().with_max_queue_size(9999999))
-        .with_trace_config(
-            trace::config()
-                .with_sampler(trace::Sampler::AlwaysOn)
-                .with_resource(Resource::new(vec![KeyValue::new(
-                    opentelemetry_semantic_conventions::resource::SERVICE_NAME,
-                    "kv_server",
-                )])),
-        )
-        .install_batch(runtime::Tokio)?;
+impl Command {
+    fn run(&self) -> Result<()> {
+        let files = match self {
+            Command::GenConfig {
+                dir,
+                addr,
+                domain,
+                force,
+            } => gen_config(dir, addr, domain, *force)?,
+            Command::GenKeys { dir, force } => gen_keys(dir, *force)?,
+        };
+        for file in files {
+            println!("Generated {}", file.display());
+        }
+        Ok(())
+    }
+}
+
+impl Args {
+    /// 读取配置文件，依次用 KV_* 环境变量和命令行参数覆盖
+    fn load_config(&self) -> Result<ServerConfig> {
+        let config = match &self.config {
+            Some(path) => fs::read_to_string(path)?,
+            None => include_str!("../fixtures/server.conf").to_string(),
+        };
+        let config: ServerConfig = toml::from_str(&config)?;
+        let mut config = config.apply_env()?;
+
+        if let Some(addr) = &self.addr {
+            config.general.addr = addr.clone();
+        }
+        if let Some(storage) = &self.storage {
+            config.storage = storage.clone();
+        }
+        if let Some(log_level) = &self.log_level {
+            config.log.log_level = log_level.clone();
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    if let Some(command) = &args.command {
+        return command.run();
+    }
+    let config = args.load_config()?;
 
-    let opentelemetry = tracing_opentelemetry::layer().with_tracer(tracer).boxed();
+    // 必须在创建 tokio runtime 之前 fork
+    if args.daemonize {
+        Daemonize::new()
+            .working_directory(env::current_dir()?)
+            .start()?;
+    }
+    // 修改环境变量只在单线程时安全，所以在创建 tokio runtime 之前读取
+    let handover = Handover::from_env()?;
+
+    tokio::runtime::Runtime::new()?.block_on(run(args, config, handover))
+}
+
+/// 从旧进程（或者 systemd 的 socket activation）继承的 socket
+struct Handover {
+    listener: Option<TcpListener>,
+    /// 接管 socket 之后写入一个字节，通知旧进程开始退出
+    ready: Option<fs::File>,
+}
+
+impl Handover {
+    fn from_env() -> Result<Self> {
+        let listener = match take_fd(LISTEN_FD_ENV)? {
+            Some(fd) => Some(fd),
+            // SAFETY: systemd 传过来的 fd 只在这里取得所有权
+            None => sd_notify::listen_fds()?
+                .next()
+                .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+        };
+        Ok(Self {
+            listener: listener.map(TcpListener::from),
+            ready: take_fd(READY_FD_ENV)?.map(fs::File::from),
+        })
+    }
+
+    fn notify_ready(&mut self) {
+        if let Some(mut ready) = self.ready.take()
+            && let Err(e) = ready.write_all(b"1")
+        {
+            warn!("Failed to notify the old process: {}", e);
+        }
+    }
+}
+
+/// 取出环境变量里的 fd，并清除环境变量，之后启动的进程不会再继承
+fn take_fd(name: &str) -> Result<Option<OwnedFd>> {
+    let Ok(value) = env::var(name) else {
+        return Ok(None);
+    };
+    // SAFETY: 在创建 tokio runtime 之前调用，这时只有一个线程
+    unsafe { env::remove_var(name) };
+    let fd: RawFd = value
+        .parse()
+        .map_err(|_| anyhow!("invalid {}: {}", name, value))?;
+    // SAFETY: 这个 fd 是旧进程特意留给这个进程的，只在这里取得所有权
+    Ok(Some(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
 
-    global::set_text_map_propagator(TraceContextPropagator::new());
-    global::tracer("kv_server");
+async fn run(args: Args, config: ServerConfig, mut handover: Handover) -> Result<()> {
+    // 没有启用时不创建 exporter，也就不会去连接 collector
+    let opentelemetry = if config.telemetry.enabled {
+        let tracer = init_tracer(&config.telemetry)?;
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        Some(tracing_opentelemetry::layer().with_tracer(tracer).boxed())
+    } else {
+        None
+    };
 
     // 添加
     let log = &config.log;
@@ -58,25 +209,11 @@ async fn main() -> Result<()> {
 
     let (non_blocking, _guard1) = tracing_appender::non_blocking(file_appender);
 
-    let base_env_filter = match &log.log_level {
-        LogLevel::Trace => LevelFilter::TRACE,
-        LogLevel::Debug => LevelFilter::DEBUG,
-        LogLevel::Info => LevelFilter::INFO,
-        LogLevel::Warn => LevelFilter::WARN,
-        LogLevel::Error => LevelFilter::ERROR,
-        LogLevel::Fatal => LevelFilter::ERROR, // fatal 通常映射为 error
-    };
-
-    let env_filter = EnvFilter::from_default_env().add_directive(base_env_filter.into());
-
-    // 针对文件日志的 level 过滤
-    let fmt_layer = fmt::layer()
-        .event_format(format().compact())
-        .with_writer(non_blocking)
-        .and_then(env_filter.clone());
+    // 日志级别可以热加载，所以过滤放在所有 layer 之前，而不是每个 layer 各自过滤
+    let (env_filter, filter_handle) = reload::Layer::new(env_filter(&log.log_level));
 
     // 日志格式 format
-    let format = tracing_subscriber::fmt::format()
+    let console_format = tracing_subscriber::fmt::format()
         .with_thread_ids(true)
         .with_thread_names(true)
         .with_file(true)
@@ -85,30 +222,225 @@ async fn main() -> Result<()> {
         .with_source_location(true)
         .with_target(true)
         // .with_timer(time::ChronoLocal::new("%Y-%m-%d %H:%M:%S %Z".to_string()))
-        .with_timer(time::LocalTime::rfc_3339())
-        .compact();
+        .with_timer(time::LocalTime::rfc_3339());
 
-    // 针对控制台日志的 level 过滤
-    let std_layer = fmt::layer()
-        .event_format(format.clone())
-        .with_writer(std::io::stdout)
-        .and_then(env_filter.clone());
+    let (fmt_layer, std_layer) = match log.format {
+        LogFormat::Text => {
+            let fmt_layer = fmt::layer()
+                .event_format(format().compact())
+                .with_writer(non_blocking)
+                .boxed();
+            let std_layer = fmt::layer()
+                .event_format(console_format.compact())
+                .with_writer(std::io::stdout)
+                .boxed();
+            (fmt_layer, std_layer)
+        }
+        LogFormat::Json => {
+            // 每行一个 JSON 对象，带上当前 span 的字段
+            let fmt_layer = fmt::layer()
+                .event_format(console_format.clone().json().with_current_span(true))
+                .fmt_fields(format::JsonFields::new())
+                .with_writer(non_blocking)
+                .boxed();
+            let std_layer = fmt::layer()
+                .event_format(console_format.json().with_current_span(true))
+                .fmt_fields(format::JsonFields::new())
+                .with_writer(std::io::stdout)
+                .boxed();
+            (fmt_layer, std_layer)
+        }
+    };
 
     // 判断是否启用了日志文件输出
     if log.enable_log_file {
         tracing_subscriber::registry()
+            .with(env_filter)
             .with(opentelemetry)
             .with(std_layer)
             .with(fmt_layer)
             .init();
     } else {
         tracing_subscriber::registry()
+            .with(env_filter)
             .with(opentelemetry)
             .with(std_layer)
             .init();
     }
 
-    start_server_with_config(&config).await?;
+    let listener = match handover.listener.take() {
+        Some(listener) => {
+            info!("Inherited listening socket {}", listener.local_addr()?);
+            listener
+        }
+        None => bind_listener(&config.general.addr, &config.general.socket)?,
+    };
+    handover.notify_ready();
+    let upgraded = Arc::new(Notify::new());
+    tokio::spawn(upgrade_on_sigusr2(listener.try_clone()?, upgraded.clone()));
+
+    let (tx, updates) = watch::channel(config.clone());
+    let reload_requests = Arc::new(Notify::new());
+    tokio::spawn(reload_on_sighup(
+        args,
+        config.clone(),
+        filter_handle,
+        tx,
+        reload_requests.clone(),
+    ));
+    let mut terminate = signal(SignalKind::terminate())?;
+    let control = ServerControl::default()
+        .listener(listener)
+        .reload(updates)
+        .on_reload_request(reload_requests)
+        .shutdown(async move {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = upgraded.notified() => {}
+            }
+            notify_systemd(NotifyState::Stopping);
+        })
+        .on_ready(|_| notify_systemd(NotifyState::Ready));
+    start_server_with_control(&config, control).await?;
+
+    Ok(())
+}
+
+/// 收到 SIGUSR2 时平滑重启：用同样的参数启动新的进程，把监听的 socket 交给它，
+/// 等新进程接管之后，本进程不再接受新连接，处理完已有的请求后退出。
+/// 新进程启动失败时本进程继续运行。管理端口不会交接，开启管理端口时需要设置 socket.reuse_port
+async fn upgrade_on_sigusr2(listener: TcpListener, upgraded: Arc<Notify>) -> Result<()> {
+    let mut usr2 = signal(SignalKind::user_defined2())?;
+    while usr2.recv().await.is_some() {
+        match spawn_successor(&listener).await {
+            Ok(pid) => {
+                info!("Process {} took over the listening socket, draining", pid);
+                upgraded.notify_one();
+                break;
+            }
+            Err(e) => warn!("Failed to restart: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// 启动新的进程并继承 socket，新进程接管之后返回它的 pid
+async fn spawn_successor(listener: &TcpListener) -> Result<u32> {
+    let (mut reader, writer) = io::pipe()?;
+    let listen_fd = inheritable(listener)?;
+    let ready_fd = inheritable(&writer)?;
+    let mut child = std::process::Command::new(env::current_exe()?)
+        .args(env::args_os().skip(1))
+        .env(LISTEN_FD_ENV, listen_fd.as_raw_fd().to_string())
+        .env(READY_FD_ENV, ready_fd.as_raw_fd().to_string())
+        .spawn()?;
+    // 本进程不能留着管道的写端，否则新进程失败退出时读不到 EOF
+    drop((listen_fd, ready_fd, writer));
+
+    let pid = child.id();
+    tokio::task::spawn_blocking(move || {
+        let mut buf = [0; 1];
+        if reader.read(&mut buf)? == 1 {
+            return Ok(pid);
+        }
+        let status = child.wait()?;
+        bail!("new process {} exited before taking over: {}", pid, status)
+    })
+    .await?
+}
+
+/// 复制一个没有 FD_CLOEXEC 的 fd，启动的子进程会继承它
+fn inheritable(fd: &impl AsFd) -> Result<OwnedFd> {
+    let fd = fd.as_fd().try_clone_to_owned()?;
+    // fcntl 对任何 fd 都有效，不只是 socket
+    SockRef::from(&fd).set_cloexec(false)?;
+    Ok(fd)
+}
+
+/// 通知 systemd 服务器的状态，不是由 systemd 启动（没有 NOTIFY_SOCKET）时什么都不做
+fn notify_systemd(state: NotifyState) {
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        warn!("Failed to notify systemd: {}", e);
+    }
+}
 
+/// 收到 SIGHUP 或者 RELOAD 命令时重新读取配置，热加载日志级别、limits 和 TLS 证书，
+/// 已经建立的连接不受影响
+async fn reload_on_sighup(
+    args: Args,
+    config: ServerConfig,
+    filter: reload::Handle<EnvFilter, Registry>,
+    updates: watch::Sender<ServerConfig>,
+    requests: Arc<Notify>,
+) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            signal = hangup.recv() => if signal.is_none() {
+                break;
+            },
+            _ = requests.notified() => {}
+        }
+        let new = match args.load_config() {
+            Ok(new) => new,
+            Err(e) => {
+                warn!("Failed to reload configuration: {}", e);
+                continue;
+            }
+        };
+        // 和启动时的配置比较，没有生效的修改每次 reload 都会提示
+        for section in config.restart_required(&new) {
+            warn!("Changes to [{}] require a restart", section);
+        }
+        if let Err(e) = filter.modify(|f| *f = env_filter(&new.log.log_level)) {
+            warn!("Failed to reload log level: {}", e);
+        }
+        updates.send_replace(new);
+        info!("Configuration reloaded");
+    }
     Ok(())
 }
+
+/// 配置的日志级别，RUST_LOG 里的指令仍然生效
+fn env_filter(level: &LogLevel) -> EnvFilter {
+    let level = match level {
+        LogLevel::Trace => LevelFilter::TRACE,
+        LogLevel::Debug => LevelFilter::DEBUG,
+        LogLevel::Info => LevelFilter::INFO,
+        LogLevel::Warn => LevelFilter::WARN,
+        LogLevel::Error => LevelFilter::ERROR,
+        LogLevel::Fatal => LevelFilter::ERROR, // fatal 通常映射为 error
+    };
+    EnvFilter::from_default_env().add_directive(level.into())
+}
+
+/// 按配置创建 OTLP exporter
+fn init_tracer(telemetry: &TelemetryConfig) -> Result<Tracer> {
+    let sampler = if telemetry.sample_ratio >= 1.0 {
+        Sampler::AlwaysOn
+    } else if telemetry.sample_ratio <= 0.0 {
+        Sampler::AlwaysOff
+    } else {
+        Sampler::TraceIdRatioBased(telemetry.sample_ratio)
+    };
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(&telemetry.endpoint),
+        )
+        .with_batch_config(BatchConfig::default().with_max_queue_size(telemetry.max_queue_size))
+        .with_trace_config(
+            trace::config()
+                .with_sampler(sampler)
+                .with_resource(Resource::new(vec![KeyValue::new(
+                    opentelemetry_semantic_conventions::resource::SERVICE_NAME,
+                    telemetry.service_name.clone(),
+                )])),
+        )
+        .install_batch(runtime::Tokio)?;
+
+    Ok(tracer)
+}
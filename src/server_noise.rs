@@ -1,32 +1,25 @@
 use anyhow::Result;
-use kv::{MemTable, NoiseServerAcceptor, ProstServerStream, Service};
-use tokio::net::TcpListener;
-use tracing::info;
+use kv::{ServerConfig, start_server_with_config};
 
+/// 使用 Noise 协议的 KV 服务器
+///
+/// 设置了 KV_SERVER_CONFIG 时读取该配置文件，否则使用内置的 fixtures_noise/server.conf。
+/// 和 kvs 一样可以用 KV_* 环境变量覆盖配置，比如 KV_NOISE_PATTERN=IK、KV_NOISE_KEY_PATH=server.key，
+/// KV_NOISE_PSK_PATH=noise.psk 启用预共享密钥，客户端要在 [noise] 里配置同一个 psk
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
-    let addr = "127.0.0.1:9527";
 
-    // 加载服务器静态密钥（可选）
-    let server_key = include_str!("../fixtures_noise/server.key");
-    let server_key_bytes = if server_key.is_empty() {
-        None
-    } else {
-        Some(kv::load_key(server_key)?)
+    let config = match std::env::var("KV_SERVER_CONFIG") {
+        Ok(path) => ServerConfig::load(&path)?,
+        Err(_) => {
+            let config: ServerConfig =
+                toml::from_str(include_str!("../fixtures_noise/server.conf"))?;
+            let config = config.apply_env()?;
+            config.validate()?;
+            config
+        }
     };
-
-    let acceptor = NoiseServerAcceptor::new(server_key_bytes)?;
-
-    let service: Service = Service::new(MemTable::new());
-    let listener = TcpListener::bind(addr).await?;
-    info!("Start listening on {}", addr);
-    loop {
-        let noise_acceptor = acceptor.clone();
-        let (stream, addr) = listener.accept().await?;
-        info!("Client {:?} connected", addr);
-        let stream = noise_acceptor.accept(stream).await?;
-        let stream = ProstServerStream::new(stream, service.clone());
-        tokio::spawn(async move { stream.process().await });
-    }
+    // 和 TLS 一样走 serve_stream：连接上跑 yamux，受 limits、超时、连接注册表和认证的约束
+    start_server_with_config(&config).await
 }
@@ -0,0 +1,265 @@
+use super::constant_time_eq;
+use crate::{
+    AccessRole, AuthConfig, CommandRequest, CommandsConfig, KvError, command_request::RequestData,
+};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// 基于角色的访问控制，按连接认证后的身份找到角色，检查角色是否可以执行命令
+#[derive(Debug, Clone, Default)]
+pub struct AccessControl {
+    roles: BTreeMap<String, AccessRole>,
+    default_role: Option<AccessRole>,
+}
+
+impl AccessControl {
+    pub fn new(config: &AuthConfig) -> Self {
+        Self {
+            roles: config.roles.clone(),
+            default_role: config.default_role,
+        }
+    }
+
+    /// 身份对应的角色，没有配置时使用默认角色。为 None 时，配置了 roles 就拒绝所有命令，
+    /// 否则不限制
+    pub fn role(&self, identity: Option<&str>) -> Option<AccessRole> {
+        identity
+            .and_then(|identity| self.roles.get(identity).copied())
+            .or(self.default_role)
+    }
+
+    pub fn check(&self, identity: Option<&str>, cmd: &CommandRequest) -> Result<(), KvError> {
+        match self.role(identity) {
+            Some(role) if !allows(role, cmd) => Err(KvError::PermissionDenied(format!(
+                "role {} can not execute {}",
+                role,
+                cmd.name()
+            ))),
+            // 配置了 roles 却没有 default_role 时，不在 roles 里的连接不能当成不受限制
+            None if !self.roles.is_empty() && !is_public(cmd) => {
+                Err(KvError::PermissionDenied(format!(
+                    "{} has no role",
+                    identity.unwrap_or("connection without client certificate")
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// 按配置关闭命令或者给命令改名，和角色无关，对所有连接生效
+#[derive(Debug, Clone, Default)]
+pub struct CommandFilter {
+    /// 为空时不限制
+    allow: BTreeSet<String>,
+    deny: BTreeSet<String>,
+    /// 命令的名字 -> 请求里要带的新名字
+    rename: BTreeMap<String, String>,
+}
+
+impl CommandFilter {
+    pub fn new(config: &CommandsConfig) -> Self {
+        let names = |names: &[String]| names.iter().map(|n| n.to_ascii_lowercase()).collect();
+        Self {
+            allow: names(&config.allow),
+            deny: names(&config.deny),
+            rename: config
+                .rename
+                .iter()
+                .map(|(name, alias)| (name.to_ascii_lowercase(), alias.clone()))
+                .collect(),
+        }
+    }
+
+    /// 没有关闭任何命令，也没有给命令改名
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty() && self.rename.is_empty()
+    }
+
+    pub fn check(&self, cmd: &CommandRequest) -> Result<(), KvError> {
+        if matches!(cmd.request_data, Some(RequestData::Auth(_))) {
+            return Ok(());
+        }
+        let name = cmd.name();
+        // 和 Redis 一样，用原来的名字执行改了名的命令时当成不认识的命令
+        if let Some(alias) = self.rename.get(name)
+            && (alias.is_empty() || !constant_time_eq(alias.as_bytes(), cmd.alias.as_bytes()))
+        {
+            return Err(KvError::InvalidCommand(format!("unknown command {}", name)));
+        }
+        match self.deny.contains(name) || (!self.allow.is_empty() && !self.allow.contains(name)) {
+            true => Err(KvError::CommandDisabled(name.into())),
+            false => Ok(()),
+        }
+    }
+}
+
+/// 所有连接都可以执行的命令
+fn is_public(cmd: &CommandRequest) -> bool {
+    use RequestData::*;
+    match &cmd.request_data {
+        Some(data) => matches!(data, Auth(_) | Cluster(_) | ClusterSlots(_)),
+        None => true,
+    }
+}
+
+fn allows(role: AccessRole, cmd: &CommandRequest) -> bool {
+    use RequestData::*;
+    let Some(data) = &cmd.request_data else {
+        return true;
+    };
+    if is_public(cmd) {
+        return true;
+    }
+    match role {
+        AccessRole::Admin => true,
+        AccessRole::ReadWrite => {
+            cmd.table().is_some()
+                || matches!(data, Subscribe(_) | Unsubscribe(_) | Publish(_) | Hwatch(_))
+        }
+        AccessRole::ReadOnly => {
+            cmd.is_read() || matches!(data, Subscribe(_) | Unsubscribe(_) | Hwatch(_))
+        }
+        AccessRole::PubSubOnly => matches!(data, Subscribe(_) | Unsubscribe(_) | Publish(_)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_should_limit_commands() {
+        let hget = CommandRequest::new_hget("t1", "k1");
+        let hset = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let publish = CommandRequest::new_publish("chat", vec!["hello".into()]);
+        let latency = CommandRequest::new_latency();
+        let gossip = CommandRequest::new_gossip(vec![]);
+
+        assert!(allows(AccessRole::Admin, &latency));
+        assert!(allows(AccessRole::Admin, &gossip));
+        assert!(allows(AccessRole::ReadWrite, &hset));
+        assert!(allows(AccessRole::ReadWrite, &publish));
+        assert!(!allows(AccessRole::ReadWrite, &latency));
+        assert!(allows(AccessRole::ReadOnly, &hget));
+        assert!(!allows(AccessRole::ReadOnly, &hset));
+        assert!(!allows(AccessRole::ReadOnly, &publish));
+        assert!(allows(AccessRole::PubSubOnly, &publish));
+        assert!(!allows(AccessRole::PubSubOnly, &hget));
+
+        let auth = CommandRequest::new_auth("secret");
+        assert!(allows(AccessRole::PubSubOnly, &auth));
+    }
+
+    #[test]
+    fn identity_should_map_to_role() {
+        let config = AuthConfig {
+            roles: [("alice".to_string(), AccessRole::ReadWrite)].into(),
+            default_role: Some(AccessRole::ReadOnly),
+            ..Default::default()
+        };
+        let access = AccessControl::new(&config);
+        assert_eq!(access.role(Some("alice")), Some(AccessRole::ReadWrite));
+        assert_eq!(access.role(Some("bob")), Some(AccessRole::ReadOnly));
+        assert_eq!(access.role(None), Some(AccessRole::ReadOnly));
+
+        let hset = CommandRequest::new_hset("t1", "k1", "v1".into());
+        assert!(access.check(Some("alice"), &hset).is_ok());
+        let err = access.check(None, &hset).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permission denied: role read-only can not execute hset"
+        );
+
+        // 没有配置角色时不受限制
+        let access = AccessControl::new(&AuthConfig::default());
+        assert!(access.check(None, &CommandRequest::new_latency()).is_ok());
+    }
+
+    #[test]
+    fn unmapped_identity_should_be_denied_without_default_role() {
+        let config = AuthConfig {
+            roles: [("alice".to_string(), AccessRole::Admin)].into(),
+            ..Default::default()
+        };
+        let access = AccessControl::new(&config);
+        let hget = CommandRequest::new_hget("t1", "k1");
+        assert!(access.check(Some("alice"), &hget).is_ok());
+
+        // 没有客户端证书的连接和不在 roles 里的身份都不能执行命令
+        let err = access.check(None, &hget).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permission denied: connection without client certificate has no role"
+        );
+        assert!(access.check(Some("bob"), &hget).is_err());
+        assert!(
+            access
+                .check(None, &CommandRequest::new_flushall(""))
+                .is_err()
+        );
+        assert!(
+            access
+                .check(None, &CommandRequest::new_auth("secret"))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn filter_should_disable_commands() {
+        let hget = CommandRequest::new_hget("t1", "k1");
+        let hgetall = CommandRequest::new_hgetall("t1");
+        let hset = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let auth = CommandRequest::new_auth("secret");
+
+        let filter = CommandFilter::new(&CommandsConfig {
+            deny: vec!["HGETALL".into()],
+            ..Default::default()
+        });
+        assert!(filter.check(&hget).is_ok());
+        let err = filter.check(&hgetall).unwrap_err();
+        assert_eq!(err.to_string(), "Command disabled: hgetall");
+
+        // deny 优先于 allow，AUTH 总是可以执行
+        let filter = CommandFilter::new(&CommandsConfig {
+            allow: vec!["hget".into(), "hgetall".into()],
+            deny: vec!["hgetall".into()],
+            ..Default::default()
+        });
+        assert!(filter.check(&hget).is_ok());
+        assert!(filter.check(&hgetall).is_err());
+        assert!(filter.check(&hset).is_err());
+        assert!(filter.check(&auth).is_ok());
+
+        assert!(CommandFilter::new(&CommandsConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn renamed_commands_should_require_alias() {
+        let filter = CommandFilter::new(&CommandsConfig {
+            rename: [
+                ("PROMOTE".to_string(), "promote-7f3a".to_string()),
+                ("client_kill".to_string(), String::new()),
+            ]
+            .into(),
+            ..Default::default()
+        });
+        let promote = CommandRequest::new_promote(1);
+        let err = filter.check(&promote).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Command is invalid: `unknown command promote`"
+        );
+        assert!(
+            filter
+                .check(&promote.clone().with_alias("promote"))
+                .is_err()
+        );
+        assert!(filter.check(&promote.with_alias("promote-7f3a")).is_ok());
+
+        // 新名字为空时不能执行
+        let kill = CommandRequest::new_client_kill(1);
+        assert!(filter.check(&kill.clone().with_alias("")).is_err());
+        // 没有改名的命令不需要带新名字
+        assert!(filter.check(&CommandRequest::new_latency()).is_ok());
+    }
+}
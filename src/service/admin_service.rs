@@ -0,0 +1,382 @@
+use crate::{
+    ClientKill, ClientList, Cluster, ClusterSlots, CommandResponse, CrdtSync, Flushall, Gossip,
+    Hdebug, Jobs, KvError, Kvpair, Latency, Promote, Reload, ReplicaAck, Service, Value, key_slot,
+};
+use prost::Message;
+use std::net::SocketAddr;
+use tracing::warn;
+
+/// 管理类命令，操作的是服务器自身的状态而不是 Storage
+pub trait AdminService {
+    fn execute(self, svc: &Service) -> CommandResponse;
+}
+
+impl AdminService for ClientList {
+    fn execute(self, svc: &Service) -> CommandResponse {
+        // 每个连接一行，格式和 redis CLIENT LIST 类似
+        svc.connections()
+            .list()
+            .into_iter()
+            .map(|info| Value::from(info.to_string()))
+            .collect::<Vec<_>>()
+            .into()
+    }
+}
+
+impl AdminService for ClientKill {
+    fn execute(self, svc: &Service) -> CommandResponse {
+        let conns = svc.connections();
+        let killed = if self.id != 0 {
+            conns.kill(|conn| conn.id == self.id)
+        } else {
+            match self.addr.parse::<SocketAddr>() {
+                Ok(addr) => conns.kill(|conn| conn.peer_addr == addr),
+                Err(_) => {
+                    return KvError::InvalidCommand(format!("invalid addr: {}", self.addr)).into();
+                }
+            }
+        };
+
+        match killed {
+            0 => KvError::NotFound("no such client".into()).into(),
+            n => Value::from(n as i64).into(),
+        }
+    }
+}
+
+impl AdminService for Latency {
+    fn execute(self, svc: &Service) -> CommandResponse {
+        // 每种命令一行，延迟单位是微秒
+        svc.latency_stats()
+            .into_iter()
+            .map(|stats| Value::from(stats.to_string()))
+            .collect::<Vec<_>>()
+            .into()
+    }
+}
+
+impl AdminService for Jobs {
+    fn execute(self, svc: &Service) -> CommandResponse {
+        // 每个 job 一行，按注册顺序
+        svc.scheduler()
+            .jobs()
+            .into_iter()
+            .map(|info| Value::from(info.to_string()))
+            .collect::<Vec<_>>()
+            .into()
+    }
+}
+
+impl AdminService for Reload {
+    fn execute(self, svc: &Service) -> CommandResponse {
+        // 只是发出通知，配置是否加载成功见服务器的日志
+        match svc.reload_trigger() {
+            Some(notify) => {
+                notify.notify_one();
+                CommandResponse::ok()
+            }
+            None => KvError::InvalidCommand("reload is not supported by this server".into()).into(),
+        }
+    }
+}
+
+impl AdminService for Flushall {
+    fn execute(self, svc: &Service) -> CommandResponse {
+        if !self.is_confirmed() {
+            let msg = format!("flushall requires confirmation {:?}", Flushall::CONFIRM);
+            return KvError::InvalidCommand(msg).into();
+        }
+        if svc.is_read_only() {
+            return KvError::PermissionDenied("replica is read-only".into()).into();
+        }
+        let res = svc.flushall();
+        if res.status == 200 {
+            warn!("Flushed all tables: {:?}", res.values);
+        }
+        res
+    }
+}
+
+impl AdminService for ClusterSlots {
+    fn execute(self, svc: &Service) -> CommandResponse {
+        match svc.slot_map() {
+            Some(map) => map.to_response(),
+            None => KvError::InvalidCommand("sharding is not enabled".into()).into(),
+        }
+    }
+}
+
+impl AdminService for Cluster {
+    fn execute(self, svc: &Service) -> CommandResponse {
+        // 每个成员一行，按地址排序
+        match svc.membership() {
+            Some(membership) => membership
+                .members()
+                .into_iter()
+                .map(|info| Value::from(info.to_string()))
+                .collect::<Vec<_>>()
+                .into(),
+            None => KvError::InvalidCommand("cluster membership is not enabled".into()).into(),
+        }
+    }
+}
+
+impl AdminService for Gossip {
+    fn execute(self, svc: &Service) -> CommandResponse {
+        match svc.membership() {
+            Some(membership) => membership.handle_gossip(&self.members),
+            None => KvError::InvalidCommand("cluster membership is not enabled".into()).into(),
+        }
+    }
+}
+
+impl AdminService for ReplicaAck {
+    fn execute(self, svc: &Service) -> CommandResponse {
+        match svc.replication() {
+            // replica 已经见过更新的 epoch，说明本节点不再是 primary，不能续租
+            Some(log) if self.epoch > log.epoch() => KvError::Fenced(format!(
+                "epoch {} is newer than {}",
+                self.epoch,
+                log.epoch()
+            ))
+            .into(),
+            Some(log) if !svc.is_read_only() => Value::from(log.ack() as i64).into(),
+            _ => KvError::InvalidCommand("node is not primary".into()).into(),
+        }
+    }
+}
+
+impl AdminService for Promote {
+    fn execute(self, svc: &Service) -> CommandResponse {
+        match svc.promote(self.epoch) {
+            Ok(epoch) => Value::from(epoch as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl AdminService for CrdtSync {
+    fn execute(self, svc: &Service) -> CommandResponse {
+        let Some(mm) = svc.multi_master() else {
+            return KvError::InvalidCommand("multi-master is not enabled".into()).into();
+        };
+        // 同步过来的写操作不经过 execute_with，在这里检查大小
+        for entry in &self.entries {
+            if let Err(e) = svc.check_entry_size(&entry.table, &entry.key, entry.value.as_ref()) {
+                return e.into();
+            }
+        }
+        mm.handle_sync(self, |cmd| svc.apply_replicated(cmd))
+    }
+}
+
+impl AdminService for Hdebug {
+    fn execute(self, svc: &Service) -> CommandResponse {
+        let value = match svc.store.get(&self.table, &self.key) {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                return KvError::NotFound(format!("table {},key {}", self.table, self.key)).into();
+            }
+            Err(e) => return e.into(),
+        };
+        let slot = key_slot(&self.table);
+        let mut pairs = vec![
+            Kvpair::new("encoding", value.type_name().into()),
+            Kvpair::new("serialized_length", (value.encoded_len() as i64).into()),
+            Kvpair::new("storage", svc.store.name().into()),
+            Kvpair::new("slot", (slot as i64).into()),
+            // 还没有按 key 过期的功能
+            Kvpair::new("ttl", (-1).into()),
+        ];
+        if let Some(node) = svc.slot_map().and_then(|map| map.owner(slot)) {
+            pairs.push(Kvpair::new("node", node.into()));
+        }
+        if let Some(mm) = svc.multi_master() {
+            match mm.version(&self.table, &self.key) {
+                Ok(Some(version)) => {
+                    let clock: Vec<_> = version
+                        .clock
+                        .iter()
+                        .map(|dot| format!("{}:{}", dot.node, dot.counter))
+                        .collect();
+                    pairs.push(Kvpair::new("last_write", version.timestamp.into()));
+                    pairs.push(Kvpair::new("writer", version.node.into()));
+                    pairs.push(Kvpair::new("clock", clock.join(",").into()));
+                }
+                Ok(None) => {}
+                Err(e) => return e.into(),
+            }
+        }
+        pairs.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandRequest, MemTable, dispatch_admin};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::Notify;
+
+    #[test]
+    fn client_list_should_work() {
+        let svc = Service::new(MemTable::new());
+        let addr: SocketAddr = "127.0.0.1:9527".parse().unwrap();
+        let _conn1 = svc.connections().register(addr);
+        let _conn2 = svc.connections().register(addr);
+
+        let res = dispatch_admin(CommandRequest::new_client_list(), &svc).unwrap();
+        assert_eq!(res.status, 200);
+        assert_eq!(res.values.len(), 2);
+        assert!(res.values[0].format().contains("addr=127.0.0.1:9527"));
+    }
+
+    #[test]
+    fn client_kill_should_work() {
+        let svc = Service::new(MemTable::new());
+        let conn1 = svc
+            .connections()
+            .register("127.0.0.1:9527".parse().unwrap());
+        let conn2 = svc
+            .connections()
+            .register("127.0.0.1:9528".parse().unwrap());
+
+        let res = dispatch_admin(CommandRequest::new_client_kill(conn1.stats().id), &svc);
+        assert_eq!(res.unwrap().values, &[1.into()]);
+        assert!(conn1.stats().is_killed());
+        assert!(!conn2.stats().is_killed());
+
+        let cmd = CommandRequest::new_client_kill_addr("127.0.0.1:9528");
+        let res = dispatch_admin(cmd, &svc).unwrap();
+        assert_eq!(res.values, &[1.into()]);
+        assert!(conn2.stats().is_killed());
+
+        let cmd = CommandRequest::new_client_kill_addr("127.0.0.1:1");
+        assert_eq!(dispatch_admin(cmd, &svc).unwrap().status, 404);
+
+        let cmd = CommandRequest::new_client_kill_addr("bad addr");
+        assert_eq!(dispatch_admin(cmd, &svc).unwrap().status, 400);
+    }
+
+    #[tokio::test]
+    async fn latency_should_report_executed_commands() {
+        let svc = Service::new(MemTable::new());
+        let _ = svc.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let _ = svc.execute(CommandRequest::new_hget("t1", "k1"));
+        let _ = svc.execute(CommandRequest::new_hget("t1", "k1"));
+
+        let res = dispatch_admin(CommandRequest::new_latency(), &svc).unwrap();
+        assert_eq!(res.status, 200);
+        assert_eq!(res.values.len(), 2);
+        assert!(res.values[0].format().contains("cmd=hget count=2"));
+        assert!(res.values[1].format().contains("cmd=hset count=1"));
+    }
+
+    #[tokio::test]
+    async fn hdebug_should_describe_stored_value() {
+        let svc = Service::new(MemTable::new());
+        let cmd = CommandRequest::new_hdebug("t1", "k1");
+        assert_eq!(dispatch_admin(cmd.clone(), &svc).unwrap().status, 404);
+
+        let _ = svc.execute(CommandRequest::new_hset("t1", "k1", "hello".into()));
+        let res = dispatch_admin(cmd, &svc).unwrap();
+        assert_eq!(
+            res.pairs,
+            vec![
+                Kvpair::new("encoding", "string".into()),
+                Kvpair::new("serialized_length", 7.into()),
+                Kvpair::new("storage", "memory".into()),
+                Kvpair::new("slot", (key_slot("t1") as i64).into()),
+                Kvpair::new("ttl", (-1).into()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn jobs_should_list_scheduled_jobs() {
+        let svc = Service::new(MemTable::new());
+        svc.scheduler()
+            .spawn("noop", std::time::Duration::from_secs(60), || async {
+                Ok(())
+            });
+
+        let res = dispatch_admin(CommandRequest::new_jobs(), &svc).unwrap();
+        assert_eq!(res.status, 200);
+        assert_eq!(res.values.len(), 1);
+        assert!(
+            res.values[0]
+                .format()
+                .contains("name=noop interval=60000ms runs=0 failures=0")
+        );
+    }
+
+    #[tokio::test]
+    async fn reload_should_notify_server() {
+        let svc = Service::new(MemTable::new());
+        let res = dispatch_admin(CommandRequest::new_reload(), &svc).unwrap();
+        assert_eq!(res.status, 400);
+
+        let notify = Arc::new(Notify::new());
+        let svc = svc.with_reload_trigger(notify.clone());
+        let res = dispatch_admin(CommandRequest::new_reload(), &svc).unwrap();
+        assert_eq!(res.status, 200);
+        // 通知在等待之前发出也不会丢
+        tokio::time::timeout(Duration::from_secs(1), notify.notified())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn flushall_should_require_confirmation() {
+        let svc = Service::new(MemTable::new());
+        svc.store.set("t1", "k1".into(), "v1".into()).unwrap();
+        svc.store.set("t2", "k1".into(), "v1".into()).unwrap();
+
+        let res = dispatch_admin(CommandRequest::new_flushall("yes"), &svc).unwrap();
+        assert_eq!(res.status, 400);
+        assert!(svc.store.contains("t1", "k1").unwrap());
+
+        let cmd = CommandRequest::new_flushall(Flushall::CONFIRM);
+        let res = dispatch_admin(cmd.clone(), &svc).unwrap();
+        assert_eq!(res.values, vec![Value::from(2)]);
+        assert!(!svc.store.contains("t1", "k1").unwrap());
+        assert!(!svc.store.contains("t2", "k1").unwrap());
+
+        let svc = svc.read_only();
+        let res = dispatch_admin(cmd, &svc).unwrap();
+        assert_eq!(res.status, 403);
+    }
+
+    #[test]
+    fn cluster_should_list_members() {
+        let svc = Service::new(MemTable::new());
+        let res = dispatch_admin(CommandRequest::new_cluster(), &svc).unwrap();
+        assert_eq!(res.status, 400);
+
+        let mut config = crate::ServerConfig::builder().build().unwrap();
+        config.membership.enabled = true;
+        config.membership.seeds = vec!["127.0.0.1:9528".into()];
+        let svc = svc.with_membership(crate::Membership::new(&config));
+
+        let res = dispatch_admin(CommandRequest::new_cluster(), &svc).unwrap();
+        assert_eq!(res.values.len(), 2);
+        assert!(res.values[0].format().contains("status=up"));
+        assert!(res.values[1].format().contains("status=unknown"));
+    }
+
+    #[test]
+    fn replica_ack_should_renew_primary_lease() {
+        let log = crate::ReplicationLog::new(16).with_lease(std::time::Duration::from_secs(10));
+        let svc = Service::new(MemTable::new()).with_replication(log);
+        assert!(svc.replication().unwrap().check_lease().is_err());
+
+        let res = dispatch_admin(CommandRequest::new_replica_ack(0, 0), &svc).unwrap();
+        assert_eq!(res.values, &[0.into()]);
+        assert!(svc.replication().unwrap().check_lease().is_ok());
+
+        // replica 已经见过更新的 epoch
+        let res = dispatch_admin(CommandRequest::new_replica_ack(1, 0), &svc).unwrap();
+        assert_eq!(res.status, 503);
+    }
+}
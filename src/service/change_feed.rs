@@ -0,0 +1,306 @@
+use crate::{
+    ChangeEvent, ChangeOp, CommandRequest, CommandResponse, KvError, Storage, StreamingResponse,
+    Value, command_request::RequestData, unary,
+};
+use bytes::Bytes;
+use prost::Message;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::warn;
+
+/// 每个 watcher 最多积压的变更
+const WATCH_CAPACITY: usize = 1024;
+
+struct Watcher {
+    id: u32,
+    /// 为 None 时接收所有 table 的变更
+    table: Option<String>,
+    tx: mpsc::Sender<Arc<CommandResponse>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    seq: u64,
+    next_id: u32,
+    watchers: Vec<Watcher>,
+}
+
+/// 变更流（change data capture），HWATCH 的 watcher 按顺序收到每个 key 的变更
+///
+/// 有 watcher 时写操作在锁里执行，写之前和写之后各读一次修改的 key，得到旧值和新值，
+/// 所以 watcher 收到的变更顺序和写操作的实际顺序一致。没有 watcher 时不加锁，也不额外读取。
+/// 变更直接发到 watcher 的队列里，不经过 pub/sub 的 Broadcaster；跟不上的 watcher 会被断开，
+/// 需要重新 HWATCH
+///
+/// 带快照的 HWATCH 需要和所有写操作互斥，包括没有 watcher 时不加锁的写操作。
+/// 写操作执行期间持有 gate 的读锁，彼此之间不互斥；读快照时持有 gate 的写锁，等正在执行的写操作完成
+#[derive(Default)]
+pub struct ChangeFeed {
+    gate: RwLock<()>,
+    inner: Mutex<Inner>,
+}
+
+impl ChangeFeed {
+    /// 订阅 table 的变更，table 为空时订阅所有 table。第一个响应是 watch id。
+    /// 传入 snapshot 时用它读出 table 当前的数据作为第二个响应，之后的变更都发生在快照之后；
+    /// 快照读取失败（比如超过 max_result_entries）时只返回这个错误，不会订阅
+    pub fn watch(
+        &self,
+        table: String,
+        snapshot: Option<&dyn Fn(&str) -> CommandResponse>,
+    ) -> StreamingResponse {
+        if snapshot.is_some() && table.is_empty() {
+            let res: CommandResponse =
+                KvError::InvalidCommand("hwatch snapshot requires a table".into()).into();
+            return unary(res);
+        }
+        let _gate = snapshot.map(|_| self.gate.write().unwrap());
+        let snapshot = snapshot.map(|read| read(&table));
+        if let Some(res) = snapshot.as_ref().filter(|res| res.status != 200) {
+            return unary(res.clone());
+        }
+        let (tx, rx) = mpsc::channel(WATCH_CAPACITY);
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        // 队列是空的，不会失败
+        let _ = tx.try_send(Arc::new(CommandResponse::subscription_ack(id, &table)));
+        if let Some(res) = snapshot {
+            let _ = tx.try_send(Arc::new(res));
+        }
+        let table = (!table.is_empty()).then_some(table);
+        inner.watchers.push(Watcher { id, table, tx });
+        Box::pin(ReceiverStream::new(rx))
+    }
+
+    /// 执行写命令，成功后把修改的 key 的变更发给 watcher
+    pub fn apply(
+        &self,
+        cmd: CommandRequest,
+        store: &dyn Storage,
+        f: impl FnOnce(CommandRequest) -> CommandResponse,
+    ) -> CommandResponse {
+        let Some((table, keys)) = cmd.modified_keys() else {
+            return f(cmd);
+        };
+        let _gate = self.gate.read().unwrap();
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.watches(table) {
+            drop(inner);
+            return f(cmd);
+        }
+
+        // 有 watcher 的 table 才需要复制 key，请求本身交给 f
+        let table = table.to_string();
+        let keys: Vec<String> = match &cmd.request_data {
+            // HDELPREFIX 删除的 key 要在执行之前读出来
+            Some(RequestData::Hdelprefix(param)) => match store.get_prefix(&table, &param.prefix) {
+                Ok(iter) => iter.map(|pair| pair.key).collect(),
+                Err(e) => return e.into(),
+            },
+            _ => keys.into_iter().map(String::from).collect(),
+        };
+        let old: Vec<_> = keys.iter().map(|key| store.get(&table, key)).collect();
+        let res = f(cmd);
+        if res.status != 200 {
+            return res;
+        }
+        for (key, old) in keys.into_iter().zip(old) {
+            let (old_value, new_value) = match (old, store.get(&table, &key)) {
+                (Ok(old), Ok(new)) => (old, new),
+                (Err(e), _) | (_, Err(e)) => {
+                    warn!("Failed to capture change of {}/{}: {}", table, key, e);
+                    continue;
+                }
+            };
+            // 删除不存在的 key 不算变更
+            if old_value.is_none() && new_value.is_none() {
+                continue;
+            }
+            inner.seq += 1;
+            let op = match new_value {
+                Some(_) => ChangeOp::Set,
+                None => ChangeOp::Del,
+            };
+            let event = ChangeEvent {
+                seq: inner.seq,
+                op: op as i32,
+                table: table.clone(),
+                key,
+                old_value,
+                new_value,
+            };
+            inner.send(event);
+        }
+        res
+    }
+}
+
+impl Inner {
+    fn watches(&self, table: &str) -> bool {
+        self.watchers
+            .iter()
+            .any(|w| w.table.as_deref().is_none_or(|t| t == table))
+    }
+
+    /// 发给关心这个 table 的 watcher，断开的和跟不上的 watcher 被移除
+    fn send(&mut self, event: ChangeEvent) {
+        let res = Arc::new(Value::from(Bytes::from(event.encode_to_vec())).into());
+        self.watchers.retain(|w| {
+            if w.table.as_deref().is_some_and(|t| t != event.table) {
+                return !w.tx.is_closed();
+            }
+            match w.tx.try_send(Arc::clone(&res)) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    warn!("Watcher {} is lagging, disconnected", w.id);
+                    false
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            }
+        });
+    }
+}
+
+/// 从 HWATCH 的响应里解出变更
+pub fn decode_change(res: &CommandResponse) -> Result<ChangeEvent, KvError> {
+    match (res.status, res.values.first()) {
+        (200, Some(value)) => {
+            let data: Bytes = value.clone().try_into()?;
+            Ok(ChangeEvent::decode(data)?)
+        }
+        _ => Err(KvError::Internal(format!(
+            "invalid change: {}",
+            res.message
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Kvpair, MemTable, dispatch};
+    use futures::StreamExt;
+
+    fn write(feed: &ChangeFeed, store: &MemTable, cmd: CommandRequest) {
+        let res = feed.apply(cmd, store, |cmd| dispatch(cmd, store));
+        assert_eq!(res.status, 200);
+    }
+
+    #[tokio::test]
+    async fn watcher_should_receive_ordered_changes() {
+        let feed = ChangeFeed::default();
+        let store = MemTable::new();
+        let mut all = feed.watch("".into(), None);
+        let mut t2 = feed.watch("t2".into(), None);
+        assert_eq!(all.next().await.unwrap().values, &[1.into()]);
+        assert_eq!(t2.next().await.unwrap().values, &[2.into()]);
+
+        write(
+            &feed,
+            &store,
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+        );
+        write(
+            &feed,
+            &store,
+            CommandRequest::new_hset("t1", "k1", "v2".into()),
+        );
+        write(&feed, &store, CommandRequest::new_hdel("t1", "k1"));
+        write(
+            &feed,
+            &store,
+            CommandRequest::new_hset("t2", "k1", "v1".into()),
+        );
+
+        let events: Vec<_> = all
+            .take(4)
+            .map(|res| decode_change(&res).unwrap())
+            .collect()
+            .await;
+        assert_eq!(events[0].op(), ChangeOp::Set);
+        assert_eq!(events[0].old_value, None);
+        assert_eq!(events[1].old_value, Some("v1".into()));
+        assert_eq!(events[1].new_value, Some("v2".into()));
+        assert_eq!(events[2].op(), ChangeOp::Del);
+        assert_eq!(events[2].new_value, None);
+        assert_eq!(
+            events.iter().map(|e| e.seq).collect::<Vec<_>>(),
+            [1, 2, 3, 4]
+        );
+
+        // 只订阅 t2 的 watcher 只收到 t2 的变更
+        let event = decode_change(&t2.next().await.unwrap()).unwrap();
+        assert_eq!((event.seq, event.table.as_str()), (4, "t2"));
+    }
+
+    #[tokio::test]
+    async fn hdelprefix_should_produce_del_for_each_key() {
+        let feed = ChangeFeed::default();
+        let store = MemTable::new();
+        let mut all = feed.watch("".into(), None);
+        all.next().await.unwrap();
+        for key in ["user:1", "user:2", "order:1"] {
+            store.set("t1", key.into(), "v".into()).unwrap();
+        }
+
+        write(&feed, &store, CommandRequest::new_hdelprefix("t1", "user:"));
+        let events: Vec<_> = all
+            .take(2)
+            .map(|res| decode_change(&res).unwrap())
+            .collect()
+            .await;
+        let keys: Vec<_> = events.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(keys, ["user:1", "user:2"]);
+        assert!(events.iter().all(|e| e.op() == ChangeOp::Del));
+    }
+
+    #[test]
+    fn closed_watcher_should_be_removed() {
+        let feed = ChangeFeed::default();
+        let store = MemTable::new();
+        drop(feed.watch("".into(), None));
+        write(
+            &feed,
+            &store,
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+        );
+        assert!(feed.inner.lock().unwrap().watchers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn snapshot_should_be_followed_by_later_changes_only() {
+        let feed = ChangeFeed::default();
+        let store = MemTable::new();
+        // 没有 watcher 时的写操作只出现在快照里
+        write(
+            &feed,
+            &store,
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+        );
+        let read = |table: &str| dispatch(CommandRequest::new_hgetall(table), &store);
+        let mut watch = feed.watch("t1".into(), Some(&read));
+        write(
+            &feed,
+            &store,
+            CommandRequest::new_hset("t1", "k2", "v2".into()),
+        );
+
+        assert_eq!(watch.next().await.unwrap().values, &[1.into()]);
+        let snapshot = watch.next().await.unwrap();
+        assert_eq!(snapshot.pairs, vec![Kvpair::new("k1", "v1".into())]);
+        let event = decode_change(&watch.next().await.unwrap()).unwrap();
+        assert_eq!((event.seq, event.key.as_str()), (1, "k2"));
+
+        let mut res = feed.watch("".into(), Some(&read));
+        assert_eq!(res.next().await.unwrap().status, 400);
+
+        // 快照读取失败时只返回错误，不会留下 watcher
+        let too_large = |_: &str| KvError::ResultTooLarge(1).into();
+        let mut res = feed.watch("t1".into(), Some(&too_large));
+        assert_eq!(res.next().await.unwrap().status, 413);
+        assert!(res.next().await.is_none());
+        assert_eq!(feed.inner.lock().unwrap().watchers.len(), 1);
+    }
+}
@@ -17,9 +17,11 @@ impl CommandService for Hgetall {
         //     Err(e) => e.into(),
         // }
         // 使用迭代器是否更好？
+        // 读 table 在这一时刻的内容，遍历期间的写入不会让结果一半新一半旧
         store
-            .get_iter(&self.table)
+            .get_iter_snapshot(&self.table)
             .unwrap()
+            .filter(|pair| self.matches(pair))
             .collect::<Vec<_>>()
             .into()
     }
@@ -111,6 +113,24 @@ impl CommandService for Hmdel {
     }
 }
 
+impl CommandService for Hdelprefix {
+    fn execute(self, store: &dyn Storage) -> CommandResponse {
+        match store.del_prefix(&self.table, &self.prefix) {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Htableexists {
+    fn execute(self, store: &dyn Storage) -> CommandResponse {
+        match store.table_exists(&self.table) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
 impl CommandService for Hexist {
     fn execute(self, store: &dyn Storage) -> CommandResponse {
         match store.contains(&self.table, &self.key) {
@@ -266,6 +286,65 @@ mod tests {
         assert_res_ok(res, &["hello".into(), "world".into()], &[]);
     }
 
+    #[test]
+    fn hgetall_with_filter_should_work() {
+        let store = MemTable::new();
+        let pairs = vec![
+            Kvpair::new("u1", 5.into()),
+            Kvpair::new("u2", 20.into()),
+            Kvpair::new("u3", 15.5.into()),
+            Kvpair::new("u4", "alice".into()),
+            Kvpair::new("u5", "bob".into()),
+        ];
+        _ = dispatch(CommandRequest::new_hmset("t1", pairs), &store);
+
+        let filter = Filter::compare(CompareOp::Gt, 10.0);
+        let res = dispatch(CommandRequest::new_hgetall_filtered("t1", filter), &store);
+        let pairs = vec![Kvpair::new("u2", 20.into()), Kvpair::new("u3", 15.5.into())];
+        assert_res_ok(res, &[], &pairs);
+
+        let res = dispatch(
+            CommandRequest::new_hgetall_filtered("t1", Filter::contains("li")),
+            &store,
+        );
+        assert_res_ok(res, &[], &[Kvpair::new("u4", "alice".into())]);
+
+        let res = dispatch(
+            CommandRequest::new_hgetall_filtered("t1", Filter::value_type("string")),
+            &store,
+        );
+        let pairs = vec![
+            Kvpair::new("u4", "alice".into()),
+            Kvpair::new("u5", "bob".into()),
+        ];
+        assert_res_ok(res, &[], &pairs);
+    }
+
+    #[test]
+    fn hdelprefix_should_work() {
+        let store = MemTable::new();
+        for key in ["user:1", "user:2", "order:1"] {
+            _ = dispatch(CommandRequest::new_hset("t1", key, 1.into()), &store);
+        }
+        let res = dispatch(CommandRequest::new_hdelprefix("t1", "user:"), &store);
+        assert_res_ok(res, &[2.into()], &[]);
+        let res = dispatch(CommandRequest::new_hgetall("t1"), &store);
+        assert_res_ok(res, &[], &[Kvpair::new("order:1", 1.into())]);
+    }
+
+    #[test]
+    fn htableexists_should_work() {
+        let store = MemTable::new();
+        _ = dispatch(CommandRequest::new_hset("t1", "k1", 1.into()), &store);
+        let res = dispatch(CommandRequest::new_htableexists("t1"), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+
+        // 读不存在的 table 不会创建它
+        _ = dispatch(CommandRequest::new_hget("t2", "k1"), &store);
+        let res = dispatch(CommandRequest::new_htableexists("t2"), &store);
+        assert_res_ok(res, &[false.into()], &[]);
+    }
+
     #[test]
     fn hexist_should_work() {
         let store = MemTable::new();
@@ -305,6 +384,8 @@ mod tests {
             RequestData::Hmset(v) => v.execute(store),
             RequestData::Hdel(v) => v.execute(store),
             RequestData::Hmdel(v) => v.execute(store),
+            RequestData::Hdelprefix(v) => v.execute(store),
+            RequestData::Htableexists(v) => v.execute(store),
             RequestData::Hexist(v) => v.execute(store),
             RequestData::Hmexist(v) => v.execute(store),
             _ => unreachable!(),
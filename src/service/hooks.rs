@@ -0,0 +1,293 @@
+use super::RequestContext;
+use crate::{CommandRequest, CommandResponse};
+use futures::{FutureExt, future::BoxFuture};
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+
+/// 没有指定优先级时使用的优先级
+pub const DEFAULT_PRIORITY: i32 = 0;
+/// 建议给认证和访问控制类的 hook 使用的优先级，最先执行
+pub const AUTH_PRIORITY: i32 = -200;
+/// 建议给限流类的 hook 使用的优先级，在认证之后执行
+pub const RATE_LIMIT_PRIORITY: i32 = -100;
+/// 建议给统计类的 hook 使用的优先级，最后执行，能看到前面的 hook 修改过的结果
+pub const METRICS_PRIORITY: i32 = 100;
+
+pub type ReceivedHook = fn(&RequestContext, &CommandRequest) -> Option<CommandResponse>;
+pub type ExecutedHook = fn(&RequestContext, &CommandResponse) -> Option<CommandResponse>;
+pub type BeforeSendHook = fn(&RequestContext, &mut CommandResponse) -> Option<CommandResponse>;
+pub type AfterSendHook = fn(&RequestContext) -> Option<CommandResponse>;
+/// 异步的 executed hook，拿到响应的副本，可以 await（比如写审计记录），不能修改响应
+pub type AsyncExecutedHook =
+    Arc<dyn Fn(RequestContext, CommandResponse) -> BoxFuture<'static, ()> + Send + Sync>;
+/// 异步的 before_send hook，返回的响应会交给下一个 hook，最后发给客户端
+pub type AsyncBeforeSendHook = Arc<
+    dyn Fn(RequestContext, CommandResponse) -> BoxFuture<'static, CommandResponse> + Send + Sync,
+>;
+
+/// hook 执行的阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HookStage {
+    /// 收到命令之后、执行之前
+    Received,
+    /// 命令执行之后
+    Executed,
+    /// 发送响应之前，可以修改响应
+    BeforeSend,
+    /// 网络层发出响应之后
+    AfterSend,
+}
+
+impl fmt::Display for HookStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            HookStage::Received => "received",
+            HookStage::Executed => "executed",
+            HookStage::BeforeSend => "before_send",
+            HookStage::AfterSend => "after_send",
+        };
+        f.write_str(s)
+    }
+}
+
+/// 已经注册的 hook，用于查看 hook 链
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HookInfo {
+    pub stage: HookStage,
+    pub name: String,
+    pub priority: i32,
+    pub is_async: bool,
+}
+
+impl fmt::Display for HookInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} priority={}", self.stage, self.name, self.priority)?;
+        if self.is_async {
+            f.write_str(" async")?;
+        }
+        Ok(())
+    }
+}
+
+/// 一个阶段的 hook 链，按优先级从小到大执行，不依赖 builder 的调用顺序；优先级相同时按注册的顺序执行
+#[derive(Clone)]
+pub struct HookChain<F> {
+    hooks: Vec<Hook<F>>,
+    /// 没有名字的 hook 的数量，用来生成名字
+    unnamed: usize,
+}
+
+#[derive(Clone)]
+struct Hook<F> {
+    name: String,
+    priority: i32,
+    f: F,
+}
+
+impl<F> Default for HookChain<F> {
+    fn default() -> Self {
+        Self {
+            hooks: Vec::new(),
+            unnamed: 0,
+        }
+    }
+}
+
+impl<F> HookChain<F> {
+    /// 注册 hook，同一个阶段里同名的 hook 会被替换
+    pub fn insert(&mut self, name: impl Into<String>, priority: i32, f: F) {
+        let name = name.into();
+        self.remove(&name);
+        let pos = self.hooks.partition_point(|h| h.priority <= priority);
+        self.hooks.insert(pos, Hook { name, priority, f });
+    }
+
+    /// 用默认优先级注册没有名字的 hook，名字是 `#1`、`#2`……
+    pub fn push(&mut self, f: F) {
+        self.unnamed += 1;
+        let name = format!("#{}", self.unnamed);
+        self.insert(name, DEFAULT_PRIORITY, f);
+    }
+
+    /// 删除 hook，返回是否存在
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.hooks.len();
+        self.hooks.retain(|h| h.name != name);
+        self.hooks.len() != len
+    }
+
+    /// 按执行顺序
+    pub fn iter(&self) -> impl Iterator<Item = &F> {
+        self.hooks.iter().map(|h| &h.f)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    fn info(&self, stage: HookStage, is_async: bool) -> impl Iterator<Item = HookInfo> + '_ {
+        self.hooks.iter().map(move |h| HookInfo {
+            stage,
+            name: h.name.clone(),
+            priority: h.priority,
+            is_async,
+        })
+    }
+}
+
+/// 把返回 future 的闭包包装成异步的 executed hook
+pub fn async_executed<F, Fut>(f: F) -> AsyncExecutedHook
+where
+    F: Fn(RequestContext, CommandResponse) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    Arc::new(move |ctx, res| f(ctx, res).boxed())
+}
+
+/// 把返回 future 的闭包包装成异步的 before_send hook
+pub fn async_before_send<F, Fut>(f: F) -> AsyncBeforeSendHook
+where
+    F: Fn(RequestContext, CommandResponse) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = CommandResponse> + Send + 'static,
+{
+    Arc::new(move |ctx, res| f(ctx, res).boxed())
+}
+
+/// Service 上所有阶段的 hook
+///
+/// 同一个阶段里先执行同步的 hook，再执行异步的 hook。异步的 hook 在返回的响应流里执行，
+/// 不会阻塞 execute 本身，客户端收到响应之前等待它们完成
+#[derive(Clone, Default)]
+pub struct Hooks {
+    pub received: HookChain<ReceivedHook>,
+    pub executed: HookChain<ExecutedHook>,
+    pub executed_async: HookChain<AsyncExecutedHook>,
+    pub before_send: HookChain<BeforeSendHook>,
+    pub before_send_async: HookChain<AsyncBeforeSendHook>,
+    pub after_send: HookChain<AfterSendHook>,
+}
+
+impl Hooks {
+    /// 所有 hook，按阶段排列，同一个阶段里按执行顺序
+    pub fn info(&self) -> Vec<HookInfo> {
+        self.received
+            .info(HookStage::Received, false)
+            .chain(self.executed.info(HookStage::Executed, false))
+            .chain(self.executed_async.info(HookStage::Executed, true))
+            .chain(self.before_send.info(HookStage::BeforeSend, false))
+            .chain(self.before_send_async.info(HookStage::BeforeSend, true))
+            .chain(self.after_send.info(HookStage::AfterSend, false))
+            .collect()
+    }
+
+    pub fn has_async(&self) -> bool {
+        !self.executed_async.is_empty() || !self.before_send_async.is_empty()
+    }
+
+    /// 依次执行异步的 executed 和 before_send hook，返回最终发给客户端的响应
+    pub async fn run_async(
+        &self,
+        ctx: &RequestContext,
+        mut res: CommandResponse,
+    ) -> CommandResponse {
+        for f in self.executed_async.iter() {
+            f(ctx.clone(), res.clone()).await;
+        }
+        for f in self.before_send_async.iter() {
+            res = f(ctx.clone(), res).await;
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chain_should_order_by_priority_then_registration() {
+        let mut chain = HookChain::default();
+        chain.insert("metrics", METRICS_PRIORITY, 3);
+        chain.push(2);
+        chain.insert("rate-limit", RATE_LIMIT_PRIORITY, 1);
+        chain.insert("auth", AUTH_PRIORITY, 0);
+        chain.push(22);
+        assert_eq!(chain.iter().copied().collect::<Vec<_>>(), [0, 1, 2, 22, 3]);
+
+        // 同名的 hook 被替换
+        chain.insert("auth", METRICS_PRIORITY + 1, 4);
+        assert_eq!(chain.iter().copied().collect::<Vec<_>>(), [1, 2, 22, 3, 4]);
+        assert!(chain.remove("#1"));
+        assert!(!chain.remove("#1"));
+        assert_eq!(chain.iter().copied().collect::<Vec<_>>(), [1, 22, 3, 4]);
+    }
+
+    #[test]
+    fn hooks_should_list_installed_chain() {
+        fn noop(_: &RequestContext) -> Option<CommandResponse> {
+            None
+        }
+        let mut hooks = Hooks::default();
+        hooks.after_send.insert("metrics", METRICS_PRIORITY, noop);
+        hooks.received.insert(
+            "auth",
+            AUTH_PRIORITY,
+            |_: &RequestContext, _: &CommandRequest| None,
+        );
+        let info: Vec<_> = hooks.info().iter().map(|h| h.to_string()).collect();
+        assert_eq!(
+            info,
+            [
+                "received auth priority=-200",
+                "after_send metrics priority=100"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn async_hooks_should_run_after_sync_hooks_of_the_same_stage() {
+        fn noop(_: &RequestContext, _: &CommandResponse) -> Option<CommandResponse> {
+            None
+        }
+        let mut hooks = Hooks::default();
+        hooks.before_send_async.insert(
+            "second",
+            DEFAULT_PRIORITY,
+            async_before_send(|_, mut res: CommandResponse| async move {
+                res.message.push_str("second;");
+                res
+            }),
+        );
+        hooks.before_send_async.insert(
+            "first",
+            AUTH_PRIORITY,
+            async_before_send(|_, mut res: CommandResponse| async move {
+                tokio::task::yield_now().await;
+                res.message.push_str("first;");
+                res
+            }),
+        );
+        hooks.executed.insert("log", DEFAULT_PRIORITY, noop);
+        hooks
+            .executed_async
+            .insert("audit", DEFAULT_PRIORITY, async_executed(|_, _| async {}));
+        assert!(hooks.has_async());
+
+        let res = hooks
+            .run_async(&RequestContext::default(), CommandResponse::default())
+            .await;
+        assert_eq!(res.message, "first;second;");
+
+        let info: Vec<_> = hooks.info().iter().map(|h| h.to_string()).collect();
+        assert_eq!(
+            info,
+            [
+                "executed log priority=0",
+                "executed audit priority=0 async",
+                "before_send first priority=-200 async",
+                "before_send second priority=0 async",
+            ]
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use crate::{CommandResponse, IdempotencyConfig};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// 记住最近执行过的带幂等 key 的写命令的响应
+///
+/// 客户端超时后重试时，服务器可能已经执行过第一次的请求。带着同一个 key 的重复请求直接返回第一次的响应，
+/// 不会再执行一遍。只记住成功的响应，失败的请求重试时会重新执行。
+/// key 过了 ttl 或者超过容量时按写入的顺序淘汰，所有 key 的 ttl 一样，最早写入的也最早过期
+pub struct IdempotencyCache {
+    ttl: Duration,
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    responses: HashMap<String, (Instant, CommandResponse)>,
+    /// 按写入顺序排列的 key
+    order: VecDeque<(Instant, String)>,
+}
+
+impl IdempotencyCache {
+    pub fn new(config: &IdempotencyConfig) -> Self {
+        Self {
+            ttl: Duration::from_millis(config.ttl_ms),
+            capacity: config.capacity.max(1),
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// 没有过期的响应
+    pub fn get(&self, key: &str) -> Option<CommandResponse> {
+        let inner = self.inner.lock().unwrap();
+        match inner.responses.get(key) {
+            Some((at, res)) if at.elapsed() < self.ttl => Some(res.clone()),
+            _ => None,
+        }
+    }
+
+    /// 记住成功的响应
+    pub fn insert(&self, key: &str, res: &CommandResponse) {
+        if key.is_empty() || res.status != 200 {
+            return;
+        }
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { responses, order } = &mut *inner;
+        responses.insert(key.to_string(), (now, res.clone()));
+        order.push_back((now, key.to_string()));
+        while let Some((at, key)) = order.front() {
+            if now.duration_since(*at) < self.ttl && order.len() <= self.capacity {
+                break;
+            }
+            // 同一个 key 后来又写入过时，map 里已经是新的记录，不能删除
+            if responses.get(key).is_some_and(|(t, _)| t == at) {
+                responses.remove(key);
+            }
+            order.pop_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().responses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KvError, Value};
+
+    fn cache(ttl_ms: u64, capacity: usize) -> IdempotencyCache {
+        IdempotencyCache::new(&IdempotencyConfig {
+            enabled: true,
+            ttl_ms,
+            capacity,
+        })
+    }
+
+    #[test]
+    fn duplicate_key_should_get_original_response() {
+        let cache = cache(60_000, 10);
+        let res: CommandResponse = Value::from("v1").into();
+        cache.insert("token1", &res);
+        assert_eq!(cache.get("token1"), Some(res));
+        assert_eq!(cache.get("token2"), None);
+
+        // 失败的响应不记住
+        cache.insert("token2", &KvError::Busy("busy".into()).into());
+        assert_eq!(cache.get("token2"), None);
+    }
+
+    #[test]
+    fn keys_should_expire_and_be_evicted() {
+        let cache = cache(60_000, 2);
+        let res: CommandResponse = Value::from("v1").into();
+        for key in ["k1", "k2", "k3"] {
+            cache.insert(key, &res);
+        }
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("k1"), None);
+        assert!(cache.get("k3").is_some());
+
+        let cache = self::cache(10, 10);
+        cache.insert("k1", &res);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get("k1"), None);
+        cache.insert("k2", &res);
+        assert_eq!(cache.len(), 1);
+    }
+}
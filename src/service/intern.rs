@@ -0,0 +1,88 @@
+use dashmap::DashMap;
+use std::sync::{Arc, LazyLock};
+
+/// 全局驻留池最多保存的字符串数
+const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+static INTERNER: LazyLock<Interner> = LazyLock::new(|| Interner::new(DEFAULT_CAPACITY));
+
+/// 从全局驻留池里取出和 s 相同的共享字符串
+pub fn intern(s: &str) -> Arc<str> {
+    INTERNER.intern(s)
+}
+
+/// 字符串驻留池，用于 table 名、热点 key 和主题名这类反复出现的字符串
+///
+/// 同一个字符串第一次出现时分配一个 `Arc<str>`，之后都返回它的 clone，只增加引用计数。
+/// 驻留池只增不减，超过容量之后新出现的字符串不再保存，每次都重新分配，
+/// 避免客户端用大量不同的名字把内存撑大
+pub struct Interner {
+    strings: DashMap<Arc<str>, Arc<str>>,
+    capacity: usize,
+}
+
+impl Interner {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            strings: DashMap::new(),
+            capacity,
+        }
+    }
+
+    /// 返回和 s 相同的共享字符串
+    pub fn intern(&self, s: &str) -> Arc<str> {
+        if let Some(v) = self.strings.get(s) {
+            return Arc::clone(&v);
+        }
+        let v: Arc<str> = s.into();
+        self.insert(Arc::clone(&v), v)
+    }
+
+    /// 返回由 s 生成的共享字符串，比如 table 对应的 keyspace 主题名。f 只在 s 第一次出现时调用
+    pub fn intern_with(&self, s: &str, f: impl FnOnce(&str) -> String) -> Arc<str> {
+        if let Some(v) = self.strings.get(s) {
+            return Arc::clone(&v);
+        }
+        self.insert(s.into(), f(s).into())
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+
+    fn insert(&self, key: Arc<str>, value: Arc<str>) -> Arc<str> {
+        if self.strings.len() >= self.capacity {
+            return value;
+        }
+        // 并发插入同一个字符串时，大家拿到的是先插入的那一个
+        Arc::clone(&self.strings.entry(key).or_insert(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_string_should_be_shared() {
+        let interner = Interner::new(2);
+        let a = interner.intern("t1");
+        let b = interner.intern(&String::from("t1"));
+        assert!(Arc::ptr_eq(&a, &b));
+
+        let topic = interner.intern_with("t2", |t| format!("__keyspace@{}", t));
+        assert_eq!(&*topic, "__keyspace@t2");
+        let again = interner.intern_with("t2", |_| unreachable!());
+        assert!(Arc::ptr_eq(&topic, &again));
+
+        // 超过容量之后不再保存
+        let c = interner.intern("t3");
+        assert_eq!(&*c, "t3");
+        assert!(!Arc::ptr_eq(&c, &interner.intern("t3")));
+        assert_eq!(interner.len(), 2);
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// 默认的分段数
+const DEFAULT_STRIPES: usize = 1024;
+
+/// 按 key 加锁，用于 read-modify-write 的命令（HINCR、APPEND、CAS 等）
+///
+/// 先读再写的命令在不同的存储后端上都需要对同一个 key 串行执行，否则并发的两个请求会基于同一个旧值修改。
+/// 锁按 (table, key) 的哈希分段，每段一把异步锁：不相关的 key 大概率落在不同的段上，可以并发执行；
+/// 同一个 key 总是落在同一段上。锁的数量固定，不会随 key 增长
+pub struct KeyLocks {
+    stripes: Box<[Arc<Mutex<()>>]>,
+}
+
+/// 持有一个或者多个段的锁，drop 时释放
+#[must_use = "the key is unlocked when the guard is dropped"]
+pub struct KeyGuard {
+    _guards: Vec<OwnedMutexGuard<()>>,
+}
+
+impl Default for KeyLocks {
+    fn default() -> Self {
+        Self::new(DEFAULT_STRIPES)
+    }
+}
+
+impl KeyLocks {
+    pub fn new(stripes: usize) -> Self {
+        Self {
+            stripes: (0..stripes.max(1))
+                .map(|_| Arc::new(Mutex::new(())))
+                .collect(),
+        }
+    }
+
+    /// 锁住一个 key，等到其它持有同一段锁的命令执行完
+    pub async fn lock(&self, table: &str, key: &str) -> KeyGuard {
+        self.lock_all(table, [key]).await
+    }
+
+    /// 锁住多个 key，用于 HMSET 这样一次修改多个 key 的命令。
+    /// 按段的顺序加锁，同一段只加一次，多个命令同时锁多个 key 也不会死锁
+    pub async fn lock_all<'a>(
+        &self,
+        table: &str,
+        keys: impl IntoIterator<Item = &'a str>,
+    ) -> KeyGuard {
+        let mut stripes: Vec<_> = keys
+            .into_iter()
+            .map(|key| self.stripe(table, key))
+            .collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        let mut guards = Vec::with_capacity(stripes.len());
+        for i in stripes {
+            guards.push(Arc::clone(&self.stripes[i]).lock_owned().await);
+        }
+        KeyGuard { _guards: guards }
+    }
+
+    fn stripe(&self, table: &str, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        (table, key).hash(&mut hasher);
+        hasher.finish() as usize % self.stripes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, Storage, Value};
+    use std::time::Duration;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn same_key_should_be_serialized() {
+        let locks = Arc::new(KeyLocks::default());
+        let store = Arc::new(MemTable::new());
+        store.set("t1", "counter".into(), 0i64.into()).unwrap();
+
+        // 读和写之间让出执行权，不加锁的话会丢失更新
+        let tasks: Vec<_> = (0..20)
+            .map(|_| {
+                let (locks, store) = (locks.clone(), store.clone());
+                tokio::spawn(async move {
+                    let _guard = locks.lock("t1", "counter").await;
+                    let n: i64 = store
+                        .get("t1", "counter")
+                        .unwrap()
+                        .unwrap()
+                        .try_into()
+                        .unwrap();
+                    tokio::task::yield_now().await;
+                    store.set("t1", "counter".into(), (n + 1).into()).unwrap();
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(
+            store.get("t1", "counter").unwrap(),
+            Some(Value::from(20i64))
+        );
+    }
+
+    #[tokio::test]
+    async fn unrelated_keys_should_not_block() {
+        let locks = KeyLocks::default();
+        let other = (0..)
+            .map(|i| format!("k{}", i))
+            .find(|k| locks.stripe("t1", k) != locks.stripe("t1", "k"))
+            .unwrap();
+
+        let _guard = locks.lock("t1", "k").await;
+        let res = time::timeout(Duration::from_millis(100), locks.lock("t1", &other)).await;
+        assert!(res.is_ok());
+        let res = time::timeout(Duration::from_millis(50), locks.lock("t1", "k")).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn lock_all_should_not_deadlock_on_shared_stripes() {
+        // 只有一段时所有 key 都在同一段上，重复的 key 也只加一次锁
+        let locks = KeyLocks::new(1);
+        let guard = time::timeout(
+            Duration::from_millis(100),
+            locks.lock_all("t1", ["k1", "k2", "k1"]),
+        )
+        .await;
+        assert!(guard.is_ok());
+    }
+}
@@ -0,0 +1,197 @@
+use dashmap::DashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// 桶的上界按 2 的幂增长，第 i 个桶的上界是 2^i 微秒，最后一个桶约 33 秒
+const BUCKETS: usize = 26;
+/// 每个窗口的时长，统计结果覆盖当前窗口和上一个窗口
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// 按 2 的幂分桶的延迟直方图，百分位数取所在桶的上界，误差在 2 倍以内
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    counts: [u64; BUCKETS],
+    total: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros() as u64;
+        let i = ((u64::BITS - us.leading_zeros()) as usize).min(BUCKETS - 1);
+        self.counts[i] += 1;
+        self.total += 1;
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (a, b) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *a += b;
+        }
+        self.total += other.total;
+    }
+
+    fn percentile(&self, p: f64) -> Duration {
+        let target = ((self.total as f64 * p).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return Duration::from_micros(1 << i);
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+/// 滚动窗口的直方图，每过一个 WINDOW 丢弃更早的数据
+#[derive(Debug)]
+struct RollingHistogram {
+    current: Histogram,
+    previous: Histogram,
+    started: Instant,
+}
+
+impl Default for RollingHistogram {
+    fn default() -> Self {
+        Self {
+            current: Histogram::default(),
+            previous: Histogram::default(),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl RollingHistogram {
+    fn rotate(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.started);
+        if elapsed >= WINDOW * 2 {
+            self.previous = Histogram::default();
+            self.current = Histogram::default();
+            self.started = now;
+        } else if elapsed >= WINDOW {
+            self.previous = std::mem::take(&mut self.current);
+            self.started += WINDOW;
+        }
+    }
+
+    fn snapshot(&self) -> Histogram {
+        let mut hist = self.previous.clone();
+        hist.merge(&self.current);
+        hist
+    }
+}
+
+/// 某个命令最近的延迟统计
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyStats {
+    pub command: &'static str,
+    pub count: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+/// 类似 CLIENT LIST 的单行输出，延迟单位是微秒
+impl fmt::Display for LatencyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cmd={} count={} p50={}us p95={}us p99={}us",
+            self.command,
+            self.count,
+            self.p50.as_micros(),
+            self.p95.as_micros(),
+            self.p99.as_micros(),
+        )
+    }
+}
+
+/// 按命令类型记录服务器处理命令的延迟
+#[derive(Debug, Default)]
+pub struct LatencyTracker {
+    commands: DashMap<&'static str, RollingHistogram>,
+}
+
+impl LatencyTracker {
+    pub fn record(&self, command: &'static str, latency: Duration) {
+        let mut hist = self.commands.entry(command).or_default();
+        hist.rotate(Instant::now());
+        hist.current.record(latency);
+    }
+
+    /// 所有命令最近两个窗口内的延迟统计，按命令名排序
+    pub fn stats(&self) -> Vec<LatencyStats> {
+        let now = Instant::now();
+        let mut stats: Vec<_> = self
+            .commands
+            .iter_mut()
+            .filter_map(|mut entry| {
+                entry.rotate(now);
+                let hist = entry.snapshot();
+                (hist.total > 0).then(|| LatencyStats {
+                    command: entry.key(),
+                    count: hist.total,
+                    p50: hist.percentile(0.5),
+                    p95: hist.percentile(0.95),
+                    p99: hist.percentile(0.99),
+                })
+            })
+            .collect();
+        stats.sort_by_key(|v| v.command);
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_percentile_should_work() {
+        let mut hist = Histogram::default();
+        for _ in 0..90 {
+            hist.record(Duration::from_micros(10));
+        }
+        for _ in 0..10 {
+            hist.record(Duration::from_millis(10));
+        }
+
+        assert_eq!(hist.total, 100);
+        // 10us 落在 (8us, 16us] 的桶里
+        assert_eq!(hist.percentile(0.5), Duration::from_micros(16));
+        assert_eq!(hist.percentile(0.95), Duration::from_micros(16384));
+        assert_eq!(hist.percentile(0.99), Duration::from_micros(16384));
+    }
+
+    #[test]
+    fn rolling_histogram_should_drop_old_windows() {
+        let mut hist = RollingHistogram::default();
+        let start = hist.started;
+        hist.current.record(Duration::from_micros(10));
+
+        hist.rotate(start + WINDOW);
+        assert_eq!(hist.snapshot().total, 1);
+
+        hist.current.record(Duration::from_micros(10));
+        hist.rotate(start + WINDOW * 2);
+        assert_eq!(hist.snapshot().total, 1);
+
+        hist.rotate(start + WINDOW * 5);
+        assert_eq!(hist.snapshot().total, 0);
+    }
+
+    #[test]
+    fn latency_tracker_should_group_by_command() {
+        let tracker = LatencyTracker::default();
+        tracker.record("hset", Duration::from_micros(100));
+        tracker.record("hget", Duration::from_micros(3));
+        tracker.record("hget", Duration::from_micros(5));
+
+        let stats = tracker.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].command, "hget");
+        assert_eq!(stats[0].count, 2);
+        assert_eq!(stats[0].p99, Duration::from_micros(8));
+        assert_eq!(stats[1].command, "hset");
+        assert!(stats[1].to_string().starts_with("cmd=hset count=1"));
+    }
+}
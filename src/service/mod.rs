@@ -1,13 +1,51 @@
-use crate::{CommandRequest, CommandResponse, KvError, Storage, command_request::RequestData};
-use futures::stream;
-use std::sync::Arc;
-use tracing::{debug, instrument};
+use crate::{
+    AccessRole, CommandRequest, CommandResponse, ConnectionRegistry, ConnectionStats, KvError,
+    Membership, MultiMaster, RaftNode, Shadow, ShardMode, ShardRouter, SlotMap, Storage,
+    command_request::RequestData, is_reserved_table, replay_snapshot, save_snapshot,
+};
+use futures::{future, stream};
+use prost::Message;
+use std::net::SocketAddr;
+use std::ops::ControlFlow;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, instrument};
 
+mod access;
+mod admin_service;
+mod change_feed;
 mod command_service;
+mod hooks;
+mod idempotency;
+mod intern;
+mod key_lock;
+mod latency;
+mod namespace;
+mod overload;
+mod replication;
+mod scheduler;
 mod topic;
 mod topic_service;
 
-pub use topic::{Broadcaster, Topic};
+pub use access::{AccessControl, CommandFilter};
+pub use admin_service::AdminService;
+pub use change_feed::{ChangeFeed, decode_change};
+pub use hooks::{
+    AUTH_PRIORITY, AfterSendHook, AsyncBeforeSendHook, AsyncExecutedHook, BeforeSendHook,
+    DEFAULT_PRIORITY, ExecutedHook, HookChain, HookInfo, HookStage, Hooks, METRICS_PRIORITY,
+    RATE_LIMIT_PRIORITY, ReceivedHook, async_before_send, async_executed,
+};
+pub use idempotency::IdempotencyCache;
+pub use intern::{Interner, intern};
+pub use key_lock::{KeyGuard, KeyLocks};
+pub use latency::{LatencyStats, LatencyTracker};
+pub use namespace::{Namespaces, Tenant};
+pub use overload::{InFlightGuard, LoadShedder};
+pub use replication::{ReplicationLog, decode_entry, encode_entry};
+pub use scheduler::{JobInfo, Scheduler};
+pub use topic::{Broadcaster, Topic, TopicMetrics, keyspace_topic};
 pub use topic_service::{StreamingResponse, TopicService};
 
 pub trait CommandService {
@@ -17,22 +55,104 @@ pub trait CommandService {
 pub struct Service {
     // inner: Arc<ServiceInner<Store>>,
     pub store: Arc<dyn Storage>,
-    on_received: Vec<fn(&CommandRequest) -> Option<CommandResponse>>,
-    on_executed: Vec<fn(&CommandResponse) -> Option<CommandResponse>>,
-    on_before_send: Vec<fn(&mut CommandResponse) -> Option<CommandResponse>>,
-    on_after_send: Vec<fn() -> Option<CommandResponse>>,
+    /// 所有 clone 共享，运行中可以修改。修改时复制一份新的 Hooks 再替换（copy-on-write），
+    /// 执行命令时拿到当时的快照，正在执行的命令不受影响
+    hooks: Arc<RwLock<Arc<Hooks>>>,
     broadcaster: Arc<Broadcaster>,
+    /// HWATCH 的变更流
+    changes: Arc<ChangeFeed>,
+    connections: Arc<ConnectionRegistry>,
+    latency: Arc<LatencyTracker>,
+    /// 复制日志，primary 用来发送写操作；开启自动故障转移的 replica 也有，提升为 primary 后使用
+    replication: Option<Arc<ReplicationLog>>,
+    /// replica 只处理读请求，写操作只能来自 primary。提升为 primary 时清除，所有 clone 共享
+    read_only: Arc<AtomicBool>,
+    /// 开启 Raft 时，读写命令都经过 Raft 节点
+    raft: Option<Arc<RaftNode>>,
+    /// 开启分片时，不属于本节点的数据命令会被重定向或者代理
+    shard: Option<Arc<ShardRouter>>,
+    membership: Option<Arc<Membership>>,
+    /// 多主模式下，本地写操作会记录版本，供其它节点同步
+    multi_master: Option<Arc<MultiMaster>>,
+    /// 是否是管理端口的 Service，管理端口上的连接可以执行运维命令
+    admin: bool,
+    /// 开启过载保护时，过载后拒绝低优先级的命令
+    shedder: Option<Arc<LoadShedder>>,
+    /// 设置后连接要先用 AUTH 认证
+    password: Option<Arc<str>>,
+    /// 开启访问控制时，按连接的身份限制能执行的命令
+    access: Option<Arc<AccessControl>>,
+    /// 配置了租户时，按连接的身份把 table 和主题放进租户的 namespace
+    namespaces: Option<Arc<Namespaces>>,
+    /// 按配置关闭的命令
+    commands: Option<Arc<CommandFilter>>,
+    /// 开启影子流量时，收到的数据命令按比例转发给影子服务器
+    shadow: Option<Arc<Shadow>>,
+    /// 带幂等 key 的写命令的响应
+    idempotency: Option<Arc<IdempotencyCache>>,
+    /// 单个 HGETALL/HMGET 响应最多包含的结果数
+    max_result_entries: Option<usize>,
+    /// 单个命令最多包含的 pair、key 或者 value 数
+    max_request_entries: Option<usize>,
+    /// 写入的 key 和 value 的最大字节数
+    max_key_bytes: Option<usize>,
+    max_value_bytes: Option<usize>,
+    /// 定期执行的后台任务
+    scheduler: Arc<Scheduler>,
+    /// read-modify-write 命令按 key 加的锁
+    key_locks: Arc<KeyLocks>,
+    /// RELOAD 命令通知服务器重新读取配置
+    reload: Option<Arc<tokio::sync::Notify>>,
+    /// SAVE 和 RESTORE 只能读写这个目录里的文件，没有设置时这两个命令返回错误
+    backup_dir: Option<Arc<Path>>,
+}
+
+/// 请求所在连接的信息，传给 hook，用来按客户端做安全和配额相关的决定。
+/// 进程内直接调用 execute 时没有连接，所有字段都是空的
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RequestContext {
+    /// 连接的 id，和 CLIENT LIST 里的一样
+    pub conn_id: Option<u64>,
+    pub peer_addr: Option<SocketAddr>,
+    /// mTLS 客户端证书的指纹
+    pub identity: Option<String>,
+    /// 连接是否可以执行命令：已经 AUTH 成功，或者服务器没有设置密码
+    pub authenticated: bool,
+    /// 按身份得到的访问控制角色，没有开启访问控制时为 None
+    pub role: Option<AccessRole>,
 }
 
 impl Clone for Service {
     fn clone(&self) -> Self {
         Self {
             store: Arc::clone(&self.store),
-            on_received: self.on_received.clone(),
-            on_executed: self.on_executed.clone(),
-            on_before_send: self.on_before_send.clone(),
-            on_after_send: self.on_after_send.clone(),
+            hooks: Arc::clone(&self.hooks),
             broadcaster: Arc::clone(&self.broadcaster),
+            changes: Arc::clone(&self.changes),
+            connections: Arc::clone(&self.connections),
+            latency: Arc::clone(&self.latency),
+            replication: self.replication.clone(),
+            read_only: Arc::clone(&self.read_only),
+            raft: self.raft.clone(),
+            shard: self.shard.clone(),
+            membership: self.membership.clone(),
+            multi_master: self.multi_master.clone(),
+            admin: self.admin,
+            shedder: self.shedder.clone(),
+            password: self.password.clone(),
+            access: self.access.clone(),
+            namespaces: self.namespaces.clone(),
+            commands: self.commands.clone(),
+            shadow: self.shadow.clone(),
+            idempotency: self.idempotency.clone(),
+            max_result_entries: self.max_result_entries,
+            max_request_entries: self.max_request_entries,
+            max_key_bytes: self.max_key_bytes,
+            max_value_bytes: self.max_value_bytes,
+            scheduler: Arc::clone(&self.scheduler),
+            key_locks: Arc::clone(&self.key_locks),
+            reload: self.reload.clone(),
+            backup_dir: self.backup_dir.clone(),
         }
     }
 }
@@ -46,58 +166,906 @@ impl Service {
     pub fn new<S: Storage>(store: S) -> Self {
         Self {
             store: Arc::new(store),
-            on_received: Vec::new(),
-            on_executed: Vec::new(),
-            on_before_send: Vec::new(),
-            on_after_send: Vec::new(),
+            hooks: Default::default(),
             broadcaster: Default::default(),
+            changes: Default::default(),
+            connections: Default::default(),
+            latency: Default::default(),
+            replication: None,
+            read_only: Default::default(),
+            raft: None,
+            shard: None,
+            membership: None,
+            multi_master: None,
+            admin: false,
+            shedder: None,
+            password: None,
+            access: None,
+            namespaces: None,
+            commands: None,
+            shadow: None,
+            idempotency: None,
+            max_result_entries: None,
+            max_request_entries: None,
+            max_key_bytes: None,
+            max_value_bytes: None,
+            scheduler: Default::default(),
+            key_locks: Default::default(),
+            reload: None,
+            backup_dir: None,
         }
     }
 
-    #[instrument(name = "service_execute", skip_all)]
+    /// 使用按配置创建的调度器，需要在注册 job 之前调用
+    pub fn with_scheduler(mut self, scheduler: Scheduler) -> Self {
+        self.scheduler = Arc::new(scheduler);
+        self
+    }
+
+    /// 后台任务调度器，所有 clone 共享
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    /// 收到 RELOAD 命令时通知 notify，没有设置时 RELOAD 返回错误
+    pub fn with_reload_trigger(mut self, notify: Arc<tokio::sync::Notify>) -> Self {
+        self.reload = Some(notify);
+        self
+    }
+
+    pub(crate) fn reload_trigger(&self) -> Option<&tokio::sync::Notify> {
+        self.reload.as_deref()
+    }
+
+    /// SAVE 和 RESTORE 读写的目录，客户端只能指定其中的文件名
+    pub fn with_backup_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.backup_dir = Some(Arc::from(dir.into()));
+        self
+    }
+
+    /// 限制每个主题和所有主题的订阅者数量，None 表示不限制，所有 clone 共享
+    pub fn with_subscriber_limits(self, per_topic: Option<usize>, total: Option<usize>) -> Self {
+        self.broadcaster.set_limits(per_topic, total);
+        self
+    }
+
+    /// 定期回收 subscriber 已经断开、空闲超过 idle 的主题
+    pub fn start_topic_gc(&self, idle: Duration) {
+        self.broadcaster.start_gc(&self.scheduler, idle);
+    }
+
+    /// 按 key 加锁，先读再写的命令在读之前锁住 key，所有 clone 共享
+    pub fn key_locks(&self) -> &KeyLocks {
+        &self.key_locks
+    }
+
+    /// 要求连接先用 AUTH 认证，网络层在认证之前拒绝其它命令
+    pub fn with_password(mut self, password: impl Into<String>) -> Self {
+        self.password = Some(password.into().into());
+        self
+    }
+
+    /// 开启基于角色的访问控制
+    pub fn with_access_control(mut self, access: AccessControl) -> Self {
+        self.access = Some(Arc::new(access));
+        self
+    }
+
+    /// 按身份把租户的 table 和主题放进各自的 namespace，并检查租户的配额
+    pub fn with_namespaces(mut self, namespaces: Namespaces) -> Self {
+        self.namespaces = Some(Arc::new(namespaces));
+        self
+    }
+
+    /// 关闭配置里禁止的命令，执行之前就拒绝
+    pub fn with_command_filter(mut self, filter: CommandFilter) -> Self {
+        self.commands = Some(Arc::new(filter));
+        self
+    }
+
+    /// 把收到的数据命令按比例转发给影子服务器
+    pub fn with_shadow(mut self, shadow: Shadow) -> Self {
+        self.shadow = Some(Arc::new(shadow));
+        self
+    }
+
+    /// 带着同一个幂等 key 的重复写命令返回第一次的响应
+    pub fn with_idempotency(mut self, cache: IdempotencyCache) -> Self {
+        self.idempotency = Some(Arc::new(cache));
+        self
+    }
+
+    /// 限制单个 HGETALL/HMGET 响应（包括 HWATCH 的快照）最多包含的结果数，避免不小心读出整个大 table
+    pub fn with_max_result_entries(mut self, limit: usize) -> Self {
+        self.max_result_entries = Some(limit);
+        self
+    }
+
+    /// 限制单个命令最多包含的 pair、key 或者 value 数，超过时返回 413，不会执行
+    pub fn with_max_request_entries(mut self, limit: usize) -> Self {
+        self.max_request_entries = Some(limit);
+        self
+    }
+
+    /// 限制写入的 key 和 value 的字节数，None 表示不限制。
+    /// 在路由和执行之前检查，超过的写命令不会被代理、复制或者写进存储
+    pub fn with_entry_limits(mut self, max_key: Option<usize>, max_value: Option<usize>) -> Self {
+        self.max_key_bytes = max_key;
+        self.max_value_bytes = max_value;
+        self
+    }
+
+    /// 不要求认证，也不做访问控制，用于进程内的连接。clone 出来的其它 Service 不受影响
+    pub fn without_auth(mut self) -> Self {
+        self.password = None;
+        self.access = None;
+        self
+    }
+
+    /// 检查连接认证后的身份是否可以执行命令，网络层在执行命令之前调用
+    pub fn authorize(&self, identity: Option<&str>, cmd: &CommandRequest) -> Result<(), KvError> {
+        match &self.access {
+            Some(access) => access.check(identity, cmd),
+            None => Ok(()),
+        }
+    }
+
+    /// 身份对应的访问控制角色
+    pub fn role(&self, identity: Option<&str>) -> Option<AccessRole> {
+        self.access
+            .as_ref()
+            .and_then(|access| access.role(identity))
+    }
+
+    pub fn requires_auth(&self) -> bool {
+        self.password.is_some()
+    }
+
+    /// 检查 AUTH 的密码，没有设置密码时总是成功
+    fn authenticate(&self, password: &str) -> CommandResponse {
+        match &self.password {
+            Some(expected) if !constant_time_eq(expected.as_bytes(), password.as_bytes()) => {
+                KvError::Unauthorized("invalid password".into()).into()
+            }
+            _ => CommandResponse::ok(),
+        }
+    }
+
+    /// 允许执行运维命令，用于管理端口。clone 出来的其它 Service 不受影响
+    pub fn with_admin_listener(mut self) -> Self {
+        self.admin = true;
+        self
+    }
+
+    /// 开启过载保护
+    pub fn with_load_shedding(mut self, shedder: Arc<LoadShedder>) -> Self {
+        self.shedder = Some(shedder);
+        self
+    }
+
+    /// 开始处理一个请求，过载保护据此统计处理中的请求数。没有开启过载保护时为 None
+    pub fn track_request(&self) -> Option<InFlightGuard> {
+        self.shedder.as_ref().map(|shedder| shedder.track())
+    }
+
+    /// 作为 primary 运行，写操作会追加到复制日志
+    pub fn with_replication(mut self, log: ReplicationLog) -> Self {
+        self.replication = Some(Arc::new(log));
+        self
+    }
+
+    /// 作为 replica 运行，拒绝客户端的写操作
+    pub fn read_only(self) -> Self {
+        self.read_only.store(true, Ordering::SeqCst);
+        self
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    /// 把 replica 提升为 primary，返回新的 epoch
+    ///
+    /// epoch 为 0 时使用当前 epoch 加一，否则必须比当前 epoch 大。
+    /// 提升之后开始接受写操作，并停止从原来的 primary 复制
+    pub fn promote(&self, epoch: u64) -> Result<u64, KvError> {
+        let log = self.replication.as_ref().ok_or_else(|| {
+            KvError::InvalidCommand("failover is not enabled on this node".into())
+        })?;
+        if !self.is_read_only() {
+            return Err(KvError::InvalidCommand("node is already primary".into()));
+        }
+        let epoch = match epoch {
+            0 => log.epoch() + 1,
+            epoch if epoch > log.epoch() => epoch,
+            epoch => {
+                return Err(KvError::InvalidCommand(format!(
+                    "epoch {} is not newer than {}",
+                    epoch,
+                    log.epoch()
+                )));
+            }
+        };
+        log.set_epoch(epoch);
+        self.read_only.store(false, Ordering::SeqCst);
+        info!("Promoted to primary at epoch {}", epoch);
+        Ok(epoch)
+    }
+
+    /// 复制日志，没有开启复制时为 None
+    pub fn replication(&self) -> Option<&ReplicationLog> {
+        self.replication.as_deref()
+    }
+
+    /// 以 Raft 集群节点的身份运行，读写命令由 leader 处理
+    pub fn with_raft(mut self, raft: Arc<RaftNode>) -> Self {
+        self.raft = Some(raft);
+        self
+    }
+
+    /// 作为分片集群的节点运行
+    pub fn with_sharding(mut self, shard: ShardRouter) -> Self {
+        self.shard = Some(Arc::new(shard));
+        self
+    }
+
+    /// 分片集群的 slot 分布，没有开启分片时为 None
+    pub fn slot_map(&self) -> Option<&SlotMap> {
+        self.shard.as_ref().map(|shard| shard.slots())
+    }
+
+    /// 开启集群成员管理，响应 CLUSTER 和 GOSSIP 命令
+    pub fn with_membership(mut self, membership: Arc<Membership>) -> Self {
+        self.membership = Some(membership);
+        self
+    }
+
+    /// 集群成员管理，没有开启时为 None
+    pub fn membership(&self) -> Option<&Membership> {
+        self.membership.as_deref()
+    }
+
+    /// 以多主模式运行，响应其它节点的 CRDTSYNC 命令
+    pub fn with_multi_master(mut self, multi_master: Arc<MultiMaster>) -> Self {
+        self.multi_master = Some(multi_master);
+        self
+    }
+
+    /// 多主模式，没有开启时为 None
+    pub fn multi_master(&self) -> Option<&MultiMaster> {
+        self.multi_master.as_deref()
+    }
+
+    /// 当前所有连接的注册表，网络层 accept 连接后在这里注册
+    pub fn connections(&self) -> &Arc<ConnectionRegistry> {
+        &self.connections
+    }
+
+    /// 清理某个连接上还有效的订阅，对应的订阅 stream 随之结束
+    pub fn remove_subscriptions(&self, conn: &ConnectionStats) {
+        for (topic, id) in conn.active_subscriptions() {
+            self.broadcaster.remove_subscription(&topic, id);
+        }
+    }
+
+    /// 在进程内订阅变更流，和 HWATCH 一样，第一个消息是 watch id
+    pub fn watch_changes(&self, table: impl Into<String>) -> StreamingResponse {
+        self.changes.watch(table.into(), None)
+    }
+
+    /// 每种命令最近的延迟统计
+    pub fn latency_stats(&self) -> Vec<LatencyStats> {
+        self.latency.stats()
+    }
+
+    /// 所有 pub/sub 主题的 metrics
+    pub fn topic_metrics(&self) -> Vec<TopicMetrics> {
+        self.broadcaster.metrics()
+    }
+
+    /// 执行命令，hook 拿到的连接信息为空
     pub fn execute(&self, cmd: CommandRequest) -> StreamingResponse {
+        self.execute_with(cmd, &RequestContext::default())
+    }
+
+    /// 执行命令，ctx 是请求所在连接的信息，会传给所有 hook
+    #[instrument(name = "service_execute", skip_all)]
+    pub fn execute_with(&self, cmd: CommandRequest, ctx: &RequestContext) -> StreamingResponse {
+        // 不在日志里记录密码
+        if let Some(RequestData::Auth(param)) = &cmd.request_data {
+            let res = self.authenticate(&param.password);
+            return unary(res);
+        }
         debug!("Got request: {:?}", cmd);
+        let hooks = self.hook_chain();
+        hooks.received.notify(ctx, &cmd);
+        if let Some(filter) = &self.commands
+            && let Err(e) = filter.check(&cmd)
+        {
+            let res: CommandResponse = e.into();
+            return unary(res);
+        }
+        let tenant = self
+            .namespaces
+            .as_ref()
+            .and_then(|namespaces| namespaces.tenant(ctx.identity.as_deref()));
+        let cmd = match tenant {
+            Some(tenant) => match self.scope(tenant, cmd) {
+                Ok(cmd) => cmd,
+                Err(e) => return unary(e.into()),
+            },
+            None => cmd,
+        };
+        if let Err(e) = self.check_request_size(&cmd) {
+            return unary(e.into());
+        }
+        let table = match &cmd.request_data {
+            Some(RequestData::Hwatch(param)) => Some(param.table.as_str()),
+            _ => cmd.table(),
+        };
+        if let Some(table) = table.filter(|table| is_reserved_table(table)) {
+            let res = KvError::PermissionDenied(format!("table {} is reserved", table));
+            return unary(res.into());
+        }
         // self.store.deref()解引用 Arc<dyn Storage> -> &dyn Storage
         // self.store.as_ref()同理
-        let mut res = dispatch(cmd.clone(), self.store.as_ref());
+        match &cmd.request_data {
+            Some(RequestData::Replicate(param)) => return self.replicate(param.snapshot),
+            Some(RequestData::Hwatch(param)) => {
+                // 快照和 HGETALL 一样受 max_result_entries 限制
+                let read = |table: &str| self.read(CommandRequest::new_hgetall(table));
+                let snapshot = param.snapshot.then_some(&read as &dyn Fn(&str) -> _);
+                let res = self.changes.watch(param.table.clone(), snapshot);
+                return match tenant {
+                    Some(tenant) => tenant.unscope_changes(res),
+                    None => res,
+                };
+            }
+            _ => {}
+        }
+
+        if let Some(RequestData::CrdtSync(_)) = &cmd.request_data
+            && let Some(mm) = &self.multi_master
+            && !mm.is_peer(ctx.peer_addr)
+        {
+            let res = KvError::PermissionDenied(
+                "crdt sync is only accepted from multi_master.peers".into(),
+            );
+            return unary(res.into());
+        }
+        if cmd.is_admin() && !self.allows_admin(ctx) {
+            let res: CommandResponse = KvError::PermissionDenied(format!(
+                "{} requires the admin listener or the admin role",
+                cmd.name()
+            ))
+            .into();
+            return unary(res);
+        }
+        if let Some(res) = self.snapshot_command(&cmd) {
+            return res;
+        }
+
+        if let Some(shedder) = &self.shedder
+            && shedder.should_reject(&cmd)
+        {
+            let res: CommandResponse =
+                KvError::Busy(format!("{} requests in flight", shedder.in_flight())).into();
+            return unary(res);
+        }
+
+        if let Some(cache) = &self.idempotency
+            && !cmd.idempotency_key.is_empty()
+            && let Some(res) = cache.get(&cmd.idempotency_key)
+        {
+            debug!(
+                "Duplicate request with idempotency key {}",
+                cmd.idempotency_key
+            );
+            return unary(res);
+        }
+
+        if let Some(shadow) = &self.shadow {
+            shadow.offer(&cmd);
+        }
+
+        // 请求从这里开始只沿着一条路径移动，不再复制
+        let cmd = match self.shard_execute(cmd) {
+            ControlFlow::Break(res) => return res,
+            ControlFlow::Continue(cmd) => cmd,
+        };
+        let cmd = match self.raft_execute(cmd, ctx) {
+            ControlFlow::Break(res) => return res,
+            ControlFlow::Continue(cmd) => cmd,
+        };
+
+        // pub/sub 命令的响应是持续的流，不统计延迟，也不经过 hook
+        if cmd.is_pubsub() {
+            let res = dispatch_stream(cmd, Arc::clone(&self.broadcaster));
+            return match tenant {
+                Some(tenant) => tenant.unscope_subscription(res),
+                None => res,
+            };
+        }
+
+        let start = Instant::now();
+        let name = cmd.name();
+        let mut res = match cmd.modified_keys() {
+            Some(_) if self.is_read_only() => {
+                KvError::PermissionDenied("replica is read-only".into()).into()
+            }
+            Some(_) => self.execute_write(cmd),
+            None if cmd.is_read() || cmd.request_data.is_none() => self.read(cmd),
+            None => dispatch_admin(cmd, self).unwrap_or_else(|| {
+                KvError::InvalidCommand(format!("{} is not supported here", name)).into()
+            }),
+        };
         debug!("Executed response: {:?}", res);
+        self.latency.record(name, start.elapsed());
+
+        hooks.executed.notify(ctx, &res);
+        hooks.before_send.notify(ctx, &mut res);
+        if !hooks.before_send.is_empty() {
+            debug!("Modified response: {:?}", res);
+        }
+        if hooks.has_async() {
+            let ctx = ctx.clone();
+            return Box::pin(stream::once(async move {
+                hooks.run_async(&ctx, res).await.into_shared()
+            }));
+        }
+        unary(res)
+    }
+
+    /// 把租户的命令放进它的 namespace，再检查配额
+    fn scope(&self, tenant: &Tenant, cmd: CommandRequest) -> Result<CommandRequest, KvError> {
+        let cmd = tenant.scope(cmd)?;
+        tenant.check_quota(&cmd, self.store.as_ref(), &self.broadcaster)?;
+        Ok(cmd)
+    }
+
+    /// 检查命令包含的 pair、key 或者 value 数，以及 HSET/HMSET 写入的 key 和 value 是否超过上限
+    fn check_request_size(&self, cmd: &CommandRequest) -> Result<(), KvError> {
+        if let Some(limit) = self.max_request_entries
+            && cmd.entries() > limit
+        {
+            return Err(KvError::RequestTooLarge(format!(
+                "{} has {} entries, more than {}",
+                cmd.name(),
+                cmd.entries(),
+                limit
+            )));
+        }
+        if self.max_key_bytes.is_none() && self.max_value_bytes.is_none() {
+            return Ok(());
+        }
+        let (table, pairs) = match &cmd.request_data {
+            Some(RequestData::Hset(param)) => (&param.table, param.pair.as_slice()),
+            Some(RequestData::Hmset(param)) => (&param.table, param.pairs.as_slice()),
+            _ => return Ok(()),
+        };
+        for pair in pairs {
+            self.check_entry_size(table, &pair.key, pair.value.as_ref())?;
+        }
+        Ok(())
+    }
 
-        if res == CommandResponse::default() {
-            dispatch_stream(cmd, Arc::clone(&self.broadcaster))
-        } else {
-            debug!("Executed response returned: {:?}", res);
-            self.on_executed.notify(&res);
-            self.on_before_send.notify(&mut res);
-            if !self.on_before_send.is_empty() {
-                debug!("Modified response: {:?}", res);
+    /// 运维命令只能在管理端口上、由 admin 角色的身份或者进程内（没有对端地址）执行
+    fn allows_admin(&self, ctx: &RequestContext) -> bool {
+        self.admin || ctx.role == Some(AccessRole::Admin) || ctx.peer_addr.is_none()
+    }
+
+    /// 检查一个 key 和 value 是否超过 max_key_bytes 和 max_value_bytes
+    fn check_entry_size(
+        &self,
+        table: &str,
+        key: &str,
+        value: Option<&Value>,
+    ) -> Result<(), KvError> {
+        let max_key = self.max_key_bytes.unwrap_or(usize::MAX);
+        let max_value = self.max_value_bytes.unwrap_or(usize::MAX);
+        if key.len() > max_key {
+            return Err(KvError::EntryTooLarge(format!(
+                "key in {} is {} bytes, more than {}",
+                table,
+                key.len(),
+                max_key
+            )));
+        }
+        let len = value.map_or(0, |v| v.encoded_len());
+        if len > max_value {
+            return Err(KvError::EntryTooLarge(format!(
+                "value of {}/{} is {} bytes, more than {}",
+                table, key, len, max_value
+            )));
+        }
+        Ok(())
+    }
+
+    /// 执行写命令：记录复制日志或者 CRDT 的版本，写成功后发布 keyspace 通知，
+    /// 客户端据此让本地缓存失效。通知和幂等的 key 在请求被移走之前取出
+    fn execute_write(&self, cmd: CommandRequest) -> CommandResponse {
+        let event = self.keyspace_event(&cmd);
+        let idempotency_key = self.idempotency_key(&cmd);
+        let res = match (&self.replication, &self.multi_master) {
+            // 复制日志要保存一份请求，这里的复制省不掉
+            (Some(log), _) => log.apply(&cmd, || self.write(cmd.clone())),
+            (_, Some(mm)) => mm.apply(&cmd, || self.write(cmd.clone())),
+            _ => self.write(cmd),
+        };
+        self.remember(idempotency_key, &res);
+        if res.status == 200 {
+            self.notify_keyspace(event);
+        }
+        res
+    }
+
+    /// FLUSHALL：逐个 table 执行 HDELPREFIX，和其它写操作一样记录复制日志，发布 keyspace 通知和变更。
+    /// Raft 的日志和多主的版本都是按 key 记录的，这两种模式下不支持
+    fn flushall(&self) -> CommandResponse {
+        if self.raft.is_some() || self.multi_master.is_some() {
+            let msg = "flushall is not supported with raft or multi-master";
+            return KvError::InvalidCommand(msg.into()).into();
+        }
+        let tables = match self.store.tables() {
+            Ok(tables) => tables,
+            Err(e) => return e.into(),
+        };
+        let mut count = 0;
+        for table in tables.into_iter().filter(|t| !is_reserved_table(t)) {
+            let res = self.execute_write(CommandRequest::new_hdelprefix(table, ""));
+            if res.status != 200 {
+                return res;
             }
-            Box::pin(stream::once(async { Arc::new(res) }))
+            count += res
+                .values
+                .first()
+                .and_then(|v| i64::try_from(v).ok())
+                .unwrap_or(0);
+        }
+        Value::from(count).into()
+    }
+
+    /// replica 执行从 primary 收到的写操作，同样会发布 keyspace 通知和变更。
+    /// 开启 Raft 时，等到 read index 之后的读命令也从这里执行，和直接执行的读命令一样受 max_result_entries 限制
+    pub fn apply_replicated(&self, cmd: CommandRequest) -> CommandResponse {
+        if cmd.is_read() {
+            return self.read(cmd);
         }
+        let event = self.keyspace_event(&cmd);
+        let res = self.write(cmd);
+        if res.status == 200 {
+            self.notify_keyspace(event);
+        }
+        res
+    }
+
+    /// 执行读命令，结果超过 max_result_entries 的 HGETALL/HMGET 返回错误
+    fn read(&self, cmd: CommandRequest) -> CommandResponse {
+        let store = self.store.as_ref();
+        let Some(limit) = self.max_result_entries else {
+            return dispatch(cmd, store);
+        };
+        match &cmd.request_data {
+            Some(RequestData::Hmget(param)) if param.keys.len() > limit => {
+                KvError::ResultTooLarge(limit).into()
+            }
+            // 最多读出 limit + 1 个就知道是否超过上限，不用把整个 table 读出来
+            Some(RequestData::Hgetall(param)) => match store.get_iter(&param.table) {
+                Ok(iter) => {
+                    let iter = iter.filter(|pair| param.matches(pair));
+                    let pairs: Vec<_> = iter.take(limit + 1).collect();
+                    match pairs.len() > limit {
+                        true => KvError::ResultTooLarge(limit).into(),
+                        false => pairs.into(),
+                    }
+                }
+                Err(e) => e.into(),
+            },
+            _ => dispatch(cmd, store),
+        }
+    }
+
+    /// 执行写命令，有 HWATCH 时把变更发给 watcher
+    fn write(&self, cmd: CommandRequest) -> CommandResponse {
+        let store = self.store.as_ref();
+        self.changes.apply(cmd, store, |cmd| dispatch(cmd, store))
+    }
+
+    /// 数据命令的 table 不在本节点时，按配置重定向或者代理给所在的节点，
+    /// 在本节点执行的命令原样还给调用者
+    fn shard_execute(&self, cmd: CommandRequest) -> ControlFlow<StreamingResponse, CommandRequest> {
+        let Some(shard) = self.shard.as_ref() else {
+            return ControlFlow::Continue(cmd);
+        };
+        let Some((slot, addr)) = shard.route(&cmd) else {
+            return ControlFlow::Continue(cmd);
+        };
+        let res = match shard.mode() {
+            ShardMode::Redirect => KvError::Moved(slot, addr.into()).into(),
+            ShardMode::Proxy => {
+                let shard = Arc::clone(shard);
+                return ControlFlow::Break(Box::pin(stream::once(async move {
+                    shard.forward(slot, cmd).await.into_shared()
+                })));
+            }
+        };
+        ControlFlow::Break(unary(res))
     }
 
-    // 修改注册方法，使用新的函数签名
-    pub fn fn_received(mut self, f: fn(&CommandRequest) -> Option<CommandResponse>) -> Self {
-        self.on_received.push(f);
+    /// 处理 Raft 节点之间的 RPC，以及开启 Raft 时的读写命令，其它命令原样还给调用者
+    fn raft_execute(
+        &self,
+        cmd: CommandRequest,
+        ctx: &RequestContext,
+    ) -> ControlFlow<StreamingResponse, CommandRequest> {
+        let res = match (&self.raft, &cmd.request_data) {
+            (Some(raft), Some(RequestData::RaftVote(_) | RequestData::RaftAppend(_)))
+                if !raft.is_peer(ctx.peer_addr) =>
+            {
+                KvError::PermissionDenied("raft rpc is only accepted from raft.peers".into()).into()
+            }
+            (Some(raft), Some(RequestData::RaftVote(req))) => raft.handle_vote(req),
+            (Some(raft), Some(RequestData::RaftAppend(req))) => raft.handle_append(req),
+            (None, Some(RequestData::RaftVote(_) | RequestData::RaftAppend(_))) => {
+                KvError::InvalidCommand("raft is not enabled".into()).into()
+            }
+            (Some(raft), _) if cmd.is_read() || cmd.modified_keys().is_some() => {
+                let (raft, svc, ctx) = (Arc::clone(raft), self.clone(), ctx.clone());
+                let (name, idempotency_key) = (cmd.name(), self.idempotency_key(&cmd));
+                return ControlFlow::Break(Box::pin(stream::once(async move {
+                    let start = Instant::now();
+                    let mut res = raft.execute(cmd).await;
+                    svc.remember(idempotency_key, &res);
+                    svc.latency.record(name, start.elapsed());
+                    let hooks = svc.hook_chain();
+                    hooks.executed.notify(&ctx, &res);
+                    hooks.before_send.notify(&ctx, &mut res);
+                    hooks.run_async(&ctx, res).await.into_shared()
+                })));
+            }
+            _ => return ControlFlow::Continue(cmd),
+        };
+        ControlFlow::Break(unary(res))
+    }
+
+    /// 开启幂等时，写命令需要记住响应的 key
+    fn idempotency_key(&self, cmd: &CommandRequest) -> Option<String> {
+        (self.idempotency.is_some() && cmd.modified_keys().is_some())
+            .then(|| cmd.idempotency_key.clone())
+    }
+
+    /// 记住带幂等 key 的写命令的响应
+    fn remember(&self, idempotency_key: Option<String>, res: &CommandResponse) {
+        if let (Some(cache), Some(key)) = (&self.idempotency, idempotency_key) {
+            cache.insert(&key, res);
+        }
+    }
+
+    fn replicate(&self, snapshot: bool) -> StreamingResponse {
+        let res = match &self.replication {
+            // 还没有提升为 primary 的 replica 不能被复制
+            Some(_) if self.is_read_only() => {
+                KvError::InvalidCommand("replica cannot be replicated".into()).into()
+            }
+            Some(log) => return log.stream(snapshot.then(|| Arc::clone(&self.store))),
+            None => KvError::InvalidCommand("replication is not enabled".into()).into(),
+        };
+        unary(res)
+    }
+
+    /// SAVE 和 RESTORE 在 blocking 线程里读写备份文件，不阻塞请求的处理，其它命令返回 None。
+    /// RESTORE 把备份里的数据当成 HMSET 执行，和普通的写操作一样复制给 replica、发布通知
+    fn snapshot_command(&self, cmd: &CommandRequest) -> Option<StreamingResponse> {
+        let (name, file) = match &cmd.request_data {
+            Some(RequestData::Save(param)) => ("save", &param.name),
+            Some(RequestData::Restore(_)) if self.is_read_only() => {
+                let res = KvError::PermissionDenied("replica is read-only".into());
+                return Some(unary(res.into()));
+            }
+            // Raft 的写操作要经过日志，这里的写入会绕过它
+            Some(RequestData::Restore(_)) if self.raft.is_some() => {
+                let res = KvError::InvalidCommand("restore is not supported with raft".into());
+                return Some(unary(res.into()));
+            }
+            Some(RequestData::Restore(param)) => ("restore", &param.name),
+            _ => return None,
+        };
+        let path = match self.backup_path(name, file) {
+            Ok(path) => path,
+            Err(e) => return Some(unary(e.into())),
+        };
+        let svc = self.clone();
+        let task = move || match name {
+            "save" => save_snapshot(svc.store.as_ref(), &path),
+            // 内部的 table 属于保存备份的那个节点，不恢复
+            _ => replay_snapshot(&path, |record| {
+                if is_reserved_table(&record.table) {
+                    return Ok(());
+                }
+                let res = svc.execute_write(CommandRequest::new_hmset(record.table, record.pairs));
+                match res.status {
+                    200 => Ok(()),
+                    _ => Err(KvError::Internal(format!(
+                        "restore failed: {}",
+                        res.message
+                    ))),
+                }
+            }),
+        };
+        Some(Box::pin(stream::once(async move {
+            let res: CommandResponse = match tokio::task::spawn_blocking(task).await {
+                Ok(Ok(count)) => Value::from(count as i64).into(),
+                Ok(Err(e)) => e.into(),
+                Err(e) => KvError::Internal(format!("{} failed: {}", name, e)).into(),
+            };
+            res.into_shared()
+        })))
+    }
+
+    /// 备份目录里名为 file 的文件。只接受文件名，绝对路径和带目录的路径（包括 ..）都会被拒绝，
+    /// 客户端不能读写备份目录之外的文件
+    fn backup_path(&self, name: &str, file: &str) -> Result<PathBuf, KvError> {
+        let Some(dir) = &self.backup_dir else {
+            let msg = format!("{} is disabled: backup directory is not configured", name);
+            return Err(KvError::InvalidCommand(msg));
+        };
+        if file.is_empty() {
+            return Err(KvError::InvalidCommand(format!(
+                "{} requires a file name",
+                name
+            )));
+        }
+        let mut components = Path::new(file).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(f)), None) if f == file => Ok(dir.join(f)),
+            _ => Err(KvError::InvalidCommand(format!(
+                "{} only accepts a file name in the backup directory, got {}",
+                name, file
+            ))),
+        }
+    }
+
+    /// 写命令成功之后发布的 keyspace 通知：topic 和修改的 key。
+    /// HDELPREFIX 删除的 key 要在执行之前读出来，没有订阅者时不读
+    fn keyspace_event(&self, cmd: &CommandRequest) -> Option<(Arc<str>, CommandResponse)> {
+        let (table, keys) = cmd.modified_keys()?;
+        let topic = topic::interned_keyspace_topic(table);
+        let keys: Vec<Value> = match &cmd.request_data {
+            Some(RequestData::Hdelprefix(param)) => {
+                if !self.broadcaster.has_subscribers(&topic) {
+                    return None;
+                }
+                let iter = self.store.get_prefix(table, &param.prefix).ok()?;
+                iter.map(|pair| Value::from(pair.key)).collect()
+            }
+            _ => keys.into_iter().map(Value::from).collect(),
+        };
+        Some((topic, keys.into()))
+    }
+
+    fn notify_keyspace(&self, event: Option<(Arc<str>, CommandResponse)>) {
+        if let Some((topic, res)) = event {
+            Arc::clone(&self.broadcaster).publish(topic, Arc::new(res));
+        }
+    }
+
+    /// 网络层每发出一个响应调用一次，通知 after_send 的 hook
+    pub fn notify_sent(&self, ctx: &RequestContext) {
+        for f in self.hook_chain().after_send.iter() {
+            if f(ctx).is_some() {
+                break;
+            }
+        }
+    }
+
+    // 修改注册方法，使用新的函数签名，所有 hook 都能拿到请求所在连接的信息。
+    // fn_* 注册的 hook 没有名字，使用默认优先级。
+    // hook 由所有 clone 共享，在 clone 上注册的 hook 对原来的 Service 同样生效
+    pub fn fn_received(self, f: ReceivedHook) -> Self {
+        self.update_hooks(|h| h.received.push(f));
+        self
+    }
+
+    pub fn fn_executed(self, f: ExecutedHook) -> Self {
+        self.update_hooks(|h| h.executed.push(f));
+        self
+    }
+
+    pub fn fn_before_send(self, f: BeforeSendHook) -> Self {
+        self.update_hooks(|h| h.before_send.push(f));
+        self
+    }
+
+    pub fn fn_after_send(self, f: AfterSendHook) -> Self {
+        self.update_hooks(|h| h.after_send.push(f));
+        self
+    }
+
+    /// 注册有名字和优先级的 hook，优先级小的先执行，比如认证类的 hook 使用 AUTH_PRIORITY，
+    /// 不管注册的顺序如何都在限流和统计之前执行。同一个阶段里同名的 hook 会被替换
+    pub fn hook_received(self, name: impl Into<String>, priority: i32, f: ReceivedHook) -> Self {
+        self.update_hooks(|h| h.received.insert(name, priority, f));
+        self
+    }
+
+    pub fn hook_executed(self, name: impl Into<String>, priority: i32, f: ExecutedHook) -> Self {
+        self.update_hooks(|h| h.executed.insert(name, priority, f));
         self
     }
 
-    pub fn fn_executed(mut self, f: fn(&CommandResponse) -> Option<CommandResponse>) -> Self {
-        self.on_executed.push(f);
+    pub fn hook_before_send(
+        self,
+        name: impl Into<String>,
+        priority: i32,
+        f: BeforeSendHook,
+    ) -> Self {
+        self.update_hooks(|h| h.before_send.insert(name, priority, f));
+        self
+    }
+
+    pub fn hook_after_send(self, name: impl Into<String>, priority: i32, f: AfterSendHook) -> Self {
+        self.update_hooks(|h| h.after_send.insert(name, priority, f));
         self
     }
 
-    pub fn fn_before_send(
-        mut self,
-        f: fn(&mut CommandResponse) -> Option<CommandResponse>,
+    /// 注册异步的 executed hook，在同步的 executed hook 之后执行。用 async_executed 包装闭包：
+    /// `service.hook_executed_async("audit", METRICS_PRIORITY, async_executed(|ctx, res| async move { ... }))`
+    pub fn hook_executed_async(
+        self,
+        name: impl Into<String>,
+        priority: i32,
+        f: AsyncExecutedHook,
     ) -> Self {
-        self.on_before_send.push(f);
+        self.update_hooks(|h| h.executed_async.insert(name, priority, f));
         self
     }
 
-    pub fn fn_after_send(mut self, f: fn() -> Option<CommandResponse>) -> Self {
-        self.on_after_send.push(f);
+    /// 注册异步的 before_send hook，在同步的 before_send hook 之后执行，比如调用外部的策略服务改写响应
+    pub fn hook_before_send_async(
+        self,
+        name: impl Into<String>,
+        priority: i32,
+        f: AsyncBeforeSendHook,
+    ) -> Self {
+        self.update_hooks(|h| h.before_send_async.insert(name, priority, f));
         self
     }
+
+    /// 已经注册的 hook，按阶段和执行顺序排列
+    pub fn hooks(&self) -> Vec<HookInfo> {
+        self.hook_chain().info()
+    }
+
+    /// 在运行中的 Service 上修改 hook，比如临时打开一个调试用的 hook，不需要重启。
+    /// 修改对所有 clone 生效，之后开始执行的命令使用新的 hook，正在执行的命令不受影响
+    ///
+    /// ```ignore
+    /// service.update_hooks(|h| h.before_send.insert("debug-tap", METRICS_PRIORITY, tap));
+    /// // ...
+    /// service.remove_hook(HookStage::BeforeSend, "debug-tap");
+    /// ```
+    pub fn update_hooks<R>(&self, f: impl FnOnce(&mut Hooks) -> R) -> R {
+        let mut current = self.hooks.write().unwrap();
+        let mut hooks = Hooks::clone(&current);
+        let ret = f(&mut hooks);
+        *current = Arc::new(hooks);
+        ret
+    }
+
+    /// 删除一个阶段里指定名字的 hook，同步和异步的都会删除，返回是否存在
+    pub fn remove_hook(&self, stage: HookStage, name: &str) -> bool {
+        self.update_hooks(|h| match stage {
+            HookStage::Received => h.received.remove(name),
+            HookStage::Executed => h.executed.remove(name) | h.executed_async.remove(name),
+            HookStage::BeforeSend => h.before_send.remove(name) | h.before_send_async.remove(name),
+            HookStage::AfterSend => h.after_send.remove(name),
+        })
+    }
+
+    /// 当前 hook 的快照
+    fn hook_chain(&self) -> Arc<Hooks> {
+        Arc::clone(&self.hooks.read().unwrap())
+    }
 }
 
 // 从 Request 中得到 Response，目前处理 HGET/HGETALL/HSET
@@ -105,10 +1073,13 @@ pub fn dispatch(cmd: CommandRequest, store: &dyn Storage) -> CommandResponse {
     match cmd.request_data {
         Some(RequestData::Hget(param)) => param.execute(store),
         Some(RequestData::Hgetall(param)) => param.execute(store),
+        Some(RequestData::Hmget(param)) => param.execute(store),
         Some(RequestData::Hset(param)) => param.execute(store),
         Some(RequestData::Hmset(param)) => param.execute(store),
         Some(RequestData::Hdel(param)) => param.execute(store),
         Some(RequestData::Hmdel(param)) => param.execute(store),
+        Some(RequestData::Hdelprefix(param)) => param.execute(store),
+        Some(RequestData::Htableexists(param)) => param.execute(store),
         Some(RequestData::Hexist(param)) => param.execute(store),
         Some(RequestData::Hmexist(param)) => param.execute(store),
         None => KvError::InvalidCommand("Request has no data".into()).into(),
@@ -117,6 +1088,36 @@ pub fn dispatch(cmd: CommandRequest, store: &dyn Storage) -> CommandResponse {
     }
 }
 
+/// 只有一个响应的流，常见的响应使用共享的实例
+pub fn unary(res: CommandResponse) -> StreamingResponse {
+    Box::pin(stream::once(future::ready(res.into_shared())))
+}
+
+/// 处理管理类命令，不是管理类命令时返回 None
+pub fn dispatch_admin(cmd: CommandRequest, svc: &Service) -> Option<CommandResponse> {
+    match cmd.request_data {
+        Some(RequestData::ClientList(param)) => Some(param.execute(svc)),
+        Some(RequestData::ClientKill(param)) => Some(param.execute(svc)),
+        Some(RequestData::Latency(param)) => Some(param.execute(svc)),
+        Some(RequestData::Jobs(param)) => Some(param.execute(svc)),
+        Some(RequestData::Reload(param)) => Some(param.execute(svc)),
+        Some(RequestData::Flushall(param)) => Some(param.execute(svc)),
+        Some(RequestData::ClusterSlots(param)) => Some(param.execute(svc)),
+        Some(RequestData::Cluster(param)) => Some(param.execute(svc)),
+        Some(RequestData::Gossip(param)) => Some(param.execute(svc)),
+        Some(RequestData::ReplicaAck(param)) => Some(param.execute(svc)),
+        Some(RequestData::Promote(param)) => Some(param.execute(svc)),
+        Some(RequestData::CrdtSync(param)) => Some(param.execute(svc)),
+        Some(RequestData::Hdebug(param)) => Some(param.execute(svc)),
+        _ => None,
+    }
+}
+
+/// 比较的时间只和长度有关，避免通过响应时间猜出密码
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 /// 从 Request 中得到 Response，目前处理所有 PUBLISH/SUBSCRIBE/UNSUBSCRIBE
 pub fn dispatch_stream(cmd: CommandRequest, topic: impl Topic) -> StreamingResponse {
     match cmd.request_data {
@@ -130,19 +1131,19 @@ pub fn dispatch_stream(cmd: CommandRequest, topic: impl Topic) -> StreamingRespo
 
 /// 事件通知（不可变事件）
 pub trait Notify<Arg> {
-    fn notify(&self, arg: &Arg) -> Option<CommandResponse>;
+    fn notify(&self, ctx: &RequestContext, arg: &Arg) -> Option<CommandResponse>;
 }
 
 /// 事件通知（可变事件）
 pub trait NotifyMut<Arg> {
-    fn notify(&self, arg: &mut Arg) -> Option<CommandResponse>;
+    fn notify(&self, ctx: &RequestContext, arg: &mut Arg) -> Option<CommandResponse>;
 }
 
-impl<Arg> Notify<Arg> for Vec<fn(&Arg) -> Option<CommandResponse>> {
+impl<Arg> Notify<Arg> for HookChain<fn(&RequestContext, &Arg) -> Option<CommandResponse>> {
     #[inline]
-    fn notify(&self, arg: &Arg) -> Option<CommandResponse> {
-        for f in self {
-            if let Some(res) = f(arg) {
+    fn notify(&self, ctx: &RequestContext, arg: &Arg) -> Option<CommandResponse> {
+        for f in self.iter() {
+            if let Some(res) = f(ctx, arg) {
                 return Some(res);
             }
         }
@@ -150,11 +1151,11 @@ impl<Arg> Notify<Arg> for Vec<fn(&Arg) -> Option<CommandResponse>> {
     }
 }
 
-impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg) -> Option<CommandResponse>> {
+impl<Arg> NotifyMut<Arg> for HookChain<fn(&RequestContext, &mut Arg) -> Option<CommandResponse>> {
     #[inline]
-    fn notify(&self, arg: &mut Arg) -> Option<CommandResponse> {
-        for f in self {
-            if let Some(res) = f(arg) {
+    fn notify(&self, ctx: &RequestContext, arg: &mut Arg) -> Option<CommandResponse> {
+        for f in self.iter() {
+            if let Some(res) = f(ctx, arg) {
                 return Some(res);
             }
         }
@@ -187,7 +1188,10 @@ pub fn assert_res_error(res: &CommandResponse, code: u32, msg: &str) {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{MemTable, Value};
+    use crate::{
+        AuthConfig, CrdtEntry, Dot, Flushall, Kvpair, MemTable, MultiMasterConfig, OverloadConfig,
+        RaftConfig, RaftVote, ServerConfig, TenantConfig, Value, Version,
+    };
     use http::StatusCode;
     use tokio_stream::StreamExt;
     use tracing::info;
@@ -215,27 +1219,160 @@ mod tests {
         assert_res_ok(&data, &["v1".into()], &[]);
     }
 
+    #[tokio::test]
+    async fn admin_commands_should_require_admin_listener_or_role() {
+        let data = Service::new(MemTable::new());
+        let admin = data.clone().with_admin_listener();
+        let remote = RequestContext {
+            peer_addr: Some("10.0.0.1:5000".parse().unwrap()),
+            authenticated: true,
+            ..Default::default()
+        };
+        let admin_role = RequestContext {
+            role: Some(AccessRole::Admin),
+            ..remote.clone()
+        };
+
+        // 没有管理端口也没有角色的远程连接不能执行运维命令，数据命令不受影响
+        for cmd in [
+            CommandRequest::new_client_list(),
+            CommandRequest::new_flushall(Flushall::CONFIRM),
+            CommandRequest::new_promote(1),
+        ] {
+            let res = data.execute_with(cmd, &remote).next().await.unwrap();
+            assert_eq!(res.status, 403);
+        }
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = data.execute_with(cmd, &remote).next().await.unwrap();
+        assert_eq!(res.status, 200);
+
+        // 管理端口、admin 角色和进程内的调用可以执行
+        let cmd = CommandRequest::new_client_list();
+        let res = admin
+            .execute_with(cmd.clone(), &remote)
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(res.status, 200);
+        let res = data
+            .execute_with(cmd.clone(), &admin_role)
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(res.status, 200);
+        let res = data.execute(cmd).next().await.unwrap();
+        assert_eq!(res.status, 200);
+    }
+
+    #[tokio::test]
+    async fn tenants_should_be_isolated_by_namespace() {
+        let tenant = |namespace: &str| TenantConfig {
+            namespace: namespace.into(),
+            ..Default::default()
+        };
+        let config = AuthConfig {
+            tenants: [
+                ("alice".into(), tenant("app1")),
+                ("bob".into(), tenant("app2")),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let service = Service::new(MemTable::new()).with_namespaces(Namespaces::new(&config));
+        let ctx = |identity: &str| RequestContext {
+            identity: Some(identity.into()),
+            ..Default::default()
+        };
+        let (alice, bob) = (ctx("alice"), ctx("bob"));
+
+        let hset = CommandRequest::new_hset("t1", "k1", "v1".into());
+        service.execute_with(hset, &alice).next().await.unwrap();
+        let hget = CommandRequest::new_hget("t1", "k1");
+        let res = service
+            .execute_with(hget.clone(), &alice)
+            .next()
+            .await
+            .unwrap();
+        assert_res_ok(&res, &["v1".into()], &[]);
+        // 其它租户看不到，没有绑定租户的连接看到的是加了前缀的 table
+        let res = service.execute_with(hget, &bob).next().await.unwrap();
+        assert_eq!(res.status, 404);
+        let hget = CommandRequest::new_hget("app1:t1", "k1");
+        let res = service.execute(hget).next().await.unwrap();
+        assert_res_ok(&res, &["v1".into()], &[]);
+
+        // 订阅确认和变更里是租户自己的名字
+        let mut sub = service.execute_with(CommandRequest::new_subscribe("lobby"), &alice);
+        let ack = sub.next().await.unwrap();
+        assert_eq!(ack.subscription.as_ref().unwrap().topic, "lobby");
+        let publish = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        let res = service.execute_with(publish.clone(), &bob).next().await;
+        assert_res_ok(&res.unwrap(), &[0.into()], &[]);
+        let res = service.execute_with(publish, &alice).next().await;
+        assert_res_ok(&res.unwrap(), &[1.into()], &[]);
+
+        let mut watch = service.execute_with(CommandRequest::new_hwatch("t1"), &alice);
+        assert_eq!(
+            watch
+                .next()
+                .await
+                .unwrap()
+                .subscription
+                .as_ref()
+                .unwrap()
+                .topic,
+            "t1"
+        );
+        let hdel = CommandRequest::new_hdel("t1", "k1");
+        service.execute_with(hdel, &alice).next().await.unwrap();
+        let change = decode_change(&watch.next().await.unwrap()).unwrap();
+        assert_eq!((change.table.as_str(), change.key.as_str()), ("t1", "k1"));
+
+        let list = CommandRequest::new_client_list();
+        let res = service.execute_with(list, &alice).next().await.unwrap();
+        assert_eq!(res.status, 403);
+    }
+
+    #[tokio::test]
+    async fn overloaded_service_should_reject_data_commands() {
+        let config = OverloadConfig {
+            enabled: true,
+            max_in_flight: 1,
+            ..Default::default()
+        };
+        let service = Service::new(MemTable::new()).with_load_shedding(LoadShedder::new(&config));
+        let _g1 = service.track_request();
+        let _g2 = service.track_request();
+
+        let mut res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        let res = res.next().await.unwrap();
+        assert_eq!(res.status, 503);
+        assert!(res.message.contains("retry later"));
+        let mut res = service.execute(CommandRequest::new_latency());
+        assert_eq!(res.next().await.unwrap().status, 200);
+    }
+
     #[tokio::test]
     async fn event_registration_should_work() {
-        fn b(cmd: &CommandRequest) -> Option<CommandResponse> {
+        fn b(_: &RequestContext, cmd: &CommandRequest) -> Option<CommandResponse> {
             info!("Got {:?}", cmd);
             None
         }
-        fn c(res: &CommandResponse) -> Option<CommandResponse> {
+        fn c(_: &RequestContext, res: &CommandResponse) -> Option<CommandResponse> {
             info!("{:?}", res);
             None
         }
-        fn d(res: &mut CommandResponse) -> Option<CommandResponse> {
+        fn d(_: &RequestContext, res: &mut CommandResponse) -> Option<CommandResponse> {
             res.status = StatusCode::CREATED.as_u16() as _;
             None
         }
-        fn e() -> Option<CommandResponse> {
+        fn e(_: &RequestContext) -> Option<CommandResponse> {
             info!("Data is sent");
             None
         }
 
         let service: Service = Service::new(MemTable::default())
-            .fn_received(|_: &CommandRequest| None)
+            .fn_received(|_: &RequestContext, _: &CommandRequest| None)
             .fn_received(b)
             .fn_executed(c)
             .fn_before_send(d)
@@ -248,10 +1385,129 @@ mod tests {
         assert_eq!(data.values, vec![Value::default()]);
     }
 
+    #[tokio::test]
+    async fn hooks_should_see_request_context() {
+        // 按连接的身份修改响应，验证 hook 拿到了 execute_with 传入的连接信息
+        fn tag(ctx: &RequestContext, res: &mut CommandResponse) -> Option<CommandResponse> {
+            if let (Some(id), Some(identity)) = (ctx.conn_id, &ctx.identity) {
+                res.message = format!("{} {}", id, identity);
+            }
+            None
+        }
+
+        let service = Service::new(MemTable::default()).fn_before_send(tag);
+        let ctx = RequestContext {
+            conn_id: Some(7),
+            peer_addr: Some("127.0.0.1:50000".parse().unwrap()),
+            identity: Some("cert:abcd".into()),
+            authenticated: true,
+            role: None,
+        };
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = service.execute_with(cmd, &ctx).next().await.unwrap();
+        assert_eq!(res.message, "7 cert:abcd");
+
+        // 进程内执行时没有连接信息
+        let cmd = CommandRequest::new_hset("t1", "k1", "v2".into());
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_eq!(res.message, "");
+    }
+
+    #[tokio::test]
+    async fn hooks_should_run_in_priority_order() {
+        fn metrics(_: &RequestContext, res: &mut CommandResponse) -> Option<CommandResponse> {
+            res.message.push_str("metrics;");
+            None
+        }
+        fn auth(_: &RequestContext, res: &mut CommandResponse) -> Option<CommandResponse> {
+            res.message.push_str("auth;");
+            None
+        }
+
+        // 先注册统计再注册认证，认证仍然先执行
+        let service = Service::new(MemTable::default())
+            .hook_before_send("metrics", METRICS_PRIORITY, metrics)
+            .hook_before_send("auth", AUTH_PRIORITY, auth);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_eq!(res.message, "auth;metrics;");
+
+        let hooks: Vec<_> = service.hooks().iter().map(|h| h.to_string()).collect();
+        assert_eq!(
+            hooks,
+            [
+                "before_send auth priority=-200",
+                "before_send metrics priority=100"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn hooks_should_be_registered_at_runtime() {
+        fn tap(_: &RequestContext, res: &mut CommandResponse) -> Option<CommandResponse> {
+            res.message = "tapped".into();
+            None
+        }
+
+        let service = Service::new(MemTable::default());
+        // 网络层每个连接使用一个 clone，在原来的 Service 上注册的 hook 对所有连接生效
+        let conn = service.clone();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        assert_eq!(conn.execute(cmd.clone()).next().await.unwrap().message, "");
+
+        service.update_hooks(|h| h.before_send.insert("debug-tap", METRICS_PRIORITY, tap));
+        let res = conn.execute(cmd.clone()).next().await.unwrap();
+        assert_eq!(res.message, "tapped");
+        assert_eq!(conn.hooks().len(), 1);
+
+        assert!(conn.remove_hook(HookStage::BeforeSend, "debug-tap"));
+        assert!(!service.remove_hook(HookStage::BeforeSend, "debug-tap"));
+        assert_eq!(service.execute(cmd).next().await.unwrap().message, "");
+        assert!(service.hooks().is_empty());
+    }
+
+    #[tokio::test]
+    async fn async_hooks_should_run_in_response_stream() {
+        let audit = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = audit.clone();
+        let service = Service::new(MemTable::default())
+            .hook_executed_async(
+                "audit",
+                METRICS_PRIORITY,
+                async_executed(move |ctx: RequestContext, res: CommandResponse| {
+                    let log = log.clone();
+                    async move {
+                        tokio::task::yield_now().await;
+                        log.lock().unwrap().push((ctx.conn_id, res.status));
+                    }
+                }),
+            )
+            .hook_before_send_async(
+                "policy",
+                DEFAULT_PRIORITY,
+                async_before_send(|_, mut res: CommandResponse| async move {
+                    res.message = "checked".into();
+                    res
+                }),
+            );
+
+        let ctx = RequestContext {
+            conn_id: Some(3),
+            ..Default::default()
+        };
+        let mut stream =
+            service.execute_with(CommandRequest::new_hset("t1", "k1", "v1".into()), &ctx);
+        // hook 在响应流里执行，取响应之前不会执行
+        assert!(audit.lock().unwrap().is_empty());
+        let res = stream.next().await.unwrap();
+        assert_eq!(res.message, "checked");
+        assert_eq!(*audit.lock().unwrap(), [(Some(3), 200)]);
+    }
+
     #[tokio::test]
     async fn early_return_for_special_keys() {
         // 定义一个检查特殊键的回调
-        fn check_special_keys(cmd: &CommandRequest) -> Option<CommandResponse> {
+        fn check_special_keys(_: &RequestContext, cmd: &CommandRequest) -> Option<CommandResponse> {
             match &cmd.request_data {
                 Some(RequestData::Hget(param)) => {
                     if param.key == "admin" {
@@ -304,4 +1560,486 @@ mod tests {
         assert_eq!(data.status, 200); // 正常处理
         assert_eq!(data.values, vec![Value::default()]);
     }
+
+    #[tokio::test]
+    async fn write_should_publish_keyspace_notification() {
+        let service = Service::new(MemTable::default());
+
+        let mut sub = service.execute(CommandRequest::new_subscribe(keyspace_topic("t1")));
+        // 第一个消息是 subscription id
+        sub.next().await.unwrap();
+
+        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        res.next().await.unwrap();
+        let data = sub.next().await.unwrap();
+        assert_res_ok(&data, &["k1".into()], &[]);
+
+        // 读命令和失败的写命令不会产生通知
+        let mut res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        res.next().await.unwrap();
+        let mut res = service.execute(CommandRequest::new_hdel("t1", "k2"));
+        res.next().await.unwrap();
+
+        let keys = vec!["k1".to_string(), "k3".to_string()];
+        let mut res = service.execute(CommandRequest::new_hmdel("t1", keys));
+        res.next().await.unwrap();
+        let data = sub.next().await.unwrap();
+        assert_res_ok(&data, &["k1".into(), "k3".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn hdelprefix_should_notify_deleted_keys() {
+        let service = Service::new(MemTable::default());
+        for key in ["user:1", "user:2", "order:1"] {
+            let cmd = CommandRequest::new_hset("t1", key, "v".into());
+            service.execute(cmd).next().await.unwrap();
+        }
+
+        // 没有订阅者时不用读出要删除的 key
+        let mut res = service.execute(CommandRequest::new_hdelprefix("t1", "order:"));
+        assert_res_ok(&res.next().await.unwrap(), &[1.into()], &[]);
+
+        let mut sub = service.execute(CommandRequest::new_subscribe(keyspace_topic("t1")));
+        sub.next().await.unwrap();
+        let mut res = service.execute(CommandRequest::new_hdelprefix("t1", "user:"));
+        assert_res_ok(&res.next().await.unwrap(), &[2.into()], &[]);
+        let data = sub.next().await.unwrap();
+        assert_res_ok(&data, &["user:1".into(), "user:2".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn primary_writes_should_be_applied_to_replica() {
+        let primary = Service::new(MemTable::default()).with_replication(ReplicationLog::new(16));
+        let replica = Service::new(MemTable::default()).read_only();
+
+        let mut log = primary.execute(CommandRequest::new_replicate(false));
+        // 第一个消息是当前的复制位置
+        assert_res_ok(&log.next().await.unwrap(), &[0.into()], &[]);
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        primary.execute(cmd).next().await.unwrap();
+        let (seq, cmd) = decode_entry(&log.next().await.unwrap()).unwrap();
+        assert_eq!(seq, 1);
+        replica.apply_replicated(cmd);
+
+        let data = replica
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .next()
+            .await;
+        assert_res_ok(&data.unwrap(), &["v1".into()], &[]);
+
+        // replica 拒绝客户端的写操作
+        let cmd = CommandRequest::new_hset("t1", "k2", "v2".into());
+        let data = replica.execute(cmd).next().await.unwrap();
+        assert_res_error(&data, 403, "read-only");
+
+        // 不是 primary 时不能订阅复制日志
+        let data = replica
+            .execute(CommandRequest::new_replicate(true))
+            .next()
+            .await;
+        assert_eq!(data.unwrap().status, 400);
+    }
+
+    #[tokio::test]
+    async fn flushall_should_be_replicated_and_notified() {
+        let primary = Service::new(MemTable::default()).with_replication(ReplicationLog::new(16));
+        let replica = Service::new(MemTable::default()).read_only();
+        for table in ["t1", "t2"] {
+            let cmd = CommandRequest::new_hset(table, "k1", "v1".into());
+            primary.execute(cmd.clone()).next().await.unwrap();
+            replica.apply_replicated(cmd);
+        }
+        let mut log = primary.execute(CommandRequest::new_replicate(false));
+        log.next().await.unwrap();
+        let mut sub = primary.execute(CommandRequest::new_subscribe(keyspace_topic("t1")));
+        sub.next().await.unwrap();
+
+        let cmd = CommandRequest::new_flushall(Flushall::CONFIRM);
+        let res = primary.execute(cmd.clone()).next().await.unwrap();
+        assert_res_ok(&res, &[2.into()], &[]);
+        let data = sub.next().await.unwrap();
+        assert_res_ok(&data, &["k1".into()], &[]);
+
+        // 每个 table 的删除都进了复制日志，replica 重放之后也是空的
+        for _ in 0..2 {
+            let (_, cmd) = decode_entry(&log.next().await.unwrap()).unwrap();
+            assert_eq!(replica.apply_replicated(cmd).status, 200);
+        }
+        assert!(replica.store.get_iter("t1").unwrap().next().is_none());
+        assert!(replica.store.get_iter("t2").unwrap().next().is_none());
+
+        let res = replica.execute(cmd).next().await.unwrap();
+        assert_res_error(&res, 403, "read-only");
+    }
+
+    #[tokio::test]
+    async fn reserved_tables_should_be_rejected() {
+        let service = Service::new(MemTable::default());
+        service
+            .store
+            .set("__raft__", "term".into(), 1.into())
+            .unwrap();
+        let cmds = [
+            CommandRequest::new_hgetall("__raft__"),
+            CommandRequest::new_hset("__raft__", "term", 2.into()),
+            CommandRequest::new_hdelprefix("__raft__", ""),
+            CommandRequest::new_hset("__crdt__:t1", "k1", "v1".into()),
+            CommandRequest::new_hwatch("__raft__"),
+        ];
+        for cmd in cmds {
+            let res = service.execute(cmd).next().await.unwrap();
+            assert_res_error(&res, 403, "reserved");
+        }
+        let value = service.store.get("__raft__", "term").unwrap();
+        assert_eq!(value, Some(1.into()));
+    }
+
+    #[tokio::test]
+    async fn crdt_sync_should_be_checked() {
+        let mut config = ServerConfig::builder().build().unwrap();
+        config.multi_master = MultiMasterConfig {
+            enabled: true,
+            node_id: Some("a".into()),
+            peers: vec!["127.0.0.1:9528".into()],
+            ..Default::default()
+        };
+        let service = Service::new(MemTable::default()).with_entry_limits(None, Some(16));
+        let mm = MultiMaster::new(&config, Arc::clone(&service.store)).unwrap();
+        let service = service.with_multi_master(mm);
+
+        let entry = |table: &str, value: &str| CrdtEntry {
+            table: table.into(),
+            key: "k1".into(),
+            value: Some(value.into()),
+            version: Some(Version {
+                clock: vec![Dot {
+                    node: "b".into(),
+                    counter: 1,
+                }],
+                timestamp: 1,
+                node: "b".into(),
+                deleted: false,
+            }),
+        };
+        let sync = |entry| CommandRequest::new_crdt_sync(vec![], vec![entry]);
+        let ctx = |addr: &str| RequestContext {
+            peer_addr: Some(addr.parse().unwrap()),
+            authenticated: true,
+            ..Default::default()
+        };
+
+        // 不是 peer 发来的同步被拒绝
+        let outsider = ctx("10.0.0.1:50000");
+        let cmd = sync(entry("t1", "v1"));
+        let res = service.execute_with(cmd, &outsider).next().await.unwrap();
+        assert_res_error(&res, 403, "multi_master.peers");
+
+        // 同步过来的数据也要遵守大小限制，也不能写内部 table
+        let peer = ctx("127.0.0.1:50000");
+        let cmd = sync(entry("t1", &"v".repeat(32)));
+        let res = service.execute_with(cmd, &peer).next().await.unwrap();
+        assert_eq!(res.status, 413);
+        let cmd = sync(entry("__raft__", "v1"));
+        let res = service.execute_with(cmd, &peer).next().await.unwrap();
+        assert_res_error(&res, 400, "reserved");
+        assert!(service.store.get("__raft__", "k1").unwrap().is_none());
+
+        let cmd = sync(entry("t1", "v1"));
+        let res = service.execute_with(cmd, &peer).next().await.unwrap();
+        assert_eq!(res.status, 200);
+        assert_eq!(service.store.get("t1", "k1").unwrap(), Some("v1".into()));
+    }
+
+    #[tokio::test]
+    async fn raft_rpc_should_only_be_accepted_from_peers() {
+        let service = Service::new(MemTable::default());
+        let config = RaftConfig {
+            enabled: true,
+            peers: vec!["127.0.0.1:9527".into(), "127.0.0.1:9528".into()],
+            ..Default::default()
+        };
+        let raft = RaftNode::new(&config, Arc::clone(&service.store), |_| {
+            CommandResponse::ok()
+        });
+        let service = service.with_raft(raft.unwrap());
+
+        let cmd = CommandRequest {
+            request_data: Some(RequestData::RaftVote(RaftVote {
+                term: 1,
+                candidate: 2,
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let ctx = |addr: &str| RequestContext {
+            peer_addr: Some(addr.parse().unwrap()),
+            authenticated: true,
+            ..Default::default()
+        };
+        let outsider = ctx("10.0.0.1:50000");
+        let res = service
+            .execute_with(cmd.clone(), &outsider)
+            .next()
+            .await
+            .unwrap();
+        assert_res_error(&res, 403, "raft.peers");
+
+        // 节点之间的连接从临时端口发出，只比较 IP
+        let peer = ctx("127.0.0.1:50000");
+        let res = service.execute_with(cmd, &peer).next().await.unwrap();
+        assert_res_ok(&res, &[1.into(), true.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn promoted_replica_should_accept_writes() {
+        let replica = Service::new(MemTable::default())
+            .with_replication(ReplicationLog::new(16))
+            .read_only();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let data = replica.clone().execute(cmd.clone()).next().await.unwrap();
+        assert_res_error(&data, 403, "read-only");
+
+        // clone 出来的 Service 也能看到提升
+        assert_eq!(replica.clone().promote(0).unwrap(), 1);
+        assert!(!replica.is_read_only());
+        let data = replica.execute(cmd).next().await.unwrap();
+        assert_res_ok(&data, &[Value::default()], &[]);
+
+        // 已经是 primary 的节点不能再提升
+        assert!(replica.promote(5).is_err());
+        assert!(
+            Service::new(MemTable::default())
+                .read_only()
+                .promote(0)
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn sharded_service_should_redirect_foreign_tables() {
+        let config = crate::ShardingConfig {
+            enabled: true,
+            id: 1,
+            nodes: vec!["127.0.0.1:9527".into(), "127.0.0.1:9528".into()],
+            ..Default::default()
+        };
+        let service =
+            Service::new(MemTable::default()).with_sharding(ShardRouter::new(&config).unwrap());
+        let map = service.slot_map().unwrap().clone();
+
+        for i in 0..20 {
+            let table = format!("table{}", i);
+            let cmd = CommandRequest::new_hset(&table, "k1", "v1".into());
+            let res = service.execute(cmd).next().await.unwrap();
+            match map.owner(crate::key_slot(&table)) {
+                Some("127.0.0.1:9527") => assert_eq!(res.status, 200),
+                _ => assert_res_error(&res, 421, "127.0.0.1:9528"),
+            }
+        }
+
+        let res = service
+            .execute(CommandRequest::new_cluster_slots())
+            .next()
+            .await
+            .unwrap();
+        let slots = crate::SlotMap::from_response(&res).unwrap();
+        assert_eq!(slots.ranges(), map.ranges());
+    }
+
+    #[tokio::test]
+    async fn duplicate_idempotency_key_should_not_reapply_write() {
+        let cache = IdempotencyCache::new(&crate::IdempotencyConfig::default());
+        let service = Service::new(MemTable::default()).with_idempotency(cache);
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into()).with_idempotency_key("token1");
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&res, &[Value::default()], &[]);
+
+        // 同一个 key 的重复请求返回第一次的响应，不会再写入
+        let cmd = CommandRequest::new_hset("t1", "k1", "v2".into()).with_idempotency_key("token1");
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&res, &[Value::default()], &[]);
+        let res = service
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .next()
+            .await
+            .unwrap();
+        assert_res_ok(&res, &["v1".into()], &[]);
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v2".into()).with_idempotency_key("token2");
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&res, &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn oversized_reads_should_be_rejected() {
+        let service = Service::new(MemTable::default()).with_max_result_entries(2);
+        for key in ["k1", "k2"] {
+            let cmd = CommandRequest::new_hset("t1", key, key.into());
+            service.execute(cmd).next().await.unwrap();
+        }
+        let mut res = service.execute(CommandRequest::new_hgetall("t1"));
+        assert_eq!(res.next().await.unwrap().pairs.len(), 2);
+
+        let cmd = CommandRequest::new_hset("t1", "k3", "k3".into());
+        service.execute(cmd).next().await.unwrap();
+        let mut res = service.execute(CommandRequest::new_hgetall("t1"));
+        assert_res_error(&res.next().await.unwrap(), 413, "more than 2 entries");
+
+        let keys = vec!["k1".to_string(), "k2".to_string(), "k3".to_string()];
+        let mut res = service.execute(CommandRequest::new_hmget("t1", keys));
+        assert_eq!(res.next().await.unwrap().status, 413);
+        let keys = vec!["k1".to_string(), "k3".to_string()];
+        let mut res = service.execute(CommandRequest::new_hmget("t1", keys));
+        assert_eq!(res.next().await.unwrap().values.len(), 2);
+
+        // HWATCH 的快照同样受限，超过时不会订阅
+        let mut res = service.execute(CommandRequest::new_hwatch_snapshot("t1"));
+        assert_eq!(res.next().await.unwrap().status, 413);
+        assert!(res.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn oversized_raft_reads_should_be_rejected() {
+        let service = Service::new(MemTable::default()).with_max_result_entries(2);
+        let config = RaftConfig {
+            enabled: true,
+            id: 1,
+            peers: vec!["127.0.0.1:9527".into()],
+            election_timeout_ms: 50,
+            heartbeat_interval_ms: 10,
+            client: None,
+        };
+        let svc = service.clone();
+        let raft = RaftNode::new(&config, Arc::clone(&service.store), move |cmd| {
+            svc.apply_replicated(cmd)
+        });
+        let raft = raft.unwrap();
+        raft.start();
+        let service = service.with_raft(raft);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        for key in ["k1", "k2", "k3"] {
+            let cmd = CommandRequest::new_hset("t1", key, key.into());
+            assert_eq!(service.execute(cmd).next().await.unwrap().status, 200);
+        }
+        let mut res = service.execute(CommandRequest::new_hgetall("t1"));
+        assert_res_error(&res.next().await.unwrap(), 413, "more than 2 entries");
+        let mut res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_eq!(res.next().await.unwrap().values, &["k1".into()]);
+    }
+
+    #[tokio::test]
+    async fn oversized_entries_should_be_rejected() {
+        let service = Service::new(MemTable::default()).with_entry_limits(Some(4), Some(8));
+        let cmd = CommandRequest::new_hset("t1", "k1", "hello".into());
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_eq!(res.status, 200);
+
+        let cmd = CommandRequest::new_hset("t1", "key01", "v1".into());
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_res_error(&res, 413, "key in t1 is 5 bytes, more than 4");
+
+        // HMSET 里任何一个 value 超过上限，整个命令都不执行
+        let pairs = vec![
+            Kvpair::new("k2", "v2".into()),
+            Kvpair::new("k3", "hello world".into()),
+        ];
+        let res = service
+            .execute(CommandRequest::new_hmset("t1", pairs))
+            .next()
+            .await
+            .unwrap();
+        assert_res_error(&res, 413, "value of t1/k3 is 13 bytes, more than 8");
+        let mut res = service.execute(CommandRequest::new_hexist("t1", "k2"));
+        assert_res_ok(&res.next().await.unwrap(), &[false.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn save_and_restore_should_work() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = Service::new(MemTable::default()).with_backup_dir(dir.path());
+        service
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .next()
+            .await;
+        let mut res = service.execute(CommandRequest::new_save("backup.bin"));
+        assert_res_ok(&res.next().await.unwrap(), &[1.into()], &[]);
+        assert!(dir.path().join("backup.bin").exists());
+
+        // RESTORE 和普通的写操作一样发布 keyspace 通知
+        let restored = Service::new(MemTable::default()).with_backup_dir(dir.path());
+        let mut sub = restored.execute(CommandRequest::new_subscribe(keyspace_topic("t1")));
+        sub.next().await.unwrap();
+        let mut res = restored.execute(CommandRequest::new_restore("backup.bin"));
+        assert_res_ok(&res.next().await.unwrap(), &[1.into()], &[]);
+        assert_res_ok(&sub.next().await.unwrap(), &["k1".into()], &[]);
+        let mut res = restored.execute(CommandRequest::new_hget("t1", "k1"));
+        assert_res_ok(&res.next().await.unwrap(), &["v1".into()], &[]);
+
+        // 备份不存在时报错，不会当成空的备份
+        let cmd = CommandRequest::new_restore("missing.bin");
+        let res = restored.execute(cmd).next().await.unwrap();
+        assert_eq!(res.status, 404);
+        let res = restored
+            .execute(CommandRequest::new_save(""))
+            .next()
+            .await
+            .unwrap();
+        assert_res_error(&res, 400, "save requires a file name");
+
+        // 没有配置备份目录时不能执行
+        let service = Service::new(MemTable::default());
+        let res = service
+            .execute(CommandRequest::new_save("backup.bin"))
+            .next()
+            .await
+            .unwrap();
+        assert_res_error(&res, 400, "backup directory is not configured");
+    }
+
+    #[tokio::test]
+    async fn save_and_restore_should_stay_in_backup_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let backups = dir.path().join("backups");
+        let service = Service::new(MemTable::default()).with_backup_dir(&backups);
+        let cmds = [
+            CommandRequest::new_restore("/etc/passwd"),
+            CommandRequest::new_save("../../x"),
+            CommandRequest::new_save(".."),
+            CommandRequest::new_save("sub/x"),
+            CommandRequest::new_save("./x"),
+        ];
+        for cmd in cmds {
+            let res = service.execute(cmd).next().await.unwrap();
+            assert_res_error(&res, 400, "only accepts a file name");
+        }
+        assert!(!dir.path().join("x").exists());
+        assert!(!backups.exists());
+    }
+
+    #[tokio::test]
+    async fn requests_with_too_many_entries_should_be_rejected() {
+        let service = Service::new(MemTable::default()).with_max_request_entries(2);
+        let keys = vec!["k1".to_string(), "k2".to_string()];
+        let res = service
+            .execute(CommandRequest::new_hmget("t1", keys))
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(res.status, 200);
+
+        let pairs: Vec<_> = (0..3)
+            .map(|i| Kvpair::new(format!("k{}", i), i.into()))
+            .collect();
+        let res = service
+            .execute(CommandRequest::new_hmset("t1", pairs))
+            .next()
+            .await
+            .unwrap();
+        assert_res_error(&res, 413, "hmset has 3 entries, more than 2");
+        let mut res = service.execute(CommandRequest::new_hexist("t1", "k0"));
+        assert_res_ok(&res.next().await.unwrap(), &[false.into()], &[]);
+    }
 }
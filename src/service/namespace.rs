@@ -0,0 +1,288 @@
+use super::topic::KEYSPACE_PREFIX;
+use crate::{
+    AuthConfig, Broadcaster, CommandRequest, CommandResponse, KvError, Storage, StreamingResponse,
+    Value, command_request::RequestData, decode_change, keyspace_topic,
+};
+use bytes::Bytes;
+use futures::StreamExt;
+use prost::Message;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// namespace 和 table 名、主题名之间的分隔符
+const SEPARATOR: char = ':';
+
+/// 按连接的身份找到租户，把租户的 table 名和主题名放进它的 namespace
+#[derive(Debug, Clone, Default)]
+pub struct Namespaces {
+    tenants: BTreeMap<String, Arc<Tenant>>,
+}
+
+impl Namespaces {
+    pub fn new(config: &AuthConfig) -> Self {
+        let tenants = config
+            .tenants
+            .iter()
+            .map(|(identity, tenant)| {
+                let tenant = Tenant {
+                    namespace: tenant.namespace.clone(),
+                    prefix: format!("{}{}", tenant.namespace, SEPARATOR),
+                    max_tables: tenant.max_tables,
+                    max_subscribers: tenant.max_subscribers,
+                };
+                (identity.clone(), Arc::new(tenant))
+            })
+            .collect();
+        Self { tenants }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tenants.is_empty()
+    }
+
+    /// 身份绑定的租户，没有绑定时为 None，不做任何转换
+    pub fn tenant(&self, identity: Option<&str>) -> Option<&Arc<Tenant>> {
+        self.tenants.get(identity?)
+    }
+}
+
+/// 一个租户：它的 namespace 和配额
+///
+/// 租户只能执行和 table、主题相关的命令。请求里的 table 名和主题名加上 `namespace:` 前缀之后再执行，
+/// 返回给租户的订阅确认和 HWATCH 变更去掉前缀，租户看到的还是自己的名字
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tenant {
+    namespace: String,
+    prefix: String,
+    max_tables: Option<usize>,
+    max_subscribers: Option<usize>,
+}
+
+impl Tenant {
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// 把命令里的 table、主题和幂等 key 放进 namespace，租户不能执行的命令返回错误
+    pub fn scope(&self, mut cmd: CommandRequest) -> Result<CommandRequest, KvError> {
+        let name = cmd.name();
+        if let Some(table) = cmd.table_mut() {
+            *table = self.table(table);
+        } else {
+            match &mut cmd.request_data {
+                Some(RequestData::Subscribe(v)) => v.topic = self.topic(&v.topic),
+                Some(RequestData::Unsubscribe(v)) => v.topic = self.topic(&v.topic),
+                Some(RequestData::Publish(v)) => v.topic = self.topic(&v.topic),
+                // 订阅所有 table 的变更会看到其它租户的数据
+                Some(RequestData::Hwatch(v)) if !v.table.is_empty() => {
+                    v.table = self.table(&v.table)
+                }
+                // 只返回路由信息，和租户的数据无关
+                Some(RequestData::Cluster(_) | RequestData::ClusterSlots(_)) => {}
+                _ => {
+                    return Err(KvError::PermissionDenied(format!(
+                        "{} is not available in namespace {}",
+                        name, self.namespace
+                    )));
+                }
+            }
+        }
+        // 幂等 key 也按租户隔离，不会拿到其它租户的响应
+        if !cmd.idempotency_key.is_empty() {
+            cmd.idempotency_key = format!("{}{}", self.prefix, cmd.idempotency_key);
+        }
+        Ok(cmd)
+    }
+
+    /// 检查 scope 之后的命令是否超过租户的配额。
+    /// 检查和执行之间没有加锁，并发创建 table 或者订阅时可能略微超出
+    pub fn check_quota(
+        &self,
+        cmd: &CommandRequest,
+        store: &dyn Storage,
+        broadcaster: &Broadcaster,
+    ) -> Result<(), KvError> {
+        match &cmd.request_data {
+            Some(RequestData::Subscribe(_)) => {
+                let Some(max) = self.max_subscribers else {
+                    return Ok(());
+                };
+                let count = broadcaster.subscriber_count_by(|topic| self.owns_topic(topic));
+                if count >= max {
+                    return Err(KvError::QuotaExceeded(format!(
+                        "namespace {} already has {} subscribers",
+                        self.namespace, max
+                    )));
+                }
+            }
+            // 只有写入 key 时会创建 table
+            Some(RequestData::Hset(_) | RequestData::Hmset(_)) => {
+                let (Some(max), Some(table)) = (self.max_tables, cmd.table()) else {
+                    return Ok(());
+                };
+                if store.table_exists(table)? {
+                    return Ok(());
+                }
+                let count = store
+                    .tables()?
+                    .iter()
+                    .filter(|t| t.starts_with(&self.prefix))
+                    .count();
+                if count >= max {
+                    return Err(KvError::QuotaExceeded(format!(
+                        "namespace {} already has {} tables",
+                        self.namespace, max
+                    )));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// 去掉 SUBSCRIBE 的订阅确认里主题的前缀
+    pub fn unscope_subscription(self: &Arc<Self>, res: StreamingResponse) -> StreamingResponse {
+        let tenant = Arc::clone(self);
+        Box::pin(res.map(move |res| tenant.unscope_ack(res)))
+    }
+
+    /// 去掉 HWATCH 的订阅确认和每个变更里 table 的前缀
+    pub fn unscope_changes(self: &Arc<Self>, res: StreamingResponse) -> StreamingResponse {
+        let tenant = Arc::clone(self);
+        Box::pin(res.map(move |res| {
+            if res.subscription.is_some() {
+                return tenant.unscope_ack(res);
+            }
+            // 快照和错误的响应里没有 table
+            let Ok(mut event) = decode_change(&res) else {
+                return res;
+            };
+            event.table = tenant.unscope_table(&event.table).into();
+            let value = Value::from(Bytes::from(event.encode_to_vec()));
+            Arc::new(value.into())
+        }))
+    }
+
+    fn unscope_ack(&self, res: Arc<CommandResponse>) -> Arc<CommandResponse> {
+        let Some(ack) = &res.subscription else {
+            return res;
+        };
+        let topic = self.unscope_topic(&ack.topic);
+        let mut res = (*res).clone();
+        if let Some(ack) = res.subscription.as_mut() {
+            ack.topic = topic;
+        }
+        Arc::new(res)
+    }
+
+    fn table(&self, table: &str) -> String {
+        format!("{}{}", self.prefix, table)
+    }
+
+    fn unscope_table<'a>(&self, table: &'a str) -> &'a str {
+        table.strip_prefix(&self.prefix).unwrap_or(table)
+    }
+
+    /// keyspace 主题里的 table 名放进 namespace，其它主题直接加前缀
+    fn topic(&self, topic: &str) -> String {
+        match topic.strip_prefix(KEYSPACE_PREFIX) {
+            Some(table) => keyspace_topic(&self.table(table)),
+            None => format!("{}{}", self.prefix, topic),
+        }
+    }
+
+    fn unscope_topic(&self, topic: &str) -> String {
+        match topic.strip_prefix(KEYSPACE_PREFIX) {
+            Some(table) => keyspace_topic(self.unscope_table(table)),
+            None => self.unscope_table(topic).to_string(),
+        }
+    }
+
+    fn owns_topic(&self, topic: &str) -> bool {
+        topic
+            .strip_prefix(KEYSPACE_PREFIX)
+            .unwrap_or(topic)
+            .starts_with(&self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, TenantConfig, Topic};
+
+    fn tenant(max_tables: Option<usize>, max_subscribers: Option<usize>) -> Arc<Tenant> {
+        let config = AuthConfig {
+            tenants: [(
+                "alice".to_string(),
+                TenantConfig {
+                    namespace: "app1".into(),
+                    max_tables,
+                    max_subscribers,
+                },
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let namespaces = Namespaces::new(&config);
+        assert!(namespaces.tenant(None).is_none());
+        assert!(namespaces.tenant(Some("bob")).is_none());
+        Arc::clone(namespaces.tenant(Some("alice")).unwrap())
+    }
+
+    #[test]
+    fn commands_should_be_scoped() {
+        let tenant = tenant(None, None);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into()).with_idempotency_key("req1");
+        let cmd = tenant.scope(cmd).unwrap();
+        assert_eq!(cmd.table(), Some("app1:t1"));
+        assert_eq!(cmd.idempotency_key, "app1:req1");
+
+        let cmd = tenant.scope(CommandRequest::new_publish("lobby", vec![]));
+        let Some(RequestData::Publish(publish)) = cmd.unwrap().request_data else {
+            panic!("expect publish");
+        };
+        assert_eq!(publish.topic, "app1:lobby");
+
+        // keyspace 主题对应的是 namespace 里的 table
+        let topic = tenant.topic(&keyspace_topic("t1"));
+        assert_eq!(topic, keyspace_topic("app1:t1"));
+        assert_eq!(tenant.unscope_topic(&topic), keyspace_topic("t1"));
+        assert!(tenant.owns_topic(&topic));
+        assert!(!tenant.owns_topic(&keyspace_topic("app2:t1")));
+
+        // 运维命令和订阅所有 table 的变更不能执行
+        let err = tenant.scope(CommandRequest::new_latency()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permission denied: latency is not available in namespace app1"
+        );
+        assert!(tenant.scope(CommandRequest::new_hwatch("")).is_err());
+        assert!(tenant.scope(CommandRequest::new_hwatch("t1")).is_ok());
+    }
+
+    #[test]
+    fn quota_should_limit_tables_and_subscribers() {
+        let tenant = tenant(Some(1), Some(1));
+        let store = MemTable::new();
+        let broadcaster = Arc::new(Broadcaster::default());
+        let check = |cmd| {
+            let cmd = tenant.scope(cmd).unwrap();
+            tenant.check_quota(&cmd, &store, &broadcaster)
+        };
+
+        assert!(check(CommandRequest::new_hset("t1", "k1", "v1".into())).is_ok());
+        store.set("app1:t1", "k1".into(), "v1".into()).unwrap();
+        // 其它 namespace 的 table 不算在内
+        store.set("app2:t1", "k1".into(), "v1".into()).unwrap();
+        assert!(check(CommandRequest::new_hset("t1", "k2", "v2".into())).is_ok());
+        let err = check(CommandRequest::new_hset("t2", "k1", "v1".into())).unwrap_err();
+        assert!(matches!(err, KvError::QuotaExceeded(_)));
+
+        assert!(check(CommandRequest::new_subscribe("lobby")).is_ok());
+        let _rx = broadcaster.clone().subscribe("app1:lobby".into()).unwrap();
+        let _other = broadcaster.clone().subscribe("app2:lobby".into()).unwrap();
+        let err = check(CommandRequest::new_subscribe("chat")).unwrap_err();
+        assert!(matches!(err, KvError::QuotaExceeded(_)));
+    }
+}
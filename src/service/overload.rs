@@ -0,0 +1,128 @@
+use crate::{CommandRequest, OverloadConfig, command_request::RequestData};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio::time;
+
+/// 过载保护（load shedding）
+///
+/// 处理中的请求太多，或者事件循环的延迟（定时器实际唤醒时间比预期晚多少）太大时，
+/// 认为服务器过载，直接拒绝数据命令和 pub/sub 命令，让客户端稍后重试或者换一个节点。
+/// 运维命令和集群内部的命令不受影响，保证过载时仍然可以排查问题，集群也不会误判节点下线
+pub struct LoadShedder {
+    in_flight: AtomicUsize,
+    /// 最近一次采样的事件循环延迟（微秒）
+    lag_us: AtomicU64,
+    config: OverloadConfig,
+}
+
+/// 处理中的请求，drop 时计数减一
+pub struct InFlightGuard {
+    shedder: Arc<LoadShedder>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.shedder.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl LoadShedder {
+    pub fn new(config: &OverloadConfig) -> Arc<Self> {
+        Arc::new(Self {
+            in_flight: AtomicUsize::new(0),
+            lag_us: AtomicU64::new(0),
+            config: config.clone(),
+        })
+    }
+
+    /// 启动定期采样事件循环延迟的后台任务
+    pub fn start(self: &Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(self.clone().sample_lag())
+    }
+
+    /// 开始处理一个请求
+    pub fn track(self: &Arc<Self>) -> InFlightGuard {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard {
+            shedder: self.clone(),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn lag(&self) -> Duration {
+        Duration::from_micros(self.lag_us.load(Ordering::Relaxed))
+    }
+
+    pub fn is_overloaded(&self) -> bool {
+        // 计数里包括了当前这个请求
+        self.in_flight() > self.config.max_in_flight
+            || self.lag() >= Duration::from_millis(self.config.max_lag_ms)
+    }
+
+    /// 过载时是否拒绝这个命令
+    pub fn should_reject(&self, cmd: &CommandRequest) -> bool {
+        is_sheddable(cmd) && self.is_overloaded()
+    }
+
+    async fn sample_lag(self: Arc<Self>) {
+        let interval = Duration::from_millis(self.config.sample_interval_ms);
+        loop {
+            let start = Instant::now();
+            time::sleep(interval).await;
+            let lag = start.elapsed().saturating_sub(interval);
+            self.lag_us.store(lag.as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// 低优先级的命令：数据命令和 pub/sub 命令。UNSUBSCRIBE 会减少负载，不拒绝
+fn is_sheddable(cmd: &CommandRequest) -> bool {
+    cmd.table().is_some()
+        || matches!(
+            cmd.request_data,
+            Some(RequestData::Publish(_) | RequestData::Subscribe(_) | RequestData::Hwatch(_))
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shedder(max_in_flight: usize) -> Arc<LoadShedder> {
+        LoadShedder::new(&OverloadConfig {
+            enabled: true,
+            max_in_flight,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn too_many_in_flight_requests_should_be_rejected() {
+        let shedder = shedder(1);
+        let hget = CommandRequest::new_hget("t1", "k1");
+        let g1 = shedder.track();
+        assert!(!shedder.should_reject(&hget));
+
+        let g2 = shedder.track();
+        assert!(shedder.should_reject(&hget));
+        // 运维命令不受影响
+        assert!(!shedder.should_reject(&CommandRequest::new_client_list()));
+
+        drop((g1, g2));
+        assert_eq!(shedder.in_flight(), 0);
+        assert!(!shedder.should_reject(&hget));
+    }
+
+    #[test]
+    fn event_loop_lag_should_trigger_shedding() {
+        let shedder = shedder(1024);
+        shedder.lag_us.store(200_000, Ordering::Relaxed);
+        assert!(shedder.should_reject(&CommandRequest::new_publish("lobby", vec![])));
+        assert!(!shedder.should_reject(&CommandRequest::new_unsubscribe("lobby", 1)));
+    }
+}
@@ -0,0 +1,258 @@
+use crate::service::StreamingResponse;
+use crate::{CommandRequest, CommandResponse, KvError, Storage, Value};
+use bytes::Bytes;
+use futures::{StreamExt, stream};
+use prost::Message;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+/// 快照里每条 HMSET 最多包含的 kv pair 数量
+const SNAPSHOT_BATCH: usize = 128;
+
+/// primary 上的复制日志
+///
+/// 写操作在同一把锁里完成本地执行和追加日志，保证 replica 收到的顺序和本地执行的顺序一致。
+/// 日志只在内存里缓冲 capacity 条，replica 落后太多时复制流会被断开，由 replica 重连。
+/// replica 每次连接时先拉取快照，再接着接收复制位置之后的写操作
+pub struct ReplicationLog {
+    seq: Mutex<u64>,
+    tx: broadcast::Sender<Arc<CommandResponse>>,
+    /// 每次故障转移加一，replica 提升为 primary 时使用更大的 epoch
+    epoch: AtomicU64,
+    /// 写租约，设置之后要在租约内收到过 replica 的确认才能写
+    lease: Option<Duration>,
+    last_ack: Mutex<Option<Instant>>,
+}
+
+impl ReplicationLog {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Self {
+            seq: Mutex::new(0),
+            tx,
+            epoch: AtomicU64::new(0),
+            lease: None,
+            last_ack: Mutex::new(None),
+        }
+    }
+
+    /// 开启写租约，用于自动故障转移时防止出现两个 primary 同时写
+    ///
+    /// 启动之后在第一个 replica 确认之前也不接受写操作
+    pub fn with_lease(mut self, lease: Duration) -> Self {
+        self.lease = Some(lease);
+        self
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+
+    pub fn set_epoch(&self, epoch: u64) {
+        self.epoch.store(epoch, Ordering::SeqCst);
+    }
+
+    /// 记录 replica 的确认，返回当前的 epoch
+    pub fn ack(&self) -> u64 {
+        *self.last_ack.lock().unwrap() = Some(Instant::now());
+        self.epoch()
+    }
+
+    /// 写租约是否有效，没有开启写租约时总是有效
+    pub fn check_lease(&self) -> Result<(), KvError> {
+        let Some(lease) = self.lease else {
+            return Ok(());
+        };
+        match *self.last_ack.lock().unwrap() {
+            Some(ack) if ack.elapsed() < lease => Ok(()),
+            _ => Err(KvError::Fenced(
+                "no replica acknowledged within lease".into(),
+            )),
+        }
+    }
+
+    /// 当前的复制位置，也就是最后一条写操作的序号
+    pub fn position(&self) -> u64 {
+        *self.seq.lock().unwrap()
+    }
+
+    /// 执行写操作，成功后追加到复制日志
+    pub fn apply(
+        &self,
+        cmd: &CommandRequest,
+        f: impl FnOnce() -> CommandResponse,
+    ) -> CommandResponse {
+        if let Err(e) = self.check_lease() {
+            return e.into();
+        }
+        let mut seq = self.seq.lock().unwrap();
+        let res = f();
+        if res.status == 200 {
+            *seq += 1;
+            // 没有 replica 时 send 会失败，直接忽略
+            let _ = self.tx.send(Arc::new(encode_entry(*seq, cmd)));
+        }
+        res
+    }
+
+    /// 复制流：第一个响应是当前的复制位置，传入 store 时接着是快照和快照结束标记，之后是新的写操作
+    ///
+    /// 快照不加锁读取，可能已经包含复制位置之后的写操作。写操作都是覆盖或删除某个 key，
+    /// replica 在快照之上重放复制位置之后的写操作，最终结果和 primary 一致
+    pub fn stream(&self, store: Option<Arc<dyn Storage>>) -> StreamingResponse {
+        // 在锁里订阅，保证复制位置之后的写操作都能收到
+        let (position, rx) = {
+            let seq = self.seq.lock().unwrap();
+            (*seq, self.tx.subscribe())
+        };
+        let first = Arc::new(Value::from(position as i64).into());
+        let snapshot = match store {
+            Some(store) => {
+                let end = Arc::new(Value::from(position as i64).into());
+                snapshot_entries(position, store)
+                    .chain(stream::once(async move { end }))
+                    .boxed()
+            }
+            None => stream::empty().boxed(),
+        };
+
+        let entries = stream::unfold(rx, |mut rx| async move {
+            match rx.recv().await {
+                Ok(entry) => Some((entry, rx)),
+                Err(RecvError::Lagged(n)) => {
+                    warn!("Replica lagged behind {} entries, closing replication", n);
+                    None
+                }
+                Err(RecvError::Closed) => None,
+            }
+        });
+
+        Box::pin(
+            stream::once(async move { first })
+                .chain(snapshot)
+                .chain(entries),
+        )
+    }
+}
+
+/// 把 store 里的数据按 table 转换成一批批 HMSET，序号都是 position。
+/// 拿到复制位置之后马上创建 snapshot_handle，replica 接收快照期间的写入不会混进快照里
+fn snapshot_entries(position: u64, store: Arc<dyn Storage>) -> StreamingResponse {
+    let snapshot = match store.snapshot_handle() {
+        Ok(snapshot) => snapshot,
+        Err(e) => return Box::pin(stream::once(async move { Arc::new(e.into()) })),
+    };
+
+    let entries = stream::iter(snapshot.tables()).flat_map(move |table| {
+        let pairs: Vec<_> = snapshot.get_iter(&table).collect();
+        let res: Vec<_> = pairs
+            .chunks(SNAPSHOT_BATCH)
+            .map(|pairs| {
+                let cmd = CommandRequest::new_hmset(table.as_str(), pairs.to_vec());
+                Arc::new(encode_entry(position, &cmd))
+            })
+            .collect();
+        stream::iter(res)
+    });
+    Box::pin(entries)
+}
+
+/// 把一条写操作编码成复制流里的一个响应
+pub fn encode_entry(seq: u64, cmd: &CommandRequest) -> CommandResponse {
+    let data = Bytes::from(cmd.encode_to_vec());
+    vec![Value::from(seq as i64), Value::from(data)].into()
+}
+
+/// 从复制流的响应里解出序号和写操作
+pub fn decode_entry(res: &CommandResponse) -> Result<(u64, CommandRequest), KvError> {
+    let (seq, data) = match &res.values[..] {
+        [seq, data] => (seq, data),
+        _ => return Err(KvError::Internal("invalid replication entry".into())),
+    };
+    let seq: i64 = seq.try_into()?;
+    let data: Bytes = data.clone().try_into()?;
+    Ok((seq as u64, CommandRequest::decode(data)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_encode_decode_should_work() {
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = encode_entry(3, &cmd);
+        assert_eq!(decode_entry(&res).unwrap(), (3, cmd));
+
+        assert!(decode_entry(&CommandResponse::ok()).is_err());
+    }
+
+    #[tokio::test]
+    async fn replication_log_should_stream_successful_writes() {
+        let log = ReplicationLog::new(16);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        log.apply(&cmd, CommandResponse::ok);
+
+        let mut stream = log.stream(None);
+        let first = stream.next().await.unwrap();
+        assert_eq!(first.values, &[1.into()]);
+
+        // 失败的写操作不进入日志
+        log.apply(&cmd, || KvError::NotFound("t1".into()).into());
+        let cmd = CommandRequest::new_hdel("t1", "k1");
+        log.apply(&cmd, CommandResponse::ok);
+
+        let entry = stream.next().await.unwrap();
+        assert_eq!(decode_entry(&entry).unwrap(), (2, cmd));
+        assert_eq!(log.position(), 2);
+    }
+
+    #[test]
+    fn lease_should_fence_writes_without_acks() {
+        let log = ReplicationLog::new(16).with_lease(Duration::from_millis(50));
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        assert_eq!(log.apply(&cmd, CommandResponse::ok).status, 503);
+
+        log.set_epoch(2);
+        assert_eq!(log.ack(), 2);
+        assert_eq!(log.apply(&cmd, CommandResponse::ok).status, 200);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(log.apply(&cmd, CommandResponse::ok).status, 503);
+        assert_eq!(log.position(), 1);
+    }
+
+    #[tokio::test]
+    async fn replication_log_should_stream_snapshot_first() {
+        let store = crate::MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        let log = ReplicationLog::new(16);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        log.apply(&cmd, CommandResponse::ok);
+
+        let mut stream = log.stream(Some(Arc::new(store)));
+        assert_eq!(stream.next().await.unwrap().values, &[1.into()]);
+
+        let (seq, cmd) = decode_entry(&stream.next().await.unwrap()).unwrap();
+        assert_eq!(seq, 1);
+        match cmd.request_data {
+            Some(crate::command_request::RequestData::Hmset(v)) => {
+                assert_eq!(v.table, "t1");
+                assert_eq!(v.pairs.len(), 2);
+            }
+            _ => panic!("snapshot entry should be HMSET"),
+        }
+
+        // 快照结束标记
+        assert_eq!(stream.next().await.unwrap().values, &[1.into()]);
+
+        let cmd = CommandRequest::new_hdel("t1", "k1");
+        log.apply(&cmd, CommandResponse::ok);
+        let entry = stream.next().await.unwrap();
+        assert_eq!(decode_entry(&entry).unwrap(), (2, cmd));
+    }
+}
@@ -0,0 +1,246 @@
+use crate::{KvError, SchedulerConfig};
+use rand::Rng;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, info, warn};
+
+/// 后台任务调度器，由 Service 持有
+///
+/// 需要定期执行的维护工作（gossip、反熵同步等）注册成 job，不再各自 spawn 一个循环。
+/// 每个 job 在自己的 tokio task 里运行，两次执行之间的间隔带上随机抖动，避免集群里的节点同时执行；
+/// 执行次数、失败次数、最近一次的耗时和错误可以通过 JOBS 命令查看
+#[derive(Default)]
+pub struct Scheduler {
+    config: SchedulerConfig,
+    jobs: Mutex<Vec<Job>>,
+}
+
+struct Job {
+    name: &'static str,
+    interval: Duration,
+    stats: Arc<JobStats>,
+    handle: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct JobStats {
+    runs: AtomicU64,
+    failures: AtomicU64,
+    last: Mutex<Option<LastRun>>,
+}
+
+struct LastRun {
+    at: Instant,
+    duration: Duration,
+    error: Option<String>,
+}
+
+/// JOBS 命令返回的 job 信息
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobInfo {
+    pub name: &'static str,
+    pub interval: Duration,
+    pub runs: u64,
+    pub failures: u64,
+    /// 距离最近一次执行结束的时间，还没有执行过时为 None
+    pub last_run: Option<Duration>,
+    pub last_duration: Duration,
+    pub last_error: Option<String>,
+}
+
+/// 类似 CLIENT LIST 的单行输出
+impl fmt::Display for JobInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "name={} interval={}ms runs={} failures={}",
+            self.name,
+            self.interval.as_millis(),
+            self.runs,
+            self.failures
+        )?;
+        if let Some(last_run) = self.last_run {
+            write!(
+                f,
+                " last_run={}ms last_duration={}ms",
+                last_run.as_millis(),
+                self.last_duration.as_millis()
+            )?;
+        }
+        if let Some(error) = &self.last_error {
+            write!(f, " last_error={:?}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Scheduler {
+    pub fn new(config: &SchedulerConfig) -> Self {
+        Self {
+            config: config.clone(),
+            jobs: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// 注册一个定期执行的 job，interval 是默认的间隔，配置里可以按名字修改间隔或者关闭。
+    /// 第一次执行在一个间隔之后，上一次执行结束后才开始等待下一次
+    pub fn spawn<F, Fut>(&self, name: &'static str, interval: Duration, job: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), KvError>> + Send + 'static,
+    {
+        let interval = match self.config.jobs.get(name) {
+            Some(c) if !c.enabled => {
+                info!("Job {} is disabled", name);
+                return;
+            }
+            Some(c) => c.interval_ms.map_or(interval, Duration::from_millis),
+            None => interval,
+        };
+        let jitter = self.config.jitter_pct.min(100);
+        let stats = Arc::new(JobStats::default());
+        let job_stats = stats.clone();
+        let handle = tokio::spawn(async move {
+            loop {
+                time::sleep(jittered(interval, jitter)).await;
+                let start = Instant::now();
+                let res = job().await;
+                job_stats.record(start, &res);
+                if let Err(e) = res {
+                    warn!("Job {} failed: {}", name, e);
+                }
+            }
+        });
+        debug!("Scheduled job {} every {:?}", name, interval);
+        self.jobs.lock().unwrap().push(Job {
+            name,
+            interval,
+            stats,
+            handle,
+        });
+    }
+
+    /// 所有 job 的信息，按注册顺序
+    pub fn jobs(&self) -> Vec<JobInfo> {
+        let now = Instant::now();
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|job| {
+                let last = job.stats.last.lock().unwrap();
+                JobInfo {
+                    name: job.name,
+                    interval: job.interval,
+                    runs: job.stats.runs.load(Ordering::Relaxed),
+                    failures: job.stats.failures.load(Ordering::Relaxed),
+                    last_run: last.as_ref().map(|l| now.duration_since(l.at)),
+                    last_duration: last.as_ref().map_or(Duration::ZERO, |l| l.duration),
+                    last_error: last.as_ref().and_then(|l| l.error.clone()),
+                }
+            })
+            .collect()
+    }
+
+    /// 停止所有 job，正在执行的 job 在下一个 await 处取消
+    pub fn shutdown(&self) {
+        for job in self.jobs.lock().unwrap().drain(..) {
+            job.handle.abort();
+        }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl JobStats {
+    fn record(&self, start: Instant, res: &Result<(), KvError>) {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        if res.is_err() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        *self.last.lock().unwrap() = Some(LastRun {
+            at: Instant::now(),
+            duration: start.elapsed(),
+            error: res.as_ref().err().map(|e| e.to_string()),
+        });
+    }
+}
+
+/// 在 interval 上下 pct% 的范围内随机选一个间隔
+fn jittered(interval: Duration, pct: u32) -> Duration {
+    let range = interval.as_millis() as u64 * pct as u64 / 100;
+    if range == 0 {
+        return interval;
+    }
+    let offset = rand::thread_rng().gen_range(0..=2 * range);
+    (interval + Duration::from_millis(offset)).saturating_sub(Duration::from_millis(range))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::JobConfig;
+
+    #[test]
+    fn jitter_should_stay_in_range() {
+        let interval = Duration::from_millis(1000);
+        for _ in 0..100 {
+            let d = jittered(interval, 10);
+            assert!(d >= Duration::from_millis(900) && d <= Duration::from_millis(1100));
+        }
+        assert_eq!(jittered(interval, 0), interval);
+    }
+
+    #[tokio::test]
+    async fn jobs_should_run_periodically_and_record_failures() {
+        let config = SchedulerConfig {
+            jitter_pct: 0,
+            jobs: [(
+                "disabled".to_string(),
+                JobConfig {
+                    enabled: false,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+        };
+        let scheduler = Scheduler::new(&config);
+        let count = Arc::new(AtomicU64::new(0));
+        let c = count.clone();
+        scheduler.spawn("flaky", Duration::from_millis(10), move || {
+            let n = c.fetch_add(1, Ordering::Relaxed);
+            async move {
+                match n % 2 {
+                    0 => Ok(()),
+                    _ => Err(KvError::Internal("boom".into())),
+                }
+            }
+        });
+        scheduler.spawn("disabled", Duration::from_millis(10), || async { Ok(()) });
+
+        while scheduler.jobs()[0].runs < 2 {
+            time::sleep(Duration::from_millis(5)).await;
+        }
+        let jobs = scheduler.jobs();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].name, "flaky");
+        assert!(jobs[0].failures >= 1);
+        assert!(jobs[0].to_string().contains("interval=10ms"));
+
+        // 停止之后不再执行
+        scheduler.shutdown();
+        let runs = count.load(Ordering::Relaxed);
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(count.load(Ordering::Relaxed), runs);
+        assert!(scheduler.jobs().is_empty());
+    }
+}
@@ -1,147 +1,460 @@
-use crate::{CommandResponse, KvError, Value};
-use dashmap::{DashMap, DashSet};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicU32, Ordering};
-use tokio::sync::mpsc;
+use crate::{CommandResponse, Interner, KvError, Scheduler};
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TrySendError};
 use tracing::{debug, info, instrument, warn};
 
 /// topic 里最大存放的数据
 const BROADCAST_CAPACITY: usize = 128;
 
+/// subscriber 队列里积压的消息超过这个数量时打印警告
+const LAG_WARN_THRESHOLD: usize = BROADCAST_CAPACITY * 3 / 4;
+
+/// keyspace 通知的主题前缀，后面跟 table 名
+pub(crate) const KEYSPACE_PREFIX: &str = "__keyspace__:";
+
 /// 下一个 subscription id
 static NEXT_ID: AtomicU32 = AtomicU32::new(1);
 
+/// 主题最近活动时间的起点
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+
 /// 获取下一个 subscription id
 fn get_next_subscription_id() -> u32 {
     NEXT_ID.fetch_add(1, Ordering::Relaxed)
 }
 
+fn now_ms() -> u64 {
+    EPOCH.elapsed().as_millis() as u64
+}
+
+/// table 到 keyspace 通知主题名的缓存，写命令不用每次拼接主题名
+static KEYSPACE_TOPICS: LazyLock<Interner> = LazyLock::new(|| Interner::new(64 * 1024));
+
+/// table 的 keyspace 通知主题，写命令成功后会把修改的 key 发布到这里
+pub fn keyspace_topic(table: &str) -> String {
+    format!("{}{}", KEYSPACE_PREFIX, table)
+}
+
+/// 和 keyspace_topic 一样，同一个 table 返回同一个共享的主题名
+pub(crate) fn interned_keyspace_topic(table: &str) -> Arc<str> {
+    KEYSPACE_TOPICS.intern_with(table, keyspace_topic)
+}
+
 pub trait Topic: Send + Sync + 'static {
-    /// 订阅某个主题
-    fn subscribe(self, name: String) -> mpsc::Receiver<Arc<CommandResponse>>;
+    /// 订阅某个主题，订阅者数量达到上限时返回错误
+    fn subscribe(self, name: Arc<str>) -> Result<mpsc::Receiver<Arc<CommandResponse>>, KvError>;
     /// 取消对主题的订阅
-    fn unsubscribe(self, name: String, id: u32) -> Result<u32, KvError>;
-    /// 往主题里发布一个数据
-    fn publish(self, name: String, value: Arc<CommandResponse>);
+    fn unsubscribe(self, name: Arc<str>, id: u32) -> Result<u32, KvError>;
+    /// 往主题里发布一个数据，返回收到这个数据的 subscriber 数量。
+    /// 数据放进每个 subscriber 的队列后异步发送，返回时不一定已经送达
+    fn publish(self, name: Arc<str>, value: Arc<CommandResponse>) -> usize;
 }
 
+/// 一个主题的订阅者按 subscription id 分到这么多个分片里，
+/// 订阅、取消订阅只锁一个分片，发布时每个分片只锁一次
+const SHARDS: usize = 16;
+
 /// 用于主题发布和订阅的数据结构
-#[derive(Default)]
+///
+/// 每个主题直接持有自己的订阅者列表，发布时只查一次主题表，然后在每个分片里用 try_send
+/// 把消息放进订阅者的队列，不复制订阅者列表，也不为每次发布 spawn 任务。
+/// 队列满了的订阅者的消息按顺序排进它自己的 backlog，由一个后台任务等待发送，不会拖慢其它订阅者
 pub struct Broadcaster {
-    /// 所有的主题列表
-    topics: DashMap<String, DashSet<u32>>,
-    /// 所有的订阅列表
-    subscriptions: DashMap<u32, mpsc::Sender<Arc<CommandResponse>>>,
+    /// 所有的主题，主题名和请求里驻留的字符串共享
+    topics: DashMap<Arc<str>, Arc<TopicState>>,
+    /// 所有主题的订阅者总数
+    subscribers: AtomicUsize,
+    /// 每个主题的订阅者数量上限，usize::MAX 表示不限制
+    max_per_topic: AtomicUsize,
+    /// 订阅者总数的上限，usize::MAX 表示不限制
+    max_total: AtomicUsize,
+}
+
+impl Default for Broadcaster {
+    fn default() -> Self {
+        Self {
+            topics: DashMap::new(),
+            subscribers: AtomicUsize::new(0),
+            max_per_topic: AtomicUsize::new(usize::MAX),
+            max_total: AtomicUsize::new(usize::MAX),
+        }
+    }
+}
+
+#[derive(Default)]
+struct TopicState {
+    shards: [RwLock<Vec<Subscriber>>; SHARDS],
+    published: AtomicU64,
+    dropped: AtomicU64,
+    /// 最近一次订阅或者发布的时间（EPOCH 之后的毫秒数）
+    last_active: AtomicU64,
+}
+
+struct Subscriber {
+    id: u32,
+    tx: mpsc::Sender<Arc<CommandResponse>>,
+    backlog: Arc<Mutex<Backlog>>,
+}
+
+/// 队列满了之后发给订阅者的消息
+#[derive(Default)]
+struct Backlog {
+    messages: VecDeque<Arc<CommandResponse>>,
+    /// 有后台任务正在把 messages 发给订阅者，这期间新的消息也要排在后面，保证顺序
+    draining: bool,
+}
+
+enum Offer {
+    Sent,
+    /// 放进了 backlog，第一个放进去的消息需要启动后台任务
+    Queued {
+        drain: bool,
+    },
+    Closed,
+}
+
+impl Subscriber {
+    fn new(id: u32, tx: mpsc::Sender<Arc<CommandResponse>>) -> Self {
+        Self {
+            id,
+            tx,
+            backlog: Arc::default(),
+        }
+    }
+
+    /// 队列和 backlog 里积压的消息数
+    fn lag(&self) -> usize {
+        let backlog = self.backlog.lock().unwrap().messages.len();
+        self.tx.max_capacity() - self.tx.capacity() + backlog
+    }
+
+    fn offer(&self, value: &Arc<CommandResponse>) -> Offer {
+        let mut backlog = self.backlog.lock().unwrap();
+        if !backlog.draining {
+            match self.tx.try_send(Arc::clone(value)) {
+                Ok(()) => return Offer::Sent,
+                Err(TrySendError::Closed(_)) => return Offer::Closed,
+                Err(TrySendError::Full(_)) => {}
+            }
+        }
+        backlog.messages.push_back(Arc::clone(value));
+        let drain = !backlog.draining;
+        backlog.draining = true;
+        Offer::Queued { drain }
+    }
+}
+
+impl TopicState {
+    fn touch(&self) {
+        self.last_active.store(now_ms(), Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        Duration::from_millis(now_ms().saturating_sub(self.last_active.load(Ordering::Relaxed)))
+    }
+
+    fn shard(&self, id: u32) -> &RwLock<Vec<Subscriber>> {
+        &self.shards[id as usize % SHARDS]
+    }
+
+    fn subscribers(&self) -> impl Iterator<Item = RwLockReadGuard<'_, Vec<Subscriber>>> {
+        self.shards.iter().map(|shard| shard.read().unwrap())
+    }
+
+    fn len(&self) -> usize {
+        self.subscribers().map(|shard| shard.len()).sum()
+    }
+
+    /// 删除所有订阅者，返回删除的数量。和 remove 一样在分片的锁里删除，同一个订阅者只会被算一次
+    fn clear(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.write().unwrap().drain(..).count())
+            .sum()
+    }
+
+    /// 删除订阅者，返回是否存在
+    fn remove(&self, id: u32) -> bool {
+        let mut shard = self.shard(id).write().unwrap();
+        let len = shard.len();
+        shard.retain(|s| s.id != id);
+        shard.len() != len
+    }
+}
+
+/// 某个主题当前的状态，用于判断 subscriber 是否跟不上发布速度
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicMetrics {
+    pub topic: String,
+    pub subscribers: usize,
+    /// 发布到这个主题的消息数
+    pub published: u64,
+    /// 因为 subscriber 已断开而没有送达的消息数
+    pub dropped: u64,
+    /// 所有 subscriber 队列里积压的消息总数
+    pub queue_depth: usize,
+    /// 积压最多的 subscriber 的积压数量
+    pub max_lag: usize,
 }
 
 impl Topic for Arc<Broadcaster> {
     #[instrument(name = "topic_subscribe", skip_all)]
-    fn subscribe(self, name: String) -> mpsc::Receiver<Arc<CommandResponse>> {
-        let id = {
-            let entry = self.topics.entry(name).or_default();
-            let id = get_next_subscription_id();
-            entry.value().insert(id);
-            id
-        };
+    fn subscribe(self, name: Arc<str>) -> Result<mpsc::Receiver<Arc<CommandResponse>>, KvError> {
+        // 先占一个总数的名额，主题的订阅者已满时再还回去
+        let max_total = self.max_total.load(Ordering::Relaxed);
+        let reserved = self
+            .subscribers
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < max_total).then_some(n + 1)
+            });
+        if reserved.is_err() {
+            return Err(KvError::TooManySubscribers(format!(
+                "server already has {} subscribers",
+                max_total
+            )));
+        }
+
+        // 持有主题表的 entry 时检查上限并加入订阅者，同一个主题的订阅者不会超过上限，
+        // 也不会和删除空主题交错
+        let topic = self.topics.entry(Arc::clone(&name)).or_default();
+        let max_per_topic = self.max_per_topic.load(Ordering::Relaxed);
+        if topic.len() >= max_per_topic {
+            self.subscribers.fetch_sub(1, Ordering::Relaxed);
+            return Err(KvError::TooManySubscribers(format!(
+                "topic {} already has {} subscribers",
+                name, max_per_topic
+            )));
+        }
+        topic.touch();
 
         // 生成一个 mpsc channel
         let (tx, rx) = mpsc::channel(BROADCAST_CAPACITY);
+        let id = get_next_subscription_id();
 
-        let v: Value = (id as i64).into();
-
-        // 立刻发送 subscription id 到 rx
-        let tx1 = tx.clone();
-        tokio::spawn(async move {
-            if let Err(e) = tx1.send(Arc::new(v.into())).await {
-                // TODO: 这个很小概率发生，但目前我们没有善后
-                warn!("Failed to send subscription id: {}. Error: {:?}", id, e);
-            }
-        });
-
-        // 把 tx 存入 subscription table
-        self.subscriptions.insert(id, tx);
+        // 新的队列是空的，订阅确认一定能立刻放进去
+        let ack = CommandResponse::subscription_ack(id, &*name);
+        let _ = tx.try_send(Arc::new(ack));
+        topic
+            .shard(id)
+            .write()
+            .unwrap()
+            .push(Subscriber::new(id, tx));
         debug!("Subscription {} is added", id);
 
         // 返回 rx 给网络处理的上下文
-        rx
+        Ok(rx)
     }
 
     #[instrument(name = "topic_unsubscribe", skip_all)]
-    fn unsubscribe(self, name: String, id: u32) -> Result<u32, KvError> {
-        match self.remove_subscription(name, id) {
+    fn unsubscribe(self, name: Arc<str>, id: u32) -> Result<u32, KvError> {
+        match self.remove_subscription(&name, id) {
             Some(id) => Ok(id),
             None => Err(KvError::NotFound(format!("subscription {} ", id))),
         }
     }
 
     #[instrument(name = "topic_publish", skip_all)]
-    fn publish(self, name: String, value: Arc<CommandResponse>) {
-        tokio::spawn(async move {
-            let mut ids = vec![];
-            if let Some(topic) = self.topics.get(&name) {
-                // 复制整个 topic 下所有的 subscription id
-                // 这里我们每个 id 是 u32，如果一个 topic 下有 10k 订阅，复制的成本
-                // 也就是 40k 堆内存（外加一些控制结构），所以效率不算差
-                // 这也是为什么我们用 NEXT_ID 来控制 subscription id 的生成
-
-                let subscriptions = topic.value().clone();
-                // 尽快释放锁
-                drop(topic);
-
-                // 循环发送
-                for id in subscriptions.into_iter() {
-                    if let Some(tx) = self.subscriptions.get(&id)
-                        && let Err(e) = tx.send(value.clone()).await
-                    {
-                        warn!("Publish to {} failed! error: {:?}", id, e);
-                        // client 中断连接
-                        ids.push(id);
+    fn publish(self, name: Arc<str>, value: Arc<CommandResponse>) -> usize {
+        let Some(topic) = self.topics.get(&*name).map(|topic| Arc::clone(&topic)) else {
+            return 0;
+        };
+        topic.published.fetch_add(1, Ordering::Relaxed);
+        topic.touch();
+
+        let mut count = 0;
+        let mut closed = Vec::new();
+        for shard in topic.subscribers() {
+            for sub in shard.iter() {
+                match sub.offer(&value) {
+                    Offer::Sent => {
+                        let lag = sub.lag();
+                        if lag >= LAG_WARN_THRESHOLD {
+                            warn!(
+                                "Subscriber {} of {} is lagging: {} pending",
+                                sub.id, name, lag
+                            );
+                        }
+                    }
+                    Offer::Queued { drain } => {
+                        if drain {
+                            warn!("Subscriber {} of {} is full, queueing", sub.id, name);
+                            self.clone().drain(Arc::clone(&name), &topic, sub);
+                        }
+                    }
+                    // client 中断连接
+                    Offer::Closed => {
+                        closed.push(sub.id);
+                        continue;
                     }
                 }
+                count += 1;
             }
+        }
 
-            for id in ids {
-                self.remove_subscription(name.clone(), id);
+        if !closed.is_empty() {
+            topic
+                .dropped
+                .fetch_add(closed.len() as u64, Ordering::Relaxed);
+            for id in closed {
+                debug!("Publish to {} failed: subscriber is gone", id);
+                self.remove_subscription(&name, id);
             }
-        });
+        }
+        count
     }
 }
 
 impl Broadcaster {
-    pub fn remove_subscription(&self, name: String, id: u32) -> Option<u32> {
-        if let Some(v) = self.topics.get_mut(&name) {
-            // 在 topics 表里找到 topic 的 subscription id，删除
-            v.remove(&id);
-            // 如果这个 topic 为空，则也删除 topic
-            if v.is_empty() {
-                info!("Topic: {:?} is deleted", &name);
-                drop(v);
-                self.topics.remove(&name);
+    /// 按顺序把订阅者 backlog 里的消息发出去，直到 backlog 为空
+    fn drain(self: Arc<Self>, name: Arc<str>, topic: &Arc<TopicState>, sub: &Subscriber) {
+        let (topic, id, tx) = (Arc::clone(topic), sub.id, sub.tx.clone());
+        let backlog = Arc::clone(&sub.backlog);
+        tokio::spawn(async move {
+            loop {
+                let value = {
+                    let mut backlog = backlog.lock().unwrap();
+                    match backlog.messages.pop_front() {
+                        Some(value) => value,
+                        None => {
+                            backlog.draining = false;
+                            return;
+                        }
+                    }
+                };
+                if tx.send(value).await.is_err() {
+                    let lost = 1 + backlog.lock().unwrap().messages.len();
+                    warn!("Publish to {} failed: subscriber is gone", id);
+                    topic.dropped.fetch_add(lost as u64, Ordering::Relaxed);
+                    self.remove_subscription(&name, id);
+                    return;
+                }
             }
-        }
+        });
+    }
+
+    /// 设置每个主题和所有主题的订阅者数量上限，None 表示不限制。已有的订阅者不受影响
+    pub fn set_limits(&self, per_topic: Option<usize>, total: Option<usize>) {
+        let per_topic = per_topic.unwrap_or(usize::MAX);
+        let total = total.unwrap_or(usize::MAX);
+        self.max_per_topic.store(per_topic, Ordering::Relaxed);
+        self.max_total.store(total, Ordering::Relaxed);
+    }
+
+    /// 所有主题的订阅者总数
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.load(Ordering::Relaxed)
+    }
+
+    /// 名字满足 f 的主题的订阅者数量，需要遍历所有主题
+    pub fn subscriber_count_by(&self, f: impl Fn(&str) -> bool) -> usize {
+        self.topics
+            .iter()
+            .filter(|topic| f(topic.key()))
+            .map(|topic| topic.len())
+            .sum()
+    }
+
+    /// 注册定期回收空闲主题的 job，每个 idle 周期执行一次
+    pub fn start_gc(self: &Arc<Self>, scheduler: &Scheduler, idle: Duration) {
+        let broadcaster = self.clone();
+        scheduler.spawn("topic_gc", idle, move || {
+            let broadcaster = broadcaster.clone();
+            async move {
+                let removed = broadcaster.collect_garbage(idle);
+                if removed > 0 {
+                    info!("Removed {} idle topics", removed);
+                }
+                Ok(())
+            }
+        });
+    }
 
+    /// 删除没有 subscriber、并且超过 idle 没有订阅和发布的主题，返回删除的主题数
+    ///
+    /// 取消订阅时最后一个 subscriber 离开的主题会立即删除；这里回收的是 subscriber 已经断开
+    /// 却没有取消订阅的主题
+    pub fn collect_garbage(&self, idle: Duration) -> usize {
+        let len = self.topics.len();
+        self.topics.retain(|name, topic| {
+            let dead = topic
+                .subscribers()
+                .all(|shard| shard.iter().all(|s| s.tx.is_closed()));
+            let collect = dead && topic.idle_for() >= idle;
+            if collect {
+                debug!("Topic {} is collected", name);
+                self.subscribers.fetch_sub(topic.clear(), Ordering::Relaxed);
+            }
+            !collect
+        });
+        len - self.topics.len()
+    }
+
+    /// 所有主题当前的 metrics，按主题名排序
+    pub fn metrics(&self) -> Vec<TopicMetrics> {
+        let mut metrics: Vec<_> = self
+            .topics
+            .iter()
+            .map(|topic| {
+                let lags: Vec<usize> = topic
+                    .subscribers()
+                    .flat_map(|shard| shard.iter().map(Subscriber::lag).collect::<Vec<_>>())
+                    .collect();
+                TopicMetrics {
+                    topic: topic.key().to_string(),
+                    subscribers: lags.len(),
+                    published: topic.published.load(Ordering::Relaxed),
+                    dropped: topic.dropped.load(Ordering::Relaxed),
+                    queue_depth: lags.iter().sum(),
+                    max_lag: lags.iter().copied().max().unwrap_or(0),
+                }
+            })
+            .collect();
+        metrics.sort_by(|a, b| a.topic.cmp(&b.topic));
+        metrics
+    }
+
+    /// 主题是否有订阅者，发布之前需要额外读取数据时用来跳过没人订阅的主题
+    pub fn has_subscribers(&self, name: &str) -> bool {
+        self.topics.get(name).is_some_and(|topic| topic.len() > 0)
+    }
+
+    pub fn remove_subscription(&self, name: &str, id: u32) -> Option<u32> {
+        let topic = self.topics.get(name).map(|topic| Arc::clone(&topic))?;
+        if !topic.remove(id) {
+            return None;
+        }
+        self.subscribers.fetch_sub(1, Ordering::Relaxed);
         debug!("Subscription {} is removed!", id);
-        // 在 subscription 表中同样删除
-        self.subscriptions.remove(&id).map(|(id, _)| id)
+        // 如果这个 topic 为空，则也删除 topic；判断和删除都持有主题表的锁，期间不会有新的订阅
+        if self.topics.remove_if(name, |_, t| t.len() == 0).is_some() {
+            info!("Topic: {:?} is deleted", name);
+        }
+        Some(id)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::assert_res_ok;
+    use crate::{Value, assert_res_ok};
     use std::convert::TryInto;
     use std::slice::from_ref;
+    use std::time::Duration;
     use tokio::sync::mpsc::Receiver;
+    use tokio::time;
 
     #[tokio::test]
     async fn pub_sub_should_work() {
         let b = Arc::new(Broadcaster::default());
-        let lobby = "lobby".to_string();
+        let lobby: Arc<str> = "lobby".into();
 
         // subscribe
-        let mut stream1 = b.clone().subscribe(lobby.clone());
-        let mut stream2 = b.clone().subscribe(lobby.clone());
+        let mut stream1 = b.clone().subscribe(lobby.clone()).unwrap();
+        let mut stream2 = b.clone().subscribe(lobby.clone()).unwrap();
 
         // publish
         let v: Value = "hello".into();
@@ -161,7 +474,7 @@ mod tests {
 
         // 如果 subscriber 取消订阅，则收不到新数据
         let result = b.clone().unsubscribe(lobby.clone(), id1 as _).unwrap();
-        assert_eq!(result, id1 as _);
+        assert_eq!(result, id1);
 
         // publish
         let v: Value = "world".into();
@@ -172,6 +485,132 @@ mod tests {
         assert_res_ok(&res2, from_ref(&v), &[]);
     }
 
+    #[tokio::test]
+    async fn topic_metrics_should_track_lag_and_drops() {
+        let b = Arc::new(Broadcaster::default());
+        let lobby: Arc<str> = "lobby".into();
+
+        let stream1 = b.clone().subscribe(lobby.clone()).unwrap();
+        let mut stream2 = b.clone().subscribe(lobby.clone()).unwrap();
+        get_id(&mut stream2).await;
+
+        // stream1 一直不读，消息积压在它的队列里
+        for i in 0..2 {
+            let v: Value = (i as i64).into();
+            b.clone().publish(lobby.clone(), Arc::new(v.into()));
+        }
+        stream2.recv().await.unwrap();
+        stream2.recv().await.unwrap();
+        time::sleep(Duration::from_millis(10)).await;
+
+        let metrics = b.metrics();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].subscribers, 2);
+        assert_eq!(metrics[0].published, 2);
+        // subscription id 加上两条消息
+        assert_eq!(metrics[0].max_lag, 3);
+        assert_eq!(metrics[0].queue_depth, 3);
+
+        // stream1 断开后，发给它的消息算作丢弃
+        drop(stream1);
+        let v: Value = "hello".into();
+        b.clone().publish(lobby.clone(), Arc::new(v.into()));
+        stream2.recv().await.unwrap();
+        time::sleep(Duration::from_millis(10)).await;
+
+        let metrics = b.metrics();
+        assert_eq!(metrics[0].published, 3);
+        assert_eq!(metrics[0].dropped, 1);
+        assert_eq!(metrics[0].subscribers, 1);
+    }
+
+    #[tokio::test]
+    async fn full_subscriber_should_not_block_others() {
+        let b = Arc::new(Broadcaster::default());
+        let lobby: Arc<str> = "lobby".into();
+        let mut slow = b.clone().subscribe(lobby.clone()).unwrap();
+        let mut fast = b.clone().subscribe(lobby.clone()).unwrap();
+        get_id(&mut fast).await;
+
+        // slow 的队列里已经有 subscription id，再发 BROADCAST_CAPACITY 条就满了
+        let total = BROADCAST_CAPACITY + 5;
+        for i in 0..total {
+            let v: Value = (i as i64).into();
+            assert_eq!(b.clone().publish(lobby.clone(), Arc::new(v.into())), 2);
+            let res = fast.recv().await.unwrap();
+            assert_res_ok(&res, &[(i as i64).into()], &[]);
+        }
+        // 队列满了之后的消息积压在 backlog 里，也算在 lag 里
+        assert!(b.metrics()[0].max_lag > BROADCAST_CAPACITY);
+
+        // slow 开始读之后，等待发送的消息也能按顺序收到
+        get_id(&mut slow).await;
+        for i in 0..total {
+            let res = time::timeout(Duration::from_secs(1), slow.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_res_ok(&res, &[(i as i64).into()], &[]);
+        }
+        assert_eq!(b.metrics()[0].dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn idle_topics_should_be_collected() {
+        let b = Arc::new(Broadcaster::default());
+        // 断开连接但是没有取消订阅
+        let mut stream = b.clone().subscribe("lobby".into()).unwrap();
+        get_id(&mut stream).await;
+        drop(stream);
+        let mut active = b.clone().subscribe("chat".into()).unwrap();
+        get_id(&mut active).await;
+
+        // 还没有空闲足够久
+        assert_eq!(b.collect_garbage(Duration::from_secs(60)), 0);
+        assert_eq!(b.metrics().len(), 2);
+
+        assert_eq!(b.collect_garbage(Duration::ZERO), 1);
+        let metrics = b.metrics();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].topic, "chat");
+        assert_eq!(b.topics.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn subscribers_should_be_limited() {
+        let b = Arc::new(Broadcaster::default());
+        b.set_limits(Some(2), Some(3));
+        let lobby: Arc<str> = "lobby".into();
+        let chat: Arc<str> = "chat".into();
+
+        let mut stream1 = b.clone().subscribe(lobby.clone()).unwrap();
+        let _stream2 = b.clone().subscribe(lobby.clone()).unwrap();
+        let err = b.clone().subscribe(lobby.clone()).unwrap_err();
+        assert!(matches!(err, KvError::TooManySubscribers(_)));
+
+        // 主题没满，但是总数满了
+        let stream3 = b.clone().subscribe(chat.clone()).unwrap();
+        assert!(b.clone().subscribe(chat.clone()).is_err());
+        assert_eq!(b.subscriber_count(), 3);
+
+        // 取消订阅之后空出名额
+        let id1 = get_id(&mut stream1).await;
+        b.clone().unsubscribe(lobby.clone(), id1).unwrap();
+        let stream4 = b.clone().subscribe(chat.clone()).unwrap();
+        assert_eq!(b.subscriber_count(), 3);
+
+        // 回收的主题里的订阅者也不再计数
+        drop(stream3);
+        drop(stream4);
+        assert_eq!(b.collect_garbage(Duration::ZERO), 1);
+        assert_eq!(b.subscriber_count(), 1);
+
+        b.set_limits(None, None);
+        for _ in 0..3 {
+            b.clone().subscribe(lobby.clone()).unwrap();
+        }
+    }
+
     pub async fn get_id(res: &mut Receiver<Arc<CommandResponse>>) -> u32 {
         let id: i64 = res.recv().await.unwrap().as_ref().try_into().unwrap();
         id as u32
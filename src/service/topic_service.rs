@@ -1,6 +1,6 @@
 use crate::service::topic::Topic;
-use crate::{CommandResponse, Publish, Subscribe, Unsubscribe};
-use futures::{Stream, stream};
+use crate::{CommandResponse, Publish, Subscribe, Unsubscribe, Value, intern, unary};
+use futures::Stream;
 use std::pin::Pin;
 use std::sync::Arc;
 use tokio_stream::wrappers::ReceiverStream;
@@ -13,25 +13,29 @@ pub trait TopicService {
 
 impl TopicService for Subscribe {
     fn execute(self, topic: impl Topic) -> StreamingResponse {
-        let rx = topic.subscribe(self.topic);
-        Box::pin(ReceiverStream::new(rx))
+        match topic.subscribe(intern(&self.topic)) {
+            Ok(rx) => Box::pin(ReceiverStream::new(rx)),
+            Err(e) => unary(e.into()),
+        }
     }
 }
 
 impl TopicService for Unsubscribe {
     fn execute(self, topic: impl Topic) -> StreamingResponse {
-        let res = match topic.unsubscribe(self.topic, self.id) {
+        let res = match topic.unsubscribe(intern(&self.topic), self.id) {
             Ok(_) => CommandResponse::ok(),
             Err(e) => e.into(),
         };
-        Box::pin(stream::once(async { Arc::new(res) }))
+        unary(res)
     }
 }
 
 impl TopicService for Publish {
     fn execute(self, topic: impl Topic) -> StreamingResponse {
-        topic.publish(self.topic, Arc::new(self.data.into()));
-        Box::pin(stream::once(async { Arc::new(CommandResponse::ok()) }))
+        // 返回收到数据的 subscriber 数量，为 0 说明没有人订阅这个主题
+        let receivers = topic.publish(intern(&self.topic), Arc::new(self.data.into()));
+        let res: CommandResponse = Value::from(receivers as i64).into();
+        unary(res)
     }
 }
 
@@ -49,7 +53,28 @@ mod tests {
         let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
         let mut res = dispatch_stream(cmd, topic);
         let data = res.next().await.unwrap();
-        assert_res_ok(&data, &[], &[]);
+        assert_res_ok(&data, &[0.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn publish_should_report_receiver_count() {
+        let topic = Arc::new(Broadcaster::default());
+        let mut sub1 = dispatch_stream(CommandRequest::new_subscribe("lobby"), topic.clone());
+        get_id(&mut sub1).await;
+        let mut sub2 = dispatch_stream(CommandRequest::new_subscribe("lobby"), topic.clone());
+        get_id(&mut sub2).await;
+
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        let data = dispatch_stream(cmd.clone(), topic.clone())
+            .next()
+            .await
+            .unwrap();
+        assert_res_ok(&data, &[2.into()], &[]);
+
+        // 断开的 subscriber 不计入
+        drop(sub2);
+        let data = dispatch_stream(cmd, topic).next().await.unwrap();
+        assert_res_ok(&data, &[1.into()], &[]);
     }
 
     #[tokio::test]
@@ -61,6 +86,23 @@ mod tests {
         assert!(id > 0);
     }
 
+    #[tokio::test]
+    async fn dispatch_subscribe_over_limit_should_error() {
+        let topic = Arc::new(Broadcaster::default());
+        topic.set_limits(Some(1), None);
+        let mut res = dispatch_stream(CommandRequest::new_subscribe("lobby"), topic.clone());
+        get_id(&mut res).await;
+
+        let mut res = dispatch_stream(CommandRequest::new_subscribe("lobby"), topic);
+        let data = res.next().await.unwrap();
+        assert_res_error(
+            &data,
+            429,
+            "Too many subscribers: topic lobby already has 1 subscribers",
+        );
+        assert!(res.next().await.is_none());
+    }
+
     #[tokio::test]
     async fn dispatch_subscribe_abnormal_quit_should_be_removed_on_next_publish() {
         let topic = Arc::new(Broadcaster::default());
@@ -0,0 +1,163 @@
+use crate::{ClientConfig, LogConfig, ServerConfig, StorageConfig, generate_keypair};
+use anyhow::{Result, bail};
+use certify::{CA, CertSigAlgo, generate_ca, generate_cert};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// 生成一套可以直接使用的 TLS 配置：CA、服务器证书、客户端证书，以及引用这些文件的
+/// server.conf 和 client.conf。服务器要求客户端证书（mTLS），数据和日志也放在 dir 下。
+/// 文件已经存在时不会覆盖，除非 force 为 true。返回写入的文件
+pub fn gen_config(dir: &Path, addr: &str, domain: &str, force: bool) -> Result<Vec<PathBuf>> {
+    let names = [
+        "ca.cert",
+        "ca.key",
+        "server.cert",
+        "server.key",
+        "client.cert",
+        "client.key",
+        "server.conf",
+        "client.conf",
+    ];
+    let dir = prepare_dir(dir, &names, force)?;
+    let path = |name: &str| dir.join(name).display().to_string();
+
+    let (ca_cert, ca_key) = generate_ca(
+        domain,
+        "CN",
+        "Acme Inc.",
+        CertSigAlgo::EcDsa,
+        None,
+        Some(10 * 365),
+    )?;
+    let ca = CA::load(&ca_cert, &ca_key)?;
+    let (server_cert, server_key) = generate_cert(
+        &ca,
+        vec![domain],
+        "CN",
+        "Acme Inc.",
+        "KV server",
+        CertSigAlgo::EcDsa,
+        None,
+        false,
+        Some(5 * 365),
+    )?;
+    let (client_cert, client_key) = generate_cert(
+        &ca,
+        Vec::<String>::new(),
+        "CN",
+        "Acme Inc.",
+        "KV client",
+        CertSigAlgo::EcDsa,
+        None,
+        true,
+        Some(365),
+    )?;
+    for (name, pem) in [
+        ("ca.cert", &ca_cert),
+        ("ca.key", &ca_key),
+        ("server.cert", &server_cert),
+        ("server.key", &server_key),
+        ("client.cert", &client_cert),
+        ("client.key", &client_key),
+    ] {
+        fs::write(path(name), pem)?;
+    }
+
+    // 证书已经写好，build() 的检查会确认私钥和证书匹配
+    let server = ServerConfig::builder()
+        .addr(addr)
+        .storage(StorageConfig::SledDb(path("data")))
+        .tls_files(
+            path("server.cert"),
+            path("server.key"),
+            Some(path("ca.cert")),
+        )
+        .log(LogConfig {
+            path: path("log"),
+            enable_log_file: true,
+            ..Default::default()
+        })
+        .build()?;
+    let mut client = ClientConfig::builder()
+        .addr(addr)
+        .tls_files(domain, Some(path("ca.cert")))
+        .build()?;
+    if let Some(tls) = client.tls.as_mut() {
+        tls.identity_path = Some((path("client.cert"), path("client.key")));
+    }
+    client.validate()?;
+    fs::write(path("server.conf"), toml::to_string_pretty(&server)?)?;
+    fs::write(path("client.conf"), toml::to_string_pretty(&client)?)?;
+
+    Ok(names.iter().map(|name| dir.join(name)).collect())
+}
+
+/// 生成 Noise 协议使用的服务器和客户端密钥对，base64 编码。
+/// 文件已经存在时不会覆盖，除非 force 为 true。返回写入的文件
+pub fn gen_keys(dir: &Path, force: bool) -> Result<Vec<PathBuf>> {
+    let names = ["server.key", "server.pub", "client.key", "client.pub"];
+    let dir = prepare_dir(dir, &names, force)?;
+    for role in ["server", "client"] {
+        let (private, public) = generate_keypair()?;
+        fs::write(dir.join(format!("{}.key", role)), private)?;
+        fs::write(dir.join(format!("{}.pub", role)), public)?;
+    }
+    Ok(names.iter().map(|name| dir.join(name)).collect())
+}
+
+/// 创建目录，返回绝对路径，这样生成的配置在哪个目录下启动都能用
+fn prepare_dir(dir: &Path, names: &[&str], force: bool) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let dir = fs::canonicalize(dir)?;
+    if !force {
+        let existing: Vec<_> = names
+            .iter()
+            .filter(|name| dir.join(name).exists())
+            .copied()
+            .collect();
+        if !existing.is_empty() {
+            bail!(
+                "{} already exist in {}, use --force to overwrite",
+                existing.join(", "),
+                dir.display()
+            );
+        }
+    }
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load_key_file;
+
+    #[test]
+    fn gen_config_should_produce_valid_configs() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = gen_config(dir.path(), "127.0.0.1:9527", "localhost", false).unwrap();
+        assert_eq!(files.len(), 8);
+
+        let server = ServerConfig::load(&files[6].display().to_string()).unwrap();
+        let pem = server.tls.unwrap().load_pem().unwrap();
+        assert!(pem.ca.is_some());
+        let client = ClientConfig::load(&files[7].display().to_string()).unwrap();
+        assert!(client.tls.unwrap().load_pem().unwrap().identity.is_some());
+
+        // 不指定 force 时不覆盖已有的文件
+        assert!(gen_config(dir.path(), "127.0.0.1:9527", "localhost", false).is_err());
+        assert!(gen_config(dir.path(), "127.0.0.1:9527", "localhost", true).is_ok());
+    }
+
+    #[test]
+    fn gen_keys_should_produce_noise_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = gen_keys(dir.path(), false).unwrap();
+        for file in &files {
+            assert_eq!(
+                load_key_file(&file.display().to_string()).unwrap().len(),
+                32
+            );
+        }
+        assert!(gen_keys(dir.path(), false).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use crate::{ClientConfig, CommandRequest, KvClient, KvError, ShadowConfig};
+use rand::Rng;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+/// 连接影子服务器失败后，等待多久再重新连接，期间的命令直接丢弃
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 影子流量
+///
+/// 抽样选中的数据命令放进有界队列，由后台任务依次发给影子服务器，响应直接丢弃。
+/// 队列满了就丢弃新的命令，影子服务器变慢或者不可用都不会影响正常请求
+pub struct Shadow {
+    tx: mpsc::Sender<CommandRequest>,
+    sample_ratio: f64,
+    /// 因为队列满了而丢弃的命令数
+    dropped: AtomicU64,
+}
+
+impl Shadow {
+    /// 启动转发命令的后台任务，需要在 tokio runtime 里调用
+    pub fn start(config: &ShadowConfig) -> Result<Self, KvError> {
+        let remote = config
+            .remote
+            .clone()
+            .ok_or_else(|| KvError::InvalidConfig("shadow.remote is required".into()))?;
+        let (shadow, rx) = Self::new(config);
+        info!("Mirroring traffic to shadow server {}", remote.general.addr);
+        tokio::spawn(forward(remote, rx));
+        Ok(shadow)
+    }
+
+    fn new(config: &ShadowConfig) -> (Self, mpsc::Receiver<CommandRequest>) {
+        let (tx, rx) = mpsc::channel(config.queue_capacity.max(1));
+        let shadow = Self {
+            tx,
+            sample_ratio: config.sample_ratio.clamp(0.0, 1.0),
+            dropped: AtomicU64::new(0),
+        };
+        (shadow, rx)
+    }
+
+    /// 按比例抽样，选中的数据命令放进队列，不等待转发的结果
+    pub fn offer(&self, cmd: &CommandRequest) {
+        if cmd.table().is_none() || !rand::thread_rng().gen_bool(self.sample_ratio) {
+            return;
+        }
+        if self.tx.try_send(cmd.clone()).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// 依次把队列里的命令发给影子服务器，忽略响应
+async fn forward(remote: ClientConfig, mut rx: mpsc::Receiver<CommandRequest>) {
+    let addr = remote.general.addr.clone();
+    let mut client: Option<KvClient> = None;
+    let mut retry_at = Instant::now();
+    while let Some(cmd) = rx.recv().await {
+        let client = match &mut client {
+            Some(client) => client,
+            None if Instant::now() < retry_at => continue,
+            None => match KvClient::connect(remote.clone()).await {
+                Ok(c) => client.insert(c),
+                Err(e) => {
+                    warn!("Failed to connect to shadow server {}: {}", addr, e);
+                    retry_at = Instant::now() + RECONNECT_INTERVAL;
+                    continue;
+                }
+            },
+        };
+        if let Err(e) = client.execute_unary(cmd).await {
+            debug!("Shadow request to {} failed: {}", addr, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn only_sampled_data_commands_should_be_queued() {
+        let config = ShadowConfig {
+            queue_capacity: 1,
+            ..Default::default()
+        };
+        let (shadow, mut rx) = Shadow::new(&config);
+        shadow.offer(&CommandRequest::new_latency());
+        shadow.offer(&CommandRequest::new_hset("t1", "k1", "v1".into()));
+        // 队列满了，丢弃而不是等待
+        shadow.offer(&CommandRequest::new_hget("t1", "k1"));
+        assert_eq!(shadow.dropped(), 1);
+        assert_eq!(rx.recv().await.unwrap().name(), "hset");
+        assert!(rx.try_recv().is_err());
+
+        let config = ShadowConfig {
+            sample_ratio: 0.0,
+            ..Default::default()
+        };
+        let (shadow, mut rx) = Shadow::new(&config);
+        shadow.offer(&CommandRequest::new_hget("t1", "k1"));
+        assert!(rx.try_recv().is_err());
+    }
+}
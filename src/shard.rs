@@ -0,0 +1,305 @@
+use crate::{
+    CommandRequest, CommandResponse, KvError, PeerClient, ShardMode, ShardingConfig, Value, value,
+};
+use std::time::Duration;
+
+/// slot 的数量，table 先映射到 slot，再由 slot 找到所在的节点
+pub const SLOTS: u16 = 1024;
+
+/// FNV-1a 之后再做一次 splitmix64 的混合
+///
+/// 和 DefaultHasher 不同，不同的进程、不同的 Rust 版本得到的结果都一样，
+/// 服务器和客户端可以各自算出同一个 slot
+fn hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in data {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xbf58476d1ce4e5b9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94d049bb133111eb);
+    h ^ (h >> 31)
+}
+
+/// key（数据命令的 table，pub/sub 命令的 topic）所在的 slot
+pub fn key_slot(key: &str) -> u16 {
+    (hash(key.as_bytes()) % SLOTS as u64) as u16
+}
+
+/// slot 到节点的映射
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlotMap {
+    nodes: Vec<String>,
+    /// 每个 slot 所在节点在 nodes 里的下标
+    slots: Vec<usize>,
+}
+
+impl SlotMap {
+    /// 用一致性 hash 把 slot 分配给节点
+    ///
+    /// 每个节点在 hash 环上有 virtual_nodes 个点，slot 按编号均匀分布在环上，
+    /// 属于顺时针方向遇到的第一个点所在的节点。增减节点时只有相邻区间里的 slot 会换节点
+    pub fn new(nodes: Vec<String>, virtual_nodes: usize) -> Self {
+        let mut ring: Vec<(u64, usize)> = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(i, addr)| {
+                (0..virtual_nodes).map(move |v| (hash(format!("{}#{}", addr, v).as_bytes()), i))
+            })
+            .collect();
+        ring.sort_unstable();
+
+        let step = u64::MAX / SLOTS as u64;
+        let slots = if ring.is_empty() {
+            Vec::new()
+        } else {
+            (0..SLOTS as u64)
+                .map(|slot| {
+                    let i = ring.partition_point(|(point, _)| *point < slot * step);
+                    ring[i % ring.len()].1
+                })
+                .collect()
+        };
+        Self { nodes, slots }
+    }
+
+    /// 所有节点的地址
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    /// slot 所在节点在 nodes 里的下标
+    pub fn owner_index(&self, slot: u16) -> Option<usize> {
+        self.slots.get(slot as usize).copied()
+    }
+
+    /// slot 所在节点的地址
+    pub fn owner(&self, slot: u16) -> Option<&str> {
+        self.owner_index(slot).map(|i| self.nodes[i].as_str())
+    }
+
+    /// 属于同一个节点的连续 slot 合并成一段：(起始 slot, 结束 slot, 节点地址)
+    pub fn ranges(&self) -> Vec<(u16, u16, &str)> {
+        let mut ranges: Vec<(u16, u16, &str)> = Vec::new();
+        for (slot, &i) in self.slots.iter().enumerate() {
+            let addr = self.nodes[i].as_str();
+            match ranges.last_mut() {
+                Some((_, end, last)) if *last == addr => *end = slot as u16,
+                _ => ranges.push((slot as u16, slot as u16, addr)),
+            }
+        }
+        ranges
+    }
+
+    /// CLUSTER SLOTS 的响应，每三个 value 是一段：起始 slot, 结束 slot, 节点地址
+    pub fn to_response(&self) -> CommandResponse {
+        self.ranges()
+            .into_iter()
+            .flat_map(|(start, end, addr)| {
+                [
+                    Value::from(start as i64),
+                    Value::from(end as i64),
+                    Value::from(addr),
+                ]
+            })
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    /// 从 CLUSTER SLOTS 的响应里解出 slot 分布，所有 slot 都要有节点
+    pub fn from_response(res: &CommandResponse) -> Result<Self, KvError> {
+        let invalid = || KvError::Internal("invalid cluster slots".into());
+        if !res.values.len().is_multiple_of(3) {
+            return Err(invalid());
+        }
+
+        let mut nodes: Vec<String> = Vec::new();
+        let mut slots = vec![usize::MAX; SLOTS as usize];
+        for range in res.values.chunks(3) {
+            let start: i64 = (&range[0]).try_into()?;
+            let end: i64 = (&range[1]).try_into()?;
+            let addr = match &range[2].value {
+                Some(value::Value::String(addr)) => addr,
+                _ => return Err(invalid()),
+            };
+            if start < 0 || start > end || end >= SLOTS as i64 {
+                return Err(invalid());
+            }
+
+            let i = match nodes.iter().position(|node| node == addr) {
+                Some(i) => i,
+                None => {
+                    nodes.push(addr.clone());
+                    nodes.len() - 1
+                }
+            };
+            slots[start as usize..=end as usize].fill(i);
+        }
+
+        if slots.contains(&usize::MAX) {
+            return Err(invalid());
+        }
+        Ok(Self { nodes, slots })
+    }
+}
+
+/// 分片集群里服务器这一侧的路由
+///
+/// 只有数据命令按 table 分片；pub/sub 命令在收到命令的节点上处理，
+/// 客户端使用 `Routing::Slots` 时会按 topic 把同一个 topic 的命令发到同一个节点。
+/// 所有节点的 nodes 配置必须一致，否则代理的请求可能在节点之间来回转发
+pub struct ShardRouter {
+    /// 本节点在 nodes 里的下标
+    id: usize,
+    map: SlotMap,
+    mode: ShardMode,
+    /// 到其它节点的连接，下标和 nodes 一致，本节点的位置是 None
+    peers: Vec<Option<PeerClient>>,
+    timeout: Duration,
+}
+
+impl ShardRouter {
+    pub fn new(config: &ShardingConfig) -> Result<Self, KvError> {
+        let id = config.id as usize - 1;
+        let mut peers = Vec::with_capacity(config.nodes.len());
+        for (i, addr) in config.nodes.iter().enumerate() {
+            let peer = if i == id {
+                None
+            } else {
+                Some(PeerClient::new(addr, config.client.as_ref())?)
+            };
+            peers.push(peer);
+        }
+
+        Ok(Self {
+            id,
+            map: SlotMap::new(config.nodes.clone(), config.virtual_nodes),
+            mode: config.mode,
+            peers,
+            timeout: Duration::from_millis(config.proxy_timeout_ms),
+        })
+    }
+
+    pub fn slots(&self) -> &SlotMap {
+        &self.map
+    }
+
+    pub fn mode(&self) -> ShardMode {
+        self.mode
+    }
+
+    /// 命令操作的 table 不在本节点时，返回它所在的 slot 和节点地址
+    pub fn route(&self, cmd: &CommandRequest) -> Option<(u16, &str)> {
+        let slot = key_slot(cmd.table()?);
+        match self.map.owner_index(slot)? {
+            i if i == self.id => None,
+            _ => Some((slot, self.map.owner(slot)?)),
+        }
+    }
+
+    /// 把命令转发给 slot 所在的节点
+    pub async fn forward(&self, slot: u16, cmd: CommandRequest) -> CommandResponse {
+        let peer = self
+            .map
+            .owner_index(slot)
+            .and_then(|i| self.peers[i].as_ref());
+        match peer {
+            Some(peer) => match peer.call(cmd, self.timeout).await {
+                Ok(res) => res,
+                Err(e) => e.into(),
+            },
+            None => KvError::Internal(format!("no node for slot {}", slot)).into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("127.0.0.1:{}", 9527 + i)).collect()
+    }
+
+    #[test]
+    fn key_slot_should_be_stable() {
+        // 固定的值，保证不同版本的服务器和客户端算出同一个 slot
+        assert_eq!(key_slot("table1"), 896);
+        assert_eq!(key_slot("hello"), 271);
+        assert_eq!(key_slot(""), 155);
+    }
+
+    #[test]
+    fn slot_map_should_spread_slots_across_nodes() {
+        let map = SlotMap::new(nodes(3), 64);
+        for i in 0..3 {
+            let count = (0..SLOTS)
+                .filter(|slot| map.owner_index(*slot) == Some(i))
+                .count();
+            // 每个节点至少分到 1/6 的 slot
+            assert!(
+                count > SLOTS as usize / 6,
+                "node {} owns {} slots",
+                i,
+                count
+            );
+        }
+    }
+
+    #[test]
+    fn adding_node_should_only_move_slots_to_new_node() {
+        let old = SlotMap::new(nodes(3), 64);
+        let new = SlotMap::new(nodes(4), 64);
+        for slot in 0..SLOTS {
+            let owner = new.owner(slot).unwrap();
+            assert!(owner == old.owner(slot).unwrap() || owner == "127.0.0.1:9530");
+        }
+    }
+
+    #[test]
+    fn slot_map_response_should_roundtrip() {
+        let map = SlotMap::new(nodes(3), 8);
+        let res = map.to_response();
+        assert_eq!(res.values.len(), map.ranges().len() * 3);
+        assert_eq!(SlotMap::from_response(&res).unwrap().ranges(), map.ranges());
+
+        // 缺少 slot 的响应不合法
+        let values: Vec<Value> = vec![0.into(), 10.into(), "127.0.0.1:9527".into()];
+        let res: CommandResponse = values.into();
+        assert!(SlotMap::from_response(&res).is_err());
+    }
+
+    #[test]
+    fn shard_router_should_route_foreign_tables() {
+        let config = ShardingConfig {
+            enabled: true,
+            id: 1,
+            nodes: nodes(2),
+            ..Default::default()
+        };
+        let router = ShardRouter::new(&config).unwrap();
+
+        let mut local = 0;
+        for i in 0..100 {
+            let table = format!("table{}", i);
+            let cmd = CommandRequest::new_hget(&table, "k1");
+            match router.route(&cmd) {
+                Some((slot, addr)) => {
+                    assert_eq!(slot, key_slot(&table));
+                    assert_eq!(addr, "127.0.0.1:9528");
+                }
+                None => local += 1,
+            }
+        }
+        assert!(local > 0 && local < 100);
+
+        // pub/sub 命令不分片
+        assert!(
+            router
+                .route(&CommandRequest::new_publish("t", vec![]))
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use super::LruCache;
+use crate::{KvError, Kvpair, Storage, StorageSnapshot, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+/// 按 key 分片的锁的数量
+const LOCK_STRIPES: usize = 64;
+
+/// 把快的存储（比如 MemTable）放在慢的存储（比如 sled）前面做缓存
+///
+/// 写入先写 slow 再写 fast，slow 失败时 fast 不变；读取先读 fast，没有时从 slow 读出来放进 fast。
+/// slow 保存完整的数据，遍历、范围查询和 table 列表都直接读 slow。
+/// fast 最多保留 capacity 个 key，超出时把最久没用的 key 从 fast 里删掉
+pub struct CachedStorage<Fast, Slow> {
+    fast: Fast,
+    slow: Slow,
+    /// 同一个 key 的写入和缓存填充互斥，避免并发的删除之后把旧的值放回 fast
+    locks: [Mutex<()>; LOCK_STRIPES],
+    /// fast 里有哪些 key 以及它们的新旧。先写 fast 再记到这里，
+    /// 淘汰时先从这里去掉再删 fast，fast 里的 key 总是被记录着，不会漏掉淘汰
+    keys: Mutex<LruCache<(String, String), ()>>,
+}
+
+impl<Fast: Storage, Slow: Storage> CachedStorage<Fast, Slow> {
+    pub fn new(fast: Fast, slow: Slow, capacity: usize) -> Self {
+        Self {
+            fast,
+            slow,
+            locks: std::array::from_fn(|_| Mutex::new(())),
+            keys: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    pub fn fast(&self) -> &Fast {
+        &self.fast
+    }
+
+    pub fn slow(&self) -> &Slow {
+        &self.slow
+    }
+
+    fn lock(&self, table: &str, key: &str) -> std::sync::MutexGuard<'_, ()> {
+        let mut hasher = DefaultHasher::new();
+        (table, key).hash(&mut hasher);
+        let lock = &self.locks[hasher.finish() as usize % LOCK_STRIPES];
+        lock.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn keys(&self) -> std::sync::MutexGuard<'_, LruCache<(String, String), ()>> {
+        self.keys.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 把 fast 里的 value 换成 value，超出容量时删掉最久没用的 key
+    fn fill(&self, table: &str, key: String, value: Value) -> Result<(), KvError> {
+        self.fast.set(table, key.clone(), value)?;
+        let evicted = self.keys().insert((table.into(), key), ());
+        if let Some(((table, key), _)) = evicted {
+            self.fast.del(&table, &key)?;
+        }
+        Ok(())
+    }
+
+    fn all_locks(&self) -> Vec<std::sync::MutexGuard<'_, ()>> {
+        self.locks
+            .iter()
+            .map(|lock| lock.lock().unwrap_or_else(|e| e.into_inner()))
+            .collect()
+    }
+}
+
+impl<Fast: Storage, Slow: Storage> Storage for CachedStorage<Fast, Slow> {
+    fn name(&self) -> &'static str {
+        "cached"
+    }
+
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        if let Some(v) = self.fast.get(table, key)? {
+            self.keys().get(&(table.into(), key.into()));
+            return Ok(Some(v));
+        }
+        let _guard = self.lock(table, key);
+        // 等锁的时候可能已经被其它请求放进了 fast
+        if let Some(v) = self.fast.get(table, key)? {
+            return Ok(Some(v));
+        }
+        let value = self.slow.get(table, key)?;
+        if let Some(v) = &value {
+            self.fill(table, key.into(), v.clone())?;
+        }
+        Ok(value)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let _guard = self.lock(table, &key);
+        let old = self.slow.set(table, key.clone(), value.clone())?;
+        self.fill(table, key, value)?;
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(self.fast.contains(table, key)? || self.slow.contains(table, key)?)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let _guard = self.lock(table, key);
+        let old = self.slow.del(table, key)?;
+        self.keys().remove(&(table.into(), key.into()));
+        self.fast.del(table, key)?;
+        Ok(old)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.slow.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.slow.get_iter(table)
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        self.slow.tables()
+    }
+
+    fn get_iter_snapshot(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.slow.get_iter_snapshot(table)
+    }
+
+    fn snapshot_handle(&self) -> Result<Box<dyn StorageSnapshot>, KvError> {
+        self.slow.snapshot_handle()
+    }
+
+    fn table_exists(&self, table: &str) -> Result<bool, KvError> {
+        self.slow.table_exists(table)
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.slow.get_range(table, start, end)
+    }
+
+    fn get_prefix(
+        &self,
+        table: &str,
+        prefix: &str,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.slow.get_prefix(table, prefix)
+    }
+
+    fn del_prefix(&self, table: &str, prefix: &str) -> Result<usize, KvError> {
+        // 不知道会删除哪些 key，拿着所有的锁，删除期间其它的写入和缓存填充都要等待
+        let _guards = self.all_locks();
+        let count = self.slow.del_prefix(table, prefix)?;
+        self.keys()
+            .retain(|(t, k)| t != table || !k.starts_with(prefix));
+        self.fast.del_prefix(table, prefix)?;
+        Ok(count)
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// 简单的 LRU 缓存，用递增的访问序号记录新旧，淘汰序号最小的
+pub struct LruCache<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (V, u64)>,
+    order: BTreeMap<u64, K>,
+}
+
+impl<K, V> LruCache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// 读取一个 key，同时把它标记为最近使用
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        let (v, t) = self.entries.get_mut(key)?;
+        self.order.remove(&*t);
+        *t = self.tick;
+        self.order.insert(self.tick, key.clone());
+        Some(v.clone())
+    }
+
+    /// 写入一个 key，超出容量时淘汰最久没用的，返回被淘汰的 kv
+    pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)> {
+        if self.capacity == 0 {
+            return Some((key, value));
+        }
+
+        self.tick += 1;
+        if let Some((_, t)) = self.entries.insert(key.clone(), (value, self.tick)) {
+            self.order.remove(&t);
+        }
+        self.order.insert(self.tick, key);
+
+        if self.entries.len() <= self.capacity {
+            return None;
+        }
+        let (_, k) = self.order.pop_first()?;
+        let (v, _) = self.entries.remove(&k)?;
+        Some((k, v))
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (v, t) = self.entries.remove(key)?;
+        self.order.remove(&t);
+        Some(v)
+    }
+
+    /// 只保留 f 返回 true 的 key
+    pub fn retain(&mut self, mut f: impl FnMut(&K) -> bool) {
+        self.entries.retain(|k, _| f(k));
+        self.order.retain(|_, k| f(k));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru_should_evict_least_recently_used() {
+        let mut lru = LruCache::new(2);
+        lru.insert("k1", 1);
+        lru.insert("k2", 2);
+
+        // 访问 k1 之后，k2 变成最久没用的
+        assert_eq!(lru.get(&"k1"), Some(1));
+        assert_eq!(lru.insert("k3", 3), Some(("k2", 2)));
+
+        assert_eq!(lru.entries.len(), 2);
+        assert_eq!(lru.get(&"k2"), None);
+        assert_eq!(lru.get(&"k1"), Some(1));
+        assert_eq!(lru.get(&"k3"), Some(3));
+
+        assert_eq!(lru.remove(&"k1"), Some(1));
+        assert_eq!(lru.entries.len(), 1);
+    }
+}
@@ -13,7 +13,7 @@ impl MemTable {
         Self::default()
     }
 
-    /// 如果名为 name 的 hash table 不存在，则创建，否则返回
+    /// 如果名为 name 的 hash table 不存在，则创建，否则返回。只有写入才会创建 table
     fn get_or_create_table(&self, name: &str) -> Ref<'_, String, DashMap<String, Value>> {
         match self.tables.get(name) {
             Some(table) => table,
@@ -26,8 +26,14 @@ impl MemTable {
 }
 
 impl Storage for MemTable {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
+        let Some(table) = self.tables.get(table) else {
+            return Ok(None);
+        };
         Ok(table.get(key).map(|v| v.value().clone()))
     }
 
@@ -37,17 +43,23 @@ impl Storage for MemTable {
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.contains_key(key))
+        Ok(self
+            .tables
+            .get(table)
+            .is_some_and(|table| table.contains_key(key)))
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
+        let Some(table) = self.tables.get(table) else {
+            return Ok(None);
+        };
         Ok(table.remove(key).map(|(_k, v)| v))
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
-        let table = self.get_or_create_table(table);
+        let Some(table) = self.tables.get(table) else {
+            return Ok(Vec::new());
+        };
         Ok(table
             .iter()
             .map(|v| Kvpair::new(v.key(), v.value().clone()))
@@ -55,10 +67,39 @@ impl Storage for MemTable {
     }
 
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
-        let table = self.get_or_create_table(table).clone();
+        let table = match self.tables.get(table) {
+            Some(table) => table.clone(),
+            None => DashMap::new(),
+        };
         let iter = StorageIter::new(table.into_iter());
         Ok(Box::new(iter))
     }
+
+    fn get_iter_snapshot(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        // get_iter 遍历的是复制出来的 table
+        self.get_iter(table)
+    }
+
+    fn del_prefix(&self, table: &str, prefix: &str) -> Result<usize, KvError> {
+        let Some(table) = self.tables.get(table) else {
+            return Ok(0);
+        };
+        let mut count = 0;
+        table.retain(|k, _| {
+            let matched = k.starts_with(prefix);
+            count += matched as usize;
+            !matched
+        });
+        Ok(count)
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        Ok(self.tables.iter().map(|v| v.key().clone()).collect())
+    }
+
+    fn table_exists(&self, table: &str) -> Result<bool, KvError> {
+        Ok(self.tables.contains_key(table))
+    }
 }
 
 impl From<(String, Value)> for Kvpair {
@@ -1,14 +1,34 @@
+mod cached;
+mod lru;
 mod memory;
+mod ordered;
 mod sleddb;
+mod snapshot;
+mod ttl_cache;
 // mod rocksdb;
 
+pub use cached::CachedStorage;
+pub(crate) use lru::LruCache;
 pub use memory::MemTable;
+pub use ordered::MemTableOrdered;
 pub use sleddb::SledDb;
+pub use snapshot::{load_snapshot, replay_snapshot, save_snapshot};
+pub use ttl_cache::TtlCache;
 // pub use rocksdb::Rocksdb;
 
 use crate::{KvError, Kvpair, Value};
+use std::collections::BTreeMap;
+
+/// 以这个前缀开头的 table 是服务器内部使用的，比如 Raft 的状态和多主的版本，客户端不能读写
+pub const RESERVED_TABLE_PREFIX: &str = "__";
+
+pub fn is_reserved_table(table: &str) -> bool {
+    table.starts_with(RESERVED_TABLE_PREFIX)
+}
 
 pub trait Storage: Send + Sync + 'static {
+    /// 存储后端的名字，和命令行里的存储一样
+    fn name(&self) -> &'static str;
     /// 从一个 HashTable 里获取一个 key 的 value
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
     /// 从一个 HashTable 里设置一个 key 的 value，返回旧的 value
@@ -21,6 +41,95 @@ pub trait Storage: Send + Sync + 'static {
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError>;
     /// 遍历 HashTable，返回 kv pair 的 Iterator
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError>;
+    /// 所有 HashTable 的名字
+    fn tables(&self) -> Result<Vec<String>, KvError>;
+
+    /// 遍历 HashTable 在调用时刻的内容，遍历期间的写入不会出现在结果里。
+    /// 缺省的实现先把 table 复制出来，get_iter 已经返回副本或者有快照的存储应该直接返回
+    fn get_iter_snapshot(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        let pairs: Vec<_> = self.get_iter(table)?.collect();
+        Ok(Box::new(pairs.into_iter()))
+    }
+
+    /// 所有 HashTable 的只读视图，用于导出、复制的全量同步这些耗时长的遍历，期间写入照常进行。
+    /// 缺省的实现逐个 table 调用 get_iter_snapshot 复制出来，每个 table 各自是一致的，
+    /// table 之间不保证是同一时刻
+    fn snapshot_handle(&self) -> Result<Box<dyn StorageSnapshot>, KvError> {
+        let mut tables = BTreeMap::new();
+        for table in self.tables()? {
+            let pairs: Vec<_> = self.get_iter_snapshot(&table)?.collect();
+            tables.insert(table, pairs);
+        }
+        Ok(Box::new(CopiedSnapshot(tables)))
+    }
+
+    /// HashTable 是否存在，缺省的实现在所有 table 的名字里查找
+    fn table_exists(&self, table: &str) -> Result<bool, KvError> {
+        Ok(self.tables()?.iter().any(|t| t == table))
+    }
+
+    /// 按 key 的顺序返回 [start, end) 里的 kv pair，end 为 None 时到 table 的结尾。
+    /// 缺省的实现遍历整个 table 再排序，有序的存储应该直接按范围读取
+    fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        let in_range = |k: &str| k >= start && end.is_none_or(|end| k < end);
+        Ok(Box::new(sorted(self.get_iter(table)?, in_range)))
+    }
+
+    /// 按 key 的顺序返回以 prefix 开头的 kv pair，缺省的实现遍历整个 table 再排序
+    fn get_prefix(
+        &self,
+        table: &str,
+        prefix: &str,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        let matched = |k: &str| k.starts_with(prefix);
+        Ok(Box::new(sorted(self.get_iter(table)?, matched)))
+    }
+
+    /// 删除以 prefix 开头的 key，返回删除的数量。
+    /// 缺省的实现先读出所有的 key 再逐个删除，存储应该在内部直接删除整个范围
+    fn del_prefix(&self, table: &str, prefix: &str) -> Result<usize, KvError> {
+        let keys: Vec<_> = self.get_prefix(table, prefix)?.map(|p| p.key).collect();
+        for key in &keys {
+            self.del(table, key)?;
+        }
+        Ok(keys.len())
+    }
+}
+
+/// Storage::snapshot_handle 返回的只读视图，创建之后的写入不会出现在里面
+pub trait StorageSnapshot: Send + Sync + 'static {
+    /// 所有 HashTable 的名字
+    fn tables(&self) -> Vec<String>;
+    /// 遍历 HashTable，table 不存在时返回空的 Iterator
+    fn get_iter(&self, table: &str) -> Box<dyn Iterator<Item = Kvpair>>;
+}
+
+/// 复制出来的数据，Storage::snapshot_handle 的缺省实现
+struct CopiedSnapshot(BTreeMap<String, Vec<Kvpair>>);
+
+impl StorageSnapshot for CopiedSnapshot {
+    fn tables(&self) -> Vec<String> {
+        self.0.keys().cloned().collect()
+    }
+
+    fn get_iter(&self, table: &str) -> Box<dyn Iterator<Item = Kvpair>> {
+        let pairs = self.0.get(table).cloned().unwrap_or_default();
+        Box::new(pairs.into_iter())
+    }
+}
+
+fn sorted(
+    iter: impl Iterator<Item = Kvpair>,
+    filter: impl Fn(&str) -> bool,
+) -> impl Iterator<Item = Kvpair> + 'static {
+    let mut pairs: Vec<_> = iter.filter(|pair| filter(&pair.key)).collect();
+    pairs.sort_by(|a, b| a.key.cmp(&b.key));
+    pairs.into_iter()
 }
 
 pub struct StorageIter<T> {
@@ -48,6 +157,7 @@ where
 mod tests {
     use super::*;
     // use crate::storage::rocksdb::Rocksdb;
+    use std::time::Duration;
     use tempfile::tempdir;
 
     #[test]
@@ -68,6 +178,165 @@ mod tests {
         test_get_iter(store);
     }
 
+    #[test]
+    fn memtable_tables_should_work() {
+        let store = MemTable::new();
+        test_tables(store);
+    }
+
+    #[test]
+    fn memtable_range_should_work() {
+        let store = MemTable::new();
+        test_range(store);
+    }
+
+    #[test]
+    fn memtable_del_prefix_should_work() {
+        let store = MemTable::new();
+        test_del_prefix(store);
+    }
+
+    #[test]
+    fn memtable_snapshot_should_work() {
+        let store = MemTable::new();
+        test_snapshot(store);
+    }
+
+    #[test]
+    fn memtable_ordered_should_work() {
+        test_base_interface(MemTableOrdered::new());
+        test_get_all(MemTableOrdered::new());
+        test_get_iter(MemTableOrdered::new());
+        test_tables(MemTableOrdered::new());
+        test_range(MemTableOrdered::new());
+        test_del_prefix(MemTableOrdered::new());
+        test_snapshot(MemTableOrdered::new());
+    }
+
+    #[test]
+    fn cached_storage_should_work() {
+        let dirs: Vec<_> = (0..7).map(|_| tempdir().unwrap()).collect();
+        let cached = |i: usize| CachedStorage::new(MemTable::new(), SledDb::new(&dirs[i]), 16);
+        test_base_interface(cached(0));
+        test_get_all(cached(1));
+        test_get_iter(cached(2));
+        test_tables(cached(3));
+        test_range(cached(4));
+        test_del_prefix(cached(5));
+        test_snapshot(cached(6));
+    }
+
+    #[test]
+    fn cached_storage_should_populate_fast_layer_on_miss() {
+        let dir = tempdir().unwrap();
+        let slow = SledDb::new(dir);
+        slow.set("t1", "k1".into(), "v1".into()).unwrap();
+        let store = CachedStorage::new(MemTable::new(), slow, 16);
+
+        assert!(store.fast().get("t1", "k1").unwrap().is_none());
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.fast().get("t1", "k1").unwrap(), Some("v1".into()));
+        // 不存在的 key 不会放进 fast
+        assert!(store.get("t1", "k2").unwrap().is_none());
+        assert!(!store.fast().contains("t1", "k2").unwrap());
+
+        // 写入和删除同时修改两层
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        assert_eq!(store.slow().get("t1", "k2").unwrap(), Some("v2".into()));
+        assert_eq!(store.fast().get("t1", "k2").unwrap(), Some("v2".into()));
+        store.del("t1", "k1").unwrap();
+        assert!(!store.fast().contains("t1", "k1").unwrap());
+        assert!(!store.slow().contains("t1", "k1").unwrap());
+    }
+
+    #[test]
+    fn cached_storage_should_cap_fast_layer() {
+        let dir = tempdir().unwrap();
+        let store = CachedStorage::new(MemTable::new(), SledDb::new(dir), 2);
+        for key in ["k1", "k2", "k3"] {
+            store.set("t1", key.into(), key.into()).unwrap();
+        }
+        // k1 最久没用，被淘汰出 fast，slow 里还在
+        assert_eq!(store.fast().get_iter("t1").unwrap().count(), 2);
+        assert!(!store.fast().contains("t1", "k1").unwrap());
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("k1".into()));
+
+        // 读 k1 把它放回 fast，淘汰最久没用的 k2
+        assert!(store.fast().contains("t1", "k1").unwrap());
+        assert!(!store.fast().contains("t1", "k2").unwrap());
+        assert_eq!(store.fast().get_iter("t1").unwrap().count(), 2);
+
+        // 命中 fast 也会更新新旧，接下来淘汰的是 k3
+        store.get("t1", "k1").unwrap();
+        store.get("t1", "k2").unwrap();
+        assert!(!store.fast().contains("t1", "k3").unwrap());
+        assert_eq!(store.get_iter("t1").unwrap().count(), 3);
+    }
+
+    #[test]
+    fn ttl_cache_should_work() {
+        let dirs: Vec<_> = (0..7).map(|_| tempdir().unwrap()).collect();
+        let cached = |i: usize| TtlCache::new(SledDb::new(&dirs[i]), 16, Duration::from_secs(60));
+        test_base_interface(cached(0));
+        test_get_all(cached(1));
+        test_get_iter(cached(2));
+        test_tables(cached(3));
+        test_range(cached(4));
+        test_del_prefix(cached(5));
+        test_snapshot(cached(6));
+    }
+
+    #[test]
+    fn ttl_cache_should_expire_and_invalidate_entries() {
+        let dir = tempdir().unwrap();
+        let store = TtlCache::new(SledDb::new(dir), 2, Duration::from_millis(50));
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+
+        // 绕过缓存直接修改底层存储，ttl 之内读到的还是缓存里的值
+        store.inner().set("t1", "k1".into(), "v2".into()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v2".into()));
+
+        // 通过缓存写入和删除时立即失效
+        store.set("t1", "k1".into(), "v3".into()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v3".into()));
+        store.del("t1", "k1").unwrap();
+        assert!(store.get("t1", "k1").unwrap().is_none());
+
+        // 超出容量时淘汰最久没用的 key
+        for key in ["a", "b", "c"] {
+            store.set("t1", key.into(), key.into()).unwrap();
+            store.get("t1", key).unwrap();
+        }
+        store.inner().set("t1", "a".into(), "a2".into()).unwrap();
+        store.inner().set("t1", "c".into(), "c2".into()).unwrap();
+        assert_eq!(store.get("t1", "a").unwrap(), Some("a2".into()));
+        assert_eq!(store.get("t1", "c").unwrap(), Some("c".into()));
+
+        store.get("t1", "b").unwrap();
+        store.del_prefix("t1", "").unwrap();
+        assert!(store.get("t1", "b").unwrap().is_none());
+    }
+
+    #[test]
+    fn memtable_ordered_should_iterate_in_key_order() {
+        let store = MemTableOrdered::new();
+        for key in ["b", "c", "a", "ab"] {
+            store.set("t1", key.into(), key.into()).unwrap();
+        }
+        let keys: Vec<_> = store.get_iter("t1").unwrap().map(|p| p.key).collect();
+        assert_eq!(keys, ["a", "ab", "b", "c"]);
+        let keys: Vec<_> = store
+            .get_all("t1")
+            .unwrap()
+            .into_iter()
+            .map(|p| p.key)
+            .collect();
+        assert_eq!(keys, ["a", "ab", "b", "c"]);
+    }
+
     fn test_base_interface(store: impl Storage) {
         // 第一次 set 会创建 table，插入 key 并返回 None（之前没值）
         let v = store.set("t1", "hello".into(), "world".into());
@@ -112,6 +381,80 @@ mod tests {
         )
     }
 
+    fn test_tables(store: impl Storage) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k2".into(), "v2".into()).unwrap();
+        // 读不存在的 table 不会创建它
+        assert!(store.get("t3", "k1").unwrap().is_none());
+        assert!(!store.contains("t3", "k1").unwrap());
+        assert_eq!(store.get_iter("t3").unwrap().count(), 0);
+        let mut tables = store.tables().unwrap();
+        tables.sort();
+        assert_eq!(tables, vec!["t1".to_string(), "t2".to_string()]);
+        assert!(store.table_exists("t2").unwrap());
+        assert!(!store.table_exists("t3").unwrap());
+        assert!(!store.table_exists("t").unwrap());
+    }
+
+    fn test_range(store: impl Storage) {
+        for key in ["user:2", "user:1", "order:1", "user:10", "user"] {
+            store.set("t3", key.into(), key.into()).unwrap();
+        }
+        store.set("t4", "user:3".into(), "v".into()).unwrap();
+        let keys = |iter: Box<dyn Iterator<Item = Kvpair>>| iter.map(|p| p.key).collect::<Vec<_>>();
+
+        let prefix = store.get_prefix("t3", "user:").unwrap();
+        assert_eq!(keys(prefix), ["user:1", "user:10", "user:2"]);
+        let range = store.get_range("t3", "user:1", Some("user:2")).unwrap();
+        assert_eq!(keys(range), ["user:1", "user:10"]);
+        let range = store.get_range("t3", "p", None).unwrap();
+        assert_eq!(keys(range), ["user", "user:1", "user:10", "user:2"]);
+        assert_eq!(store.get_prefix("t5", "").unwrap().count(), 0);
+    }
+
+    fn test_del_prefix(store: impl Storage) {
+        for key in ["user:2", "user:1", "order:1", "user:10", "user"] {
+            store.set("t3", key.into(), key.into()).unwrap();
+        }
+        store.set("t4", "user:3".into(), "v".into()).unwrap();
+
+        assert_eq!(store.del_prefix("t3", "user:").unwrap(), 3);
+        let mut keys: Vec<_> = store.get_iter("t3").unwrap().map(|p| p.key).collect();
+        keys.sort();
+        assert_eq!(keys, ["order:1", "user"]);
+        // 其它 table 里的 key 不受影响
+        assert!(store.contains("t4", "user:3").unwrap());
+        assert_eq!(store.del_prefix("t3", "user:").unwrap(), 0);
+        assert_eq!(store.del_prefix("t5", "").unwrap(), 0);
+        // 空的 prefix 删除整个 table
+        assert_eq!(store.del_prefix("t3", "").unwrap(), 2);
+        assert_eq!(store.get_iter("t3").unwrap().count(), 0);
+    }
+
+    fn test_snapshot(store: impl Storage) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        let iter = store.get_iter_snapshot("t1").unwrap();
+        let snapshot = store.snapshot_handle().unwrap();
+
+        // 之后的写入不会出现在快照里
+        store.set("t1", "k1".into(), "v2".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.del("t2", "k1").unwrap();
+        store.set("t3", "k1".into(), "v1".into()).unwrap();
+
+        let data: Vec<_> = iter.collect();
+        assert_eq!(data, vec![Kvpair::new("k1", "v1".into())]);
+        let mut tables = snapshot.tables();
+        tables.sort();
+        assert_eq!(tables, ["t1", "t2"]);
+        let data: Vec<_> = snapshot.get_iter("t2").collect();
+        assert_eq!(data, vec![Kvpair::new("k1", "v1".into())]);
+        assert_eq!(snapshot.get_iter("t3").count(), 0);
+        assert_eq!(store.get_iter("t1").unwrap().count(), 2);
+    }
+
     fn test_get_iter(store: impl Storage) {
         store.set("t2", "k1".into(), "v1".into()).unwrap();
         store.set("t2", "k2".into(), "v2".into()).unwrap();
@@ -147,6 +490,34 @@ mod tests {
         test_get_iter(store);
     }
 
+    #[test]
+    fn sleddb_tables_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_tables(store);
+    }
+
+    #[test]
+    fn sleddb_range_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_range(store);
+    }
+
+    #[test]
+    fn sleddb_del_prefix_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_del_prefix(store);
+    }
+
+    #[test]
+    fn sleddb_snapshot_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_snapshot(store);
+    }
+
     // #[test]
     // fn rocksdb_basic_interface_should_work() {
     //     let dir = tempdir().unwrap();
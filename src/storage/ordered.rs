@@ -0,0 +1,140 @@
+use crate::{KvError, Kvpair, Storage, StorageIter, Value};
+use dashmap::DashMap;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+
+type Table = Arc<RwLock<BTreeMap<String, Value>>>;
+
+/// 使用 BTreeMap 构建的 MemTable，key 按字典序排列，
+/// HGETALL 按 key 的顺序返回，范围查询和前缀查询不需要遍历整个 table
+#[derive(Clone, Debug, Default)]
+pub struct MemTableOrdered {
+    tables: DashMap<String, Table>,
+}
+
+impl MemTableOrdered {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 如果名为 name 的 table 不存在，则创建，否则返回。只有写入才会创建 table
+    fn get_or_create_table(&self, name: &str) -> Table {
+        match self.get_table(name) {
+            Some(table) => table,
+            None => self.tables.entry(name.into()).or_default().clone(),
+        }
+    }
+
+    fn get_table(&self, name: &str) -> Option<Table> {
+        self.tables.get(name).map(|table| table.clone())
+    }
+
+    /// 复制出 range 里以 prefix 开头的 kv pair，迭代时不持有锁
+    fn collect(
+        &self,
+        table: &str,
+        range: (Bound<&str>, Bound<&str>),
+        prefix: &str,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        let Some(table) = self.get_table(table) else {
+            return Ok(Box::new(std::iter::empty()));
+        };
+        let table = table.read().unwrap();
+        let data: Vec<_> = table
+            .range::<str, _>(range)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        Ok(Box::new(StorageIter::new(data.into_iter())))
+    }
+}
+
+impl Storage for MemTableOrdered {
+    fn name(&self) -> &'static str {
+        "memory-ordered"
+    }
+
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let Some(table) = self.get_table(table) else {
+            return Ok(None);
+        };
+        Ok(table.read().unwrap().get(key).cloned())
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let table = self.get_or_create_table(table);
+        Ok(table.write().unwrap().insert(key, value))
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let Some(table) = self.get_table(table) else {
+            return Ok(false);
+        };
+        Ok(table.read().unwrap().contains_key(key))
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let Some(table) = self.get_table(table) else {
+            return Ok(None);
+        };
+        Ok(table.write().unwrap().remove(key))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        Ok(self.get_iter(table)?.collect())
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.collect(table, (Bound::Unbounded, Bound::Unbounded), "")
+    }
+
+    fn get_iter_snapshot(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        // get_iter 在读锁里复制整个 table，本身就是一个时刻的视图
+        self.get_iter(table)
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+        self.collect(table, (Bound::Included(start), end), "")
+    }
+
+    fn get_prefix(
+        &self,
+        table: &str,
+        prefix: &str,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.collect(table, (Bound::Included(prefix), Bound::Unbounded), prefix)
+    }
+
+    fn del_prefix(&self, table: &str, prefix: &str) -> Result<usize, KvError> {
+        let Some(table) = self.get_table(table) else {
+            return Ok(0);
+        };
+        let mut table = table.write().unwrap();
+        // 以 prefix 开头的 key 挨在一起，从 prefix 开始往后找到范围的结尾
+        let keys: Vec<_> = table
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .map(|(k, _)| k)
+            .take_while(|k| k.starts_with(prefix))
+            .cloned()
+            .collect();
+        for key in &keys {
+            table.remove(key);
+        }
+        Ok(keys.len())
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        Ok(self.tables.iter().map(|v| v.key().clone()).collect())
+    }
+
+    fn table_exists(&self, table: &str) -> Result<bool, KvError> {
+        Ok(self.tables.contains_key(table))
+    }
+}
@@ -13,6 +13,10 @@
 // }
 
 // impl Storage for Rocksdb {
+//     fn name(&self) -> &'static str {
+//         "rocksdb"
+//     }
+
 //     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
 //         let db = self.0.read().unwrap();
 //         let Some(cf_handle) = db.cf_handle(table) else {
@@ -115,6 +119,32 @@
 //         .collect()
 //     }
 
+//     fn del_prefix(&self, table: &str, prefix: &str) -> Result<usize, KvError> {
+//         let db = self.0.read().unwrap();
+//         let Some(cf) = db.cf_handle(table) else {
+//             return Ok(0); // 表不存在，什么都不用删
+//         };
+
+//         // 以 prefix 开头的 key 挨在一起，先数出有多少个，记下最后一个
+//         let mut count = 0;
+//         let mut last = None;
+//         for item in db.prefix_iterator_cf(cf, prefix.as_bytes()) {
+//             let (key, _) = item?;
+//             if !key.starts_with(prefix.as_bytes()) {
+//                 break;
+//             }
+//             count += 1;
+//             last = Some(key);
+//         }
+
+//         // 用 range delete 一次删除 [prefix, 最后一个 key + "\0")
+//         if let Some(last) = last {
+//             let end = [&last[..], b"\0"].concat();
+//             db.delete_range_cf(cf, prefix.as_bytes(), end)?;
+//         }
+//         Ok(count)
+//     }
+
 //     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
 //         let pairs = self.get_all(table)?; // 先获取所有数据
 //         Ok(Box::new(pairs.into_iter())) // 转换为owned迭代器
@@ -123,7 +153,7 @@
 
 // impl From<rocksdb::Error> for KvError {
 //     fn from(error: rocksdb::Error) -> Self {
-//         KvError::Internal(error.to_string())
+//         KvError::Storage(Box::new(error))
 //     }
 // }
 
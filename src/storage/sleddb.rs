@@ -8,7 +8,12 @@ pub struct SledDb(Db);
 
 impl SledDb {
     pub fn new(path: impl AsRef<Path>) -> Self {
-        Self(sled::open(path).unwrap())
+        Self::open(path).unwrap()
+    }
+
+    /// 和 new 一样，但打开失败（比如数据库被其它进程锁住）时返回错误
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        Ok(Self(sled::open(path)?))
     }
 
     // 在 sleddb 里，因为它可以 scan_prefix，我们用 prefix
@@ -30,6 +35,10 @@ fn flip<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
 }
 
 impl Storage for SledDb {
+    fn name(&self) -> &'static str {
+        "sled"
+    }
+
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, key);
         let result = self.0.get(name.as_bytes())?.map(|v| v.as_ref().try_into());
@@ -69,6 +78,70 @@ impl Storage for SledDb {
         let iter = StorageIter::new(self.0.scan_prefix(prefix));
         Ok(Box::new(iter))
     }
+
+    fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        let start = SledDb::get_full_key(table, start);
+        // ';' 紧跟在 ':' 后面，table 里所有的 key 都小于 "table;"
+        let end = match end {
+            Some(end) => SledDb::get_full_key(table, end),
+            None => format!("{};", table),
+        };
+        let iter = StorageIter::new(self.0.range(start..end));
+        Ok(Box::new(iter))
+    }
+
+    fn get_prefix(
+        &self,
+        table: &str,
+        prefix: &str,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        let prefix = SledDb::get_full_key(table, prefix);
+        let iter = StorageIter::new(self.0.scan_prefix(prefix));
+        Ok(Box::new(iter))
+    }
+
+    fn del_prefix(&self, table: &str, prefix: &str) -> Result<usize, KvError> {
+        let prefix = SledDb::get_full_key(table, prefix);
+        // 放在一个 batch 里原子地删除，不需要读出 value
+        let mut batch = sled::Batch::default();
+        let mut count = 0;
+        for key in self.0.scan_prefix(prefix).keys() {
+            batch.remove(key?);
+            count += 1;
+        }
+        self.0.apply_batch(batch)?;
+        Ok(count)
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        // key 是有序的，同一个 table 的 key 都挨在一起
+        let mut tables: Vec<String> = Vec::new();
+        for key in self.0.iter().keys() {
+            let key = key?;
+            let table = ivec_to_table(key.as_ref());
+            if tables.last().map(|t| t.as_str()) != Some(table) {
+                tables.push(table.into());
+            }
+        }
+        Ok(tables)
+    }
+
+    fn table_exists(&self, table: &str) -> Result<bool, KvError> {
+        // sled 里没有单独的 table，有以 table: 开头的 key 时 table 才存在
+        let prefix = SledDb::get_table_prefix(table);
+        Ok(self
+            .0
+            .scan_prefix(prefix)
+            .keys()
+            .next()
+            .transpose()?
+            .is_some())
+    }
 }
 
 impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
@@ -83,9 +156,13 @@ impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
     }
 }
 
+fn ivec_to_table(ivec: &[u8]) -> &str {
+    let s = str::from_utf8(ivec).unwrap();
+    s.split(":").next().unwrap()
+}
+
 fn ivec_to_key(ivec: &[u8]) -> &str {
+    // key 本身可以带 ':'，只去掉第一个 ':' 之前的 table
     let s = str::from_utf8(ivec).unwrap();
-    let mut iter = s.split(":");
-    iter.next();
-    iter.next().unwrap()
+    s.split_once(':').unwrap().1
 }
@@ -0,0 +1,113 @@
+use crate::{Hmset, KvError, Storage};
+use bytes::Buf;
+use prost::Message;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// 快照里每条记录最多包含的 kv pair
+const SNAPSHOT_BATCH: usize = 1024;
+
+/// 把 store 里所有的数据写到 path，返回写入的 key 数
+///
+/// 快照是一串 length delimited 的 Hmset，每条最多 SNAPSHOT_BATCH 个 kv pair。
+/// 先写到 path.tmp 再 rename，中途崩溃不会破坏上一次的快照。
+/// 数据从 store 的 snapshot_handle 读取，开始写快照之后的写入不会出现在快照里
+pub fn save_snapshot(store: &dyn Storage, path: impl AsRef<Path>) -> Result<usize, KvError> {
+    let path = path.as_ref();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    let mut count = 0;
+    let snapshot = store.snapshot_handle()?;
+    for table in snapshot.tables() {
+        let pairs: Vec<_> = snapshot.get_iter(&table).collect();
+        for pairs in pairs.chunks(SNAPSHOT_BATCH) {
+            let record = Hmset {
+                table: table.clone(),
+                pairs: pairs.to_vec(),
+            };
+            writer.write_all(&record.encode_length_delimited_to_vec())?;
+            count += pairs.len();
+        }
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(count)
+}
+
+/// 启动时把 path 里的快照读回 store，返回读入的 key 数。快照不存在时什么都不做
+pub fn load_snapshot(store: &dyn Storage, path: impl AsRef<Path>) -> Result<usize, KvError> {
+    let path = path.as_ref();
+    if !path.exists() {
+        return Ok(0);
+    }
+    replay_snapshot(path, |record| {
+        for pair in record.pairs {
+            store.set(&record.table, pair.key, pair.value.unwrap_or_default())?;
+        }
+        Ok(())
+    })
+}
+
+/// 把 path 里快照的每条记录交给 f，返回读到的 key 数。快照不存在时返回 NotFound
+pub fn replay_snapshot(
+    path: impl AsRef<Path>,
+    mut f: impl FnMut(Hmset) -> Result<(), KvError>,
+) -> Result<usize, KvError> {
+    let path = path.as_ref();
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(KvError::NotFound(path.display().to_string()));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut buf = data.as_slice();
+    let mut count = 0;
+    while buf.has_remaining() {
+        let record = Hmset::decode_length_delimited(&mut buf)?;
+        count += record.pairs.len();
+        f(record)?;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, MemTableOrdered, Value};
+    use tempfile::tempdir;
+
+    #[test]
+    fn snapshot_should_be_saved_and_loaded() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data/snapshot.bin");
+        assert_eq!(load_snapshot(&MemTable::new(), &path).unwrap(), 0);
+
+        let store = MemTable::new();
+        for i in 0..SNAPSHOT_BATCH + 1 {
+            store
+                .set("t1", format!("k{}", i), (i as i64).into())
+                .unwrap();
+        }
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(save_snapshot(&store, &path).unwrap(), SNAPSHOT_BATCH + 2);
+        assert!(!path.with_extension("tmp").exists());
+
+        let restored = MemTableOrdered::new();
+        assert_eq!(load_snapshot(&restored, &path).unwrap(), SNAPSHOT_BATCH + 2);
+        assert_eq!(restored.get("t1", "k3").unwrap(), Some(Value::from(3)));
+        assert_eq!(restored.get("t2", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(restored.get_iter("t1").unwrap().count(), SNAPSHOT_BATCH + 1);
+
+        // 快照写坏了时报错，不会静默地丢掉数据
+        fs::write(&path, [0xff, 0xff]).unwrap();
+        assert!(load_snapshot(&restored, &path).is_err());
+    }
+}
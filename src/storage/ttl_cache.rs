@@ -0,0 +1,144 @@
+use super::LruCache;
+use crate::{KvError, Kvpair, Storage, StorageSnapshot, Value};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// 持久化存储前面的读缓存，缓存 get 读到的值，每个值最多保留 ttl
+///
+/// 写入直接写到底层的存储，成功之后让对应的 key 失效，持久性和底层的存储一样。
+/// 缓存满了之后淘汰最久没用的 key；ttl 限制了其它进程直接修改底层存储时读到旧值的时间
+pub struct TtlCache<S> {
+    inner: S,
+    ttl: Duration,
+    cache: Mutex<CacheState>,
+}
+
+struct CacheState {
+    entries: LruCache<(String, String), (Value, Instant)>,
+    /// 每次失效时递增。读底层存储之前记下，读完时变了说明期间有写入，读到的值不放进缓存
+    generation: u64,
+}
+
+impl<S: Storage> TtlCache<S> {
+    pub fn new(inner: S, capacity: usize, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            cache: Mutex::new(CacheState {
+                entries: LruCache::new(capacity),
+                generation: 0,
+            }),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn invalidate(&self, table: &str, key: &str) {
+        let mut state = self.state();
+        state.generation += 1;
+        state.entries.remove(&(table.into(), key.into()));
+    }
+}
+
+impl<S: Storage> Storage for TtlCache<S> {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let id = (table.to_string(), key.to_string());
+        let generation = {
+            let mut state = self.state();
+            match state.entries.get(&id) {
+                Some((v, expires)) if expires > Instant::now() => return Ok(Some(v)),
+                Some(_) => {
+                    state.entries.remove(&id);
+                }
+                None => {}
+            }
+            state.generation
+        };
+        let value = self.inner.get(table, key)?;
+        if let Some(v) = &value {
+            let mut state = self.state();
+            if state.generation == generation {
+                let expires = Instant::now() + self.ttl;
+                state.entries.insert(id, (v.clone(), expires));
+            }
+        }
+        Ok(value)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let res = self.inner.set(table, key.clone(), value);
+        self.invalidate(table, &key);
+        res
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let res = self.inner.del(table, key);
+        self.invalidate(table, key);
+        res
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.inner.get_iter(table)
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        self.inner.tables()
+    }
+
+    fn get_iter_snapshot(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.inner.get_iter_snapshot(table)
+    }
+
+    fn snapshot_handle(&self) -> Result<Box<dyn StorageSnapshot>, KvError> {
+        self.inner.snapshot_handle()
+    }
+
+    fn table_exists(&self, table: &str) -> Result<bool, KvError> {
+        self.inner.table_exists(table)
+    }
+
+    fn get_range(
+        &self,
+        table: &str,
+        start: &str,
+        end: Option<&str>,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.inner.get_range(table, start, end)
+    }
+
+    fn get_prefix(
+        &self,
+        table: &str,
+        prefix: &str,
+    ) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.inner.get_prefix(table, prefix)
+    }
+
+    fn del_prefix(&self, table: &str, prefix: &str) -> Result<usize, KvError> {
+        let res = self.inner.del_prefix(table, prefix);
+        let mut state = self.state();
+        state.generation += 1;
+        state
+            .entries
+            .retain(|(t, k)| t != table || !k.starts_with(prefix));
+        res
+    }
+}
@@ -1,9 +1,17 @@
 use anyhow::Result;
+use futures::StreamExt;
+use kv::bench::{BenchConfig, KeyDistribution};
 use kv::{
-    ClientConfig, CommandRequest, ServerConfig, StorageConfig, start_client_with_config,
-    start_server_with_config,
+    AdminConfig, ClientConfig, ClientNoiseConfig, ClusterConfig, CommandRequest, CommandsConfig,
+    FailoverConfig, Flushall, GeneralConfig, KvClient, KvCluster, Kvpair, LimitsConfig,
+    MembershipConfig, MemcachedConfig, MirrorConfig, MultiMasterConfig, RaftConfig, Role, Routing,
+    Security, ServerConfig, ServerControl, ServerNoiseConfig, ShadowConfig, ShardMode,
+    ShardingConfig, SnapshotConfig, StorageConfig, bind_listener, decode_change, gen_config,
+    key_slot, start_client_with_config, start_server_with_config, start_server_with_control,
 };
 use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio::time;
 
 #[tokio::test]
@@ -39,3 +47,1725 @@ async fn yamux_server_client_full_tests() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn plaintext_server_client_full_tests() -> Result<()> {
+    let addr = "127.0.0.1:10087";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.general.security = Security::None;
+    config.storage = StorageConfig::MemTable;
+    config.tls = None;
+
+    // 启动不带 TLS 的服务器
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+
+    time::sleep(Duration::from_millis(10)).await;
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+    config.general.security = Security::None;
+    config.tls = None;
+
+    let mut ctrl = start_client_with_config(&config).await.unwrap();
+    let mut stream = ctrl.open_stream().await?;
+
+    let cmd = CommandRequest::new_hset("table1", "hello", "world".to_string().into());
+    stream.execute_unary(cmd).await?;
+
+    let cmd = CommandRequest::new_hget("table1", "hello");
+    let data = stream.execute_unary(cmd).await?;
+
+    assert_eq!(data.status, 200);
+    assert_eq!(data.values, &["world".into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn kv_client_should_work() -> Result<()> {
+    let addr = "127.0.0.1:10088";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.general.security = Security::None;
+    config.storage = StorageConfig::MemTable;
+    config.tls = None;
+
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+
+    time::sleep(Duration::from_millis(10)).await;
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+    config.general.security = Security::None;
+    config.tls = None;
+
+    let mut client = KvClient::connect(config).await?;
+
+    let cmd = CommandRequest::new_hset("table1", "hello", "world".to_string().into());
+    client.execute_unary(cmd).await?;
+
+    let cmd = CommandRequest::new_hget("table1", "hello");
+    let data = client.execute_unary(cmd).await?;
+
+    assert_eq!(data.status, 200);
+    assert_eq!(data.values, &["world".into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn kv_client_cache_should_be_invalidated_by_other_writers() -> Result<()> {
+    let addr = "127.0.0.1:10089";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.general.security = Security::None;
+    config.storage = StorageConfig::MemTable;
+    config.tls = None;
+
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+
+    time::sleep(Duration::from_millis(10)).await;
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+    config.general.security = Security::None;
+    config.tls = None;
+
+    let mut writer = KvClient::connect(config.clone()).await?;
+    config.cache.capacity = 16;
+    let mut reader = KvClient::connect(config).await?;
+
+    let cmd = CommandRequest::new_hset("table1", "hello", "world".to_string().into());
+    writer.execute_unary(cmd).await?;
+
+    // 第一次读取后 reader 会缓存这个 key
+    let cmd = CommandRequest::new_hget("table1", "hello");
+    let data = reader.execute_unary(cmd.clone()).await?;
+    assert_eq!(data.values, &["world".into()]);
+
+    // 其它客户端写入后，reader 收到 keyspace 通知让缓存失效
+    let cmd1 = CommandRequest::new_hset("table1", "hello", "rust".to_string().into());
+    writer.execute_unary(cmd1).await?;
+    time::sleep(Duration::from_millis(50)).await;
+
+    let data = reader.execute_unary(cmd).await?;
+    assert_eq!(data.status, 200);
+    assert_eq!(data.values, &["rust".into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn kv_client_subscribe_with_should_work() -> Result<()> {
+    let addr = "127.0.0.1:10090";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.general.security = Security::None;
+    config.storage = StorageConfig::MemTable;
+    config.tls = None;
+
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+
+    time::sleep(Duration::from_millis(10)).await;
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+    config.general.security = Security::None;
+    config.tls = None;
+
+    let mut client = KvClient::connect(config).await?;
+
+    let (tx, mut rx) = mpsc::channel(16);
+    let sub = client
+        .subscribe_with("lobby", move |values| {
+            let tx = tx.clone();
+            async move {
+                tx.send(values).await.unwrap();
+            }
+        })
+        .await?;
+    assert!(sub.id > 0);
+
+    let cmd = CommandRequest::new_publish("lobby", vec!["hello".into(), 1.into()]);
+    let res = client.execute_unary(cmd).await?;
+    assert_eq!(res.values, vec![1.into()]);
+    let values = rx.recv().await.unwrap();
+    assert_eq!(values, vec!["hello".into(), 1.into()]);
+
+    // 取消订阅后 handler 被释放，channel 随之关闭
+    sub.unsubscribe().await?;
+    let cmd = CommandRequest::new_publish("lobby", vec!["world".into()]);
+    let res = client.execute_unary(cmd).await?;
+    assert_eq!(res.values, vec![0.into()]);
+    assert!(rx.recv().await.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn dropped_subscription_should_be_cancelled() -> Result<()> {
+    let addr = "127.0.0.1:10134";
+    let config = ServerConfig::builder().addr(addr).build()?;
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    let config = ClientConfig::builder().addr(addr).build()?;
+    let mut ctrl = start_client_with_config(&config).await?;
+    let mut client = KvClient::connect(config).await?;
+    let publish = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+
+    // 消息流和 Subscription 被 drop 之后，服务器上不再有订阅者
+    let stream = ctrl
+        .open_stream()
+        .await?
+        .execute_stream(&CommandRequest::new_subscribe("lobby"))
+        .await?
+        .unsubscribe_on_drop(ctrl.clone(), "lobby");
+    assert_eq!(stream.subscription().topic, "lobby");
+    let sub = client.subscribe_with("lobby", |_| async {}).await?;
+    assert_eq!(
+        client.execute_unary(publish.clone()).await?.values,
+        [2.into()]
+    );
+
+    drop(stream);
+    drop(sub);
+    time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(client.execute_unary(publish).await?.values, [0.into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn kv_client_batch_should_work() -> Result<()> {
+    let addr = "127.0.0.1:10091";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.general.security = Security::None;
+    config.storage = StorageConfig::MemTable;
+    config.tls = None;
+
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+
+    time::sleep(Duration::from_millis(10)).await;
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+    config.general.security = Security::None;
+    config.tls = None;
+
+    let mut client = KvClient::connect(config).await?;
+
+    let res = client
+        .batch()
+        .add(CommandRequest::new_hset("table1", "k1", "v1".into()))
+        .add(CommandRequest::new_hget("table1", "k1"))
+        .add(CommandRequest::new_hdel("table1", "k2"))
+        .execute()
+        .await?;
+
+    assert_eq!(res.len(), 3);
+    assert_eq!(res[1].values, &["v1".into()]);
+    // 单个命令失败不影响其它命令
+    assert_eq!(res[2].status, 404);
+
+    let res = client
+        .batch()
+        .add(CommandRequest::new_subscribe("lobby"))
+        .execute()
+        .await;
+    assert!(res.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn kv_cluster_should_failover_to_healthy_server() -> Result<()> {
+    let addr = "127.0.0.1:10092";
+    // 没有服务器监听的地址
+    let dead_addr = "127.0.0.1:10093";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.general.security = Security::None;
+    config.storage = StorageConfig::MemTable;
+    config.tls = None;
+
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+
+    time::sleep(Duration::from_millis(10)).await;
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.security = Security::None;
+    config.tls = None;
+    config.cluster.addrs = vec![dead_addr.into(), addr.into()];
+
+    let mut cluster = KvCluster::connect(config).await?;
+    assert_eq!(cluster.healthy_nodes(), vec![addr]);
+
+    // 轮询到不可用的服务器时会自动转到可用的服务器
+    for i in 0..4 {
+        let cmd = CommandRequest::new_hset("table1", format!("k{}", i), i.into());
+        let res = cluster.execute_unary(cmd).await?;
+        assert_eq!(res.status, 200);
+    }
+
+    let cmd = CommandRequest::new_hget("table1", "k3");
+    let data = cluster.execute_unary(cmd).await?;
+    assert_eq!(data.values, &[3.into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_list_should_show_connected_clients() -> Result<()> {
+    let addr = "127.0.0.1:10094";
+    let admin_addr = "127.0.0.1:10136";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.general.security = Security::None;
+    config.storage = StorageConfig::MemTable;
+    config.tls = None;
+    config.admin = AdminConfig {
+        enabled: true,
+        addr: admin_addr.into(),
+        ..Default::default()
+    };
+
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+
+    time::sleep(Duration::from_millis(10)).await;
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+    config.general.security = Security::None;
+    config.tls = None;
+
+    let mut client2 = KvClient::connect(config.clone()).await?;
+    config.general.addr = admin_addr.into();
+    let mut client1 = KvClient::connect(config).await?;
+
+    let cmd = CommandRequest::new_hset("table1", "hello", "world".into());
+    client2.execute_unary(cmd).await?;
+
+    let res = client1
+        .execute_unary(CommandRequest::new_client_list())
+        .await?;
+    assert_eq!(res.status, 200);
+    assert_eq!(res.values.len(), 2);
+    let lines: Vec<_> = res.values.iter().map(|v| v.format()).collect();
+    assert!(lines.iter().any(|line| line.contains("cmd=hset")));
+    assert!(lines.iter().any(|line| line.contains("cmd=client_list")));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_kill_should_close_connection_and_subscriptions() -> Result<()> {
+    let addr = "127.0.0.1:10095";
+    let admin_addr = "127.0.0.1:10137";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.general.security = Security::None;
+    config.storage = StorageConfig::MemTable;
+    config.tls = None;
+    config.admin = AdminConfig {
+        enabled: true,
+        addr: admin_addr.into(),
+        ..Default::default()
+    };
+
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+
+    time::sleep(Duration::from_millis(10)).await;
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+    config.general.security = Security::None;
+    config.tls = None;
+
+    let mut subscriber = KvClient::connect(config.clone()).await?;
+    config.general.addr = admin_addr.into();
+    let mut admin = KvClient::connect(config).await?;
+
+    let (tx, mut rx) = mpsc::channel(16);
+    let _sub = subscriber
+        .subscribe_with("lobby", move |values| {
+            let tx = tx.clone();
+            async move {
+                tx.send(values).await.unwrap();
+            }
+        })
+        .await?;
+
+    // 从 CLIENT LIST 里找到订阅者的 connection id
+    let res = admin
+        .execute_unary(CommandRequest::new_client_list())
+        .await?;
+    let line = res
+        .values
+        .iter()
+        .map(|v| v.format())
+        .find(|line| line.contains("cmd=subscribe"))
+        .unwrap();
+    let id: u64 = line
+        .split("id=")
+        .nth(1)
+        .and_then(|s| s.split(' ').next())
+        .unwrap()
+        .parse()?;
+
+    let res = admin
+        .execute_unary(CommandRequest::new_client_kill(id))
+        .await?;
+    assert_eq!(res.values, &[1.into()]);
+
+    // 订阅随连接一起被清理，handler 被释放，channel 随之关闭
+    let values = time::timeout(Duration::from_secs(1), rx.recv()).await?;
+    assert!(values.is_none());
+
+    time::sleep(Duration::from_millis(50)).await;
+    let res = admin
+        .execute_unary(CommandRequest::new_client_list())
+        .await?;
+    assert_eq!(res.values.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn replica_should_apply_writes_from_primary() -> Result<()> {
+    let primary_addr = "127.0.0.1:10096";
+    let replica_addr = "127.0.0.1:10097";
+
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.security = Security::None;
+    config.tls = None;
+
+    let mut primary: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    primary.general.addr = primary_addr.into();
+    primary.general.security = Security::None;
+    primary.storage = StorageConfig::MemTable;
+    primary.tls = None;
+    primary.replication.role = Role::Primary;
+
+    let mut replica = primary.clone();
+    replica.general.addr = replica_addr.into();
+    replica.replication.role = Role::Replica;
+    replica.replication.primary = Some(ClientConfig {
+        general: GeneralConfig {
+            addr: primary_addr.into(),
+            ..config.general.clone()
+        },
+        ..config.clone()
+    });
+
+    tokio::spawn(async move {
+        start_server_with_config(&primary).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+    tokio::spawn(async move {
+        start_server_with_config(&replica).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(50)).await;
+
+    config.general.addr = primary_addr.into();
+    let mut writer = KvClient::connect(config.clone()).await?;
+    config.general.addr = replica_addr.into();
+    let mut reader = KvClient::connect(config).await?;
+
+    let cmd = CommandRequest::new_hset("table1", "hello", "world".into());
+    writer.execute_unary(cmd).await?;
+
+    time::sleep(Duration::from_millis(50)).await;
+    let res = reader
+        .execute_unary(CommandRequest::new_hget("table1", "hello"))
+        .await?;
+    assert_eq!(res.values, &["world".into()]);
+
+    // replica 只读
+    let cmd = CommandRequest::new_hset("table1", "hello", "world".into());
+    let res = reader.execute_unary(cmd).await?;
+    assert_eq!(res.status, 403);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn raft_cluster_should_elect_leader_and_replicate_writes() -> Result<()> {
+    let addrs = ["127.0.0.1:10098", "127.0.0.1:10099", "127.0.0.1:10100"];
+
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.security = Security::None;
+    config.tls = None;
+
+    let mut server: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    server.general.security = Security::None;
+    server.storage = StorageConfig::MemTable;
+    server.tls = None;
+    server.raft = RaftConfig {
+        enabled: true,
+        peers: addrs.iter().map(|addr| addr.to_string()).collect(),
+        election_timeout_ms: 150,
+        heartbeat_interval_ms: 30,
+        client: Some(config.clone()),
+        ..Default::default()
+    };
+
+    for (i, addr) in addrs.iter().enumerate() {
+        let mut server = server.clone();
+        server.general.addr = addr.to_string();
+        server.raft.id = i as u64 + 1;
+        tokio::spawn(async move {
+            start_server_with_config(&server).await.unwrap();
+        });
+    }
+    time::sleep(Duration::from_millis(50)).await;
+
+    let mut clients = Vec::new();
+    for addr in addrs {
+        config.general.addr = addr.into();
+        clients.push(KvClient::connect(config.clone()).await?);
+    }
+
+    // follower 返回 421，轮流尝试直到找到 leader
+    let mut leader = None;
+    for _ in 0..50 {
+        time::sleep(Duration::from_millis(100)).await;
+        for (i, client) in clients.iter_mut().enumerate() {
+            let cmd = CommandRequest::new_hset("table1", "hello", "world".into());
+            if client.execute_unary(cmd).await?.status == 200 {
+                leader = Some(i);
+                break;
+            }
+        }
+        if leader.is_some() {
+            break;
+        }
+    }
+    let leader = leader.expect("no leader elected");
+
+    let res = clients[leader]
+        .execute_unary(CommandRequest::new_hget("table1", "hello"))
+        .await?;
+    assert_eq!(res.values, &["world".into()]);
+
+    let follower = (leader + 1) % addrs.len();
+    let res = clients[follower]
+        .execute_unary(CommandRequest::new_hget("table1", "hello"))
+        .await?;
+    assert_eq!(res.status, 421);
+    assert!(res.message.contains(addrs[leader]));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sharded_cluster_should_route_tables_to_owner() -> Result<()> {
+    let addrs = ["127.0.0.1:10101", "127.0.0.1:10102"];
+
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.security = Security::None;
+    config.tls = None;
+
+    let mut server: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    server.general.security = Security::None;
+    server.storage = StorageConfig::MemTable;
+    server.tls = None;
+    server.sharding = ShardingConfig {
+        enabled: true,
+        nodes: addrs.iter().map(|addr| addr.to_string()).collect(),
+        ..Default::default()
+    };
+
+    // 第一个节点代理，第二个节点重定向
+    for (i, mode) in [ShardMode::Proxy, ShardMode::Redirect]
+        .into_iter()
+        .enumerate()
+    {
+        let mut server = server.clone();
+        server.general.addr = addrs[i].into();
+        server.sharding.id = i as u64 + 1;
+        server.sharding.mode = mode;
+        tokio::spawn(async move {
+            start_server_with_config(&server).await.unwrap();
+        });
+    }
+    time::sleep(Duration::from_millis(50)).await;
+
+    config.cluster.addrs = addrs.iter().map(|addr| addr.to_string()).collect();
+    config.cluster.routing = Routing::Slots;
+    let mut cluster = KvCluster::connect(config.clone()).await?;
+    let slots = cluster.slots().expect("slots should be loaded").clone();
+
+    let tables: Vec<String> = (0..20).map(|i| format!("table{}", i)).collect();
+    for table in &tables {
+        let cmd = CommandRequest::new_hset(table, "hello", table.as_str().into());
+        assert_eq!(cluster.execute_unary(cmd).await?.status, 200);
+    }
+
+    // 代理节点能读到所有 table
+    config.general.addr = addrs[0].into();
+    let mut proxy = KvClient::connect(config.clone()).await?;
+    for table in &tables {
+        let res = proxy
+            .execute_unary(CommandRequest::new_hget(table, "hello"))
+            .await?;
+        assert_eq!(res.values, &[table.as_str().into()]);
+    }
+
+    // 重定向节点对不属于自己的 table 返回 421
+    config.general.addr = addrs[1].into();
+    let mut redirect = KvClient::connect(config).await?;
+    let table = tables
+        .iter()
+        .find(|table| slots.owner(key_slot(table)) == Some(addrs[0]))
+        .expect("some table should be owned by the first node");
+    let res = redirect
+        .execute_unary(CommandRequest::new_hget(table, "hello"))
+        .await?;
+    assert_eq!(res.status, 421);
+    assert!(res.message.contains(addrs[0]));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn new_replica_should_bootstrap_from_primary_snapshot() -> Result<()> {
+    let primary_addr = "127.0.0.1:10103";
+    let replica_addr = "127.0.0.1:10104";
+
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.security = Security::None;
+    config.tls = None;
+
+    let mut primary: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    primary.general.addr = primary_addr.into();
+    primary.general.security = Security::None;
+    primary.storage = StorageConfig::MemTable;
+    primary.tls = None;
+    primary.replication.role = Role::Primary;
+
+    let mut replica = primary.clone();
+    replica.general.addr = replica_addr.into();
+    replica.replication.role = Role::Replica;
+    replica.replication.primary = Some(ClientConfig {
+        general: GeneralConfig {
+            addr: primary_addr.into(),
+            ..config.general.clone()
+        },
+        ..config.clone()
+    });
+
+    tokio::spawn(async move {
+        start_server_with_config(&primary).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    // replica 启动之前写入的数据
+    config.general.addr = primary_addr.into();
+    let mut writer = KvClient::connect(config.clone()).await?;
+    for i in 0..200 {
+        let cmd = CommandRequest::new_hset("table1", format!("key{}", i), (i as i64).into());
+        writer.execute_unary(cmd).await?;
+    }
+
+    tokio::spawn(async move {
+        start_server_with_config(&replica).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(100)).await;
+
+    // replica 启动之后写入的数据
+    let cmd = CommandRequest::new_hset("table2", "hello", "world".into());
+    writer.execute_unary(cmd).await?;
+    time::sleep(Duration::from_millis(50)).await;
+
+    config.general.addr = replica_addr.into();
+    let mut reader = KvClient::connect(config).await?;
+    let res = reader
+        .execute_unary(CommandRequest::new_hgetall("table1"))
+        .await?;
+    assert_eq!(res.pairs.len(), 200);
+    let res = reader
+        .execute_unary(CommandRequest::new_hget("table2", "hello"))
+        .await?;
+    assert_eq!(res.values, &["world".into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn cluster_members_should_be_discovered_by_gossip() -> Result<()> {
+    let addrs = ["127.0.0.1:10105", "127.0.0.1:10106", "127.0.0.1:10107"];
+
+    let mut server: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    server.general.security = Security::None;
+    server.storage = StorageConfig::MemTable;
+    server.tls = None;
+    server.membership = MembershipConfig {
+        enabled: true,
+        gossip_interval_ms: 30,
+        ..Default::default()
+    };
+
+    // 后两个节点只知道第一个节点
+    for (i, addr) in addrs.iter().enumerate() {
+        let mut server = server.clone();
+        server.general.addr = addr.to_string();
+        if i > 0 {
+            server.membership.seeds = vec![addrs[0].into()];
+        }
+        tokio::spawn(async move {
+            start_server_with_config(&server).await.unwrap();
+        });
+    }
+    time::sleep(Duration::from_millis(500)).await;
+
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addrs[2].into();
+    config.general.security = Security::None;
+    config.tls = None;
+    let mut client = KvClient::connect(config).await?;
+
+    let res = client.execute_unary(CommandRequest::new_cluster()).await?;
+    assert_eq!(res.values.len(), 3);
+    for (value, addr) in res.values.iter().zip(addrs) {
+        let line = value.format();
+        assert!(line.contains(&format!("addr={} role=standalone status=up", addr)));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn promoted_replica_should_take_over_writes() -> Result<()> {
+    let primary_addr = "127.0.0.1:10108";
+    let replica_addr = "127.0.0.1:10109";
+    let replica_admin_addr = "127.0.0.1:10138";
+
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.security = Security::None;
+    config.tls = None;
+
+    let mut primary: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    primary.general.addr = primary_addr.into();
+    primary.general.security = Security::None;
+    primary.storage = StorageConfig::MemTable;
+    primary.tls = None;
+    primary.replication.role = Role::Primary;
+    primary.replication.failover = FailoverConfig {
+        enabled: true,
+        timeout_ms: 5000,
+        lease_ms: 150,
+    };
+
+    let mut replica = primary.clone();
+    replica.general.addr = replica_addr.into();
+    replica.admin = AdminConfig {
+        enabled: true,
+        addr: replica_admin_addr.into(),
+        ..Default::default()
+    };
+    replica.replication.role = Role::Replica;
+    replica.replication.primary = Some(ClientConfig {
+        general: GeneralConfig {
+            addr: primary_addr.into(),
+            ..config.general.clone()
+        },
+        ..config.clone()
+    });
+
+    tokio::spawn(async move {
+        start_server_with_config(&primary).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+    tokio::spawn(async move {
+        start_server_with_config(&replica).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(100)).await;
+
+    config.cluster = ClusterConfig {
+        addrs: vec![primary_addr.into(), replica_addr.into()],
+        routing: Routing::RoundRobin,
+        ..Default::default()
+    };
+    let mut cluster = KvCluster::connect(config.clone()).await?;
+
+    // replica 确认过之后 primary 可以写
+    let cmd = CommandRequest::new_hset("table1", "hello", "world".into());
+    config.general.addr = primary_addr.into();
+    let mut writer = KvClient::connect(config.clone()).await?;
+    assert_eq!(writer.execute_unary(cmd).await?.status, 200);
+    time::sleep(Duration::from_millis(50)).await;
+
+    // 外部协调者通过管理端口把 replica 提升为 primary
+    config.general.addr = replica_admin_addr.into();
+    let mut coordinator = KvClient::connect(config.clone()).await?;
+    let res = coordinator
+        .execute_unary(CommandRequest::new_promote(0))
+        .await?;
+    assert_eq!(res.values, &[1.into()]);
+    config.general.addr = replica_addr.into();
+    let mut replica = KvClient::connect(config).await?;
+
+    // 没有 replica 确认，原来的 primary 的写租约过期
+    time::sleep(Duration::from_millis(300)).await;
+    let cmd = CommandRequest::new_hset("table1", "hello", "again".into());
+    assert_eq!(writer.execute_unary(cmd.clone()).await?.status, 503);
+
+    // 集群客户端的写操作转到新的 primary
+    for _ in 0..2 {
+        assert_eq!(cluster.execute_unary(cmd.clone()).await?.status, 200);
+    }
+    let res = replica
+        .execute_unary(CommandRequest::new_hget("table1", "hello"))
+        .await?;
+    assert_eq!(res.values, &["again".into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn multi_master_writes_should_converge() -> Result<()> {
+    let addrs = ["127.0.0.1:10110", "127.0.0.1:10111"];
+
+    let mut server: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    server.general.security = Security::None;
+    server.storage = StorageConfig::MemTable;
+    server.tls = None;
+    for (i, addr) in addrs.iter().enumerate() {
+        let mut server = server.clone();
+        server.general.addr = addr.to_string();
+        server.multi_master = MultiMasterConfig {
+            enabled: true,
+            peers: vec![addrs[1 - i].into()],
+            sync_interval_ms: 50,
+            ..Default::default()
+        };
+        tokio::spawn(async move {
+            start_server_with_config(&server).await.unwrap();
+        });
+    }
+    time::sleep(Duration::from_millis(10)).await;
+
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.security = Security::None;
+    config.tls = None;
+    let mut clients = Vec::new();
+    for addr in addrs {
+        config.general.addr = addr.into();
+        clients.push(KvClient::connect(config.clone()).await?);
+    }
+
+    // 两个节点都可以写
+    let cmd = CommandRequest::new_hset("table1", "k1", "a".into());
+    assert_eq!(clients[0].execute_unary(cmd).await?.status, 200);
+    let cmd = CommandRequest::new_hset("table1", "k2", "b".into());
+    assert_eq!(clients[1].execute_unary(cmd).await?.status, 200);
+    time::sleep(Duration::from_millis(300)).await;
+
+    for client in &mut clients {
+        for (key, value) in [("k1", "a"), ("k2", "b")] {
+            let res = client
+                .execute_unary(CommandRequest::new_hget("table1", key))
+                .await?;
+            assert_eq!(res.values, &[value.into()]);
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn hwatch_should_stream_table_changes() -> Result<()> {
+    let addr = "127.0.0.1:10112";
+
+    let mut server: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    server.general.addr = addr.into();
+    server.general.security = Security::None;
+    server.storage = StorageConfig::MemTable;
+    server.tls = None;
+    tokio::spawn(async move {
+        start_server_with_config(&server).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+    config.general.security = Security::None;
+    config.tls = None;
+    let mut client = KvClient::connect(config.clone()).await?;
+    let mut changes = client.hwatch("table1").await?;
+
+    let mut writer = KvClient::connect(config).await?;
+    let cmd = CommandRequest::new_hset("table1", "hello", "world".into());
+    writer.execute_unary(cmd).await?;
+    let cmd = CommandRequest::new_hset("table2", "hello", "world".into());
+    writer.execute_unary(cmd).await?;
+    writer
+        .execute_unary(CommandRequest::new_hdel("table1", "hello"))
+        .await?;
+
+    // 只收到 table1 的变更
+    let event = decode_change(&changes.next().await.unwrap()?)?;
+    assert_eq!(
+        (event.table.as_str(), event.key.as_str()),
+        ("table1", "hello")
+    );
+    assert_eq!(event.new_value, Some("world".into()));
+    let next = decode_change(&changes.next().await.unwrap()?)?;
+    assert_eq!(next.old_value, Some("world".into()));
+    assert_eq!(next.new_value, None);
+    assert!(next.seq > event.seq);
+
+    // 带快照的 watch 先拿到当前的数据，再收到之后的变更
+    let (snapshot, mut changes) = client.hwatch_snapshot("table2").await?;
+    assert_eq!(snapshot, vec![Kvpair::new("hello", "world".into())]);
+    writer
+        .execute_unary(CommandRequest::new_hdel("table2", "hello"))
+        .await?;
+    let event = decode_change(&changes.next().await.unwrap()?)?;
+    assert_eq!(event.old_value, Some("world".into()));
+    assert_eq!(event.new_value, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn mirror_should_replay_selected_tables_on_remote() -> Result<()> {
+    let local_addr = "127.0.0.1:10113";
+    let remote_addr = "127.0.0.1:10114";
+
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.security = Security::None;
+    config.tls = None;
+
+    let mut remote: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    remote.general.addr = remote_addr.into();
+    remote.general.security = Security::None;
+    remote.storage = StorageConfig::MemTable;
+    remote.tls = None;
+
+    let mut local = remote.clone();
+    local.general.addr = local_addr.into();
+    local.mirror = MirrorConfig {
+        enabled: true,
+        remote: Some(ClientConfig {
+            general: GeneralConfig {
+                addr: remote_addr.into(),
+                ..config.general.clone()
+            },
+            ..config.clone()
+        }),
+        tables: vec!["table1".into()],
+        reconnect_interval_ms: 50,
+        ..Default::default()
+    };
+
+    tokio::spawn(async move {
+        start_server_with_config(&remote).await.unwrap();
+    });
+    tokio::spawn(async move {
+        start_server_with_config(&local).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(100)).await;
+
+    config.general.addr = local_addr.into();
+    let mut writer = KvClient::connect(config.clone()).await?;
+    for table in ["table1", "table2"] {
+        let cmd = CommandRequest::new_hset(table, "hello", "world".into());
+        writer.execute_unary(cmd).await?;
+    }
+    writer
+        .execute_unary(CommandRequest::new_hset("table1", "k2", "v2".into()))
+        .await?;
+    writer
+        .execute_unary(CommandRequest::new_hdel("table1", "k2"))
+        .await?;
+    time::sleep(Duration::from_millis(100)).await;
+
+    config.general.addr = remote_addr.into();
+    let mut reader = KvClient::connect(config).await?;
+    let res = reader
+        .execute_unary(CommandRequest::new_hgetall("table1"))
+        .await?;
+    assert_eq!(res.pairs.len(), 1);
+    assert_eq!(res.pairs[0].key, "hello");
+    // 没有选中的 table 不会同步
+    let res = reader
+        .execute_unary(CommandRequest::new_hget("table2", "hello"))
+        .await?;
+    assert_eq!(res.status, 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn reloaded_tls_config_should_apply_to_new_connections() -> Result<()> {
+    let addr = "127.0.0.1:10115";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+    let (tx, updates) = watch::channel(config.clone());
+    let server = config.clone();
+    tokio::spawn(async move {
+        let control = ServerControl::default().reload(updates);
+        start_server_with_control(&server, control).await.unwrap();
+    });
+
+    time::sleep(Duration::from_millis(10)).await;
+    let mut client_config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    client_config.general.addr = addr.into();
+    let mut ctrl = start_client_with_config(&client_config).await?;
+    let mut stream = ctrl.open_stream().await?;
+    let cmd = CommandRequest::new_hset("table1", "hello", "world".into());
+    assert_eq!(stream.execute_unary(cmd).await?.status, 200);
+
+    // 要求客户端证书之后，没有证书的新连接会失败
+    config.tls.as_mut().unwrap().ca = Some(include_str!("../fixtures/ca.cert").into());
+    tx.send(config)?;
+    time::sleep(Duration::from_millis(10)).await;
+    let res = async {
+        let mut ctrl = start_client_with_config(&client_config).await?;
+        let mut stream = ctrl.open_stream().await?;
+        let res = stream
+            .execute_unary(CommandRequest::new_hget("table1", "hello"))
+            .await?;
+        Ok::<_, anyhow::Error>(res)
+    };
+    assert!(res.await.is_err());
+
+    // 已有的连接不受影响
+    let mut stream = ctrl.open_stream().await?;
+    let res = stream
+        .execute_unary(CommandRequest::new_hget("table1", "hello"))
+        .await?;
+    assert_eq!(res.values, &["world".into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_should_drain_connections_on_shutdown() -> Result<()> {
+    let addr = "127.0.0.1:10116";
+
+    let mut config = ServerConfig::builder().addr(addr).build()?;
+    config.limits.shutdown_timeout_ms = 200;
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        let control = ServerControl::default()
+            .shutdown(async move {
+                let _ = shutdown_rx.await;
+            })
+            .on_ready(|addr| {
+                let _ = ready_tx.send(addr);
+            });
+        start_server_with_control(&config, control).await
+    });
+    assert_eq!(ready_rx.await?.to_string(), addr);
+
+    let mut client = KvClient::connect(ClientConfig::builder().addr(addr).build()?).await?;
+    let res = client
+        .execute_unary(CommandRequest::new_hset("table1", "hello", "world".into()))
+        .await?;
+    assert_eq!(res.status, 200);
+    // 一直不结束的订阅最多等到 shutdown_timeout_ms
+    let _subscription = client.subscribe_with("lobby", |_| async {}).await?;
+
+    shutdown_tx.send(()).unwrap();
+    time::timeout(Duration::from_secs(1), server).await???;
+
+    // 已有的连接被断开，也不再接受新连接
+    let res = client
+        .execute_unary(CommandRequest::new_hget("table1", "hello"))
+        .await;
+    assert!(res.is_err());
+    assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn listening_socket_should_be_handed_over() -> Result<()> {
+    let addr = "127.0.0.1:10122";
+    let listener = bind_listener(addr, &Default::default())?;
+
+    // 旧的服务器和新的服务器使用同一个 socket，旧的退出之后新的继续接受连接
+    let start = |listener, shutdown: oneshot::Receiver<()>| {
+        let mut config = ServerConfig::builder().addr(addr).build().unwrap();
+        config.limits.shutdown_timeout_ms = 200;
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let server = tokio::spawn(async move {
+            let control = ServerControl::default()
+                .listener(listener)
+                .shutdown(async move {
+                    let _ = shutdown.await;
+                })
+                .on_ready(|_| {
+                    let _ = ready_tx.send(());
+                });
+            start_server_with_control(&config, control).await
+        });
+        (server, ready_rx)
+    };
+    let (old_tx, old_rx) = oneshot::channel();
+    let (old, ready) = start(listener.try_clone()?, old_rx);
+    ready.await?;
+    let mut client = KvClient::connect(ClientConfig::builder().addr(addr).build()?).await?;
+    let cmd = CommandRequest::new_hset("table1", "hello", "world".into());
+    assert_eq!(client.execute_unary(cmd).await?.status, 200);
+
+    let (_new_tx, new_rx) = oneshot::channel();
+    let (_new, ready) = start(listener, new_rx);
+    ready.await?;
+    old_tx.send(()).unwrap();
+    time::timeout(Duration::from_secs(1), old).await???;
+
+    // 新的服务器有自己的 MemTable
+    let mut client = KvClient::connect(ClientConfig::builder().addr(addr).build()?).await?;
+    let res = client
+        .execute_unary(CommandRequest::new_hget("table1", "hello"))
+        .await?;
+    assert_eq!(res.status, 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn generated_config_should_work() -> Result<()> {
+    let addr = "127.0.0.1:10117";
+
+    let dir = tempfile::tempdir()?;
+    gen_config(dir.path(), addr, "localhost", false)?;
+    let mut config = ServerConfig::load(&dir.path().join("server.conf").display().to_string())?;
+    config.storage = StorageConfig::MemTable;
+    config.log.enable_log_file = false;
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+
+    time::sleep(Duration::from_millis(10)).await;
+    let config = ClientConfig::load(&dir.path().join("client.conf").display().to_string())?;
+    let mut client = KvClient::connect(config).await?;
+    let res = client
+        .execute_unary(CommandRequest::new_hset("table1", "hello", "world".into()))
+        .await?;
+    assert_eq!(res.status, 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_commands_should_only_work_on_admin_listener() -> Result<()> {
+    let addr = "127.0.0.1:10118";
+    let admin_addr = "127.0.0.1:10119";
+
+    let admin = AdminConfig {
+        enabled: true,
+        addr: admin_addr.into(),
+        ..Default::default()
+    };
+    let config = ServerConfig::builder().addr(addr).admin(admin).build()?;
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    let mut client = KvClient::connect(ClientConfig::builder().addr(addr).build()?).await?;
+    let res = client
+        .execute_unary(CommandRequest::new_hset("table1", "hello", "world".into()))
+        .await?;
+    assert_eq!(res.status, 200);
+    let res = client
+        .execute_unary(CommandRequest::new_client_list())
+        .await?;
+    assert_eq!(res.status, 403);
+
+    // 管理端口和数据端口共用同一份数据和连接列表
+    let mut admin = KvClient::connect(ClientConfig::builder().addr(admin_addr).build()?).await?;
+    let res = admin
+        .execute_unary(CommandRequest::new_client_list())
+        .await?;
+    assert_eq!(res.status, 200);
+    assert_eq!(res.values.len(), 2);
+    let res = admin
+        .execute_unary(CommandRequest::new_hget("table1", "hello"))
+        .await?;
+    assert_eq!(res.values, &["world".into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_commands_should_be_refused_by_default() -> Result<()> {
+    let addr = "127.0.0.1:10139";
+
+    let config = ServerConfig::builder().addr(addr).build()?;
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    // 没有开启管理端口也没有配置角色时，数据端口上的客户端不能执行运维命令
+    let mut client = KvClient::connect(ClientConfig::builder().addr(addr).build()?).await?;
+    let res = client
+        .execute_unary(CommandRequest::new_hset("table1", "hello", "world".into()))
+        .await?;
+    assert_eq!(res.status, 200);
+    for cmd in [
+        CommandRequest::new_flushall(Flushall::CONFIRM),
+        CommandRequest::new_promote(0),
+        CommandRequest::new_client_kill(1),
+    ] {
+        let res = client.execute_unary(cmd).await?;
+        assert_eq!(res.status, 403);
+    }
+    let res = client
+        .execute_unary(CommandRequest::new_hget("table1", "hello"))
+        .await?;
+    assert_eq!(res.values, &["world".into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn noise_server_should_apply_auth_and_limits() -> Result<()> {
+    let addr = "127.0.0.1:10140";
+
+    let noise = ServerNoiseConfig {
+        key: include_str!("../fixtures_noise/server.key").trim().into(),
+        ..Default::default()
+    };
+    let config = ServerConfig::builder()
+        .addr(addr)
+        .noise(noise)
+        .password("secret")
+        .limits(LimitsConfig {
+            max_concurrent_streams: 1,
+            ..Default::default()
+        })
+        .build()?;
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    let noise = ClientNoiseConfig {
+        key: include_str!("../fixtures_noise/client.key").trim().into(),
+        ..Default::default()
+    };
+    let cmd = CommandRequest::new_hset("table1", "hello", "world".into());
+    let config = ClientConfig::builder()
+        .addr(addr)
+        .noise(noise.clone())
+        .build()?;
+    let mut client = KvClient::connect(config).await?;
+    assert_eq!(client.execute_unary(cmd.clone()).await?.status, 401);
+
+    // Noise 连接和 TLS 连接一样要先认证，也受每个连接的 stream 上限约束
+    let config = ClientConfig::builder()
+        .addr(addr)
+        .noise(noise)
+        .password("secret")
+        .build()?;
+    let mut client = KvClient::connect(config.clone()).await?;
+    assert_eq!(client.execute_unary(cmd).await?.status, 200);
+
+    let mut ctrl = start_client_with_config(&config).await?;
+    let mut stream = ctrl.open_stream().await?;
+    let res = stream
+        .execute_unary(CommandRequest::new_hget("table1", "hello"))
+        .await?;
+    assert_eq!(res.values, &["world".into()]);
+    let mut other = ctrl.open_stream().await?;
+    let res = other
+        .execute_unary(CommandRequest::new_hget("table1", "hello"))
+        .await?;
+    assert_eq!(res.status, 503);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn noise_psk_should_be_loaded_from_config() -> Result<()> {
+    let addr = "127.0.0.1:10141";
+    let dir = tempfile::tempdir()?;
+    let psk_path = dir.path().join("noise.psk");
+    let (psk, _) = kv::generate_keypair()?;
+    std::fs::write(&psk_path, &psk)?;
+
+    let noise = ServerNoiseConfig {
+        key: include_str!("../fixtures_noise/server.key").trim().into(),
+        psk_path: Some(psk_path.display().to_string()),
+        ..Default::default()
+    };
+    let config = ServerConfig::builder().addr(addr).noise(noise).build()?;
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    // 没有 PSK 的客户端握手失败
+    let mut noise = ClientNoiseConfig {
+        key: include_str!("../fixtures_noise/client.key").trim().into(),
+        ..Default::default()
+    };
+    let config = ClientConfig::builder()
+        .addr(addr)
+        .noise(noise.clone())
+        .build()?;
+    assert!(KvClient::connect(config).await.is_err());
+
+    noise.psk = psk;
+    let config = ClientConfig::builder().addr(addr).noise(noise).build()?;
+    let mut client = KvClient::connect(config).await?;
+    let res = client
+        .execute_unary(CommandRequest::new_hset("table1", "hello", "world".into()))
+        .await?;
+    assert_eq!(res.status, 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn failed_handshake_should_not_affect_other_clients() -> Result<()> {
+    let addr = "127.0.0.1:10120";
+
+    let mut config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf"))?;
+    config.general.addr = addr.into();
+    config.storage = StorageConfig::MemTable;
+    let server = tokio::spawn(async move { start_server_with_config(&config).await });
+    time::sleep(Duration::from_millis(10)).await;
+
+    // 不做 TLS 握手，直接发送明文数据后断开
+    let mut stream = tokio::net::TcpStream::connect(addr).await?;
+    stream.write_all(b"not a tls handshake").await?;
+    drop(stream);
+
+    let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
+    config.general.addr = addr.into();
+    let mut client = KvClient::connect(config).await?;
+    let res = client
+        .execute_unary(CommandRequest::new_hset("table1", "hello", "world".into()))
+        .await?;
+    assert_eq!(res.status, 200);
+    assert!(!server.is_finished());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn clients_should_authenticate_with_password() -> Result<()> {
+    let addr = "127.0.0.1:10121";
+
+    let config = ServerConfig::builder()
+        .addr(addr)
+        .password("secret")
+        .build()?;
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    let cmd = CommandRequest::new_hset("table1", "hello", "world".into());
+    let mut client = KvClient::connect(ClientConfig::builder().addr(addr).build()?).await?;
+    let res = client.execute_unary(cmd.clone()).await?;
+    assert_eq!(res.status, 401);
+
+    let config = ClientConfig::builder()
+        .addr(addr)
+        .password("wrong")
+        .build()?;
+    assert!(KvClient::connect(config).await.is_err());
+
+    // 连接建立后自动认证，之后每个请求的 stream 都不需要再认证
+    let config = ClientConfig::builder()
+        .addr(addr)
+        .password("secret")
+        .build()?;
+    let mut client = KvClient::connect(config).await?;
+    let res = client.execute_unary(cmd).await?;
+    assert_eq!(res.status, 200);
+    let res = client
+        .execute_unary(CommandRequest::new_hget("table1", "hello"))
+        .await?;
+    assert_eq!(res.values, &["world".into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn disabled_commands_should_be_rejected() -> Result<()> {
+    let addr = "127.0.0.1:10123";
+
+    let commands = CommandsConfig {
+        deny: vec!["hgetall".into()],
+        ..Default::default()
+    };
+    let config = ServerConfig::builder()
+        .addr(addr)
+        .commands(commands)
+        .build()?;
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    let mut client = KvClient::connect(ClientConfig::builder().addr(addr).build()?).await?;
+    let res = client
+        .execute_unary(CommandRequest::new_hset("table1", "hello", "world".into()))
+        .await?;
+    assert_eq!(res.status, 200);
+    let res = client
+        .execute_unary(CommandRequest::new_hgetall("table1"))
+        .await?;
+    assert_eq!(res.status, 403);
+    assert_eq!(res.message, "Command disabled: hgetall");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn renamed_commands_should_need_new_name() -> Result<()> {
+    let addr = "127.0.0.1:10124";
+
+    let commands = CommandsConfig {
+        rename: [("hdelprefix".to_string(), "hdelprefix-7f3a".to_string())].into(),
+        ..Default::default()
+    };
+    let config = ServerConfig::builder()
+        .addr(addr)
+        .commands(commands)
+        .build()?;
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    let mut client = KvClient::connect(ClientConfig::builder().addr(addr).build()?).await?;
+    let cmd = CommandRequest::new_hdelprefix("table1", "");
+    let res = client.execute_unary(cmd.clone()).await?;
+    assert_eq!(res.status, 400);
+    assert!(res.message.contains("unknown command hdelprefix"));
+
+    // 客户端配置了同样的 rename 时自动带上新名字
+    let config = ClientConfig::builder()
+        .addr(addr)
+        .rename_command("hdelprefix", "hdelprefix-7f3a")
+        .build()?;
+    let mut client = KvClient::connect(config).await?;
+    let res = client.execute_unary(cmd).await?;
+    assert_eq!(res.status, 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn shadow_server_should_receive_mirrored_commands() -> Result<()> {
+    let addr = "127.0.0.1:10125";
+    let shadow_addr = "127.0.0.1:10126";
+
+    let shadow = ShadowConfig {
+        enabled: true,
+        remote: Some(ClientConfig::builder().addr(shadow_addr).build()?),
+        ..Default::default()
+    };
+    let config = ServerConfig::builder().addr(addr).shadow(shadow).build()?;
+    let shadow_config = ServerConfig::builder().addr(shadow_addr).build()?;
+    tokio::spawn(async move {
+        start_server_with_config(&shadow_config).await.unwrap();
+    });
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    let mut client = KvClient::connect(ClientConfig::builder().addr(addr).build()?).await?;
+    let res = client
+        .execute_unary(CommandRequest::new_hset("table1", "hello", "world".into()))
+        .await?;
+    assert_eq!(res.status, 200);
+
+    // 转发是异步的，等影子服务器执行完
+    let mut shadow = KvClient::connect(ClientConfig::builder().addr(shadow_addr).build()?).await?;
+    let mut values = vec![];
+    for _ in 0..50 {
+        let res = shadow
+            .execute_unary(CommandRequest::new_hget("table1", "hello"))
+            .await?;
+        if res.status == 200 {
+            values = res.values;
+            break;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(values, &["world".into()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn memcached_clients_should_share_data_with_kv_clients() -> Result<()> {
+    let addr = "127.0.0.1:10127";
+    let memcached_addr = "127.0.0.1:10128";
+
+    let memcached = MemcachedConfig {
+        enabled: true,
+        addr: memcached_addr.into(),
+        table: "cache".into(),
+        ..Default::default()
+    };
+    let config = ServerConfig::builder()
+        .addr(addr)
+        .memcached(memcached)
+        .build()?;
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    let mut stream =
+        tokio::io::BufReader::new(tokio::net::TcpStream::connect(memcached_addr).await?);
+    stream.write_all(b"set hello 0 0 5\r\nworld\r\n").await?;
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    assert_eq!(line, "STORED\r\n");
+
+    // 用普通的客户端读 memcached 写入的数据
+    let mut client = KvClient::connect(ClientConfig::builder().addr(addr).build()?).await?;
+    let res = client
+        .execute_unary(CommandRequest::new_hget("cache", "hello"))
+        .await?;
+    assert_eq!(res.values, &[b"world".into()]);
+
+    client
+        .execute_unary(CommandRequest::new_hset("cache", "count", "41".into()))
+        .await?;
+    stream.write_all(b"incr count 1\r\n").await?;
+    line.clear();
+    stream.read_line(&mut line).await?;
+    assert_eq!(line, "42\r\n");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn bench_should_report_every_command_in_mix() -> Result<()> {
+    let addr = "127.0.0.1:10129";
+    let config = ServerConfig::builder().addr(addr).build()?;
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    let config = BenchConfig {
+        concurrency: 2,
+        duration: Duration::from_millis(200),
+        mix: "hset=1,hget=1,publish=1".parse()?,
+        keys: 10,
+        distribution: KeyDistribution::Zipf(0.99),
+        ..BenchConfig::new(ClientConfig::builder().addr(addr).build()?)
+    };
+    let report = kv::bench::run(&config).await?;
+    let commands: Vec<_> = report.ops.iter().map(|op| op.command).collect();
+    assert_eq!(commands, ["hset", "hget", "publish"]);
+    for op in &report.ops {
+        assert!(op.count > 0 && op.errors == 0, "{}", op);
+        assert!(op.p50 <= op.p99 && op.p99 <= op.max);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn ordered_memtable_should_return_hgetall_in_key_order() -> Result<()> {
+    let addr = "127.0.0.1:10130";
+    let config = ServerConfig::builder()
+        .addr(addr)
+        .storage(StorageConfig::MemTableOrdered)
+        .build()?;
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    let mut client = KvClient::connect(ClientConfig::builder().addr(addr).build()?).await?;
+    for key in ["k3", "k1", "k10", "k2"] {
+        client
+            .execute_unary(CommandRequest::new_hset("t1", key, key.into()))
+            .await?;
+    }
+    let res = client
+        .execute_unary(CommandRequest::new_hgetall("t1"))
+        .await?;
+    let keys: Vec<_> = res.pairs.iter().map(|p| p.key.as_str()).collect();
+    assert_eq!(keys, ["k1", "k10", "k2", "k3"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn oversized_responses_should_be_reassembled_by_client() -> Result<()> {
+    let addr = "127.0.0.1:10131";
+    let config = ServerConfig::builder()
+        .addr(addr)
+        .limits(LimitsConfig {
+            max_response_frame_bytes: 1024,
+            ..Default::default()
+        })
+        .build()?;
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+
+    let mut client = KvClient::connect(ClientConfig::builder().addr(addr).build()?).await?;
+    let (tx, mut rx) = mpsc::channel(16);
+    let _sub = client
+        .subscribe_with("lobby", move |values| {
+            let tx = tx.clone();
+            async move {
+                tx.send(values).await.unwrap();
+            }
+        })
+        .await?;
+
+    // 值本身就超过了响应 frame 的上限
+    let value: kv::Value = "x".repeat(10 * 1024).into();
+    client
+        .execute_unary(CommandRequest::new_hset("t1", "k1", value.clone()))
+        .await?;
+    let res = client
+        .execute_unary(CommandRequest::new_hget("t1", "k1"))
+        .await?;
+    assert_eq!(res.values, vec![value.clone()]);
+
+    // 订阅的推送同样会被拆开再拼起来
+    client
+        .execute_unary(CommandRequest::new_publish("lobby", vec![value.clone()]))
+        .await?;
+    assert_eq!(rx.recv().await.unwrap(), vec![value]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn memtable_should_be_restored_from_snapshot() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let snapshot = SnapshotConfig {
+        enabled: true,
+        path: dir.path().join("snapshot.bin").display().to_string(),
+        interval_ms: 60_000,
+    };
+
+    // 正常关闭时写一次快照
+    let addr = "127.0.0.1:10132";
+    let mut config = ServerConfig::builder()
+        .addr(addr)
+        .snapshot(snapshot.clone())
+        .build()?;
+    config.limits.shutdown_timeout_ms = 200;
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        let control = ServerControl::default().shutdown(async move {
+            let _ = shutdown_rx.await;
+        });
+        start_server_with_control(&config, control).await
+    });
+    time::sleep(Duration::from_millis(10)).await;
+    let mut client = KvClient::connect(ClientConfig::builder().addr(addr).build()?).await?;
+    client
+        .execute_unary(CommandRequest::new_hset("t1", "k1", "v1".into()))
+        .await?;
+    drop(client);
+    shutdown_tx.send(()).unwrap();
+    time::timeout(Duration::from_secs(1), server).await???;
+
+    // 重新启动的服务器从快照里恢复数据
+    let addr = "127.0.0.1:10133";
+    let config = ServerConfig::builder()
+        .addr(addr)
+        .snapshot(snapshot)
+        .build()?;
+    tokio::spawn(async move {
+        start_server_with_config(&config).await.unwrap();
+    });
+    time::sleep(Duration::from_millis(10)).await;
+    let mut client = KvClient::connect(ClientConfig::builder().addr(addr).build()?).await?;
+    let res = client
+        .execute_unary(CommandRequest::new_hget("t1", "k1"))
+        .await?;
+    assert_eq!(res.values, vec!["v1".into()]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn streams_over_limit_should_be_refused() -> Result<()> {
+    let addr = "127.0.0.1:10135";
+
+    let mut config = ServerConfig::builder().addr(addr).build()?;
+    config.limits.max_concurrent_streams = 2;
+    let (ready_tx, ready_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let control = ServerControl::default().on_ready(|addr| {
+            let _ = ready_tx.send(addr);
+        });
+        start_server_with_control(&config, control).await
+    });
+    ready_rx.await?;
+
+    let config = ClientConfig::builder().addr(addr).build()?;
+    let mut ctrl = start_client_with_config(&config).await?;
+    let mut streams = Vec::new();
+    for i in 0..2 {
+        let mut stream = ctrl.open_stream().await?;
+        let cmd = CommandRequest::new_hset("t1", format!("k{i}"), "v".into());
+        assert_eq!(stream.execute_unary(cmd).await?.status, 200);
+        streams.push(stream);
+    }
+
+    // 前两个 stream 还开着，第三个超过了上限，只有它收到 503，连接和其它 stream 不受影响
+    let mut stream = ctrl.open_stream().await?;
+    let res = stream
+        .execute_unary(CommandRequest::new_hget("t1", "k0"))
+        .await?;
+    assert_eq!(res.status, 503);
+    let res = streams[0]
+        .execute_unary(CommandRequest::new_hget("t1", "k0"))
+        .await?;
+    assert_eq!(res.values, vec!["v".into()]);
+
+    // 关掉一个 stream 之后可以再打开新的
+    streams.pop();
+    time::sleep(Duration::from_millis(50)).await;
+    let mut stream = ctrl.open_stream().await?;
+    let res = stream
+        .execute_unary(CommandRequest::new_hget("t1", "k1"))
+        .await?;
+    assert_eq!(res.values, vec!["v".into()]);
+
+    // 上限只针对单个连接，新的连接不受影响
+    let mut client = KvClient::connect(config).await?;
+    let res = client
+        .execute_unary(CommandRequest::new_hget("t1", "k0"))
+        .await?;
+    assert_eq!(res.values, vec!["v".into()]);
+    Ok(())
+}